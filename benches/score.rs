@@ -0,0 +1,84 @@
+//! Scoring reads that all overlap the same locus, similar to high coverage
+//! targeted sequencing data.
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use assert_fs::TempDir;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libcawlr::{
+    arrow::{
+        arrow_utils::{load_apply, save, wrap_writer},
+        eventalign::Eventalign,
+    },
+    collapse::CollapseOptions,
+    motif::Motif,
+    rank::RankOptions,
+    score::ScoreOptions,
+    train::{Train, TrainStrategy},
+    utils::CawlrIO,
+};
+
+const GENOME: &str = "extra/sacCer3.fa";
+
+/// Write the single test read to the output many times over
+fn duplicate_read(temp_dir: &Path, coverage: usize) -> eyre::Result<()> {
+    let collapsed = temp_dir.join("single_read.arrow");
+    let mut collapse = CollapseOptions::try_new("extra/single_read.bam", &collapsed)?;
+    collapse.run(File::open("extra/single_read.eventalign.txt")?)?;
+
+    let output = File::create(temp_dir.join("high_coverage.arrow"))?;
+    let mut writer = wrap_writer(output, &Eventalign::schema())?;
+    load_apply(File::open(&collapsed)?, |reads: Vec<Eventalign>| {
+        let reads = reads
+            .iter()
+            .cycle()
+            .take(reads.len() * coverage)
+            .cloned()
+            .collect::<Vec<_>>();
+        save(&mut writer, &reads)
+    })?;
+    writer.finish()?;
+
+    let model = Train::try_new(&collapsed, GENOME, 50, TrainStrategy::AvgSample)?.run()?;
+    model.save_as(temp_dir.join("model.pickle"))?;
+    let ranks = RankOptions::new(2456, 1_000).rank(&model, &model);
+    ranks.save_as(temp_dir.join("ranks.pickle"))?;
+    Ok(())
+}
+
+fn score_high_coverage(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    duplicate_read(temp_dir.path(), 100).unwrap();
+    let model = temp_dir.path().join("model.pickle");
+    let ranks = temp_dir.path().join("ranks.pickle");
+    let input = temp_dir.path().join("high_coverage.arrow");
+    let output = temp_dir.path().join("scores.arrow");
+    let genome = PathBuf::from(GENOME);
+
+    c.bench_function("score 100x coverage", |b| {
+        b.iter_batched(
+            || {
+                let mut scoring =
+                    ScoreOptions::try_new(&model, &model, &genome, &ranks, &output).unwrap();
+                scoring
+                    .motifs(vec![
+                        "2:AT".parse::<Motif>().unwrap(),
+                        "1:TA".parse::<Motif>().unwrap(),
+                    ])
+                    .unwrap();
+                scoring
+            },
+            |scoring| scoring.run(&input).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = score_high_coverage
+}
+criterion_main!(benches);
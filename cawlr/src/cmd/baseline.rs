@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{baseline::BaselineOptions, context::GenomeCache, motif::Motif, utils::ChromAlias};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct BaselineCmd {
+    /// Path to Apache Arrow file from cawlr collapse
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// Path to output file
+    #[clap(short, long)]
+    pub output: PathBuf,
+
+    /// Tab-separated pore model with kmer, level_mean, and level_stdv
+    /// columns, ie a .model file from nanopolish or ONT's kmer_models
+    #[clap(short, long)]
+    pub pore_model: ValidPathBuf,
+
+    /// Path to fasta file for organisms genome, must have a .fai file from
+    /// samtools faidx
+    #[clap(short, long)]
+    pub genome: ValidPathBuf,
+
+    /// Genome sequence to keep in memory, either a size in MB for the most
+    /// recently used parts of the genome or "preload" to read the whole
+    /// genome up front
+    #[clap(long, default_value = "256")]
+    pub genome_cache: GenomeCache,
+
+    /// UCSC style chromosome alias file, each line has tab-separated names
+    /// for the same chromosome. Used if reads and the genome name
+    /// chromosomes differently, ie "1" and "chr1"
+    #[clap(long)]
+    pub chrom_alias: Option<PathBuf>,
+
+    /// Only score in kmers that contain this motif, by default will score
+    /// all kmers. Format = "{position of modified base}:{motif}", ie "2:GC"
+    #[clap(short, long, num_args = 1.., value_delimiter = ',')]
+    pub motif: Option<Vec<Motif>>,
+}
+
+impl BaselineCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = BaselineOptions::try_new(self.pore_model.0, self.genome.0, self.output)?;
+        opts.genome_cache(self.genome_cache)?;
+        if let Some(chrom_alias) = self.chrom_alias {
+            opts.chrom_alias(ChromAlias::from_path(chrom_alias)?);
+        }
+        if let Some(motifs) = self.motif {
+            opts.motifs(motifs);
+        }
+        opts.run(self.input)
+    }
+}
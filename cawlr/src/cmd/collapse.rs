@@ -5,7 +5,11 @@ use std::{
 };
 
 use clap::Parser;
-use libcawlr::{collapse::CollapseOptions, utils};
+use libcawlr::{
+    arrow::arrow_utils::{ArrowCompression, ReadMode},
+    collapse::CollapseOptions,
+    utils::{self, ChromAlias},
+};
 
 #[derive(Parser, Debug)]
 pub struct CollapseCmd {
@@ -26,13 +30,72 @@ pub struct CollapseCmd {
     #[clap(short, long, default_value_t = 2048)]
     /// Number of eventalign records to hold in memory.
     pub capacity: usize,
+
+    /// Approximate memory limit in MiB. Use for eventalign output that isn't
+    /// grouped by read, ie sorted by position, so pieces of each read are
+    /// merged back together. Reads spill to temporary files when over the
+    /// limit.
+    #[clap(long)]
+    pub max_memory: Option<usize>,
+
+    /// Write a tab-separated file with quality statistics for each read, ie
+    /// number of events, skipped positions, mean dwell time and current.
+    #[clap(long)]
+    pub summary: Option<PathBuf>,
+
+    /// Write every line of eventalign left out of the output to a
+    /// tab-separated file with a reason code, ie lines that failed to parse
+    /// or reads missing from the BAM. Counts for each reason are written at
+    /// the end.
+    #[clap(long)]
+    pub errors: Option<PathBuf>,
+
+    /// UCSC style chromosome alias file, each line has tab-separated names
+    /// for the same chromosome. Used if eventalign and the BAM name
+    /// chromosomes differently, ie "1" and "chr1"
+    #[clap(long)]
+    pub chrom_alias: Option<PathBuf>,
+
+    /// Only use primary alignments from the BAM, leaving out eventalign from
+    /// supplementary or secondary alignments. By default the strand of each
+    /// piece of a read comes from the alignment at its position.
+    #[clap(long)]
+    pub primary_only: bool,
+
+    /// What the reads were sequenced from and aligned to, either
+    /// "dna-genome", "rna" for direct RNA aligned to a genome, or
+    /// "transcriptome". Kmers of minus strand reads are only reverse
+    /// complemented for DNA, and models can only score reads with the same
+    /// mode.
+    #[clap(long, default_value_t = ReadMode::DnaGenome)]
+    pub mode: ReadMode,
+
+    /// Drop the raw samples of each event, storing only the mean current and
+    /// dwell time. Outputs are much smaller and can still be trained with
+    /// the avg strategy and scored, but not with npsmlr or other train
+    /// strategies.
+    #[clap(long, overrides_with = "samples")]
+    pub no_samples: bool,
+
+    /// Keep the raw samples of each event, the default
+    #[clap(long, overrides_with = "no_samples")]
+    pub samples: bool,
+
+    /// Compression of the output, either "lz4", "zstd" for smaller files at
+    /// some CPU cost, ie for archiving, or "none"
+    #[clap(long, default_value_t = ArrowCompression::Lz4)]
+    pub compression: ArrowCompression,
 }
 
 impl CollapseCmd {
-    pub fn run(self) -> eyre::Result<()> {
+    /// Shows how much of the input was read on stderr if progress is set
+    pub fn run(self, progress: bool) -> eyre::Result<()> {
         if self.capacity == 0 {
             return Err(eyre::eyre!("Capacity must be greater than 0"));
         }
+        if self.max_memory == Some(0) {
+            return Err(eyre::eyre!("Max memory must be greater than 0"));
+        }
         let final_input: Box<dyn Read> = {
             if let Some(path) = self.input {
                 Box::new(File::open(path)?)
@@ -46,7 +109,25 @@ impl CollapseCmd {
         let final_output = BufWriter::new(final_output);
 
         let mut collapse = CollapseOptions::from_writer(final_output, &self.bam)?;
-        collapse.capacity(self.capacity).progress(true);
+        collapse
+            .capacity(self.capacity)
+            .samples(!self.no_samples)
+            .compression(self.compression)
+            .primary_only(self.primary_only)
+            .mode(self.mode)
+            .progress(progress);
+        if let Some(max_memory) = self.max_memory {
+            collapse.max_memory(max_memory << 20);
+        }
+        if let Some(summary) = &self.summary {
+            collapse.summary(summary)?;
+        }
+        if let Some(errors) = &self.errors {
+            collapse.errors(errors)?;
+        }
+        if let Some(chrom_alias) = &self.chrom_alias {
+            collapse.chrom_alias(ChromAlias::from_path(chrom_alias)?);
+        }
         collapse.run(final_input)?;
         Ok(())
     }
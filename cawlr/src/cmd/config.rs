@@ -0,0 +1,436 @@
+use std::{fs, io::Write, path::PathBuf};
+
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command, CommandFactory, Subcommand};
+use eyre::{Result, WrapErr};
+use libcawlr::utils;
+use toml::{Table, Value};
+
+use crate::Args;
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCmd {
+    /// Write a config file listing every option of a subcommand, to fill in
+    /// and pass with --config
+    Init {
+        /// Subcommand to list the options of, with the names of any parent
+        /// commands, ie "score" or "npsmlr train"
+        #[clap(required = true, num_args = 1..)]
+        command: Vec<String>,
+
+        /// TOML output, defaults to stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+impl ConfigCmd {
+    pub fn run(self) -> Result<()> {
+        match self {
+            ConfigCmd::Init { command, output } => {
+                let mut writer = utils::stdout_or_file(output.as_ref())?;
+                write_template(&mut writer, &command)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Add the options from the --config file to the command line arguments, so
+/// they are parsed as if they were passed on the command line. Options of a
+/// subcommand are in a table named after it, ie [score] or [npsmlr.train],
+/// and global options like threads are at the top level. Options given on
+/// the command line take precedence over the file, and tables of other
+/// subcommands are ignored so one file can hold the options of several.
+pub fn expand_args(args: Vec<String>) -> Result<Vec<String>> {
+    expand_args_for(Args::command(), args)
+}
+
+/// [expand_args] for any command
+fn expand_args_for(cmd: Command, args: Vec<String>) -> Result<Vec<String>> {
+    let mut cmd = cmd.ignore_errors(true);
+    cmd.build();
+    // Invalid arguments, --help, and --version are left to the full parse
+    let Ok(matches) = cmd.clone().try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+    let table = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read config file {}", path.display()))?
+        .parse::<Table>()
+        .wrap_err_with(|| format!("Invalid config file {}", path.display()))?;
+
+    // Options of each table go right after the name of its subcommand, so a
+    // parent's options aren't parsed as options of its child
+    let mut inserts = Vec::new();
+    let (mut cmd, mut matches, mut table) = (&cmd, &matches, &table);
+    let mut section = String::from("the top level");
+    let mut at = 1;
+    loop {
+        let mut options = Vec::new();
+        push_options(cmd, matches, table, &section, &mut options)?;
+        inserts.push((at, options));
+        let Some((name, sub_matches)) = matches.subcommand() else {
+            break;
+        };
+        table = match table.get(name) {
+            Some(Value::Table(sub_table)) => sub_table,
+            Some(_) => eyre::bail!("Expected a [{name}] table in the config file"),
+            None => break,
+        };
+        let sub = cmd
+            .find_subcommand(name)
+            .ok_or_else(|| eyre::eyre!("Unknown subcommand {name}"))?;
+        at = subcommand_index(cmd, sub, &args, at)
+            .ok_or_else(|| eyre::eyre!("Subcommand {name} is missing from the arguments"))?
+            + 1;
+        cmd = sub;
+        matches = sub_matches;
+        section = format!("[{}]", subcommand_path(&section, name));
+    }
+    let mut expanded = args;
+    for (at, options) in inserts.into_iter().rev() {
+        expanded.splice(at..at, options);
+    }
+    Ok(expanded)
+}
+
+/// Position of the name of sub in the arguments of cmd, which start at start.
+/// Values of options given as a separate argument are skipped, so a value that
+/// is the same as the name isn't taken for the subcommand.
+fn subcommand_index(cmd: &Command, sub: &Command, args: &[String], start: usize) -> Option<usize> {
+    let mut idx = start;
+    while idx < args.len() {
+        let token = args[idx].as_str();
+        if token == sub.get_name() || sub.get_all_aliases().any(|alias| alias == token) {
+            return Some(idx);
+        }
+        if takes_separate_value(cmd, token) {
+            idx += 1;
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Whether the token is an option of cmd with its value in the next argument,
+/// ie "--output out.arrow" or "-o out.arrow"
+fn takes_separate_value(cmd: &Command, token: &str) -> bool {
+    let arg = if let Some(long) = token.strip_prefix("--") {
+        cmd.get_arguments().find(|arg| arg.get_long() == Some(long))
+    } else if let Some(short) = token.strip_prefix('-').filter(|s| s.chars().count() == 1) {
+        cmd.get_arguments()
+            .find(|arg| arg.get_short() == short.chars().next())
+    } else {
+        None
+    };
+    arg.map_or(false, |arg| arg.get_action().takes_values())
+}
+
+/// Dotted table name of a subcommand, ie npsmlr.train
+fn subcommand_path(parent: &str, name: &str) -> String {
+    match parent.strip_prefix('[').and_then(|p| p.strip_suffix(']')) {
+        Some(parent) => format!("{parent}.{name}"),
+        None => name.to_string(),
+    }
+}
+
+/// Options of the command that can be set from a config file, ie not
+/// positional arguments, --help, or --config itself
+fn configurable(arg: &Arg) -> bool {
+    arg.get_long().is_some()
+        && arg.get_id() != "config"
+        && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+}
+
+fn push_options(
+    cmd: &Command,
+    matches: &ArgMatches,
+    table: &Table,
+    section: &str,
+    args: &mut Vec<String>,
+) -> Result<()> {
+    for (key, value) in table {
+        // Options of subcommands
+        if value.is_table() {
+            continue;
+        }
+        let arg = cmd
+            .get_arguments()
+            .filter(|arg| configurable(arg))
+            .find(|arg| arg.get_long() == Some(key.as_str()) || arg.get_id() == key.as_str())
+            .ok_or_else(|| {
+                eyre::eyre!("Unknown option \"{key}\" in {section} of the config file")
+            })?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        push_option(arg, value, args).wrap_err_with(|| format!("Invalid value for \"{key}\""))?;
+    }
+    Ok(())
+}
+
+fn push_option(arg: &Arg, value: &Value, args: &mut Vec<String>) -> Result<()> {
+    let flag = format!("--{}", arg.get_long().expect("Options have a long flag"));
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue | ArgAction::SetFalse, Value::Boolean(set)) => {
+            let default_set = matches!(arg.get_action(), ArgAction::SetTrue);
+            if *set == default_set {
+                args.push(flag);
+            }
+        }
+        (ArgAction::Count, Value::Integer(n)) => {
+            let n = usize::try_from(*n)
+                .map_err(|_| eyre::eyre!("Expected a count of 0 or more, not {n}"))?;
+            args.extend(std::iter::repeat(flag).take(n));
+        }
+        (_, Value::Array(values)) => {
+            let values = values.iter().map(scalar).collect::<Result<Vec<_>>>()?;
+            let multiple = arg
+                .get_num_args()
+                .map(|n| n.max_values() > 1)
+                .unwrap_or(false);
+            if multiple {
+                args.push(flag);
+                args.extend(values);
+            } else {
+                for value in values {
+                    args.push(format!("{flag}={value}"));
+                }
+            }
+        }
+        (action, value) if action.takes_values() => {
+            args.push(format!("{flag}={}", scalar(value)?));
+        }
+        _ => eyre::bail!("Expected true or false"),
+    }
+    Ok(())
+}
+
+/// Command line form of a single TOML value
+fn scalar(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) | Value::Datetime(_) => {
+            Ok(value.to_string())
+        }
+        _ => Err(eyre::eyre!("Expected a string, number, or boolean")),
+    }
+}
+
+/// TOML form of a default value from the command line, numbers and booleans
+/// are left bare and everything else is quoted
+fn toml_literal(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok() || value.parse::<bool>().is_ok()
+    {
+        value.to_string()
+    } else {
+        Value::String(value.to_string()).to_string()
+    }
+}
+
+/// Write every option of the subcommand as a commented out line with its help
+/// and default value, under the table for the subcommand
+fn write_template<W: Write>(mut writer: W, command: &[String]) -> Result<()> {
+    let mut cmd = Args::command();
+    cmd.build();
+    let mut sub = &cmd;
+    for name in command {
+        sub = sub
+            .find_subcommand(name)
+            .ok_or_else(|| eyre::eyre!("Unknown subcommand \"{}\"", command.join(" ")))?;
+    }
+    if sub.is_subcommand_required_set() {
+        let names = sub
+            .get_subcommands()
+            .map(|s| s.get_name())
+            .collect::<Vec<_>>()
+            .join(", ");
+        eyre::bail!(
+            "Choose one of the subcommands of {}: {names}",
+            command.join(" ")
+        );
+    }
+    writeln!(
+        writer,
+        "# Options for cawlr {name}, use with cawlr --config <FILE> {name}. Uncomment\n\
+         # options to set them, options given on the command line take precedence.",
+        name = command.join(" ")
+    )?;
+    writeln!(writer, "[{}]", command.join("."))?;
+    for arg in sub.get_arguments().filter(|arg| configurable(arg)) {
+        if arg.is_global_set() {
+            continue;
+        }
+        writeln!(writer)?;
+        if let Some(help) = arg.get_long_help().or_else(|| arg.get_help()) {
+            for line in help.to_string().lines() {
+                writeln!(writer, "# {line}")?;
+            }
+        }
+        if arg.is_required_set() {
+            writeln!(writer, "# Required")?;
+        }
+        let key = arg.get_long().expect("Options have a long flag");
+        let defaults = arg
+            .get_default_values()
+            .iter()
+            .map(|value| toml_literal(&value.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let value = match (arg.get_action(), defaults.as_slice()) {
+            (ArgAction::SetTrue, _) => String::from("false"),
+            (ArgAction::SetFalse, _) => String::from("true"),
+            (_, []) => String::from("\"\""),
+            (_, [value]) => value.clone(),
+            (_, values) => format!("[{}]", values.join(", ")),
+        };
+        writeln!(writer, "# {key} = {value}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::{prelude::*, TempDir};
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand_args() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let config = tmp.child("score.toml");
+        config.write_str(
+            "threads = 2\n\
+             [score]\n\
+             input = \"config.arrow\"\n\
+             cutoff = 5\n\
+             motif = [\"1:CG\", \"2:GC\"]\n\
+             by-chrom = true\n\
+             [collapse]\n\
+             capacity = 10\n",
+        )?;
+        let config = config.path().to_str().unwrap();
+
+        let expanded = expand_args(args(&[
+            "cawlr",
+            "--config",
+            config,
+            "score",
+            "-i",
+            "cli.arrow",
+        ]))?;
+        assert_eq!(
+            expanded,
+            args(&[
+                "cawlr",
+                "--threads=2",
+                "--config",
+                config,
+                "score",
+                "--by-chrom",
+                "--cutoff=5",
+                "--motif=1:CG",
+                "--motif=2:GC",
+                "-i",
+                "cli.arrow",
+            ])
+        );
+
+        // Without --config the arguments are unchanged
+        let cli = args(&["cawlr", "score", "-i", "cli.arrow"]);
+        assert_eq!(expand_args(cli.clone())?, cli);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_args_nested() -> Result<()> {
+        let cmd = Command::new("tool")
+            .arg(
+                Arg::new("config")
+                    .long("config")
+                    .global(true)
+                    .value_parser(clap::value_parser!(PathBuf)),
+            )
+            .subcommand(
+                Command::new("parent")
+                    .arg(Arg::new("level").long("level"))
+                    .subcommand(Command::new("child").arg(Arg::new("name").long("name"))),
+            );
+        let tmp = TempDir::new()?;
+        let config = tmp.child("nested.toml");
+        config.write_str("[parent]\nlevel = 3\n[parent.child]\nname = \"child\"\n")?;
+        let config = config.path().to_str().unwrap();
+
+        let expanded = expand_args_for(
+            cmd.clone(),
+            args(&["tool", "--config", config, "parent", "child"]),
+        )?;
+        assert_eq!(
+            expanded,
+            args(&[
+                "tool",
+                "--config",
+                config,
+                "parent",
+                "--level=3",
+                "child",
+                "--name=child"
+            ])
+        );
+        let matches = cmd.clone().try_get_matches_from(&expanded)?;
+        let (_, parent) = matches.subcommand().unwrap();
+        assert_eq!(parent.get_one::<String>("level").unwrap(), "3");
+
+        // A value that is the name of the child isn't taken for it
+        let cli = [
+            "tool", "--config", config, "parent", "--level", "child", "child",
+        ];
+        let expanded = expand_args_for(cmd, args(&cli))?;
+        assert_eq!(expanded[..7], args(&cli));
+        assert_eq!(expanded[7..], args(&["--name=child"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_args_unknown_option() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let config = tmp.child("score.toml");
+        config.write_str("[score]\nnot-an-option = 1\n")?;
+        let config = config.path().to_str().unwrap();
+        let res = expand_args(args(&["cawlr", "--config", config, "score"]));
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_count() -> Result<()> {
+        let arg = Arg::new("verbose").long("verbose").action(ArgAction::Count);
+        let mut expanded = Vec::new();
+        push_option(&arg, &Value::Integer(2), &mut expanded)?;
+        assert_eq!(expanded, args(&["--verbose", "--verbose"]));
+        assert!(push_option(&arg, &Value::Integer(-1), &mut expanded).is_err());
+        assert_eq!(expanded.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_template() -> Result<()> {
+        let mut template = Vec::new();
+        write_template(&mut template, &args(&["score"]))?;
+        let template = String::from_utf8(template)?;
+        assert!(template.contains("[score]"));
+        assert!(template.contains("# cutoff = 10"));
+        // Template parses as TOML with every option commented out
+        let table = template.parse::<Table>()?;
+        assert!(table["score"].as_table().unwrap().is_empty());
+
+        assert!(write_template(Vec::new(), &args(&["npsmlr"])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::diff::{DiffOptions, DiffTest};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct DiffCmd {
+    /// Two samples to compare, each output from cawlr score, a BAM file with
+    /// modification calls, or output from cawlr pileup. Or a single Arrow file
+    /// from cawlr merge along with the two samples to compare in it.
+    #[clap(short, long, required = true, num_args = 1..=2)]
+    pub input: Vec<ValidPathBuf>,
+
+    /// Samples to compare from a single input from cawlr merge
+    #[clap(short, long, num_args = 2)]
+    pub sample: Vec<String>,
+
+    /// Path to BED output ranked by p-value, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Statistical test, either "fisher" for Fisher's exact test or "lrt" for
+    /// a likelihood ratio test, which is faster for large windows
+    #[clap(long, default_value = "fisher")]
+    pub test: DiffTest,
+
+    /// Sum calls on both strands in windows of this many bases, by default
+    /// each position and strand is tested
+    #[clap(short, long)]
+    pub window: Option<u64>,
+
+    /// Leave out positions or windows with fewer calls in either sample, not
+    /// counting no-calls
+    #[clap(long, default_value_t = 5)]
+    pub min_coverage: u64,
+
+    /// Only write regions with a Benjamini-Hochberg adjusted p-value at or
+    /// below this, 1 writes every region tested
+    #[clap(long, default_value_t = 0.05)]
+    pub max_q_value: f64,
+
+    /// Scores greater than the threshold are counted as modified
+    #[clap(long, default_value_t = 0.5)]
+    pub threshold: f64,
+
+    /// Scores within this distance of the threshold are counted as no-calls
+    #[clap(long, default_value_t = 0.0)]
+    pub no_call_margin: f64,
+
+    /// Bam tag to use for modification detection, only used if the input is a
+    /// BAM file, ie C+m
+    #[clap(short, long)]
+    pub tag: Option<String>,
+}
+
+impl DiffCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = DiffOptions::default();
+        opts.threshold(self.threshold)
+            .no_call_margin(self.no_call_margin)
+            .mod_tag(self.tag)
+            .test(self.test)
+            .window(self.window)
+            .min_coverage(self.min_coverage)
+            .max_q_value(self.max_q_value);
+        let (a, b) = match (self.input.as_slice(), self.sample.as_slice()) {
+            ([merged], [a, b]) => {
+                opts.names(a.as_str(), b.as_str());
+                opts.load_samples(merged.as_ref(), a, b)?
+            }
+            ([a, b], []) => {
+                let stem = |p: &ValidPathBuf| {
+                    p.0.file_stem()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                };
+                opts.names(stem(a), stem(b));
+                (opts.load(a.as_ref())?, opts.load(b.as_ref())?)
+            }
+            ([_], []) => {
+                return Err(eyre::eyre!(
+                    "Give two inputs, or one input from cawlr merge with two samples to compare"
+                ))
+            }
+            _ => {
+                return Err(eyre::eyre!(
+                    "Samples can only be given with a single input from cawlr merge"
+                ))
+            }
+        };
+        let regions = opts.diff(a, b);
+        opts.write(&regions, self.output.as_ref())
+    }
+}
@@ -0,0 +1,48 @@
+use std::{io, path::PathBuf};
+
+use clap::Parser;
+use libcawlr::doctor::DoctorOptions;
+
+#[derive(Parser, Debug)]
+pub struct DoctorCmd {
+    /// Genome fasta to check against its .fai index
+    #[clap(short, long)]
+    pub genome: Option<PathBuf>,
+
+    /// Warn if the temporary directory has less free space than this, in GB
+    #[clap(long, default_value_t = 20)]
+    pub min_free_gb: u64,
+
+    /// Path to nanopolish, by default searches $PATH
+    #[clap(long)]
+    pub nanopolish_path: Option<PathBuf>,
+
+    /// Path to f5c, by default searches $PATH
+    #[clap(long)]
+    pub f5c_path: Option<PathBuf>,
+
+    /// Path to samtools, by default searches $PATH
+    #[clap(long)]
+    pub samtools_path: Option<PathBuf>,
+
+    /// Path to minimap2, by default searches $PATH
+    #[clap(long)]
+    pub minimap2_path: Option<PathBuf>,
+}
+
+impl DoctorCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = DoctorOptions::default();
+        opts.genome(self.genome)
+            .min_free_gb(self.min_free_gb)
+            .nanopolish(self.nanopolish_path)
+            .f5c(self.f5c_path)
+            .samtools(self.samtools_path)
+            .minimap2(self.minimap2_path);
+        let n_failed = opts.run(io::stdout())?;
+        if n_failed > 0 {
+            return Err(eyre::eyre!("{n_failed} checks failed"));
+        }
+        Ok(())
+    }
+}
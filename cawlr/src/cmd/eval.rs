@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{
+    eval::{EvalOptions, Truth},
+    utils,
+};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct EvalCmd {
+    /// Output from cawlr score, a BAM file with modification calls, or output
+    /// from cawlr pileup
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// BED file of modified positions, where every other position is
+    /// unmodified, or a bedMethyl file such as from bisulfite sequencing
+    #[clap(long)]
+    pub truth: ValidPathBuf,
+
+    /// Path to tab-separated confusion matrix at each threshold, defaults to
+    /// stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Path to tab-separated area under the ROC and precision-recall curves,
+    /// defaults to stderr
+    #[clap(long)]
+    pub summary: Option<PathBuf>,
+
+    /// Number of evenly spaced thresholds between 0 and 1
+    #[clap(long, default_value_t = 100)]
+    pub n_thresholds: usize,
+
+    /// bedMethyl sites with at least this percent modified are modified
+    #[clap(long, default_value_t = 90.0)]
+    pub min_modified: f64,
+
+    /// bedMethyl sites with at most this percent modified are unmodified
+    #[clap(long, default_value_t = 10.0)]
+    pub max_unmodified: f64,
+
+    /// bedMethyl sites covered by fewer reads are left out
+    #[clap(long, default_value_t = 5)]
+    pub min_coverage: u64,
+
+    /// Bam tag to use for modification detection, only used if the input is a
+    /// BAM file, ie C+m
+    #[clap(short, long)]
+    pub tag: Option<String>,
+}
+
+impl EvalCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let truth = Truth::from_path(
+            self.truth.as_ref(),
+            self.min_modified,
+            self.max_unmodified,
+            self.min_coverage,
+        )?;
+        let report = EvalOptions::default()
+            .n_thresholds(self.n_thresholds)
+            .mod_tag(self.tag)
+            .run(self.input.as_ref(), &truth)?;
+        report.write_curve(utils::stdout_or_file(self.output.as_ref())?)?;
+        match self.summary {
+            Some(summary) => report.write_summary(utils::create_output(summary)?)?,
+            None => report.write_summary(std::io::stderr())?,
+        }
+        Ok(())
+    }
+}
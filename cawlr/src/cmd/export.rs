@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use libcawlr::arrow::modbam_export::ModBamExport;
+
+use crate::file::ValidPathBuf;
+
+#[derive(Debug, Subcommand)]
+pub enum ExportCmd {
+    /// Parquet dataset with one row per score, partitioned by chromosome into
+    /// chrom=... directories. Requires building cawlr with --features parquet
+    Parquet {
+        /// Path to scored data from cawlr score, or a BAM file with
+        /// modification calls
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// Directory to write the dataset to
+        #[clap(short, long)]
+        output_dir: PathBuf,
+
+        /// Number of reads in each row group
+        #[clap(long, default_value_t = 4096)]
+        batch_size: usize,
+
+        /// Bam tag to use for modification detection, only used if the input
+        /// is a BAM file, ie C+m
+        #[clap(short, long)]
+        tag: Option<String>,
+    },
+
+    /// Copy a BAM file with the scores of each read written as MM and ML
+    /// modification tags, ie to view them in IGV or summarize them with
+    /// modkit
+    Modbam {
+        /// BAM file the scored reads were aligned in
+        #[clap(short, long)]
+        bam: ValidPathBuf,
+
+        /// Path to scored data from cawlr score
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// BAM output
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Modification to write the scores as, with the canonical base, strand,
+        /// and modification code, ie A+a for 6mA or C+m for 5mC. Scores on
+        /// other bases are left out.
+        #[clap(short, long, default_value = "A+a")]
+        tag: String,
+    },
+}
+
+impl ExportCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        match self {
+            ExportCmd::Parquet {
+                input,
+                output_dir,
+                batch_size,
+                tag,
+            } => export_parquet(input, output_dir, batch_size, tag),
+            ExportCmd::Modbam {
+                bam,
+                input,
+                output,
+                tag,
+            } => {
+                let (tagged, skipped) = ModBamExport::new(tag)?.run(bam, input, output)?;
+                log::info!("Wrote modification tags for {tagged} reads");
+                if skipped > 0 {
+                    log::warn!(
+                        "{skipped} scores were left out because they weren't on an aligned base \
+                         of the modification"
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+fn export_parquet(
+    input: ValidPathBuf,
+    output_dir: PathBuf,
+    batch_size: usize,
+    tag: Option<String>,
+) -> eyre::Result<()> {
+    use libcawlr::arrow::{io::ModFile, parquet::ParquetOptions};
+
+    let mod_file = ModFile::open_path(input, tag)?;
+    let paths = ParquetOptions::default()
+        .batch_size(batch_size)
+        .run(mod_file, &output_dir)?;
+    for path in paths {
+        log::info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn export_parquet(
+    _input: ValidPathBuf,
+    _output_dir: PathBuf,
+    _batch_size: usize,
+    _tag: Option<String>,
+) -> eyre::Result<()> {
+    Err(eyre::eyre!(
+        "cawlr was built without Parquet support, rebuild with --features parquet"
+    ))
+}
@@ -0,0 +1,64 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use libcawlr::hash::{content_hash, ContentHash};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct HashCmd {
+    /// Arrow files from cawlr collapse, score, or sma. Prints the hash and
+    /// path of each, one per line, ie to save as golden outputs
+    #[clap(required_unless_present = "check")]
+    pub input: Vec<ValidPathBuf>,
+
+    /// Check the files listed in the output of a previous cawlr hash still
+    /// have the same hashes, failing if any differ
+    #[clap(short, long, conflicts_with = "input")]
+    pub check: Option<ValidPathBuf>,
+}
+
+impl HashCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        if let Some(check) = self.check {
+            return check_hashes(&check);
+        }
+        for input in self.input.iter() {
+            let hash = content_hash(input)?;
+            log::info!("{} reads in {}", hash.n_reads, input.0.display());
+            println!("{hash}  {}", input.0.display());
+        }
+        Ok(())
+    }
+}
+
+fn check_hashes(check: &ValidPathBuf) -> eyre::Result<()> {
+    let file = File::open(check)?;
+    let mut n_failed = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (expected, path) = line
+            .split_once("  ")
+            .ok_or_else(|| eyre::eyre!("Expected a hash and path, found \"{line}\""))?;
+        let expected: ContentHash = expected.parse().map_err(|e: String| eyre::eyre!(e))?;
+        let path = PathBuf::from(path);
+        let hash = content_hash(&path)?;
+        if hash.hash == expected.hash {
+            println!("{}: OK", path.display());
+        } else {
+            println!("{}: FAILED", path.display());
+            n_failed += 1;
+        }
+    }
+    if n_failed > 0 {
+        return Err(eyre::eyre!("{n_failed} files didn't match their hash"));
+    }
+    Ok(())
+}
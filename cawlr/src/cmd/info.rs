@@ -0,0 +1,20 @@
+use std::io;
+
+use clap::Parser;
+use libcawlr::info::ArrowInfo;
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct InfoCmd {
+    /// Arrow output from cawlr collapse, score, or sma
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+}
+
+impl InfoCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let info = ArrowInfo::from_path(&self.input)?;
+        info.write(io::stdout().lock())
+    }
+}
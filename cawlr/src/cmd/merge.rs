@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{arrow::arrow_utils::ArrowCompression, merge::MergeOptions};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct MergeCmd {
+    /// Arrow files to merge, all from either cawlr collapse, score, or sma
+    #[clap(short, long, required = true, num_args = 1..)]
+    pub input: Vec<ValidPathBuf>,
+
+    /// Sample of each input, in the same order. Defaults to the file name
+    /// without its extension, or the sample from an earlier merge. Inputs
+    /// can share a sample, ie for flowcells of the same library.
+    #[clap(short, long, num_args = 1..)]
+    pub sample: Vec<String>,
+
+    /// Path to the merged Arrow file, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Compression of the output, either "lz4", "zstd" for smaller files at
+    /// some CPU cost, or "none"
+    #[clap(long, default_value_t = ArrowCompression::Lz4)]
+    pub compression: ArrowCompression,
+}
+
+impl MergeCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        if !self.sample.is_empty() && self.sample.len() != self.input.len() {
+            return Err(eyre::eyre!(
+                "Got {} samples for {} inputs, give one sample for each input or none",
+                self.sample.len(),
+                self.input.len()
+            ));
+        }
+        let mut opts = MergeOptions::default();
+        opts.compression(self.compression);
+        let samples = self
+            .sample
+            .into_iter()
+            .map(Some)
+            .chain(std::iter::repeat(None));
+        for (input, sample) in self.input.into_iter().zip(samples) {
+            opts.input(input.0, sample);
+        }
+        opts.run(self.output.as_ref())
+    }
+}
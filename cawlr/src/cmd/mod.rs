@@ -1,6 +1,37 @@
+pub mod baseline;
 pub mod collapse;
+pub mod config;
+pub mod diff;
+pub mod doctor;
+pub mod eval;
+pub mod export;
+pub mod hash;
+pub mod info;
+pub mod merge;
+pub mod normalize;
+pub mod pileup;
+pub mod rank;
+pub mod repro;
 pub mod score;
+pub mod split;
+pub mod stats;
+pub mod subsample;
+pub mod track;
 pub mod train;
+pub mod watch;
+
+use clap::Args;
+
+/// Options for commands that load whole Arrow chunks of reads, shared so
+/// each has the same flag
+#[derive(Args, Debug, Default)]
+pub struct BatchArgs {
+    /// Load at most this many reads at a time, splitting larger Arrow
+    /// chunks to lower peak memory on long reads. By default whole chunks
+    /// are loaded
+    #[clap(long)]
+    pub batch_size: Option<usize>,
+}
 
 #[cfg(test)]
 mod test {
@@ -21,8 +52,17 @@ mod test {
             bam: PathBuf::from("../extra/pos_control.bam"),
             output: Some(collapse_output.clone()),
             capacity: 2048,
+            max_memory: None,
+            summary: None,
+            errors: None,
+            chrom_alias: None,
+            primary_only: false,
+            mode: Default::default(),
+            no_samples: false,
+            samples: true,
+            compression: Default::default(),
         };
-        collapse_cmd.run()?;
+        collapse_cmd.run(false)?;
 
         let train_output = temp_dir.join("train_output");
         let train_db_output = temp_dir.join("train_db");
@@ -34,8 +74,16 @@ mod test {
             single: false,
             dbscan: true,
             db_path: Some(train_db_output),
+            num_threads: None,
+            min_kmer_samples: 2,
+            max_sigma: None,
+            min_separation: 0.0,
+            include_region: Vec::new(),
+            exclude_region: Vec::new(),
+            batch: BatchArgs::default(),
+            deterministic: false,
         };
-        train_cmd.run()?;
+        train_cmd.run(false)?;
         Ok(())
     }
 }
@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::normalize::NormalizeOptions;
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct NormalizeCmd {
+    /// Sample the other samples' scores are aligned to, output from cawlr
+    /// score or a BAM file with modification calls
+    #[clap(short, long)]
+    pub reference: ValidPathBuf,
+
+    /// Samples to normalize, outputs from cawlr score or BAM files with
+    /// modification calls. Include the reference to also write it alongside
+    /// the others.
+    #[clap(short, long, required = true, num_args = 1..)]
+    pub input: Vec<ValidPathBuf>,
+
+    /// Directory for the normalized outputs, each named
+    /// {input name}.normalized.arrow, or {input name}.{n}.normalized.arrow
+    /// for the nth input if several inputs have the same name
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Number of evenly spaced quantiles used to map scores onto the
+    /// reference
+    #[clap(long, default_value_t = 1000)]
+    pub n_quantiles: usize,
+
+    /// Bam tag to use for modification detection, only used if the inputs are
+    /// BAM files, ie C+m
+    #[clap(short, long)]
+    pub tag: Option<String>,
+}
+
+impl NormalizeCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = NormalizeOptions::default();
+        opts.n_quantiles(self.n_quantiles).mod_tag(self.tag);
+        let outputs = opts.run(self.reference.as_ref(), &self.input, &self.output_dir)?;
+        for output in outputs {
+            log::info!("Wrote {}", output.display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::pileup::{PileupFormat, PileupOptions};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct PileupCmd {
+    /// Output from cawlr score or a BAM file with modification calls
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// Path to output, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Output format, either tsv or bedmethyl
+    #[clap(long, default_value = "tsv")]
+    pub format: PileupFormat,
+
+    /// Scores greater than the threshold are counted as modified
+    #[clap(long, default_value_t = 0.5)]
+    pub threshold: f64,
+
+    /// Scores within this distance of the threshold are counted as no-calls
+    #[clap(long, default_value_t = 0.0)]
+    pub no_call_margin: f64,
+
+    /// Leave out positions covered by fewer reads
+    #[clap(long, default_value_t = 1)]
+    pub min_coverage: u64,
+
+    /// Bam tag to use for modification detection, only used if the input is a
+    /// BAM file, ie C+m
+    #[clap(short, long)]
+    pub tag: Option<String>,
+
+    /// Previous cawlr pileup output of the same sample to add the counts to,
+    /// ie when more data is sequenced. Inputs listed in its .sources file,
+    /// written next to each output, are refused.
+    #[clap(long)]
+    pub update: Option<ValidPathBuf>,
+
+    /// Count each sample from cawlr merge separately. TSV output gets a
+    /// sample column after the strand, and bedMethyl uses the sample as the
+    /// name.
+    #[clap(long, conflicts_with = "update")]
+    pub split_by_sample: bool,
+
+    /// Also count each haplotype from the HP tags of a phased bam file and
+    /// write it to its own output next to --output, ie pileup.hp1.tsv for
+    /// pileup.tsv
+    #[clap(
+        long,
+        requires = "output",
+        conflicts_with_all = ["update", "split_by_sample"]
+    )]
+    pub split_haplotype: bool,
+}
+
+impl PileupCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = PileupOptions::default();
+        opts.threshold(self.threshold)
+            .no_call_margin(self.no_call_margin)
+            .min_coverage(self.min_coverage)
+            .format(self.format)
+            .update(self.update.map(|p| p.0))
+            .split_by_sample(self.split_by_sample)
+            .split_haplotypes(self.split_haplotype);
+        if let Some(tag) = self.tag.as_ref() {
+            opts.name(tag.as_str());
+        }
+        opts.mod_tag(self.tag);
+        opts.run(self.input.as_ref(), self.output.as_ref())
+    }
+}
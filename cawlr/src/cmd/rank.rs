@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use libcawlr::{
+    rank::{RankExportOptions, RankOptions},
+    train::Model,
+    utils::{self, CawlrIO},
+};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Subcommand, Debug)]
+pub enum RankCmd {
+    /// Rank each kmer by the Kulback-Leibler Divergence between the positive
+    /// and negative control models, for cawlr score
+    Build(RankBuildCmd),
+
+    /// Write the density of the positive and negative control models of each
+    /// kmer over a range of current levels, along with its rank, as a TSV for
+    /// plotting
+    Export(RankExportCmd),
+}
+
+impl RankCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        match self {
+            RankCmd::Build(cmd) => cmd.run(),
+            RankCmd::Export(cmd) => cmd.run(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct RankBuildCmd {
+    /// Positive control output from cawlr train
+    #[clap(long)]
+    pub pos_ctrl: ValidPathBuf,
+
+    /// Negative control output from cawlr train
+    #[clap(long)]
+    pub neg_ctrl: ValidPathBuf,
+
+    /// Path to output file
+    #[clap(short, long)]
+    pub output: PathBuf,
+
+    /// Ranks are estimated via sampling, so to keep values consistent
+    /// between subsequent runs a seed value is used
+    #[clap(long, default_value_t = 2456)]
+    pub seed: u64,
+
+    /// Ranks are estimated via sampling, higher value for samples means it
+    /// takes longer to run but the ranks will be more accurate
+    #[clap(long, default_value_t = 100_000_usize)]
+    pub samples: usize,
+}
+
+impl RankBuildCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let pos_ctrl = Model::load(&self.pos_ctrl)?;
+        let neg_ctrl = Model::load(&self.neg_ctrl)?;
+        let ranks = RankOptions::new(self.seed, self.samples).rank(&pos_ctrl, &neg_ctrl);
+        ranks.save_as(self.output)
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct RankExportCmd {
+    /// Positive control output from cawlr train
+    #[clap(long)]
+    pub pos_ctrl: ValidPathBuf,
+
+    /// Negative control output from cawlr train
+    #[clap(long)]
+    pub neg_ctrl: ValidPathBuf,
+
+    /// TSV output, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Number of evenly spaced current levels to evaluate the densities at
+    #[clap(long, default_value_t = 200)]
+    pub points: usize,
+
+    /// Lowest current level, by default 4 standard deviations below the
+    /// lowest mean of the models
+    #[clap(long, requires = "max")]
+    pub min: Option<f64>,
+
+    /// Highest current level, by default 4 standard deviations above the
+    /// highest mean of the models
+    #[clap(long, requires = "min")]
+    pub max: Option<f64>,
+
+    /// Models are from cawlr npsmlr train
+    #[clap(long)]
+    pub npsmlr: bool,
+
+    /// Ranks are estimated via sampling, so to keep values consistent
+    /// between subsequent runs a seed value is used
+    #[clap(long, default_value_t = 2456)]
+    pub seed: u64,
+
+    /// Ranks are estimated via sampling, higher value for samples means it
+    /// takes longer to run but the ranks will be more accurate
+    #[clap(long, default_value_t = 100_000_usize)]
+    pub samples: usize,
+}
+
+impl RankExportCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let pos_ctrl = Model::load(&self.pos_ctrl)?;
+        let neg_ctrl = Model::load(&self.neg_ctrl)?;
+        let mut opts = RankExportOptions::default();
+        opts.rank_options(RankOptions::new(self.seed, self.samples))
+            .npsmlr(self.npsmlr)
+            .n_points(self.points);
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            opts.range(min, max);
+        }
+        let writer = utils::stdout_or_file(self.output.as_ref())?;
+        opts.run(&pos_ctrl, &neg_ctrl, writer)
+    }
+}
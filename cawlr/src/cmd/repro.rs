@@ -0,0 +1,63 @@
+use std::process::Command;
+
+use clap::Parser;
+use libcawlr::repro::ReproManifest;
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct ReproCmd {
+    /// Manifest saved next to the outputs of a previous run, ie
+    /// ranks.pickle.repro.json, or repro.json in a pipeline's output directory
+    pub manifest: ValidPathBuf,
+
+    /// Print the command and seeds instead of running it
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Replace the outputs of the previous run, from the global --force option
+    #[clap(skip)]
+    pub force: bool,
+}
+
+impl ReproCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let manifest = ReproManifest::load(&self.manifest)?;
+        if !manifest.same_version() {
+            log::warn!(
+                "Manifest was written by cawlr {}, results may differ with version {}",
+                manifest.cawlr_version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        let mut args = manifest.args.iter().skip(1).cloned().collect::<Vec<_>>();
+        if args.is_empty() {
+            return Err(eyre::eyre!("Manifest has no command to rerun"));
+        }
+        if self.force && !args.iter().any(|a| a == "--force") {
+            args.push("--force".to_string());
+        }
+        for seed in manifest.seeds.iter() {
+            log::info!(
+                "{} seed: {}, parameters: {}",
+                seed.stage,
+                seed.seed,
+                seed.params
+            );
+        }
+        if self.dry_run {
+            println!("cd {}", manifest.working_dir.display());
+            println!("cawlr {}", args.join(" "));
+            return Ok(());
+        }
+        log::info!("Running cawlr {}", args.join(" "));
+        let status = Command::new(std::env::current_exe()?)
+            .args(&args)
+            .current_dir(&manifest.working_dir)
+            .status()?;
+        if !status.success() {
+            return Err(eyre::eyre!("Rerun failed with {status}"));
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,48 @@
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{io::BufReader, path::PathBuf, sync::Arc};
 
 use clap::Parser;
-use libcawlr::{motif::Motif, npsmlr};
+use libcawlr::{
+    arrow::arrow_utils::ArrowCompression,
+    input,
+    motif::Motif,
+    npsmlr::{self, Ensemble},
+    progress::ProgressBarSink,
+    utils::create_output,
+};
+
+use super::BatchArgs;
+
+fn parse_ensemble(src: &str) -> Result<Ensemble, String> {
+    match src {
+        "mean" => Ok(Ensemble::Mean),
+        "vote" => Ok(Ensemble::Vote),
+        _ => Err(String::from("Invalid ensemble: either 'mean' or 'vote'")),
+    }
+}
 
 #[derive(Parser, Debug)]
 pub struct ScoreCmd {
-    /// Input arrow file, usually from cawlr collapse
+    /// Input arrow file, usually from cawlr collapse, or a http:// URL to read
+    /// it from remote storage
     #[clap(short, long)]
     input: PathBuf,
 
-    /// Path to positive control model, usually from cawlr train
-    #[clap(short, long)]
-    pos_ctrl: PathBuf,
+    /// Path to positive control model, usually from cawlr train. Can be
+    /// repeated to score with an ensemble of models, ie from replicates, in
+    /// which case each is paired with the --neg-ctrl in the same order.
+    #[clap(short, long, required = true)]
+    pos_ctrl: Vec<PathBuf>,
 
-    /// Path to negative control model, usually from cawlr train
-    #[clap(short, long)]
-    neg_ctrl: PathBuf,
+    /// Path to negative control model, usually from cawlr train. Can be
+    /// repeated, see --pos-ctrl.
+    #[clap(short, long, required = true)]
+    neg_ctrl: Vec<PathBuf>,
+
+    /// How to combine scores when multiple model pairs are given, either
+    /// "mean" to average the log-likelihoods or "vote" for the fraction of
+    /// models calling the position as modified
+    #[clap(long, default_value_t = Ensemble::Mean, value_parser = parse_ensemble)]
+    ensemble: Ensemble,
 
     /// Path to ranks file, usually from cawlr rank
     #[clap(short, long)]
@@ -25,7 +52,8 @@ pub struct ScoreCmd {
     #[clap(short, long)]
     output: PathBuf,
 
-    /// Motifs to score on, at least 1 motif must be provided
+    /// Motifs to score on, at least 1 motif must be provided. IUPAC ambiguity
+    /// codes are allowed, ie "3:DRACH".
     #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
     motif: Vec<Motif>,
 
@@ -37,18 +65,49 @@ pub struct ScoreCmd {
     /// If an events has more than freq_thresh samples, it will be filtered
     #[clap(short, long, default_value_t = 10)]
     freq_thresh: usize,
+
+    /// Also use how often each kmer had signal data in the controls, which
+    /// requires models from cawlr train
+    #[clap(long)]
+    skips: bool,
+
+    /// Also write the reads of each haplotype to their own output next to
+    /// --output, ie scores.hp1.arrow and scores.hp2.arrow for scores.arrow.
+    /// Haplotypes come from the HP tags of the phased bam given to cawlr
+    /// collapse
+    #[clap(long)]
+    split_haplotype: bool,
+
+    /// Compression of the output, either "lz4", "zstd" for smaller files at
+    /// some CPU cost, or "none"
+    #[clap(long, default_value_t = ArrowCompression::Lz4)]
+    compression: ArrowCompression,
+
+    #[clap(flatten)]
+    batch: BatchArgs,
 }
 
 impl ScoreCmd {
-    pub fn run(self) -> eyre::Result<()> {
-        let reader = BufReader::new(File::open(self.input)?);
-        let writer = File::create(self.output)?;
+    /// Draws a progress bar on stderr if progress is set
+    pub fn run(self, progress: bool) -> eyre::Result<()> {
+        let reader = BufReader::new(input::open_input(self.input)?);
+        let writer = create_output(&self.output)?;
         let mut score_options =
-            npsmlr::ScoreOptions::load(self.pos_ctrl, self.neg_ctrl, self.ranks)?;
+            npsmlr::ScoreOptions::load_ensemble(&self.pos_ctrl, &self.neg_ctrl, self.ranks)?;
+        if self.split_haplotype {
+            score_options.split_haplotypes(&self.output);
+        }
+        if progress {
+            score_options.progress_sink(Arc::new(ProgressBarSink::new()?));
+        }
         score_options
+            .ensemble(self.ensemble)
             .freq_thresh(self.freq_thresh)
             .cutoff(self.cutoff)
             .motifs(self.motif)
+            .skips(self.skips)
+            .compression(self.compression)
+            .batch_size(self.batch.batch_size)
             .run(reader, writer)
     }
 }
@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{
+    arrow::arrow_utils::ArrowCompression,
+    split::{CatOptions, SplitBy, SplitOptions},
+};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct SplitCmd {
+    /// Arrow output from cawlr collapse, score, or sma
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// Directory to write shard_000.arrow, shard_001.arrow, ... and
+    /// manifest.json to
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Number of shards, fewer are written if there are fewer reads, or fewer
+    /// chromosomes with --by chrom
+    #[clap(short, long)]
+    pub n_shards: usize,
+
+    /// Either "reads" for about the same number of consecutive reads in each
+    /// shard, or "chrom" to keep each chromosome in one shard
+    #[clap(long, default_value_t = SplitBy::Reads)]
+    pub by: SplitBy,
+
+    /// Compression of the shards, either "lz4", "zstd" for smaller files at
+    /// some CPU cost, or "none"
+    #[clap(long, default_value_t = ArrowCompression::Lz4)]
+    pub compression: ArrowCompression,
+}
+
+impl SplitCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        SplitOptions::new(self.n_shards)
+            .by(self.by)
+            .compression(self.compression)
+            .run(self.input, self.output_dir)?;
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct CatCmd {
+    /// Arrow files to concatenate in order, all from either cawlr collapse,
+    /// score, or sma
+    #[clap(short, long, required = true, num_args = 1..)]
+    pub input: Vec<ValidPathBuf>,
+
+    /// Path to the concatenated Arrow file, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Compression of the output, either "lz4", "zstd" for smaller files at
+    /// some CPU cost, or "none"
+    #[clap(long, default_value_t = ArrowCompression::Lz4)]
+    pub compression: ArrowCompression,
+}
+
+impl CatCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = CatOptions::default();
+        opts.compression(self.compression);
+        for input in self.input {
+            opts.input(input.0);
+        }
+        opts.run(self.output.as_ref())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use std::{io::BufWriter, path::PathBuf};
+
+use clap::Subcommand;
+use libcawlr::{arrow::io::ModFile, quantiles::QuantileOptions, stats::ReadStatsOptions, utils};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Debug, Subcommand)]
+pub enum StatsCmd {
+    /// Number of scored positions, fraction modified, and mean score for each
+    /// read
+    Reads {
+        /// Path to scored data from cawlr score, or a BAM file with
+        /// modification calls
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// Path to tab-separated output file, defaults to stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Scores greater than the threshold are counted as modified
+        #[clap(long, default_value_t = 0.5)]
+        threshold: f64,
+
+        /// Bam tag to use for modification detection, only used if the input
+        /// is a BAM file, ie C+m
+        #[clap(short, long)]
+        tag: Option<String>,
+    },
+
+    /// Quantiles of all scores and a suggested threshold for counting a
+    /// position as modified, computed in a single pass over the input
+    Quantiles {
+        /// Path to scored data from cawlr score, or a BAM file with
+        /// modification calls
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// Path to tab-separated output file, defaults to stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Quantiles to report, between 0 and 1
+        #[clap(
+            short,
+            long,
+            value_delimiter = ',',
+            default_value = "0.01,0.05,0.1,0.25,0.5,0.75,0.9,0.95,0.99"
+        )]
+        quantiles: Vec<f64>,
+
+        /// Keep every score in memory to compute exact quantiles, by default
+        /// quantiles are approximated with a t-digest
+        #[clap(long)]
+        exact: bool,
+
+        /// Bam tag to use for modification detection, only used if the input
+        /// is a BAM file, ie C+m
+        #[clap(short, long)]
+        tag: Option<String>,
+    },
+}
+
+impl StatsCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        match self {
+            StatsCmd::Reads {
+                input,
+                output,
+                threshold,
+                tag,
+            } => {
+                let mod_file = ModFile::open_path(input, tag)?;
+                let writer = BufWriter::new(utils::stdout_or_file(output.as_ref())?);
+                let (n_scored, n_modified) = ReadStatsOptions::default()
+                    .threshold(threshold)
+                    .run(mod_file, writer)?;
+                log::info!("Total scored positions: {n_scored}");
+                log::info!("Total positions modified: {n_modified}");
+            }
+            StatsCmd::Quantiles {
+                input,
+                output,
+                quantiles,
+                exact,
+                tag,
+            } => {
+                let mod_file = ModFile::open_path(input, tag)?;
+                let score_quantiles = QuantileOptions::default()
+                    .quantiles(quantiles)
+                    .exact(exact)
+                    .run(mod_file)?;
+                if let Some(threshold) = score_quantiles.otsu_threshold {
+                    log::info!("Suggested threshold from Otsu's method: {threshold}");
+                }
+                let writer = BufWriter::new(utils::stdout_or_file(output.as_ref())?);
+                score_quantiles.write(writer)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{
+    region::Region,
+    subsample::{SubsampleOptions, SubsampleTarget},
+};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct SubsampleCmd {
+    /// Arrow output from cawlr collapse or cawlr score
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// Path to the subsampled Arrow file
+    #[clap(short, long)]
+    pub output: PathBuf,
+
+    /// Keep each read with this probability
+    #[clap(
+        short,
+        long,
+        required_unless_present = "count",
+        conflicts_with = "count"
+    )]
+    pub fraction: Option<f64>,
+
+    /// Keep this many reads, or every read if there are fewer. Reads are kept
+    /// in the same order as the input.
+    #[clap(short = 'n', long)]
+    pub count: Option<usize>,
+
+    /// Only sample reads overlapping these regions, ie chrI:1000-2000,
+    /// chrI:1000- to the end of the chromosome, or chrI
+    #[clap(short, long, num_args = 1..)]
+    pub region: Vec<Region>,
+
+    /// Keep --count reads from each chromosome, so chromosomes with more reads
+    /// don't dominate the output
+    #[clap(long, requires = "count")]
+    pub per_chrom: bool,
+
+    /// Reads are sampled randomly, so to keep the same reads between
+    /// subsequent runs a seed value is used
+    #[clap(long, default_value_t = 2456)]
+    pub seed: u64,
+}
+
+impl SubsampleCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let target = match (self.fraction, self.count) {
+            (Some(fraction), _) => SubsampleTarget::Fraction(fraction),
+            (None, Some(count)) => SubsampleTarget::Count(count),
+            (None, None) => eyre::bail!("Give either --fraction or --count"),
+        };
+        SubsampleOptions::new(target)
+            .regions(self.region)
+            .per_chrom(self.per_chrom)
+            .seed(self.seed)
+            .run(self.input, self.output)?;
+        Ok(())
+    }
+}
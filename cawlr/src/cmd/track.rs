@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{track::TrackOptions, utils};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct TrackCmd {
+    /// Output from cawlr score or a BAM file with modification calls for the
+    /// fraction of reads modified, or output from cawlr sma for the fraction
+    /// of reads with a nucleosome at each position
+    #[clap(short, long)]
+    pub input: ValidPathBuf,
+
+    /// Path to bedGraph output, defaults to stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Also write a bigWig file, requires --genome for the chromosome sizes
+    #[clap(long, requires = "genome")]
+    pub bigwig: Option<PathBuf>,
+
+    /// Genome fasta with a .fai index
+    #[clap(short, long)]
+    pub genome: Option<ValidPathBuf>,
+
+    /// Scores greater than the threshold are counted as modified
+    #[clap(long, default_value_t = 0.5)]
+    pub threshold: f64,
+
+    /// Leave out positions covered by fewer reads
+    #[clap(long, default_value_t = 1)]
+    pub min_coverage: u64,
+
+    /// Bam tag to use for modification detection, only used if the input is a
+    /// BAM file, ie C+m
+    #[clap(short, long)]
+    pub tag: Option<String>,
+
+    /// Output from cawlr sma is sorted by chromosome and start, lowers memory
+    /// use on large inputs
+    #[clap(long)]
+    pub sorted: bool,
+
+    /// Index the bedGraph output with tabix, requires an --output ending in
+    /// .gz
+    #[clap(long, requires = "output")]
+    pub tabix: bool,
+}
+
+impl TrackCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = TrackOptions::default();
+        opts.threshold(self.threshold)
+            .min_coverage(self.min_coverage)
+            .mod_tag(self.tag)
+            .sorted(self.sorted);
+        if let Some(name) = self.output.as_ref().and_then(|o| o.file_name()) {
+            opts.track_name(name.to_string_lossy());
+        }
+        if let (Some(bigwig), Some(genome)) = (self.bigwig, self.genome) {
+            opts.bigwig(bigwig, genome);
+        }
+        opts.run(self.input.as_ref(), self.output.as_ref())?;
+        if let (true, Some(output)) = (self.tabix, &self.output) {
+            utils::tabix_bed(output, &None)?;
+        }
+        Ok(())
+    }
+}
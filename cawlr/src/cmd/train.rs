@@ -1,9 +1,14 @@
 use std::{fs::File, io::BufReader, path::PathBuf};
 
 use clap::Parser;
+
+use super::BatchArgs;
 use libcawlr::{
+    filter::RegionFilter,
     motif::{all_bases, Motif},
     npsmlr::train::TrainOptions,
+    train::KmerThresholds,
+    utils::create_output,
 };
 
 #[derive(Debug, Parser)]
@@ -37,23 +42,73 @@ pub struct TrainCmd {
     /// time
     #[clap(short, long, value_delimiter = ',')]
     pub motif: Vec<Motif>,
+
+    /// Number of kmers to train at once, by default num cpus. Same as the
+    /// global --threads option
+    #[clap(short = 'j', long)]
+    pub num_threads: Option<usize>,
+
+    /// Fewest values a kmer needs to be in the model
+    #[clap(long, default_value_t = 2)]
+    pub min_kmer_samples: usize,
+
+    /// Leave kmers out of the model if either component has a larger
+    /// sigma, ie kmers dominated by noise
+    #[clap(long)]
+    pub max_sigma: Option<f64>,
+
+    /// Leave kmers out of the model if the means of their components are
+    /// closer than this many sigmas, since near-identical components
+    /// can't separate modified from unmodified signal
+    #[clap(long, default_value_t = 0.0)]
+    pub min_separation: f64,
+
+    /// Only train on reads overlapping these regions, either a BED file or
+    /// a region like "chrI:1000-2000". Can be given more than once
+    #[clap(long)]
+    pub include_region: Vec<String>,
+
+    /// Skip reads overlapping these regions, ie rDNA or the mitochondria,
+    /// either a BED file or a region like "chrM". Can be given more than
+    /// once
+    #[clap(long)]
+    pub exclude_region: Vec<String>,
+
+    #[clap(flatten)]
+    pub batch: BatchArgs,
+
+    /// Sample the training data of each kmer with a fixed seed. Set with the
+    /// global --deterministic option
+    #[clap(skip)]
+    pub deterministic: bool,
 }
 
 impl TrainCmd {
-    pub fn run(mut self) -> eyre::Result<()> {
+    /// Shows how many chunks of reads were loaded on stderr if progress is set
+    pub fn run(mut self, progress: bool) -> eyre::Result<()> {
         log::info!("Train command");
         let reader = BufReader::new(File::open(self.input)?);
-        let writer = File::create(self.output)?;
+        let writer = create_output(self.output)?;
         if self.motif.is_empty() {
             log::info!("No motifs found, will train on all motifs");
             self.motif = all_bases();
         }
+        let thresholds =
+            KmerThresholds::new(self.min_kmer_samples, self.max_sigma, self.min_separation);
         TrainOptions::default()
             .n_samples(self.samples)
+            .thresholds(thresholds)
             .db_path(self.db_path)
             .single(self.single)
             .dbscan(self.dbscan)
             .motifs(self.motif)
+            .regions(RegionFilter::load(
+                &self.include_region,
+                &self.exclude_region,
+            )?)
+            .batch_size(self.batch.batch_size)
+            .progress(progress)
+            .deterministic(self.deterministic)
             .run(reader, writer)?;
         Ok(())
     }
@@ -0,0 +1,90 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use libcawlr::{motif::Motif, npsmlr::ScoreOptions, watch::WatchOptions};
+
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct WatchCmd {
+    /// Directory where nanopolish eventalign writes chunk files, or a stream
+    /// of eventalign output from stdin if not provided
+    #[clap(short, long)]
+    pub input_dir: Option<ValidPathBuf>,
+
+    /// Path to BAM alignment file used in nanopolish eventalign, reread for
+    /// each chunk so it can be updated with newly aligned reads
+    #[clap(short, long)]
+    pub bam: PathBuf,
+
+    /// Path to positive control model, usually from cawlr train. Can be
+    /// repeated to score with an ensemble of models, see cawlr npsmlr score.
+    #[clap(short, long, required = true)]
+    pub pos_ctrl: Vec<PathBuf>,
+
+    /// Path to negative control model, usually from cawlr train
+    #[clap(short, long, required = true)]
+    pub neg_ctrl: Vec<PathBuf>,
+
+    /// Path to ranks file, usually from cawlr rank
+    #[clap(short, long)]
+    pub ranks: PathBuf,
+
+    /// Motifs to score on, ie "2:AT"
+    #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
+    pub motif: Vec<Motif>,
+
+    /// Values less than -cutoff for the positive and negative control will
+    /// be filtered
+    #[clap(short, long, default_value_t = 10.0)]
+    pub cutoff: f64,
+
+    /// If an events has more than freq_thresh samples, it will be filtered
+    #[clap(short, long, default_value_t = 10)]
+    pub freq_thresh: usize,
+
+    /// Directory for the collapsed and scored Arrow file of each chunk, and
+    /// watch.tsv with the number of reads and mean score of each. Watching
+    /// again with the same directory skips chunks that were already scored.
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Only process files in the input directory matching this glob pattern
+    #[clap(long, default_value = "*.eventalign.txt")]
+    pub pattern: String,
+
+    /// Seconds between checking the input directory for new files. Files are
+    /// processed once they haven't changed for this long. For stdin, the
+    /// longest reads are buffered before being processed.
+    #[clap(long, default_value_t = 30)]
+    pub interval: u64,
+
+    /// Stop once no new files have appeared in the input directory for this
+    /// many seconds, otherwise watch until interrupted
+    #[clap(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Number of reads from stdin to process at once
+    #[clap(long, default_value_t = 1000)]
+    pub batch_reads: usize,
+}
+
+impl WatchCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut scoring = ScoreOptions::load_ensemble(&self.pos_ctrl, &self.neg_ctrl, self.ranks)?;
+        scoring
+            .freq_thresh(self.freq_thresh)
+            .cutoff(self.cutoff)
+            .motifs(self.motif);
+        let mut watch = WatchOptions::try_new(scoring, &self.bam, &self.output_dir)?;
+        watch
+            .pattern(&self.pattern)?
+            .interval(Duration::from_secs(self.interval))
+            .idle_timeout(self.idle_timeout.map(Duration::from_secs))
+            .batch_reads(self.batch_reads);
+        match &self.input_dir {
+            Some(dir) => watch.watch_dir(dir),
+            None => watch.watch_stream(io::stdin().lock()),
+        }
+    }
+}
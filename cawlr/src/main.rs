@@ -3,9 +3,10 @@ mod file;
 mod pipeline;
 
 use std::{
-    fs::File,
+    fs::{self, File},
     io::BufReader,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
@@ -15,23 +16,35 @@ use file::ValidPathBuf;
 use human_panic::setup_panic;
 use libcawlr::{
     arrow::{
-        arrow_utils::{load_apply2, load_read_write_arrow},
+        arrow_utils::{self, load_apply2, load_read_write_arrow_with, ArrowCompression},
         eventalign::Eventalign,
         io::ModFile,
+        kmer,
         scored_read::ScoredRead,
     },
     bkde::BinnedKde,
-    filter::FilterOptions,
-    index,
+    cancel,
+    context::GenomeCache,
+    discover::{self, DiscoverOptions},
+    duplex::DuplexSuffixes,
+    filter::{FilterOptions, OvermodOptions, ReadFilter, RegionFilter},
+    index, input,
     motif::{all_bases, Motif},
+    profile::Profiler,
+    progress::{ProgressBarSink, ProgressSink},
     rank::RankOptions,
     region::Region,
-    score::ScoreOptions,
+    repro::{self, ReproManifest},
+    score::{ScoreError, ScoreOptions},
     score_model,
-    sma::SmaOptions,
-    train::{self, Model, Train, TrainStrategy},
-    utils::{self, CawlrIO},
+    score_summary::summary_path,
+    sma::{Rgb, SmaBlockOrder, SmaFormat, SmaOptions, StrandColors},
+    split_clusters,
+    train::{self, KmerThresholds, Model, Train, TrainStrategy},
+    train_test_split::{self, Partition},
+    utils::{self, CawlrIO, FaiIndex},
 };
+use log::LevelFilter;
 #[cfg(feature = "mimalloc")]
 use mimalloc::MiMalloc;
 use pipeline::PipelineCmds;
@@ -40,11 +53,42 @@ use pipeline::PipelineCmds;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+fn parse_palette(src: &str) -> Result<StrandColors, String> {
+    match src {
+        "colorblind" => Ok(StrandColors::colorblind()),
+        "classic" => Ok(StrandColors::classic()),
+        _ => Err(String::from(
+            "Invalid palette: either 'colorblind' or 'classic'",
+        )),
+    }
+}
+
+/// Parse a motif and output path separated by '=', ie "1:CG=cpg.bed"
+fn parse_motif_track(src: &str) -> Result<(Motif, PathBuf), String> {
+    let (motif, path) = src
+        .split_once('=')
+        .ok_or_else(|| format!("Expected MOTIF=PATH, found \"{src}\""))?;
+    let motif = motif.parse::<Motif>().map_err(|e| e.to_string())?;
+    Ok((motif, PathBuf::from(path)))
+}
+
 fn parse_strategy(src: &str) -> Result<TrainStrategy, String> {
     match src {
         "all" => Ok(TrainStrategy::AllSamples),
         "avg" => Ok(TrainStrategy::AvgSample),
-        _ => Err(String::from("Invalid strategy: either 'avg' or 'all'")),
+        "median" => Ok(TrainStrategy::Median),
+        _ => match src.strip_prefix("trimmed") {
+            Some("") => Ok(TrainStrategy::TrimmedMean(0.1)),
+            Some(trim) => match trim.strip_prefix(':').and_then(|t| t.parse::<f32>().ok()) {
+                Some(trim) if (0.0..0.5).contains(&trim) => Ok(TrainStrategy::TrimmedMean(trim)),
+                _ => Err(String::from(
+                    "Invalid trimmed mean: expected 'trimmed:{fraction}' with a fraction between 0 and 0.5",
+                )),
+            },
+            None => Err(String::from(
+                "Invalid strategy: either 'avg', 'all', 'median', or 'trimmed:{fraction}'",
+            )),
+        },
     }
 }
 
@@ -64,6 +108,8 @@ enum QCCmd {
 #[derive(Debug, Subcommand)]
 enum FilterCmd {
     Score {
+        /// Arrow file from cawlr score, or a http:// URL to read it from
+        /// remote storage
         #[clap(short, long)]
         input: PathBuf,
 
@@ -71,11 +117,15 @@ enum FilterCmd {
         #[clap(short, long)]
         output: PathBuf,
 
+        /// Keep reads overlapping these regions, ie chrI:1,000-2,000,
+        /// chrI:1000- to the end of the chromosome, or chrI
         #[clap(short, long, num_args = 1..)]
         region: Vec<Region>,
     },
 
     Eventalign {
+        /// Arrow file from cawlr collapse, or a http:// URL to read it from
+        /// remote storage
         #[clap(short, long)]
         input: PathBuf,
 
@@ -83,8 +133,71 @@ enum FilterCmd {
         #[clap(short, long)]
         output: PathBuf,
 
+        /// Keep reads overlapping these regions, ie chrI:1,000-2,000,
+        /// chrI:1000- to the end of the chromosome, or chrI
         #[clap(short, long, num_args = 1..)]
         region: Vec<Region>,
+
+        #[clap(flatten)]
+        batch: cmd::BatchArgs,
+    },
+
+    /// Remove over-modified reads from a modification bam file, ie before
+    /// running model-scores or sma
+    Overmod {
+        /// Bam file with Mm and Ml tags
+        #[clap(short, long)]
+        input: PathBuf,
+
+        /// Bam file output
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Modification tags to check, separated by commas, ie "A+a" or
+        /// "C+m". Reads over-modified for any tag are removed.
+        #[clap(short = 't', long, required = true, num_args = 1.., value_delimiter = ',')]
+        mod_tag: Vec<String>,
+
+        /// Reads with a fraction of modified bases greater than this are
+        /// removed
+        #[clap(long, default_value_t = 0.9)]
+        threshold: f64,
+
+        /// Reads with fewer bases called for a modification are always kept
+        #[clap(long, default_value_t = 10)]
+        min_bases: usize,
+
+        /// Bases with a modification probability greater than this are
+        /// counted as modified
+        #[clap(long, default_value_t = 0.5)]
+        mod_prob: f64,
+    },
+
+    /// Keep or remove reads by name or mapping quality in Arrow files from
+    /// cawlr collapse, score, or sma, ie chimeric or contaminant reads found
+    /// by other QC tools
+    Reads {
+        /// Arrow file from cawlr collapse, score, or sma
+        #[clap(short, long)]
+        input: PathBuf,
+
+        /// Arrow file output
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Text file with a read name at the start of each line, optionally
+        /// gzip compressed. Only these reads are kept, unless --exclude is set
+        #[clap(long, required_unless_present = "min_mapq")]
+        read_names: Option<PathBuf>,
+
+        /// Remove the reads in --read-names instead of keeping them
+        #[clap(long, requires = "read_names")]
+        exclude: bool,
+
+        /// Remove reads with a lower mapping quality, including reads without
+        /// one from Arrow files written by older versions of cawlr
+        #[clap(long)]
+        min_mapq: Option<u8>,
     },
 }
 
@@ -104,6 +217,52 @@ struct Args {
     #[clap(flatten)]
     verbose: Verbosity,
 
+    /// Number of threads for every subcommand, overrides per-command options
+    /// like -j. By default uses the per-command option if there is one,
+    /// otherwise the number of logical cores.
+    #[clap(long, global = true)]
+    threads: Option<usize>,
+
+    /// Overwrite existing outputs, by default commands refuse to replace
+    /// existing files. Pipelines also rerun every step, even those that
+    /// completed in a previous run with the same inputs.
+    #[clap(long, global = true, default_value_t = false)]
+    force: bool,
+
+    /// Directory for temporary files, like training databases and sorting
+    /// alignments. Defaults to $CAWLR_TMP_DIR, or the system temporary
+    /// directory. Files are removed when cawlr exits, or on the next run if
+    /// it crashed.
+    #[clap(long, global = true)]
+    tmp_dir: Option<PathBuf>,
+
+    /// Fail instead of building the .fai index of a genome when it's missing
+    #[clap(long, global = true)]
+    no_build_fai: bool,
+
+    /// Time each stage and chunk of reads, writing the spans to this file in
+    /// the Chrome trace format. Open it in chrome://tracing or
+    /// https://ui.perfetto.dev to see where time is spent.
+    #[clap(long, global = true)]
+    profile: Option<PathBuf>,
+
+    /// Hide the progress bars, which are drawn on stderr with an estimated
+    /// time remaining. Bars are never drawn when stderr isn't a terminal.
+    #[clap(long, global = true)]
+    no_progress: bool,
+
+    /// TOML file setting the options of the subcommand, in a table named
+    /// after it, ie [score]. Options given on the command line take
+    /// precedence, write a file to fill in with cawlr config init.
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Make the outputs of a run the same every time, ie for checking them
+    /// with cawlr hash. Stages without a seed option use a fixed seed, reads
+    /// are always written in the order of the input with any --threads.
+    #[clap(long, global = true)]
+    deterministic: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -123,19 +282,92 @@ enum Commands {
     /// Preprocess nanopolish eventalign output
     Collapse(cmd::collapse::CollapseCmd),
 
-    /// Create bed file of the reads in the Arrow file
+    /// Create bed file of the reads in the Arrow or modification BAM file
     ///
     /// Output file will be named {input}.idx.bed
     Index {
-        /// Arrow file from collapse or score
+        /// Arrow file from collapse or score, or a position sorted BAM file
+        /// with modification calls. BAM files without a BAI index are
+        /// indexed, writing {input}.bai.
         #[clap(short, long)]
         input: PathBuf,
     },
 
-    /// Filter Arrow output file based on genomic coordinates
+    /// Summarize an Arrow file from cawlr, ie its record type, number of reads,
+    /// span of each chromosome, and how it was created
+    Info(cmd::info::InfoCmd),
+
+    /// Hash the reads of Arrow files from cawlr, ignoring how they were
+    /// written, to compare runs against golden outputs
+    Hash(cmd::hash::HashCmd),
+
+    /// Filter Arrow output file based on genomic coordinates, or remove
+    /// over-modified reads from a modification bam file
     #[clap(subcommand)]
     Filter(FilterCmd),
 
+    /// Summary statistics of scored data
+    #[clap(subcommand)]
+    Stats(cmd::stats::StatsCmd),
+
+    /// Check that external tools, the genome index, and the temporary
+    /// directory are ready for running the pipelines
+    Doctor(cmd::doctor::DoctorCmd),
+
+    /// Rerun a command from the reproducibility manifest saved next to its
+    /// outputs, with the same arguments and seeds
+    Repro(cmd::repro::ReproCmd),
+
+    /// Config files for setting options with --config
+    #[clap(subcommand)]
+    Config(cmd::config::ConfigCmd),
+
+    /// bedGraph and bigWig tracks of the fraction of reads modified, or with a
+    /// nucleosome, at each position
+    Track(cmd::track::TrackCmd),
+
+    /// Counts of modified, unmodified, and no-call reads at each position and
+    /// strand, as TSV or bedMethyl
+    Pileup(cmd::pileup::PileupCmd),
+
+    /// ROC and precision-recall curves of modification calls against a truth
+    /// set, such as bisulfite data or known motif positions
+    Eval(cmd::eval::EvalCmd),
+
+    /// Align score distributions across samples to a reference sample by
+    /// quantile normalization, ie before comparing or aggregating flowcells
+    Normalize(cmd::normalize::NormalizeCmd),
+
+    /// Combine Arrow files from several samples into one, tagging each read
+    /// with its sample, ie to compare treated and untreated samples with
+    /// pileup --split-by-sample
+    Merge(cmd::merge::MergeCmd),
+
+    /// Differentially modified positions or windows between two samples, as
+    /// a BED file ranked by p-value
+    Diff(cmd::diff::DiffCmd),
+
+    /// Randomly keep a fraction or number of reads from collapse or score
+    /// output, ie for quick parameter sweeps or to balance controls before
+    /// training
+    Subsample(cmd::subsample::SubsampleCmd),
+
+    /// Split collapse, score, or sma output into shards by reads or by
+    /// chromosome, ie to score each shard in its own job of an HPC job array
+    Split(cmd::split::SplitCmd),
+
+    /// Concatenate Arrow files in order, ie the outputs of each shard from
+    /// cawlr split, keeping the sample of each read
+    Cat(cmd::split::CatCmd),
+
+    /// Convert scored data to other formats for downstream analysis
+    #[clap(subcommand)]
+    Export(cmd::export::ExportCmd),
+
+    /// Collapse and score eventalign chunks as they are written during
+    /// sequencing, for real-time quality control
+    Watch(cmd::watch::WatchCmd),
+
     /// For each kmer, train a two-component gaussian mixture model and save
     /// models to a file
     Train {
@@ -151,25 +383,80 @@ enum Commands {
         #[clap(short, long)]
         genome: PathBuf,
 
+        /// Path to the .fai index of the genome, by default the .fai next to
+        /// the genome, which is built if it's missing
+        #[clap(long)]
+        fai_path: Option<PathBuf>,
+
+        /// Genome sequence to keep in memory, either a size in MB for the most
+        /// recently used parts of the genome or "preload" to read the whole
+        /// genome up front
+        #[clap(long, default_value = "256")]
+        genome_cache: GenomeCache,
+
+        /// UCSC style chromosome alias file, each line has tab-separated names
+        /// for the same chromosome. Used if reads and the genome name
+        /// chromosomes differently, ie "1" and "chr1"
+        #[clap(long)]
+        chrom_alias: Option<PathBuf>,
+
         /// Number of samples per kmer to allow
         #[clap(short, long, default_value_t = 50_000)]
         samples: usize,
 
-        /// Number of threads to use for training, by default num cpus
+        /// Number of threads to use for training, by default num cpus. Same as
+        /// the global --threads option
         #[clap(short = 'j', long)]
         num_threads: Option<usize>,
 
         /// Pick what data is used to train models
         ///
         /// Either train on individual samples using "all" or just the average
-        /// using "avg"
+        /// using "avg". "median" and "trimmed:{fraction}", ie "trimmed:0.1",
+        /// reduce each event like "avg" but are less affected by outlier
+        /// samples, such as spikes from the open pore.
         #[clap(long, default_value_t = TrainStrategy::AllSamples, value_parser=parse_strategy)]
         strategy: train::TrainStrategy,
+
+        /// Train on direct RNA reads from cawlr collapse with --mode rna or
+        /// --mode transcriptome, using the 5-mers of RNA pore models and
+        /// reading U as T
+        #[clap(long)]
+        rna: bool,
+
+        /// Fewest values a kmer needs to be in the model
+        #[clap(long, default_value_t = 2)]
+        min_kmer_samples: usize,
+
+        /// Leave kmers out of the model if either component has a larger
+        /// sigma, ie kmers dominated by noise
+        #[clap(long)]
+        max_sigma: Option<f64>,
+
+        /// Leave kmers out of the model if the means of their components are
+        /// closer than this many sigmas, since near-identical components
+        /// can't separate modified from unmodified signal
+        #[clap(long, default_value_t = 0.0)]
+        min_separation: f64,
+
+        /// Only train on reads overlapping these regions, either a BED file or
+        /// a region like "chrI:1000-2000". Can be given more than once
+        #[clap(long)]
+        include_region: Vec<String>,
+
+        /// Skip reads overlapping these regions, ie rDNA or the mitochondria,
+        /// either a BED file or a region like "chrM". Can be given more than
+        /// once
+        #[clap(long)]
+        exclude_region: Vec<String>,
+
+        #[clap(flatten)]
+        batch: cmd::BatchArgs,
     },
 
-    /// Rank each kmer by the Kulback-Leibler Divergence and between the trained
-    /// models
-    Rank {
+    /// Find candidate motifs shared by the kmers that differ the most between
+    /// the positive and negative control models
+    DiscoverMotifs {
         /// Positive control output from cawlr train
         #[clap(long)]
         pos_ctrl: ValidPathBuf,
@@ -178,25 +465,47 @@ enum Commands {
         #[clap(long)]
         neg_ctrl: ValidPathBuf,
 
-        /// Path to output file
+        /// Path to output file, tab-separated table of candidate motifs,
+        /// defaults to stdout
         #[clap(short, long)]
-        output: PathBuf,
+        output: Option<PathBuf>,
+
+        /// Number of the highest ranked kmers used to find motifs
+        #[clap(long, default_value_t = 50)]
+        top: usize,
+
+        /// Length of the candidate motifs
+        #[clap(long, default_value_t = 2)]
+        motif_len: usize,
+
+        /// Minimum number of top kmers that must share a motif for it to be
+        /// reported
+        #[clap(long, default_value_t = 3)]
+        min_support: usize,
+
+        /// Models are from cawlr npsmlr train
+        #[clap(long, default_value_t = false)]
+        npsmlr: bool,
 
         /// Ranks are estimated via sampling, so to keep values consistent
         /// between subsequent runs a seed value is used
         #[clap(long, default_value_t = 2456)]
         seed: u64,
 
-        /// Ranks are estimated via sampling, higher value for samples means it
-        /// takes longer for cawlr rank to run but the ranks will be more
-        /// accurate
-        #[clap(long, default_value_t = 100_000_usize)]
+        /// Number of samples used to estimate the rank of each kmer
+        #[clap(long, default_value_t = 10_000_usize)]
         samples: usize,
     },
 
+    /// Rank each kmer by the Kulback-Leibler Divergence between the trained
+    /// models
+    #[clap(subcommand)]
+    Rank(cmd::rank::RankCmd),
+
     /// Score each kmer with likelihood based on positive and negative controls
     Score {
-        /// Path to Apache Arrow file from cawlr collapse
+        /// Path to Apache Arrow file from cawlr collapse, or a http:// URL to
+        /// read it from remote storage
         #[clap(short, long)]
         input: PathBuf,
 
@@ -216,11 +525,29 @@ enum Commands {
         #[clap(short, long)]
         ranks: PathBuf,
 
-        /// Path to fasta file for organisms genome, must have a .fai file from
-        /// samtools faidx
+        /// Path to fasta file for organisms genome. The .fai index next to it
+        /// is built if it's missing. Can be a http:// URL with the .fai next
+        /// to it.
         #[clap(short, long)]
         genome: PathBuf,
 
+        /// Path to the .fai index of a local genome, ie when the genome is in
+        /// a read-only directory without one
+        #[clap(long)]
+        fai_path: Option<PathBuf>,
+
+        /// Genome sequence to keep in memory, either a size in MB for the most
+        /// recently used parts of the genome or "preload" to read the whole
+        /// genome up front
+        #[clap(long, default_value = "256")]
+        genome_cache: GenomeCache,
+
+        /// UCSC style chromosome alias file, each line has tab-separated names
+        /// for the same chromosome. Used if reads and the genome name
+        /// chromosomes differently, ie "1" and "chr1"
+        #[clap(long)]
+        chrom_alias: Option<PathBuf>,
+
         /// Threshold for current value to be considered reasonable
         #[clap(long, default_value_t = 10.0)]
         cutoff: f64,
@@ -229,17 +556,113 @@ enum Commands {
         #[clap(long, default_value_t = 0.05)]
         p_value_threshold: f64,
 
+        /// Also score by how often each kmer had signal data in the controls,
+        /// so positions without signal data get a skip score instead of 0.
+        /// Skip frequencies are unreliable with some basecallers.
+        #[clap(long)]
+        skip_score: bool,
+
+        /// Weight of the skip score when averaged with the signal score, from
+        /// 0 for signal alone to 1 for the skip score alone. Only used with
+        /// --skip-score
+        #[clap(long, default_value_t = 0.5)]
+        skip_weight: f64,
+
         /// Only score in kmers that contain this motif, by default will score
         /// all kmers. Format = "{position of modified base}:{motif}", ie "2:GC"
-        /// if the C in GC is the modified base.
+        /// if the C in GC is the modified base. IUPAC ambiguity codes are
+        /// allowed, ie "2:GCH" for GpC excluding CpG.
         #[clap(short, long)]
         motif: Option<Vec<Motif>>,
+
+        /// Also write the reads of each haplotype to their own output next to
+        /// --output, ie scores.hp1.arrow and scores.hp2.arrow for
+        /// scores.arrow. Haplotypes come from the HP tags of the phased bam
+        /// given to cawlr collapse
+        #[clap(long)]
+        split_haplotype: bool,
+
+        /// Write how each position in --debug-region was scored to a TSV,
+        /// including the surrounding kmers considered, their p-values and
+        /// ranks, and the likelihoods under each control
+        #[clap(long, requires = "debug_region")]
+        debug_tsv: Option<PathBuf>,
+
+        /// Region for --debug-tsv, ie chrI:1000-1100. Keep it small, every
+        /// read over the region adds rows.
+        #[clap(long, requires = "debug_tsv")]
+        debug_region: Option<Region>,
+
+        /// Write a TSV with a row for every scored position, with the kmer
+        /// chosen for the score, its rank, p-value, log-likelihoods under each
+        /// control, and the skip score inputs. Works on the whole input, ie
+        /// to find out why a motif scores poorly without debug logging.
+        #[clap(long)]
+        emit_details: Option<PathBuf>,
+
+        /// Where to write counts of scored and skipped positions per
+        /// chromosome and per motif, with mean scores and the positions
+        /// dropped by --cutoff and --p-value-threshold. Written as a TSV if
+        /// the path ends in .tsv, by default {output}.summary.json
+        #[clap(long)]
+        summary: Option<PathBuf>,
+
+        /// Score each chromosome in its own thread, using the index from cawlr
+        /// index to find its reads if there is one. Output reads are grouped
+        /// by chromosome.
+        #[clap(long, conflicts_with = "debug_tsv")]
+        by_chrom: bool,
+
+        /// Combine the template and complement halves of duplex reads, matched
+        /// by --duplex-suffixes, into one read on the template strand with a
+        /// single score from both measurements at sites they share. Halves
+        /// without their other half are written after the combined reads.
+        #[clap(long)]
+        duplex: bool,
+
+        /// Read name suffixes of the template and complement halves of duplex
+        /// reads, separated by a comma
+        #[clap(long, default_value = "_template,_complement")]
+        duplex_suffixes: DuplexSuffixes,
+
+        /// Score direct RNA reads from cawlr collapse with --mode rna or
+        /// --mode transcriptome, with models from cawlr train --rna. Motifs
+        /// match the RNA and must fit in a 5-mer, ie "3:DRACH" for m6A.
+        #[clap(long)]
+        rna: bool,
+
+        /// Compression of the output, either "lz4", "zstd" for smaller files
+        /// at some CPU cost, or "none"
+        #[clap(long, default_value_t = ArrowCompression::Lz4)]
+        compression: ArrowCompression,
+
+        #[clap(flatten)]
+        batch: cmd::BatchArgs,
     },
+    /// Score without trained controls, by how far the signal is from a
+    /// canonical pore model. Scores are approximate and meant for exploring
+    /// accessibility when there are no matched controls.
+    ScoreBaseline(cmd::baseline::BaselineCmd),
+
     /// Compute kernel density estimate of control score data
     ModelScores {
-        /// Arrow output from cawlr score
-        #[clap(short, long)]
-        input: ValidPathBuf,
+        /// Arrow output from cawlr score, or a BAM, SAM, or CRAM file with
+        /// modification calls. Scores from more than one input, ie several
+        /// control flowcells, are pooled into one estimate.
+        #[clap(short, long, required = true, num_args = 1..)]
+        input: Vec<ValidPathBuf>,
+
+        /// Weight of each --input, in the same order, so each input
+        /// contributes a share of the sampled scores proportional to its
+        /// weight, ie -w 1 2 samples twice as many scores from the second
+        /// input. By default scores are sampled from the pooled inputs.
+        #[clap(
+            short,
+            long,
+            num_args = 1..,
+            conflicts_with_all = ["full", "combine_tags", "split_strand"]
+        )]
+        weight: Vec<f64>,
 
         /// Pickle file containing estimated kernel density estimate values
         #[clap(short, long)]
@@ -250,18 +673,51 @@ enum Commands {
         bins: u32,
 
         /// Number of scores sampled from the input to compute the kernel
-        /// density estimate
+        /// density estimate, sampled uniformly across the whole input
         #[clap(short, long, default_value_t = 10_000)]
         samples: usize,
 
+        /// Use every score instead of sampling, ignores --samples
+        #[clap(long, conflicts_with = "samples")]
+        full: bool,
+
+        /// Scores are sampled randomly, so to keep the estimate consistent
+        /// between subsequent runs a seed value is used
+        #[clap(long, default_value_t = 2456)]
+        seed: u64,
+
+        /// Sample an equal number of scores from each chromosome
+        #[clap(long)]
+        stratify: bool,
+
         /// Bam tag to use for modification detection. This is only used if the
         /// input is a BAM file, usually as input from another tool. This is on
         /// the MM tag in the bam file with typical format such as C+m
         /// for methylation on the top strand. For more information, see
         /// section 1.7 of the Sequence Alignment/Map Optional Fields
         /// Specification link: https://samtools.github.io/hts-specs/SAMtags.pdf
+        ///
+        /// Can be given more than once, ie -t C+m -t C+h for 5mC and 5hmC
+        /// calls. Each tag gets its own output with the tag added before the
+        /// extension, unless --combine-tags is used.
         #[clap(short, long)]
-        tag: Option<String>,
+        tag: Vec<String>,
+
+        /// Pool scores from every --tag into one kernel density estimate
+        /// written to --output
+        #[clap(long)]
+        combine_tags: bool,
+
+        /// Also estimate the scores of reads on each strand, written next to
+        /// --output with plus or minus added before the extension, ie
+        /// pos.plus.pickle and pos.minus.pickle, for cawlr sma --split-strand
+        #[clap(long, conflicts_with = "combine_tags")]
+        split_strand: bool,
+
+        /// Reference genome the reads were aligned to, needed to read CRAM
+        /// input
+        #[clap(long)]
+        reference: Option<ValidPathBuf>,
     },
     /// Infer nucleosome positions on single molecules
     Sma {
@@ -281,10 +737,11 @@ enum Commands {
         #[clap(long)]
         neg_ctrl_scores: ValidPathBuf,
 
-        // /// Only that contain this motif will be used to perform single molecule
-        // /// analysis, by default will use all kmers
-        // #[clap(short, long)]
-        // motif: Option<Vec<Motif>>,
+        /// Only scores that match these motifs will be used to perform single
+        /// molecule analysis, by default will use all scores. IUPAC ambiguity
+        /// codes are allowed, ie "2:GCH" to exclude CpG from GpC footprinting.
+        #[clap(short, long, value_delimiter = ',')]
+        motif: Option<Vec<Motif>>,
         /// Bam tag to use for modification detection. This is only used if the
         /// input is a BAM file, usually as input from another tool. This is on
         /// the MM tag in the bam file with typical format such as C+m
@@ -293,6 +750,134 @@ enum Commands {
         /// Specification link: https://samtools.github.io/hts-specs/SAMtags.pdf
         #[clap(short, long)]
         tag: Option<String>,
+
+        /// Reference genome the reads were aligned to, needed to read CRAM
+        /// input
+        #[clap(long)]
+        reference: Option<ValidPathBuf>,
+
+        /// Color palette for reads on each strand, either 'colorblind' for
+        /// orange (+) and blue (-) or 'classic' for red (+) and blue (-)
+        #[clap(long, default_value = "colorblind", value_parser = parse_palette)]
+        palette: StrandColors,
+
+        /// Color for reads on the (+) strand as R,G,B, overrides the palette
+        #[clap(long)]
+        plus_color: Option<Rgb>,
+
+        /// Color for reads on the (-) strand as R,G,B, overrides the palette
+        #[clap(long)]
+        minus_color: Option<Rgb>,
+
+        /// Output format, either 'bed' for a bed12 file for genome browsers
+        /// or 'arrow' for an Arrow file with every linker and nucleosome block
+        /// of each read
+        #[clap(long, default_value = "bed")]
+        format: SmaFormat,
+
+        /// Order of the blocks of minus strand reads in bed output, either
+        /// 'genome' for genomic order, or 'read' to start from the 5' end of
+        /// the read, with blockStarts relative to chromEnd
+        #[clap(long, default_value = "genome")]
+        block_order: SmaBlockOrder,
+
+        /// Use the score distributions of each strand from cawlr model-scores
+        /// --split-strand for reads on that strand, found next to
+        /// --pos-ctrl-scores and --neg-ctrl-scores
+        #[clap(long)]
+        split_strand: bool,
+
+        /// Also segment reads with only the scores from one motif and write
+        /// them to a separate file, as MOTIF=PATH, ie "2:GCH=gpc.bed". Can be
+        /// given multiple times to get a track for each motif in one pass
+        #[clap(long, value_parser = parse_motif_track)]
+        motif_track: Vec<(Motif, PathBuf)>,
+
+        /// Also write the reads of each haplotype to their own output next to
+        /// --output, ie sma.hp1.bed and sma.hp2.bed for sma.bed. Haplotypes
+        /// come from the HP tags of the phased bam given to cawlr collapse, or
+        /// of the input bam
+        #[clap(long, requires = "output")]
+        split_haplotype: bool,
+
+        /// Also segment each read with its scores shuffled between scored
+        /// positions and write the results here, in the same format. The
+        /// nucleosomes called on shuffled reads give an empirical estimate of
+        /// the false discovery rate, which is logged at the end.
+        #[clap(long)]
+        null_output: Option<PathBuf>,
+
+        /// Scores are shuffled randomly, so to keep the null output consistent
+        /// between subsequent runs a seed value is used
+        #[clap(long, default_value_t = 2456)]
+        seed: u64,
+
+        /// Skip reads with fewer than this many scores matching --motif, the
+        /// number of filtered reads is logged at the end
+        #[clap(long, default_value_t = 0)]
+        min_scores: usize,
+
+        /// Skip reads where fewer than this fraction of positions have a score
+        /// matching --motif, ie 0.1 for at least one every 10 bases
+        #[clap(long, default_value_t = 0.0)]
+        min_score_fraction: f64,
+
+        /// Skip reads aligned to fewer than this many bases
+        #[clap(long, default_value_t = 0)]
+        min_read_length: u64,
+
+        /// Index the bed output with tabix, requires an --output ending in .gz
+        /// and input sorted by position
+        #[clap(long, requires = "output")]
+        tabix: bool,
+
+        /// Number of threads to segment reads with, by default num cpus. Same
+        /// as the global --threads option
+        #[clap(short = 'j', long)]
+        n_threads: Option<usize>,
+    },
+
+    /// Split a bed file from cawlr sma into one bed file per cluster, to load
+    /// each cluster as a separate track in a genome browser
+    SplitClusters {
+        /// Bed file from cawlr sma
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// Tab-separated file of cluster assignments, with the read name in
+        /// the first column and the cluster in the second column
+        #[clap(short, long)]
+        clusters: ValidPathBuf,
+
+        /// Directory to write bed files for each cluster, defaults to the
+        /// directory containing the input
+        #[clap(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Split collapse or score output into training and test sets by genomic
+    /// partition, so models aren't evaluated on reads from the same loci used
+    /// to train them
+    TrainTestSplit {
+        /// Arrow output from cawlr collapse or cawlr score
+        #[clap(short, long)]
+        input: ValidPathBuf,
+
+        /// Arrow output of reads used for training
+        #[clap(long)]
+        train: PathBuf,
+
+        /// Arrow output of held out reads
+        #[clap(long)]
+        test: PathBuf,
+
+        /// Which reads to hold out. "odd-even" holds out even numbered
+        /// chromosomes, ie chr2, and "odd-even:roman" for genomes numbered
+        /// with roman numerals, ie chrII. "chroms:chrII,chrIV" holds out those
+        /// chromosomes, and "regions:chrI:1000-2000,..." holds out reads
+        /// overlapping those regions.
+        #[clap(short, long, default_value = "odd-even")]
+        partition: Partition,
     },
 }
 
@@ -300,28 +885,188 @@ fn main() -> Result<()> {
     setup_panic!();
     jane_eyre::install()?;
 
-    let args = Args::parse();
+    // Options from the config file are added to the arguments, so they are
+    // also saved in reproducibility manifests
+    let raw_args = cmd::config::expand_args(std::env::args().collect())?;
+    let args = Args::parse_from(&raw_args);
     let log_level_filter = args.verbose.log_level_filter();
     env_logger::Builder::new()
         .filter_level(log_level_filter)
         .init();
+    if let Some(config) = &args.config {
+        log::info!("Using options from {}", config.display());
+    }
+
+    if let Some(tmp_dir) = &args.tmp_dir {
+        utils::set_tmp_dir(tmp_dir);
+    }
+    if let Err(e) = utils::clean_stale_tmp() {
+        log::warn!("Failed to remove temporary files from previous runs: {e}");
+    }
+    let mut command = args.command;
+    // Pipelines check their own outputs to decide which steps to rerun, and
+    // show a spinner for each step instead of progress bars
+    let progress = if let Commands::Pipeline(cmd) = &mut command {
+        cmd.force(args.force);
+        utils::allow_overwrite(true);
+        false
+    } else {
+        utils::allow_overwrite(args.force);
+        !args.no_progress
+    };
+    if let Commands::Repro(cmd) = &mut command {
+        cmd.force = args.force;
+    }
+    if args.deterministic {
+        log::info!("Deterministic mode, using fixed seeds");
+        command.deterministic();
+    }
+    if let Some(n_threads) = args.threads {
+        command.override_threads(n_threads);
+    }
+    let repro_manifest = command.repro_manifest();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads.or_else(|| command.threads()).unwrap_or(0))
+        .build()?;
+    log::info!("Using {} threads", pool.current_num_threads());
+    cancel::install_handler();
+    let profiler = args.profile.map(Profiler::start).transpose()?;
+    let build_fai = !args.no_build_fai;
+    let res = pool.install(|| run(command, log_level_filter, build_fai, progress));
+    let invalid_kmers = kmer::take_invalid_kmers();
+    if invalid_kmers > 0 {
+        log::warn!("{invalid_kmers} kmers were too long or not ASCII and were left empty");
+    }
+    let seeds = repro::take_seeds();
+    if let (Ok(()), Some(path), false) = (&res, repro_manifest, seeds.is_empty()) {
+        match ReproManifest::new(raw_args, seeds).and_then(|m| m.save(&path)) {
+            Ok(()) => log::info!("Reproducibility manifest written to {}", path.display()),
+            Err(e) => log::warn!("Failed to write reproducibility manifest: {e}"),
+        }
+    }
+    if let Some(profiler) = profiler {
+        if let Err(e) = profiler.finish() {
+            log::warn!("Failed to write profile: {e}");
+        }
+    }
+    // Library errors keep the cancellation inside their Other variant, so the
+    // flag is checked instead of the error chain
+    if res.is_err() && cancel::is_cancelled() {
+        eprintln!("Cancelled, outputs written so far were finished and can be read");
+        std::process::exit(130);
+    }
+    res
+}
+
+impl Commands {
+    /// Threads requested by the subcommand's own options, if it has any
+    fn threads(&self) -> Option<usize> {
+        match self {
+            Commands::Train { num_threads, .. } => *num_threads,
+            Commands::Npsmlr(NpsmlrCmd::Train(cmd)) => cmd.num_threads,
+            Commands::Sma { n_threads, .. } => *n_threads,
+            Commands::Pipeline(cmd) => Some(cmd.threads()),
+            _ => None,
+        }
+    }
+
+    /// Replace the subcommand's own thread options, ie from --threads
+    fn override_threads(&mut self, n_threads: usize) {
+        match self {
+            Commands::Train { num_threads, .. } => *num_threads = Some(n_threads),
+            Commands::Npsmlr(NpsmlrCmd::Train(cmd)) => cmd.num_threads = Some(n_threads),
+            Commands::Sma {
+                n_threads: sma_threads,
+                ..
+            } => *sma_threads = Some(n_threads),
+            Commands::Pipeline(cmd) => cmd.override_threads(n_threads),
+            _ => (),
+        }
+    }
+
+    /// Use a fixed seed in stages that otherwise sample without one, from the
+    /// global --deterministic option
+    fn deterministic(&mut self) {
+        match self {
+            Commands::Npsmlr(NpsmlrCmd::Train(cmd)) => cmd.deterministic = true,
+            Commands::Pipeline(cmd) => cmd.deterministic(true),
+            _ => (),
+        }
+    }
+
+    /// Where the seeds of the run are saved, next to the main output of
+    /// commands with stochastic stages
+    fn repro_manifest(&self) -> Option<PathBuf> {
+        match self {
+            Commands::Train { output, .. } | Commands::ModelScores { output, .. } => {
+                Some(repro::manifest_path(output))
+            }
+            Commands::Rank(cmd::rank::RankCmd::Build(cmd)) => {
+                Some(repro::manifest_path(&cmd.output))
+            }
+            Commands::Npsmlr(NpsmlrCmd::Train(cmd)) => Some(repro::manifest_path(&cmd.output)),
+            Commands::Subsample(cmd) => Some(repro::manifest_path(&cmd.output)),
+            Commands::DiscoverMotifs { output, .. } | Commands::Sma { output, .. } => {
+                output.as_ref().map(repro::manifest_path)
+            }
+            Commands::Pipeline(cmd) => Some(cmd.output_dir().join("repro.json")),
+            _ => None,
+        }
+    }
+}
 
-    match args.command {
-        Commands::Collapse(cmd) => cmd.run()?,
+/// Report invalid cawlr score options as clap usage errors
+fn score_cli_error(e: ScoreError) -> eyre::Report {
+    let kind = match e {
+        ScoreError::MissingFaiIndex { .. } => ErrorKind::MissingRequiredArgument,
+        ScoreError::MotifTooLong { .. } | ScoreError::InvalidSkipWeight { .. } => {
+            ErrorKind::InvalidValue
+        }
+        _ => return e.into(),
+    };
+    Args::command().error(kind, e).exit()
+}
+
+fn run(
+    command: Commands,
+    log_level_filter: LevelFilter,
+    build_fai: bool,
+    progress: bool,
+) -> Result<()> {
+    // Genome indexes are at the path given or next to the genome, and built
+    // unless --no-build-fai is set
+    let fai_index = |fai_path: Option<PathBuf>| {
+        let mut fai = FaiIndex::default();
+        fai.path(fai_path).build(build_fai);
+        fai
+    };
+    // Progress bars on stderr, unless --no-progress is set
+    let progress_bar = if progress {
+        Some(Arc::new(ProgressBarSink::new()?) as Arc<dyn ProgressSink>)
+    } else {
+        None
+    };
+    match command {
+        Commands::Collapse(cmd) => cmd.run(progress)?,
         Commands::Index { input } => {
-            index::index(input)?;
+            index::index_input(input)?;
         }
+        Commands::Info(cmd) => cmd.run()?,
+        Commands::Hash(cmd) => cmd.run()?,
         Commands::Filter(FilterCmd::Eventalign {
             input,
             output,
             region,
+            batch,
         }) => {
             let filters = FilterOptions::new(region);
-            let reader = File::open(input)?;
-            let writer = File::create(output)?;
-            load_read_write_arrow(reader, writer, |xs: Vec<Eventalign>| {
+            let reader = input::open_input(input)?;
+            let writer = utils::create_output(output)?;
+            let compression = ArrowCompression::default();
+            let filter = |xs: Vec<Eventalign>| -> Result<Vec<Eventalign>> {
                 Ok(xs.into_iter().filter(|x| filters.any_valid(x)).collect())
-            })?;
+            };
+            load_read_write_arrow_with(reader, writer, compression, batch.batch_size, filter)?;
         }
 
         Commands::Filter(FilterCmd::Score {
@@ -330,49 +1075,110 @@ fn main() -> Result<()> {
             region,
         }) => {
             let filters = FilterOptions::new(region);
-            let reader = File::open(input)?;
-            let writer = File::create(output)?;
-            load_read_write_arrow(reader, writer, |xs: Vec<ScoredRead>| {
+            let reader = input::open_input(input)?;
+            let writer = utils::create_output(output)?;
+            arrow_utils::load_read_write_arrow(reader, writer, |xs: Vec<ScoredRead>| {
                 Ok(xs.into_iter().filter(|x| filters.any_valid(x)).collect())
             })?;
         }
 
+        Commands::Filter(FilterCmd::Overmod {
+            input,
+            output,
+            mod_tag,
+            threshold,
+            min_bases,
+            mod_prob,
+        }) => {
+            let (kept, removed) = OvermodOptions::new(mod_tag)
+                .threshold(threshold)
+                .min_bases(min_bases)
+                .mod_prob(mod_prob)
+                .run(input, output)?;
+            log::info!("Kept {kept} reads, removed {removed} over-modified reads");
+        }
+
+        Commands::Filter(FilterCmd::Reads {
+            input,
+            output,
+            read_names,
+            exclude,
+            min_mapq,
+        }) => {
+            let mut filter = ReadFilter::default();
+            if let Some(read_names) = read_names {
+                filter.load_names(read_names)?.remove_names(exclude);
+            }
+            if let Some(min_mapq) = min_mapq {
+                filter.min_mapq(min_mapq);
+            }
+            let (kept, removed) = filter.run(input, output)?;
+            log::info!("Kept {kept} reads, removed {removed} reads");
+        }
+
         Commands::Train {
             input,
             output,
             genome,
+            fai_path,
+            genome_cache,
+            chrom_alias,
             samples,
             strategy,
-            num_threads,
+            num_threads: _,
+            rna,
+            min_kmer_samples,
+            max_sigma,
+            min_separation,
+            include_region,
+            exclude_region,
+            batch,
         } => {
             log::info!("Train command");
-            let mut n_logical_cores = num_cpus::get();
-
-            if let Some(n) = num_threads {
-                rayon::ThreadPoolBuilder::new()
-                    .num_threads(n)
-                    .build_global()?;
-                n_logical_cores = n;
-            }
-
-            log::info!("Using {n_logical_cores} logical cores");
             log::info!("Using strategy: {strategy}");
-            let train = Train::try_new(input, genome, samples, strategy)?;
+            let thresholds = KmerThresholds::new(min_kmer_samples, max_sigma, min_separation);
+            let mut train =
+                Train::try_new_with_fai(input, genome, &fai_index(fai_path), samples, strategy)?;
+            train
+                .rna(rna)
+                .thresholds(thresholds)
+                .regions(RegionFilter::load(&include_region, &exclude_region)?)
+                .batch_size(batch.batch_size)
+                .genome_cache(genome_cache)?;
+            if let Some(chrom_alias) = chrom_alias {
+                train.chrom_alias(utils::ChromAlias::from_path(chrom_alias)?);
+            }
+            if let Some(progress_bar) = progress_bar {
+                train.progress_sink(progress_bar);
+            }
             let model = train.run()?;
             model.save_as(output)?;
         }
 
-        Commands::Rank {
+        Commands::Rank(cmd) => cmd.run()?,
+
+        Commands::DiscoverMotifs {
             pos_ctrl,
             neg_ctrl,
             output,
+            top,
+            motif_len,
+            min_support,
+            npsmlr,
             seed,
             samples,
         } => {
             let pos_ctrl_db = Model::load(pos_ctrl)?;
             let neg_ctrl_db = Model::load(neg_ctrl)?;
-            let kmer_ranks = RankOptions::new(seed, samples).rank(&pos_ctrl_db, &neg_ctrl_db);
-            kmer_ranks.save_as(output)?;
+            let candidates = DiscoverOptions::default()
+                .rank_options(RankOptions::new(seed, samples))
+                .npsmlr(npsmlr)
+                .top(top)
+                .motif_len(motif_len)
+                .min_support(min_support)
+                .run(&pos_ctrl_db, &neg_ctrl_db)?;
+            let writer = utils::stdout_or_file(output.as_ref())?;
+            discover::write_candidates(writer, &candidates)?;
         }
 
         Commands::Score {
@@ -382,58 +1188,150 @@ fn main() -> Result<()> {
             neg_ctrl,
             ranks,
             genome,
+            fai_path,
+            genome_cache,
+            chrom_alias,
             cutoff,
             p_value_threshold,
+            skip_score,
+            skip_weight,
             motif,
+            split_haplotype,
+            debug_tsv,
+            debug_region,
+            emit_details,
+            summary,
+            by_chrom,
+            duplex,
+            duplex_suffixes,
+            rna,
+            compression,
+            batch,
         } => {
-            let fai_file = format!("{}.fai", genome.display());
-            let fai_file = Path::new(&fai_file);
-            log::debug!("fasta index file filename: {fai_file:?}");
-            if !fai_file.exists() {
-                let mut cmd = Args::command();
-                cmd.error(
-                    ErrorKind::MissingRequiredArgument,
-                    "Missing .fai index file, run samtools faidx on genome file.",
-                )
-                .exit();
-            }
-
-            motif.iter().for_each(|ms| {
-                ms.iter().for_each(|m| {
-                    if m.len_motif() > 6 {
-                        let mut cmd = Args::command();
-                        cmd.error(
-                            ErrorKind::InvalidValue,
-                            "Length of motif must be less than 6 (size of kmer)",
-                        )
-                        .exit();
-                    }
-                })
-            });
-
             log::debug!("Motifs parsed: {motif:?}");
-            let mut scoring =
-                ScoreOptions::try_new(&pos_ctrl, &neg_ctrl, &genome, &ranks, &output)?;
-            scoring.cutoff(cutoff).p_value_threshold(p_value_threshold);
+            let mut scoring = ScoreOptions::try_new_with_fai(
+                &pos_ctrl,
+                &neg_ctrl,
+                &genome,
+                &fai_index(fai_path),
+                &ranks,
+                &output,
+            )
+            .map_err(score_cli_error)?;
+            scoring
+                .cutoff(cutoff)
+                .p_value_threshold(p_value_threshold)
+                .skip_score(skip_score)
+                .by_chrom(by_chrom)
+                .compression(compression)
+                .batch_size(batch.batch_size)
+                .genome_cache(genome_cache)?;
+            scoring.skip_weight(skip_weight).map_err(score_cli_error)?;
+            if let Some(chrom_alias) = chrom_alias {
+                scoring.chrom_alias(utils::ChromAlias::from_path(chrom_alias)?);
+            }
             if let Some(motifs) = motif {
-                scoring.motifs(motifs);
+                scoring.motifs(motifs).map_err(score_cli_error)?;
+            }
+            scoring.rna(rna).map_err(score_cli_error)?;
+            scoring.split_haplotypes(split_haplotype);
+            if let (Some(debug_tsv), Some(debug_region)) = (debug_tsv, debug_region) {
+                let debug_region = debug_region.resolve_with_genome(&genome)?;
+                scoring.debug_tsv(debug_region, debug_tsv)?;
+            }
+            if let Some(emit_details) = emit_details {
+                scoring.emit_details(emit_details)?;
+            }
+            scoring.summary(summary.unwrap_or_else(|| summary_path(&output)))?;
+            if duplex {
+                scoring.duplex(duplex_suffixes);
+            }
+            if let Some(progress_bar) = progress_bar {
+                scoring.progress_sink(progress_bar);
             }
             scoring.run(input)?;
         }
 
         Commands::ModelScores {
             input,
+            weight,
             output,
             bins,
             samples,
+            full,
+            seed,
+            stratify,
             tag,
+            combine_tags,
+            split_strand,
+            reference,
         } => {
-            let mod_file = ModFile::open_path(input, tag)?;
-            let bkde = score_model::Options::default()
-                .bins(bins)
+            if !weight.is_empty() && weight.len() != input.len() {
+                eyre::bail!(
+                    "Found {} weights for {} inputs, give one weight per input",
+                    weight.len(),
+                    input.len()
+                );
+            }
+            let mut opts = score_model::Options::default();
+            opts.bins(bins)
                 .samples(samples)
-                .run_modfile(mod_file)?;
-            bkde.save_as(output)?;
+                .seed(seed)
+                .full(full)
+                .stratify(stratify);
+            if let Some(progress_bar) = progress_bar {
+                opts.progress_sink(progress_bar);
+            }
+            let mut run = |mod_files: Vec<ModFile>, output: &Path| -> Result<()> {
+                if split_strand {
+                    opts.run_modfiles_split_strand(mod_files)?.save_as(output)
+                } else if !weight.is_empty() {
+                    let inputs = mod_files.into_iter().zip(weight.iter().copied()).collect();
+                    opts.run_weighted(inputs)?.save_as(output)
+                } else {
+                    opts.run_modfiles(mod_files)?.save_as(output)
+                }
+            };
+            let open = |t: Option<&str>| {
+                input
+                    .iter()
+                    .map(|i| {
+                        ModFile::open_path_with_reference(
+                            i,
+                            t,
+                            reference.as_ref(),
+                            &fai_index(None),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()
+            };
+            if tag.len() <= 1 {
+                run(open(tag.first().map(String::as_str))?, &output)?;
+            } else {
+                let mod_files = tag
+                    .iter()
+                    .map(|t| open(Some(t.as_str())))
+                    .collect::<Result<Vec<_>>>()?;
+                if mod_files
+                    .iter()
+                    .flatten()
+                    .any(|m| matches!(m, ModFile::Arrow(_)))
+                {
+                    eyre::bail!(
+                        "Multiple --tag values are only supported for BAM, SAM, or CRAM input"
+                    );
+                }
+                if combine_tags {
+                    opts.run_modfiles(mod_files.into_iter().flatten().collect())?
+                        .save_as(output)?;
+                } else {
+                    for (t, mod_files) in tag.iter().zip(mod_files) {
+                        let tag_output = score_model::tag_output_path(&output, t);
+                        log::info!("Writing {t} scores to {}", tag_output.display());
+                        run(mod_files, &tag_output)?;
+                    }
+                }
+            }
         }
 
         Commands::Sma {
@@ -441,16 +1339,70 @@ fn main() -> Result<()> {
             output,
             pos_ctrl_scores,
             neg_ctrl_scores,
-            // motif,
+            motif,
             tag,
+            reference,
+            mut palette,
+            plus_color,
+            minus_color,
+            format,
+            block_order,
+            split_strand,
+            motif_track,
+            split_haplotype,
+            null_output,
+            seed,
+            min_scores,
+            min_score_fraction,
+            min_read_length,
+            tabix,
+            n_threads: _,
         } => {
-            let mod_file = ModFile::open_path(input, tag)?;
-            let pos_bkde = BinnedKde::load(pos_ctrl_scores)?;
-            let neg_bkde = BinnedKde::load(neg_ctrl_scores)?;
+            let mod_file = ModFile::open_path_with_reference(
+                input,
+                tag,
+                reference.as_ref(),
+                &fai_index(None),
+            )?;
+            let pos_bkde = BinnedKde::load(&pos_ctrl_scores)?;
+            let neg_bkde = BinnedKde::load(&neg_ctrl_scores)?;
             let writer = utils::stdout_or_file(output.as_ref())?;
-            let motifs = all_bases();
+            let motifs = motif.unwrap_or_else(all_bases);
+            if let Some(plus_color) = plus_color {
+                palette.plus = plus_color;
+            }
+            if let Some(minus_color) = minus_color {
+                palette.minus = minus_color;
+            }
             let mut sma = SmaOptions::new(pos_bkde, neg_bkde, motifs, writer);
-            if let Some(output_filename) = output {
+            sma.strand_colors(palette)
+                .format(format)
+                .block_order(block_order)
+                .min_scores(min_scores)
+                .min_score_fraction(min_score_fraction)
+                .min_read_length(min_read_length);
+            if let Some(progress_bar) = progress_bar {
+                sma.progress_sink(progress_bar);
+            }
+            if split_strand {
+                sma.load_strand_bkdes(&pos_ctrl_scores, &neg_ctrl_scores)?;
+            }
+            for (track_motif, track_output) in motif_track {
+                let track_name = track_output
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .ok_or_else(|| eyre::eyre!("Not a filename"))?
+                    .to_string();
+                let writer = utils::stdout_or_file(Some(&track_output))?;
+                sma.motif_track(track_name, vec![track_motif], writer);
+            }
+            if let Some(null_output) = null_output {
+                sma.null_model(utils::stdout_or_file(Some(&null_output))?, seed);
+            }
+            if let (true, Some(output)) = (split_haplotype, &output) {
+                sma.split_haplotypes(output);
+            }
+            if let Some(output_filename) = &output {
                 let track_name = output_filename
                     .file_name()
                     .ok_or_else(|| eyre::eyre!("Not a filename"))?
@@ -459,6 +1411,40 @@ fn main() -> Result<()> {
                 sma.track_name(track_name);
             }
             sma.run_modfile(mod_file)?;
+            if let (true, Some(output)) = (tabix, &output) {
+                utils::tabix_bed(output, &None)?;
+            }
+        }
+        Commands::SplitClusters {
+            input,
+            clusters,
+            output_dir,
+        } => {
+            if let Some(output_dir) = &output_dir {
+                fs::create_dir_all(output_dir)?;
+            }
+            let outputs =
+                split_clusters::run(input.as_ref(), clusters.as_ref(), output_dir.as_deref())?;
+            for (cluster, path) in outputs {
+                println!("{cluster}\t{}", path.display());
+            }
+        }
+        Commands::TrainTestSplit {
+            input,
+            train,
+            test,
+            partition,
+        } => {
+            let kind = arrow_utils::arrow_type(&mut File::open(&input)?)?;
+            match kind.as_str() {
+                "eventalign" => train_test_split::split_files::<Eventalign, _, _, _>(
+                    input, train, test, &partition,
+                )?,
+                "scored" => train_test_split::split_files::<ScoredRead, _, _, _>(
+                    input, train, test, &partition,
+                )?,
+                _ => eyre::bail!("Expected output from cawlr collapse or score, found {kind}"),
+            };
         }
         Commands::QC(cmd) => match cmd {
             QCCmd::Score { input } => {
@@ -472,10 +1458,26 @@ fn main() -> Result<()> {
         },
 
         Commands::Npsmlr(cmd) => match cmd {
-            NpsmlrCmd::Train(cmd) => cmd.run()?,
-            NpsmlrCmd::Score(cmd) => cmd.run()?,
+            NpsmlrCmd::Train(cmd) => cmd.run(progress)?,
+            NpsmlrCmd::Score(cmd) => cmd.run(progress)?,
         },
         Commands::Pipeline(plcmd) => plcmd.run(log_level_filter)?,
+        Commands::Stats(cmd) => cmd.run()?,
+        Commands::Doctor(cmd) => cmd.run()?,
+        Commands::Repro(cmd) => cmd.run()?,
+        Commands::Config(cmd) => cmd.run()?,
+        Commands::Track(cmd) => cmd.run()?,
+        Commands::ScoreBaseline(cmd) => cmd.run()?,
+        Commands::Pileup(cmd) => cmd.run()?,
+        Commands::Export(cmd) => cmd.run()?,
+        Commands::Eval(cmd) => cmd.run()?,
+        Commands::Normalize(cmd) => cmd.run()?,
+        Commands::Merge(cmd) => cmd.run()?,
+        Commands::Diff(cmd) => cmd.run()?,
+        Commands::Subsample(cmd) => cmd.run()?,
+        Commands::Split(cmd) => cmd.run()?,
+        Commands::Cat(cmd) => cmd.run()?,
+        Commands::Watch(cmd) => cmd.run()?,
     }
     Ok(())
 }
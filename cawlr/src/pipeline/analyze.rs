@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{
+    motif::Motif,
+    pipeline::{AnalyzeInput, AnalyzeOptions, CtrlModels},
+    region::Region,
+};
+use log::LevelFilter;
+
+use super::report_plan;
+use crate::file::ValidPathBuf;
+
+#[derive(Debug, Parser)]
+pub struct AnalyzeCmd {
+    /// Region of interested {chromosome}:{start}-{stop}, ie chrI:10,000-20,000.
+    /// With --genome the end can be left out to go to the end of the
+    /// chromosome, ie chrI:10000- or chrI, and the locus is checked against the
+    /// .fai index.
+    #[clap(short, long)]
+    pub locus: Region,
+
+    /// Where to output results
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Path to bam file to filter on the locus
+    #[clap(short, long, required_unless_present_any = ["from_collapse", "from_score"])]
+    pub bam: Option<ValidPathBuf>,
+
+    /// Path to full fastq, doesn't need to be filtered
+    #[clap(long, required_unless_present_any = ["from_collapse", "from_score"])]
+    pub reads: Option<ValidPathBuf>,
+
+    /// Path to genome
+    #[clap(short, long, required_unless_present_any = ["from_collapse", "from_score"])]
+    pub genome: Option<ValidPathBuf>,
+
+    /// Existing output of cawlr collapse, ie for the whole genome, to filter
+    /// to the locus instead of running samtools and nanopolish
+    #[clap(long, conflicts_with_all = ["bam", "reads", "genome", "from_score"])]
+    pub from_collapse: Option<ValidPathBuf>,
+
+    /// Existing output of cawlr score to filter to the locus, skipping
+    /// samtools, nanopolish, and scoring
+    #[clap(long, conflicts_with_all = ["bam", "reads", "genome"])]
+    pub from_score: Option<ValidPathBuf>,
+
+    /// Path to postive control model, from cawlr train
+    #[clap(long)]
+    pub pos_model: ValidPathBuf,
+
+    /// Path to postive control scores, from cawlr model-scores
+    #[clap(long)]
+    pub pos_scores: ValidPathBuf,
+
+    /// Path to negative control model, from cawlr train
+    #[clap(long)]
+    pub neg_model: ValidPathBuf,
+
+    /// Path to negative control scores, from cawlr model-scores
+    #[clap(long)]
+    pub neg_scores: ValidPathBuf,
+
+    /// Path to ranks file, from cawlr ranks
+    #[clap(long)]
+    pub ranks: ValidPathBuf,
+
+    /// Number of clusters to use for clustering script
+    #[clap(long, default_value_t = 3)]
+    pub n_clusters: usize,
+
+    /// Percent of read that should overlap region to be clustered
+    #[clap(long)]
+    pub pct: f64,
+
+    /// Motifs of modification to filter on, separated by commas, format is
+    /// "{position}:{motif}" ie for GpC and CpG motif , motif is "2:GC,1:CG"
+    #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
+    pub motifs: Vec<Motif>,
+
+    /// Regions to highlight during clustering
+    #[clap(long)]
+    pub highlights: Vec<String>,
+
+    /// Path to nanopolish binary, if not specified will look in $PATH
+    #[clap(long)]
+    pub nanopolish_path: Option<PathBuf>,
+
+    /// Path to samtools binary, if not specified will look in $PATH
+    #[clap(long)]
+    pub samtools_path: Option<PathBuf>,
+
+    /// Keep the existing output directory instead of removing it, steps that
+    /// completed in a previous run with the same inputs will be skipped
+    #[clap(long, default_value_t = false)]
+    pub no_overwrite: bool,
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs. Set with the global --force option
+    #[clap(skip)]
+    pub force: bool,
+
+    #[clap(short = 'j', long, default_value_t = 4)]
+    pub n_threads: usize,
+
+    /// Write a JSON line for each step of the pipeline to log.jsonl in the
+    /// output directory
+    #[clap(long, default_value_t = false)]
+    pub json_log: bool,
+
+    /// Print every step and command with resolved paths and check that the
+    /// required binaries and input files exist, without running anything
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+impl AnalyzeCmd {
+    pub fn run(self, log_level_filter: LevelFilter) -> eyre::Result<()> {
+        let ctrls = CtrlModels::new(
+            self.pos_model.0,
+            self.neg_model.0,
+            self.ranks.0,
+            self.pos_scores.0,
+            self.neg_scores.0,
+        );
+        let locus = match &self.genome {
+            Some(genome) => self.locus.resolve_with_genome(genome)?,
+            None => self.locus,
+        };
+        if locus.is_open_ended() {
+            return Err(eyre::eyre!(
+                "Locus {locus} has no end, give one or a --genome with a .fai index"
+            ));
+        }
+        let input = match (self.from_collapse, self.from_score) {
+            (Some(collapse), _) => AnalyzeInput::Collapse(collapse.0),
+            (_, Some(score)) => AnalyzeInput::Score(score.0),
+            // clap requires these unless one of the above is given
+            (None, None) => AnalyzeInput::Alignments {
+                bam: self.bam.unwrap().0,
+                reads: self.reads.unwrap().0,
+                genome: self.genome.unwrap().0,
+            },
+        };
+        let mut opts =
+            AnalyzeOptions::with_input(locus, self.output_dir, input, ctrls, self.motifs);
+        opts.n_clusters(self.n_clusters)
+            .pct(self.pct)
+            .highlights(self.highlights)
+            .nanopolish_path(self.nanopolish_path)
+            .samtools_path(self.samtools_path)
+            .overwrite(!self.no_overwrite)
+            .force(self.force)
+            .n_threads(self.n_threads)
+            .json_log(self.json_log)
+            .log_level(log_level_filter);
+        if self.dry_run {
+            report_plan(opts.dry_run()?)
+        } else {
+            opts.run()
+        }
+    }
+}
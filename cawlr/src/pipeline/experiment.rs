@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{
+    motif::Motif,
+    pipeline::{CtrlModels, ExperimentOptions, Manifest},
+    region::Region,
+};
+
+use super::report_plan;
+use crate::file::ValidPathBuf;
+
+#[derive(Debug, Parser)]
+pub struct ExperimentCmd {
+    /// Tab-separated file listing each sample, with the columns sample,
+    /// condition, reads, fast5, and optionally summary. Relative paths are
+    /// relative to the directory containing the manifest.
+    #[clap(short, long)]
+    pub manifest: ValidPathBuf,
+
+    /// Path to genome fasta file
+    #[clap(short, long)]
+    pub genome: ValidPathBuf,
+
+    /// Output directory of cawlr pipeline train-ctrls
+    #[clap(long)]
+    pub ctrls: ValidPathBuf,
+
+    /// Region of interested {chromosome}:{start}-{stop}, ie chrI:10,000-20,000.
+    /// The end can be left out to go to the end of the chromosome, ie
+    /// chrI:10000- or chrI.
+    #[clap(short, long)]
+    pub locus: Region,
+
+    /// Where to output results, each sample is written to its own directory
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Motifs of modification to filter on, separated by commas, format is
+    /// "{position}:{motif}" ie for GpC and CpG motif , motif is "2:GC,1:CG"
+    #[clap(long, required=true, num_args=1.., value_delimiter=',')]
+    pub motifs: Vec<Motif>,
+
+    /// Number of clusters to use for clustering script
+    #[clap(long, default_value_t = 3)]
+    pub n_clusters: usize,
+
+    /// Percent of read that should overlap region to be clustered
+    #[clap(long, default_value_t = 0.9)]
+    pub pct: f64,
+
+    /// Path to minimap2 binary, if not specified will look in $PATH
+    #[clap(long)]
+    pub minimap2_path: Option<PathBuf>,
+
+    /// Path to nanopolish binary, if not specified will look in $PATH
+    #[clap(long)]
+    pub nanopolish_path: Option<PathBuf>,
+
+    /// Path to samtools binary, if not specified will look in $PATH
+    #[clap(long)]
+    pub samtools_path: Option<PathBuf>,
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs. Set with the global --force option
+    #[clap(skip)]
+    pub force: bool,
+
+    #[clap(short = 'j', long, default_value_t = 4)]
+    pub n_threads: usize,
+
+    /// Write a JSON line for each step of the pipeline to log.jsonl in the
+    /// output directory of each sample
+    #[clap(long, default_value_t = false)]
+    pub json_log: bool,
+
+    /// Print every step and command with resolved paths and check that the
+    /// required binaries and input files exist, without running anything
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+impl ExperimentCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let manifest = Manifest::from_path(&self.manifest)?;
+        let ctrls = CtrlModels::from_train_ctrls_dir(&self.ctrls);
+        let locus = self.locus.resolve_with_genome(&self.genome)?;
+        if locus.is_open_ended() {
+            return Err(eyre::eyre!(
+                "Locus {locus} has no end, give one or index the genome with samtools faidx"
+            ));
+        }
+        let mut opts = ExperimentOptions::new(
+            manifest,
+            self.genome.0,
+            ctrls,
+            locus,
+            self.output_dir,
+            self.motifs,
+        );
+        opts.n_clusters(self.n_clusters)
+            .pct(self.pct)
+            .minimap2_path(self.minimap2_path)
+            .nanopolish_path(self.nanopolish_path)
+            .samtools_path(self.samtools_path)
+            .force(self.force)
+            .n_threads(self.n_threads)
+            .json_log(self.json_log);
+        if self.dry_run {
+            return report_plan(opts.dry_run()?);
+        }
+        let comparison = opts.run()?;
+        log::info!("Comparison of samples written to {}", comparison.display());
+        Ok(())
+    }
+}
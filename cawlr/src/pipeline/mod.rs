@@ -1,13 +1,19 @@
 mod analyze;
-mod external;
+mod experiment;
 mod preprocess;
+mod sma_genome;
 mod train_ctrls;
-mod utils;
+
+use std::path::Path;
 
 use clap::Subcommand;
+use libcawlr::pipeline::Plan;
 use log::LevelFilter;
 
-use self::{analyze::AnalyzeCmd, preprocess::PreprocessCmd, train_ctrls::TrainCtrlPipelineCmd};
+use self::{
+    analyze::AnalyzeCmd, experiment::ExperimentCmd, preprocess::PreprocessCmd,
+    sma_genome::SmaGenomeCmd, train_ctrls::TrainCtrlPipelineCmd,
+};
 
 #[derive(Subcommand, Debug)]
 pub enum PipelineCmds {
@@ -21,14 +27,90 @@ pub enum PipelineCmds {
     /// for visualizing nucleosomes on single molecules, and clustering of
     /// nucleosome density
     AnalyzeRegion(AnalyzeCmd),
+
+    /// Preprocess and analyze every sample listed in a manifest, then compare
+    /// nucleosome occupancy across samples and conditions
+    Experiment(ExperimentCmd),
+
+    /// Score, segment, and aggregate nucleosomes across the whole genome,
+    /// producing single molecule .bed files and occupancy tracks
+    SmaGenome(SmaGenomeCmd),
 }
 
 impl PipelineCmds {
     pub fn run(self, log_level_filter: LevelFilter) -> eyre::Result<()> {
         match self {
-            PipelineCmds::AnalyzeRegion(args) => analyze::run(args, log_level_filter),
+            PipelineCmds::AnalyzeRegion(args) => args.run(log_level_filter),
             PipelineCmds::PreprocessSample(cmd) => cmd.run(),
-            PipelineCmds::TrainCtrls(cmd) => train_ctrls::run(cmd),
+            PipelineCmds::TrainCtrls(cmd) => cmd.run(),
+            PipelineCmds::Experiment(cmd) => cmd.run(),
+            PipelineCmds::SmaGenome(cmd) => cmd.run(log_level_filter),
+        }
+    }
+
+    /// Threads given with -j/--n-threads
+    pub fn threads(&self) -> usize {
+        match self {
+            PipelineCmds::AnalyzeRegion(cmd) => cmd.n_threads,
+            PipelineCmds::PreprocessSample(cmd) => cmd.n_threads,
+            PipelineCmds::TrainCtrls(cmd) => cmd.n_threads,
+            PipelineCmds::Experiment(cmd) => cmd.n_threads,
+            PipelineCmds::SmaGenome(cmd) => cmd.n_threads,
         }
     }
+
+    /// Rerun every step, from the global --force option
+    pub fn force(&mut self, force: bool) {
+        match self {
+            PipelineCmds::AnalyzeRegion(cmd) => cmd.force = force,
+            PipelineCmds::PreprocessSample(cmd) => cmd.force = force,
+            PipelineCmds::TrainCtrls(cmd) => cmd.force = force,
+            PipelineCmds::Experiment(cmd) => cmd.force = force,
+            PipelineCmds::SmaGenome(cmd) => cmd.force = force,
+        }
+    }
+
+    /// Train with fixed seeds, from the global --deterministic option
+    pub fn deterministic(&mut self, deterministic: bool) {
+        if let PipelineCmds::TrainCtrls(cmd) = self {
+            cmd.deterministic = deterministic;
+        }
+    }
+
+    /// Use the same number of threads for every step, replacing -j/--n-threads
+    pub fn override_threads(&mut self, n_threads: usize) {
+        match self {
+            PipelineCmds::AnalyzeRegion(cmd) => cmd.n_threads = n_threads,
+            PipelineCmds::PreprocessSample(cmd) => cmd.n_threads = n_threads,
+            PipelineCmds::TrainCtrls(cmd) => cmd.n_threads = n_threads,
+            PipelineCmds::Experiment(cmd) => cmd.n_threads = n_threads,
+            PipelineCmds::SmaGenome(cmd) => cmd.n_threads = n_threads,
+        }
+    }
+
+    /// Directory the pipeline writes its outputs to
+    pub fn output_dir(&self) -> &Path {
+        match self {
+            PipelineCmds::AnalyzeRegion(cmd) => &cmd.output_dir,
+            PipelineCmds::PreprocessSample(cmd) => &cmd.output_dir,
+            PipelineCmds::TrainCtrls(cmd) => &cmd.output_dir,
+            PipelineCmds::Experiment(cmd) => &cmd.output_dir,
+            PipelineCmds::SmaGenome(cmd) => &cmd.output_dir,
+        }
+    }
+}
+
+/// Print the steps of a pipeline for --dry-run, failing if any binaries or
+/// inputs are missing.
+fn report_plan(plan: Plan) -> eyre::Result<()> {
+    log::info!("Dry run, no commands will be executed");
+    print!("{plan}");
+    if plan.is_valid() {
+        Ok(())
+    } else {
+        Err(eyre::eyre!(
+            "Found {} problem(s), see above",
+            plan.problems.len()
+        ))
+    }
 }
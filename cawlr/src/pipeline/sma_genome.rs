@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use libcawlr::{motif::Motif, pipeline::SmaGenomeOptions};
+use log::LevelFilter;
+
+use super::report_plan;
+use crate::file::ValidPathBuf;
+
+#[derive(Parser, Debug)]
+pub struct SmaGenomeCmd {
+    /// Path to genome fasta file, with a .fai index for the bigWig track
+    #[clap(short, long)]
+    pub genome: ValidPathBuf,
+
+    /// Whole-genome output of cawlr collapse for the sample
+    #[clap(short, long)]
+    pub collapse: ValidPathBuf,
+
+    /// Output directory of cawlr pipeline train-ctrls
+    #[clap(long)]
+    pub ctrls: ValidPathBuf,
+
+    /// Where to output results
+    #[clap(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Motifs of modification to filter on, separated by commas, format is
+    /// "{position}:{motif}" ie for GpC and CpG motif , motif is "2:GC,1:CG"
+    #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
+    pub motifs: Vec<Motif>,
+
+    /// Number of control scores sampled for each kernel density estimate
+    #[clap(long, default_value_t = 10_000)]
+    pub samples: usize,
+
+    /// Sample control scores uniformly across the genome instead of an equal
+    /// number from each chromosome
+    #[clap(long, default_value_t = false)]
+    pub no_stratify: bool,
+
+    /// Positions covered by fewer reads are left out of the occupancy track
+    #[clap(long, default_value_t = 1)]
+    pub min_coverage: u64,
+
+    #[clap(long, default_value_t = false)]
+    pub overwrite: bool,
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs. Set with the global --force option
+    #[clap(skip)]
+    pub force: bool,
+
+    /// Threads used to score and segment chunks of reads in parallel
+    #[clap(short = 'j', long, default_value_t = 4)]
+    pub n_threads: usize,
+
+    /// Write a JSON line for each step of the pipeline to log.jsonl in the
+    /// output directory
+    #[clap(long, default_value_t = false)]
+    pub json_log: bool,
+
+    /// Print every step and command with resolved paths and check that the
+    /// required input files exist, without running anything
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+impl SmaGenomeCmd {
+    pub fn run(self, log_level_filter: LevelFilter) -> eyre::Result<()> {
+        let mut opts = SmaGenomeOptions::new(
+            self.genome.0,
+            self.collapse.0,
+            self.ctrls.0,
+            self.output_dir,
+            self.motifs,
+        );
+        opts.samples(self.samples)
+            .stratify(!self.no_stratify)
+            .min_coverage(self.min_coverage)
+            .overwrite(self.overwrite)
+            .force(self.force)
+            .json_log(self.json_log)
+            .log_level(log_level_filter);
+        if self.dry_run {
+            return report_plan(opts.dry_run()?);
+        }
+        opts.run()
+    }
+}
@@ -1,24 +1,9 @@
-use std::{
-    fs::{self, File},
-    io::{BufRead, BufReader, BufWriter, Write},
-    path::{Path, PathBuf},
-    process::{Command, Stdio},
-};
+use std::path::PathBuf;
 
 use clap::Parser;
-use eyre::{Context, Result};
-use fnv::FnvHashMap;
-use libcawlr::{
-    collapse::CollapseOptions,
-    motif::Motif,
-    npsmlr::{train::TrainOptions, ScoreOptions},
-    rank::RankOptions,
-    score_model::Options,
-    train::Model,
-    utils::{self, check_if_failed, wrap_cmd, wrap_cmd_output, CawlrIO},
-};
-use log::LevelFilter;
+use libcawlr::{motif::Motif, pipeline::TrainCtrlsOptions};
 
+use super::report_plan;
 use crate::file::ValidPathBuf;
 
 #[derive(Parser, Debug)]
@@ -57,7 +42,7 @@ pub struct TrainCtrlPipelineCmd {
 
     /// Output directory for pipeline
     #[clap(short, long)]
-    output_dir: PathBuf,
+    pub output_dir: PathBuf,
 
     /// Path to nanopolish tool, optional if in docker container or in PATH
     #[clap(long)]
@@ -73,348 +58,58 @@ pub struct TrainCtrlPipelineCmd {
 
     /// Number of threads to use
     #[clap(short = 'j', long, default_value_t = 4)]
-    n_threads: usize,
+    pub n_threads: usize,
 
-    /// Motifs of modification to filter on, separated by commas, format is
-    /// "{position}:{motif}" ie for GpC and CpG motif , motif is "2:GC,1:CG"
-    #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
-    motifs: Vec<Motif>,
-}
+    /// Write a JSON line for each step of the pipeline to log.jsonl in the
+    /// output directory
+    #[clap(long, default_value_t = false)]
+    json_log: bool,
 
-fn np_index(
-    nanopolish: &Path,
-    fast5s: &Path,
-    reads: &Path,
-    summary: &Option<PathBuf>,
-    log_file: File,
-) -> Result<()> {
-    let mut cmd = Command::new(nanopolish);
-    cmd.arg("index").arg("-d").arg(fast5s);
-    if let Some(summary) = summary {
-        cmd.arg("-s").arg(summary);
-    }
-    cmd.arg(reads);
-    cmd.stderr(log_file);
-    log::info!("{cmd:?}");
-    let output = cmd.output()?;
-    check_if_failed(output).wrap_err("nanopolish index failed")
-}
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs. Set with the global --force option
+    #[clap(skip)]
+    pub force: bool,
 
-fn aln_reads(
-    minimap2: &Path,
-    samtools: &Path,
-    genome: &ValidPathBuf,
-    reads: &Path,
-    output: &Path,
-    output_dir: &Path,
-    log_file: File,
-) -> eyre::Result<()> {
-    let mut map_cmd = Command::new(minimap2);
-    map_cmd
-        .arg("-ax")
-        .arg("map-ont")
-        .arg("--sam-hit-only")
-        .arg("--secondary=no")
-        .args(["-t", "4"])
-        .arg(genome)
-        .arg(reads)
-        .stdout(Stdio::piped())
-        .stderr(log_file.try_clone()?);
-    log::info!("{map_cmd:?}");
-    let map_output = map_cmd.spawn()?;
+    /// Sample the training data of each kmer with a fixed seed. Set with the
+    /// global --deterministic option
+    #[clap(skip)]
+    pub deterministic: bool,
 
-    let mut sam_cmd = Command::new(samtools);
-    sam_cmd
-        .arg("sort")
-        .arg("--write-index")
-        .arg("-T")
-        .arg(output_dir)
-        .arg("-o")
-        .arg(output)
-        .stderr(log_file)
-        .stdin(map_output.stdout.unwrap());
-    log::info!("{sam_cmd:?}");
-    let output = sam_cmd.output()?;
-    check_if_failed(output).wrap_err("minimap2 | samtools failed")
-}
-
-fn eventalign_collapse(
-    nanopolish: &Path,
-    reads: &Path,
-    bam: &Path,
-    genome: &ValidPathBuf,
-    output: &Path,
-    log_file: File,
-) -> Result<()> {
-    let mut cmd = Command::new(nanopolish);
-    cmd.arg("eventalign")
-        .arg("-r")
-        .arg(reads)
-        .arg("-b")
-        .arg(bam)
-        .arg("-g")
-        .arg(genome)
-        .arg("-t")
-        .arg("4")
-        .arg("--scale-events")
-        .arg("--print-read-names")
-        .arg("--samples");
-    log::info!("nanopolish cmd: {cmd:?}");
-    let mut cmd = cmd.stdout(Stdio::piped()).stderr(log_file).spawn()?;
-    let stdout = cmd
-        .stdout
-        .take()
-        .ok_or_else(|| eyre::eyre!("Could not capture stdout"))?;
-    let reader = BufReader::new(stdout);
-    let mut collapse = CollapseOptions::try_new(bam, output)?;
-    collapse.run(reader)?;
-    Ok(())
-}
+    /// Print every step and command with resolved paths and check that the
+    /// required binaries and input files exist, without running anything
+    #[clap(long, default_value_t = false)]
+    dry_run: bool,
 
-fn train_npsmlr(
-    collapse_file: &Path,
-    db_file: &Path,
-    single: bool,
-    motifs: &[Motif],
-) -> Result<Model> {
-    let train_opts = TrainOptions::default()
-        .dbscan(true)
-        .single(single)
-        .db_path(Some(db_file.to_path_buf()))
-        .motifs(motifs.to_vec());
-    let reader = BufReader::new(File::open(collapse_file)?);
-    let model = train_opts.run_model(reader)?;
-    Ok(model)
-}
-
-fn rank_models(
-    rank_output: &Path,
-    pos_model: &Model,
-    neg_model: &Model,
-) -> Result<FnvHashMap<String, f64>> {
-    let mut rank_opts = RankOptions::default();
-    let ranks = rank_opts.rank(pos_model, neg_model);
-    ranks.save_as(rank_output)?;
-    Ok(ranks)
+    /// Motifs of modification to filter on, separated by commas, format is
+    /// "{position}:{motif}" ie for GpC and CpG motif , motif is "2:GC,1:CG"
+    #[clap(short, long, required=true, num_args=1.., value_delimiter=',')]
+    motifs: Vec<Motif>,
 }
 
-// Takes a path reads and checks if it is a directory. If its a directory, find
-// all the fastqs and concatenate them all into a single file.
-fn reads_to_single_reads(reads: &Path, name: &str, output_dir: &Path) -> Result<PathBuf> {
-    if reads.is_dir() {
-        log::info!("Detected directory, concatenating into a single fastq file.");
-        let output_filepath = output_dir.join(name);
-        let mut output_file = BufWriter::new(File::create(&output_filepath)?);
-        let fastq_matcher = format!(
-            "{}/**/*fastq",
-            reads.as_os_str().to_str().ok_or(eyre::eyre!(
-                "Failed to convert path into str, unicode issue?"
-            ))?
+impl TrainCtrlPipelineCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let mut opts = TrainCtrlsOptions::new(
+            self.genome.0,
+            self.pos_fast5,
+            self.pos_reads,
+            self.neg_fast5,
+            self.neg_reads,
+            self.output_dir,
+            self.motifs,
         );
-        let mut n_fastq_files = 0;
-        for fastq in glob::glob(&fastq_matcher)? {
-            let fastq = fastq?;
-            n_fastq_files += 1;
-            log::info!("Found fastq: {}", fastq.display());
-            let mut fastq_file = BufReader::new(File::open(fastq)?);
-            loop {
-                let buf_len = {
-                    let buf = fastq_file.fill_buf()?;
-                    if buf.is_empty() {
-                        break;
-                    }
-                    output_file.write_all(buf)?;
-                    buf.len()
-                };
-                fastq_file.consume(buf_len);
-            }
+        opts.pos_summary(self.pos_summary)
+            .neg_summary(self.neg_summary)
+            .nanopolish_path(self.nanopolish_path)
+            .minimap2_path(self.minimap2_path)
+            .samtools_path(self.samtools_path)
+            .n_threads(self.n_threads)
+            .json_log(self.json_log)
+            .force(self.force)
+            .deterministic(self.deterministic);
+        if self.dry_run {
+            return report_plan(opts.dry_run()?);
         }
-        output_file.flush()?;
-
-        if n_fastq_files == 0 {
-            return Err(eyre::eyre!(
-                "No fastq files processed, check if directory contained files ending with .fastq"
-            ));
-        } else {
-            log::info!("Processed {n_fastq_files} fastq files");
-        }
-
-        Ok(output_filepath)
-    } else {
-        Ok(reads.to_path_buf())
-    }
-}
-
-pub fn run(args: TrainCtrlPipelineCmd) -> eyre::Result<()> {
-    log::info!("{args:?}");
-    let nanopolish = utils::find_binary("nanopolish", &args.nanopolish_path)?;
-    let minimap2 = utils::find_binary("minimap2", &args.minimap2_path)?;
-    let samtools = utils::find_binary("samtools", &args.samtools_path)?;
-
-    fs::create_dir_all(&args.output_dir)?;
-
-    let log_file_path = args.output_dir.join("log.txt");
-    let log_file = File::create(log_file_path)?;
-    simple_logging::log_to(log_file.try_clone()?, LevelFilter::Info);
-
-    let neg_reads = reads_to_single_reads(&args.neg_reads, "neg_reads.fastq", &args.output_dir)?;
-    let pos_reads = reads_to_single_reads(&args.pos_reads, "pos_reads.fastq", &args.output_dir)?;
-
-    wrap_cmd("nanopolish index for (+) ctrl", || {
-        np_index(
-            &nanopolish,
-            &args.pos_fast5,
-            &pos_reads,
-            &args.pos_summary,
-            log_file.try_clone()?,
-        )
-    })?;
-    wrap_cmd("nanopolish index for (-) ctrl", || {
-        np_index(
-            &nanopolish,
-            &args.neg_fast5,
-            &neg_reads,
-            &args.neg_summary,
-            log_file.try_clone()?,
-        )
-    })?;
-
-    let pos_aln = args.output_dir.join("pos.bam");
-    wrap_cmd("align (+) ctrl reads", || {
-        aln_reads(
-            &minimap2,
-            &samtools,
-            &args.genome,
-            &pos_reads,
-            &pos_aln,
-            &args.output_dir,
-            log_file.try_clone()?,
-        )
-    })?;
-    let neg_aln = args.output_dir.join("neg.bam");
-    wrap_cmd("align (-) ctrl reads", || {
-        aln_reads(
-            &minimap2,
-            &samtools,
-            &args.genome,
-            &neg_reads,
-            &neg_aln,
-            &args.output_dir,
-            log_file.try_clone()?,
-        )
-    })?;
-
-    let pos_collapse = args.output_dir.join("pos_collapse.arrow");
-    wrap_cmd("nanopolish eventalign (+) ctrl | cawlr collapse", || {
-        eventalign_collapse(
-            &nanopolish,
-            &pos_reads,
-            &pos_aln,
-            &args.genome,
-            &pos_collapse,
-            log_file.try_clone()?,
-        )
-    })?;
-
-    let neg_collapse = args.output_dir.join("neg_collapse.arrow");
-    wrap_cmd("nanopolish eventalign (-) ctrl | cawlr collapse", || {
-        eventalign_collapse(
-            &nanopolish,
-            &neg_reads,
-            &neg_aln,
-            &args.genome,
-            &neg_collapse,
-            log_file.try_clone()?,
-        )
-    })?;
-
-    let pos_train = args.output_dir.join("pos_train.pickle");
-    let neg_train = args.output_dir.join("neg_train.pickle");
-
-    let pos_db_path = "pos.db.sqlite3";
-    let neg_db_path = "neg.db.sqlite3";
-
-    let pos_db_file = args.output_dir.join(pos_db_path);
-    let neg_db_file = args.output_dir.join(neg_db_path);
-
-    let pos_model = wrap_cmd_output("Train (+) ctrl", || {
-        log::info!("Starting  + training");
-        train_npsmlr(&pos_collapse, &pos_db_file, false, &args.motifs)
-    })?;
-    pos_model.save_as(pos_train)?;
-    let neg_model = wrap_cmd_output("Train (-) ctrl", || {
-        log::info!("Starting - training");
-        train_npsmlr(&neg_collapse, &neg_db_file, true, &args.motifs)
-    })?;
-    neg_model.save_as(neg_train)?;
-
-    let rank_output = args.output_dir.join("ranks.pickle");
-    let ranks = wrap_cmd_output("ranking model kmers", || {
-        rank_models(&rank_output, &pos_model, &neg_model)
-    })?;
-
-    let score_opts = ScoreOptions::new(pos_model, neg_model, ranks, 10, 10.0, args.motifs.clone());
-
-    let pos_scores_path = args.output_dir.join("pos_scored.arrow");
-    wrap_cmd("Scoring (+) ctrl", || {
-        let pos_collapse = File::open(&pos_collapse)?;
-        let pos_scores = File::create(&pos_scores_path)?;
-        score_opts.run(pos_collapse, &pos_scores)?;
-        log::info!("Finished scoring positive control");
-        Ok(())
-    })?;
-
-    let neg_scores_path = args.output_dir.join("neg_scored.arrow");
-    wrap_cmd("Scoring (-) ctrl", || {
-        let neg_collapse = File::open(&neg_collapse)?;
-        let neg_scores = File::create(&neg_scores_path)?;
-        score_opts.run(neg_collapse, neg_scores)?;
-        log::info!("Finished scoring positive control");
-        Ok(())
-    })?;
-
-    let pos_bkde_path = args.output_dir.join("pos_model_scores.pickle");
-    wrap_cmd("(+) model score dist", || {
-        let pos_scores = File::open(&pos_scores_path)?;
-        let pos_bkde = Options::default().run(pos_scores)?;
-        pos_bkde.save_as(&pos_bkde_path)?;
-        log::info!("Completed BKDE for (+) control");
-        Ok(())
-    })?;
-
-    let neg_bkde_path = args.output_dir.join("neg_model_scores.pickle");
-    wrap_cmd("(-) model score dist", || {
-        let neg_scores = File::open(&neg_scores_path)?;
-        let neg_bkde = Options::default().run(neg_scores)?;
-        neg_bkde.save_as(&neg_bkde_path)?;
-        log::info!("Completed BKDE for (-) control");
-        Ok(())
-    })?;
-
-    let score_plot = args.output_dir.join("score_dist.png");
-    wrap_cmd("Score dist", || {
-        let mut score_dist_cmd = Command::new("plot_scoring_dist.py");
-        score_dist_cmd
-            .arg("-i")
-            .arg(&neg_bkde_path)
-            .arg(&pos_bkde_path)
-            .arg("-o")
-            .arg(&score_plot)
-            .arg("--title")
-            .arg("Score distribution between (+) and (-) controls");
-        log::info!("Score dist command: {score_dist_cmd:?}");
-        score_dist_cmd.stderr(log_file.try_clone()?);
-        let output = score_dist_cmd.output()?;
-        check_if_failed(output).wrap_err("Score distribution command")?;
-
-        Ok(())
-    })?;
-
-    wrap_cmd("Cleaning up database files", || {
-        fs::remove_file(pos_db_path)?;
-        fs::remove_file(neg_db_path)?;
+        opts.run()?;
         Ok(())
-    })?;
-
-    Ok(())
+    }
 }
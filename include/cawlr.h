@@ -0,0 +1,60 @@
+/*
+ * Score single reads with cawlr models, see src/ffi.rs.
+ *
+ * Functions returning int return 0 on success and -1 on failure, and functions
+ * returning pointers return NULL on failure. cawlr_last_error() describes the
+ * last failure on the calling thread.
+ */
+#ifndef CAWLR_H
+#define CAWLR_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct CawlrScorer CawlrScorer;
+
+/* Current measured at one position of a read */
+typedef struct {
+    /* Zero-based genomic position of the start of the kmer */
+    uint64_t pos;
+    /* Reference kmer starting at pos, NUL-terminated */
+    const char *kmer;
+    /* Current samples aligned to the kmer, averaged to score it */
+    const double *samples;
+    size_t n_samples;
+} CawlrSignal;
+
+/* Score for one position, like the scores in cawlr score outputs */
+typedef struct {
+    uint64_t pos;
+    /* True if there was no confident signal to score, score is then 0 */
+    bool skipped;
+    /* NaN if skipped */
+    double signal_score;
+    double score;
+} CawlrScore;
+
+const char *cawlr_last_error(void);
+
+CawlrScorer *cawlr_scorer_new(const char *pos_ctrl, const char *neg_ctrl, const char *ranks);
+void cawlr_scorer_free(CawlrScorer *scorer);
+
+int cawlr_scorer_set_cutoff(CawlrScorer *scorer, double cutoff);
+int cawlr_scorer_set_p_value_threshold(CawlrScorer *scorer, double p_value_threshold);
+/* Comma separated motifs, ie "2:GC,1:CG" */
+int cawlr_scorer_set_motifs(CawlrScorer *scorer, const char *motifs);
+
+/* scores must have room for n_signals scores, fails on kmers longer than 16 bases */
+int cawlr_score_read(const CawlrScorer *scorer, const CawlrSignal *signals, size_t n_signals,
+                     CawlrScore *scores, size_t *n_scores);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CAWLR_H */
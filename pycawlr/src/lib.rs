@@ -0,0 +1,612 @@
+//! Python bindings for running cawlr in-process, ie from a Jupyter notebook,
+//! instead of scripting around the command line.
+//!
+//! Options are kept as plain Python objects and only converted to their
+//! libcawlr counterparts when run, which releases the GIL for the duration.
+//! Arrow outputs are handed to pyarrow through the Arrow C data interface
+//! without copying, so scored reads can go straight to pandas:
+//!
+//! ```python
+//! import pycawlr
+//! reads = pycawlr.read_table("scores.arrow").flatten().to_pandas()
+//! ```
+// pyo3's macros convert the results of #[pyfunction]s to themselves
+#![allow(clippy::useless_conversion)]
+use std::{collections::VecDeque, fs::File, path::PathBuf, str::FromStr};
+
+use arrow2::{array::Array, datatypes::Field, ffi};
+use libcawlr::{
+    arrow::{
+        arrow_utils::{arrow_type, load_arrays, load_chunks, ArrowError},
+        eventalign::Eventalign,
+        metadata::Metadata,
+        scored_read::ScoredRead,
+        sma_read::SmaRead,
+    },
+    collapse::{self, CollapseError},
+    motif::{Motif, MotifError},
+    score::{self, ScoreError},
+    sma,
+    train::{self, TrainError},
+    utils::CawlrIO,
+};
+use pyo3::{
+    exceptions::{PyIOError, PyRuntimeError, PyValueError},
+    prelude::*,
+    types::{PyDict, PyList},
+};
+use rv::prelude::Gaussian;
+
+/// Python exception an error becomes
+enum ErrorKind {
+    Value,
+    Io,
+    Runtime,
+}
+
+/// Errors from invalid inputs or options are ValueError, IO errors are IOError
+/// and everything else RuntimeError. The Io and Other variants of the typed
+/// errors are classified by what they hold.
+trait PyErrorKind {
+    fn kind(&self) -> ErrorKind;
+}
+
+impl PyErrorKind for eyre::Report {
+    fn kind(&self) -> ErrorKind {
+        for cause in self.chain() {
+            if let Some(e) = cause.downcast_ref::<ScoreError>() {
+                return e.kind();
+            } else if let Some(e) = cause.downcast_ref::<CollapseError>() {
+                return e.kind();
+            } else if let Some(e) = cause.downcast_ref::<TrainError>() {
+                return e.kind();
+            } else if let Some(e) = cause.downcast_ref::<ArrowError>() {
+                return e.kind();
+            } else if cause.is::<MotifError>() {
+                return ErrorKind::Value;
+            } else if cause.is::<std::io::Error>() {
+                return ErrorKind::Io;
+            }
+        }
+        ErrorKind::Runtime
+    }
+}
+
+impl PyErrorKind for ArrowError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ArrowError::Io(_) => ErrorKind::Io,
+            ArrowError::Other(e) => e.kind(),
+            _ => ErrorKind::Value,
+        }
+    }
+}
+
+impl PyErrorKind for ScoreError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ScoreError::Arrow(e) => e.kind(),
+            ScoreError::Io(_) => ErrorKind::Io,
+            ScoreError::Other(e) => e.kind(),
+            _ => ErrorKind::Value,
+        }
+    }
+}
+
+impl PyErrorKind for CollapseError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            CollapseError::Arrow(e) => e.kind(),
+            CollapseError::Io(_) => ErrorKind::Io,
+            CollapseError::Other(e) => e.kind(),
+            _ => ErrorKind::Value,
+        }
+    }
+}
+
+impl PyErrorKind for TrainError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TrainError::Arrow(e) => e.kind(),
+            TrainError::Io(_) => ErrorKind::Io,
+            TrainError::Other(e) => e.kind(),
+            _ => ErrorKind::Value,
+        }
+    }
+}
+
+/// Convert to the Python exception of [PyErrorKind], with the whole error
+/// chain as the message
+fn to_py_err<E>(e: E) -> PyErr
+where
+    E: PyErrorKind + Into<eyre::Report>,
+{
+    let kind = e.kind();
+    let msg = format!("{:#}", e.into());
+    match kind {
+        ErrorKind::Value => PyValueError::new_err(msg),
+        ErrorKind::Io => PyIOError::new_err(msg),
+        ErrorKind::Runtime => PyRuntimeError::new_err(msg),
+    }
+}
+
+fn parse_motifs(motifs: Option<Vec<String>>) -> PyResult<Option<Vec<Motif>>> {
+    motifs
+        .map(|motifs| {
+            motifs
+                .iter()
+                .map(|m| Motif::from_str(m).map_err(|e| PyValueError::new_err(e.to_string())))
+                .collect()
+        })
+        .transpose()
+}
+
+/// Convert nanopolish eventalign output into an Arrow file, see cawlr collapse
+#[pyclass]
+#[derive(Clone)]
+struct CollapseOptions {
+    #[pyo3(get, set)]
+    bam: PathBuf,
+    #[pyo3(get, set)]
+    output: PathBuf,
+    #[pyo3(get, set)]
+    samples: bool,
+    #[pyo3(get, set)]
+    capacity: Option<usize>,
+}
+
+#[pymethods]
+impl CollapseOptions {
+    #[new]
+    #[pyo3(signature = (bam, output, samples = true, capacity = None))]
+    fn new(bam: PathBuf, output: PathBuf, samples: bool, capacity: Option<usize>) -> Self {
+        CollapseOptions {
+            bam,
+            output,
+            samples,
+            capacity,
+        }
+    }
+
+    /// Collapse the eventalign output at the given path
+    fn run(&self, py: Python<'_>, eventalign: PathBuf) -> PyResult<()> {
+        let opts = self.clone();
+        py.allow_threads(move || {
+            let mut collapse = collapse::CollapseOptions::try_new(&opts.bam, &opts.output)?;
+            collapse.samples(opts.samples).progress(false);
+            if let Some(capacity) = opts.capacity {
+                collapse.capacity(capacity);
+            }
+            collapse.run(File::open(eventalign)?)
+        })
+        .map_err(to_py_err)
+    }
+}
+
+/// Score reads with the positive and negative control models, see cawlr score
+#[pyclass]
+#[derive(Clone)]
+struct ScoreOptions {
+    #[pyo3(get, set)]
+    pos_ctrl: PathBuf,
+    #[pyo3(get, set)]
+    neg_ctrl: PathBuf,
+    #[pyo3(get, set)]
+    genome: PathBuf,
+    #[pyo3(get, set)]
+    ranks: PathBuf,
+    #[pyo3(get, set)]
+    output: PathBuf,
+    motifs: Option<Vec<Motif>>,
+    #[pyo3(get, set)]
+    cutoff: f64,
+    #[pyo3(get, set)]
+    p_value_threshold: f64,
+    #[pyo3(get, set)]
+    by_chrom: bool,
+}
+
+#[pymethods]
+impl ScoreOptions {
+    #[new]
+    #[pyo3(signature = (
+        pos_ctrl,
+        neg_ctrl,
+        genome,
+        ranks,
+        output,
+        motifs = None,
+        cutoff = 10.0,
+        p_value_threshold = 0.05,
+        by_chrom = false,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        pos_ctrl: PathBuf,
+        neg_ctrl: PathBuf,
+        genome: PathBuf,
+        ranks: PathBuf,
+        output: PathBuf,
+        motifs: Option<Vec<String>>,
+        cutoff: f64,
+        p_value_threshold: f64,
+        by_chrom: bool,
+    ) -> PyResult<Self> {
+        Ok(ScoreOptions {
+            pos_ctrl,
+            neg_ctrl,
+            genome,
+            ranks,
+            output,
+            motifs: parse_motifs(motifs)?,
+            cutoff,
+            p_value_threshold,
+            by_chrom,
+        })
+    }
+
+    /// Score the reads in an Arrow file from collapse
+    fn run(&self, py: Python<'_>, input: PathBuf) -> PyResult<()> {
+        let opts = self.clone();
+        py.allow_threads(move || {
+            let mut scoring = score::ScoreOptions::try_new(
+                &opts.pos_ctrl,
+                &opts.neg_ctrl,
+                &opts.genome,
+                &opts.ranks,
+                &opts.output,
+            )?;
+            scoring
+                .cutoff(opts.cutoff)
+                .p_value_threshold(opts.p_value_threshold)
+                .by_chrom(opts.by_chrom);
+            if let Some(motifs) = opts.motifs {
+                scoring.motifs(motifs)?;
+            }
+            scoring.run(input)
+        })
+        .map_err(to_py_err)
+    }
+}
+
+/// Infer nucleosome positions on single molecules, see cawlr sma
+#[pyclass]
+#[derive(Clone)]
+struct SmaOptions {
+    #[pyo3(get, set)]
+    pos_ctrl_scores: PathBuf,
+    #[pyo3(get, set)]
+    neg_ctrl_scores: PathBuf,
+    #[pyo3(get, set)]
+    output: PathBuf,
+    motifs: Option<Vec<Motif>>,
+    #[pyo3(get, set)]
+    track_name: Option<String>,
+}
+
+#[pymethods]
+impl SmaOptions {
+    #[new]
+    #[pyo3(signature = (pos_ctrl_scores, neg_ctrl_scores, output, motifs = None, track_name = None))]
+    fn new(
+        pos_ctrl_scores: PathBuf,
+        neg_ctrl_scores: PathBuf,
+        output: PathBuf,
+        motifs: Option<Vec<String>>,
+        track_name: Option<String>,
+    ) -> PyResult<Self> {
+        Ok(SmaOptions {
+            pos_ctrl_scores,
+            neg_ctrl_scores,
+            output,
+            motifs: parse_motifs(motifs)?,
+            track_name,
+        })
+    }
+
+    /// Run on the scored reads in an Arrow file from score
+    fn run(&self, py: Python<'_>, input: PathBuf) -> PyResult<()> {
+        let opts = self.clone();
+        py.allow_threads(move || {
+            let motifs = opts.motifs.unwrap_or_else(libcawlr::motif::all_bases);
+            let mut sma = sma::SmaOptions::try_new(
+                &opts.pos_ctrl_scores,
+                &opts.neg_ctrl_scores,
+                motifs,
+                &opts.output,
+            )?;
+            if let Some(track_name) = opts.track_name {
+                sma.track_name(track_name);
+            }
+            sma.run(input)
+        })
+        .map_err(to_py_err)
+    }
+}
+
+/// Model trained by cawlr train
+#[pyclass]
+struct Model {
+    inner: train::Model,
+}
+
+#[pymethods]
+impl Model {
+    #[staticmethod]
+    fn load(path: PathBuf) -> PyResult<Self> {
+        let inner = train::Model::load(path).map_err(to_py_err)?;
+        Ok(Model { inner })
+    }
+
+    /// Kmers with a trained model
+    fn kmers(&self) -> Vec<String> {
+        self.inner.kmers().map(|k| k.to_string()).collect()
+    }
+
+    /// Components of the mixture for a kmer as (weight, mean, standard
+    /// deviation), or None if the kmer wasn't trained
+    fn mixture(&self, kmer: &str) -> Option<Vec<(f64, f64, f64)>> {
+        self.inner.mixture(kmer).map(|mixture| {
+            mixture
+                .weights()
+                .iter()
+                .zip(mixture.components())
+                .map(|(w, g): (&f64, &Gaussian)| (*w, g.mu(), g.sigma()))
+                .collect()
+        })
+    }
+
+    /// Fraction of positions with the kmer that had signal data
+    fn skip(&self, kmer: &str) -> Option<f64> {
+        self.inner.skip(kmer)
+    }
+}
+
+fn metadata_dict<'py>(py: Python<'py>, metadata: &Metadata) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("name", &metadata.name)?;
+    dict.set_item("chrom", &metadata.chrom)?;
+    dict.set_item("start", metadata.start)?;
+    dict.set_item("length", metadata.length)?;
+    dict.set_item("strand", metadata.strand.as_str())?;
+    dict.set_item("seq", &metadata.seq)?;
+    dict.set_item("mapq", metadata.mapq)?;
+    dict.set_item("flags", metadata.flags)?;
+    dict.set_item("identity", metadata.identity)?;
+    dict.set_item("sample", &metadata.sample)?;
+    Ok(dict)
+}
+
+/// Reads that can be handed to Python one at a time as dictionaries
+trait ToDict {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>>;
+}
+
+impl ToDict for ScoredRead {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = metadata_dict(py, &self.metadata)?;
+        let scores = PyList::empty_bound(py);
+        for score in self.scores.iter() {
+            let s = PyDict::new_bound(py);
+            s.set_item("pos", score.pos)?;
+            s.set_item("kmer", score.kmer.as_str())?;
+            s.set_item("skipped", score.skipped)?;
+            s.set_item("signal_score", score.signal_score)?;
+            s.set_item("score", score.score)?;
+            scores.append(s)?;
+        }
+        dict.set_item("scores", scores)?;
+        Ok(dict)
+    }
+}
+
+impl ToDict for Eventalign {
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = metadata_dict(py, self.metadata())?;
+        let signal = PyList::empty_bound(py);
+        for sig in self.signal_iter() {
+            let s = PyDict::new_bound(py);
+            s.set_item("pos", sig.pos)?;
+            s.set_item("kmer", &sig.kmer)?;
+            s.set_item("signal_mean", sig.signal_mean)?;
+            s.set_item("signal_time", sig.signal_time)?;
+            s.set_item("samples", &sig.samples)?;
+            signal.append(s)?;
+        }
+        dict.set_item("signal_data", signal)?;
+        Ok(dict)
+    }
+}
+
+type Chunks<T> = Box<dyn Iterator<Item = Result<Vec<T>, ArrowError>>>;
+
+/// Buffers one chunk of reads at a time
+struct ReadBuffer<T> {
+    chunks: Chunks<T>,
+    buf: VecDeque<T>,
+}
+
+impl<T: ToDict> ReadBuffer<T> {
+    fn next(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        while self.buf.is_empty() {
+            match self.chunks.next() {
+                Some(chunk) => self.buf.extend(chunk.map_err(to_py_err)?),
+                None => return Ok(None),
+            }
+        }
+        self.buf
+            .pop_front()
+            .map(|read| read.to_dict(py).map(|d| d.into()))
+            .transpose()
+    }
+}
+
+enum AnyReads {
+    Scored(ReadBuffer<ScoredRead>),
+    Eventalign(ReadBuffer<Eventalign>),
+}
+
+/// Iterator over the reads of an Arrow file from collapse or score, as
+/// dictionaries
+#[pyclass(unsendable)]
+struct Reads {
+    inner: AnyReads,
+}
+
+#[pymethods]
+impl Reads {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        match &mut slf.inner {
+            AnyReads::Scored(reads) => reads.next(py),
+            AnyReads::Eventalign(reads) => reads.next(py),
+        }
+    }
+}
+
+fn buffer<T>(file: File) -> eyre::Result<ReadBuffer<T>>
+where
+    T: arrow2_convert::field::ArrowField<Type = T>
+        + arrow2_convert::deserialize::ArrowDeserialize
+        + 'static,
+    for<'a> &'a <T as arrow2_convert::deserialize::ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    Ok(ReadBuffer {
+        chunks: Box::new(load_chunks(file)?),
+        buf: VecDeque::new(),
+    })
+}
+
+/// Iterate over the reads of an Arrow file from collapse or score
+#[pyfunction]
+fn reads(path: PathBuf) -> PyResult<Reads> {
+    let open = || -> eyre::Result<AnyReads> {
+        let mut file = File::open(&path)?;
+        match arrow_type(&mut file)?.as_str() {
+            "scored" => Ok(AnyReads::Scored(buffer(file)?)),
+            "eventalign" => Ok(AnyReads::Eventalign(buffer(file)?)),
+            other => Err(eyre::eyre!("Can't iterate over {other} reads")),
+        }
+    };
+    let inner = open().map_err(to_py_err)?;
+    Ok(Reads { inner })
+}
+
+type Arrays = Box<dyn Iterator<Item = Result<Box<dyn Array>, ArrowError>>>;
+
+/// Iterator over the chunks of an Arrow file as pyarrow RecordBatches, without
+/// copying the data
+#[pyclass(unsendable)]
+struct RecordBatches {
+    field: Field,
+    arrays: Arrays,
+    pyarrow: PyObject,
+}
+
+/// Export a struct array through the C data interface and import it as a
+/// pyarrow RecordBatch with one column per field
+fn to_record_batch(
+    py: Python<'_>,
+    pyarrow: &Bound<'_, PyAny>,
+    field: &Field,
+    array: Box<dyn Array>,
+) -> PyResult<PyObject> {
+    let array = Box::new(ffi::export_array_to_c(array));
+    let schema = Box::new(ffi::export_field_to_c(field));
+    let array_ptr = &*array as *const ffi::ArrowArray as usize;
+    let schema_ptr = &*schema as *const ffi::ArrowSchema as usize;
+    // pyarrow moves out of both structs, leaving nothing to release on drop
+    let array = pyarrow
+        .getattr("Array")?
+        .call_method1("_import_from_c", (array_ptr, schema_ptr))?;
+    let batch = pyarrow
+        .getattr("RecordBatch")?
+        .call_method1("from_struct_array", (array,))?;
+    Ok(batch.into_py(py))
+}
+
+#[pymethods]
+impl RecordBatches {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        let array = match slf.arrays.next() {
+            Some(array) => array.map_err(to_py_err)?,
+            None => return Ok(None),
+        };
+        let pyarrow = slf.pyarrow.bind(py);
+        to_record_batch(py, pyarrow, &slf.field, array).map(Some)
+    }
+}
+
+fn arrays(path: &PathBuf) -> eyre::Result<(Field, Arrays)> {
+    let mut file = File::open(path)?;
+    let (field, arrays): (Field, Arrays) = match arrow_type(&mut file)?.as_str() {
+        "scored" => {
+            let (field, arrays) = load_arrays::<_, ScoredRead>(file)?;
+            (field, Box::new(arrays))
+        }
+        "eventalign" => {
+            let (field, arrays) = load_arrays::<_, Eventalign>(file)?;
+            (field, Box::new(arrays))
+        }
+        "sma" => {
+            let (field, arrays) = load_arrays::<_, SmaRead>(file)?;
+            (field, Box::new(arrays))
+        }
+        other => return Err(eyre::eyre!("Unknown Arrow file type {other}")),
+    };
+    Ok((field, arrays))
+}
+
+/// Iterate over an Arrow file from collapse, score, or sma as pyarrow
+/// RecordBatches. Files from older versions of cawlr are migrated to the
+/// current schema.
+#[pyfunction]
+fn record_batches(py: Python<'_>, path: PathBuf) -> PyResult<RecordBatches> {
+    let pyarrow = py.import_bound("pyarrow")?.into_py(py);
+    let (field, arrays) = arrays(&path).map_err(to_py_err)?;
+    Ok(RecordBatches {
+        field,
+        arrays,
+        pyarrow,
+    })
+}
+
+/// Read a whole Arrow file from collapse, score, or sma as a pyarrow Table,
+/// use Table.flatten() to split the read metadata into columns
+#[pyfunction]
+fn read_table(py: Python<'_>, path: PathBuf) -> PyResult<PyObject> {
+    let pyarrow = py.import_bound("pyarrow")?;
+    let batches = record_batches(py, path)?.into_py(py);
+    let batches = batches.bind(py).iter()?.collect::<PyResult<Vec<_>>>()?;
+    let batches = PyList::new_bound(py, batches);
+    if batches.is_empty() {
+        return Err(PyValueError::new_err("Arrow file has no reads"));
+    }
+    let table = pyarrow
+        .getattr("Table")?
+        .call_method1("from_batches", (batches,))?;
+    Ok(table.into_py(py))
+}
+
+#[pymodule]
+fn pycawlr(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<CollapseOptions>()?;
+    m.add_class::<ScoreOptions>()?;
+    m.add_class::<SmaOptions>()?;
+    m.add_class::<Model>()?;
+    m.add_class::<Reads>()?;
+    m.add_class::<RecordBatches>()?;
+    m.add_function(wrap_pyfunction!(reads, m)?)?;
+    m.add_function(wrap_pyfunction!(record_batches, m)?)?;
+    m.add_function(wrap_pyfunction!(read_table, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,50 @@
+"""Tests for the Python bindings, run from the repository root after
+`maturin develop` with `pytest pycawlr/tests`"""
+
+from pathlib import Path
+
+import pytest
+
+import pycawlr
+
+EXTRA = Path(__file__).parents[2] / "extra"
+
+
+@pytest.fixture
+def collapsed(tmp_path):
+    output = tmp_path / "single_read.arrow"
+    pycawlr.CollapseOptions(str(EXTRA / "single_read.bam"), str(output)).run(
+        str(EXTRA / "single_read.eventalign.txt")
+    )
+    return output
+
+
+def test_reads(collapsed):
+    reads = list(pycawlr.reads(str(collapsed)))
+    assert len(reads) == 1
+    assert reads[0]["name"]
+
+
+def test_read_table(collapsed):
+    table = pycawlr.read_table(str(collapsed))
+    assert table.num_rows == 1
+    assert "metadata.name" in table.flatten().column_names
+
+
+def test_missing_file_is_io_error(tmp_path):
+    with pytest.raises(IOError):
+        pycawlr.reads(str(tmp_path / "missing.arrow"))
+    with pytest.raises(IOError):
+        pycawlr.Model.load(str(tmp_path / "missing.model"))
+
+
+def test_invalid_motif_is_value_error(tmp_path):
+    with pytest.raises(ValueError):
+        pycawlr.ScoreOptions(
+            "pos.model",
+            "neg.model",
+            "genome.fa",
+            "ranks.pickle",
+            str(tmp_path / "scores.arrow"),
+            motifs=["not a motif"],
+        )
@@ -196,6 +196,13 @@ def main():
             clustered[cidx].write(line)
     close_clustered_beds(clustered)
 
+    # Cluster assignment for each read, usable with cawlr split-clusters
+    assignments = input_path.parent / (input_path.stem + ".clusters.tsv")
+    with open(assignments, "w") as fh:
+        print("read_name\tcluster", file=fh)
+        for cidx, line in zip(results, bedlines):
+            print("{}\t{}".format(line.split("\t")[3], cidx), file=fh)
+
     fig, axs = plt.subplots(
         nrows=args.n_clusters, ncols=1, sharex=True, figsize=(15, 6)
     )
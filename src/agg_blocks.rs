@@ -1,21 +1,29 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::{BufRead, BufReader},
+    io::{BufRead, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use csv::StringRecord;
-use fnv::{FnvHashMap, FnvHashSet};
+use eyre::Context;
+use fnv::FnvHashSet;
 use serde::{de::IgnoredAny, Deserialize};
 use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
 
-use crate::utils::stdout_or_file;
+use crate::{
+    arrow::{
+        arrow_utils::{is_arrow_file, load_apply, n_chunks},
+        metadata::{MetadataExt, Strand},
+        sma_read::SmaRead,
+    },
+    haplotype::HaplotypeOutputs,
+    progress::{ProgressSink, Reporter, Stage},
+    region::Region,
+    utils::{open_maybe_gz, stdout_or_file},
+};
 
-#[derive(Eq, Hash, PartialEq, Clone)]
-struct Position {
-    chrom: String,
-    pos: u64,
-}
 #[derive(Default)]
 struct Count {
     count: u64,
@@ -37,12 +45,6 @@ impl Count {
     }
 }
 
-impl Position {
-    fn new(chrom: String, pos: u64) -> Self {
-        Self { chrom, pos }
-    }
-}
-
 #[serde_as]
 #[derive(Deserialize)]
 pub struct Bed {
@@ -51,7 +53,7 @@ pub struct Bed {
     stop: u64,
     _extra: IgnoredAny,
     _score: IgnoredAny,
-    _strand: IgnoredAny,
+    strand: String,
     _thick_start: IgnoredAny,
     _thick_end: IgnoredAny,
     _item_rgb: IgnoredAny,
@@ -63,62 +65,647 @@ pub struct Bed {
 }
 
 impl Bed {
-    fn iter_counts(self) -> impl Iterator<Item = Position> {
-        self.bsizes
+    pub fn bstarts(&self) -> &[u64] {
+        &self.bstarts
+    }
+
+    pub fn bsizes(&self) -> &[u64] {
+        &self.bsizes
+    }
+
+    /// cawlr sma adds single base pseudo blocks at the first and last base
+    /// when a read doesn't start or end with a nucleosome, these are not
+    /// counted as nucleosomes.
+    fn into_molecule(self) -> Molecule {
+        let Bed {
+            chrom,
+            start,
+            stop,
+            strand,
+            bsizes,
+            bstarts,
+            ..
+        } = self;
+        let nucs = bstarts
             .into_iter()
-            .zip(self.bstarts.into_iter())
-            .map(move |(a, b)| Position::new(self.chrom.clone(), self.start + a + b))
+            .zip(bsizes)
+            .map(|(bstart, bsize)| (start + bstart, start + bstart + bsize))
+            .filter(|&(s, e)| !(e - s == 1 && (s == start || e == stop)))
+            .collect();
+        let strand = match strand.as_str() {
+            "+" => Strand::plus(),
+            "-" => Strand::minus(),
+            _ => Strand::unknown(),
+        };
+        Molecule {
+            chrom,
+            start,
+            stop,
+            strand,
+            sample: None,
+            haplotype: None,
+            nucs,
+        }
     }
+}
 
-    fn overlaps(self) -> FnvHashSet<Position> {
-        self.iter_counts().collect()
+/// Read with the zero-based, half-open intervals of each nucleosome
+struct Molecule {
+    chrom: String,
+    start: u64,
+    stop: u64,
+    strand: Strand,
+    sample: Option<String>,
+    haplotype: Option<i64>,
+    nucs: Vec<(u64, u64)>,
+}
+
+impl From<SmaRead> for Molecule {
+    fn from(read: SmaRead) -> Self {
+        let nucs = read.nucleosomes().map(|b| (b.start, b.end())).collect();
+        Molecule {
+            chrom: read.chrom().to_string(),
+            start: read.start_0b(),
+            stop: read.end_1b_excl(),
+            strand: read.strand(),
+            sample: read.sample().map(String::from),
+            haplotype: read.haplotype(),
+            nucs,
+        }
     }
+}
 
-    pub fn bstarts(&self) -> &[u64] {
-        &self.bstarts
+impl Molecule {
+    /// First base after each block of the bed12 line of the read, including
+    /// the single base pseudo block cawlr sma adds at the start of reads that
+    /// don't start with a nucleosome
+    fn block_ends(&self) -> FnvHashSet<u64> {
+        let pseudo = match self.nucs.first() {
+            Some(&(s, _)) if s == self.start => None,
+            _ => Some(self.start + 1),
+        };
+        pseudo
+            .into_iter()
+            .chain(self.nucs.iter().map(|&(_, e)| e))
+            .collect()
     }
+}
 
-    pub fn bsizes(&self) -> &[u64] {
-        &self.bsizes
+/// Counts for a chromosome, keyed by position, strand, and the index of the
+/// group of reads, see [Group]. The strand is empty unless aggregating by
+/// strand.
+type ChromCounts = BTreeMap<(u64, &'static str, usize), Count>;
+
+/// Reads counted together. Reads are counted in a sample for the main output,
+/// which is always the first unless splitting by sample, and also in their
+/// haplotype when splitting by haplotype.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Group {
+    Sample(String),
+    Haplotype(i64),
+}
+
+/// Sample of reads that weren't tagged by cawlr merge, when splitting by sample
+const NO_SAMPLE: &str = ".";
+
+/// Fraction of reads with a nucleosome at each position, from the output of
+/// cawlr sma. Output is a tsv with the chromosome, position, strand if
+/// aggregating by strand, sample if splitting by sample, number of reads with a
+/// nucleosome, number of reads, and the fraction of reads with a nucleosome,
+/// sorted by position.
+#[derive(Default)]
+pub struct AggOptions {
+    by_strand: bool,
+    split_by_sample: bool,
+    split_haplotypes: bool,
+    regions: Vec<Region>,
+    sorted: bool,
+    whole_nucleosomes: bool,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
+}
+
+/// Number of bed lines between progress updates
+const BED_CHUNK_SIZE: usize = 4096;
+
+impl AggOptions {
+    /// Aggregate reads on each strand separately, adds a strand column after
+    /// the position
+    pub fn by_strand(&mut self, by_strand: bool) -> &mut Self {
+        self.by_strand = by_strand;
+        self
+    }
+
+    /// Aggregate each sample tagged by cawlr merge separately, adds a sample
+    /// column after the strand. Only Arrow input has samples, and reads
+    /// without one are counted under ".".
+    pub fn split_by_sample(&mut self, split_by_sample: bool) -> &mut Self {
+        self.split_by_sample = split_by_sample;
+        self
+    }
+
+    /// Also write the counts of each haplotype to their own output, named after
+    /// the output, ie agg.hp1.tsv and agg.hp2.tsv for agg.tsv. Only Arrow input
+    /// has haplotypes, from the HP tags of a phased bam file given to cawlr
+    /// collapse. Reads without one are only counted in the main output.
+    pub fn split_haplotypes(&mut self, split_haplotypes: bool) -> &mut Self {
+        self.split_haplotypes = split_haplotypes;
+        self
+    }
+
+    /// Only count positions within these regions, by default all positions
+    /// are counted
+    pub fn regions(&mut self, regions: Vec<Region>) -> &mut Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Input is sorted by chromosome and start, so positions are written once
+    /// no later read can overlap them instead of keeping every position in
+    /// memory. Returns an error if the input turns out not to be sorted.
+    pub fn sorted(&mut self, sorted: bool) -> &mut Self {
+        self.sorted = sorted;
+        self
+    }
+
+    /// Count every base covered by a nucleosome. By default only the first
+    /// base after each block of the bed12 line of a read is counted, see
+    /// [cawlr sma](crate::sma).
+    pub fn whole_nucleosomes(&mut self, whole_nucleosomes: bool) -> &mut Self {
+        self.whole_nucleosomes = whole_nucleosomes;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is counted
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    /// Input is either a bed file from cawlr sma, optionally gzip or bgzip
+    /// compressed, or an Arrow file from cawlr sma --format arrow.
+    pub fn run<P: AsRef<Path>>(&self, input: &Path, output: Option<P>) -> eyre::Result<()> {
+        let haplotype_output = if self.split_haplotypes {
+            if self.split_by_sample {
+                eyre::bail!("Split by sample or by haplotype, not both");
+            }
+            if !is_arrow_file(input) {
+                eyre::bail!(
+                    "Bed files have no haplotypes, split by haplotype with Arrow output from \
+                     cawlr sma --format arrow"
+                );
+            }
+            let output = output
+                .as_ref()
+                .ok_or_else(|| eyre::eyre!("Splitting by haplotype needs an output file"))?;
+            Some(output.as_ref().to_path_buf())
+        } else {
+            None
+        };
+        let writer = stdout_or_file(output.as_ref())?;
+        let haplotypes = haplotype_output.map(HaplotypeOutputs::new);
+        let TsvSink(mut writer) = self.aggregate_with(input, TsvSink(writer), haplotypes)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Pass the counts of each position to the sink, in order for each
+    /// chromosome
+    pub(crate) fn aggregate<S: CountSink>(&self, input: &Path, sink: S) -> eyre::Result<S> {
+        self.aggregate_with(input, sink, None)
+    }
+
+    /// Like [AggOptions::aggregate], also writing the counts of each haplotype
+    /// to their own output in the same pass
+    fn aggregate_with<S: CountSink>(
+        &self,
+        input: &Path,
+        sink: S,
+        haplotypes: Option<HaplotypeOutputs<HaplotypeSink>>,
+    ) -> eyre::Result<S> {
+        let mut agg = Aggregator::new(self, sink, haplotypes);
+        let mut reporter = Reporter::new(Stage::AggBlocks, self.progress_sink.clone());
+        if is_arrow_file(input) {
+            let mut file = File::open(input)?;
+            reporter.total_chunks(n_chunks(&mut file)?);
+            load_apply(file, |reads: Vec<SmaRead>| {
+                reporter.chunk(reads.len());
+                reads
+                    .into_iter()
+                    .try_for_each(|read| agg.add(Molecule::from(read)))
+            })?;
+        } else {
+            if self.split_by_sample {
+                return Err(eyre::eyre!(
+                    "Bed files have no samples, split by sample with Arrow output from \
+                     cawlr sma --format arrow"
+                ));
+            }
+            let mut n_lines = 0;
+            for line in open_maybe_gz(input)?.lines() {
+                let line = line?;
+                if line.is_empty() || line.starts_with("track") || line.starts_with('#') {
+                    continue;
+                }
+                n_lines += 1;
+                if n_lines == BED_CHUNK_SIZE {
+                    reporter.chunk(n_lines);
+                    n_lines = 0;
+                }
+                let record = StringRecord::from(line.split('\t').collect::<Vec<_>>());
+                let bed = record
+                    .deserialize::<Bed>(None)
+                    .wrap_err_with(|| format!("Invalid bed line: {line}"))?;
+                agg.add(bed.into_molecule())?;
+            }
+            if n_lines > 0 {
+                reporter.chunk(n_lines);
+            }
+        }
+        reporter.finish();
+        agg.finish()
     }
 }
 
-pub fn run(input: &Path, output: Option<&PathBuf>) -> eyre::Result<()> {
-    let input = BufReader::new(File::open(input)?);
-    // Skip header
-
-    let mut counts: FnvHashMap<Position, Count> = FnvHashMap::default();
-    for rec in input.lines().skip(1) {
-        let rec = rec?;
-        let line: Vec<&str> = rec.split('\t').collect();
-        let line = StringRecord::from(line);
-        let line = line.deserialize::<Bed>(None)?;
-        let chrom = line.chrom.clone();
-        let start = line.start;
-        let stop = line.stop;
-        let overlapped = line.overlaps();
-        (start..stop).for_each(|pos| {
-            let pos = Position::new(chrom.clone(), pos);
-            let e = counts.entry(pos.clone()).or_default();
-            if overlapped.contains(&pos) {
-                e.both();
-            } else {
-                e.total();
+/// Receives the counts of each position once no later read can overlap it
+pub(crate) trait CountSink {
+    /// Strand is empty unless aggregating by strand, and sample is empty
+    /// unless splitting by sample
+    fn position(
+        &mut self,
+        chrom: &str,
+        pos: u64,
+        strand: &str,
+        sample: &str,
+        count: u64,
+        total: u64,
+    ) -> eyre::Result<()>;
+}
+
+/// Writes each position as a line of the agg_blocks tsv output
+struct TsvSink<W>(W);
+
+/// Tsv of the positions of a haplotype, see [AggOptions::split_haplotypes]
+type HaplotypeSink = TsvSink<Box<dyn Write + Send>>;
+
+impl<W: Write> CountSink for TsvSink<W> {
+    fn position(
+        &mut self,
+        chrom: &str,
+        pos: u64,
+        strand: &str,
+        sample: &str,
+        count: u64,
+        total: u64,
+    ) -> eyre::Result<()> {
+        let frac = Count { count, total }.frac();
+        write!(self.0, "{chrom}\t{pos}\t")?;
+        for column in [strand, sample] {
+            if !column.is_empty() {
+                write!(self.0, "{column}\t")?;
+            }
+        }
+        writeln!(self.0, "{count}\t{total}\t{frac}")?;
+        Ok(())
+    }
+}
+
+struct Aggregator<'a, S> {
+    opts: &'a AggOptions,
+    sink: S,
+    haplotypes: Option<HaplotypeOutputs<HaplotypeSink>>,
+    counts: BTreeMap<String, ChromCounts>,
+    /// Groups in the order they were seen, indexed by [ChromCounts]
+    groups: Vec<Group>,
+    /// Chromosome and start of the last read, only tracked for sorted input
+    last: Option<(String, u64)>,
+    finished: FnvHashSet<String>,
+}
+
+impl<'a, S: CountSink> Aggregator<'a, S> {
+    fn new(
+        opts: &'a AggOptions,
+        sink: S,
+        haplotypes: Option<HaplotypeOutputs<HaplotypeSink>>,
+    ) -> Self {
+        Self {
+            opts,
+            sink,
+            haplotypes,
+            counts: BTreeMap::new(),
+            groups: vec![Group::Sample(String::new())],
+            last: None,
+            finished: FnvHashSet::default(),
+        }
+    }
+
+    fn in_regions(&self, chrom: &str, pos: u64) -> bool {
+        self.opts.regions.is_empty()
+            || self
+                .opts
+                .regions
+                .iter()
+                .any(|r| r.chrom() == chrom && r.start() <= pos && pos < r.end())
+    }
+
+    fn add(&mut self, mol: Molecule) -> eyre::Result<()> {
+        if self.opts.sorted {
+            self.flush_before(&mol)?;
+        }
+        let strand = if self.opts.by_strand {
+            mol.strand.as_str()
+        } else {
+            ""
+        };
+        let sample = if self.opts.split_by_sample {
+            let sample = mol.sample.as_deref().unwrap_or(NO_SAMPLE);
+            self.group_idx(Group::Sample(sample.to_string()))
+        } else {
+            0
+        };
+        let haplotype = match mol.haplotype {
+            Some(hp) if self.haplotypes.is_some() => Some(self.group_idx(Group::Haplotype(hp))),
+            _ => None,
+        };
+        let positions = (mol.start..mol.stop)
+            .filter(|&pos| self.in_regions(&mol.chrom, pos))
+            .collect::<Vec<_>>();
+        if positions.is_empty() {
+            return Ok(());
+        }
+        let block_ends = (!self.opts.whole_nucleosomes).then(|| mol.block_ends());
+        let counts = self.counts.entry(mol.chrom).or_default();
+        let mut nucs = mol.nucs.iter().peekable();
+        for pos in positions {
+            while nucs.peek().map_or(false, |&&(_, e)| e <= pos) {
+                nucs.next();
             }
-        });
-    }
-
-    let mut output = stdout_or_file(output)?;
-    for (p, c) in counts.into_iter() {
-        writeln!(
-            &mut output,
-            "{}\t{}\t{}\t{}\t{}",
-            p.chrom,
-            p.pos,
-            c.count,
-            c.total,
-            c.frac()
-        )?;
-    }
-    Ok(())
+            let is_nuc = match &block_ends {
+                Some(ends) => ends.contains(&pos),
+                None => nucs.peek().map_or(false, |&&(s, _)| s <= pos),
+            };
+            for group in std::iter::once(sample).chain(haplotype) {
+                let e = counts.entry((pos, strand, group)).or_default();
+                if is_nuc {
+                    e.both();
+                } else {
+                    e.total();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn group_idx(&mut self, group: Group) -> usize {
+        match self.groups.iter().position(|g| g == &group) {
+            Some(idx) => idx,
+            None => {
+                self.groups.push(group);
+                self.groups.len() - 1
+            }
+        }
+    }
+
+    /// Pass counts to the sink, or to the output of their haplotype
+    fn write_counts(&mut self, chrom: &str, counts: ChromCounts) -> eyre::Result<()> {
+        for ((pos, strand, group), c) in counts {
+            match &self.groups[group] {
+                Group::Sample(sample) => {
+                    self.sink
+                        .position(chrom, pos, strand, sample, c.count, c.total)?;
+                }
+                Group::Haplotype(hp) => {
+                    let Some(haplotypes) = self.haplotypes.as_mut() else {
+                        continue;
+                    };
+                    let sink = haplotypes
+                        .get_or_create(*hp, |path| Ok(TsvSink(stdout_or_file(Some(&path))?)))?;
+                    sink.position(chrom, pos, strand, "", c.count, c.total)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write every position before the start of this read, since no later
+    /// read in sorted input can overlap them
+    fn flush_before(&mut self, mol: &Molecule) -> eyre::Result<()> {
+        let unsorted = || {
+            eyre::eyre!(
+                "Input is not sorted by chromosome and start at read starting at {}:{}, sort \
+                 with `sort -k1,1 -k2,2n` or aggregate without --sorted",
+                mol.chrom,
+                mol.start
+            )
+        };
+        match self.last.take() {
+            Some((chrom, start)) if chrom == mol.chrom => {
+                if mol.start < start {
+                    return Err(unsorted());
+                }
+                if let Some(counts) = self.counts.get_mut(&chrom) {
+                    let rest = counts.split_off(&(mol.start, "", 0));
+                    let done = std::mem::replace(counts, rest);
+                    self.write_counts(&chrom, done)?;
+                }
+            }
+            Some((chrom, _)) => {
+                if let Some(counts) = self.counts.remove(&chrom) {
+                    self.write_counts(&chrom, counts)?;
+                }
+                self.finished.insert(chrom);
+            }
+            None => (),
+        }
+        if self.finished.contains(&mol.chrom) {
+            return Err(unsorted());
+        }
+        self.last = Some((mol.chrom.clone(), mol.start));
+        Ok(())
+    }
+
+    fn finish(mut self) -> eyre::Result<S> {
+        for (chrom, counts) in std::mem::take(&mut self.counts) {
+            self.write_counts(&chrom, counts)?;
+        }
+        if let Some(haplotypes) = self.haplotypes {
+            haplotypes.finish(|TsvSink(mut writer)| Ok(writer.flush()?))?;
+        }
+        Ok(self.sink)
+    }
+}
+
+/// Aggregate with the default options, see [AggOptions]
+pub fn run(input: &Path, output: Option<&PathBuf>) -> eyre::Result<()> {
+    AggOptions::default().run(input, output)
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::{
+        arrow::sma_read::{BlockState, SmaBlock},
+        haplotype::haplotype_path,
+        test_data::{write_reads, TestRead},
+    };
+
+    const BED: &str = "track name=\"test\" itemRgb=\"on\" visibility=2
+chrI\t10\t20\tread1\t0\t+\t10\t20\t0,0,0\t3\t1,4,1\t0,2,9
+chrI\t12\t18\tread2\t0\t-\t12\t18\t0,0,0\t2\t1,2\t0,4
+chrII\t0\t5\tread3\t0\t+\t0\t5\t0,0,0\t2\t2,1\t0,4
+";
+
+    fn agg(opts: &AggOptions, input: &Path) -> eyre::Result<Vec<String>> {
+        let output = input.with_extension("tsv");
+        opts.run(input, Some(&output))?;
+        Ok(std::fs::read_to_string(output)?
+            .lines()
+            .map(String::from)
+            .collect())
+    }
+
+    #[test]
+    fn test_agg_bed() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let bed = temp_dir.path().join("sma.bed");
+        std::fs::write(&bed, BED)?;
+        // First base after each block, including the pseudo block at the
+        // first base of read1 and read2
+        let lines = agg(&AggOptions::default(), &bed)?;
+        assert_eq!(lines.len(), 15);
+        assert_eq!(lines[0], "chrI\t10\t0\t1\t0");
+        assert_eq!(lines[1], "chrI\t11\t1\t1\t1");
+        assert_eq!(lines[3], "chrI\t13\t1\t2\t0.5");
+        assert_eq!(lines[6], "chrI\t16\t1\t2\t0.5");
+        assert_eq!(lines[12], "chrII\t2\t1\t1\t1");
+
+        let mut whole = AggOptions::default();
+        whole.whole_nucleosomes(true);
+        let lines = agg(&whole, &bed)?;
+        assert_eq!(lines.len(), 15);
+        // Pseudo block at the first base of read1 is not a nucleosome
+        assert_eq!(lines[0], "chrI\t10\t0\t1\t0");
+        assert_eq!(lines[2], "chrI\t12\t1\t2\t0.5");
+        assert_eq!(lines[6], "chrI\t16\t1\t2\t0.5");
+        assert_eq!(lines[10], "chrII\t0\t1\t1\t1");
+
+        let gz = temp_dir.path().join("sma.bed.gz");
+        let mut encoder = GzEncoder::new(File::create(&gz)?, Compression::default());
+        encoder.write_all(BED.as_bytes())?;
+        encoder.finish()?;
+        assert_eq!(agg(&whole, &gz)?, lines);
+
+        whole.regions(vec!["chrI:15-17".parse()?]);
+        assert_eq!(agg(&whole, &bed)?, lines[5..7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_agg_sorted() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let bed = temp_dir.path().join("sma.bed");
+        std::fs::write(&bed, BED)?;
+        let mut opts = AggOptions::default();
+        opts.sorted(true);
+        assert_eq!(agg(&opts, &bed)?, agg(&AggOptions::default(), &bed)?);
+
+        let mut unsorted = BED.lines().collect::<Vec<_>>();
+        unsorted.swap(1, 3);
+        std::fs::write(&bed, unsorted.join("\n"))?;
+        assert!(agg(&opts, &bed).is_err());
+        assert_eq!(agg(&AggOptions::default(), &bed)?.len(), 15);
+        Ok(())
+    }
+
+    #[test]
+    fn test_agg_arrow_by_strand_and_sample() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("sma.arrow");
+        let reads = [
+            (Strand::plus(), BlockState::nucleosome()),
+            (Strand::minus(), BlockState::linker()),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (strand, state))| {
+            let blocks = vec![
+                SmaBlock::new(100, 2, BlockState::linker()),
+                SmaBlock::new(102, 3, state),
+            ];
+            TestRead::new(format!("read{i}"))
+                .span(100, 5)
+                .strand(strand)
+                .sample(Some(&format!("sample{i}")))
+                .sma(blocks)
+        })
+        .collect::<Vec<_>>();
+        write_reads(&path, &reads)?;
+
+        // Only the pseudo block ends at 101, the nucleosome ends with the read
+        let lines = agg(&AggOptions::default(), &path)?;
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "chrI\t101\t2\t2\t1");
+        assert_eq!(lines[2], "chrI\t102\t0\t2\t0");
+
+        let mut opts = AggOptions::default();
+        opts.whole_nucleosomes(true);
+        let lines = agg(&opts, &path)?;
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], "chrI\t102\t1\t2\t0.5");
+
+        opts.by_strand(true);
+        let lines = agg(&opts, &path)?;
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[4], "chrI\t102\t+\t1\t1\t1");
+        assert_eq!(lines[5], "chrI\t102\t-\t0\t1\t0");
+
+        let mut opts = AggOptions::default();
+        opts.whole_nucleosomes(true).split_by_sample(true);
+        let lines = agg(&opts, &path)?;
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[4], "chrI\t102\tsample0\t1\t1\t1");
+        assert_eq!(lines[5], "chrI\t102\tsample1\t0\t1\t0");
+
+        let bed = temp_dir.path().join("sma.bed");
+        std::fs::write(&bed, BED)?;
+        assert!(agg(&opts, &bed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_agg_split_haplotypes() -> eyre::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("sma.arrow");
+        let reads = [Some(1), Some(2), None]
+            .into_iter()
+            .enumerate()
+            .map(|(i, haplotype)| {
+                let blocks = vec![SmaBlock::new(100, 5, BlockState::nucleosome())];
+                TestRead::new(format!("read{i}"))
+                    .span(100, 5)
+                    .haplotype(haplotype)
+                    .sma(blocks)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&path, &reads)?;
+
+        let mut opts = AggOptions::default();
+        opts.whole_nucleosomes(true).split_haplotypes(true);
+        let lines = agg(&opts, &path)?;
+        assert_eq!(lines[0], "chrI\t100\t3\t3\t1");
+        let output = path.with_extension("tsv");
+        for hp in [1, 2] {
+            let hp_lines = std::fs::read_to_string(haplotype_path(&output, hp))?;
+            assert_eq!(hp_lines.lines().count(), 5);
+            assert!(hp_lines.starts_with("chrI\t100\t1\t1\t1\n"));
+        }
+        assert!(!haplotype_path(&output, 3).exists());
+
+        let bed = temp_dir.path().join("sma.bed");
+        std::fs::write(&bed, BED)?;
+        assert!(agg(&opts, &bed).is_err());
+        Ok(())
+    }
 }
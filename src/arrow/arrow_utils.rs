@@ -1,30 +1,152 @@
 use std::{
     borrow::Borrow,
+    fmt::Display,
     fs::File,
-    io::{Read, Seek, Write},
+    io::{Read, Seek, SeekFrom, Write},
     marker::PhantomData,
     path::Path,
+    str::FromStr,
 };
 
 use arrow2::{
-    array::Array,
+    array::{new_null_array, Array, ListArray, StructArray},
     chunk::Chunk,
-    datatypes::{Field, Schema},
+    datatypes::{DataType, Field, Schema},
     io::ipc::{
-        read::{read_file_metadata, FileReader},
+        read::{read_batch, read_file_dictionaries, read_file_metadata, FileMetadata, FileReader},
         write::{Compression, FileWriter, WriteOptions},
     },
+    offset::Offset,
 };
 use arrow2_convert::{
     deserialize::{arrow_array_deserialize_iterator, ArrowDeserialize, TryIntoCollection},
     field::ArrowField,
     serialize::{ArrowSerialize, TryIntoArrow},
 };
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use indicatif::{style::TemplateError, ProgressBar, ProgressStyle};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use super::{eventalign::Eventalign, scored_read::ScoredRead};
+use super::{
+    eventalign::Eventalign,
+    metadata::{MetadataExt, MetadataMutExt},
+    scored_read::ScoredRead,
+    sma_read::SmaRead,
+};
+use crate::{hash::Encode, utils::take_error};
+
+/// Failures reading Arrow files. Errors returned by the functions given to the
+/// loaders, ie [load_apply], are kept as [ArrowError::Other].
+#[derive(Error, Debug)]
+pub enum ArrowError {
+    #[error("Arrow file has no fields")]
+    NoFields,
+    #[error("Arrow file is missing the {field} field, was it written by cawlr?")]
+    MissingField { field: String },
+    #[error(
+        "Chunk {chunk} is out of range of the {n_chunks} chunks, the index may be out of date"
+    )]
+    ChunkOutOfRange { chunk: usize, n_chunks: usize },
+    #[error("Failed to load arrow chunk")]
+    UnreadableChunk(#[source] arrow2::error::Error),
+    #[error(transparent)]
+    Format(#[from] arrow2::error::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(eyre::Report),
+}
+
+impl From<eyre::Report> for ArrowError {
+    fn from(e: eyre::Report) -> Self {
+        take_error::<ArrowError>(e)
+            .or_else(|e| take_error::<std::io::Error>(e).map(ArrowError::Io))
+            .unwrap_or_else(ArrowError::Other)
+    }
+}
+
+/// Compression codec for the record batches of Arrow outputs. Readers detect
+/// the codec, so outputs with any codec can be used as inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrowCompression {
+    None,
+    /// Fast, the default
+    Lz4,
+    /// Roughly a third smaller than LZ4 at some CPU cost, ie for archiving
+    Zstd,
+}
+
+impl ArrowCompression {
+    fn write_options(self) -> WriteOptions {
+        let compression = match self {
+            ArrowCompression::None => None,
+            ArrowCompression::Lz4 => Some(Compression::LZ4),
+            ArrowCompression::Zstd => Some(Compression::ZSTD),
+        };
+        WriteOptions { compression }
+    }
+}
+
+impl Default for ArrowCompression {
+    fn default() -> Self {
+        ArrowCompression::Lz4
+    }
+}
+
+impl FromStr for ArrowCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(ArrowCompression::None),
+            "lz4" => Ok(ArrowCompression::Lz4),
+            "zstd" => Ok(ArrowCompression::Zstd),
+            _ => Err(format!(
+                "Invalid compression {s}: either 'none', 'lz4', or 'zstd'"
+            )),
+        }
+    }
+}
+
+impl Display for ArrowCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrowCompression::None => write!(f, "none"),
+            ArrowCompression::Lz4 => write!(f, "lz4"),
+            ArrowCompression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Migrate an array from a chunk and deserialize it in batches of at most
+/// batch_size items, ie chunks of 2048 long reads from cawlr collapse that
+/// take gigabytes once loaded. None loads whole chunks. Slicing doesn't copy,
+/// so only one batch of items is in memory at a time.
+fn deserialize_batches<T, F>(
+    arr: Box<dyn Array>,
+    batch_size: Option<usize>,
+    mut func: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<T>) -> Result<()>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    let arr = migrate::<T>(arr)?;
+    let batch_size = match batch_size {
+        Some(batch_size) if arr.len() > batch_size => batch_size,
+        _ => return func(arr.try_into_collection()?),
+    };
+    let mut offset = 0;
+    while offset < arr.len() {
+        let len = batch_size.min(arr.len() - offset);
+        func(arr.sliced(offset, len).try_into_collection()?)?;
+        offset += len;
+    }
+    Ok(())
+}
 
 // pub struct ArrowWriter<W: Write>(FileWriter<W>);
 pub struct ArrowWriter<W: Write, T> {
@@ -39,6 +161,12 @@ impl<W: Write, T> ArrowWriter<W, T> {
             _type: PhantomData,
         }
     }
+
+    /// Writes the file footer, must be called once all data is saved
+    pub fn finish(&mut self) -> Result<()> {
+        self.inner.finish()?;
+        Ok(())
+    }
 }
 
 /// Helper trait to wrap Writers for saving Arrow files. Only needs to implement
@@ -48,16 +176,28 @@ impl<W: Write, T> ArrowWriter<W, T> {
 pub trait SchemaExt: ArrowField {
     fn type_as_str() -> &'static str;
     fn wrap_writer<W: Write>(writer: W) -> Result<ArrowWriter<W, Self>>
+    where
+        Self: Sized,
+    {
+        Self::wrap_writer_with(writer, ArrowCompression::default())
+    }
+
+    fn wrap_writer_with<W: Write>(
+        writer: W,
+        compression: ArrowCompression,
+    ) -> Result<ArrowWriter<W, Self>>
     where
         Self: Sized,
     {
         let data_type = Self::data_type();
         let str_type = Self::type_as_str();
         let schema = Schema::from(vec![Field::new(str_type, data_type, false)]);
-        let options = WriteOptions {
-            compression: Some(Compression::LZ4),
-        };
-        let fw = FileWriter::try_new(writer, schema, None, options)?;
+        let fw = FileWriter::try_new(
+            writer,
+            with_file_metadata(&schema, compression),
+            None,
+            compression.write_options(),
+        )?;
         Ok(ArrowWriter::new(fw))
     }
 }
@@ -74,19 +214,126 @@ impl SchemaExt for ScoredRead {
     }
 }
 
-/// Wraps writer for use later with [save].
+impl SchemaExt for SmaRead {
+    fn type_as_str() -> &'static str {
+        "sma"
+    }
+}
+
+/// Wraps writer for use later with [save], compressed with the default codec,
+/// see [wrap_writer_with] for others.
 pub fn wrap_writer<W>(writer: W, schema: &Schema) -> Result<FileWriter<W>>
 where
     W: Write,
 {
-    let options = WriteOptions {
-        compression: Some(Compression::LZ4),
-    };
-    let fw = FileWriter::try_new(writer, schema.clone(), None, options)?;
+    wrap_writer_with(writer, schema, ArrowCompression::default())
+}
+
+/// Like [wrap_writer] with a specific codec
+pub fn wrap_writer_with<W>(
+    writer: W,
+    schema: &Schema,
+    compression: ArrowCompression,
+) -> Result<FileWriter<W>>
+where
+    W: Write,
+{
+    let fw = FileWriter::try_new(
+        writer,
+        with_file_metadata(schema, compression),
+        None,
+        compression.write_options(),
+    )?;
     Ok(fw)
 }
 
-/// Writes data to Arrow file
+/// Schema metadata key recording the version of the cawlr types a file was
+/// written with, see [schema_version]
+pub const SCHEMA_VERSION_KEY: &str = "cawlr.schema_version";
+
+/// Version of the cawlr types written to Arrow files. Bump this when a field is
+/// added or removed, so files written by newer versions can be detected.
+///
+/// 1. Schema version recorded, mapping information and sample in metadata
+/// 2. Number of measurements and sub-scores in scores, haplotype and phase set
+///    in metadata
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Schema metadata key recording the version of cawlr that wrote a file
+pub const CAWLR_VERSION_KEY: &str = "cawlr.version";
+
+/// Schema metadata key recording the command line that wrote a file
+pub const COMMAND_KEY: &str = "cawlr.command";
+
+/// Schema metadata key recording the codec of a file, see [ArrowCompression]
+pub const COMPRESSION_KEY: &str = "cawlr.compression";
+
+/// Add the schema version and how the file was created to the schema metadata
+fn with_file_metadata(schema: &Schema, compression: ArrowCompression) -> Schema {
+    let mut schema = schema.clone();
+    let metadata = [
+        (SCHEMA_VERSION_KEY, SCHEMA_VERSION.to_string()),
+        (CAWLR_VERSION_KEY, env!("CARGO_PKG_VERSION").to_string()),
+        (COMMAND_KEY, std::env::args().join(" ")),
+        (COMPRESSION_KEY, compression.to_string()),
+    ];
+    for (key, value) in metadata {
+        schema.metadata.insert(key.to_string(), value);
+    }
+    schema
+}
+
+/// Schema metadata of an Arrow file, ie the keys recorded when it was written,
+/// see [SCHEMA_VERSION_KEY] and [COMMAND_KEY]. The reader is rewound to the
+/// start afterwards.
+pub fn file_metadata<R>(reader: &mut R) -> Result<arrow2::datatypes::Metadata, ArrowError>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(metadata.schema.metadata)
+}
+
+fn metadata_version(metadata: &FileMetadata) -> u32 {
+    metadata
+        .schema
+        .metadata
+        .get(SCHEMA_VERSION_KEY)
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Version of the cawlr types an Arrow file was written with, 0 for files
+/// written before versions were recorded. The reader is rewound to the start
+/// afterwards.
+pub fn schema_version<R>(reader: &mut R) -> Result<u32, ArrowError>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(metadata_version(&metadata))
+}
+
+/// Read the metadata of a file to load, warning if it was written by a newer
+/// version of cawlr. Fields it doesn't know are ignored, see [migrate].
+fn read_metadata<R>(reader: &mut R) -> Result<FileMetadata>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    let version = metadata_version(&metadata);
+    if version > SCHEMA_VERSION {
+        log::warn!(
+            "Arrow file was written by a newer version of cawlr (schema version {version}, \
+             expected {SCHEMA_VERSION}), fields added since are ignored"
+        );
+    }
+    Ok(metadata)
+}
+
+/// Writes data to Arrow file, nothing is written for an empty slice
 pub fn save<W, T>(writer: &mut FileWriter<W>, x: &[T]) -> Result<()>
 where
     T: ArrowField<Type = T> + ArrowSerialize + 'static,
@@ -111,15 +358,208 @@ where
     Ok(())
 }
 
+/// Convert an array from an older or newer version of cawlr to the current
+/// type.
+///
+/// Fields added to a struct since the file was written, such as the mapping
+/// information in [Metadata](super::metadata::Metadata), are filled with nulls.
+/// Added fields are always nullable, so a missing field that isn't is an error
+/// rather than being filled with made up values. Fields the current type
+/// doesn't have, ie from a newer version, are dropped.
+/// Structs inside lists, such as [Signal](super::signal::Signal), are migrated
+/// the same way.
+pub(crate) fn migrate<T: ArrowField>(arr: Box<dyn Array>) -> Result<Box<dyn Array>> {
+    migrate_to(arr, &T::data_type())
+}
+
+fn migrate_to(arr: Box<dyn Array>, target: &DataType) -> Result<Box<dyn Array>> {
+    if arr.data_type() == target {
+        return Ok(arr);
+    }
+    match target {
+        DataType::Struct(fields) => match arr.as_any().downcast_ref::<StructArray>() {
+            Some(arr) => migrate_struct(arr, target, fields),
+            None => Ok(arr),
+        },
+        DataType::List(field) => match arr.as_any().downcast_ref::<ListArray<i32>>() {
+            Some(arr) => migrate_list(arr, target, field),
+            None => Ok(arr),
+        },
+        DataType::LargeList(field) => match arr.as_any().downcast_ref::<ListArray<i64>>() {
+            Some(arr) => migrate_list(arr, target, field),
+            None => Ok(arr),
+        },
+        // Left for deserialization to report the mismatch
+        _ => Ok(arr),
+    }
+}
+
+fn migrate_list<O: Offset>(
+    arr: &ListArray<O>,
+    target: &DataType,
+    target_field: &Field,
+) -> Result<Box<dyn Array>> {
+    let values = migrate_to(arr.values().clone(), &target_field.data_type)?;
+    let migrated = ListArray::try_new(
+        target.clone(),
+        arr.offsets().clone(),
+        values,
+        arr.validity().cloned(),
+    )?;
+    Ok(migrated.boxed())
+}
+
+fn migrate_struct(
+    arr: &StructArray,
+    target: &DataType,
+    target_fields: &[Field],
+) -> Result<Box<dyn Array>> {
+    let fields = StructArray::get_fields(arr.data_type());
+    let values = target_fields
+        .iter()
+        .map(
+            |target_field| match fields.iter().position(|f| f.name == target_field.name) {
+                Some(idx) => migrate_to(arr.values()[idx].clone(), &target_field.data_type),
+                None if target_field.is_nullable => {
+                    Ok(new_null_array(target_field.data_type.clone(), arr.len()))
+                }
+                None => Err(ArrowError::MissingField {
+                    field: target_field.name.clone(),
+                }
+                .into()),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let migrated = StructArray::try_new(target.clone(), values, arr.validity().cloned())?;
+    Ok(migrated.boxed())
+}
+
 pub(crate) fn load<R>(mut reader: R) -> Result<FileReader<R>>
 where
     R: Read + Seek,
 {
-    let metadata = read_file_metadata(&mut reader)?;
+    let metadata = read_metadata(&mut reader)?;
     let reader = FileReader::new(reader, metadata, None, None);
     Ok(reader)
 }
 
+/// Number of chunks in an Arrow file, rewinds the reader afterwards.
+pub(crate) fn n_chunks<R>(reader: &mut R) -> Result<usize>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(metadata.blocks.len())
+}
+
+/// Schema metadata key recording whether the signals of collapsed reads kept
+/// their raw samples
+pub const SAMPLES_KEY: &str = "cawlr.samples";
+
+/// Schema metadata key recording what the reads were sequenced from and
+/// aligned to, see [ReadMode]
+pub const MODE_KEY: &str = "cawlr.mode";
+
+/// What the reads were sequenced from and aligned to. Decides whether cawlr
+/// collapse reverse complements the kmers of minus strand reads, so models
+/// and reads need to match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadMode {
+    /// DNA aligned to a genome, the default
+    #[default]
+    DnaGenome,
+    /// Direct RNA aligned to a genome, kmers are already on the strand of the
+    /// RNA
+    Rna,
+    /// Reads aligned to a transcriptome, every read is on the plus strand of
+    /// its transcript
+    Transcriptome,
+}
+
+impl FromStr for ReadMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dna-genome" => Ok(ReadMode::DnaGenome),
+            "rna" => Ok(ReadMode::Rna),
+            "transcriptome" => Ok(ReadMode::Transcriptome),
+            _ => Err(format!(
+                "Invalid mode {s}: either 'dna-genome', 'rna', or 'transcriptome'"
+            )),
+        }
+    }
+}
+
+impl Display for ReadMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadMode::DnaGenome => write!(f, "dna-genome"),
+            ReadMode::Rna => write!(f, "rna"),
+            ReadMode::Transcriptome => write!(f, "transcriptome"),
+        }
+    }
+}
+
+/// Eventalign schema recording whether raw samples are kept and the mode
+/// reads were collapsed with, see [has_samples] and [read_mode]
+pub fn eventalign_schema(samples: bool, mode: ReadMode) -> Schema {
+    let mut metadata = arrow2::datatypes::Metadata::new();
+    metadata.insert(SAMPLES_KEY.to_string(), samples.to_string());
+    metadata.insert(MODE_KEY.to_string(), mode.to_string());
+    Eventalign::schema().with_metadata(metadata)
+}
+
+/// Mode of the reads in an Arrow file from cawlr collapse, rewinds the reader
+/// afterwards. Files written before this was recorded are DNA aligned to a
+/// genome.
+pub fn read_mode<R>(reader: &mut R) -> Result<ReadMode, ArrowError>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    match metadata.schema.metadata.get(MODE_KEY) {
+        Some(mode) => mode
+            .parse()
+            .map_err(|e: String| ArrowError::Other(eyre::eyre!(e))),
+        None => Ok(ReadMode::default()),
+    }
+}
+
+/// Whether the signals in an Arrow file from cawlr collapse kept their raw
+/// samples, rewinds the reader afterwards. Files written before this was
+/// recorded always have samples.
+pub fn has_samples<R>(reader: &mut R) -> Result<bool, ArrowError>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(metadata
+        .schema
+        .metadata
+        .get(SAMPLES_KEY)
+        .map_or(true, |samples| samples != "false"))
+}
+
+/// Type of the records in an Arrow file written by cawlr, ie "eventalign" or
+/// "scored". The reader is rewound to the start afterwards.
+pub fn arrow_type<R>(reader: &mut R) -> Result<String, ArrowError>
+where
+    R: Read + Seek,
+{
+    let metadata = read_file_metadata(reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+    metadata
+        .schema
+        .fields
+        .first()
+        .map(|field| field.name.clone())
+        .ok_or(ArrowError::NoFields)
+}
+
 pub fn is_arrow_file<P>(path: P) -> bool
 where
     P: AsRef<Path>,
@@ -132,6 +572,68 @@ where
     is_arrow().is_ok()
 }
 
+/// Type of the reads in an Arrow file from cawlr collapse, score, or sma
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrowKind {
+    Eventalign,
+    Scored,
+    Sma,
+}
+
+impl ArrowKind {
+    /// Type of the reads in the file, failing for Arrow files not written by
+    /// cawlr collapse, score, or sma
+    pub fn of<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let kind = arrow_type(&mut File::open(path)?)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        match kind.as_str() {
+            "eventalign" => Ok(ArrowKind::Eventalign),
+            "scored" => Ok(ArrowKind::Scored),
+            "sma" => Ok(ArrowKind::Sma),
+            _ => eyre::bail!("Expected output from cawlr collapse, score, or sma, found {kind}"),
+        }
+    }
+}
+
+impl Display for ArrowKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrowKind::Eventalign => write!(f, "{}", Eventalign::type_as_str()),
+            ArrowKind::Scored => write!(f, "{}", ScoredRead::type_as_str()),
+            ArrowKind::Sma => write!(f, "{}", SmaRead::type_as_str()),
+        }
+    }
+}
+
+/// Code generic over the type of reads, ie [Eventalign], [ScoredRead], or
+/// [SmaRead], run with the type matching an Arrow file by
+/// [dispatch_arrow_kind]
+pub trait ArrowKindFn {
+    type Output;
+
+    fn call<T>(self, kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator;
+}
+
+/// Run f with the type of reads of kind
+pub fn dispatch_arrow_kind<F: ArrowKindFn>(kind: ArrowKind, f: F) -> Result<F::Output> {
+    match kind {
+        ArrowKind::Eventalign => f.call::<Eventalign>(kind),
+        ArrowKind::Scored => f.call::<ScoredRead>(kind),
+        ArrowKind::Sma => f.call::<SmaRead>(kind),
+    }
+}
+
 /// Apply a function to chunks of data loaded from an Arrow Feather File.
 ///
 /// # Example
@@ -159,7 +661,24 @@ where
 /// # Ok(())
 /// # }
 /// ```
-pub fn load_apply<R, F, T>(reader: R, mut func: F) -> Result<()>
+pub fn load_apply<R, F, T>(reader: R, func: F) -> Result<(), ArrowError>
+where
+    R: Read + Seek,
+    F: FnMut(Vec<T>) -> eyre::Result<()>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    load_apply_batched(reader, None, func)
+}
+
+/// Like [load_apply], but chunks with more than batch_size items are split
+/// into batches before they're deserialized, and the function is called once
+/// per batch to lower peak memory on long reads. None loads whole chunks.
+pub fn load_apply_batched<R, F, T>(
+    reader: R,
+    batch_size: Option<usize>,
+    mut func: F,
+) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     F: FnMut(Vec<T>) -> eyre::Result<()>,
@@ -170,8 +689,7 @@ where
     for read in feather {
         if let Ok(chunk) = read {
             for arr in chunk.into_arrays().into_iter() {
-                let eventaligns: Vec<T> = arr.try_into_collection()?;
-                func(eventaligns)?;
+                deserialize_batches(arr, batch_size, &mut func)?;
             }
         } else {
             log::warn!("Failed to load arrow chunk")
@@ -180,7 +698,7 @@ where
     Ok(())
 }
 
-pub fn load_apply2<R, F, T>(reader: R, mut func: F) -> Result<()>
+pub fn load_apply2<R, F, T>(reader: R, mut func: F) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     F: FnMut(T) -> Result<()>,
@@ -189,7 +707,8 @@ where
 {
     let feather = load(reader)?;
     for chunk in feather {
-        for arr in chunk?.arrays() {
+        for arr in chunk.map_err(ArrowError::UnreadableChunk)?.into_arrays() {
+            let arr = migrate::<T>(arr)?;
             let iter = arrow_array_deserialize_iterator(arr.as_ref())?;
             for x in iter {
                 func(x)?;
@@ -200,22 +719,43 @@ where
 }
 
 /// Trying different ways if iterating over files, can be deleted safely
-pub fn load_apply_indy<R, F, T>(reader: R, mut func: F) -> Result<()>
+pub fn load_apply_indy<R, F, T>(reader: R, func: F) -> Result<(), ArrowError>
+where
+    R: Read + Seek,
+    F: FnMut(T) -> eyre::Result<()>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    Ok(load_apply_indy_chunked(reader, func, |_| ())?)
+}
+
+/// Same as [load_apply_indy], also calling chunk_done with the number of
+/// items after each chunk, ie to report progress
+pub(crate) fn load_apply_indy_chunked<R, F, G, T>(
+    reader: R,
+    mut func: F,
+    mut chunk_done: G,
+) -> Result<()>
 where
     R: Read + Seek,
     F: FnMut(T) -> eyre::Result<()>,
+    G: FnMut(usize),
     T: ArrowField<Type = T> + ArrowDeserialize + 'static,
     for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
 {
     let feather = load(reader)?;
     for read in feather {
         if let Ok(chunk) = read {
+            let mut n_items = 0;
             for arr in chunk.into_arrays().into_iter() {
+                let arr = migrate::<T>(arr)?;
                 let iter = arrow_array_deserialize_iterator(arr.borrow())?;
                 for x in iter {
                     func(x)?;
+                    n_items += 1;
                 }
             }
+            chunk_done(n_items);
         } else {
             log::warn!("Failed to load arrow chunk")
         }
@@ -231,7 +771,7 @@ pub fn load_read_write<R, W, F, T, U>(
     reader: R,
     mut writer: FileWriter<W>,
     mut func: F,
-) -> Result<()>
+) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     W: Write,
@@ -244,9 +784,10 @@ where
     for read in feather {
         if let Ok(chunk) = read {
             for arr in chunk.into_arrays().into_iter() {
-                let eventaligns: Vec<T> = arr.try_into_collection()?;
-                let res = func(eventaligns)?;
-                save(&mut writer, &res)?;
+                deserialize_batches(arr, None, |eventaligns| {
+                    let res = func(eventaligns)?;
+                    save(&mut writer, &res)
+                })?;
             }
         } else {
             log::warn!("Failed to load arrow chunk")
@@ -257,7 +798,140 @@ where
 }
 
 /// Takes a ArrowWriter instead of FileWriter to avoid exposing FileWriter
-pub fn load_read_write_arrow<R, W, F, T, U>(reader: R, writer: W, mut func: F) -> Result<()>
+/// Lazily read an Arrow file one chunk of reads at a time, for callers that
+/// can't pass a closure to [load_apply], ie language bindings.
+pub fn load_chunks<R, T>(
+    reader: R,
+) -> Result<impl Iterator<Item = Result<Vec<T>, ArrowError>>, ArrowError>
+where
+    R: Read + Seek,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    let reader = load(reader)?;
+    Ok(reader.map(|chunk| -> Result<Vec<T>, ArrowError> {
+        let chunk = chunk.map_err(ArrowError::UnreadableChunk)?;
+        let mut reads = Vec::new();
+        for arr in chunk.into_arrays().into_iter() {
+            let xs: Vec<T> = migrate::<T>(arr)?.try_into_collection()?;
+            reads.extend(xs);
+        }
+        Ok(reads)
+    }))
+}
+
+/// Lazily read the arrays of an Arrow file without deserializing them, ie to
+/// hand them to other Arrow implementations without copying. Arrays from
+/// older versions of cawlr are migrated to the current schema of T, which is
+/// described by the returned field.
+pub fn load_arrays<R, T>(
+    mut reader: R,
+) -> Result<
+    (
+        Field,
+        impl Iterator<Item = Result<Box<dyn Array>, ArrowError>>,
+    ),
+    ArrowError,
+>
+where
+    R: Read + Seek,
+    T: ArrowField<Type = T> + 'static,
+{
+    let metadata = read_metadata(&mut reader)?;
+    let name = metadata
+        .schema
+        .fields
+        .first()
+        .map(|field| field.name.clone())
+        .ok_or(ArrowError::NoFields)?;
+    let field = Field::new(name, T::data_type(), false);
+    let reader = FileReader::new(reader, metadata, None, None);
+    let arrays = reader.flat_map(|chunk| match chunk {
+        Ok(chunk) => chunk
+            .into_arrays()
+            .into_iter()
+            .map(|arr| migrate::<T>(arr).map_err(ArrowError::from))
+            .collect::<Vec<_>>(),
+        Err(e) => vec![Err(ArrowError::UnreadableChunk(e))],
+    });
+    Ok((field, arrays))
+}
+
+/// Like [load_apply], but only reads the chunks at the given indices, ie the
+/// chunks of one chromosome from [crate::index::chrom_blocks].
+pub fn load_blocks_apply<R, F, T>(reader: R, blocks: &[usize], func: F) -> Result<(), ArrowError>
+where
+    R: Read + Seek,
+    F: FnMut(Vec<T>) -> eyre::Result<()>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    load_blocks_apply_batched(reader, blocks, None, func)
+}
+
+/// Like [load_blocks_apply], splitting chunks into batches like
+/// [load_apply_batched]
+pub fn load_blocks_apply_batched<R, F, T>(
+    mut reader: R,
+    blocks: &[usize],
+    batch_size: Option<usize>,
+    mut func: F,
+) -> Result<(), ArrowError>
+where
+    R: Read + Seek,
+    F: FnMut(Vec<T>) -> eyre::Result<()>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    let metadata = read_metadata(&mut reader)?;
+    let mut message_scratch = Vec::new();
+    let mut data_scratch = Vec::new();
+    let dictionaries = read_file_dictionaries(&mut reader, &metadata, &mut data_scratch)?;
+    for &block in blocks {
+        if block >= metadata.blocks.len() {
+            return Err(ArrowError::ChunkOutOfRange {
+                chunk: block,
+                n_chunks: metadata.blocks.len(),
+            });
+        }
+        let chunk = read_batch(
+            &mut reader,
+            &dictionaries,
+            &metadata,
+            None,
+            None,
+            block,
+            &mut message_scratch,
+            &mut data_scratch,
+        )?;
+        for arr in chunk.into_arrays().into_iter() {
+            deserialize_batches(arr, batch_size, &mut func)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn load_read_write_arrow<R, W, F, T, U>(reader: R, writer: W, func: F) -> Result<(), ArrowError>
+where
+    R: Read + Seek,
+    W: Write,
+    F: FnMut(Vec<T>) -> eyre::Result<Vec<U>>,
+    T: ArrowField<Type = T> + ArrowDeserialize + 'static,
+    U: ArrowField<Type = U> + ArrowSerialize + 'static + SchemaExt,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    load_read_write_arrow_with(reader, writer, ArrowCompression::default(), None, func)
+}
+
+/// Like [load_read_write_arrow], with the codec of the output and splitting
+/// chunks into batches like [load_apply_batched]
+pub fn load_read_write_arrow_with<R, W, F, T, U>(
+    reader: R,
+    writer: W,
+    compression: ArrowCompression,
+    batch_size: Option<usize>,
+    mut func: F,
+) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     W: Write,
@@ -267,24 +941,26 @@ where
     for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
 {
     let feather = load(reader)?;
-    let mut writer = U::wrap_writer(writer)?;
-    for read in feather {
-        if let Ok(chunk) = read {
+    let mut writer = U::wrap_writer_with(writer, compression)?;
+    let apply = || -> Result<(), ArrowError> {
+        for read in feather {
+            let chunk = read.map_err(ArrowError::UnreadableChunk)?;
             for arr in chunk.into_arrays().into_iter() {
-                let eventaligns: Vec<T> = arr.try_into_collection()?;
-                let res = func(eventaligns)?;
-                save_t(&mut writer, &res)?;
+                deserialize_batches(arr, batch_size, |eventaligns| {
+                    let res = func(eventaligns)?;
+                    save_t(&mut writer, &res)
+                })?;
             }
-        } else {
-            log::error!("Failed to load arrow chunk");
-            return Err(eyre::eyre!("Failed to load arrow chunk"));
         }
-    }
+        Ok(())
+    };
+    let res = apply();
+    // Finish even if func failed, so chunks already written stay readable
     writer.inner.finish()?;
-    Ok(())
+    res
 }
 
-pub fn load_read_arrow<R, F, T>(reader: R, mut func: F) -> Result<()>
+pub fn load_read_arrow<R, F, T>(reader: R, mut func: F) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     F: FnMut(Vec<T>) -> eyre::Result<()>,
@@ -293,27 +969,34 @@ where
 {
     let feather = load(reader)?;
     for read in feather {
-        if let Ok(chunk) = read {
-            for arr in chunk.into_arrays().into_iter() {
-                let eventaligns: Vec<T> = arr.try_into_collection()?;
-                func(eventaligns)?;
-            }
-        } else {
-            log::error!("Failed to load arrow chunk");
-            return Err(eyre::eyre!("Failed to load arrow chunk"));
+        let chunk = read.map_err(ArrowError::UnreadableChunk)?;
+        for arr in chunk.into_arrays().into_iter() {
+            deserialize_batches(arr, None, &mut func)?;
         }
     }
     Ok(())
 }
 
-fn block_bar(n_blocks: u64) -> Result<ProgressBar, TemplateError> {
-    let style = ProgressStyle::default_bar().template("[{elapsed}] - {percent}% - {bar}")?;
+fn block_bar(n_blocks: u64, show: bool) -> Result<ProgressBar, TemplateError> {
+    if !show {
+        return Ok(ProgressBar::hidden());
+    }
+    let style =
+        ProgressStyle::default_bar().template("[{elapsed}] - {percent}% - {bar} (ETA {eta})")?;
     let pb = ProgressBar::new(n_blocks).with_style(style);
 
     Ok(pb)
 }
 
-pub fn load_read_arrow_measured<R, F, T>(reader: R, mut func: F) -> Result<()>
+/// Like [load_read_arrow], drawing a bar of the chunks read so far on stderr
+/// if show_bar is set, and splitting chunks into batches like
+/// [load_apply_batched]
+pub fn load_read_arrow_measured<R, F, T>(
+    reader: R,
+    batch_size: Option<usize>,
+    show_bar: bool,
+    mut func: F,
+) -> Result<(), ArrowError>
 where
     R: Read + Seek,
     F: FnMut(Vec<T>) -> eyre::Result<()>,
@@ -322,18 +1005,13 @@ where
 {
     let feather = load(reader)?;
     let n_blocks = feather.metadata().blocks.len();
-    let pb = block_bar(n_blocks as u64)?;
+    let pb = block_bar(n_blocks as u64, show_bar).map_err(eyre::Report::from)?;
     for read in feather {
-        if let Ok(chunk) = read {
-            for arr in chunk.into_arrays().into_iter() {
-                let eventaligns: Vec<T> = arr.try_into_collection()?;
-                func(eventaligns)?;
-            }
-        } else {
-            log::error!("Failed to load arrow chunk");
-            return Err(eyre::eyre!("Failed to load arrow chunk"));
+        let chunk = read.map_err(ArrowError::UnreadableChunk)?;
+        for arr in chunk.into_arrays().into_iter() {
+            deserialize_batches(arr, batch_size, &mut func)?;
         }
-        pb.tick();
+        pb.inc(1);
     }
     pb.finish();
     Ok(())
@@ -353,6 +1031,7 @@ where
         .map(|q| {
             q.map(|b| {
                 b.flat_map(|r| {
+                    let r = migrate::<Eventalign>(r).unwrap();
                     let v: Vec<Eventalign> = r.try_into_collection().unwrap();
                     v
                 })
@@ -381,11 +1060,271 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::io::Cursor;
+
+    use arrow2_convert::{ArrowDeserialize, ArrowField, ArrowSerialize};
+
     use super::*;
+    use crate::{
+        arrow::{
+            metadata::{MetadataExt, Strand},
+            signal::Signal,
+        },
+        test_data::TestRead,
+    };
 
     #[test]
     fn test_is_arrow_file() {
         let path = "extra/modbams/MM-double.bam";
         assert!(!is_arrow_file(path))
     }
+
+    /// Metadata before mapping information was added
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct OldMetadata {
+        name: String,
+        chrom: String,
+        start: u64,
+        length: u64,
+        strand: Strand,
+        seq: String,
+    }
+
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct OldEventalign {
+        metadata: OldMetadata,
+        signal_data: Vec<Signal>,
+    }
+
+    #[test]
+    fn test_deserialize_batches() -> Result<()> {
+        let reads = (0..5)
+            .map(|i| {
+                let metadata = TestRead::new(format!("read{i}"))
+                    .span(i * 10, 10)
+                    .metadata();
+                let signal = Signal::new(i * 10, "AAAAAA".to_string(), 80.0, 0.01, vec![1.0; 3]);
+                Eventalign::new(metadata, vec![signal])
+            })
+            .collect::<Vec<_>>();
+        let arr: Box<dyn Array> = reads.try_into_arrow()?;
+
+        for (batch_size, expected) in [
+            (None, vec![5]),
+            (Some(2), vec![2, 2, 1]),
+            (Some(5), vec![5]),
+        ] {
+            let mut lens = Vec::new();
+            let mut loaded = Vec::new();
+            deserialize_batches(arr.clone(), batch_size, |xs: Vec<Eventalign>| {
+                lens.push(xs.len());
+                loaded.extend(xs);
+                Ok(())
+            })?;
+            assert_eq!(lens, expected);
+            assert_eq!(loaded, reads);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocks_out_of_range() -> Result<()> {
+        let schema = Schema::from(vec![Field::new(
+            "eventalign",
+            OldEventalign::data_type(),
+            false,
+        )]);
+        let mut writer = wrap_writer(Vec::new(), &schema)?;
+        save(&mut writer, &[OldEventalign::default()])?;
+        writer.finish()?;
+
+        let reader = Cursor::new(writer.into_inner());
+        let err = load_blocks_apply(reader, &[1], |_: Vec<Eventalign>| Ok(()))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ArrowError::ChunkOutOfRange {
+                chunk: 1,
+                n_chunks: 1
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_old_metadata() -> Result<()> {
+        let old = OldEventalign {
+            metadata: OldMetadata {
+                name: "read".to_string(),
+                chrom: "chrI".to_string(),
+                start: 10,
+                length: 20,
+                strand: Strand::minus(),
+                seq: String::new(),
+            },
+            signal_data: vec![Signal::new(11, "AAAAAA".to_string(), 80.0, 0.01, vec![])],
+        };
+        let schema = Schema::from(vec![Field::new(
+            "eventalign",
+            OldEventalign::data_type(),
+            false,
+        )]);
+        let mut writer = wrap_writer(Vec::new(), &schema)?;
+        save(&mut writer, &[old.clone(), old])?;
+        writer.finish()?;
+
+        let mut reads = Vec::new();
+        load_apply(
+            Cursor::new(writer.into_inner()),
+            |chunk: Vec<Eventalign>| {
+                reads.extend(chunk);
+                Ok(())
+            },
+        )?;
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].name(), "read");
+        assert_eq!(reads[0].strand(), Strand::minus());
+        assert_eq!(reads[0].signal_iter().count(), 1);
+        assert_eq!(reads[0].mapq(), None);
+        assert_eq!(reads[0].identity(), None);
+        assert_eq!(reads[0].sample(), None);
+        Ok(())
+    }
+
+    /// Signal with a field from a newer version
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct NewSignal {
+        pos: u64,
+        kmer: String,
+        signal_mean: f64,
+        signal_time: f64,
+        samples: Vec<f64>,
+        n_stalls: u32,
+    }
+
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct NewEventalign {
+        metadata: OldMetadata,
+        signal_data: Vec<NewSignal>,
+        basecaller: String,
+    }
+
+    #[test]
+    fn test_schema_evolution() -> Result<()> {
+        let new = NewEventalign {
+            metadata: OldMetadata {
+                name: "read".to_string(),
+                ..Default::default()
+            },
+            signal_data: vec![NewSignal {
+                pos: 11,
+                kmer: "AAAAAA".to_string(),
+                signal_mean: 80.0,
+                signal_time: 0.01,
+                samples: vec![79.0, 81.0],
+                n_stalls: 2,
+            }],
+            basecaller: "dorado".to_string(),
+        };
+        let schema = Schema::from(vec![Field::new(
+            "eventalign",
+            NewEventalign::data_type(),
+            false,
+        )]);
+        let mut writer = wrap_writer(Vec::new(), &schema)?;
+        save(&mut writer, &[new])?;
+        writer.finish()?;
+        let mut file = Cursor::new(writer.into_inner());
+        assert_eq!(schema_version(&mut file)?, SCHEMA_VERSION);
+
+        let mut reads = Vec::new();
+        load_apply(file, |chunk: Vec<Eventalign>| {
+            reads.extend(chunk);
+            Ok(())
+        })?;
+        assert_eq!(reads[0].name(), "read");
+        let signal = reads[0].signal_iter().next().unwrap();
+        assert_eq!(signal.pos, 11);
+        assert_eq!(signal.signal_mean, 80.0);
+        assert_eq!(signal.samples, [79.0, 81.0]);
+
+        // Files from before versions were recorded
+        let mut writer = FileWriter::try_new(Vec::new(), schema, None, Default::default())?;
+        writer.finish()?;
+        assert_eq!(schema_version(&mut Cursor::new(writer.into_inner()))?, 0);
+        Ok(())
+    }
+
+    /// Signal missing the raw samples, which every version of cawlr writes
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct NoSamplesSignal {
+        pos: u64,
+        kmer: String,
+        signal_mean: f64,
+        signal_time: f64,
+    }
+
+    #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default)]
+    struct NoSamplesEventalign {
+        metadata: OldMetadata,
+        signal_data: Vec<NoSamplesSignal>,
+    }
+
+    #[test]
+    fn test_migrate_missing_required_field() -> Result<()> {
+        let schema = Schema::from(vec![Field::new(
+            "eventalign",
+            NoSamplesEventalign::data_type(),
+            false,
+        )]);
+        let mut writer = wrap_writer(Vec::new(), &schema)?;
+        let read = NoSamplesEventalign {
+            signal_data: vec![NoSamplesSignal::default()],
+            ..Default::default()
+        };
+        save(&mut writer, &[read])?;
+        writer.finish()?;
+
+        let err = load_apply(
+            Cursor::new(writer.into_inner()),
+            |_: Vec<Eventalign>| Ok(()),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ArrowError::MissingField { field } if field == "samples"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_chunks_and_arrays() -> Result<()> {
+        let schema = Schema::from(vec![Field::new(
+            "eventalign",
+            OldEventalign::data_type(),
+            false,
+        )]);
+        let mut writer = wrap_writer(Vec::new(), &schema)?;
+        save(
+            &mut writer,
+            &[OldEventalign::default(), OldEventalign::default()],
+        )?;
+        save(&mut writer, &[OldEventalign::default()])?;
+        writer.finish()?;
+        let file = writer.into_inner();
+
+        let chunks = load_chunks(Cursor::new(file.clone()))?
+            .map(|chunk| chunk.map(|reads: Vec<Eventalign>| reads.len()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(chunks, vec![2, 1]);
+
+        let (field, arrays) = load_arrays::<_, Eventalign>(Cursor::new(file))?;
+        assert_eq!(field.name, "eventalign");
+        assert_eq!(field.data_type, Eventalign::data_type());
+        for arr in arrays {
+            assert_eq!(arr?.data_type(), &Eventalign::data_type());
+        }
+        Ok(())
+    }
 }
@@ -4,7 +4,7 @@ use arrow2_convert::ArrowSerialize;
 use arrow2_convert::{field::ArrowField, ArrowField};
 
 use super::{
-    metadata::{Metadata, MetadataExt},
+    metadata::{Metadata, MetadataExt, MetadataMutExt},
     signal::Signal,
 };
 
@@ -38,6 +38,16 @@ impl Eventalign {
         self.signal_data.iter()
     }
 
+    /// Signal data of the read, ordered as in the eventalign output
+    pub fn signals(&self) -> &[Signal] {
+        &self.signal_data
+    }
+
+    /// Iterate over the zero-based position and mean current of each signal
+    pub fn iter_signal_means(&self) -> impl Iterator<Item = (u64, f64)> + '_ {
+        self.signal_data.iter().map(|s| (s.pos, s.signal_mean))
+    }
+
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
@@ -48,3 +58,9 @@ impl MetadataExt for Eventalign {
         &self.metadata
     }
 }
+
+impl MetadataMutExt for Eventalign {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
@@ -1,14 +1,94 @@
-use std::{fs::File, io, path::Path};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+use eyre::WrapErr;
 
 use super::{
-    arrow_utils::{is_arrow_file, load_apply_indy},
+    arrow_utils::{is_arrow_file, load_apply_indy_chunked, n_chunks},
     mod_bam::{BamRecords, ModBamIter},
     scored_read::ScoredRead,
 };
+use crate::{
+    progress::Reporter,
+    utils::{self, FaiIndex},
+};
+
+/// Number of reads from a modification bam file between progress updates
+const MOD_BAM_CHUNK_SIZE: usize = 4096;
 
 pub enum ModFile {
     Arrow(File),
-    ModBam { file: File, mod_tag: Vec<u8> },
+    ModBam {
+        file: File,
+        mod_tag: Vec<u8>,
+    },
+    ModSam {
+        file: File,
+        mod_tag: Vec<u8>,
+    },
+    ModCram {
+        records: BamRecords,
+        mod_tag: Vec<u8>,
+    },
+}
+
+/// Input formats detected by [ModFile::open_path]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputFormat {
+    Arrow,
+    Bam,
+    Sam,
+    Cram,
+}
+
+impl InputFormat {
+    /// Detect from the extension, in any case, or the first few bytes of the
+    /// file if there isn't a known extension
+    fn detect<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let path = path.as_ref();
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("arrow") => return Some(InputFormat::Arrow),
+            Some("bam") => return Some(InputFormat::Bam),
+            Some("sam") => return Some(InputFormat::Sam),
+            Some("cram") => return Some(InputFormat::Cram),
+            _ => (),
+        }
+        let mut magic = [0; 4];
+        File::open(path).ok()?.read_exact(&mut magic).ok()?;
+        if &magic == b"CRAM" {
+            Some(InputFormat::Cram)
+        } else if magic[0] == b'@' || is_headerless_sam(path) {
+            Some(InputFormat::Sam)
+        } else if is_bam_file(path) {
+            Some(InputFormat::Bam)
+        } else if is_arrow_file(path) {
+            Some(InputFormat::Arrow)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the first line is a SAM record, for SAM files without a header
+fn is_headerless_sam(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut line = Vec::new();
+    if BufReader::new(file).read_until(b'\n', &mut line).is_err() {
+        return false;
+    }
+    // Eleven mandatory fields, with the flag and position as numbers
+    let fields = line.split(|&b| b == b'\t').collect::<Vec<_>>();
+    let is_number = |field: &[u8]| !field.is_empty() && field.iter().all(u8::is_ascii_digit);
+    fields.len() >= 11 && is_number(fields[1]) && is_number(fields[3])
 }
 
 impl ModFile {
@@ -29,64 +109,175 @@ impl ModFile {
         })
     }
 
+    pub fn open_mod_sam<P, B>(path: P, mod_tag: B) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        B: Into<Vec<u8>>,
+    {
+        let file = File::open(path)?;
+        Ok(Self::ModSam {
+            file,
+            mod_tag: mod_tag.into(),
+        })
+    }
+
+    /// Open a CRAM file, decoding the reads with the reference genome they
+    /// were aligned to, see [utils::ensure_faidx] for finding its index.
+    pub fn open_mod_cram<P, R, B>(
+        path: P,
+        mod_tag: B,
+        reference: R,
+        fai: &FaiIndex,
+    ) -> eyre::Result<Self>
+    where
+        P: AsRef<Path>,
+        R: AsRef<Path>,
+        B: Into<Vec<u8>>,
+    {
+        let (path, reference) = (path.as_ref(), reference.as_ref());
+        if utils::ensure_faidx(reference, fai)?.is_none() {
+            eyre::bail!(
+                "Reading CRAM files needs the index of {}, build it with samtools faidx",
+                reference.display()
+            );
+        }
+        let records = BamRecords::from_cram_path(path, reference)
+            .wrap_err_with(|| format!("Failed to read CRAM file {}", path.display()))?;
+        Ok(ModFile::ModCram {
+            records,
+            mod_tag: mod_tag.into(),
+        })
+    }
+
     pub fn open_path<P, B>(path: P, tag: Option<B>) -> eyre::Result<Self>
     where
         P: AsRef<Path>,
         B: Into<Vec<u8>>,
     {
+        ModFile::open_path_with_reference(path, tag, None::<&Path>, &FaiIndex::default())
+    }
+
+    /// Same as [ModFile::open_path], with the reference genome needed to read
+    /// CRAM files and where its index is
+    pub fn open_path_with_reference<P, B, R>(
+        path: P,
+        tag: Option<B>,
+        reference: Option<R>,
+        fai: &FaiIndex,
+    ) -> eyre::Result<Self>
+    where
+        P: AsRef<Path>,
+        B: Into<Vec<u8>>,
+        R: AsRef<Path>,
+    {
+        let Some(format) = InputFormat::detect(&path) else {
+            return Err(eyre::eyre!(
+                "Failed to detect input as .bam, .sam, .cram, or .arrow file"
+            ));
+        };
         let tag: Option<Vec<u8>> = tag.map(|t| t.into());
-        let mod_file = match (path.as_ref().extension(), tag) {
-            (Some(ext), _) if ext == "arrow" => ModFile::open_arrow(&path)?,
-            (Some(ext), tag) if ext == "bam" => {
-                let Some(tag) = tag else { return Err(eyre::eyre!("Detected bam file but no tag given, please from tag with -t/--tag parameter. See -h/--help for more info"))};
-                ModFile::open_mod_bam(&path, tag)?
-            }
-            (None, tag) if is_bam_file(&path) => {
-                let Some(tag) = tag else { return Err(eyre::eyre!("Detected bam file but no tag given, please from tag with -t/--tag parameter. See -h/--help for more info"))};
-                ModFile::open_mod_bam(&path, tag)?
+        let require_tag = |name: &str| {
+            tag.clone().ok_or_else(|| {
+                eyre::eyre!("Detected {name} file but no tag given, please from tag with -t/--tag parameter. See -h/--help for more info")
+            })
+        };
+        let mod_file = match format {
+            InputFormat::Arrow => ModFile::open_arrow(&path)?,
+            InputFormat::Bam => ModFile::open_mod_bam(&path, require_tag("bam")?)?,
+            InputFormat::Sam => ModFile::open_mod_sam(&path, require_tag("sam")?)?,
+            InputFormat::Cram => {
+                let tag = require_tag("cram")?;
+                let Some(reference) = reference else {
+                    return Err(eyre::eyre!(
+                        "Detected cram file but no reference given, please give the reference genome with --reference"
+                    ));
+                };
+                ModFile::open_mod_cram(&path, tag, reference, fai)?
             }
-            (None, None) if is_arrow_file(&path) => ModFile::open_arrow(&path)?,
-            (_, _) => return Err(eyre::eyre!("Failed to detect input as .bam or .arrow file")),
         };
         Ok(mod_file)
     }
+
+    /// Number of chunks of reads, only known ahead of time for Arrow files
+    pub(crate) fn n_chunks(&mut self) -> eyre::Result<Option<usize>> {
+        match self {
+            ModFile::Arrow(file) => Ok(Some(n_chunks(file)?)),
+            ModFile::ModBam { .. } | ModFile::ModSam { .. } | ModFile::ModCram { .. } => Ok(None),
+        }
+    }
+}
+
+/// Total chunks of reads in every file, if every file is an Arrow file
+pub(crate) fn total_chunks(mod_files: &mut [ModFile]) -> eyre::Result<Option<usize>> {
+    mod_files
+        .iter_mut()
+        .map(ModFile::n_chunks)
+        .sum::<eyre::Result<Option<usize>>>()
 }
 
 /// Try to read modification bam data from path, if it fails, try to read as an
 /// Arrow file. If both those fail, then error out.
-pub fn read_mod_bam_or_arrow<F>(mod_file: ModFile, mut f: F) -> eyre::Result<()>
+pub fn read_mod_bam_or_arrow<F>(mod_file: ModFile, f: F) -> eyre::Result<()>
+where
+    F: FnMut(ScoredRead) -> eyre::Result<()>,
+{
+    read_mod_bam_or_arrow_chunked(mod_file, f, |_| ())
+}
+
+/// Same as [read_mod_bam_or_arrow], reporting each chunk of reads. Set the
+/// total number of chunks beforehand with [total_chunks].
+pub(crate) fn read_mod_bam_or_arrow_reported<F>(
+    mod_file: ModFile,
+    reporter: &mut Reporter,
+    f: F,
+) -> eyre::Result<()>
 where
     F: FnMut(ScoredRead) -> eyre::Result<()>,
+{
+    match mod_file {
+        ModFile::Arrow(_) => read_mod_bam_or_arrow_chunked(mod_file, f, |n| reporter.chunk(n)),
+        ModFile::ModBam { .. } | ModFile::ModSam { .. } | ModFile::ModCram { .. } => {
+            let mut n_reads = 0;
+            let res = read_mod_bam_or_arrow_chunked(mod_file, f, |_| {
+                n_reads += 1;
+                if n_reads == MOD_BAM_CHUNK_SIZE {
+                    reporter.chunk(n_reads);
+                    n_reads = 0;
+                }
+            });
+            if n_reads > 0 {
+                reporter.chunk(n_reads);
+            }
+            res
+        }
+    }
+}
+
+/// Calls chunk_done after each chunk of an Arrow file, and after each read of
+/// a modification bam, sam, or cram file
+fn read_mod_bam_or_arrow_chunked<F, G>(mod_file: ModFile, f: F, chunk_done: G) -> eyre::Result<()>
+where
+    F: FnMut(ScoredRead) -> eyre::Result<()>,
+    G: FnMut(usize),
 {
     match mod_file {
         ModFile::Arrow(file) => {
             log::info!("Detected arrow file");
-            load_apply_indy(file, f)
+            load_apply_indy_chunked(file, f, chunk_done)
         }
         ModFile::ModBam { file, mod_tag } => {
             log::info!("Detected modification bam file");
             let records = BamRecords::from_file(file)?;
-            let mut iter = ModBamIter::new(records, mod_tag);
-            while let Some(res) = iter.next() {
-                if res.is_err() {
-                    log::warn!("Failed to convert to modbam to ScoredRead: {res:?}");
-                    continue;
-                }
-                let mba = res.unwrap();
-
-                // TODO Avoid clone by pass it into the error
-                let rec = mba.rec.clone();
-                match mba.try_into() {
-                    Ok(scored_read) => f(scored_read)?,
-                    Err(e) => {
-                        log::warn!(
-                            "{} failed with error {e}",
-                            String::from_utf8_lossy(rec.name())
-                        );
-                    }
-                }
-            }
-            Ok(())
+            apply_mod_bam(records, mod_tag, f, chunk_done)
+        }
+        ModFile::ModSam { file, mod_tag } => {
+            log::info!("Detected modification sam file");
+            let records = BamRecords::from_sam_file(file)?;
+            apply_mod_bam(records, mod_tag, f, chunk_done)
+        }
+        ModFile::ModCram { records, mod_tag } => {
+            log::info!("Detected modification cram file");
+            apply_mod_bam(records, mod_tag, f, chunk_done)
         }
     }
     // match BamRecords::from_file(&path) {
@@ -107,7 +298,43 @@ where
     // }
 }
 
-fn is_bam_file<P: AsRef<Path>>(path: P) -> bool {
+fn apply_mod_bam<F, G>(
+    records: BamRecords,
+    mod_tag: Vec<u8>,
+    mut f: F,
+    mut chunk_done: G,
+) -> eyre::Result<()>
+where
+    F: FnMut(ScoredRead) -> eyre::Result<()>,
+    G: FnMut(usize),
+{
+    let mut iter = ModBamIter::new(records, mod_tag);
+    while let Some(res) = iter.next() {
+        if res.is_err() {
+            log::warn!("Failed to convert to modbam to ScoredRead: {res:?}");
+            continue;
+        }
+        let mba = res.unwrap();
+
+        // TODO Avoid clone by pass it into the error
+        let rec = mba.rec.clone();
+        match mba.try_into() {
+            Ok(scored_read) => {
+                f(scored_read)?;
+                chunk_done(1);
+            }
+            Err(e) => {
+                log::warn!(
+                    "{} failed with error {e}",
+                    String::from_utf8_lossy(rec.name())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn is_bam_file<P: AsRef<Path>>(path: P) -> bool {
     BamRecords::from_path(path).is_ok()
 }
 
@@ -137,6 +364,57 @@ mod test {
         assert_eq!(count, 1);
     }
 
+    #[test]
+    fn test_sam_input() -> eyre::Result<()> {
+        let modsam_file = "extra/modbams/megalodon-modbam.sam";
+        assert_eq!(InputFormat::detect(modsam_file), Some(InputFormat::Sam));
+        let modsam = ModFile::open_path(modsam_file, Some("A+Y"))?;
+        let mut sam_reads = Vec::new();
+        read_mod_bam_or_arrow(modsam, |read| {
+            sam_reads.push(read);
+            Ok(())
+        })?;
+        assert_eq!(sam_reads.len(), 1);
+
+        // Same scores as the BAM file it was converted from
+        let modbam = ModFile::open_mod_bam("extra/modbams/megalodon-modbam.bam", "A+Y")?;
+        let mut bam_reads = Vec::new();
+        read_mod_bam_or_arrow(modbam, |read| {
+            bam_reads.push(read);
+            Ok(())
+        })?;
+        let pos_scores = |read: &ScoredRead| {
+            read.scores()
+                .iter()
+                .map(|s| (s.pos, s.score))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pos_scores(&sam_reads[0]), pos_scores(&bam_reads[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_headerless_sam() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sam = std::fs::read_to_string("extra/modbams/megalodon-modbam.sam")?;
+        let records = sam
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let path = dir.path().join("reads.txt");
+        std::fs::write(&path, records)?;
+        assert_eq!(InputFormat::detect(&path), Some(InputFormat::Sam));
+        assert_eq!(InputFormat::detect("reads.SAM"), Some(InputFormat::Sam));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cram_needs_reference() {
+        let res = ModFile::open_path("reads.cram", Some("C+m"));
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_nonexistent_file() {
         let bad_file = "random_file";
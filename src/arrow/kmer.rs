@@ -0,0 +1,222 @@
+//! Kmer stored inline without a heap allocation, used for the kmer of each
+//! [Score](super::scored_read::Score).
+//!
+//! Kmers are written to Arrow as Utf8 the same as a String, so scored files
+//! written before the switch to [Kmer] are still readable.
+use std::{
+    fmt,
+    ops::Deref,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use arrow2::{
+    array::{MutableUtf8Array, TryPush, Utf8Array},
+    datatypes::DataType,
+};
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use thiserror::Error;
+
+use crate::kmer_map::KmerId;
+
+/// Longest kmer that can be stored
+pub const MAX_KMER_LEN: usize = 16;
+
+/// Kmers read from Arrow files that weren't valid, see [take_invalid_kmers]
+static INVALID_KMERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of kmers read from Arrow files since the last call that were too
+/// long or not ASCII, and were left empty so they don't match any model.
+pub fn take_invalid_kmers() -> usize {
+    INVALID_KMERS.swap(0, Ordering::Relaxed)
+}
+
+/// Replace uracil with thymine, ie in kmers from direct RNA pore models, so
+/// they match kmers from the genome and DNA motifs
+pub fn rna_to_dna(kmer: &str) -> String {
+    kmer.replace('U', "T")
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KmerError {
+    #[error("Kmer {0} is longer than the maximum of {MAX_KMER_LEN} bases")]
+    TooLong(String),
+
+    #[error("Kmer contains non-ASCII bases: {0:?}")]
+    NonAscii(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Kmer {
+    bases: [u8; MAX_KMER_LEN],
+    len: u8,
+}
+
+impl Kmer {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KmerError> {
+        if !bytes.is_ascii() {
+            return Err(KmerError::NonAscii(bytes.to_vec()));
+        }
+        if bytes.len() > MAX_KMER_LEN {
+            return Err(KmerError::TooLong(
+                String::from_utf8_lossy(bytes).into_owned(),
+            ));
+        }
+        let mut bases = [0; MAX_KMER_LEN];
+        bases[..bytes.len()].copy_from_slice(bytes);
+        Ok(Kmer {
+            bases,
+            len: bytes.len() as u8,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bases[..self.len as usize]
+    }
+
+    /// Interned id of the kmer, None if it isn't a 6-mer of A, C, G, and T
+    pub fn id(&self) -> Option<KmerId> {
+        KmerId::new(self.as_bytes())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII bytes are accepted in from_bytes
+        std::str::from_utf8(self.as_bytes()).expect("Kmer is always ASCII")
+    }
+}
+
+impl Deref for Kmer {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Kmer {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl FromStr for Kmer {
+    type Err = KmerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kmer::from_bytes(s.as_bytes())
+    }
+}
+
+impl PartialEq<str> for Kmer {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Kmer {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Kmer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Kmer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl ArrowField for Kmer {
+    type Type = Self;
+
+    fn data_type() -> DataType {
+        DataType::Utf8
+    }
+}
+
+impl ArrowSerialize for Kmer {
+    type MutableArrayType = MutableUtf8Array<i32>;
+
+    fn new_array() -> Self::MutableArrayType {
+        Self::MutableArrayType::default()
+    }
+
+    fn arrow_serialize(v: &Self, array: &mut Self::MutableArrayType) -> arrow2::error::Result<()> {
+        array.try_push(Some(v.as_str()))
+    }
+}
+
+impl ArrowDeserialize for Kmer {
+    type ArrayType = Utf8Array<i32>;
+
+    fn arrow_deserialize(v: Option<&str>) -> Option<Self> {
+        v.map(|s| {
+            s.parse().unwrap_or_else(|e| {
+                // Deserializing can't fail, warn on the first and count the rest
+                if INVALID_KMERS.fetch_add(1, Ordering::Relaxed) == 0 {
+                    log::warn!("{e}, kmers that can't be read are left empty");
+                }
+                Kmer::default()
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow2_convert::{deserialize::TryIntoCollection, serialize::TryIntoArrow};
+
+    use super::*;
+
+    #[test]
+    fn test_kmer() {
+        let kmer: Kmer = "GATTAC".parse().unwrap();
+        assert_eq!(kmer, "GATTAC");
+        assert_eq!(kmer.len(), 6);
+        assert!(kmer.starts_with("GA"));
+        assert_eq!(kmer.to_string(), "GATTAC");
+        assert_eq!(Kmer::default().as_str(), "");
+
+        assert!(matches!(
+            "A".repeat(MAX_KMER_LEN + 1).parse::<Kmer>(),
+            Err(KmerError::TooLong(_))
+        ));
+        assert!(matches!(
+            Kmer::from_bytes(&[0xff]),
+            Err(KmerError::NonAscii(_))
+        ));
+    }
+
+    #[test]
+    fn test_rna_to_dna() {
+        assert_eq!(rna_to_dna("GGACU"), "GGACT");
+        assert_eq!(rna_to_dna("GGACT"), "GGACT");
+    }
+
+    #[test]
+    fn test_kmer_from_string_array() {
+        let kmers = vec!["AAAAAA".to_string(), "TTATCG".to_string()];
+        let array: Box<dyn arrow2::array::Array> = kmers.try_into_arrow().unwrap();
+        let parsed: Vec<Kmer> = array.try_into_collection().unwrap();
+        assert_eq!(parsed, ["AAAAAA", "TTATCG"]);
+
+        let array: Box<dyn arrow2::array::Array> = parsed.try_into_arrow().unwrap();
+        let strings: Vec<String> = array.try_into_collection().unwrap();
+        assert_eq!(strings, kmers);
+    }
+
+    #[test]
+    fn test_invalid_kmer_from_string_array() {
+        let kmers = vec!["AAAAAA".to_string(), "A".repeat(MAX_KMER_LEN + 1)];
+        let array: Box<dyn arrow2::array::Array> = kmers.try_into_arrow().unwrap();
+        let parsed: Vec<Kmer> = array.try_into_collection().unwrap();
+        assert_eq!(parsed, ["AAAAAA", ""]);
+        // Other tests may read invalid kmers concurrently
+        assert!(take_invalid_kmers() >= 1);
+    }
+}
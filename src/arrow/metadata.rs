@@ -1,5 +1,6 @@
 use arrow2_convert::ArrowDeserialize;
 use arrow2_convert::ArrowSerialize;
+use bam::record::{cigar::Operation, tags::TagValue};
 use std::fmt::Display;
 
 use arrow2_convert::ArrowField;
@@ -9,7 +10,12 @@ use arrow2_convert::ArrowField;
 ///
 /// Note: All coordinate data will be zero-based for the start and one based
 /// (zero-based not inclusive) for the end
-#[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default, PartialEq, Eq)]
+///
+/// Mapping information is only known for reads from a BAM file, and is None for
+/// Arrow files written before it was added. The haplotype and phase set are
+/// only known for reads with HP and PS tags, ie from whatshap haplotag. The
+/// sample is only set for reads from cawlr merge.
+#[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default, PartialEq)]
 pub struct Metadata {
     pub name: String,
     pub chrom: String,
@@ -17,8 +23,18 @@ pub struct Metadata {
     pub length: u64,
     pub strand: Strand,
     pub seq: String,
+    pub mapq: Option<u8>,
+    pub flags: Option<u16>,
+    pub identity: Option<f64>,
+    pub sample: Option<String>,
+    pub haplotype: Option<i64>,
+    pub phase_set: Option<i64>,
 }
 
+/// identity is only ever set from [MappingInfo], which never makes it NaN, so
+/// equality stays reflexive
+impl Eq for Metadata {}
+
 impl Metadata {
     pub fn new(
         name: String,
@@ -35,8 +51,83 @@ impl Metadata {
             length,
             strand,
             seq,
+            mapq: None,
+            flags: None,
+            identity: None,
+            sample: None,
+            haplotype: None,
+            phase_set: None,
         }
     }
+
+    /// Set the mapping quality, SAM flags, alignment identity, and haplotype
+    pub fn set_mapping(&mut self, mapping: MappingInfo) {
+        self.mapq = Some(mapping.mapq);
+        self.flags = Some(mapping.flags);
+        self.identity = mapping.identity;
+        self.haplotype = mapping.haplotype;
+        self.phase_set = mapping.phase_set;
+    }
+}
+
+/// Mapping quality, SAM flags, alignment identity, and haplotype of a read
+/// from a BAM file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MappingInfo {
+    pub mapq: u8,
+    pub flags: u16,
+    /// Fraction of alignment columns that match the reference, from the NM tag.
+    /// None if the record has no NM tag.
+    pub identity: Option<f64>,
+    /// Haplotype from the HP tag, None if the read isn't phased
+    pub haplotype: Option<i64>,
+    /// Phase set from the PS tag, None if the read isn't phased
+    pub phase_set: Option<i64>,
+}
+
+impl MappingInfo {
+    pub fn from_record(record: &bam::Record) -> Self {
+        MappingInfo {
+            mapq: record.mapq(),
+            flags: record.flag().0,
+            identity: alignment_identity(record),
+            haplotype: int_tag(record, b"HP"),
+            phase_set: int_tag(record, b"PS"),
+        }
+    }
+}
+
+fn int_tag(record: &bam::Record, tag: &[u8; 2]) -> Option<i64> {
+    match record.tags().get(tag) {
+        Some(TagValue::Int(value, _)) => Some(value),
+        _ => None,
+    }
+}
+
+/// 1 - NM / alignment columns, where alignment columns are matches,
+/// mismatches, insertions, and deletions
+fn alignment_identity(record: &bam::Record) -> Option<f64> {
+    let edit_distance = match record.tags().get(b"NM") {
+        Some(TagValue::Int(nm, _)) => nm as f64,
+        _ => return None,
+    };
+    let columns = record
+        .cigar()
+        .iter()
+        .filter_map(|(len, op)| match op {
+            Operation::AlnMatch
+            | Operation::SeqMatch
+            | Operation::SeqMismatch
+            | Operation::Insertion
+            | Operation::Deletion => Some(len as u64),
+            _ => None,
+        })
+        .sum::<u64>();
+    if columns == 0 {
+        None
+    } else {
+        Some(1.0 - edit_distance / columns as f64)
+    }
 }
 
 pub trait MetadataExt {
@@ -77,6 +168,37 @@ pub trait MetadataExt {
         self.metadata().strand
     }
 
+    /// Mapping quality, if the read came from a BAM file
+    fn mapq(&self) -> Option<u8> {
+        self.metadata().mapq
+    }
+
+    /// SAM flags, if the read came from a BAM file
+    fn flags(&self) -> Option<u16> {
+        self.metadata().flags
+    }
+
+    /// Fraction of alignment columns matching the reference, if the read came
+    /// from a BAM file with NM tags
+    fn identity(&self) -> Option<f64> {
+        self.metadata().identity
+    }
+
+    /// Sample the read came from, if it was tagged by cawlr merge
+    fn sample(&self) -> Option<&str> {
+        self.metadata().sample.as_deref()
+    }
+
+    /// Haplotype from the HP tag of the read in the BAM file, if it was phased
+    fn haplotype(&self) -> Option<i64> {
+        self.metadata().haplotype
+    }
+
+    /// Phase set from the PS tag of the read in the BAM file, if it was phased
+    fn phase_set(&self) -> Option<i64> {
+        self.metadata().phase_set
+    }
+
     fn seq_stop_1b_excl(&self) -> u64 {
         self.metadata().start + self.seq_length()
     }
@@ -111,6 +233,11 @@ pub trait MetadataMutExt {
     fn strand_mut(&mut self) -> &mut Strand {
         &mut self.metadata_mut().strand
     }
+
+    /// Tag the read with the sample it came from
+    fn set_sample<S: Into<String>>(&mut self, sample: S) {
+        self.metadata_mut().sample = Some(sample.into());
+    }
 }
 
 /// Read orientation relative to a genome
@@ -1,10 +1,39 @@
+//! Types stored in the Arrow files written by cawlr, and functions to read and
+//! write them.
+//!
+//! The read-only accessors on [eventalign::Eventalign], [signal::Signal],
+//! [scored_read::ScoredRead], [scored_read::Score], and
+//! [metadata::MetadataExt] are the stable API for custom analyses. Struct
+//! fields may change between versions, the accessors won't.
+//!
+//! ```no_run
+//! # use std::fs::File;
+//! # use libcawlr::arrow::{arrow_utils::load_apply, metadata::MetadataExt, scored_read::ScoredRead};
+//! # fn main() -> eyre::Result<()> {
+//! let file = File::open("sample.score.arrow")?;
+//! load_apply(file, |reads: Vec<ScoredRead>| {
+//!     for read in reads {
+//!         for (pos, score) in read.iter_scores() {
+//!             println!("{}\t{pos}\t{score}", read.chrom());
+//!         }
+//!     }
+//!     Ok(())
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
 pub mod arrow_utils;
 pub mod eventalign;
 pub mod io;
+pub mod kmer;
 pub mod metadata;
-mod mod_bam;
+pub(crate) mod mod_bam;
+pub mod modbam_export;
+#[cfg(feature = "parquet")]
+pub mod parquet;
 pub mod scored_read;
 pub mod signal;
+pub mod sma_read;
 
 #[cfg(test)]
 mod test {
@@ -17,8 +46,10 @@ mod test {
         arrow_utils::{load, save, wrap_writer},
         eventalign::Eventalign,
         metadata::{Metadata, Strand},
+        scored_read::{Score, ScoredRead},
         signal::Signal,
     };
+    use crate::test_data::{chrom_seq, MiniGenome};
 
     #[test]
     fn test_single_read() {}
@@ -57,18 +88,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_data_api() {
+        let metadata = Metadata::new(
+            "abc".to_string(),
+            "chrI".to_string(),
+            0u64,
+            100u64,
+            Strand::plus(),
+            String::new(),
+        );
+        let signal = Signal::new(1u64, "AAAAAA".to_string(), 80.0, 0.01, vec![79.0, 81.0]);
+        let eventalign = Eventalign::new(metadata.clone(), vec![signal]);
+        assert_eq!(
+            eventalign.iter_signal_means().collect::<Vec<_>>(),
+            [(1, 80.0)]
+        );
+        let signal = &eventalign.signals()[0];
+        assert_eq!(signal.kmer(), "AAAAAA");
+        assert_eq!(signal.samples(), [79.0, 81.0]);
+
+        let score = Score::new(1, "AAAAAA".parse().unwrap(), false, Some(0.7), 0.6);
+        let read = ScoredRead::new(metadata, vec![score]);
+        assert_eq!(read.iter_scores().collect::<Vec<_>>(), [(1, 0.6)]);
+        let score = &read.scores()[0];
+        assert_eq!(score.kmer().as_bytes(), b"AAAAAA");
+        assert_eq!(score.signal_score(), Some(0.7));
+        assert!(!score.is_skipped());
+    }
+
     #[allow(clippy::read_zero_byte_vec)]
     #[test]
     fn test_fasta_reader_start() {
-        let genome = "extra/sacCer3.fa";
-        let mut genome = IndexedReader::from_file(&genome).unwrap();
-        let chrom = "chrI";
-        let start = 71071;
+        let mini = MiniGenome::new().unwrap();
+        let mut genome = IndexedReader::from_file(&mini.genome()).unwrap();
+        let chrom = "chrII";
+        // Crosses a line break in the fasta file
+        let start = 57;
         let stop = start + 6;
         genome.fetch(chrom, start, stop).unwrap();
         let mut seq = Vec::new();
         genome.read(&mut seq).unwrap();
 
-        assert_eq!(b"GCAAGC", seq.as_slice());
+        assert_eq!(&chrom_seq(chrom).as_bytes()[57..63], seq.as_slice());
     }
 }
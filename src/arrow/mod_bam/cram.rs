@@ -0,0 +1,96 @@
+//! CRAM input with noodles. Records are decoded against the reference on a
+//! background thread and written out as SAM text, which is read like any other
+//! SAM file, so CRAM files are streamed without converting them first.
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+};
+
+use noodles::{
+    cram, fasta,
+    sam::{self, alignment::io::Write as _},
+};
+
+/// Chunks of SAM text buffered between the decoding thread and the reader
+const N_CHUNKS: usize = 4;
+
+/// Size a chunk of SAM text grows to before it is sent to the reader
+const CHUNK_BYTES: usize = 1 << 20;
+
+type Chunk = io::Result<Vec<u8>>;
+
+/// SAM text of the records in a CRAM file, header first
+pub(crate) struct CramSamStream {
+    chunks: Receiver<Chunk>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl CramSamStream {
+    /// Open a CRAM file with the reference genome the reads were aligned to,
+    /// which needs a .fai index
+    pub(crate) fn open(path: &Path, reference: &Path) -> io::Result<Self> {
+        let fasta = fasta::io::indexed_reader::Builder::default().build_from_path(reference)?;
+        let repository =
+            fasta::Repository::new(fasta::repository::adapters::IndexedReader::new(fasta));
+        let mut reader = cram::io::reader::Builder::default()
+            .set_reference_sequence_repository(repository)
+            .build_from_path(path)?;
+        let header = reader.read_header()?;
+        let (tx, rx) = mpsc::sync_channel(N_CHUNKS);
+        thread::spawn(move || {
+            if let Err(e) = send_sam(&mut reader, &header, &tx) {
+                let _ = tx.send(Err(e));
+            }
+        });
+        Ok(CramSamStream {
+            chunks: rx,
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+}
+
+/// Decode every record, sending the SAM text in chunks. Stops early without an
+/// error once the reader is dropped.
+fn send_sam<R: Read>(
+    reader: &mut cram::io::Reader<R>,
+    header: &sam::Header,
+    tx: &SyncSender<Chunk>,
+) -> io::Result<()> {
+    let mut writer = sam::io::Writer::new(Vec::new());
+    writer.write_header(header)?;
+    for record in reader.records(header) {
+        let record = record?.try_into_alignment_record(header)?;
+        writer.write_alignment_record(header, &record)?;
+        if writer.get_ref().len() >= CHUNK_BYTES {
+            let chunk = std::mem::take(writer.get_mut());
+            if tx.send(Ok(chunk)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    let _ = tx.send(Ok(std::mem::take(writer.get_mut())));
+    Ok(())
+}
+
+impl Read for CramSamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // Decoding thread finished and every chunk was read
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -1,5 +1,375 @@
-use std::collections::HashSet;
+//! Parse the MM and ML tags, see section 1.7 of the Sequence Alignment/Map
+//! Optional Fields Specification https://samtools.github.io/hts-specs/SAMtags.pdf
+use std::collections::BTreeMap;
 
-struct MmTag {
-    tags: HashSet<String>,
+/// How bases that could be modified but aren't listed in the MM tag are
+/// treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SkipMode {
+    /// '.', unlisted bases are unmodified
+    Unmodified,
+    /// '?', nothing is known about unlisted bases
+    Unknown,
+    /// No suffix, the specification treats this as '.' but older callers like
+    /// megalodon list every base they call, so only listed bases are used
+    Implicit,
+}
+
+/// A modification, ie C+m from -t/--tag, or the start of a section of the MM
+/// tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct ModCode {
+    /// Unmodified base, N for any base
+    pub(super) base: u8,
+    /// Modification is on the same strand as the sequenced read ie '+', instead
+    /// of the opposite strand ie '-'
+    pub(super) is_top: bool,
+    /// Single letter codes, ie m and h for C+mh, or a ChEBI number
+    pub(super) codes: Vec<Vec<u8>>,
+}
+
+impl ModCode {
+    /// Parse a modification with an optional '.' or '?' suffix
+    pub(super) fn parse(tag: &[u8]) -> Option<(Self, SkipMode)> {
+        let (tag, skip_mode) = match tag.last()? {
+            b'.' => (&tag[..tag.len() - 1], SkipMode::Unmodified),
+            b'?' => (&tag[..tag.len() - 1], SkipMode::Unknown),
+            _ => (tag, SkipMode::Implicit),
+        };
+        let [base, strand, codes @ ..] = tag else {
+            return None;
+        };
+        let is_top = match *strand {
+            b'+' => true,
+            b'-' => false,
+            _ => return None,
+        };
+        if codes.is_empty() || !codes.is_ascii() {
+            return None;
+        }
+        let codes = if codes.iter().all(u8::is_ascii_digit) {
+            vec![codes.to_vec()]
+        } else if codes.iter().all(u8::is_ascii_alphabetic) {
+            codes.iter().map(|&c| vec![c]).collect()
+        } else {
+            return None;
+        };
+        let mod_code = ModCode {
+            base: base.to_ascii_uppercase(),
+            is_top,
+            codes,
+        };
+        Some((mod_code, skip_mode))
+    }
+
+    /// Base in the sequenced read that the modification is called on
+    pub(super) fn read_base(&self) -> u8 {
+        if self.is_top {
+            self.base
+        } else {
+            complement(self.base)
+        }
+    }
+
+    /// Index in self of each of the codes of other that self has
+    fn code_indices(&self, other: &ModCode) -> Vec<usize> {
+        if self.base != other.base || self.is_top != other.is_top {
+            return Vec::new();
+        }
+        other
+            .codes
+            .iter()
+            .filter_map(|code| self.codes.iter().position(|c| c == code))
+            .collect()
+    }
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' | b'U' => b'A',
+        _ => b'N',
+    }
+}
+
+/// Section of the MM tag, ie C+mh?,5,12,0
+#[derive(Debug, PartialEq)]
+struct MmSection {
+    mod_code: ModCode,
+    skip_mode: SkipMode,
+    /// Number of bases skipped before each listed base
+    deltas: Vec<u64>,
+}
+
+impl MmSection {
+    /// Probability of any of the codes at idxs for each listed base, from the
+    /// ML probabilities starting at offset
+    fn probs(&self, offset: usize, idxs: &[usize], ml: &[f64]) -> Option<Vec<f64>> {
+        let n_codes = self.mod_code.codes.len();
+        (0..self.deltas.len())
+            .map(|i| {
+                idxs.iter()
+                    .map(|idx| ml.get(offset + i * n_codes + idx))
+                    .sum::<Option<f64>>()
+                    .map(|p| p.min(1.))
+            })
+            .collect()
+    }
+
+    fn parse(section: &[u8]) -> Option<Self> {
+        let mut fields = section.split(|&b| b == b',');
+        let (mod_code, skip_mode) = ModCode::parse(fields.next()?)?;
+        let deltas = fields
+            .map(|d| std::str::from_utf8(d).ok()?.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(MmSection {
+            mod_code,
+            skip_mode,
+            deltas,
+        })
+    }
+}
+
+/// The MM tag, can contain multiple modifications, ie C+m,0,3,5;C+Y,1,3,4;
+#[derive(Debug, PartialEq)]
+pub(super) struct MmTag {
+    sections: Vec<MmSection>,
+}
+
+/// Bases listed in the MM tag for a modification, along with their
+/// probability from the ML tag
+#[derive(Debug, PartialEq)]
+pub(super) struct ModCalls {
+    pub(super) mod_code: ModCode,
+    pub(super) skip_mode: SkipMode,
+    pub(super) deltas: Vec<u64>,
+    pub(super) probs: Vec<f64>,
+}
+
+impl MmTag {
+    pub(super) fn parse(tag: &[u8]) -> Option<Self> {
+        let sections = tag
+            .split(|&b| b == b';')
+            .filter(|section| !section.is_empty())
+            .map(MmSection::parse)
+            .collect::<Option<Vec<_>>>()?;
+        Some(MmTag { sections })
+    }
+
+    /// Calls for the modification from the ML probabilities, which have one
+    /// value per code for each listed base, in the order of the MM tag. If
+    /// more than one code is asked for, ie C+mh, the probability is of any of
+    /// them, and codes in separate sections, ie C+m,...;C+h,..., are combined
+    /// by base. Returns None if the modification isn't in the tag, or the ML
+    /// tag is too short.
+    pub(super) fn calls(&self, mod_code: &ModCode, ml: &[f64]) -> Option<ModCalls> {
+        let mut offset = 0;
+        // Sections with only some of the codes, with where their
+        // probabilities start and the indices of the codes not already in an
+        // earlier section
+        let mut partial = Vec::new();
+        let mut covered = vec![false; mod_code.codes.len()];
+        for section in self.sections.iter() {
+            let start = offset;
+            offset += section.deltas.len() * section.mod_code.codes.len();
+            let idxs = section.mod_code.code_indices(mod_code);
+            if idxs.len() == mod_code.codes.len() {
+                return Some(ModCalls {
+                    mod_code: section.mod_code.clone(),
+                    skip_mode: section.skip_mode,
+                    deltas: section.deltas.clone(),
+                    probs: section.probs(start, &idxs, ml)?,
+                });
+            }
+            let idxs = idxs
+                .into_iter()
+                .filter(|&idx| {
+                    let code = &section.mod_code.codes[idx];
+                    let pos = mod_code.codes.iter().position(|c| c == code);
+                    match pos {
+                        Some(pos) if !covered[pos] => {
+                            covered[pos] = true;
+                            true
+                        }
+                        _ => false,
+                    }
+                })
+                .collect::<Vec<_>>();
+            if !idxs.is_empty() {
+                partial.push((section, start, idxs));
+            }
+        }
+        if !covered.iter().all(|&c| c) {
+            return None;
+        }
+        combine_sections(mod_code, &partial, ml)
+    }
+}
+
+/// Calls for codes listed in separate sections, with the probabilities of
+/// each base summed over the sections. A base a section doesn't list counts
+/// as unmodified for that section if it uses '.', otherwise nothing is known
+/// about the base and it is left out.
+fn combine_sections(
+    mod_code: &ModCode,
+    sections: &[(&MmSection, usize, Vec<usize>)],
+    ml: &[f64],
+) -> Option<ModCalls> {
+    let n_required = sections
+        .iter()
+        .filter(|(section, ..)| section.skip_mode != SkipMode::Unmodified)
+        .count();
+    // Summed probability of each base, indexed among the bases that could be
+    // modified, and the number of sections without '.' that list it
+    let mut by_base: BTreeMap<u64, (f64, usize)> = BTreeMap::new();
+    for (section, offset, idxs) in sections {
+        let probs = section.probs(*offset, idxs, ml)?;
+        let mut base = 0;
+        for (delta, prob) in section.deltas.iter().zip(probs) {
+            base += delta;
+            let (sum, n_listed) = by_base.entry(base).or_default();
+            *sum += prob;
+            if section.skip_mode != SkipMode::Unmodified {
+                *n_listed += 1;
+            }
+            base += 1;
+        }
+    }
+
+    let mut deltas = Vec::with_capacity(by_base.len());
+    let mut probs = Vec::with_capacity(by_base.len());
+    let mut next = 0;
+    for (base, (prob, n_listed)) in by_base {
+        if n_listed < n_required {
+            continue;
+        }
+        deltas.push(base - next);
+        probs.push(prob.min(1.));
+        next = base + 1;
+    }
+    let skip_mode = if n_required == 0 {
+        SkipMode::Unmodified
+    } else if sections
+        .iter()
+        .any(|(section, ..)| section.skip_mode == SkipMode::Unknown)
+    {
+        SkipMode::Unknown
+    } else {
+        SkipMode::Implicit
+    };
+    Some(ModCalls {
+        mod_code: mod_code.clone(),
+        skip_mode,
+        deltas,
+        probs,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mod_code(tag: &[u8]) -> ModCode {
+        ModCode::parse(tag).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_mod_code() {
+        let (code, skip_mode) = ModCode::parse(b"C+mh?").unwrap();
+        assert_eq!(code.base, b'C');
+        assert!(code.is_top);
+        assert_eq!(code.codes, vec![b"m".to_vec(), b"h".to_vec()]);
+        assert_eq!(skip_mode, SkipMode::Unknown);
+
+        let (code, skip_mode) = ModCode::parse(b"G-m.").unwrap();
+        assert!(!code.is_top);
+        assert_eq!(code.read_base(), b'C');
+        assert_eq!(skip_mode, SkipMode::Unmodified);
+
+        let (code, skip_mode) = ModCode::parse(b"C+27551").unwrap();
+        assert_eq!(code.codes, vec![b"27551".to_vec()]);
+        assert_eq!(skip_mode, SkipMode::Implicit);
+
+        assert!(ModCode::parse(b"C*m").is_none());
+        assert!(ModCode::parse(b"C+").is_none());
+        assert!(ModCode::parse(b"").is_none());
+    }
+
+    #[test]
+    fn test_parse_mm_tag() {
+        let tag = MmTag::parse(b"C+mh?,5,12,0;G-m,0,2;").unwrap();
+        assert_eq!(tag.sections.len(), 2);
+        assert_eq!(tag.sections[0].deltas, vec![5, 12, 0]);
+        assert_eq!(tag.sections[1].mod_code, mod_code(b"G-m"));
+
+        assert!(MmTag::parse(b"C+m,1,x").is_none());
+    }
+
+    #[test]
+    fn test_calls_multiple_codes() {
+        // Probabilities of m and h alternate for each base, then G-m
+        let tag = MmTag::parse(b"C+mh,5,12;G-m,0;").unwrap();
+        let ml = [0.1, 0.2, 0.3, 0.4, 0.5];
+
+        let calls = tag.calls(&mod_code(b"C+m"), &ml).unwrap();
+        assert_eq!(calls.deltas, vec![5, 12]);
+        assert_eq!(calls.probs, vec![0.1, 0.3]);
+
+        let calls = tag.calls(&mod_code(b"C+h"), &ml).unwrap();
+        assert_eq!(calls.probs, vec![0.2, 0.4]);
+
+        let calls = tag.calls(&mod_code(b"C+mh"), &ml).unwrap();
+        let expected = [0.1 + 0.2, 0.3 + 0.4];
+        assert!(calls
+            .probs
+            .iter()
+            .zip(expected)
+            .all(|(p, e)| (p - e).abs() < 1e-9));
+
+        let calls = tag.calls(&mod_code(b"G-m"), &ml).unwrap();
+        assert_eq!(calls.probs, vec![0.5]);
+    }
+
+    #[test]
+    fn test_calls_separate_sections() {
+        // m is listed for the second and third C, h for the second and fifth
+        let ml = [0.1, 0.2, 0.3, 0.4];
+        let tag = MmTag::parse(b"C+m.,1,0;C+h.,1,2;").unwrap();
+        let calls = tag.calls(&mod_code(b"C+mh"), &ml).unwrap();
+        assert_eq!(calls.mod_code, mod_code(b"C+mh"));
+        assert_eq!(calls.skip_mode, SkipMode::Unmodified);
+        assert_eq!(calls.deltas, vec![1, 0, 1]);
+        let expected = [0.1 + 0.3, 0.2, 0.4];
+        assert!(calls
+            .probs
+            .iter()
+            .zip(expected)
+            .all(|(p, e)| (p - e).abs() < 1e-9));
+
+        // Nothing is known about h for the third C, so it is left out
+        let tag = MmTag::parse(b"C+m.,1,0;C+h?,1,2;").unwrap();
+        let calls = tag.calls(&mod_code(b"C+mh"), &ml).unwrap();
+        assert_eq!(calls.skip_mode, SkipMode::Unknown);
+        assert_eq!(calls.deltas, vec![1, 2]);
+        assert!((calls.probs[0] - 0.4).abs() < 1e-9);
+        assert!((calls.probs[1] - 0.4).abs() < 1e-9);
+
+        // Single codes still come from their own section
+        let calls = tag.calls(&mod_code(b"C+h"), &ml).unwrap();
+        assert_eq!(calls.probs, vec![0.3, 0.4]);
+
+        let tag = MmTag::parse(b"C+m,1,0;").unwrap();
+        assert!(tag.calls(&mod_code(b"C+mh"), &ml).is_none());
+    }
+
+    #[test]
+    fn test_calls_missing() {
+        let tag = MmTag::parse(b"C+m,1,3,0;").unwrap();
+        // Other strand, other code, and too few probabilities
+        assert!(tag.calls(&mod_code(b"C-m"), &[0.1, 0.2, 0.3]).is_none());
+        assert!(tag.calls(&mod_code(b"C+h"), &[0.1, 0.2, 0.3]).is_none());
+        assert!(tag.calls(&mod_code(b"C+m"), &[0.1, 0.2]).is_none());
+    }
 }
\ No newline at end of file
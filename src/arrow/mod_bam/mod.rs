@@ -2,15 +2,23 @@
 //!
 //! Current uses bam, but should be switched over to rust-htslib or
 //! noodles
+mod cram;
 mod ml;
 mod mm_tag;
 
-use std::{fmt, fs::File, io, path::Path};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
 
-use bam::{record::tags::TagValue, BamReader};
+use bam::{record::tags::TagValue, RecordReader, SamReader};
 
+use self::mm_tag::{MmTag, ModCalls, ModCode, SkipMode};
 use super::{
-    metadata::{Metadata, Strand},
+    kmer::Kmer,
+    metadata::{MappingInfo, Metadata, Strand},
     scored_read::{Score, ScoredRead},
 };
 
@@ -21,6 +29,9 @@ pub enum ModBamConversionError {
 
     #[error("No scores found for modification motif")]
     NoScores,
+
+    #[error("Mm tag has more positions than bases in the read")]
+    PositionOutOfRange,
 }
 
 pub struct ModBamAlignment<'a> {
@@ -66,7 +77,12 @@ impl<'a> ModBamAlignment<'a> {
                 s as u64
             }
         };
-        let length = self.rec.query_len() as u64;
+        // Reference span of aligned reads, so every score is within the read
+        let length = if self.rec.flag().is_mapped() && self.rec.start() >= 0 {
+            (self.rec.calculate_end() - self.rec.start()) as u64
+        } else {
+            self.rec.query_len() as u64
+        };
         let strand = if !self.rec.flag().is_mapped() {
             Strand::unknown()
         } else if self.rec.flag().is_reverse_strand() {
@@ -83,75 +99,120 @@ impl<'a> ModBamAlignment<'a> {
                 .expect("Read reference name not found")
                 .to_string()
         };
-        Metadata::new(name, chrom, start, length, strand, String::new())
+        let mut metadata = Metadata::new(name, chrom, start, length, strand, String::new());
+        metadata.set_mapping(MappingInfo::from_record(&self.rec));
+        metadata
     }
 
     fn mod_prob_positions(&self) -> Result<ModProbsMl, ModBamConversionError> {
-        let tags = self.rec.tags();
-        let Some(TagValue::String(score_pos, _)) = tags.get(b"Mm").or(tags.get(b"MM")) else {
-            return Err(ModBamConversionError::NoTags);
-        };
-        let ModPosMm { skipped, positions } = ModPosMm::parse_mm_tag(self.base_mod, score_pos)
-            .ok_or(ModBamConversionError::NoTags)?;
-
-        let Some(TagValue::IntArray(score_prob_arr)) = tags.get(b"Ml").or(tags.get(b"ML")) else {
-            return Err(ModBamConversionError::NoTags);
-        };
-        let probs = score_prob_arr
-            .raw()
-            .iter()
-            .map(|&x| (x as f64) / 256.)
-            .collect::<Vec<_>>();
-        let probs = probs[skipped..skipped + positions.len()].to_vec();
+        let calls = mod_calls(&self.rec, self.base_mod)?;
         Ok(ModProbsMl {
-            probs,
-            positions,
+            probs: calls.probs,
+            positions: calls.deltas,
+            mod_code: calls.mod_code,
+            skip_mode: calls.skip_mode,
             modbam: self,
         })
     }
 }
 
+/// Modification probabilities from the Ml tag, along with the Mm tag positions
+/// they belong to, for a single modification in a record
+pub(crate) fn mod_probs(
+    rec: &bam::Record,
+    base_mod: &[u8],
+) -> Result<(Vec<f64>, Vec<u64>), ModBamConversionError> {
+    let calls = mod_calls(rec, base_mod)?;
+    Ok((calls.probs, calls.deltas))
+}
+
+fn mod_calls(rec: &bam::Record, base_mod: &[u8]) -> Result<ModCalls, ModBamConversionError> {
+    let tags = rec.tags();
+    let Some(TagValue::String(score_pos, _)) = tags.get(b"Mm").or(tags.get(b"MM")) else {
+        return Err(ModBamConversionError::NoTags);
+    };
+    let mm = MmTag::parse(score_pos).ok_or(ModBamConversionError::NoTags)?;
+    let (mod_code, _) = ModCode::parse(base_mod).ok_or(ModBamConversionError::NoTags)?;
+
+    let Some(TagValue::IntArray(score_prob_arr)) = tags.get(b"Ml").or(tags.get(b"ML")) else {
+        return Err(ModBamConversionError::NoTags);
+    };
+    let probs = score_prob_arr
+        .iter()
+        .map(|x| (x as f64) / 256.)
+        .collect::<Vec<_>>();
+    mm.calls(&mod_code, &probs)
+        .ok_or(ModBamConversionError::NoTags)
+}
+
 struct ModProbsMl<'a> {
     probs: Vec<f64>,
     positions: Vec<u64>,
+    mod_code: ModCode,
+    skip_mode: SkipMode,
     modbam: &'a ModBamAlignment<'a>,
 }
 
 impl<'a> ModProbsMl<'a> {
     fn into_scores(self) -> Result<Vec<Score>, ModBamConversionError> {
-        let mut pos_acc = 0;
-        let mut scores = Vec::with_capacity(self.probs.len());
-
-        let start = u64::try_from(self.modbam.rec.start()).unwrap_or(0);
-        let end = start + u64::try_from(self.modbam.rec.query_len()).unwrap_or(0);
-        let mod_base = self.modbam.base_mod[0];
-        let kmer = String::from_utf8(vec![mod_base]).unwrap();
-
-        let seq = if self.modbam.rec.flag().is_reverse_strand() {
-            self.modbam.rec.sequence().rev_compl(..).collect()
+        let rec = &self.modbam.rec;
+        let is_reverse = rec.flag().is_reverse_strand();
+        // Mm positions are on the read as it was sequenced
+        let seq: Vec<u8> = if is_reverse {
+            rec.sequence().rev_compl(..).collect()
         } else {
-            self.modbam.rec.sequence().to_vec()
+            rec.sequence().to_vec()
         };
+        let read_base = self.mod_code.read_base();
         let seq_positions = seq
-            .into_iter()
+            .iter()
             .enumerate()
-            .filter_map(|b| if b.1 == mod_base { Some(b.0) } else { None })
+            .filter_map(|(i, &b)| (read_base == b'N' || b == read_base).then_some(i))
             .collect::<Vec<_>>();
         if seq_positions.is_empty() {
             return Err(ModBamConversionError::NoScores);
         }
 
+        let mut probs = vec![None; seq_positions.len()];
+        let mut pos_acc = 0;
         for (prob, pos) in self.probs.into_iter().zip(self.positions.into_iter()) {
-            pos_acc += pos;
-            // let abs_pos: u64 = start + (seq_positions[pos_acc as usize] as u64);
-            let abs_pos: u64 = if self.modbam.rec.flag().is_reverse_strand() {
-                end - (seq_positions[pos_acc as usize] as u64) - 1
+            pos_acc += pos as usize;
+            let Some(p) = probs.get_mut(pos_acc) else {
+                return Err(ModBamConversionError::PositionOutOfRange);
+            };
+            *p = Some(prob);
+            pos_acc += 1;
+        }
+        if self.skip_mode == SkipMode::Unmodified {
+            probs
+                .iter_mut()
+                .filter(|p| p.is_none())
+                .for_each(|p| *p = Some(0.));
+        }
+
+        let ref_positions = reference_positions(rec);
+        let mut scores = Vec::with_capacity(probs.len());
+        for (seq_pos, prob) in seq_positions.into_iter().zip(probs) {
+            let Some(prob) = prob else {
+                continue;
+            };
+            let query_pos = if is_reverse {
+                seq.len() - seq_pos - 1
+            } else {
+                seq_pos
+            };
+            // Inserted and soft clipped bases have no position on the reference
+            let Some(abs_pos) = ref_positions[query_pos] else {
+                continue;
+            };
+            let base = if self.mod_code.base == b'N' {
+                seq[seq_pos]
             } else {
-                start + (seq_positions[pos_acc as usize] as u64)
+                self.mod_code.base
             };
-            let score = Score::new(abs_pos, kmer.clone(), false, Some(prob), prob);
+            let kmer = Kmer::from_bytes(&[base]).unwrap();
+            let score = Score::new(abs_pos, kmer, false, Some(prob), prob);
             scores.push(score);
-            pos_acc += 1;
         }
 
         if scores.is_empty() {
@@ -162,6 +223,22 @@ impl<'a> ModProbsMl<'a> {
     }
 }
 
+/// Reference position of each base of the stored sequence, using the CIGAR.
+/// Unaligned reads use the position in the read instead.
+fn reference_positions(rec: &bam::Record) -> Vec<Option<u64>> {
+    let query_len = rec.query_len() as usize;
+    if !rec.flag().is_mapped() || rec.start() < 0 {
+        return (0..query_len as u64).map(Some).collect();
+    }
+    let mut ref_positions = vec![None; query_len];
+    for (query_pos, ref_pos) in rec.matching_pairs() {
+        if let Some(p) = ref_positions.get_mut(query_pos as usize) {
+            *p = Some(ref_pos as u64);
+        }
+    }
+    ref_positions
+}
+
 impl TryFrom<ModBamAlignment<'_>> for ScoredRead {
     type Error = ModBamConversionError;
 
@@ -172,81 +249,46 @@ impl TryFrom<ModBamAlignment<'_>> for ScoredRead {
     }
 }
 
-/// Represents the MM tag, containing data about positions of modifications
-struct ModPosMm {
-    skipped: usize,
-    positions: Vec<u64>,
+/// Alignments from a BAM or SAM file, along with the header
+pub struct BamRecords {
+    reader: Box<dyn RecordReader>,
+    header: bam::Header,
 }
 
-impl ModPosMm {
-    fn parse_mm_tag(mod_tag: &[u8], tag_bytes: &[u8]) -> Option<Self> {
-        let mut skipped = 0;
-        let mut positions = None;
-
-        let mod_base = tag_bytes.split(|&b| b == b';');
-
-        for section in mod_base {
-            match parse_mod_base(mod_tag, section) {
-                None => return None,
-                Some(TagMatches::Matched(pos)) => {
-                    positions = Some(pos);
-                    break;
-                }
-                Some(TagMatches::Skipped(n)) => skipped += n,
-            }
-        }
-        positions.map(|ps| ModPosMm {
-            skipped,
-            positions: ps,
-        })
+impl BamRecords {
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_file(File::open(path)?)
     }
-}
 
-/// The Mm tag can contain positions of multiple modifications for a single read
-/// If we want to focus on a particular modification, in order to get the
-/// modification probabilities, we need to parse the Mm tag so we skip the
-/// modifications we are not interested in Example
-/// C+m,0,3,5;C+Y,1,3,4
-/// If we want to focus on C+Y modification, we need to skip the C+m and count
-/// how many positions belong to it So the string would be parsed into
-/// [Skipped(3), Matched(vec![1,3,4])]
-#[derive(Debug, PartialEq)]
-enum TagMatches {
-    Skipped(usize),
-    Matched(Vec<u64>),
-}
+    pub(crate) fn from_file(file: File) -> io::Result<Self> {
+        let reader = bam::BamReader::from_stream(file, 4)?;
+        let header = reader.header().clone();
+        Ok(Self {
+            reader: Box::new(reader),
+            header,
+        })
+    }
 
-// TODO handle unwraps gracefully
-fn parse_mod_base(mod_tag: &[u8], tag_bytes: &[u8]) -> Option<TagMatches> {
-    let mut base_and_pos = tag_bytes.split(|&b| b == b',');
-    // the modification tag can have a '.' or '?' depending on the modification
-    // detector Since we don't use this information, make sure to take the from
-    // 3 to make sure we are correctly comparing
-    let next_mod_tag = base_and_pos.next()?;
-    let next_mod_tag: &[u8] = match next_mod_tag.last() {
-        Some(b'.') | Some(b'?') => &next_mod_tag[..next_mod_tag.len() - 1],
-        Some(_) => next_mod_tag,
-        None => return None,
-    };
-    if next_mod_tag != mod_tag {
-        return Some(TagMatches::Skipped(base_and_pos.count()));
-    }
-    Some(TagMatches::Matched(
-        base_and_pos
-            .map(|p| std::str::from_utf8(p).unwrap().parse::<u64>().unwrap())
-            .collect(),
-    ))
-}
+    pub(crate) fn from_sam_file(file: File) -> io::Result<Self> {
+        Self::from_sam_reader(file)
+    }
 
-pub struct BamRecords(BamReader<File>);
+    /// CRAM records, decoded with the reference genome, see [cram]
+    pub(crate) fn from_cram_path(path: &Path, reference: &Path) -> io::Result<Self> {
+        Self::from_sam_reader(cram::CramSamStream::open(path, reference)?)
+    }
 
-impl BamRecords {
-    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        Ok(Self(bam::BamReader::from_path(path, 4)?))
+    fn from_sam_reader<R: Read + 'static>(reader: R) -> io::Result<Self> {
+        let reader = SamReader::from_stream(BufReader::new(reader))?;
+        let header = reader.header().clone();
+        Ok(Self {
+            reader: Box::new(reader),
+            header,
+        })
     }
 
-    pub(crate) fn from_file(file: File) -> io::Result<Self> {
-        Ok(Self(bam::BamReader::from_stream(file, 4)?))
+    pub(crate) fn header(&self) -> &bam::Header {
+        &self.header
     }
 }
 
@@ -265,13 +307,13 @@ impl ModBamIter {
     }
 
     pub fn next(&mut self) -> Option<io::Result<ModBamAlignment<'_>>> {
-        let Some(res) = self.records.0.next() else {
+        let Some(res) = self.records.reader.next() else {
             return None;
         };
         let Ok(rec) = res else {
             return Some(Err(res.err().unwrap()));
         };
-        let mba = ModBamAlignment::from_record(rec, &self.base_mod, self.records.0.header());
+        let mba = ModBamAlignment::from_record(rec, &self.base_mod, self.records.header());
         Some(Ok(mba))
     }
 }
@@ -371,8 +413,8 @@ pub(crate) mod test {
         let example = "extra/modbams/MM-double.bam";
         let base_mod = b"C+m".to_vec();
         let mut modbam = BamRecords::from_path(example)?;
-        let header = modbam.0.header().clone();
-        let rec = modbam.0.next().unwrap().unwrap();
+        let header = modbam.header().clone();
+        let rec = modbam.reader.next().unwrap().unwrap();
         let aln = ModBamAlignment {
             rec,
             base_mod: &base_mod,
@@ -398,22 +440,121 @@ pub(crate) mod test {
         Ok(())
     }
 
+    /// Read at 100 with two soft clipped bases, an insertion, and a deletion,
+    /// so query positions 2, 3, 4, 6, 7, 8 are at 100, 101, 102, 103, 105, 106
+    fn cigar_record(reverse: bool, mm: &[u8], ml: &[u8]) -> bam::Record {
+        let mut rec = bam::Record::new();
+        rec.set_name(b"read".iter().copied());
+        rec.set_ref_id(0);
+        rec.set_start(100);
+        rec.set_cigar(b"2S3M1I1M1D2M".iter().copied()).unwrap();
+        rec.set_seq_qual(b"TTACGACGA".iter().copied(), std::iter::empty())
+            .unwrap();
+        rec.flag_mut().set_strand(!reverse);
+        rec.tags_mut().push_string(b"MM", mm);
+        rec.tags_mut().push_array(b"ML", ml);
+        rec
+    }
+
+    fn pos_scores(
+        rec: bam::Record,
+        base_mod: &[u8],
+    ) -> Result<Vec<(u64, f64)>, ModBamConversionError> {
+        let header = bam::Header::new();
+        let aln = ModBamAlignment {
+            rec,
+            base_mod,
+            header: &header,
+        };
+        let mut scores = aln
+            .mod_prob_positions()?
+            .into_scores()?
+            .into_iter()
+            .map(|s| (s.pos, s.score))
+            .collect::<Vec<_>>();
+        scores.sort_by_key(|s| s.0);
+        Ok(scores)
+    }
+
     #[test]
-    fn test_parse_mod_base() {
-        let example = b"C+mh,5,12,0";
-        let res = parse_mod_base(b"C+mh", example);
-        assert_eq!(res, Some(TagMatches::Matched(vec![5, 12, 0])));
+    fn test_cigar_positions() -> eyre::Result<()> {
+        let rec = cigar_record(false, b"C+m,0,0;A+a,0,0,0;", &[64, 128, 16, 32, 48]);
+        let scores = pos_scores(rec.clone(), b"C+m")?;
+        assert_eq!(scores, vec![(101, 64. / 256.), (103, 128. / 256.)]);
+
+        // Second A is inserted so it isn't on the reference
+        let scores = pos_scores(rec, b"A+a")?;
+        assert_eq!(scores, vec![(100, 16. / 256.), (106, 48. / 256.)]);
+        Ok(())
+    }
 
-        let example = b"C+mh?,5,12,0";
-        let res = parse_mod_base(b"C+mh", example);
-        assert_eq!(res, Some(TagMatches::Matched(vec![5, 12, 0])));
+    #[test]
+    fn test_cigar_read_extent() -> eyre::Result<()> {
+        let rec = cigar_record(false, b"A+a,0,0,0;", &[16, 32, 48]);
+        let header = bam::Header::new();
+        let aln = ModBamAlignment {
+            rec,
+            base_mod: b"A+a",
+            header: &header,
+        };
+        let read: ScoredRead = aln.try_into()?;
+        // 3M1I1M1D2M spans 7 reference bases
+        assert_eq!(read.start_0b(), 100);
+        assert_eq!(read.end_1b_excl(), 107);
+        assert!(read
+            .scores()
+            .iter()
+            .all(|s| s.pos >= read.start_0b() && s.pos < read.end_1b_excl()));
+        assert_eq!(make_scoring_vec(&read)[106 - 100 + 1], 48. / 256.);
+        Ok(())
     }
 
     #[test]
-    fn test_parse_mod_base_fail() {
-        let example = b"C-mh,5,12,0";
-        let res = parse_mod_base(b"C+mh", example);
-        assert_eq!(res, Some(TagMatches::Skipped(3)))
+    fn test_reverse_cigar_positions() -> eyre::Result<()> {
+        // Sequenced as TCGTCGTAA, so the first C is at query position 7
+        let rec = cigar_record(true, b"C+m,0,0;", &[64, 128]);
+        let scores = pos_scores(rec, b"C+m")?;
+        assert_eq!(scores, vec![(102, 128. / 256.), (105, 64. / 256.)]);
+
+        // G-m is called on the C bases of the sequenced read
+        let rec = cigar_record(true, b"G-m,1;", &[200]);
+        let scores = pos_scores(rec, b"G-m")?;
+        assert_eq!(scores, vec![(102, 200. / 256.)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_mode() -> eyre::Result<()> {
+        let rec = cigar_record(false, b"C+m.,1;", &[128]);
+        let scores = pos_scores(rec, b"C+m")?;
+        assert_eq!(scores, vec![(101, 0.), (103, 128. / 256.)]);
+
+        let rec = cigar_record(false, b"C+m?,1;", &[128]);
+        let scores = pos_scores(rec, b"C+m")?;
+        assert_eq!(scores, vec![(103, 128. / 256.)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_mod_codes() -> eyre::Result<()> {
+        let rec = cigar_record(false, b"C+mh,0;", &[64, 128]);
+        assert_eq!(pos_scores(rec.clone(), b"C+m")?, vec![(101, 64. / 256.)]);
+        assert_eq!(pos_scores(rec.clone(), b"C+h")?, vec![(101, 128. / 256.)]);
+        assert_eq!(pos_scores(rec.clone(), b"C+mh")?, vec![(101, 192. / 256.)]);
+        assert!(matches!(
+            pos_scores(rec, b"C-m"),
+            Err(ModBamConversionError::NoTags)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_position_out_of_range() {
+        let rec = cigar_record(false, b"C+m,5;", &[64]);
+        assert!(matches!(
+            pos_scores(rec, b"C+m"),
+            Err(ModBamConversionError::PositionOutOfRange)
+        ));
     }
 
     // Scratchpad for eventual reimplementation with nooodles
@@ -435,8 +576,14 @@ pub(crate) mod test {
         let Value::String(mm) = data.get(b"Mm").or(data.get(b"MM")).unwrap().unwrap() else {
             panic!("Not str")
         };
-        let ModPosMm { skipped, positions } = ModPosMm::parse_mm_tag(b"C+m", mm).unwrap();
-        let probs =
-            ml.iter().flatten().collect::<Vec<_>>()[skipped..skipped + positions.len()].to_vec();
+        let mm = MmTag::parse(mm).unwrap();
+        let ml = ml
+            .iter()
+            .flatten()
+            .map(|x| x as f64 / 256.)
+            .collect::<Vec<_>>();
+        let (mod_code, _) = ModCode::parse(b"C+m").unwrap();
+        let calls = mm.calls(&mod_code, &ml).unwrap();
+        assert_eq!(calls.probs.len(), calls.deltas.len());
     }
 }
@@ -0,0 +1,283 @@
+//! Write cawlr scores back into a BAM file as MM and ML modification tags, so
+//! they can be viewed in IGV or used with modkit and other tools for
+//! modified bases.
+use std::{fs::File, path::Path};
+
+use bam::{record::tags::TagValue, BamReader, BamWriter, Record, RecordWriter};
+use eyre::Result;
+use fnv::FnvHashMap;
+
+use super::{arrow_utils::load_apply, metadata::MetadataExt, scored_read::ScoredRead};
+use crate::utils::create_output;
+
+/// Options for converting scored reads into a modification BAM file, see
+/// [ModBamExport::run]
+pub struct ModBamExport {
+    /// Modification in the MM tag format, ie A+a
+    mod_tag: Vec<u8>,
+}
+
+impl ModBamExport {
+    /// Modification to write the scores as, given as the canonical base,
+    /// strand, and modification code, ie "A+a" for 6mA or "C+m" for 5mC. Only
+    /// modifications on the same strand as the read, "+", are supported.
+    pub fn new<B: Into<Vec<u8>>>(mod_tag: B) -> Result<Self> {
+        let mod_tag = mod_tag.into();
+        match mod_tag.as_slice() {
+            [base, b'+', _, ..] if b"ACGTUN".contains(base) => Ok(Self { mod_tag }),
+            _ => Err(eyre::eyre!(
+                "Invalid modification tag {}, expected a base, +, and a modification code, ie A+a",
+                String::from_utf8_lossy(&mod_tag)
+            )),
+        }
+    }
+
+    fn fundamental(&self) -> u8 {
+        self.mod_tag[0]
+    }
+
+    /// Copy every record of the bam to the output, replacing the modification
+    /// calls for the tag with the scores of the read with the same name.
+    /// Calls for other modifications are kept, and reads without scores are
+    /// copied unchanged. Bases without a score are marked as unknown with
+    /// "?" rather than unmodified.
+    ///
+    /// Returns the number of records tagged, and the number of scores left
+    /// out because they weren't on an aligned base of the modification.
+    pub fn run<P, Q, R>(&self, bam: P, scores: Q, output: R) -> Result<(usize, usize)>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        let mut read_scores: FnvHashMap<String, Vec<(u64, f64)>> = FnvHashMap::default();
+        load_apply(File::open(scores)?, |reads: Vec<ScoredRead>| {
+            for read in reads {
+                let scores = read
+                    .scores()
+                    .iter()
+                    .filter(|s| s.score.is_finite())
+                    .map(|s| (s.pos, s.score));
+                read_scores
+                    .entry(read.name().to_string())
+                    .or_default()
+                    .extend(scores);
+            }
+            Ok(())
+        })?;
+
+        let reader = BamReader::from_path(bam, 2u16)?;
+        let mut writer = BamWriter::from_stream(create_output(output)?, reader.header().clone())?;
+        let mut tagged = 0;
+        let mut skipped = 0;
+        for record in reader {
+            let mut record = record?;
+            let name = String::from_utf8_lossy(record.name());
+            if let (Some(scores), true) =
+                (read_scores.get(name.as_ref()), record.flag().is_mapped())
+            {
+                skipped += self.tag_record(&mut record, scores)?;
+                tagged += 1;
+            }
+            writer.write(&record)?;
+        }
+        writer.finish()?;
+        Ok((tagged, skipped))
+    }
+
+    /// Replace the MM and ML tags of the record, returns the number of scores
+    /// that couldn't be placed on the read
+    fn tag_record(&self, record: &mut Record, scores: &[(u64, f64)]) -> Result<usize> {
+        let by_pos = scores.iter().copied().collect::<FnvHashMap<_, _>>();
+        // MM positions count bases in the orientation the read was sequenced
+        let reverse = record.flag().is_reverse_strand();
+        let read_seq: Vec<u8> = if reverse {
+            record.sequence().rev_compl(..).collect()
+        } else {
+            record.sequence().to_vec()
+        };
+        let mut calls = record
+            .matching_pairs()
+            .filter_map(|(query, reference)| {
+                let score = by_pos.get(&(reference as u64))?;
+                let idx = if reverse {
+                    read_seq.len() - 1 - query as usize
+                } else {
+                    query as usize
+                };
+                (read_seq[idx] == self.fundamental()).then_some((idx, *score))
+            })
+            .collect::<Vec<_>>();
+        calls.sort_by_key(|&(idx, _)| idx);
+        let n_skipped = by_pos.len() - calls.len();
+
+        let occurrences = read_seq
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &base)| (base == self.fundamental()).then_some(idx))
+            .collect::<Vec<_>>();
+        let mut section = self.mod_tag.clone();
+        section.push(b'?');
+        let mut probs = Vec::with_capacity(calls.len());
+        let mut next_rank = 0;
+        for (idx, score) in calls {
+            let rank = occurrences
+                .binary_search(&idx)
+                .expect("Calls are on the modified base");
+            section.extend(format!(",{}", rank - next_rank).bytes());
+            next_rank = rank + 1;
+            probs.push(ml_prob(score));
+        }
+        section.push(b';');
+
+        let (mut mm, mut ml) = other_mods(record, &self.mod_tag);
+        mm.extend(section);
+        ml.extend(probs);
+        let tags = record.tags_mut();
+        for name in [b"MM", b"Mm", b"ML", b"Ml"] {
+            tags.remove(name);
+        }
+        tags.push_string(b"MM", &mm);
+        tags.push_array(b"ML", &ml);
+        Ok(n_skipped)
+    }
+}
+
+/// Probability as an ML value, where N covers the range N/256 to (N+1)/256
+fn ml_prob(score: f64) -> u8 {
+    (score.clamp(0.0, 1.0) * 256.0).floor().min(255.0) as u8
+}
+
+/// Sections of the MM tag and their ML probabilities for modifications other
+/// than mod_tag, so they are kept when the tags are rewritten
+fn other_mods(record: &Record, mod_tag: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let tags = record.tags();
+    let Some(TagValue::String(mm, _)) = tags.get(b"MM").or(tags.get(b"Mm")) else {
+        return (Vec::new(), Vec::new());
+    };
+    let probs = match tags.get(b"ML").or(tags.get(b"Ml")) {
+        Some(TagValue::IntArray(ml)) => ml.iter().map(|p| p as u8).collect(),
+        _ => Vec::new(),
+    };
+    let mut kept_mm = Vec::new();
+    let mut kept_ml = Vec::new();
+    let mut offset = 0;
+    for section in mm.split(|&b| b == b';').filter(|s| !s.is_empty()) {
+        let mut fields = section.split(|&b| b == b',');
+        let header = fields.next().unwrap_or_default();
+        let n_calls = fields.count();
+        let header = header.strip_suffix(b"?").unwrap_or(header);
+        let header = header.strip_suffix(b".").unwrap_or(header);
+        // One probability per code for each call, ie two for C+mh
+        let codes = header.get(2..).unwrap_or_default();
+        let n_probs = if codes.iter().all(u8::is_ascii_digit) {
+            n_calls
+        } else {
+            n_calls * codes.len()
+        };
+        if header != mod_tag {
+            kept_mm.extend_from_slice(section);
+            kept_mm.push(b';');
+            let end = (offset + n_probs).min(probs.len());
+            kept_ml.extend_from_slice(&probs[offset.min(end)..end]);
+        }
+        offset += n_probs;
+    }
+    (kept_mm, kept_ml)
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::{
+        arrow::{
+            io::{read_mod_bam_or_arrow, ModFile},
+            metadata::Strand,
+            scored_read::Score,
+        },
+        test_data::{chrom_seq, write_reads, MiniGenome, TestRead, MINUS_READ, PLUS_READ},
+    };
+
+    #[test]
+    fn test_ml_prob() {
+        assert_eq!(ml_prob(0.0), 0);
+        assert_eq!(ml_prob(0.5), 128);
+        assert_eq!(ml_prob(1.0), 255);
+        assert_eq!(ml_prob(2.0), 255);
+    }
+
+    #[test]
+    fn test_export_modbam() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let tmp = TempDir::new()?;
+        // Score every base of each read, only the A bases in the orientation of
+        // the read should be written
+        let reads = [(PLUS_READ, Strand::plus()), (MINUS_READ, Strand::minus())]
+            .iter()
+            .map(|(read, strand)| {
+                let scores = (read.start..read.stop)
+                    .map(|pos| {
+                        let score = (pos % 10) as f64 / 10.0;
+                        Score::new(pos, "A".parse().unwrap(), false, Some(score), score)
+                    })
+                    .collect();
+                TestRead::new(read.name)
+                    .chrom(read.chrom)
+                    .span(read.start, read.stop - read.start)
+                    .strand(*strand)
+                    .scored(scores)
+            })
+            .collect::<Vec<_>>();
+        let scores = tmp.path().join("scores.arrow");
+        write_reads(&scores, &reads)?;
+
+        let output = tmp.path().join("scores.bam");
+        let (tagged, skipped) = ModBamExport::new("A+a")?.run(mini.bam(), &scores, &output)?;
+        assert_eq!(tagged, 2);
+
+        let mut n_calls = 0;
+        read_mod_bam_or_arrow(ModFile::open_mod_bam(&output, "A+a")?, |read| {
+            let seq = chrom_seq(read.chrom()).as_bytes();
+            let modified = if read.strand().is_minus_strand() {
+                b'T'
+            } else {
+                b'A'
+            };
+            for score in read.scores() {
+                assert_eq!(seq[score.pos as usize], modified);
+                assert_eq!(
+                    score.score,
+                    ml_prob((score.pos % 10) as f64 / 10.0) as f64 / 256.
+                );
+                n_calls += 1;
+            }
+            Ok(())
+        })?;
+        let n_scores = reads.iter().map(|r| r.scores().len()).sum::<usize>();
+        assert!(n_calls > 0);
+        assert_eq!(n_calls + skipped, n_scores);
+
+        assert!(ModBamExport::new("a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_mods() {
+        let mut record = Record::new();
+        record.tags_mut().push_string(b"MM", b"C+m,0,1;A+a?,2;");
+        record.tags_mut().push_array(b"ML", &[10u8, 20, 30]);
+        let (mm, ml) = other_mods(&record, b"A+a");
+        assert_eq!(mm, b"C+m,0,1;");
+        assert_eq!(ml, vec![10, 20]);
+
+        // Two probabilities for each C+mh call
+        let mut record = Record::new();
+        record.tags_mut().push_string(b"MM", b"C+mh,0;A+a,1;");
+        record.tags_mut().push_array(b"ML", &[10u8, 20, 30]);
+        let (mm, ml) = other_mods(&record, b"A+a");
+        assert_eq!(mm, b"C+mh,0;");
+        assert_eq!(ml, vec![10, 20]);
+    }
+}
@@ -0,0 +1,306 @@
+//! Export scored reads to Parquet for analysis with Spark, polars, or duckdb.
+//!
+//! Each score becomes its own row, alongside the read it came from. Files are
+//! partitioned hive-style by chromosome, ie `{output_dir}/chrom=chrI/`, so
+//! queries on a single chromosome only read that directory. Only one file is
+//! open at a time, so input sorted by chromosome gets one file per partition
+//! and a new file is started each time a chromosome comes back otherwise.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use arrow2::{
+    array::{Array, BooleanArray, Float64Array, UInt64Array, Utf8Array},
+    chunk::Chunk,
+    datatypes::{DataType, Field, Schema},
+    io::parquet::write::{
+        transverse, CompressionOptions, Encoding, FileWriter, RowGroupIterator, Version,
+        WriteOptions,
+    },
+};
+use eyre::Result;
+
+use super::{
+    io::{read_mod_bam_or_arrow, ModFile},
+    metadata::MetadataExt,
+    scored_read::ScoredRead,
+};
+use crate::utils::create_output;
+
+/// Partition for reads without a chromosome, following the hive convention
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Escape characters that aren't allowed in partition directory names as %XX,
+/// like Hive does, ie HLA-A*01:01 becomes chrom=HLA-A%2A01%3A01
+fn escape_partition(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_control()
+            || matches!(
+                c,
+                '"' | '#' | '%' | '\'' | '*' | '/' | ':' | '=' | '?' | '\\' | '{' | '[' | ']' | '^'
+            )
+        {
+            let _ = write!(escaped, "%{:02X}", c as u8);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// One row per score, the chromosome is left out since it is the partition key
+fn score_schema() -> Schema {
+    Schema::from(vec![
+        Field::new("read_name", DataType::Utf8, false),
+        Field::new("read_start", DataType::UInt64, false),
+        Field::new("read_length", DataType::UInt64, false),
+        Field::new("strand", DataType::Utf8, false),
+        Field::new("pos", DataType::UInt64, false),
+        Field::new("kmer", DataType::Utf8, false),
+        Field::new("skipped", DataType::Boolean, false),
+        Field::new("signal_score", DataType::Float64, true),
+        Field::new("score", DataType::Float64, false),
+    ])
+}
+
+/// Columns for the scores on one chromosome
+#[derive(Default)]
+struct ScoreRows {
+    read_name: Vec<String>,
+    read_start: Vec<u64>,
+    read_length: Vec<u64>,
+    strand: Vec<&'static str>,
+    pos: Vec<u64>,
+    kmer: Vec<String>,
+    skipped: Vec<bool>,
+    signal_score: Vec<Option<f64>>,
+    score: Vec<f64>,
+}
+
+impl ScoreRows {
+    fn push(&mut self, read: &ScoredRead) {
+        for score in read.scores() {
+            self.read_name.push(read.name().to_string());
+            self.read_start.push(read.start_0b());
+            self.read_length.push(read.metadata().length);
+            self.strand.push(read.strand().as_str());
+            self.pos.push(score.pos);
+            self.kmer.push(score.kmer.to_string());
+            self.skipped.push(score.skipped);
+            self.signal_score.push(score.signal_score);
+            self.score.push(score.score);
+        }
+    }
+
+    fn into_chunk(self) -> Chunk<Box<dyn Array>> {
+        Chunk::new(vec![
+            Utf8Array::<i32>::from_slice(&self.read_name).boxed(),
+            UInt64Array::from_vec(self.read_start).boxed(),
+            UInt64Array::from_vec(self.read_length).boxed(),
+            Utf8Array::<i32>::from_slice(&self.strand).boxed(),
+            UInt64Array::from_vec(self.pos).boxed(),
+            Utf8Array::<i32>::from_slice(&self.kmer).boxed(),
+            BooleanArray::from_slice(&self.skipped).boxed(),
+            Float64Array::from(self.signal_score).boxed(),
+            Float64Array::from_vec(self.score).boxed(),
+        ])
+    }
+}
+
+/// Writes scores from cawlr score, or a modification bam, as a Parquet dataset
+/// partitioned by chromosome.
+pub struct ParquetOptions {
+    batch_size: usize,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        ParquetOptions { batch_size: 4096 }
+    }
+}
+
+impl ParquetOptions {
+    /// Number of reads in each row group, defaults to 4096
+    pub fn batch_size(&mut self, batch_size: usize) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Write the dataset to output_dir, returning the path to each partition
+    pub fn run<P: AsRef<Path>>(&self, mod_file: ModFile, output_dir: P) -> Result<Vec<PathBuf>> {
+        let mut writer = PartitionWriter::new(output_dir.as_ref());
+        let mut batch = Vec::with_capacity(self.batch_size);
+        read_mod_bam_or_arrow(mod_file, |read| {
+            batch.push(read);
+            if batch.len() >= self.batch_size {
+                writer.write(&batch)?;
+                batch.clear();
+            }
+            Ok(())
+        })?;
+        if !batch.is_empty() {
+            writer.write(&batch)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Writes each run of reads on the same chromosome to the file of its
+/// partition, keeping only the file of the current chromosome open
+struct PartitionWriter {
+    output_dir: PathBuf,
+    schema: Schema,
+    options: WriteOptions,
+    encodings: Vec<Vec<Encoding>>,
+    current: Option<(String, FileWriter<BufWriter<File>>)>,
+    /// Files written to each partition so far
+    parts: BTreeMap<String, Vec<PathBuf>>,
+}
+
+impl PartitionWriter {
+    fn new(output_dir: &Path) -> Self {
+        let schema = score_schema();
+        let encodings = schema
+            .fields
+            .iter()
+            .map(|f| transverse(&f.data_type, |_| Encoding::Plain))
+            .collect();
+        PartitionWriter {
+            output_dir: output_dir.to_path_buf(),
+            schema,
+            options: WriteOptions {
+                write_statistics: true,
+                compression: CompressionOptions::Uncompressed,
+                version: Version::V2,
+                data_pagesize_limit: None,
+            },
+            encodings,
+            current: None,
+            parts: BTreeMap::new(),
+        }
+    }
+
+    fn write(&mut self, reads: &[ScoredRead]) -> Result<()> {
+        let mut rest = reads;
+        while let Some(first) = rest.first() {
+            let chrom = partition(first);
+            let n = rest
+                .iter()
+                .position(|read| partition(read) != chrom)
+                .unwrap_or(rest.len());
+            let (run, next) = rest.split_at(n);
+            let mut rows = ScoreRows::default();
+            run.iter().for_each(|read| rows.push(read));
+            self.write_rows(chrom, rows)?;
+            rest = next;
+        }
+        Ok(())
+    }
+
+    fn write_rows(&mut self, chrom: &str, rows: ScoreRows) -> Result<()> {
+        if self.current.as_ref().map_or(true, |(c, _)| c != chrom) {
+            self.close()?;
+            let dir = self
+                .output_dir
+                .join(format!("chrom={}", escape_partition(chrom)));
+            fs::create_dir_all(&dir)?;
+            let parts = self.parts.entry(chrom.to_string()).or_default();
+            let path = dir.join(format!("part-{}.parquet", parts.len()));
+            let file = BufWriter::new(create_output(&path)?);
+            let writer = FileWriter::try_new(file, self.schema.clone(), self.options)?;
+            parts.push(path);
+            self.current = Some((chrom.to_string(), writer));
+        }
+        let (_, writer) = self.current.as_mut().unwrap();
+        let row_groups = RowGroupIterator::try_new(
+            std::iter::once(Ok(rows.into_chunk())),
+            &self.schema,
+            self.options,
+            self.encodings.clone(),
+        )?;
+        for group in row_groups {
+            writer.write(group?)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some((_, mut writer)) = self.current.take() {
+            writer.end(None)?;
+        }
+        Ok(())
+    }
+
+    /// Close the last file, returning the files of each partition in order
+    fn finish(mut self) -> Result<Vec<PathBuf>> {
+        self.close()?;
+        Ok(self.parts.into_values().flatten().collect())
+    }
+}
+
+/// Chromosome of the read, or the hive default for unaligned reads
+fn partition(read: &ScoredRead) -> &str {
+    if read.is_unaligned() {
+        DEFAULT_PARTITION
+    } else {
+        read.chrom()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arrow2::io::parquet::read::read_metadata;
+
+    use super::*;
+    use crate::test_data::{test_score, write_reads, MiniGenome, TestRead};
+
+    #[test]
+    fn test_parquet_export() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("scores.arrow");
+        let reads = [("chrI", 3), ("chrII", 2), ("chrI", 1)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(chrom, n_scores))| {
+                let scores = (0..n_scores).map(|j| test_score(10 + j, 0.5)).collect();
+                TestRead::new(format!("read{i}"))
+                    .chrom(chrom)
+                    .scored(scores)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let output_dir = mini.dir().join("scores.parquet");
+        let mut opts = ParquetOptions::default();
+        opts.batch_size(2);
+        let paths = opts.run(ModFile::open_arrow(&input)?, &output_dir)?;
+        // chrI comes back after chrII, so it gets a second file
+        assert_eq!(
+            paths,
+            [
+                output_dir.join("chrom=chrI").join("part-0.parquet"),
+                output_dir.join("chrom=chrI").join("part-1.parquet"),
+                output_dir.join("chrom=chrII").join("part-0.parquet"),
+            ]
+        );
+        let n_rows =
+            |path: &Path| -> Result<usize> { Ok(read_metadata(&mut File::open(path)?)?.num_rows) };
+        assert_eq!(n_rows(&paths[0])?, 3);
+        assert_eq!(n_rows(&paths[1])?, 1);
+        assert_eq!(n_rows(&paths[2])?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_partition() {
+        assert_eq!(escape_partition("chrI"), "chrI");
+        assert_eq!(escape_partition("chrUn_KI270302v1"), "chrUn_KI270302v1");
+        assert_eq!(escape_partition("HLA-A*01:01"), "HLA-A%2A01%3A01");
+        assert_eq!(escape_partition("a/b=c%"), "a%2Fb%3Dc%25");
+    }
+}
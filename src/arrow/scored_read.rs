@@ -1,9 +1,10 @@
 use arrow2::datatypes::{Field, Schema};
-use arrow2_convert::{field::ArrowField, ArrowSerialize, ArrowDeserialize, ArrowField};
+use arrow2_convert::{field::ArrowField, ArrowDeserialize, ArrowField, ArrowSerialize};
 
 use super::{
     eventalign::Eventalign,
-    metadata::{Metadata, MetadataExt},
+    kmer::Kmer,
+    metadata::{Metadata, MetadataExt, MetadataMutExt},
 };
 
 /// Represents a single read scored by cawlr score
@@ -33,6 +34,12 @@ impl ScoredRead {
     pub fn scores(&self) -> &[Score] {
         &self.scores
     }
+
+    /// Iterate over the zero-based position and final score of each scored
+    /// position, in the order they were scored
+    pub fn iter_scores(&self) -> impl Iterator<Item = (u64, f64)> + '_ {
+        self.scores.iter().map(|s| (s.pos, s.score))
+    }
 }
 
 impl MetadataExt for ScoredRead {
@@ -41,20 +48,32 @@ impl MetadataExt for ScoredRead {
     }
 }
 
+impl MetadataMutExt for ScoredRead {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
+
+/// Score for a single position of a read
 #[derive(Default, Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize)]
 pub struct Score {
     pub pos: u64,
-    pub kmer: String,
+    pub kmer: Kmer,
     pub skipped: bool,
     pub signal_score: Option<f64>,
     // pub skip_score: f64,
     pub score: f64,
+    /// Number of measurements combined into the score, ie 2 for a position
+    /// covered by both halves of a duplex read. None for a single measurement.
+    pub n_obs: Option<u32>,
+    /// Score of each measurement, None for a single measurement
+    pub sub_scores: Option<Vec<f64>>,
 }
 
 impl Score {
     pub fn new(
         pos: u64,
-        kmer: String,
+        kmer: Kmer,
         skipped: bool,
         signal_score: Option<f64>,
         // skip_score: f64,
@@ -67,6 +86,48 @@ impl Score {
             signal_score,
             // skip_score,
             score,
+            n_obs: None,
+            sub_scores: None,
+        }
+    }
+
+    /// Zero-based genomic position
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Kmer containing the motif at the position
+    pub fn kmer(&self) -> &Kmer {
+        &self.kmer
+    }
+
+    /// Position had no signal data, so the score doesn't come from the signal
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// Score from the signal alone, None if there was no usable signal
+    pub fn signal_score(&self) -> Option<f64> {
+        self.signal_score
+    }
+
+    /// Final score, ie the probability the position is modified
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    /// Number of measurements combined into the score, see
+    /// [crate::duplex::combine_measurements]
+    pub fn n_obs(&self) -> u32 {
+        self.n_obs.unwrap_or(1)
+    }
+
+    /// Score of each measurement combined into the score, just the score for
+    /// a single measurement
+    pub fn sub_scores(&self) -> &[f64] {
+        match self.sub_scores.as_deref() {
+            Some(sub_scores) => sub_scores,
+            None => std::slice::from_ref(&self.score),
         }
     }
 }
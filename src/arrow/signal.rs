@@ -1,8 +1,10 @@
 use arrow2_convert::ArrowDeserialize;
-use arrow2_convert::ArrowSerialize;
 use arrow2_convert::ArrowField;
+use arrow2_convert::ArrowSerialize;
 use rv::traits::ContinuousDistr;
 
+/// Current measurements of a kmer aligned to a position, from nanopolish
+/// eventalign
 #[derive(Debug, Clone, ArrowField, ArrowSerialize, ArrowDeserialize, Default, PartialEq)]
 pub struct Signal {
     pub pos: u64,
@@ -29,6 +31,31 @@ impl Signal {
         }
     }
 
+    /// Zero-based genomic position of the start of the kmer
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Reference kmer the signal was aligned to
+    pub fn kmer(&self) -> &str {
+        &self.kmer
+    }
+
+    /// Mean current of the events aligned to the kmer, in pA
+    pub fn signal_mean(&self) -> f64 {
+        self.signal_mean
+    }
+
+    /// Total time spent on the kmer, in seconds
+    pub fn signal_time(&self) -> f64 {
+        self.signal_time
+    }
+
+    /// Raw current samples, empty if eventalign was run without --samples
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
     pub fn score_lnsum<M, N>(&self, pm: &M, nm: &N) -> Option<(f64, f64)>
     where
         M: ContinuousDistr<f64>,
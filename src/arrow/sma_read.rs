@@ -0,0 +1,96 @@
+use arrow2_convert::{ArrowDeserialize, ArrowField, ArrowSerialize};
+
+use super::metadata::{Metadata, MetadataExt, MetadataMutExt};
+
+/// Single molecule analysis result for a read, output by cawlr sma
+#[derive(Debug, Clone, ArrowField, Default, ArrowDeserialize, ArrowSerialize, PartialEq, Eq)]
+pub struct SmaRead {
+    pub metadata: Metadata,
+    pub blocks: Vec<SmaBlock>,
+}
+
+impl SmaRead {
+    pub fn new(metadata: Metadata, blocks: Vec<SmaBlock>) -> Self {
+        SmaRead { metadata, blocks }
+    }
+
+    pub fn blocks(&self) -> &[SmaBlock] {
+        &self.blocks
+    }
+
+    /// Blocks where a nucleosome was inferred
+    pub fn nucleosomes(&self) -> impl Iterator<Item = &SmaBlock> {
+        self.blocks.iter().filter(|b| b.state.is_nucleosome())
+    }
+}
+
+impl MetadataExt for SmaRead {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+impl MetadataMutExt for SmaRead {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
+
+/// Contiguous region of a read in a single state. Blocks are sorted and cover
+/// the read from start_0b to end_1b_excl without gaps.
+#[derive(Debug, Clone, Copy, ArrowField, ArrowDeserialize, ArrowSerialize, PartialEq, Eq)]
+pub struct SmaBlock {
+    /// Zero-based genomic start of the block
+    pub start: u64,
+    pub length: u64,
+    pub state: BlockState,
+}
+
+impl SmaBlock {
+    pub fn new(start: u64, length: u64, state: BlockState) -> Self {
+        SmaBlock {
+            start,
+            length,
+            state,
+        }
+    }
+
+    /// One-based exclusive end of the block
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// Whether a block is linker DNA or wrapped around a nucleosome
+#[derive(Debug, Copy, Clone, ArrowField, ArrowDeserialize, ArrowSerialize, PartialEq, Eq)]
+pub struct BlockState {
+    state: i8,
+}
+
+impl BlockState {
+    pub const fn linker() -> Self {
+        BlockState { state: 0 }
+    }
+
+    pub const fn nucleosome() -> Self {
+        BlockState { state: 1 }
+    }
+
+    pub const fn is_nucleosome(&self) -> bool {
+        self.state == 1
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        if self.is_nucleosome() {
+            "nucleosome"
+        } else {
+            "linker"
+        }
+    }
+}
+
+impl Default for BlockState {
+    fn default() -> Self {
+        BlockState::linker()
+    }
+}
@@ -0,0 +1,301 @@
+//! Model-free scoring against a canonical pore model, for when there are no
+//! matched positive and negative controls to train on.
+//!
+//! Each signal is converted to a z-score against the expected current level of
+//! its kmer, and the z-scores of the kmers overlapping a position are combined
+//! into a chi-square statistic. The score is the probability of seeing a
+//! smaller deviation from the pore model by chance, so positions where the
+//! current is shifted by a modification score close to 1. Unmodified positions
+//! score uniformly between 0 and 1, so the scores are only useful for
+//! exploring, not for calling individual positions.
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use arrow2::io::ipc::write::FileWriter;
+use bio::io::fasta::IndexedReader;
+use eyre::{Result, WrapErr};
+use fnv::FnvHashMap;
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+
+use crate::{
+    arrow::{
+        arrow_utils::{load_apply, save, wrap_writer},
+        eventalign::Eventalign,
+        scored_read::{Score, ScoredRead},
+        signal::Signal,
+    },
+    cancel,
+    context::{GenomeCache, SeqCache},
+    motif::{all_bases, Motif, KMER_SIZE},
+    score::{pos_with_data, read_motif_kmers, surrounding_signal, KmerCache},
+    utils::{create_output, ChromAlias},
+};
+
+/// Expected current of a kmer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoreLevel {
+    pub mean: f64,
+    pub stdv: f64,
+}
+
+impl PoreLevel {
+    pub fn zscore(&self, signal_mean: f64) -> f64 {
+        (signal_mean - self.mean) / self.stdv
+    }
+}
+
+/// Expected current level for each kmer, ie from ONT's kmer_models repository
+/// or the .model files bundled with nanopolish.
+#[derive(Debug, Clone, Default)]
+pub struct PoreModel(FnvHashMap<String, PoreLevel>);
+
+impl PoreModel {
+    /// Parse a tab-separated pore model with kmer, level_mean, and level_stdv
+    /// columns. Lines starting with # are skipped and other columns are
+    /// ignored.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines().filter(|line| match line {
+            Ok(line) => !line.starts_with('#') && !line.trim().is_empty(),
+            Err(_) => true,
+        });
+        let header = lines
+            .next()
+            .ok_or_else(|| eyre::eyre!("Empty pore model: {}", path.display()))??;
+        let header = header.split('\t').collect::<Vec<_>>();
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|&h| h == name)
+                .ok_or_else(|| eyre::eyre!("Pore model is missing the {name} column"))
+        };
+        let (kmer_idx, mean_idx, stdv_idx) = (
+            column("kmer")?,
+            column("level_mean")?,
+            column("level_stdv")?,
+        );
+
+        let mut levels = FnvHashMap::default();
+        for (line_no, line) in lines.enumerate() {
+            let line = line?;
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let field = |idx: usize| {
+                fields.get(idx).copied().ok_or_else(|| {
+                    eyre::eyre!("Pore model line {} has too few columns", line_no + 2)
+                })
+            };
+            let level = PoreLevel {
+                mean: field(mean_idx)?.parse()?,
+                stdv: field(stdv_idx)?.parse()?,
+            };
+            if level.stdv <= 0.0 {
+                return Err(eyre::eyre!(
+                    "Pore model level_stdv must be positive for {}",
+                    field(kmer_idx)?
+                ));
+            }
+            levels.insert(field(kmer_idx)?.to_string(), level);
+        }
+        Ok(PoreModel(levels))
+    }
+
+    pub fn get(&self, kmer: &str) -> Option<&PoreLevel> {
+        self.0.get(kmer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Combine the z-scores of the kmers around a position, None if none of the
+/// kmers are in the pore model
+fn baseline_score(signals: &[&Signal], pore_model: &PoreModel) -> Option<f64> {
+    let (n, stat) = signals
+        .iter()
+        .flat_map(|s| pore_model.get(&s.kmer).map(|l| l.zscore(s.signal_mean)))
+        .fold((0, 0.0), |(n, stat), z| (n + 1, stat + z * z));
+    if n == 0 {
+        return None;
+    }
+    let chi2 = ChiSquared::new(n as f64).ok()?;
+    Some(chi2.cdf(stat))
+}
+
+/// Score reads from cawlr collapse against a pore model instead of trained
+/// controls, writing the same output as [crate::score::ScoreOptions].
+pub struct BaselineOptions {
+    pore_model: PoreModel,
+    genome: SeqCache<File>,
+    writer: FileWriter<File>,
+    motifs: Vec<Motif>,
+}
+
+impl BaselineOptions {
+    pub fn try_new<P>(pore_model_filepath: P, genome_filepath: P, output: P) -> Result<Self>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        let pore_model =
+            PoreModel::from_path(&pore_model_filepath).wrap_err("Failed to read pore model")?;
+        let genome = IndexedReader::from_file(&genome_filepath)
+            .map_err(|_| eyre::eyre!("Failed to read genome file"))?;
+        let genome = SeqCache::new(genome, GenomeCache::default())?;
+        let output: PathBuf = output.as_ref().to_path_buf();
+        let writer = wrap_writer(create_output(&output)?, &ScoredRead::schema())?;
+        Ok(BaselineOptions {
+            pore_model,
+            genome,
+            writer,
+            motifs: all_bases(),
+        })
+    }
+
+    pub fn motifs<V: Into<Vec<Motif>>>(&mut self, motifs: V) -> &mut Self {
+        self.motifs = motifs.into();
+        self
+    }
+
+    /// How much of the genome to keep in memory, see [GenomeCache]
+    pub fn genome_cache(&mut self, cache: GenomeCache) -> Result<&mut Self> {
+        self.genome.set_cache(cache)?;
+        Ok(self)
+    }
+
+    /// Match chromosome names of reads to the genome through an alias file,
+    /// ie when reads are on "1" but the genome uses "chr1"
+    pub fn chrom_alias(&mut self, alias: ChromAlias) -> &mut Self {
+        self.genome.set_chrom_alias(alias);
+        self
+    }
+
+    pub fn run<P: AsRef<Path>>(mut self, input: P) -> Result<()> {
+        let file = File::open(input)?;
+        let mut n_scored = 0;
+        let res = load_apply(file, |eventaligns: Vec<Eventalign>| {
+            cancel::check()?;
+            let mut kmer_cache = KmerCache::default();
+            let scored = eventaligns
+                .into_iter()
+                .map(|e| self.score_eventalign(e, &mut kmer_cache))
+                .collect::<Result<Vec<_>>>()?;
+            n_scored += scored
+                .iter()
+                .flat_map(|r| r.scores())
+                .filter(|s| s.signal_score.is_some())
+                .count();
+            save(&mut self.writer, &scored)
+        });
+        self.writer.finish()?;
+        if res.is_ok() && n_scored == 0 {
+            log::warn!(
+                "No positions were scored, check that the pore model has 6-mers in the same \
+                 orientation as the reads"
+            );
+        }
+        Ok(res?)
+    }
+
+    fn score_eventalign(
+        &mut self,
+        read: Eventalign,
+        kmer_cache: &mut KmerCache,
+    ) -> Result<ScoredRead> {
+        let data_pos = pos_with_data(&read);
+        let kmers = read_motif_kmers(&mut self.genome, &read, &self.motifs, KMER_SIZE, kmer_cache)?;
+        let scores = kmers
+            .into_iter()
+            .map(|(pos, kmer)| {
+                let signal_score = surrounding_signal(pos, &data_pos)
+                    .and_then(|signals| baseline_score(&signals, &self.pore_model));
+                Score::new(
+                    pos,
+                    kmer,
+                    signal_score.is_none(),
+                    signal_score,
+                    signal_score.unwrap_or(0.0),
+                )
+            })
+            .collect();
+        Ok(ScoredRead::from_read_with_scores(read, scores))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{arrow::arrow_utils::load_iter, collapse::CollapseOptions, test_data::MiniGenome};
+
+    #[test]
+    fn test_baseline_score() {
+        let signals = [
+            Signal::new(1, "AAAAAA".to_string(), 90.0, 0.0, Vec::new()),
+            Signal::new(2, "CCCCCC".to_string(), 100.0, 0.0, Vec::new()),
+        ];
+        let signals = signals.iter().collect::<Vec<_>>();
+        let mut model = PoreModel::default();
+        assert_eq!(baseline_score(&signals, &model), None);
+
+        let level = PoreLevel {
+            mean: 90.0,
+            stdv: 2.0,
+        };
+        model.0.insert("AAAAAA".to_string(), level);
+        assert_eq!(baseline_score(&signals, &model), Some(0.0));
+        model.0.insert("CCCCCC".to_string(), level);
+        assert!(baseline_score(&signals, &model).unwrap() > 0.99);
+    }
+
+    #[test]
+    fn test_baseline() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        // Kmers shifted far from their observed current, as if every
+        // position was modified
+        let reads =
+            load_iter(File::open(&collapsed)?).collect::<Result<Vec<Vec<Eventalign>>, _>>()?;
+        let pore_model_path = mini.dir().join("pore.model");
+        let mut pore_model = File::create(&pore_model_path)?;
+        writeln!(pore_model, "#ont_model_name\ttest")?;
+        writeln!(pore_model, "kmer\tlevel_mean\tlevel_stdv\tsd_mean")?;
+        for signal in reads.iter().flatten().flat_map(|r| r.signal_iter()) {
+            writeln!(
+                pore_model,
+                "{}\t{}\t1.0\t1.0",
+                signal.kmer,
+                signal.signal_mean + 50.0
+            )?;
+        }
+        drop(pore_model);
+        assert!(!PoreModel::from_path(&pore_model_path)?.is_empty());
+
+        let output = mini.dir().join("baseline");
+        let mut baseline = BaselineOptions::try_new(&pore_model_path, &mini.genome(), &output)?;
+        baseline.motifs(vec![Motif::new("AT", 2)]);
+        baseline.run(&collapsed)?;
+
+        let mut scores = Vec::new();
+        load_apply(File::open(output)?, |reads: Vec<ScoredRead>| {
+            let read_scores = reads.iter().flat_map(|r| r.scores());
+            scores.extend(read_scores.filter_map(|s| s.signal_score));
+            Ok(())
+        })?;
+        assert!(!scores.is_empty());
+        assert!(scores.iter().all(|&s| s > 0.99));
+        Ok(())
+    }
+}
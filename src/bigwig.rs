@@ -0,0 +1,857 @@
+//! Minimal writer for bigWig files, the indexed binary form of bedGraph files
+//! used by genome browsers. Follows the BBI format from the UCSC Genome
+//! Browser, with zlib compressed bedGraph sections, a chromosome B+ tree, an
+//! R-tree index, and zoom levels so browsers can show whole chromosomes
+//! without reading every base.
+use std::{
+    collections::BTreeMap,
+    io::{Seek, SeekFrom, Write},
+};
+
+use eyre::Result;
+use flate2::{write::ZlibEncoder, Compression};
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const BPT_MAGIC: u32 = 0x78CA_8C91;
+const CIRTREE_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const SUMMARY_SIZE: u64 = 40;
+
+/// Items per compressed block and children per R-tree node, same as the
+/// defaults of bedGraphToBigWig
+const ITEMS_PER_SLOT: usize = 1024;
+const BLOCK_SIZE: usize = 256;
+
+/// bedGraph section type
+const BEDGRAPH_TYPE: u8 = 1;
+const MAX_ZOOM_LEVELS: usize = 10;
+const ZOOM_FACTOR: u32 = 4;
+const FIRST_REDUCTION: u32 = 10;
+
+/// Value over the zero-based, half-open interval start..end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BedGraphEntry {
+    pub start: u32,
+    pub end: u32,
+    pub value: f32,
+}
+
+impl BedGraphEntry {
+    pub fn new(start: u32, end: u32, value: f32) -> Self {
+        Self { start, end, value }
+    }
+
+    fn len(&self) -> u64 {
+        (self.end - self.start) as u64
+    }
+}
+
+/// Location of a compressed block, used to build the R-tree index
+#[derive(Debug, Clone, Copy)]
+struct IndexItem {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Summary {
+    valid_count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            valid_count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            sum_squares: 0.0,
+        }
+    }
+}
+
+impl Summary {
+    fn add(&mut self, value: f32, n_bases: u64) {
+        let value = value as f64;
+        self.valid_count += n_bases;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * n_bases as f64;
+        self.sum_squares += value * value * n_bases as f64;
+    }
+}
+
+/// Summary of a zoom level bin
+struct ZoomRecord {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    summary: Summary,
+}
+
+fn put_u16(buf: &mut Vec<u8>, x: u16) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, x: u64) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_f32(buf: &mut Vec<u8>, x: f32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_f64(buf: &mut Vec<u8>, x: f64) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Keeps track of the current offset in the file
+struct OffsetWriter<W> {
+    inner: W,
+    offset: u64,
+}
+
+impl<W: Write> OffsetWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.write_all(buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes a bigWig file. Chromosomes are written in the order of their
+/// names, and the entries of each chromosome must be sorted and not overlap.
+pub struct BigWigWriter {
+    chrom_sizes: Vec<(String, u32)>,
+}
+
+impl BigWigWriter {
+    /// Every chromosome with data needs a size, usually from the .fai index
+    /// of the genome
+    pub fn new(mut chrom_sizes: Vec<(String, u32)>) -> Self {
+        chrom_sizes.sort();
+        chrom_sizes.dedup_by(|a, b| a.0 == b.0);
+        Self { chrom_sizes }
+    }
+
+    fn chrom_id(&self, chrom: &str) -> Result<u32> {
+        self.chrom_sizes
+            .binary_search_by(|(name, _)| name.as_str().cmp(chrom))
+            .map(|idx| idx as u32)
+            .map_err(|_| eyre::eyre!("No size for chromosome {chrom}, is it in the genome?"))
+    }
+
+    pub fn write<W: Write + Seek>(
+        &self,
+        writer: W,
+        data: &BTreeMap<String, Vec<BedGraphEntry>>,
+    ) -> Result<()> {
+        let mut by_id = Vec::with_capacity(data.len());
+        for (chrom, entries) in data {
+            let chrom_id = self.chrom_id(chrom)?;
+            let size = self.chrom_sizes[chrom_id as usize].1;
+            let mut last_end = 0;
+            for entry in entries {
+                if entry.start < last_end || entry.end <= entry.start || entry.end > size {
+                    return Err(eyre::eyre!(
+                        "Invalid bedGraph entry {chrom}:{}-{}, entries must be sorted, not \
+                         overlap, and be within the chromosome size {size}",
+                        entry.start,
+                        entry.end
+                    ));
+                }
+                last_end = entry.end;
+            }
+            by_id.push((chrom_id, entries.as_slice()));
+        }
+        by_id.sort_by_key(|(chrom_id, _)| *chrom_id);
+
+        let zoom_levels = self.zoom_levels(&by_id);
+        let mut writer = OffsetWriter {
+            inner: writer,
+            offset: 0,
+        };
+        // Header, zoom headers, and total summary are filled in at the end
+        let reserved = HEADER_SIZE + ZOOM_HEADER_SIZE * zoom_levels.len() as u64 + SUMMARY_SIZE;
+        writer.write(&vec![0u8; reserved as usize])?;
+        let total_summary_offset = reserved - SUMMARY_SIZE;
+
+        let chrom_tree_offset = writer.offset;
+        self.write_chrom_tree(&mut writer)?;
+
+        let full_data_offset = writer.offset;
+        let mut max_block_size = 0;
+        let (sections, total_summary) = write_sections(&mut writer, &by_id, &mut max_block_size)?;
+        let full_index_offset = writer.offset;
+        write_rtree(&mut writer, &sections)?;
+
+        let mut zoom_headers = Vec::with_capacity(zoom_levels.len());
+        for (reduction, records) in zoom_levels.iter() {
+            let data_offset = writer.offset;
+            let blocks = write_zoom_records(&mut writer, records, &mut max_block_size)?;
+            let index_offset = writer.offset;
+            write_rtree(&mut writer, &blocks)?;
+            zoom_headers.push((*reduction, data_offset, index_offset));
+        }
+
+        let mut header = Vec::with_capacity(reserved as usize);
+        put_u32(&mut header, BIGWIG_MAGIC);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, zoom_levels.len() as u16);
+        put_u64(&mut header, chrom_tree_offset);
+        put_u64(&mut header, full_data_offset);
+        put_u64(&mut header, full_index_offset);
+        // Field counts and autoSql offset are only used by bigBed
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u64(&mut header, 0);
+        put_u64(&mut header, total_summary_offset);
+        put_u32(&mut header, max_block_size as u32);
+        // Extension offset, unused
+        put_u64(&mut header, 0);
+        for (reduction, data_offset, index_offset) in zoom_headers {
+            put_u32(&mut header, reduction);
+            put_u32(&mut header, 0);
+            put_u64(&mut header, data_offset);
+            put_u64(&mut header, index_offset);
+        }
+        let total_summary = total_summary.unwrap_or(Summary {
+            min: 0.0,
+            max: 0.0,
+            ..Summary::default()
+        });
+        put_u64(&mut header, total_summary.valid_count);
+        put_f64(&mut header, total_summary.min);
+        put_f64(&mut header, total_summary.max);
+        put_f64(&mut header, total_summary.sum);
+        put_f64(&mut header, total_summary.sum_squares);
+
+        let mut inner = writer.inner;
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(&header)?;
+        inner.flush()?;
+        Ok(())
+    }
+
+    /// B+ tree mapping chromosome names to ids and sizes, laid out like
+    /// bedGraphToBigWig with the root first and every node padded to the
+    /// block size. Nodes hold at most [BLOCK_SIZE] items, so fragmented
+    /// assemblies with more chromosomes than fit in a node get more levels.
+    fn write_chrom_tree<W: Write>(&self, writer: &mut OffsetWriter<W>) -> Result<()> {
+        let n_chroms = self.chrom_sizes.len();
+        let key_size = self
+            .chrom_sizes
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(1)
+            .max(1);
+        let block_size = n_chroms.clamp(1, BLOCK_SIZE);
+        let mut buf = Vec::new();
+        put_u32(&mut buf, BPT_MAGIC);
+        put_u32(&mut buf, block_size as u32);
+        put_u32(&mut buf, key_size as u32);
+        put_u32(&mut buf, 8);
+        put_u64(&mut buf, n_chroms as u64);
+        put_u64(&mut buf, 0);
+
+        let key = |buf: &mut Vec<u8>, chrom_id: usize| {
+            let mut key = self.chrom_sizes[chrom_id].0.as_bytes().to_vec();
+            key.resize(key_size, 0);
+            buf.extend_from_slice(&key);
+        };
+        // Child offsets and chromosome id and size are both 8 bytes, so every
+        // node is the same size
+        let item_size = key_size + 8;
+        let node_size = 4 + block_size * item_size;
+        let mut n_levels = 1;
+        let mut n_nodes = n_chroms;
+        while n_nodes > block_size {
+            n_nodes = n_nodes.div_ceil(block_size);
+            n_levels += 1;
+        }
+
+        // Index levels from the root down, each slot pointing at the node of
+        // the next level that starts with its key
+        for level in (1..n_levels).rev() {
+            let slot_span = block_size.pow(level as u32);
+            let node_span = slot_span * block_size;
+            let n_nodes = n_chroms.div_ceil(node_span);
+            let mut child_offset = writer.offset + (buf.len() + n_nodes * node_size) as u64;
+            for node_start in (0..n_chroms).step_by(node_span) {
+                let slots = (node_start..n_chroms.min(node_start + node_span)).step_by(slot_span);
+                buf.push(0);
+                buf.push(0);
+                put_u16(&mut buf, slots.len() as u16);
+                for first in slots.clone() {
+                    key(&mut buf, first);
+                    put_u64(&mut buf, child_offset);
+                    child_offset += node_size as u64;
+                }
+                buf.resize(buf.len() + (block_size - slots.len()) * item_size, 0);
+            }
+        }
+
+        for node_start in (0..n_chroms.max(1)).step_by(block_size) {
+            let node_end = n_chroms.min(node_start + block_size);
+            buf.push(1);
+            buf.push(0);
+            put_u16(&mut buf, (node_end - node_start) as u16);
+            for chrom_id in node_start..node_end {
+                key(&mut buf, chrom_id);
+                put_u32(&mut buf, chrom_id as u32);
+                put_u32(&mut buf, self.chrom_sizes[chrom_id].1);
+            }
+            buf.resize(
+                buf.len() + (block_size - (node_end - node_start)) * item_size,
+                0,
+            );
+        }
+        writer.write(&buf)
+    }
+
+    /// Summaries of bins of increasing size. Levels that don't at least halve
+    /// the number of records are skipped.
+    fn zoom_levels(&self, by_id: &[(u32, &[BedGraphEntry])]) -> Vec<(u32, Vec<ZoomRecord>)> {
+        let mut levels = Vec::new();
+        let mut n_records = by_id.iter().map(|(_, e)| e.len()).sum::<usize>();
+        let mut reduction = FIRST_REDUCTION;
+        while levels.len() < MAX_ZOOM_LEVELS && n_records > 1 {
+            let records = self.zoom_records(by_id, reduction);
+            if records.len() * 2 <= n_records {
+                n_records = records.len();
+                levels.push((reduction, records));
+            }
+            reduction = match reduction.checked_mul(ZOOM_FACTOR) {
+                Some(r) => r,
+                None => break,
+            };
+        }
+        levels
+    }
+
+    fn zoom_records(&self, by_id: &[(u32, &[BedGraphEntry])], reduction: u32) -> Vec<ZoomRecord> {
+        let mut records: Vec<ZoomRecord> = Vec::new();
+        for &(chrom_id, entries) in by_id {
+            let size = self.chrom_sizes[chrom_id as usize].1;
+            for entry in entries {
+                let mut start = entry.start;
+                while start < entry.end {
+                    let bin_start = start - start % reduction;
+                    let bin_end = bin_start.saturating_add(reduction).min(size);
+                    let end = entry.end.min(bin_end);
+                    match records.last_mut() {
+                        Some(r) if r.chrom_id == chrom_id && r.start == bin_start => {
+                            r.summary.add(entry.value, (end - start) as u64)
+                        }
+                        _ => {
+                            let mut summary = Summary::default();
+                            summary.add(entry.value, (end - start) as u64);
+                            records.push(ZoomRecord {
+                                chrom_id,
+                                start: bin_start,
+                                end: bin_end,
+                                summary,
+                            });
+                        }
+                    }
+                    start = end;
+                }
+            }
+        }
+        records
+    }
+}
+
+/// Write the full resolution data as compressed bedGraph sections, returning
+/// the location of each section and the summary of all the data
+fn write_sections<W: Write>(
+    writer: &mut OffsetWriter<W>,
+    by_id: &[(u32, &[BedGraphEntry])],
+    max_block_size: &mut usize,
+) -> Result<(Vec<IndexItem>, Option<Summary>)> {
+    let n_sections = by_id
+        .iter()
+        .map(|(_, e)| e.len().div_ceil(ITEMS_PER_SLOT))
+        .sum::<usize>();
+    let mut buf = Vec::new();
+    put_u64(&mut buf, n_sections as u64);
+    writer.write(&buf)?;
+
+    let mut sections = Vec::with_capacity(n_sections);
+    let mut total: Option<Summary> = None;
+    for &(chrom_id, entries) in by_id {
+        for chunk in entries.chunks(ITEMS_PER_SLOT) {
+            let start = chunk[0].start;
+            let end = chunk[chunk.len() - 1].end;
+            let mut buf = Vec::with_capacity(24 + chunk.len() * 12);
+            put_u32(&mut buf, chrom_id);
+            put_u32(&mut buf, start);
+            put_u32(&mut buf, end);
+            // Item step and span are only used by fixed and variable step
+            put_u32(&mut buf, 0);
+            put_u32(&mut buf, 0);
+            buf.push(BEDGRAPH_TYPE);
+            buf.push(0);
+            put_u16(&mut buf, chunk.len() as u16);
+            for entry in chunk {
+                put_u32(&mut buf, entry.start);
+                put_u32(&mut buf, entry.end);
+                put_f32(&mut buf, entry.value);
+                total
+                    .get_or_insert_with(Summary::default)
+                    .add(entry.value, entry.len());
+            }
+            *max_block_size = (*max_block_size).max(buf.len());
+            let compressed = compress(&buf)?;
+            sections.push(IndexItem {
+                chrom_id,
+                start,
+                end,
+                offset: writer.offset,
+                size: compressed.len() as u64,
+            });
+            writer.write(&compressed)?;
+        }
+    }
+    Ok((sections, total))
+}
+
+fn write_zoom_records<W: Write>(
+    writer: &mut OffsetWriter<W>,
+    records: &[ZoomRecord],
+    max_block_size: &mut usize,
+) -> Result<Vec<IndexItem>> {
+    let mut buf = Vec::new();
+    put_u32(&mut buf, records.len() as u32);
+    writer.write(&buf)?;
+
+    let mut blocks = Vec::new();
+    for chunk in records.chunks(ITEMS_PER_SLOT) {
+        let mut buf = Vec::with_capacity(chunk.len() * 32);
+        for record in chunk {
+            put_u32(&mut buf, record.chrom_id);
+            put_u32(&mut buf, record.start);
+            put_u32(&mut buf, record.end);
+            put_u32(&mut buf, record.summary.valid_count as u32);
+            put_f32(&mut buf, record.summary.min as f32);
+            put_f32(&mut buf, record.summary.max as f32);
+            put_f32(&mut buf, record.summary.sum as f32);
+            put_f32(&mut buf, record.summary.sum_squares as f32);
+        }
+        *max_block_size = (*max_block_size).max(buf.len());
+        let compressed = compress(&buf)?;
+        let (first, last) = (&chunk[0], &chunk[chunk.len() - 1]);
+        blocks.push(IndexItem {
+            chrom_id: first.chrom_id,
+            start: first.start,
+            end: last.end,
+            offset: writer.offset,
+            size: compressed.len() as u64,
+        });
+        writer.write(&compressed)?;
+    }
+    Ok(blocks)
+}
+
+/// Bounds of a node in the R-tree
+#[derive(Clone, Copy)]
+struct Bounds {
+    start_chrom: u32,
+    start: u32,
+    end_chrom: u32,
+    end: u32,
+}
+
+fn bounds(items: &[IndexItem]) -> Bounds {
+    let (first, last) = (&items[0], &items[items.len() - 1]);
+    Bounds {
+        start_chrom: first.chrom_id,
+        start: first.start,
+        end_chrom: last.chrom_id,
+        end: items
+            .iter()
+            .filter(|i| i.chrom_id == last.chrom_id)
+            .map(|i| i.end)
+            .max()
+            .unwrap_or(last.end),
+    }
+}
+
+fn merge_bounds(bounds: &[Bounds]) -> Bounds {
+    let (first, last) = (bounds[0], bounds[bounds.len() - 1]);
+    Bounds {
+        start_chrom: first.start_chrom,
+        start: first.start,
+        end_chrom: last.end_chrom,
+        end: last.end,
+    }
+}
+
+fn put_bounds(buf: &mut Vec<u8>, bounds: Bounds) {
+    put_u32(buf, bounds.start_chrom);
+    put_u32(buf, bounds.start);
+    put_u32(buf, bounds.end_chrom);
+    put_u32(buf, bounds.end);
+}
+
+/// Write the R-tree index of the blocks, with the root node first and the
+/// leaves last. Blocks must be sorted by chromosome and start.
+fn write_rtree<W: Write>(writer: &mut OffsetWriter<W>, items: &[IndexItem]) -> Result<()> {
+    let index_offset = writer.offset;
+    let mut buf = Vec::new();
+    put_u32(&mut buf, CIRTREE_MAGIC);
+    put_u32(&mut buf, BLOCK_SIZE as u32);
+    put_u64(&mut buf, items.len() as u64);
+    let total = if items.is_empty() {
+        Bounds {
+            start_chrom: 0,
+            start: 0,
+            end_chrom: 0,
+            end: 0,
+        }
+    } else {
+        bounds(items)
+    };
+    put_bounds(&mut buf, total);
+    // End of the data being indexed, which is always right before the index
+    put_u64(&mut buf, index_offset);
+    put_u32(&mut buf, ITEMS_PER_SLOT as u32);
+    put_u32(&mut buf, 0);
+
+    if items.is_empty() {
+        buf.extend_from_slice(&[1, 0, 0, 0]);
+        return writer.write(&buf);
+    }
+
+    // Bounds and number of children of each node, from the leaves up to the
+    // root
+    let leaves = items.chunks(BLOCK_SIZE).map(bounds).collect::<Vec<_>>();
+    let mut levels = vec![leaves];
+    while levels.last().unwrap().len() > 1 {
+        let parents = levels
+            .last()
+            .unwrap()
+            .chunks(BLOCK_SIZE)
+            .map(merge_bounds)
+            .collect::<Vec<_>>();
+        levels.push(parents);
+    }
+    levels.reverse();
+
+    // Offset where each level starts, leaves are larger since they store the
+    // size of each block
+    let n_levels = levels.len();
+    let mut level_offsets = Vec::with_capacity(n_levels);
+    let mut offset = index_offset + buf.len() as u64;
+    for (depth, nodes) in levels.iter().enumerate() {
+        level_offsets.push(offset);
+        let n_children = if depth + 1 == n_levels {
+            items.len()
+        } else {
+            levels[depth + 1].len()
+        };
+        let item_size = if depth + 1 == n_levels { 32 } else { 24 };
+        offset += (nodes.len() * 4 + n_children * item_size) as u64;
+    }
+
+    for depth in 0..n_levels {
+        if depth + 1 == n_levels {
+            for chunk in items.chunks(BLOCK_SIZE) {
+                buf.push(1);
+                buf.push(0);
+                put_u16(&mut buf, chunk.len() as u16);
+                for item in chunk {
+                    put_bounds(&mut buf, bounds(std::slice::from_ref(item)));
+                    put_u64(&mut buf, item.offset);
+                    put_u64(&mut buf, item.size);
+                }
+            }
+        } else {
+            let children = &levels[depth + 1];
+            let is_leaf_child = depth + 2 == n_levels;
+            // Size of each child node, to find where each one starts
+            let mut child_offset = level_offsets[depth + 1];
+            let mut child_idx = 0;
+            for chunk in children.chunks(BLOCK_SIZE) {
+                buf.push(0);
+                buf.push(0);
+                put_u16(&mut buf, chunk.len() as u16);
+                for child in chunk {
+                    put_bounds(&mut buf, *child);
+                    put_u64(&mut buf, child_offset);
+                    let n_grandchildren = if is_leaf_child {
+                        (items.len() - child_idx * BLOCK_SIZE).min(BLOCK_SIZE)
+                    } else {
+                        (levels[depth + 2].len() - child_idx * BLOCK_SIZE).min(BLOCK_SIZE)
+                    };
+                    let item_size = if is_leaf_child { 32 } else { 24 };
+                    child_offset += (4 + n_grandchildren * item_size) as u64;
+                    child_idx += 1;
+                }
+            }
+        }
+    }
+    writer.write(&buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn f32_at(buf: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn f64_at(buf: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Offset and size of every block in the R-tree, in order
+    fn rtree_blocks(buf: &[u8], index_offset: usize) -> Vec<(u64, u64)> {
+        assert_eq!(u32_at(buf, index_offset), CIRTREE_MAGIC);
+        let n_items = u64_at(buf, index_offset + 8);
+        let mut blocks = Vec::new();
+        read_node(buf, index_offset + 48, &mut blocks);
+        assert_eq!(blocks.len() as u64, n_items);
+        blocks
+    }
+
+    fn read_node(buf: &[u8], offset: usize, blocks: &mut Vec<(u64, u64)>) {
+        let is_leaf = buf[offset] == 1;
+        let count = u16_at(buf, offset + 2) as usize;
+        for i in 0..count {
+            if is_leaf {
+                let item = offset + 4 + i * 32;
+                blocks.push((u64_at(buf, item + 16), u64_at(buf, item + 24)));
+            } else {
+                let item = offset + 4 + i * 24;
+                read_node(buf, u64_at(buf, item + 16) as usize, blocks);
+            }
+        }
+    }
+
+    fn decompress(buf: &[u8], (offset, size): (u64, u64)) -> Vec<u8> {
+        let mut data = Vec::new();
+        ZlibDecoder::new(&buf[offset as usize..(offset + size) as usize])
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    /// Chromosome names of a node of the chromosome B+ tree and its children,
+    /// in order of their ids
+    fn read_chrom_node(buf: &[u8], offset: usize, key_size: usize, names: &mut Vec<String>) {
+        let is_leaf = buf[offset] == 1;
+        let count = u16_at(buf, offset + 2) as usize;
+        for i in 0..count {
+            let item = offset + 4 + i * (key_size + 8);
+            if is_leaf {
+                let name = String::from_utf8(buf[item..item + key_size].to_vec()).unwrap();
+                assert_eq!(u32_at(buf, item + key_size) as usize, names.len());
+                names.push(name.trim_end_matches('\0').to_string());
+            } else {
+                read_chrom_node(buf, u64_at(buf, item + key_size) as usize, key_size, names);
+            }
+        }
+    }
+
+    /// Read back the chromosome names and every bedGraph entry
+    fn read_bigwig(buf: &[u8]) -> BTreeMap<String, Vec<BedGraphEntry>> {
+        assert_eq!(u32_at(buf, 0), BIGWIG_MAGIC);
+        let chrom_tree = u64_at(buf, 8) as usize;
+        assert_eq!(u32_at(buf, chrom_tree), BPT_MAGIC);
+        let key_size = u32_at(buf, chrom_tree + 8) as usize;
+        let mut names = Vec::new();
+        read_chrom_node(buf, chrom_tree + 32, key_size, &mut names);
+        assert_eq!(names.len() as u64, u64_at(buf, chrom_tree + 16));
+
+        let uncompress_buf_size = u32_at(buf, 52) as usize;
+        let mut data: BTreeMap<String, Vec<BedGraphEntry>> = BTreeMap::new();
+        for block in rtree_blocks(buf, u64_at(buf, 24) as usize) {
+            let section = decompress(buf, block);
+            assert!(section.len() <= uncompress_buf_size);
+            assert_eq!(section[20], BEDGRAPH_TYPE);
+            let chrom = &names[u32_at(&section, 0) as usize];
+            for i in 0..u16_at(&section, 22) as usize {
+                let item = 24 + i * 12;
+                data.entry(chrom.clone())
+                    .or_default()
+                    .push(BedGraphEntry::new(
+                        u32_at(&section, item),
+                        u32_at(&section, item + 4),
+                        f32_at(&section, item + 8),
+                    ));
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_bigwig_round_trip() -> Result<()> {
+        let mut data = BTreeMap::new();
+        // Enough sections for a two level R-tree
+        let n_entries = ITEMS_PER_SLOT * BLOCK_SIZE + 10;
+        let chr_i = (0..n_entries as u32)
+            .map(|i| BedGraphEntry::new(i * 2, i * 2 + 1, (i % 10) as f32 / 10.0))
+            .collect::<Vec<_>>();
+        data.insert("chrI".to_string(), chr_i);
+        data.insert(
+            "chrII".to_string(),
+            vec![
+                BedGraphEntry::new(5, 10, 0.5),
+                BedGraphEntry::new(10, 20, 1.0),
+            ],
+        );
+        let writer = BigWigWriter::new(vec![
+            ("chrII".to_string(), 100),
+            ("chrI".to_string(), 1_000_000),
+            ("chrIII".to_string(), 50),
+        ]);
+        let mut cursor = Cursor::new(Vec::new());
+        writer.write(&mut cursor, &data)?;
+        let buf = cursor.into_inner();
+        assert_eq!(read_bigwig(&buf), data);
+
+        // Total summary covers every base
+        let summary_offset = u64_at(&buf, 44) as usize;
+        assert_eq!(u64_at(&buf, summary_offset), n_entries as u64 + 15);
+        assert_eq!(f64_at(&buf, summary_offset + 8), 0.0);
+        assert_eq!(f64_at(&buf, summary_offset + 16), 1.0);
+
+        // Each zoom level summarizes every base
+        let n_zooms = u16_at(&buf, 6) as usize;
+        assert!(n_zooms > 0);
+        for zoom in 0..n_zooms {
+            let header = 64 + zoom * 24;
+            let index_offset = u64_at(&buf, header + 16) as usize;
+            let valid = rtree_blocks(&buf, index_offset)
+                .into_iter()
+                .flat_map(|block| {
+                    let records = decompress(&buf, block);
+                    (0..records.len() / 32)
+                        .map(|i| u32_at(&records, i * 32 + 12) as u64)
+                        .collect::<Vec<_>>()
+                })
+                .sum::<u64>();
+            assert_eq!(valid, n_entries as u64 + 15);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bigwig_many_chroms() -> Result<()> {
+        // More chromosomes than fit in a u16, like fragmented assemblies
+        let n_chroms = 70_000;
+        let chrom_sizes = (0..n_chroms)
+            .map(|i| (format!("contig{i:05}"), 100))
+            .collect::<Vec<_>>();
+        let mut data = BTreeMap::new();
+        for i in [0, 255, 256, 65_536, n_chroms - 1] {
+            data.insert(
+                format!("contig{i:05}"),
+                vec![BedGraphEntry::new(0, 10, 1.0)],
+            );
+        }
+        let mut cursor = Cursor::new(Vec::new());
+        BigWigWriter::new(chrom_sizes).write(&mut cursor, &data)?;
+        assert_eq!(read_bigwig(&cursor.into_inner()), data);
+        Ok(())
+    }
+
+    /// Compare against the UCSC tools, which browsers and most downstream
+    /// tools share their reading code with
+    #[test]
+    #[ignore = "Needs bigWigToBedGraph and bigWigInfo from UCSC on the PATH"]
+    fn test_bigwig_ucsc() -> Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("test.bw");
+        let chrom_sizes = (0..1000)
+            .map(|i| (format!("chr{i}"), 1_000_000))
+            .collect::<Vec<_>>();
+        let mut data = BTreeMap::new();
+        let mut expected = String::new();
+        for chrom in ["chr0", "chr10", "chr999"] {
+            let entries = (0..5000)
+                .map(|i| BedGraphEntry::new(i * 100, i * 100 + 50, (i % 7) as f32))
+                .collect::<Vec<_>>();
+            data.insert(chrom.to_string(), entries);
+        }
+        for (chrom, entries) in data.iter() {
+            for e in entries {
+                expected.push_str(&format!("{chrom}\t{}\t{}\t{}\n", e.start, e.end, e.value));
+            }
+        }
+        BigWigWriter::new(chrom_sizes).write(std::fs::File::create(&path)?, &data)?;
+
+        let bedgraph = dir.path().join("test.bedgraph");
+        let status = std::process::Command::new("bigWigToBedGraph")
+            .arg(&path)
+            .arg(&bedgraph)
+            .status()?;
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(bedgraph)?, expected);
+
+        let info = std::process::Command::new("bigWigInfo")
+            .arg(&path)
+            .output()?;
+        assert!(info.status.success());
+        let info = String::from_utf8(info.stdout)?;
+        assert!(info.contains("chromCount: 1000"), "{info}");
+        assert!(info.contains("basesCovered: 750,000"), "{info}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bigwig_invalid() {
+        let writer = BigWigWriter::new(vec![("chrI".to_string(), 100)]);
+        let mut data = BTreeMap::new();
+        data.insert("chrII".to_string(), vec![BedGraphEntry::new(0, 1, 1.0)]);
+        assert!(writer.write(Cursor::new(Vec::new()), &data).is_err());
+
+        let mut data = BTreeMap::new();
+        data.insert(
+            "chrI".to_string(),
+            vec![
+                BedGraphEntry::new(5, 10, 1.0),
+                BedGraphEntry::new(8, 12, 1.0),
+            ],
+        );
+        assert!(writer.write(Cursor::new(Vec::new()), &data).is_err());
+    }
+}
@@ -1,20 +1,65 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use clap::Parser;
-use libcawlr::agg_blocks::run;
+use libcawlr::{agg_blocks::AggOptions, progress::ProgressBarSink, region::Region};
 
 #[derive(Parser)]
 struct Args {
-    /// Bed file, usually from cawlr sma
+    /// Bed file from cawlr sma, optionally gzip or bgzip compressed, or an
+    /// Arrow file from cawlr sma --format arrow
     #[clap(short, long)]
     input: PathBuf,
 
     /// Output tsv containing chromosome, position, frac overlapped
     #[clap(short, long)]
     output: Option<PathBuf>,
+
+    /// Aggregate each strand separately, adds a strand column after the
+    /// position
+    #[clap(long)]
+    by_strand: bool,
+
+    /// Aggregate each sample from cawlr merge separately, adds a sample
+    /// column after the strand. Needs Arrow input.
+    #[clap(long)]
+    split_by_sample: bool,
+
+    /// Also write each haplotype to its own output next to --output, ie
+    /// agg.hp1.tsv for agg.tsv. Needs Arrow input from reads collapsed with
+    /// a phased bam file.
+    #[clap(long, requires = "output", conflicts_with = "split_by_sample")]
+    split_haplotype: bool,
+
+    /// Only aggregate positions in these regions, ie chrI:1000-2000
+    #[clap(short, long, num_args = 1..)]
+    region: Vec<Region>,
+
+    /// Input is sorted by chromosome and start, positions are written as
+    /// soon as they are complete to keep memory use low on large inputs
+    #[clap(long)]
+    sorted: bool,
+
+    /// Count every base covered by a nucleosome instead of the first base
+    /// after each block
+    #[clap(long)]
+    whole_nucleosomes: bool,
+
+    /// Hide the progress bar, which is only drawn when stderr is a terminal
+    #[clap(long)]
+    no_progress: bool,
 }
 
 fn main() -> eyre::Result<()> {
     let args = Args::parse();
-    run(&args.input, args.output.as_ref())
+    let mut agg = AggOptions::default();
+    if !args.no_progress {
+        agg.progress_sink(Arc::new(ProgressBarSink::new()?));
+    }
+    agg.by_strand(args.by_strand)
+        .split_by_sample(args.split_by_sample)
+        .split_haplotypes(args.split_haplotype)
+        .regions(args.region)
+        .sorted(args.sorted)
+        .whole_nucleosomes(args.whole_nucleosomes)
+        .run(&args.input, args.output.as_ref())
 }
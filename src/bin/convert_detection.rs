@@ -10,12 +10,14 @@ use indicatif::{ProgressBar, ProgressStyle};
 use libcawlr::{
     arrow::{
         arrow_utils::{save, wrap_writer},
+        kmer::Kmer,
         metadata::{Metadata, MetadataExt, Strand},
         scored_read::{Score, ScoredRead},
     },
     plus_strand_map::PlusStrandMap,
 };
 use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
 
 #[derive(Parser)]
 struct Args {
@@ -32,11 +34,13 @@ struct Args {
     output: PathBuf,
 }
 
+#[serde_as]
 #[derive(Deserialize)]
 struct DetectionLine {
     chrom: String,
     pos: u64,
-    kmer: String,
+    #[serde_as(as = "DisplayFromStr")]
+    kmer: Kmer,
     read_name: String,
     _pos_log_prob: f64,
     _neg_log_prob: f64,
@@ -67,7 +71,7 @@ fn convert_to_read(dlines: &[DetectionLine]) -> ScoredRead {
         .map(|dline| {
             Score::new(
                 dline.pos,
-                dline.kmer.clone(),
+                dline.kmer,
                 false,
                 Some(dline.score),
                 // 0.0,
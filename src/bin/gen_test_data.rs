@@ -5,7 +5,6 @@ use std::{
 };
 
 use eyre::Result;
-use fnv::FnvHashMap;
 use libcawlr::{
     collapse::CollapseOptions,
     motif::Motif,
@@ -50,7 +49,7 @@ fn rank(pos_ctrl: &Model, neg_ctrl: &Model, output: &Path) -> Result<Ranks> {
 fn score(
     pos_model: &Model,
     neg_model: &Model,
-    ranks: &FnvHashMap<String, f64>,
+    ranks: &Ranks,
     reader: &Path,
     writer: &Path,
 ) -> Result<()> {
@@ -48,7 +48,7 @@ impl CawlrIO for BinnedKde {
         P: AsRef<std::path::Path>,
         Self: Sized,
     {
-        let mut file = File::create(filename)?;
+        let mut file = crate::utils::create_output(filename)?;
         serde_pickle::to_writer(&mut file, &self, Default::default())?;
         Ok(())
     }
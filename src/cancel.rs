@@ -0,0 +1,50 @@
+//! Cooperative cancellation on Ctrl-C (SIGINT) or SIGTERM.
+//!
+//! The signal handler only sets a flag. Long running loops poll it between
+//! chunks and stop early, so writers still get finished and partial outputs
+//! stay readable. A second signal exits immediately.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Returned when a command stops early because it was interrupted
+#[derive(thiserror::Error, Debug)]
+#[error("Cancelled by interrupt")]
+pub struct Cancelled;
+
+#[cfg(unix)]
+extern "C" fn handle_signal(_: libc::c_int) {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        // Second interrupt, stop without waiting for outputs to finish
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Install handlers for SIGINT and SIGTERM that request cancellation. Does
+/// nothing on platforms without unix signals.
+pub fn install_handler() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Request cancellation, as if an interrupt was received
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Error with [`Cancelled`] if an interrupt was received
+pub fn check() -> Result<(), Cancelled> {
+    if is_cancelled() {
+        Err(Cancelled)
+    } else {
+        Ok(())
+    }
+}
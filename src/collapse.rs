@@ -1,26 +1,44 @@
 use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fmt,
     fs::File,
-    io::{BufWriter, Read, Write},
-    path::Path,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
-use arrow2::io::ipc::write::FileWriter;
+use arrow2::{
+    datatypes::{Field, Schema},
+    io::ipc::write::FileWriter,
+};
+use arrow2_convert::{field::ArrowField, ArrowDeserialize, ArrowField, ArrowSerialize};
+use bam::BamReader;
 use bio::alphabets::dna::revcomp;
-use eyre::Result;
+use csv::ByteRecord;
+use eyre::{Result, WrapErr};
+use fnv::FnvHashSet;
 use indicatif::{ProgressBar, ProgressBarIter, ProgressFinish, ProgressStyle};
+use itertools::Itertools;
 use serde::Deserialize;
 use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
 use statrs::statistics::Statistics;
+use thiserror::Error;
 
 use crate::{
     arrow::{
-        arrow_utils::{self, save},
+        arrow_utils::{self, save, ArrowCompression, ArrowError, ReadMode},
         eventalign::Eventalign,
+        kmer::rna_to_dna,
         metadata::{Metadata, MetadataExt, Strand},
         signal::Signal,
     },
+    cancel,
     plus_strand_map::PlusStrandMap,
+    progress::{ProgressSink, Reporter, Stage},
+    utils::{self, check_contig_compatibility, create_output, take_error, ChromAlias, ContigMap},
 };
 
 fn empty_from_npr(npr: Npr) -> Eventalign {
@@ -41,11 +59,146 @@ fn empty_from_npr(npr: Npr) -> Eventalign {
     Eventalign::new(metadata, signal_data)
 }
 
-/// Takes a vector of nanpolish records and converts them into a Eventalign.
+/// Failures collapsing nanopolish eventalign output, returned by
+/// [CollapseOptions::run] and the functions that set up collapse
+#[derive(Error, Debug)]
+pub enum CollapseError {
+    #[error("No data, check if eventalign has data; nanopolish eventalign may have failed")]
+    NoData,
+    #[error("Malformed eventalign input")]
+    MalformedEventalign(#[from] csv::Error),
+    #[error("Read {read} has an event with no signal samples at {position}, malformed input")]
+    MissingSamples { read: String, position: u64 },
+    #[error("Collapse has already been run")]
+    AlreadyRun,
+    #[error(transparent)]
+    Arrow(ArrowError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(eyre::Report),
+}
+
+impl From<ArrowError> for CollapseError {
+    fn from(e: ArrowError) -> Self {
+        match e {
+            ArrowError::Io(e) => CollapseError::Io(e),
+            ArrowError::Other(e) => e.into(),
+            e => CollapseError::Arrow(e),
+        }
+    }
+}
+
+impl From<eyre::Report> for CollapseError {
+    fn from(e: eyre::Report) -> Self {
+        take_error::<CollapseError>(e)
+            .or_else(|e| take_error::<ArrowError>(e).map(CollapseError::from))
+            .or_else(|e| take_error::<std::io::Error>(e).map(CollapseError::Io))
+            .unwrap_or_else(CollapseError::Other)
+    }
+}
+
+/// Why lines of eventalign were left out of the output, see
+/// [CollapseOptions::errors]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    /// Line has a different number of columns than the header
+    FieldCount,
+    /// Column couldn't be parsed, ie a missing event_index or a non-numeric
+    /// sample
+    InvalidValue,
+    /// Read isn't in the BAM file, so its strand is unknown
+    UnknownStrand,
+    /// Events of the read go backwards past its start, usually from a
+    /// multi-mapped read
+    MultiMapped,
+}
+
+impl SkipReason {
+    /// Code written in the reason column of [CollapseOptions::errors]
+    pub fn code(&self) -> &'static str {
+        match self {
+            SkipReason::FieldCount => "field_count",
+            SkipReason::InvalidValue => "invalid_value",
+            SkipReason::UnknownStrand => "unknown_strand",
+            SkipReason::MultiMapped => "multi_mapped",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Columns of the file written by [CollapseOptions::errors]
+const ERRORS_HEADER: &str = "line\treason\tread_name\tdetail\tcontent";
+
+/// Lines left out of the output, counted by reason and written to the errors
+/// file if there is one
+#[derive(Default)]
+struct Skipped {
+    writer: Option<BufWriter<File>>,
+    counts: BTreeMap<SkipReason, usize>,
+}
+
+impl Skipped {
+    /// Record n_lines skipped lines, starting at line. Lines of a read are
+    /// written as a single row.
+    fn record(
+        &mut self,
+        line: u64,
+        n_lines: usize,
+        reason: SkipReason,
+        read_name: &str,
+        detail: &str,
+        content: &str,
+    ) -> Result<()> {
+        *self.counts.entry(reason).or_default() += n_lines;
+        if let Some(writer) = self.writer.as_mut() {
+            writeln!(writer, "{line}\t{reason}\t{read_name}\t{detail}\t{content}")?;
+        }
+        Ok(())
+    }
+
+    /// Log the number of lines skipped for each reason, also written at the
+    /// end of the errors file as comments
+    fn finish(&mut self) -> Result<()> {
+        let total: usize = self.counts.values().sum();
+        if total > 0 {
+            let counts = self
+                .counts
+                .iter()
+                .map(|(reason, n)| format!("{n} {reason}"))
+                .join(", ");
+            log::warn!("Skipped {total} lines of eventalign: {counts}");
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            for (reason, n) in self.counts.iter() {
+                writeln!(writer, "# {reason}\t{n}")?;
+            }
+            writeln!(writer, "# total\t{total}")?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a line of eventalign, keeping its line number for reporting
+fn parse_npr(record: &ByteRecord, headers: &ByteRecord) -> Result<Npr, csv::Error> {
+    let mut npr: Npr = record.deserialize(Some(headers))?;
+    npr.line = record.position().map_or(0, |p| p.line());
+    Ok(npr)
+}
+
+/// Takes a vector of nanpolish records and converts them into a Eventalign, or
+/// the read name and why the read was left out.
 fn nprs_to_eventalign(
     mut nprs: impl Iterator<Item = Npr>,
     strand_map: &PlusStrandMap,
-) -> Result<Option<Eventalign>> {
+    mode: ReadMode,
+) -> Result<Result<Eventalign, (String, SkipReason)>> {
     let mut eventalign = nprs
         .next()
         .ok_or_else(|| eyre::eyre!("Empty nprs"))
@@ -58,7 +211,11 @@ fn nprs_to_eventalign(
         let mean = npr.samples().mean();
 
         if mean.is_nan() {
-            eyre::bail!("No signal samples values, malformed input");
+            return Err(CollapseError::MissingSamples {
+                read: eventalign.name().to_string(),
+                position,
+            }
+            .into());
         }
 
         let time = npr.event_length;
@@ -66,12 +223,34 @@ fn nprs_to_eventalign(
         eventalign.signal_data_mut().push(signal);
     }
 
-    // Update strand from bam file results
-    let strand = strand_map.get(eventalign.name());
-    if let Some(b) = strand {
+    // Events of RNA reads go from 3' to 5', so positions can be descending and
+    // the read spans from the lowest to the highest position
+    if mode != ReadMode::DnaGenome {
+        let (first, last) = (eventalign.start_0b(), stop);
+        eventalign.metadata.start = first.min(last);
+        stop = first.max(last);
+    }
+
+    // Update strand from the alignment in the bam file at the read's position,
+    // reads with supplementary alignments can be on both strands
+    let (name, chrom, start) = (eventalign.name(), eventalign.chrom(), eventalign.start_0b());
+    let strand = strand_map.strand_at(name, chrom, start);
+    let mapping = strand_map.mapping_at(name, chrom, start);
+    match strand {
+        Some(_) if strand_map.is_ambiguous(name) => {
+            log::debug!("Read {name} has alignments on both strands, using {chrom}:{start}")
+        }
+        None => log::debug!("Read {name} could not find strand at {chrom}:{start}"),
+        _ => (),
+    }
+    if mode == ReadMode::Transcriptome {
+        // Transcripts are already in the orientation of the RNA
+        eventalign.metadata.strand = Strand::plus();
+    } else if let Some(b) = strand {
         eventalign.metadata.strand = if b { Strand::plus() } else { Strand::minus() };
-    } else {
-        log::warn!("Read {} could not find strand", eventalign.name())
+    }
+    if let Some(mapping) = mapping {
+        eventalign.metadata.set_mapping(mapping);
     }
 
     // Handle last edge case with multi-mapped reads, throwing away the read if
@@ -79,24 +258,301 @@ fn nprs_to_eventalign(
     if let Some(len) = stop.checked_sub(eventalign.start_0b()) {
         eventalign.metadata.length = len + 1;
     } else {
-        return Ok(None);
+        return Ok(Err((
+            eventalign.name().to_string(),
+            SkipReason::MultiMapped,
+        )));
     }
 
     // Unable to infer read strand so we remove the read
     if eventalign.strand().is_unknown_strand() {
-        return Ok(None);
+        return Ok(Err((
+            eventalign.name().to_string(),
+            SkipReason::UnknownStrand,
+        )));
     }
 
-    // Reverse kmer
-    if eventalign.strand().is_minus_strand() {
+    // Reverse kmer, eventalign of RNA already gives kmers in the orientation
+    // of the RNA but they can have U from RNA pore models
+    if mode == ReadMode::DnaGenome && eventalign.strand().is_minus_strand() {
         for signal in eventalign.signal_data_mut().iter_mut() {
             let rev_kmer = revcomp(signal.kmer.as_bytes());
             let rev_kmer = String::from_utf8(rev_kmer)?;
             signal.kmer = rev_kmer;
         }
+    } else if mode != ReadMode::DnaGenome {
+        for signal in eventalign.signal_data_mut().iter_mut() {
+            signal.kmer = rna_to_dna(&signal.kmer);
+        }
     }
     log::debug!("Parsed Eventalign: {eventalign:.2?} ");
-    Ok(Some(eventalign))
+    Ok(Ok(eventalign))
+}
+
+/// Columns of the per-read summary written by [CollapseOptions::summary]
+const SUMMARY_HEADER: &str =
+    "read_name\tchrom\tstart\tlength\tn_events\tn_skipped\tmean_dwell_time\tmean_current";
+
+/// Write a row of quality statistics for a collapsed read. Skipped positions
+/// are positions within the read without any events aligned to them, dwell
+/// time is averaged over positions, and current is averaged over every
+/// sample.
+fn write_summary<S: Write>(writer: &mut S, eventalign: &Eventalign, n_events: usize) -> Result<()> {
+    let n_positions = eventalign.signal_iter().count() as u64;
+    let n_skipped = eventalign.np_length().saturating_sub(n_positions);
+    let mean_dwell_time = eventalign.signal_iter().map(|s| s.signal_time).mean();
+    let mean_current = eventalign
+        .signal_iter()
+        .flat_map(|s| s.samples.iter())
+        .mean();
+    writeln!(
+        writer,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{:.5}\t{:.3}",
+        eventalign.name(),
+        eventalign.chrom(),
+        eventalign.start_0b(),
+        eventalign.np_length(),
+        n_events,
+        n_skipped,
+        mean_dwell_time,
+        mean_current,
+    )?;
+    Ok(())
+}
+
+/// Rough number of bytes used by a line of eventalign held in memory
+fn npr_bytes(npr: &Npr) -> usize {
+    size_of::<Npr>() + npr.samples.len() * size_of::<f64>()
+}
+
+/// Rough number of bytes used by a collapsed read held in memory
+fn eventalign_bytes(eventalign: &Eventalign) -> usize {
+    let signal_bytes: usize = eventalign
+        .signal_iter()
+        .map(|s| size_of::<Signal>() + s.kmer.len() + s.samples.len() * size_of::<f64>())
+        .sum();
+    size_of::<Eventalign>()
+        + eventalign.name().len()
+        + eventalign.chrom().len()
+        + eventalign.metadata.seq.len()
+        + signal_bytes
+}
+
+/// Furthest apart two pieces of a read on the same strand can be to be merged,
+/// so positions without events in an alignment, ie from short deletions, don't
+/// split it while separate alignments of the read stay apart
+const MAX_FRAGMENT_GAP: u64 = 20;
+
+/// Read, or piece of a read, collapsed from consecutive lines of eventalign
+/// and held until pieces of the same read can be merged, see
+/// [CollapseOptions::max_memory]
+#[derive(Debug, Clone, ArrowField, ArrowDeserialize, ArrowSerialize, Default, PartialEq)]
+struct Fragment {
+    eventalign: Eventalign,
+    n_events: u64,
+}
+
+impl Fragment {
+    fn schema() -> Schema {
+        Schema::from(vec![Field::new("fragment", Self::data_type(), false)])
+    }
+
+    fn key(&self) -> (&str, &str, &str, u64) {
+        (
+            self.eventalign.name(),
+            self.eventalign.chrom(),
+            self.eventalign.strand().as_str(),
+            self.eventalign.start_0b(),
+        )
+    }
+
+    /// Whether other is a piece of the same alignment, other must not start
+    /// before this piece
+    fn same_read(&self, other: &Fragment) -> bool {
+        self.eventalign.name() == other.eventalign.name()
+            && self.eventalign.chrom() == other.eventalign.chrom()
+            && self.eventalign.strand() == other.eventalign.strand()
+            && other.eventalign.start_0b() <= self.eventalign.end_1b_excl() + MAX_FRAGMENT_GAP
+    }
+
+    /// Add the events of another piece of the same read, events at the same
+    /// position are combined like consecutive events in eventalign.
+    fn merge(&mut self, mut other: Fragment) {
+        let start = self.eventalign.start_0b().min(other.eventalign.start_0b());
+        let end = self
+            .eventalign
+            .end_1b_excl()
+            .max(other.eventalign.end_1b_excl());
+        self.eventalign.metadata.start = start;
+        self.eventalign.metadata.length = end - start;
+        self.n_events += other.n_events;
+
+        let signals = self.eventalign.signal_data_mut();
+        // Keep the order positions were given in, ie descending for some reads
+        let descending = match (signals.first(), signals.last()) {
+            (Some(first), Some(last)) => first.pos > last.pos,
+            _ => false,
+        };
+        signals.append(other.eventalign.signal_data_mut());
+        signals.sort_by_key(|s| s.pos);
+        let mut merged: Vec<Signal> = Vec::with_capacity(signals.len());
+        for mut signal in signals.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.pos == signal.pos => {
+                    last.samples.append(&mut signal.samples);
+                    let time = last.signal_time + signal.signal_time;
+                    last.signal_mean = if !last.samples.is_empty() {
+                        last.samples.as_slice().mean()
+                    } else if time > 0.0 {
+                        // Without samples, weight each mean by its dwell time,
+                        // which is proportional to its number of samples
+                        (last.signal_mean * last.signal_time
+                            + signal.signal_mean * signal.signal_time)
+                            / time
+                    } else {
+                        (last.signal_mean + signal.signal_mean) / 2.0
+                    };
+                    last.signal_time = time;
+                }
+                _ => merged.push(signal),
+            }
+        }
+        if descending {
+            merged.reverse();
+        }
+        *signals = merged;
+    }
+}
+
+/// Fragments held until all of the input is read. Once they use more than
+/// the limit they are sorted and spilled to an Arrow file in a temporary
+/// directory, and those files are merged back together by read at the end.
+struct Spill {
+    limit: usize,
+    bytes: usize,
+    fragments: Vec<Fragment>,
+    dir: Option<tempfile::TempDir>,
+    shards: Vec<PathBuf>,
+}
+
+impl Spill {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            bytes: 0,
+            fragments: Vec::new(),
+            dir: None,
+            shards: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, fragment: Fragment) -> Result<()> {
+        self.bytes += eventalign_bytes(&fragment.eventalign);
+        self.fragments.push(fragment);
+        if self.bytes >= self.limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        self.fragments.sort_by(|a, b| a.key().cmp(&b.key()));
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.sort();
+        let dir = match self.dir.as_ref() {
+            Some(dir) => dir,
+            None => {
+                let dir = utils::temp_dir()?;
+                log::warn!(
+                    "Collapse memory limit of {} MiB reached, spilling reads to {}. The \
+                     eventalign input is likely not grouped by read, ie it was sorted by \
+                     position or written by interleaved processes.",
+                    (2 * self.limit) >> 20,
+                    dir.path().display()
+                );
+                self.dir.insert(dir)
+            }
+        };
+        let path = dir
+            .path()
+            .join(format!("shard_{:05}.arrow", self.shards.len()));
+        let file = BufWriter::new(File::create(&path)?);
+        let mut writer =
+            arrow_utils::wrap_writer_with(file, &Fragment::schema(), ArrowCompression::Lz4)?;
+        for chunk in self.fragments.chunks(2048) {
+            save(&mut writer, chunk)?;
+        }
+        writer.finish()?;
+        log::debug!(
+            "Spilled {} fragments to {}",
+            self.fragments.len(),
+            path.display()
+        );
+
+        self.shards.push(path);
+        self.fragments.clear();
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Merge the pieces of each read and pass them on in order of read name,
+    /// returns the number of reads made of more than one piece.
+    fn merge<F>(mut self, mut emit: F) -> Result<usize>
+    where
+        F: FnMut(Fragment) -> Result<()>,
+    {
+        self.sort();
+        let mut runs: Vec<Box<dyn Iterator<Item = Result<Fragment>>>> = Vec::new();
+        for path in self.shards.iter() {
+            let chunks = arrow_utils::load_chunks(BufReader::new(File::open(path)?))?;
+            runs.push(Box::new(chunks.flat_map(|chunk| match chunk {
+                Ok(fragments) => fragments.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(eyre::Report::from(e))],
+            })));
+        }
+        runs.push(Box::new(
+            std::mem::take(&mut self.fragments).into_iter().map(Ok),
+        ));
+
+        let fragments = runs.into_iter().kmerge_by(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) => a.key() < b.key(),
+            (Err(_), _) => true,
+            (Ok(_), Err(_)) => false,
+        });
+        let mut n_split = 0;
+        let mut read: Option<(Fragment, bool)> = None;
+        for fragment in fragments {
+            let fragment = fragment?;
+            match read.as_mut() {
+                Some((read, split)) if read.same_read(&fragment) => {
+                    read.merge(fragment);
+                    *split = true;
+                }
+                _ => {
+                    if let Some((read, split)) = read.replace((fragment, false)) {
+                        n_split += usize::from(split);
+                        emit(read)?;
+                    }
+                }
+            }
+        }
+        if let Some((read, split)) = read {
+            n_split += usize::from(split);
+            emit(read)?;
+        }
+        Ok(n_split)
+    }
+}
+
+/// Collapsed reads waiting to be written
+struct Pending {
+    flats: Vec<Eventalign>,
+    spill: Option<Spill>,
+    seen: FnvHashSet<u64>,
+    n_split: usize,
+    reporter: Reporter,
 }
 
 /// Create spinner that wraps an IO read iterator
@@ -117,31 +573,53 @@ fn spin_iter<I: Read>(iter: I, show_progress: bool) -> ProgressBarIter<I> {
 }
 
 pub struct CollapseOptions<W: Write> {
-    writer: FileWriter<W>,
+    output: Option<W>,
+    writer: Option<FileWriter<W>>,
+    samples: bool,
+    mode: ReadMode,
+    compression: ArrowCompression,
     strand_db: PlusStrandMap,
+    bam_contigs: Vec<String>,
+    chrom_alias: Option<ChromAlias>,
+    contigs: Option<ContigMap>,
     capacity: usize,
+    max_memory: Option<usize>,
     progress: bool,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
+    summary: Option<BufWriter<File>>,
+    skipped: Skipped,
 }
 
 impl CollapseOptions<BufWriter<File>> {
-    pub fn try_new<Q, R>(bam_file: Q, output: R) -> Result<Self>
+    pub fn try_new<Q, R>(bam_file: Q, output: R) -> Result<Self, CollapseError>
     where
         Q: AsRef<Path>,
         R: AsRef<Path>,
     {
-        let writer = File::create(output)?;
+        let writer = create_output(output)?;
         let writer = BufWriter::new(writer);
         CollapseOptions::from_writer(writer, bam_file)
     }
 }
 
 impl<W: Write> CollapseOptions<W> {
-    fn new(writer: FileWriter<W>, strand_db: PlusStrandMap) -> Self {
+    fn new(output: W, strand_db: PlusStrandMap, bam_contigs: Vec<String>) -> Self {
         Self {
-            writer,
+            output: Some(output),
+            writer: None,
+            samples: true,
+            mode: ReadMode::default(),
+            compression: ArrowCompression::default(),
             strand_db,
+            bam_contigs,
+            chrom_alias: None,
+            contigs: None,
             capacity: 2048,
+            max_memory: None,
             progress: false,
+            progress_sink: None,
+            summary: None,
+            skipped: Skipped::default(),
         }
     }
 
@@ -150,104 +628,372 @@ impl<W: Write> CollapseOptions<W> {
         self
     }
 
+    /// Approximate number of bytes collapse holds in memory. Set this for
+    /// eventalign input that isn't grouped by read, ie sorted by position or
+    /// written by interleaved processes. Pieces of each read are held until
+    /// all of the input is read, spilling to temporary files in
+    /// [utils::tmp_dir] once over the limit, then merged into whole reads.
+    /// Separate alignments of a read, ie supplementary ones, stay separate.
+    /// Without a limit reads are written as they are collapsed and split reads
+    /// are written as separate pieces, with a warning.
+    pub fn max_memory(&mut self, max_memory: usize) -> &mut Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
     pub fn progress(&mut self, progress: bool) -> &mut Self {
         self.progress = progress;
         self
     }
 
-    pub fn from_writer<R>(writer: W, bam_file: R) -> Result<Self>
+    /// Keep the raw samples of each event, on by default. Without them only
+    /// the mean current and dwell time are stored, which is all training with
+    /// the avg strategy and scoring need, and outputs are much smaller. The
+    /// choice is recorded in the output's schema metadata.
+    pub fn samples(&mut self, samples: bool) -> &mut Self {
+        self.samples = samples;
+        self
+    }
+
+    /// What the reads were sequenced from and aligned to, DNA aligned to a
+    /// genome by default. Kmers of minus strand reads are only reverse
+    /// complemented for DNA, and reads aligned to a transcriptome are all on
+    /// the plus strand. The mode is recorded in the output's schema metadata.
+    pub fn mode(&mut self, mode: ReadMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Codec for the output, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is written
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    /// Only take strands from primary alignments, leaving out eventalign of
+    /// supplementary or secondary alignments. Otherwise the strand of each
+    /// piece of a read is taken from the alignment at its position.
+    pub fn primary_only(&mut self, primary_only: bool) -> &mut Self {
+        self.strand_db.primary_only(primary_only);
+        self
+    }
+
+    /// Match eventalign contig names to the BAM header through an alias file,
+    /// collapsed reads are renamed to the names used by the BAM
+    pub fn chrom_alias(&mut self, alias: ChromAlias) -> &mut Self {
+        self.chrom_alias = Some(alias);
+        self
+    }
+
+    /// Also write a tab-separated file with quality statistics for each read as
+    /// it is collapsed.
+    pub fn summary<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, CollapseError> {
+        let mut writer = BufWriter::new(create_output(path)?);
+        writeln!(writer, "{SUMMARY_HEADER}")?;
+        self.summary = Some(writer);
+        Ok(self)
+    }
+
+    /// Also write every line of eventalign left out of the output to a
+    /// tab-separated file, with a reason code and the line number, ie lines
+    /// that failed to parse or reads missing from the BAM file. Lines from the
+    /// same read are written as one row. Counts for each reason are written at
+    /// the end as comments.
+    pub fn errors<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, CollapseError> {
+        let mut writer = BufWriter::new(create_output(path)?);
+        writeln!(writer, "{ERRORS_HEADER}")?;
+        self.skipped.writer = Some(writer);
+        Ok(self)
+    }
+
+    pub fn from_writer<R>(writer: W, bam_file: R) -> Result<Self, CollapseError>
     where
         R: AsRef<Path>,
     {
-        let strand_db = PlusStrandMap::from_bam_file(bam_file)?;
-        let schema = Eventalign::schema();
-        let writer = arrow_utils::wrap_writer(writer, &schema)?;
-        Ok(CollapseOptions::new(writer, strand_db))
+        let strand_db = PlusStrandMap::from_bam_file(&bam_file)?;
+        let bam_contigs = BamReader::from_path(&bam_file, 0)?
+            .header()
+            .reference_names()
+            .to_vec();
+        Ok(CollapseOptions::new(writer, strand_db, bam_contigs))
+    }
+
+    /// Write the Arrow header, which records whether samples are kept and the
+    /// mode
+    fn start(&mut self) -> Result<(), CollapseError> {
+        let output = self.output.take().ok_or(CollapseError::AlreadyRun)?;
+        let schema = arrow_utils::eventalign_schema(self.samples, self.mode);
+        self.writer = Some(arrow_utils::wrap_writer_with(
+            output,
+            &schema,
+            self.compression,
+        )?);
+        Ok(())
     }
 
     fn save_eventalign(&mut self, eventaligns: &[Eventalign]) -> Result<()> {
-        save(&mut self.writer, eventaligns)
+        let writer = self.writer.as_mut().expect("Writer is started in run");
+        save(writer, eventaligns)
     }
 
-    fn close(&mut self) -> Result<()> {
-        self.writer.finish()?;
+    /// Keep a collapsed read, writing its summary if requested
+    fn push_eventalign(
+        &mut self,
+        flats: &mut Vec<Eventalign>,
+        mut eventalign: Eventalign,
+        n_events: usize,
+    ) -> Result<()> {
+        if let Some(contigs) = self.contigs.as_mut() {
+            if let Some(chrom) = contigs.resolve(eventalign.chrom()) {
+                if chrom != eventalign.chrom() {
+                    eventalign.metadata.chrom = chrom.to_string();
+                }
+            }
+        }
+        if let Some(summary) = self.summary.as_mut() {
+            write_summary(summary, &eventalign, n_events)?;
+        }
+        if !self.samples {
+            for signal in eventalign.signal_data_mut() {
+                signal.samples = Vec::new();
+            }
+        }
+        flats.push(eventalign);
         Ok(())
     }
 
-    pub fn run<R>(&mut self, input: R) -> Result<()>
+    /// Keep a collapsed read as a piece for merging when there is a memory
+    /// limit, otherwise write it
+    fn keep(&mut self, pending: &mut Pending, acc: &mut Vec<Npr>, n_events: usize) -> Result<()> {
+        let line = acc.first().map_or(0, |npr| npr.line);
+        let eventalign = match nprs_to_eventalign(acc.drain(..), &self.strand_db, self.mode)? {
+            Ok(eventalign) => eventalign,
+            Err((read_name, reason)) => {
+                let detail = format!("{n_events} events");
+                return self
+                    .skipped
+                    .record(line, n_events, reason, &read_name, &detail, "");
+            }
+        };
+        if let Some(spill) = pending.spill.as_mut() {
+            let n_events = n_events as u64;
+            return spill.push(Fragment {
+                eventalign,
+                n_events,
+            });
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (eventalign.name(), eventalign.chrom()).hash(&mut hasher);
+        if !pending.seen.insert(hasher.finish()) {
+            pending.n_split += 1;
+        }
+        self.push_eventalign(&mut pending.flats, eventalign, n_events)?;
+        if pending.flats.len() >= self.capacity {
+            self.save_eventalign(&pending.flats)?;
+            pending.reporter.chunk(pending.flats.len());
+            pending.flats.clear();
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.finish()?;
+        }
+        if let Some(summary) = self.summary.as_mut() {
+            summary.flush()?;
+        }
+        self.skipped.finish()
+    }
+
+    /// Record a line that failed to parse. Its read name is kept if that
+    /// column could be read.
+    fn skip_line(
+        &mut self,
+        record: &ByteRecord,
+        headers: &ByteRecord,
+        err: csv::Error,
+    ) -> Result<()> {
+        let reason = if record.len() == headers.len() {
+            SkipReason::InvalidValue
+        } else {
+            SkipReason::FieldCount
+        };
+        let read_name = headers
+            .iter()
+            .position(|h| h == b"read_name")
+            .and_then(|idx| record.get(idx))
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+        let content = record.iter().map(String::from_utf8_lossy).join("\t");
+        let line = record.position().map_or(0, |p| p.line());
+        log::debug!("Parsing failed on line {line}: {err}");
+        let detail = err.to_string().replace(['\t', '\n'], " ");
+        self.skipped
+            .record(line, 1, reason, &read_name, &detail, &content)
+    }
+
+    pub fn run<R>(&mut self, input: R) -> Result<(), CollapseError>
     where
         R: Read,
     {
+        self.start()?;
         let file = spin_iter(input, self.progress);
-        let mut builder = csv::ReaderBuilder::new().delimiter(b'\t').from_reader(file);
-        let mut npr_iter = builder.deserialize();
-
-        let mut idx_diff = 1;
-        let npr: Npr = npr_iter.next().ok_or_else(|| {
-            eyre::eyre!(
-                "No data, check if eventalign has data; nanopolish eventalign may have failed"
+        // Flexible so lines with missing columns are reported instead of
+        // failing the reader
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .from_reader(file);
+        let headers = reader
+            .byte_headers()
+            .map_err(CollapseError::MalformedEventalign)?
+            .clone();
+        let mut records = reader.into_byte_records();
+
+        let record = records
+            .next()
+            .ok_or(CollapseError::NoData)?
+            .map_err(CollapseError::MalformedEventalign)?;
+        let npr = parse_npr(&record, &headers).map_err(CollapseError::MalformedEventalign)?;
+        // Unmapped BAM files have no contigs to check against
+        if !self.bam_contigs.is_empty() {
+            let contigs = check_contig_compatibility(
+                self.bam_contigs.iter().map(String::as_str),
+                [npr.contig()],
+                self.chrom_alias.as_ref(),
             )
-        })??;
+            .wrap_err("Eventalign contigs are not in the BAM header, was a different BAM used?")?;
+            self.contigs = Some(contigs);
+        }
         let mut position = npr.position;
 
+        // Reads and spilled pieces each get half of the memory limit
+        let split_bytes = self.max_memory.map_or(usize::MAX, |limit| limit / 2);
+        let mut acc_bytes = npr_bytes(&npr);
         let mut acc = vec![npr];
-        let mut flats = Vec::with_capacity(self.capacity);
-
-        for line in npr_iter {
-            if let Ok(mut next_npr) = line {
-                let last = acc.last().unwrap();
-                let read_name = last.read_name();
-                let event_idx = last.event_index();
-                if (next_npr.read_name() == read_name)
-                    && (next_npr.event_index().abs_diff(event_idx) == idx_diff)
-                {
-                    // Same read, possibly new kmer or same
-                    if next_npr.position == position {
-                        // Same read, same kmer
-                        let npr_mut = acc.last_mut().unwrap();
-                        npr_mut.samples.append(&mut next_npr.samples);
-                        npr_mut.event_length += next_npr.event_length;
-                        npr_mut.event_index = next_npr.event_index;
-                    } else {
-                        // Same read, different kmer
-                        position = next_npr.position;
-                        acc.push(next_npr);
-                    }
-                } else {
-                    // New read, write data and move forward
-                    if let Some(eventalign) = nprs_to_eventalign(acc.drain(..), &self.strand_db)? {
-                        flats.push(eventalign);
-                    }
+        let mut n_events = 1;
+        let mut pending = Pending {
+            flats: Vec::with_capacity(self.capacity),
+            spill: self.max_memory.map(|limit| Spill::new(limit / 2)),
+            seen: FnvHashSet::default(),
+            n_split: 0,
+            reporter: Reporter::new(Stage::Collapse, self.progress_sink.clone()),
+        };
 
-                    if flats.len() >= self.capacity {
-                        self.save_eventalign(&flats)?;
-                        flats.clear();
+        // Lines that failed to parse since the last line that parsed, so events
+        // lost with them don't split the read
+        let mut n_unparsed: u64 = 0;
+        let mut cancelled = false;
+        for record in records {
+            if cancel::is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            let record = record.map_err(CollapseError::MalformedEventalign)?;
+            let mut next_npr = match parse_npr(&record, &headers) {
+                Ok(npr) => npr,
+                Err(e) => {
+                    self.skip_line(&record, &headers, e)?;
+                    n_unparsed += 1;
+                    continue;
+                }
+            };
+            let last = acc.last().unwrap();
+            let step = next_npr.event_index().abs_diff(last.event_index());
+            if (next_npr.read_name() == last.read_name()) && (1..=1 + n_unparsed).contains(&step) {
+                // Same read, possibly new kmer or same
+                n_events += 1;
+                if next_npr.position == position {
+                    // Same read, same kmer
+                    acc_bytes += next_npr.samples.len() * size_of::<f64>();
+                    let npr_mut = acc.last_mut().unwrap();
+                    npr_mut.samples.append(&mut next_npr.samples);
+                    npr_mut.event_length += next_npr.event_length;
+                    npr_mut.event_index = next_npr.event_index;
+                } else {
+                    // Same read, different kmer
+                    position = next_npr.position;
+                    if acc_bytes >= split_bytes {
+                        // Very long read, the pieces are merged at the end
+                        self.keep(&mut pending, &mut acc, n_events - 1)?;
+                        n_events = 1;
+                        acc_bytes = 0;
                     }
+                    acc_bytes += npr_bytes(&next_npr);
                     acc.push(next_npr);
                 }
-                idx_diff = 1;
             } else {
-                log::warn!("Parsing failed: {line:?}");
-                idx_diff += 1;
+                // New read, write data and move forward
+                self.keep(&mut pending, &mut acc, n_events)?;
+                acc_bytes = npr_bytes(&next_npr);
+                position = next_npr.position;
+                acc.push(next_npr);
+                n_events = 1;
             }
+            n_unparsed = 0;
         }
 
-        if !acc.is_empty() {
-            if let Some(eventalign) = nprs_to_eventalign(acc.drain(..), &self.strand_db)? {
-                flats.push(eventalign);
+        // The last read may be incomplete if cancelled, so it is left out
+        if !acc.is_empty() && !cancelled {
+            self.keep(&mut pending, &mut acc, n_events)?;
+        }
+        let Pending {
+            mut flats,
+            spill,
+            n_split,
+            mut reporter,
+            ..
+        } = pending;
+        if let (Some(spill), false) = (spill, cancelled) {
+            let capacity = self.capacity;
+            let n_split = spill.merge(|read| {
+                self.push_eventalign(&mut flats, read.eventalign, read.n_events as usize)?;
+                if flats.len() >= capacity {
+                    self.save_eventalign(&flats)?;
+                    reporter.chunk(flats.len());
+                    flats.clear();
+                }
+                Ok(())
+            })?;
+            if n_split > 0 {
+                log::warn!("Merged {n_split} reads that were split across the eventalign input");
             }
+        } else if n_split > 0 {
+            log::warn!(
+                "{n_split} reads were split across the eventalign input and written as separate \
+                 pieces, the input is likely not grouped by read. Set a memory limit to merge \
+                 them, ie --max-memory with cawlr collapse."
+            );
         }
         // If reads are left in the buffer, save those
         if !flats.is_empty() {
             self.save_eventalign(&flats)?;
+            reporter.chunk(flats.len());
+        }
+        reporter.finish();
+        self.close()?;
+        if cancelled {
+            return Err(CollapseError::Other(cancel::Cancelled.into()));
         }
-        self.close()
+        Ok(())
     }
 }
 
 #[serde_as]
 #[derive(Default, Clone, Debug, Deserialize, PartialEq)]
 struct Npr {
+    /// Line in the eventalign input, for reporting
+    #[serde(skip)]
+    line: u64,
+
     contig: String,
 
     position: u64,
@@ -315,34 +1061,303 @@ mod test {
     use assert_fs::TempDir;
 
     use super::*;
-    use crate::arrow::arrow_utils::{load_apply, load_iter, wrap_writer};
+    use crate::{
+        arrow::arrow_utils::{has_samples, load_apply, load_iter, read_mode},
+        test_data::{MiniGenome, MINUS_READ, PLUS_READ},
+    };
 
     #[test]
     fn test_collapse() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let filepath = "extra/single_read.eventalign.txt";
-        let input = File::open(filepath)?;
-        let bam_file = "extra/single_read.bam";
-        let output = temp_dir.path().join("test");
-        let mut collapse = CollapseOptions::try_new(bam_file, &output)?;
+        let mini = MiniGenome::new()?;
+        let input = File::open(mini.eventalign())?;
+        let output = mini.dir().join("test");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
         collapse.run(input)?;
 
         let output = File::open(output)?;
         let x = load_iter(output).next().unwrap().unwrap();
-        assert_eq!(x.len(), 1);
+        assert_eq!(x.len(), 2);
         let read = &x[0];
+        assert_eq!(read.name(), PLUS_READ.name);
         assert_eq!(read.strand(), Strand::plus());
-        assert_eq!(read.chrom(), "chrXIII");
-        assert_eq!(read.start_0b(), 182504);
-        assert_eq!(read.end_1b_excl(), 182682);
+        assert_eq!(read.chrom(), "chrI");
+        assert_eq!(read.start_0b(), 100);
+        assert_eq!(read.end_1b_excl(), 151);
+
+        assert_eq!(read.seq_stop_1b_excl(), 156);
+        assert_eq!(read.seq_length(), 56);
+        assert!(read.mapq().is_some());
+        assert_eq!(read.haplotype(), Some(PLUS_READ.haplotype));
+        assert_eq!(read.phase_set(), None);
+
+        let read = &x[1];
+        assert_eq!(read.name(), MINUS_READ.name);
+        assert_eq!(read.strand(), Strand::minus());
+        assert_eq!(read.haplotype(), Some(MINUS_READ.haplotype));
+        assert_eq!(read.chrom(), "chrII");
+        // Kmers of minus strand reads are reverse complemented
+        let kmer = &read.signal_iter().next().unwrap().kmer;
+        assert_eq!(kmer, "GGGAGA");
+        Ok(())
+    }
 
-        assert_eq!(read.seq_stop_1b_excl(), 182687);
-        assert_eq!(read.seq_length(), 183);
+    #[test]
+    fn test_collapse_mode() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output = mini.dir().join("rna");
+        CollapseOptions::try_new(mini.bam(), &output)?
+            .mode(ReadMode::Rna)
+            .run(File::open(mini.eventalign())?)?;
+        assert_eq!(read_mode(&mut File::open(&output)?)?, ReadMode::Rna);
+        let x = load_iter(File::open(&output)?).next().unwrap().unwrap();
+        let read = &x[1];
+        assert_eq!(read.strand(), Strand::minus());
+        // Kmers are kept as they are in eventalign
+        let kmer = &read.signal_iter().next().unwrap().kmer;
+        assert_eq!(kmer, "TCTCCC");
+
+        let output = mini.dir().join("transcriptome");
+        CollapseOptions::try_new(mini.bam(), &output)?
+            .mode(ReadMode::Transcriptome)
+            .run(File::open(mini.eventalign())?)?;
+        let x = load_iter(File::open(&output)?).next().unwrap().unwrap();
+        assert!(x.iter().all(|read| read.strand() == Strand::plus()));
+
+        let output = mini.dir().join("dna");
+        CollapseOptions::try_new(mini.bam(), &output)?.run(File::open(mini.eventalign())?)?;
+        assert_eq!(read_mode(&mut File::open(&output)?)?, ReadMode::DnaGenome);
+        Ok(())
+    }
 
+    #[test]
+    fn test_collapse_chrom_alias() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let eventalign = std::fs::read_to_string(mini.eventalign())?
+            .replace("chrII\t", "II\t")
+            .replace("chrI\t", "I\t");
+        let output = mini.dir().join("renamed");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        assert!(collapse.run(eventalign.as_bytes()).is_err());
+
+        let alias = ChromAlias::from_reader("chrI\tI\nchrII\tII\n".as_bytes())?;
+        let mut collapse = CollapseOptions::from_writer(Vec::new(), mini.bam())?;
+        collapse.chrom_alias(alias);
+        collapse.run(eventalign.as_bytes())?;
+        let output = collapse.writer.unwrap().into_inner();
+        let reads = load_iter(std::io::Cursor::new(output)).next().unwrap()?;
+        let chroms = reads.iter().map(|r| r.chrom()).collect::<Vec<_>>();
+        assert_eq!(chroms, ["chrI", "chrII"]);
         Ok(())
     }
 
     #[test]
+    fn test_collapse_no_samples() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output = mini.dir().join("test");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+        assert!(has_samples(&mut File::open(&output)?)?);
+
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        collapse.samples(false);
+        collapse.run(File::open(mini.eventalign())?)?;
+        let mut file = File::open(&output)?;
+        assert!(!has_samples(&mut file)?);
+        let reads = load_iter(file).next().unwrap()?;
+        assert_eq!(reads.len(), 2);
+        for signal in reads.iter().flat_map(|r| r.signal_iter()) {
+            assert!(signal.samples.is_empty());
+            assert!(signal.signal_mean > 0.0);
+            assert!(signal.signal_time > 0.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_collapse_compression() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let mut sizes = Vec::new();
+        for compression in [ArrowCompression::None, ArrowCompression::Zstd] {
+            let output = mini.dir().join(format!("{compression}.arrow"));
+            let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+            collapse.compression(compression);
+            collapse.run(File::open(mini.eventalign())?)?;
+            let reads = load_iter(File::open(&output)?).next().unwrap()?;
+            assert_eq!(reads.len(), 2);
+            sizes.push(std::fs::metadata(&output)?.len());
+        }
+        assert!(sizes[1] < sizes[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collapse_errors() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output = mini.dir().join("test");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        let err = collapse.run(Cursor::new("")).err().unwrap();
+        assert!(matches!(err, CollapseError::NoData));
+        let err = collapse.run(File::open(mini.eventalign())?).err().unwrap();
+        assert!(matches!(err, CollapseError::AlreadyRun));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collapse_summary() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = File::open(mini.eventalign())?;
+        let output = mini.dir().join("test");
+        let summary = mini.dir().join("summary.tsv");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        collapse.summary(&summary)?;
+        collapse.run(input)?;
+
+        let summary = std::fs::read_to_string(summary)?;
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], SUMMARY_HEADER);
+        let fields = lines[1].split('\t').collect::<Vec<_>>();
+        assert_eq!(fields[1], "chrI");
+        assert_eq!(fields[2], "100");
+        assert_eq!(fields[3], "51");
+
+        // Every third position has two events, and position 120 is skipped
+        let n_events: u64 = fields[4].parse()?;
+        let n_skipped: u64 = fields[5].parse()?;
+        assert_eq!(n_skipped, 1);
+        assert_eq!(n_events, 50 + 16);
+        let mean_current: f64 = fields[7].parse()?;
+        assert!(mean_current > 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_separate_alignments() -> Result<()> {
+        let fragment = |start: u64, end: u64, strand: Strand| {
+            let metadata = Metadata::new(
+                "read".to_string(),
+                "chrI".to_string(),
+                start,
+                end - start,
+                strand,
+                String::new(),
+            );
+            let signals = (start..end)
+                .map(|pos| Signal::new(pos, "GATTAC".to_string(), 80.0, 0.001, Vec::new()))
+                .collect();
+            Fragment {
+                eventalign: Eventalign::new(metadata, signals),
+                n_events: end - start,
+            }
+        };
+        // Every piece is spilled on its own
+        let mut spill = Spill::new(1);
+        spill.push(fragment(500, 510, Strand::minus()))?;
+        spill.push(fragment(110, 120, Strand::plus()))?;
+        spill.push(fragment(300, 310, Strand::plus()))?;
+        spill.push(fragment(100, 108, Strand::plus()))?;
+        spill.push(fragment(105, 115, Strand::minus()))?;
+        let mut reads = Vec::new();
+        let n_split = spill.merge(|read| {
+            reads.push(read);
+            Ok(())
+        })?;
+        assert_eq!(n_split, 1);
+        let spans = reads
+            .iter()
+            .map(|read| {
+                let eventalign = &read.eventalign;
+                let n_signals = eventalign.signal_iter().count();
+                let (start, end) = (eventalign.start_0b(), eventalign.end_1b_excl());
+                (start, end, eventalign.strand(), n_signals)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                (100, 120, Strand::plus(), 18),
+                (300, 310, Strand::plus(), 10),
+                (105, 115, Strand::minus(), 10),
+                (500, 510, Strand::minus(), 10),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_fragments_without_samples() {
+        let fragment = |signals: Vec<Signal>| Fragment {
+            eventalign: Eventalign::new(Metadata::default(), signals),
+            n_events: 1,
+        };
+        let signal = |mean, time| Signal::new(10, "GATTAC".to_string(), mean, time, Vec::new());
+        let mut merged = fragment(vec![signal(80.0, 0.003)]);
+        merged.merge(fragment(vec![signal(100.0, 0.001)]));
+        merged.merge(fragment(vec![signal(90.0, 0.004)]));
+        let signals = merged.eventalign.signal_data_mut();
+        assert_eq!(signals.len(), 1);
+        assert!((signals[0].signal_mean - 87.5).abs() < 1e-9);
+        assert!((signals[0].signal_time - 0.008).abs() < 1e-12);
+        assert_eq!(merged.n_events, 3);
+    }
+
+    #[test]
+    fn test_collapse_interleaved() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let expected = mini.dir().join("expected");
+        CollapseOptions::try_new(mini.bam(), &expected)?.run(File::open(mini.eventalign())?)?;
+        let expected = load_iter(File::open(expected)?).next().unwrap()?;
+
+        // Alternate between halves of each read, so both are split in two
+        let text = std::fs::read_to_string(mini.eventalign())?;
+        let mut lines = text.lines();
+        let header = lines.next().unwrap();
+        let (plus, minus): (Vec<_>, Vec<_>) = lines.partition(|l| l.contains(PLUS_READ.name));
+        let (plus, minus) = (
+            plus.split_at(plus.len() / 2),
+            minus.split_at(minus.len() / 2),
+        );
+        let interleaved = [header]
+            .into_iter()
+            .chain(plus.0.iter().copied())
+            .chain(minus.0.iter().copied())
+            .chain(plus.1.iter().copied())
+            .chain(minus.1.iter().copied())
+            .join("\n");
+
+        // Without a limit the pieces are written separately
+        let pieces = mini.dir().join("pieces");
+        CollapseOptions::try_new(mini.bam(), &pieces)?.run(Cursor::new(&interleaved))?;
+        let pieces = load_iter(File::open(pieces)?).next().unwrap()?;
+        assert_eq!(pieces.len(), 4);
+
+        // A tiny limit spills every piece and splits reads at every position
+        let output = mini.dir().join("merged");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        collapse.max_memory(1);
+        collapse.run(Cursor::new(&interleaved))?;
+        let reads = load_iter(File::open(output)?).next().unwrap()?;
+        assert_eq!(reads.len(), 2);
+        for read in reads {
+            let exp = expected.iter().find(|e| e.name() == read.name()).unwrap();
+            assert_eq!(read.metadata, exp.metadata);
+            assert_eq!(read.signal_iter().count(), exp.signal_iter().count());
+            for (signal, exp) in read.signal_iter().zip(exp.signal_iter()) {
+                assert_eq!(signal.pos, exp.pos);
+                assert_eq!(signal.kmer, exp.kmer);
+                assert_eq!(signal.samples, exp.samples);
+                assert!((signal.signal_mean - exp.signal_mean).abs() < 1e-9);
+                assert!((signal.signal_time - exp.signal_time).abs() < 1e-9);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(feature = "large-data-tests"),
+        ignore = "requires large-data-tests feature"
+    )]
     fn test_collapse_big() -> Result<()> {
         let temp_dir = TempDir::new()?;
         let filepath = "extra/neg_control.eventalign.txt";
@@ -367,6 +1382,39 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_collapse_progress_sink() -> Result<()> {
+        use std::sync::Mutex;
+
+        use crate::progress::Progress;
+
+        #[derive(Default)]
+        struct LastProgress(Mutex<Option<Progress>>);
+
+        impl ProgressSink for LastProgress {
+            fn update(&self, _progress: &Progress) {}
+
+            fn finish(&self, progress: &Progress) {
+                *self.0.lock().unwrap() = Some(*progress);
+            }
+        }
+
+        let mini = MiniGenome::new()?;
+        let input = File::open(mini.eventalign())?;
+        let output = mini.dir().join("test");
+        let sink = Arc::new(LastProgress::default());
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
+        collapse.capacity(1).progress_sink(sink.clone());
+        collapse.run(input)?;
+
+        let progress = sink.0.lock().unwrap().unwrap();
+        assert_eq!(progress.stage, Stage::Collapse);
+        assert_eq!(progress.reads, 2);
+        assert_eq!(progress.chunks, 2);
+        assert_eq!(progress.total_chunks, None);
+        Ok(())
+    }
+
     #[test]
     fn test_malformed() {
         let lines: &[u8] = b"contig	position	reference_kmer	read_name	strand	event_index	event_level_mean	event_stdv	event_length	model_kmer	model_mean	model_stdv	standardized_level	samples
@@ -400,13 +1448,11 @@ chr1	199403040	ATATAA	c25d27a8-0eec-4e7d-96f9-b8e730a25832	t	3918	87.01		72.4013
         let mut strand_db = PlusStrandMap::default();
         strand_db.insert(b"c25d27a8-0eec-4e7d-96f9-b8e730a25832" as &[u8], true);
 
-        let schema = Eventalign::schema();
-        let writer = wrap_writer(Vec::new(), &schema).unwrap();
-        let mut opts = CollapseOptions::new(writer, strand_db);
+        let mut opts = CollapseOptions::new(Vec::new(), strand_db, Vec::new());
         let res = opts.run(lines);
         assert!(res.is_ok());
 
-        let reader = Cursor::new(opts.writer.into_inner());
+        let reader = Cursor::new(opts.writer.unwrap().into_inner());
         let x = load_iter(reader).next().unwrap().unwrap();
 
         let target = Eventalign::new(
@@ -430,6 +1476,46 @@ chr1	199403040	ATATAA	c25d27a8-0eec-4e7d-96f9-b8e730a25832	t	3918	87.01		72.4013
         pretty_assertions::assert_eq!(x[0], target);
     }
 
+    #[test]
+    fn test_collapse_skipped_lines() -> Result<()> {
+        let lines: &[u8] = b"contig	position	reference_kmer	read_name	strand	event_index	event_level_mean	event_stdv	event_length	model_kmer	model_mean	model_stdv	standardized_level	samples
+chr1	199403040	ATATAA	read1	t	3919	86.81	0.500	0.00100	TTATAT	87.94	1.88	-0.59	87.1186,87.4749,86.406,86.2279
+chr1	199403040	ATATAA	read1	t	3918	87.01		72.4013,75.9601,78.395,77.6458
+chr1	199403041	GATATA	read1	t		106.85	4.255	0.00100	TATATC	107.52	3.75	-0.18	99.4103,108.674
+chr1	199403042	AGATAT	read1	t	3916	106.85	4.255	0.00100	TATATC	107.52	3.75	-0.18	99.4103,108.674
+chr1	199403040	ATATAA	read2	t	10	86.81	0.500	0.00100	TTATAT	87.94	1.88	-0.59	87.1186,87.4749
+chr1	199403041	GATATA	read2	t	11	86.81	0.500	0.00100	TTATAT	87.94	1.88	-0.59	87.1186,87.4749
+";
+        let temp_dir = TempDir::new()?;
+        let errors = temp_dir.path().join("errors.tsv");
+        let mut strand_db = PlusStrandMap::default();
+        strand_db.insert(b"read1" as &[u8], true);
+
+        let mut opts = CollapseOptions::new(Vec::new(), strand_db, Vec::new());
+        opts.errors(&errors)?;
+        opts.run(lines)?;
+
+        // Two unparsed lines between events of read1 don't split it
+        let reader = Cursor::new(opts.writer.unwrap().into_inner());
+        let reads = load_iter(reader).next().unwrap()?;
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].name(), "read1");
+        assert_eq!(reads[0].signal_iter().count(), 2);
+
+        let errors = std::fs::read_to_string(errors)?;
+        let rows = errors
+            .lines()
+            .map(|l| l.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows[0].join("\t"), ERRORS_HEADER);
+        assert_eq!(&rows[1][..3], ["3", "field_count", "read1"]);
+        assert_eq!(&rows[2][..3], ["4", "invalid_value", "read1"]);
+        assert_eq!(&rows[3][..4], ["6", "unknown_strand", "read2", "2 events"]);
+        assert!(rows.contains(&vec!["# unknown_strand", "2"]));
+        assert_eq!(rows.last().unwrap(), &vec!["# total", "4"]);
+        Ok(())
+    }
+
     #[test]
     fn test_diff_idx() {
         let lines: &[u8] = b"contig	position	reference_kmer	read_name	strand	event_index	event_level_mean	event_stdv	event_length	model_kmer	model_mean	model_stdv	standardized_level	samples
@@ -440,13 +1526,11 @@ chr1	199403041	GATATA	c25d27a8-0eec-4e7d-96f9-b8e730a25832	t	3917	106.85	4.255	0
         let mut strand_db = PlusStrandMap::default();
         strand_db.insert(b"c25d27a8-0eec-4e7d-96f9-b8e730a25832" as &[u8], true);
 
-        let schema = Eventalign::schema();
-        let writer = wrap_writer(Vec::new(), &schema).unwrap();
-        let mut opts = CollapseOptions::new(writer, strand_db);
+        let mut opts = CollapseOptions::new(Vec::new(), strand_db, Vec::new());
         let res = opts.run(lines);
         assert!(res.is_ok());
 
-        let reader = Cursor::new(opts.writer.into_inner());
+        let reader = Cursor::new(opts.writer.unwrap().into_inner());
         let x = load_iter(reader).next().unwrap().unwrap();
 
         let target = Eventalign::new(
@@ -1,11 +1,265 @@
 use core::fmt;
-use std::io::{Read, Seek};
+use std::{
+    io::{Read, Seek},
+    str::FromStr,
+};
 
 use bio::{alphabets::dna, io::fasta::IndexedReader};
 use eyre::Result;
 use fnv::FnvHashMap;
 
-use crate::{arrow::metadata::MetadataExt, motif::Motif};
+use crate::{
+    arrow::metadata::MetadataExt,
+    motif::Motif,
+    utils::{check_contig_compatibility, chrom_lens, ChromAlias, ContigMap},
+};
+
+/// Default size of each block of sequence kept by [SeqCache]
+const BLOCK_SIZE: u64 = 1 << 20;
+
+/// How much of the genome to keep in memory while fetching sequences for
+/// reads. Defaults to a 256 MB budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenomeCache {
+    /// Keep the most recently used blocks of sequence, up to this many bytes.
+    /// A budget of zero fetches from the fasta for every read.
+    Budget(usize),
+    /// Read every chromosome into memory up front
+    Preload,
+}
+
+impl Default for GenomeCache {
+    fn default() -> Self {
+        GenomeCache::Budget(256 << 20)
+    }
+}
+
+/// Parses either "preload" or the cache size in megabytes
+impl FromStr for GenomeCache {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("preload") {
+            return Ok(GenomeCache::Preload);
+        }
+        s.parse::<usize>()
+            .map(|mb| GenomeCache::Budget(mb << 20))
+            .map_err(|_| format!("Expected \"preload\" or a size in MB, found \"{s}\""))
+    }
+}
+
+struct CachedBlock {
+    seq: Vec<u8>,
+    last_used: u64,
+}
+
+/// Reads sequences from an indexed genome fasta, keeping recently used blocks
+/// in memory. Reads from high coverage regions overlap the same few blocks, so
+/// most reads are served without going back to the fasta.
+pub(crate) struct SeqCache<R: Read + Seek> {
+    genome: IndexedReader<R>,
+    chrom_lens: FnvHashMap<String, u64>,
+    block_size: u64,
+    budget: usize,
+    used: usize,
+    tick: u64,
+    blocks: FnvHashMap<String, FnvHashMap<u64, CachedBlock>>,
+    chroms: FnvHashMap<String, Vec<u8>>,
+    alias: Option<ChromAlias>,
+    contigs: Option<ContigMap>,
+}
+
+impl<R> SeqCache<R>
+where
+    R: Read + Seek,
+{
+    pub(crate) fn new(genome: IndexedReader<R>, cache: GenomeCache) -> Result<Self> {
+        let chrom_lens = chrom_lens(&genome);
+        let mut seq_cache = Self {
+            genome,
+            chrom_lens,
+            block_size: BLOCK_SIZE,
+            budget: 0,
+            used: 0,
+            tick: 0,
+            blocks: FnvHashMap::default(),
+            chroms: FnvHashMap::default(),
+            alias: None,
+            contigs: None,
+        };
+        seq_cache.set_cache(cache)?;
+        Ok(seq_cache)
+    }
+
+    /// Drop everything cached so far and start caching with the new setting
+    ///
+    /// Genome fasta reader method makes clippy think its wrong but it still
+    /// works correctly.
+    #[allow(clippy::read_zero_byte_vec)]
+    pub(crate) fn set_cache(&mut self, cache: GenomeCache) -> Result<()> {
+        self.blocks.clear();
+        self.chroms.clear();
+        self.used = 0;
+        self.budget = match cache {
+            GenomeCache::Budget(budget) => budget,
+            GenomeCache::Preload => {
+                for chrom in self.chrom_lens.keys() {
+                    self.genome.fetch_all(chrom)?;
+                    let mut seq = Vec::new();
+                    self.genome.read(&mut seq)?;
+                    self.chroms.insert(chrom.clone(), seq);
+                }
+                0
+            }
+        };
+        Ok(())
+    }
+
+    /// Match chromosome names of reads to the genome through an alias file,
+    /// used after [SeqCache::check_contigs]
+    pub(crate) fn set_chrom_alias(&mut self, alias: ChromAlias) {
+        self.alias = Some(alias);
+    }
+
+    /// Whether the genome has the chromosome, directly or through the alias
+    /// file
+    pub(crate) fn has_chrom(&self, chrom: &str) -> bool {
+        match self.alias.as_ref() {
+            Some(alias) => alias
+                .aliases(chrom)
+                .any(|name| self.chrom_lens.contains_key(name)),
+            None => self.chrom_lens.contains_key(chrom),
+        }
+    }
+
+    /// Check that the chromosomes reads are on are in the genome, see
+    /// [check_contig_compatibility]. Afterwards chromosome names are resolved
+    /// through the alias file on every fetch.
+    pub(crate) fn check_contigs<'a, I>(&mut self, contigs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let genome_contigs = self.chrom_lens.keys().map(String::as_str);
+        self.contigs = Some(check_contig_compatibility(
+            genome_contigs,
+            contigs,
+            self.alias.as_ref(),
+        )?);
+        Ok(())
+    }
+
+    /// Sequence from start to stop, zero-based and exclusive. Like
+    /// [IndexedReader::fetch], the sequence stops early at the end of the
+    /// chromosome.
+    pub(crate) fn fetch(&mut self, chrom: &str, start: u64, stop: u64) -> Result<Vec<u8>> {
+        let resolved;
+        let chrom = match self.contigs.as_mut() {
+            Some(contigs) => {
+                resolved = contigs
+                    .resolve(chrom)
+                    .ok_or_else(|| eyre::eyre!("{chrom} is not in the genome"))?
+                    .to_string();
+                resolved.as_str()
+            }
+            None => chrom,
+        };
+        if let Some(seq) = self.chroms.get(chrom) {
+            let stop = (stop as usize).min(seq.len());
+            let start = (start as usize).min(stop);
+            return Ok(seq[start..stop].to_vec());
+        }
+        let chrom_len = match self.chrom_lens.get(chrom) {
+            Some(&chrom_len) if self.budget > 0 => chrom_len,
+            _ => return self.fetch_uncached(chrom, start, stop),
+        };
+        let stop = stop.min(chrom_len);
+        if start >= stop {
+            return Ok(Vec::new());
+        }
+        let mut seq = Vec::with_capacity((stop - start) as usize);
+        for block in (start / self.block_size)..=((stop - 1) / self.block_size) {
+            let block_start = block * self.block_size;
+            let block_seq = self.block(chrom, block, chrom_len)?;
+            let from = (start.max(block_start) - block_start) as usize;
+            let to = (stop - block_start).min(block_seq.len() as u64) as usize;
+            seq.extend_from_slice(&block_seq[from..to]);
+        }
+        Ok(seq)
+    }
+
+    #[allow(clippy::read_zero_byte_vec)]
+    fn fetch_uncached(&mut self, chrom: &str, start: u64, stop: u64) -> Result<Vec<u8>> {
+        let stop = self
+            .chrom_lens
+            .get(chrom)
+            .map_or(stop, |&len| stop.min(len));
+        if start >= stop {
+            return Ok(Vec::new());
+        }
+        self.genome.fetch(chrom, start, stop)?;
+        let mut seq = Vec::new();
+        self.genome.read(&mut seq)?;
+        Ok(seq)
+    }
+
+    /// Get a block from the cache, fetching it and evicting the least recently
+    /// used blocks to stay in budget if it isn't cached.
+    fn block(&mut self, chrom: &str, block: u64, chrom_len: u64) -> Result<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+        let cached = self
+            .blocks
+            .get(chrom)
+            .map_or(false, |blocks| blocks.contains_key(&block));
+        if !cached {
+            let block_start = block * self.block_size;
+            let block_stop = (block_start + self.block_size).min(chrom_len);
+            let seq = self.fetch_uncached(chrom, block_start, block_stop)?;
+            self.evict(seq.len());
+            self.used += seq.len();
+            self.blocks
+                .entry(chrom.to_string())
+                .or_default()
+                .insert(block, CachedBlock { seq, last_used: 0 });
+        }
+        let cached = self
+            .blocks
+            .get_mut(chrom)
+            .and_then(|blocks| blocks.get_mut(&block))
+            .expect("Block was just inserted");
+        cached.last_used = tick;
+        Ok(&cached.seq)
+    }
+
+    /// Remove least recently used blocks until there is room for len more
+    /// bytes, or the cache is empty
+    fn evict(&mut self, len: usize) {
+        while self.used > 0 && self.used + len > self.budget {
+            let oldest = self
+                .blocks
+                .iter()
+                .flat_map(|(chrom, blocks)| {
+                    blocks
+                        .iter()
+                        .map(move |(&block, cached)| (cached.last_used, chrom, block))
+                })
+                .min()
+                .map(|(_, chrom, block)| (chrom.clone(), block));
+            let (chrom, block) = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(blocks) = self.blocks.get_mut(&chrom) {
+                if let Some(cached) = blocks.remove(&block) {
+                    self.used -= cached.seq.len();
+                }
+                if blocks.is_empty() {
+                    self.blocks.remove(&chrom);
+                }
+            }
+        }
+    }
+}
 
 /// Contains the genomic bases for a given position including additional
 /// metadata to handle positions near the end of the genome.
@@ -37,14 +291,7 @@ impl Context {
         }
     }
 
-    /// Genome fasta reader method makes clippy think its wrong but it still
-    /// works correctly.
-    #[allow(clippy::read_zero_byte_vec)]
-    pub(crate) fn from_read<R>(
-        genome: &mut IndexedReader<R>,
-        _chrom_lens: &FnvHashMap<String, u64>,
-        read: &impl MetadataExt,
-    ) -> Result<Self>
+    pub(crate) fn from_read<R>(genome: &mut SeqCache<R>, read: &impl MetadataExt) -> Result<Self>
     where
         R: Read + Seek,
     {
@@ -71,10 +318,7 @@ impl Context {
         // } else {
         //     stop + 1
         // };
-        genome.fetch(chrom, start, stop)?;
-        let mut seq = Vec::new();
-
-        genome.read(&mut seq)?;
+        let mut seq = genome.fetch(chrom, start, stop)?;
 
         if read.strand().is_minus_strand() {
             log::debug!("Read is on negative");
@@ -101,11 +345,11 @@ impl Context {
     }
 
     /// Returns None if the position is near the end of the chromosome and it
-    /// would return a position with a kmer size less than six
-    pub(crate) fn sixmer_at(&self, pos: u64) -> Option<&[u8]> {
+    /// would return a kmer shorter than kmer_size
+    pub(crate) fn kmer_at(&self, pos: u64, kmer_size: usize) -> Option<&[u8]> {
         let true_pos = (pos - self.read_start) + self.start_slop;
         let true_pos = true_pos as usize;
-        self.context.get(true_pos..=true_pos + 5)
+        self.context.get(true_pos..true_pos + kmer_size)
     }
 
     pub(crate) fn start_slop(&self) -> u64 {
@@ -119,16 +363,60 @@ impl Context {
 
 #[cfg(test)]
 mod test {
-    // use std::io::Cursor;
-
-    // use super::*;
-    // use crate::{
-    //     arrow::{MetadataExt, Strand},
-    //     utils::chrom_lens,
-    // };
-
-    // #[test]
-    // fn test_context() -> Result<(), eyre::Error> {
-    //     u
-    // }
+    use super::*;
+    use crate::test_data::MiniGenome;
+
+    fn genome(mini: &MiniGenome) -> IndexedReader<std::fs::File> {
+        IndexedReader::from_file(&mini.genome()).unwrap()
+    }
+
+    #[test]
+    fn test_seq_cache() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let mut uncached = SeqCache::new(genome(&mini), GenomeCache::Budget(0))?;
+        let mut preloaded = SeqCache::new(genome(&mini), GenomeCache::Preload)?;
+        let mut cached = SeqCache::new(genome(&mini), GenomeCache::Budget(100))?;
+        cached.block_size = 32;
+
+        for (chrom, start, stop) in [
+            ("chrI", 0, 10),
+            ("chrI", 20, 90),
+            ("chrII", 200, 300),
+            ("chrI", 25, 30),
+            ("chrI", 290, 300),
+            ("chrII", 100, 100),
+        ] {
+            let expected = uncached.fetch(chrom, start, stop)?;
+            assert_eq!(cached.fetch(chrom, start, stop)?, expected);
+            assert_eq!(preloaded.fetch(chrom, start, stop)?, expected);
+            assert!(cached.used <= 100);
+        }
+        assert_eq!(uncached.fetch("chrII", 200, 300)?.len(), 40);
+        assert!(cached.fetch("chrIII", 0, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seq_cache_alias() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let mut seq_cache = SeqCache::new(genome(&mini), GenomeCache::default())?;
+        assert!(seq_cache.check_contigs(["I", "II"]).is_err());
+
+        let alias = ChromAlias::from_reader("chrI\tI\nchrII\tII\n".as_bytes())?;
+        seq_cache.set_chrom_alias(alias);
+        seq_cache.check_contigs(["I", "II"])?;
+        assert_eq!(
+            seq_cache.fetch("I", 10, 20)?,
+            seq_cache.fetch("chrI", 10, 20)?
+        );
+        assert!(seq_cache.fetch("III", 10, 20).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_genome_cache_from_str() {
+        assert_eq!("preload".parse(), Ok(GenomeCache::Preload));
+        assert_eq!("16".parse(), Ok(GenomeCache::Budget(16 << 20)));
+        assert!("lots".parse::<GenomeCache>().is_err());
+    }
 }
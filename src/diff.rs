@@ -0,0 +1,436 @@
+//! Differential modification between two samples, ie treated and untreated,
+//! at each position or in fixed windows.
+//!
+//! Modified and unmodified calls of the two samples are compared with either
+//! Fisher's exact test or a likelihood ratio (G) test, and p-values are
+//! corrected for the number of positions or windows tested with the
+//! Benjamini-Hochberg procedure.
+use std::{
+    collections::BTreeMap,
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use eyre::Result;
+use statrs::{
+    distribution::{ChiSquared, ContinuousCDF, Discrete, Hypergeometric},
+    statistics::{Max, Min},
+};
+
+use crate::{
+    arrow::arrow_utils::is_arrow_file,
+    pileup::{read_pileup, Pileup, PileupCounts, PileupOptions},
+    utils::stdout_or_file,
+};
+
+/// Statistical test comparing the calls of the two samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffTest {
+    /// Fisher's exact test, two-sided
+    Fisher,
+    /// Likelihood ratio (G) test, faster than Fisher's exact test for windows
+    /// with many calls
+    Lrt,
+}
+
+impl Default for DiffTest {
+    fn default() -> Self {
+        DiffTest::Fisher
+    }
+}
+
+impl FromStr for DiffTest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fisher" => Ok(DiffTest::Fisher),
+            "lrt" => Ok(DiffTest::Lrt),
+            _ => Err(format!(
+                "Invalid test \"{s}\", expected either fisher or lrt"
+            )),
+        }
+    }
+}
+
+impl DiffTest {
+    /// Two-sided p-value for a difference in the fraction of modified calls
+    pub fn p_value(&self, a: &PileupCounts, b: &PileupCounts) -> f64 {
+        match self {
+            DiffTest::Fisher => fisher_exact(a, b),
+            DiffTest::Lrt => g_test(a, b),
+        }
+    }
+}
+
+/// Fisher's exact test on the 2x2 table of modified and unmodified calls,
+/// summing the probabilities of every table with the same margins that is as
+/// or less likely than the observed one
+fn fisher_exact(a: &PileupCounts, b: &PileupCounts) -> f64 {
+    let population = a.n_valid() + b.n_valid();
+    let successes = a.n_modified + b.n_modified;
+    let Ok(dist) = Hypergeometric::new(population, successes, a.n_valid()) else {
+        return 1.0;
+    };
+    // Tolerance for tables with the same probability as the observed one
+    let observed = dist.ln_pmf(a.n_modified) + 1e-7;
+    let p: f64 = (dist.min()..=dist.max())
+        .map(|x| dist.ln_pmf(x))
+        .filter(|&ln_p| ln_p <= observed)
+        .map(f64::exp)
+        .sum();
+    p.min(1.0)
+}
+
+/// Likelihood ratio test of independence on the 2x2 table of modified and
+/// unmodified calls, with one degree of freedom
+fn g_test(a: &PileupCounts, b: &PileupCounts) -> f64 {
+    let rows = [a.n_valid() as f64, b.n_valid() as f64];
+    let cols = [
+        (a.n_modified + b.n_modified) as f64,
+        (a.n_unmodified + b.n_unmodified) as f64,
+    ];
+    let total = rows[0] + rows[1];
+    if rows.contains(&0.0) || cols.contains(&0.0) {
+        return 1.0;
+    }
+    let observed = [
+        [a.n_modified as f64, a.n_unmodified as f64],
+        [b.n_modified as f64, b.n_unmodified as f64],
+    ];
+    let mut g = 0.0;
+    for (i, row) in observed.iter().enumerate() {
+        for (j, &o) in row.iter().enumerate() {
+            if o > 0.0 {
+                let expected = rows[i] * cols[j] / total;
+                g += o * (o / expected).ln();
+            }
+        }
+    }
+    let chi2 = ChiSquared::new(1.0).expect("One degree of freedom is valid");
+    chi2.sf(2.0 * g).min(1.0)
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the same order as the input
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| p_values[i].total_cmp(&p_values[j]));
+    let mut q_values = vec![1.0; n];
+    let mut min_q: f64 = 1.0;
+    for (rank, &idx) in order.iter().enumerate().rev() {
+        let q = p_values[idx] * n as f64 / (rank + 1) as f64;
+        min_q = min_q.min(q);
+        q_values[idx] = min_q;
+    }
+    q_values
+}
+
+/// Calls of both samples at a position or window, with the results of the test
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffRegion {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+    /// Strand of the position, or "." for windows
+    pub strand: &'static str,
+    pub a: PileupCounts,
+    pub b: PileupCounts,
+    pub p_value: f64,
+    pub q_value: f64,
+}
+
+impl DiffRegion {
+    /// Fraction modified in the second sample minus the first
+    pub fn difference(&self) -> f64 {
+        self.b.fraction_modified().unwrap_or(0.0) - self.a.fraction_modified().unwrap_or(0.0)
+    }
+}
+
+/// Compares modification between two samples, from cawlr score, modification
+/// BAMs, or cawlr pileup output. Output is a BED file ranked by p-value, with
+/// the sample that is more modified as the name and a score of
+/// -100 * log10(q-value) capped at 1000, followed by the modified and valid
+/// calls of each sample, the fraction modified in each, the difference, the
+/// p-value, and the q-value.
+pub struct DiffOptions {
+    pileup: PileupOptions,
+    test: DiffTest,
+    window: Option<u64>,
+    min_coverage: u64,
+    max_q_value: f64,
+    names: (String, String),
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            pileup: PileupOptions::default(),
+            test: DiffTest::default(),
+            window: None,
+            min_coverage: 5,
+            max_q_value: 0.05,
+            names: ("a".to_string(), "b".to_string()),
+        }
+    }
+}
+
+impl DiffOptions {
+    /// Scores greater than the threshold are counted as modified, only used
+    /// for scored inputs
+    pub fn threshold(&mut self, threshold: f64) -> &mut Self {
+        self.pileup.threshold(threshold);
+        self
+    }
+
+    /// Scores within this distance of the threshold are left out of the test,
+    /// only used for scored inputs
+    pub fn no_call_margin(&mut self, no_call_margin: f64) -> &mut Self {
+        self.pileup.no_call_margin(no_call_margin);
+        self
+    }
+
+    /// Tag of the modification calls, only used if the input is a BAM file
+    pub fn mod_tag<B: Into<Vec<u8>>>(&mut self, mod_tag: Option<B>) -> &mut Self {
+        self.pileup.mod_tag(mod_tag);
+        self
+    }
+
+    pub fn test(&mut self, test: DiffTest) -> &mut Self {
+        self.test = test;
+        self
+    }
+
+    /// Sum calls on both strands in windows of this many bases instead of
+    /// testing each position
+    pub fn window(&mut self, window: Option<u64>) -> &mut Self {
+        self.window = window;
+        self
+    }
+
+    /// Leave out positions or windows with fewer valid calls in either sample
+    pub fn min_coverage(&mut self, min_coverage: u64) -> &mut Self {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Only write regions with a q-value at or below this, 1 writes every
+    /// region tested
+    pub fn max_q_value(&mut self, max_q_value: f64) -> &mut Self {
+        self.max_q_value = max_q_value;
+        self
+    }
+
+    /// Names of the two samples, used as the name of each region
+    pub fn names<S: Into<String>>(&mut self, a: S, b: S) -> &mut Self {
+        self.names = (a.into(), b.into());
+        self
+    }
+
+    /// Counts from cawlr score, a modification BAM, or cawlr pileup output
+    pub fn load(&self, input: &Path) -> Result<Pileup> {
+        let is_bam = input.extension().map_or(false, |ext| ext == "bam");
+        if is_bam || is_arrow_file(input) {
+            self.pileup.pileup(input)
+        } else {
+            Ok(read_pileup(input)?.0)
+        }
+    }
+
+    /// Counts of two samples from a file merged with cawlr merge
+    pub fn load_samples(&self, input: &Path, a: &str, b: &str) -> Result<(Pileup, Pileup)> {
+        let mut pileups = self.pileup.pileup_by_sample(input)?;
+        let mut take = |sample: &str| {
+            pileups
+                .remove(sample)
+                .ok_or_else(|| eyre::eyre!("No reads from sample {sample} in {}", input.display()))
+        };
+        Ok((take(a)?, take(b)?))
+    }
+
+    /// Test every position, or window, with enough coverage in both samples,
+    /// sorted by p-value
+    pub fn diff(&self, a: Pileup, b: Pileup) -> Vec<DiffRegion> {
+        let (a, b) = match self.window {
+            Some(window) => (into_windows(a, window), into_windows(b, window)),
+            None => (a, b),
+        };
+        let width = self.window.unwrap_or(1);
+        let mut regions = Vec::new();
+        for (chrom, a_counts) in a.iter() {
+            let Some(b_counts) = b.get(chrom) else {
+                continue;
+            };
+            for (&(start, strand), a_count) in a_counts.iter() {
+                let Some(b_count) = b_counts.get(&(start, strand)) else {
+                    continue;
+                };
+                if a_count.n_valid() < self.min_coverage || b_count.n_valid() < self.min_coverage {
+                    continue;
+                }
+                regions.push(DiffRegion {
+                    chrom: chrom.clone(),
+                    start,
+                    end: start + width,
+                    strand,
+                    a: *a_count,
+                    b: *b_count,
+                    p_value: self.test.p_value(a_count, b_count),
+                    q_value: 1.0,
+                });
+            }
+        }
+        let p_values = regions.iter().map(|r| r.p_value).collect::<Vec<_>>();
+        for (region, q_value) in regions.iter_mut().zip(benjamini_hochberg(&p_values)) {
+            region.q_value = q_value;
+        }
+        regions.sort_by(|x, y| {
+            x.p_value
+                .total_cmp(&y.p_value)
+                .then(y.difference().abs().total_cmp(&x.difference().abs()))
+        });
+        regions
+    }
+
+    /// Write the regions at or below the maximum q-value to output, or stdout
+    /// if there is none
+    pub fn write<P: AsRef<Path>>(&self, regions: &[DiffRegion], output: Option<P>) -> Result<()> {
+        let mut writer = BufWriter::new(stdout_or_file(output.as_ref())?);
+        let (a, b) = &self.names;
+        writeln!(
+            writer,
+            "#chrom\tstart\tend\tname\tscore\tstrand\tn_modified_{a}\tn_valid_{a}\t\
+             n_modified_{b}\tn_valid_{b}\tfraction_modified_{a}\tfraction_modified_{b}\t\
+             difference\tp_value\tq_value"
+        )?;
+        let mut n_written = 0;
+        for region in regions.iter().filter(|r| r.q_value <= self.max_q_value) {
+            let name = if region.difference() > 0.0 { b } else { a };
+            let score = (-100.0 * region.q_value.log10()).round().clamp(0.0, 1000.0);
+            let fraction = |counts: &PileupCounts| {
+                counts
+                    .fraction_modified()
+                    .map_or_else(|| "NA".to_string(), |f| f.to_string())
+            };
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{name}\t{score}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                region.chrom,
+                region.start,
+                region.end,
+                region.strand,
+                region.a.n_modified,
+                region.a.n_valid(),
+                region.b.n_modified,
+                region.b.n_valid(),
+                fraction(&region.a),
+                fraction(&region.b),
+                region.difference(),
+                region.p_value,
+                region.q_value,
+            )?;
+            n_written += 1;
+        }
+        writer.flush()?;
+        log::info!(
+            "{n_written} of {} regions tested had a q-value at or below {}",
+            regions.len(),
+            self.max_q_value
+        );
+        Ok(())
+    }
+
+    /// Test two inputs and write the differential regions, see [DiffOptions]
+    pub fn run<P: AsRef<Path>>(&self, a: &Path, b: &Path, output: Option<P>) -> Result<()> {
+        let regions = self.diff(self.load(a)?, self.load(b)?);
+        self.write(&regions, output)
+    }
+}
+
+/// Sum counts on both strands within windows starting at multiples of the
+/// window size
+fn into_windows(pileup: Pileup, window: u64) -> Pileup {
+    let window = window.max(1);
+    pileup
+        .into_iter()
+        .map(|(chrom, counts)| {
+            let mut windows: BTreeMap<_, PileupCounts> = BTreeMap::new();
+            for ((pos, _), c) in counts {
+                let w = windows.entry((pos - pos % window, ".")).or_default();
+                w.n_modified += c.n_modified;
+                w.n_unmodified += c.n_unmodified;
+                w.n_no_call += c.n_no_call;
+            }
+            (chrom, windows)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use float_eq::assert_float_eq;
+
+    use super::*;
+
+    fn counts(n_modified: u64, n_unmodified: u64) -> PileupCounts {
+        PileupCounts {
+            n_modified,
+            n_unmodified,
+            n_no_call: 0,
+        }
+    }
+
+    #[test]
+    fn test_tests() {
+        // Matches fisher.test(matrix(c(3, 1, 1, 3), 2)) in R
+        let p = DiffTest::Fisher.p_value(&counts(3, 1), &counts(1, 3));
+        assert_float_eq!(p, 0.4857143, abs <= 1e-6);
+        let p = DiffTest::Fisher.p_value(&counts(10, 0), &counts(0, 10));
+        assert_float_eq!(p, 1.082509e-05, abs <= 1e-9);
+        assert_eq!(DiffTest::Fisher.p_value(&counts(5, 0), &counts(5, 0)), 1.0);
+
+        let p = DiffTest::Lrt.p_value(&counts(10, 0), &counts(0, 10));
+        assert!(p < 1e-5);
+        assert_eq!(DiffTest::Lrt.p_value(&counts(5, 0), &counts(5, 0)), 1.0);
+    }
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        let q = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        for (q, e) in q.iter().zip(expected) {
+            assert_float_eq!(*q, e, abs <= 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut a = Pileup::new();
+        let mut b = Pileup::new();
+        let a_chrom = a.entry("chrI".to_string()).or_default();
+        a_chrom.insert((10, "+"), counts(10, 0));
+        a_chrom.insert((11, "-"), counts(5, 5));
+        a_chrom.insert((12, "+"), counts(1, 1));
+        let b_chrom = b.entry("chrI".to_string()).or_default();
+        b_chrom.insert((10, "+"), counts(0, 10));
+        b_chrom.insert((11, "-"), counts(5, 5));
+        b_chrom.insert((12, "+"), counts(0, 2));
+
+        let mut opts = DiffOptions::default();
+        let regions = opts.diff(a.clone(), b.clone());
+        // Position 12 doesn't have enough coverage
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].start, regions[0].end), (10, 11));
+        assert_eq!(regions[0].difference(), -1.0);
+        assert!(regions[0].q_value < 0.05);
+        assert_eq!(regions[1].p_value, 1.0);
+
+        opts.window(Some(100)).min_coverage(1);
+        let regions = opts.diff(a, b);
+        assert_eq!(regions.len(), 1);
+        assert_eq!((regions[0].start, regions[0].end), (0, 100));
+        assert_eq!(regions[0].strand, ".");
+        assert_eq!(regions[0].a, counts(16, 6));
+    }
+}
@@ -0,0 +1,204 @@
+//! Exploratory search for motifs that are modified, based on which kmers are
+//! most different between the positive and negative control models.
+use std::{cmp::Ordering, io::Write};
+
+use eyre::Result;
+use fnv::FnvHashMap;
+
+use crate::{
+    rank::{RankOptions, Ranks},
+    train::Model,
+};
+
+/// Motif shared between many of the top ranked kmers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateMotif {
+    pub motif: String,
+    /// 0-based position in the kmer where the motif starts
+    pub offset: usize,
+    /// Number of top ranked kmers containing the motif at this offset
+    pub support: usize,
+    /// Number of ranked kmers overall containing the motif at this offset
+    pub background: usize,
+    /// Fraction of top kmers with the motif divided by the fraction of all
+    /// kmers with the motif
+    pub enrichment: f64,
+    /// Mean divergence of the top kmers with the motif
+    pub mean_rank: f64,
+}
+
+pub struct DiscoverOptions {
+    rank_opts: RankOptions,
+    npsmlr: bool,
+    top: usize,
+    motif_len: usize,
+    min_support: usize,
+}
+
+impl Default for DiscoverOptions {
+    fn default() -> Self {
+        DiscoverOptions {
+            rank_opts: RankOptions::default(),
+            npsmlr: false,
+            top: 50,
+            motif_len: 2,
+            min_support: 3,
+        }
+    }
+}
+
+impl DiscoverOptions {
+    /// Options for ranking kmers, see [RankOptions::new]
+    pub fn rank_options(&mut self, rank_opts: RankOptions) -> &mut Self {
+        self.rank_opts = rank_opts;
+        self
+    }
+
+    /// Rank using a single gaussian for the negative control, for models from
+    /// cawlr npsmlr train
+    pub fn npsmlr(&mut self, npsmlr: bool) -> &mut Self {
+        self.npsmlr = npsmlr;
+        self
+    }
+
+    /// Number of highest ranked kmers used to find motifs
+    pub fn top(&mut self, top: usize) -> &mut Self {
+        self.top = top;
+        self
+    }
+
+    /// Length of candidate motifs, must be between 1 and 6
+    pub fn motif_len(&mut self, motif_len: usize) -> &mut Self {
+        self.motif_len = motif_len;
+        self
+    }
+
+    /// Minimum number of top kmers that must share a motif to report it
+    pub fn min_support(&mut self, min_support: usize) -> &mut Self {
+        self.min_support = min_support;
+        self
+    }
+
+    pub fn run(&mut self, pos_ctrl: &Model, neg_ctrl: &Model) -> Result<Vec<CandidateMotif>> {
+        if !(1..=6).contains(&self.motif_len) {
+            return Err(eyre::eyre!(
+                "Motif length must be between 1 and 6, got {}",
+                self.motif_len
+            ));
+        }
+        let ranks = if self.npsmlr {
+            self.rank_opts.rank_npsmlr(pos_ctrl, neg_ctrl)
+        } else {
+            self.rank_opts.rank(pos_ctrl, neg_ctrl)
+        };
+        Ok(self.candidates(&ranks))
+    }
+
+    /// Group the top ranked kmers by the motifs they share at each offset, and
+    /// compare against how often that motif occurs in all of the kmers.
+    fn candidates(&self, ranks: &Ranks) -> Vec<CandidateMotif> {
+        let mut ranked = ranks
+            .iter()
+            .filter(|(_, rank)| rank.is_finite())
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| {
+            b.1.partial_cmp(a.1)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        if ranked.is_empty() {
+            return Vec::new();
+        }
+        let n_top = self.top.min(ranked.len());
+
+        let mut background: FnvHashMap<(&str, usize), usize> = FnvHashMap::default();
+        let mut top: FnvHashMap<(&str, usize), Vec<f64>> = FnvHashMap::default();
+        for (idx, (kmer, &rank)) in ranked.iter().enumerate() {
+            if kmer.len() < self.motif_len {
+                continue;
+            }
+            for offset in 0..=(kmer.len() - self.motif_len) {
+                let key = (&kmer[offset..offset + self.motif_len], offset);
+                *background.entry(key).or_default() += 1;
+                if idx < n_top {
+                    top.entry(key).or_default().push(rank);
+                }
+            }
+        }
+
+        let n_top = n_top as f64;
+        let n_all = ranked.len() as f64;
+        let mut candidates = top
+            .into_iter()
+            .filter(|(_, top_ranks)| top_ranks.len() >= self.min_support)
+            .map(|((motif, offset), top_ranks)| {
+                let support = top_ranks.len();
+                let background = background[&(motif, offset)];
+                let enrichment = (support as f64 / n_top) / (background as f64 / n_all);
+                let mean_rank = top_ranks.iter().sum::<f64>() / support as f64;
+                CandidateMotif {
+                    motif: motif.to_string(),
+                    offset,
+                    support,
+                    background,
+                    enrichment,
+                    mean_rank,
+                }
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            b.enrichment
+                .partial_cmp(&a.enrichment)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| b.support.cmp(&a.support))
+                .then_with(|| a.motif.cmp(&b.motif))
+                .then_with(|| a.offset.cmp(&b.offset))
+        });
+        candidates
+    }
+}
+
+/// Write candidate motifs as a tab-separated table with a header.
+pub fn write_candidates<W: Write>(mut writer: W, candidates: &[CandidateMotif]) -> Result<()> {
+    writeln!(
+        writer,
+        "motif\toffset\tsupport\tbackground\tenrichment\tmean_rank"
+    )?;
+    for c in candidates {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{:.3}\t{:.3}",
+            c.motif, c.offset, c.support, c.background, c.enrichment, c.mean_rank
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut ranks = Ranks::default();
+        // GC at offset 1 is shared by all of the top kmers
+        for kmer in ["AGCAAA", "TGCTTT", "CGCCCC", "AGCTTT"] {
+            ranks.insert(kmer.parse().unwrap(), 10.0);
+        }
+        for kmer in ["AAAAAA", "TTTTTT", "CCCCCC", "ATATAT", "TATATA", "AGAAAA"] {
+            ranks.insert(kmer.parse().unwrap(), 0.1);
+        }
+
+        let mut opts = DiscoverOptions::default();
+        opts.top(4).motif_len(2).min_support(4);
+        let candidates = opts.candidates(&ranks);
+        assert_eq!(candidates.len(), 1);
+        let gc = &candidates[0];
+        assert_eq!(gc.motif, "GC");
+        assert_eq!(gc.offset, 1);
+        assert_eq!(gc.support, 4);
+        assert_eq!(gc.background, 4);
+        assert!((gc.enrichment - 2.5).abs() < 1e-9);
+        assert!((gc.mean_rank - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,490 @@
+//! Checks that the environment is ready to run the cawlr pipelines, ie that
+//! external tools are installed with recent enough versions, the genome index
+//! matches the genome, and there is a writable temporary directory with enough
+//! free space.
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bio::io::fasta;
+use eyre::Result;
+use which::which;
+
+use crate::utils;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Pipelines may still run, but something might go wrong
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Status::Ok => " OK ",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new<S: Into<String>, T: Into<String>>(name: S, status: Status, detail: T) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+/// External tool used by the pipelines
+struct Tool {
+    name: &'static str,
+    path: Option<PathBuf>,
+    /// Oldest version supporting the options used by the pipelines
+    min_version: Option<&'static [u64]>,
+}
+
+impl Tool {
+    fn new(name: &'static str, min_version: Option<&'static [u64]>) -> Self {
+        Self {
+            name,
+            path: None,
+            min_version,
+        }
+    }
+
+    fn find(&self) -> Option<PathBuf> {
+        match &self.path {
+            Some(p) if p.exists() => Some(p.clone()),
+            Some(_) => None,
+            None => which(self.name).ok(),
+        }
+    }
+}
+
+pub struct DoctorOptions {
+    nanopolish: Tool,
+    f5c: Tool,
+    samtools: Tool,
+    minimap2: Tool,
+    genome: Option<PathBuf>,
+    tmp_dir: PathBuf,
+    min_free_gb: u64,
+}
+
+impl Default for DoctorOptions {
+    fn default() -> Self {
+        Self {
+            nanopolish: Tool::new("nanopolish", None),
+            f5c: Tool::new("f5c", None),
+            // sort --write-index
+            samtools: Tool::new("samtools", Some(&[1, 10])),
+            // --sam-hit-only
+            minimap2: Tool::new("minimap2", Some(&[2, 17])),
+            genome: None,
+            tmp_dir: utils::tmp_dir(),
+            min_free_gb: 20,
+        }
+    }
+}
+
+impl DoctorOptions {
+    /// Path to nanopolish, by default searches $PATH
+    pub fn nanopolish(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.nanopolish.path = path;
+        self
+    }
+
+    /// Path to f5c, by default searches $PATH
+    pub fn f5c(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.f5c.path = path;
+        self
+    }
+
+    /// Path to samtools, by default searches $PATH
+    pub fn samtools(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.samtools.path = path;
+        self
+    }
+
+    /// Path to minimap2, by default searches $PATH
+    pub fn minimap2(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.minimap2.path = path;
+        self
+    }
+
+    /// Check that the genome fasta has a .fai index that matches it
+    pub fn genome(&mut self, genome: Option<PathBuf>) -> &mut Self {
+        self.genome = genome;
+        self
+    }
+
+    /// Directory used for temporary files, defaults to [utils::tmp_dir]
+    pub fn tmp_dir(&mut self, tmp_dir: PathBuf) -> &mut Self {
+        self.tmp_dir = tmp_dir;
+        self
+    }
+
+    /// Warn if the temporary directory has less free space, defaults to 20GB
+    pub fn min_free_gb(&mut self, min_free_gb: u64) -> &mut Self {
+        self.min_free_gb = min_free_gb;
+        self
+    }
+
+    pub fn checks(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+        let eventalign_tools = [&self.nanopolish, &self.f5c];
+        let has_eventalign = eventalign_tools.iter().any(|t| t.find().is_some());
+        for tool in eventalign_tools {
+            let mut check = check_tool(tool);
+            if check.status == Status::Fail && has_eventalign {
+                check.status = Status::Warn;
+                check
+                    .detail
+                    .push_str(", only one of nanopolish or f5c is needed");
+            }
+            checks.push(check);
+        }
+        checks.push(check_tool(&self.samtools));
+        checks.push(check_tool(&self.minimap2));
+        if let Some(genome) = &self.genome {
+            checks.push(check_genome(genome));
+        }
+        checks.push(check_tmp_dir(&self.tmp_dir));
+        checks.push(check_free_space(&self.tmp_dir, self.min_free_gb));
+        checks
+    }
+
+    /// Write the report and return the number of failed checks
+    pub fn run<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let checks = self.checks();
+        for check in checks.iter() {
+            writeln!(writer, "{check}")?;
+        }
+        let n_failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+        let n_warn = checks.iter().filter(|c| c.status == Status::Warn).count();
+        if n_failed == 0 {
+            writeln!(writer, "Ready to run cawlr pipelines ({n_warn} warnings)")?;
+        } else {
+            writeln!(
+                writer,
+                "Not ready: {n_failed} checks failed, {n_warn} warnings"
+            )?;
+        }
+        writer.flush()?;
+        Ok(n_failed)
+    }
+}
+
+/// Parse the first version number in the output of `tool --version`, ie
+/// "samtools 1.16.1" or "2.24-r1122"
+fn parse_version(output: &str) -> Option<Vec<u64>> {
+    output
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| token.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|token| {
+            token
+                .split(|c: char| !c.is_ascii_digit() && c != '.')
+                .next()
+                .unwrap_or_default()
+                .split('.')
+                .filter_map(|n| n.parse::<u64>().ok())
+                .collect::<Vec<_>>()
+        })
+        .find(|v| !v.is_empty())
+}
+
+fn version_str(version: &[u64]) -> String {
+    version
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn check_tool(tool: &Tool) -> Check {
+    let path = match tool.find() {
+        Some(path) => path,
+        None => {
+            let detail = match &tool.path {
+                Some(p) => format!("not found at {}", p.display()),
+                None => "not found in $PATH".to_string(),
+            };
+            return Check::new(tool.name, Status::Fail, detail);
+        }
+    };
+    let output = match Command::new(&path).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) => {
+            return Check::new(
+                tool.name,
+                Status::Fail,
+                format!("failed to run {}: {e}", path.display()),
+            )
+        }
+    };
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let version = match parse_version(&text) {
+        Some(version) => version,
+        None => {
+            return Check::new(
+                tool.name,
+                Status::Warn,
+                format!("unknown version ({})", path.display()),
+            )
+        }
+    };
+    match tool.min_version {
+        Some(min) if version.as_slice() < min => Check::new(
+            tool.name,
+            Status::Fail,
+            format!(
+                "version {} is older than the required {} ({})",
+                version_str(&version),
+                version_str(min),
+                path.display()
+            ),
+        ),
+        _ => Check::new(
+            tool.name,
+            Status::Ok,
+            format!("version {} ({})", version_str(&version), path.display()),
+        ),
+    }
+}
+
+/// Check every sequence in the genome has a matching name and length in the
+/// .fai index, in the same order.
+fn check_genome(genome: &Path) -> Check {
+    let name = "genome";
+    if !genome.exists() {
+        return Check::new(
+            name,
+            Status::Fail,
+            format!("{} does not exist", genome.display()),
+        );
+    }
+    let mut fai = genome.as_os_str().to_owned();
+    fai.push(".fai");
+    let fai = PathBuf::from(fai);
+    if !fai.exists() {
+        return Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "{} not found, create it with samtools faidx {}",
+                fai.display(),
+                genome.display()
+            ),
+        );
+    }
+    match genome_mismatch(genome, &fai) {
+        Ok(None) => Check::new(
+            name,
+            Status::Ok,
+            format!("{} matches {}", fai.display(), genome.display()),
+        ),
+        Ok(Some(problem)) => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "{} does not match the genome ({problem}), recreate it with samtools faidx {}",
+                fai.display(),
+                genome.display()
+            ),
+        ),
+        Err(e) => Check::new(name, Status::Fail, format!("{e}")),
+    }
+}
+
+fn genome_mismatch(genome: &Path, fai: &Path) -> Result<Option<String>> {
+    let mut fai_seqs = Vec::new();
+    for line in BufReader::new(File::open(fai)?).lines() {
+        let line = line?;
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [name, len, ..] => fai_seqs.push((name.to_string(), len.parse::<u64>()?)),
+            _ => return Ok(Some(format!("invalid line \"{line}\""))),
+        }
+    }
+    let mut fai_seqs = fai_seqs.into_iter();
+    for record in fasta::Reader::new(File::open(genome)?).records() {
+        let record = record?;
+        let len = record.seq().len() as u64;
+        match fai_seqs.next() {
+            Some((name, fai_len)) if name == record.id() && fai_len == len => (),
+            Some((name, fai_len)) => {
+                return Ok(Some(format!(
+                    "{} of length {len} is {name} of length {fai_len} in the index",
+                    record.id()
+                )))
+            }
+            None => return Ok(Some(format!("{} is missing from the index", record.id()))),
+        }
+    }
+    if let Some((name, _)) = fai_seqs.next() {
+        return Ok(Some(format!("{name} is not in the genome")));
+    }
+    Ok(None)
+}
+
+fn check_tmp_dir(tmp_dir: &Path) -> Check {
+    let name = "temporary directory";
+    let test_file = tmp_dir.join(format!(".cawlr-doctor-{}", std::process::id()));
+    let res = File::create(&test_file).and_then(|mut f| f.write_all(b"cawlr"));
+    let _ = fs::remove_file(&test_file);
+    match res {
+        Ok(()) => Check::new(
+            name,
+            Status::Ok,
+            format!("{} is writable", tmp_dir.display()),
+        ),
+        Err(e) => Check::new(
+            name,
+            Status::Fail,
+            format!("{} is not writable: {e}", tmp_dir.display()),
+        ),
+    }
+}
+
+/// Parse the available kilobytes from the output of `df -Pk`
+fn parse_df(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+fn check_free_space(dir: &Path, min_free_gb: u64) -> Check {
+    let name = "free space";
+    let available_kb = Command::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .ok()
+        .and_then(|o| parse_df(&String::from_utf8_lossy(&o.stdout)));
+    match available_kb {
+        Some(kb) => {
+            let gb = kb as f64 / (1024. * 1024.);
+            let detail = format!("{gb:.1}GB available in {}", dir.display());
+            if gb < min_free_gb as f64 {
+                Check::new(
+                    name,
+                    Status::Warn,
+                    format!("{detail}, less than {min_free_gb}GB"),
+                )
+            } else {
+                Check::new(name, Status::Ok, detail)
+            }
+        }
+        None => Check::new(
+            name,
+            Status::Warn,
+            format!("could not determine free space in {}", dir.display()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::test_data::MiniGenome;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            parse_version("samtools 1.16.1\nUsing htslib 1.16"),
+            Some(vec![1, 16, 1])
+        );
+        assert_eq!(parse_version("2.24-r1122\n"), Some(vec![2, 24]));
+        assert_eq!(
+            parse_version("nanopolish version 0.14.0\nWritten by Jared Simpson."),
+            Some(vec![0, 14, 0])
+        );
+        assert_eq!(parse_version("no version here"), None);
+        assert!(vec![1, 9] < vec![1, 10]);
+    }
+
+    #[test]
+    fn test_parse_df() {
+        let output = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                      /dev/sda1 1000 400 600 40% /\n";
+        assert_eq!(parse_df(output), Some(600));
+        assert_eq!(parse_df(""), None);
+    }
+
+    #[test]
+    fn test_check_genome() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        assert_eq!(check_genome(&mini.genome()).status, Status::Ok);
+
+        let fai = mini.dir().join("genome.fa.fai");
+        let index = fs::read_to_string(&fai)?;
+        fs::write(&fai, index.replace("chrII\t240", "chrII\t241"))?;
+        assert_eq!(check_genome(&mini.genome()).status, Status::Fail);
+
+        fs::remove_file(&fai)?;
+        assert_eq!(check_genome(&mini.genome()).status, Status::Fail);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_tool() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new()?;
+        let samtools = temp_dir.path().join("samtools");
+        let mut tool = Tool::new("samtools", Some(&[1, 10]));
+        tool.path = Some(samtools.clone());
+        assert_eq!(check_tool(&tool).status, Status::Fail);
+
+        for (version, status) in [("1.9", Status::Fail), ("1.16.1", Status::Ok)] {
+            fs::write(&samtools, format!("#!/bin/sh\necho samtools {version}\n"))?;
+            fs::set_permissions(&samtools, fs::Permissions::from_mode(0o755))?;
+            assert_eq!(check_tool(&tool).status, status);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tmp_dir() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        assert_eq!(check_tmp_dir(temp_dir.path()).status, Status::Ok);
+        let missing = temp_dir.path().join("missing");
+        assert_eq!(check_tmp_dir(&missing).status, Status::Fail);
+        Ok(())
+    }
+}
@@ -0,0 +1,429 @@
+//! Combine the two halves of duplex reads, where the template and complement
+//! strands of a molecule are sequenced back to back and scored as separate
+//! reads. Halves are matched by read name, ie read_template and
+//! read_complement, and scores of the same site are combined into a single
+//! score with both measurements, see [Score::n_obs] and [Score::sub_scores].
+//!
+//! The combined read is reported on the strand of the template. Scores are at
+//! the start of the motif on each strand, and the modified base of a motif
+//! like GpC or CpG sits at a different offset on the opposite strand, so
+//! complement scores are moved to the position of the same site on the
+//! template strand, see [template_pos].
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    str::FromStr,
+};
+
+use crate::{
+    arrow::{
+        metadata::MetadataExt,
+        scored_read::{Score, ScoredRead},
+    },
+    motif::Motif,
+};
+
+/// Probabilities are clamped to within this of 0 and 1 before combining, so a
+/// single certain measurement can't override the other
+const PROB_EPS: f64 = 1e-6;
+
+/// Read name suffixes of each half of a duplex read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplexSuffixes {
+    template: String,
+    complement: String,
+}
+
+impl DuplexSuffixes {
+    pub fn new<S: Into<String>>(template: S, complement: S) -> Self {
+        DuplexSuffixes {
+            template: template.into(),
+            complement: complement.into(),
+        }
+    }
+
+    /// Name shared by both halves and which half the read is, None if the read
+    /// isn't part of a duplex read
+    fn half<'a>(&self, name: &'a str) -> Option<(&'a str, Half)> {
+        if let Some(base) = name.strip_suffix(self.template.as_str()) {
+            Some((base, Half::Template))
+        } else {
+            name.strip_suffix(self.complement.as_str())
+                .map(|base| (base, Half::Complement))
+        }
+    }
+}
+
+impl Default for DuplexSuffixes {
+    fn default() -> Self {
+        DuplexSuffixes::new("_template", "_complement")
+    }
+}
+
+impl FromStr for DuplexSuffixes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some((template, complement))
+                if !template.is_empty() && !complement.is_empty() && template != complement =>
+            {
+                Ok(DuplexSuffixes::new(template, complement))
+            }
+            _ => Err(format!(
+                "Invalid duplex suffixes \"{s}\", expected two different suffixes separated by a \
+                 comma, ie _template,_complement"
+            )),
+        }
+    }
+}
+
+impl Display for DuplexSuffixes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.template, self.complement)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Half {
+    Template,
+    Complement,
+}
+
+/// Pairs up the halves of duplex reads as they're scored. Halves are held until
+/// the other half is seen, so they don't need to be in the same chunk of
+/// reads.
+#[derive(Debug, Clone, Default)]
+pub struct DuplexCombiner {
+    suffixes: DuplexSuffixes,
+    unpaired: HashMap<String, (Half, ScoredRead)>,
+    n_pairs: u64,
+}
+
+impl DuplexCombiner {
+    pub fn new(suffixes: DuplexSuffixes) -> Self {
+        DuplexCombiner {
+            suffixes,
+            ..Default::default()
+        }
+    }
+
+    pub fn suffixes(&self) -> &DuplexSuffixes {
+        &self.suffixes
+    }
+
+    /// Number of duplex reads combined so far
+    pub fn n_pairs(&self) -> u64 {
+        self.n_pairs
+    }
+
+    /// Combine each read with its other half if it has been seen, using the
+    /// motifs the reads were scored with to line up the scores of both strands.
+    /// Reads that aren't duplex halves are returned unchanged, and halves
+    /// without their other half yet are held back, see
+    /// [DuplexCombiner::finish].
+    pub fn pair(&mut self, reads: Vec<ScoredRead>, motifs: &[Motif]) -> Vec<ScoredRead> {
+        let mut acc = Vec::with_capacity(reads.len());
+        for read in reads {
+            let (base, half) = match self.suffixes.half(read.name()) {
+                Some((base, half)) => (base.to_string(), half),
+                None => {
+                    acc.push(read);
+                    continue;
+                }
+            };
+            match self.unpaired.remove(&base) {
+                Some((Half::Template, template)) if half == Half::Complement => {
+                    acc.push(combine_reads(base, &template, &read, motifs));
+                    self.n_pairs += 1;
+                }
+                Some((Half::Complement, complement)) if half == Half::Template => {
+                    acc.push(combine_reads(base, &read, &complement, motifs));
+                    self.n_pairs += 1;
+                }
+                // Same half seen twice, keep the earlier read as is
+                Some((prev_half, prev)) => {
+                    acc.push(prev);
+                    self.unpaired.insert(base, (prev_half, read));
+                }
+                None => {
+                    self.unpaired.insert(base, (half, read));
+                }
+            }
+        }
+        acc
+    }
+
+    /// Halves whose other half was never seen, unchanged and sorted by name.
+    /// These can only be written once every read has been seen, so they come
+    /// after the combined reads.
+    pub fn finish(self) -> Vec<ScoredRead> {
+        log::info!(
+            "Combined {} duplex reads, {} halves without their other half",
+            self.n_pairs,
+            self.unpaired.len()
+        );
+        let mut unpaired = self
+            .unpaired
+            .into_values()
+            .map(|(_, read)| read)
+            .collect::<Vec<_>>();
+        unpaired.sort_by(|a, b| a.name().cmp(b.name()));
+        unpaired
+    }
+}
+
+/// One read spanning both halves, named without the suffix and on the strand of
+/// the template. Sites scored in both halves are combined with
+/// [combine_measurements], sites scored in only one are kept as is.
+fn combine_reads(
+    name: String,
+    template: &ScoredRead,
+    complement: &ScoredRead,
+    motifs: &[Motif],
+) -> ScoredRead {
+    let mut metadata = template.metadata().clone();
+    let start = template.start_0b().min(complement.start_0b());
+    let end = template.end_1b_excl().max(complement.end_1b_excl());
+    if (start, end) != (template.start_0b(), template.end_1b_excl()) {
+        metadata.seq = String::new();
+    }
+    metadata.name = name;
+    metadata.start = start;
+    metadata.length = end - start;
+
+    let mut by_pos: BTreeMap<u64, Score> = template
+        .scores()
+        .iter()
+        .map(|score| (score.pos, score.clone()))
+        .collect();
+    for score in complement.scores() {
+        let Some(pos) = template_pos(score, motifs) else {
+            continue;
+        };
+        let combined = match by_pos.get(&pos) {
+            Some(prev) => combine_measurements(prev, score),
+            None => Score {
+                pos,
+                ..score.clone()
+            },
+        };
+        by_pos.insert(pos, combined);
+    }
+    ScoredRead::new(metadata, by_pos.into_values().collect())
+}
+
+/// Position on the template strand of the site a complement score measures.
+///
+/// The modified base of a motif of length n at offset k (zero-based) pairs with
+/// the base at offset n - 1 - k of the motif on the opposite strand, so the
+/// score moves by 2k - n + 1, ie one base forward for GpC and one base back for
+/// CpG. None if the score would move before the start of the chromosome.
+fn template_pos(score: &Score, motifs: &[Motif]) -> Option<u64> {
+    let motif = motifs
+        .iter()
+        .find(|motif| motif.matches_at(score.kmer.as_bytes(), 0));
+    let shift = match motif {
+        Some(motif) => 2 * motif.position_0b() as i64 - motif.len_motif() as i64 + 1,
+        None => 0,
+    };
+    score.pos.checked_add_signed(shift)
+}
+
+/// Whether a score is evidence about its site. Skipped positions only are if
+/// cawlr score gave them a skip score, without one their score is left at
+/// exactly 0.
+fn is_measured(score: &Score) -> bool {
+    !score.skipped || score.signal_score.is_some() || score.score != 0.0
+}
+
+/// Score from two measurements of the same site, treated as independent
+/// evidence by adding their log-odds. Agreeing measurements give a more
+/// confident score, disagreeing ones cancel out. If only one half was
+/// measured, its score is kept as is. The position and kmer are from the
+/// first measurement.
+pub fn combine_measurements(a: &Score, b: &Score) -> Score {
+    match (is_measured(a), is_measured(b)) {
+        (true, true) => (),
+        (false, true) => {
+            return Score {
+                pos: a.pos,
+                kmer: a.kmer.clone(),
+                ..b.clone()
+            }
+        }
+        _ => return a.clone(),
+    }
+    let signal_score = match (a.signal_score, b.signal_score) {
+        (Some(x), Some(y)) => Some(combine_probs(x, y)),
+        (x, y) => x.or(y),
+    };
+    let mut sub_scores = a.sub_scores().to_vec();
+    sub_scores.extend_from_slice(b.sub_scores());
+    Score {
+        pos: a.pos,
+        kmer: a.kmer.clone(),
+        skipped: a.skipped && b.skipped,
+        signal_score,
+        score: combine_probs(a.score, b.score),
+        n_obs: Some(a.n_obs() + b.n_obs()),
+        sub_scores: Some(sub_scores),
+    }
+}
+
+fn combine_probs(a: f64, b: f64) -> f64 {
+    let logit = |p: f64| {
+        let p = p.clamp(PROB_EPS, 1.0 - PROB_EPS);
+        (p / (1.0 - p)).ln()
+    };
+    1.0 / (1.0 + (-(logit(a) + logit(b))).exp())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::{
+        arrow::{arrow_utils::load_read_arrow, metadata::Strand},
+        motif::all_bases,
+        test_data::{write_reads, TestRead},
+    };
+
+    fn read(name: &str, start: u64, scores: &[(u64, f64)]) -> ScoredRead {
+        read_with_kmer(name, start, Strand::plus(), "A", scores)
+    }
+
+    fn read_with_kmer(
+        name: &str,
+        start: u64,
+        strand: Strand,
+        kmer: &str,
+        scores: &[(u64, f64)],
+    ) -> ScoredRead {
+        let scores = scores
+            .iter()
+            .map(|&(pos, s)| Score::new(pos, kmer.parse().unwrap(), false, Some(s), s))
+            .collect();
+        TestRead::new(name)
+            .span(start, 100)
+            .strand(strand)
+            .scored(scores)
+    }
+
+    #[test]
+    fn test_duplex_combiner() -> eyre::Result<()> {
+        let mut combiner = DuplexCombiner::new("_template,_complement".parse().unwrap());
+        let first = combiner.pair(
+            vec![
+                read("a_template", 0, &[(10, 0.8), (20, 0.3)]),
+                read("b", 0, &[(10, 0.5)]),
+                read("c_complement", 0, &[(10, 0.5)]),
+            ],
+            &all_bases(),
+        );
+        assert_eq!(first.iter().map(|r| r.name()).collect::<Vec<_>>(), ["b"]);
+
+        let second = combiner.pair(
+            vec![read("a_complement", 50, &[(10, 0.8), (120, 0.9)])],
+            &all_bases(),
+        );
+        assert_eq!(combiner.n_pairs(), 1);
+        let combined = &second[0];
+        assert_eq!(combined.name(), "a");
+        assert_eq!((combined.start_0b(), combined.end_1b_excl()), (0, 150));
+        let scores = combined.scores();
+        assert_eq!(
+            scores.iter().map(|s| s.pos).collect::<Vec<_>>(),
+            [10, 20, 120]
+        );
+        assert!(scores[0].score > 0.8);
+        assert_eq!(scores[0].n_obs(), 2);
+        assert_eq!(scores[0].sub_scores(), [0.8, 0.8]);
+        assert_eq!(scores[1].n_obs(), 1);
+        assert_eq!(scores[1].sub_scores(), [0.3]);
+
+        let unpaired = combiner.finish();
+        assert_eq!(
+            unpaired.iter().map(|r| r.name()).collect::<Vec<_>>(),
+            ["c_complement"]
+        );
+
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("duplex.arrow");
+        write_reads(&path, &second)?;
+        let mut loaded = Vec::new();
+        load_read_arrow(File::open(&path)?, |reads: Vec<ScoredRead>| {
+            loaded.extend(reads);
+            Ok(())
+        })?;
+        assert_eq!(loaded[0].scores()[0].sub_scores(), [0.8, 0.8]);
+        assert_eq!(loaded[0].scores()[2].n_obs, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplex_motif_offset() {
+        let template = read_with_kmer("a_template", 0, Strand::plus(), "GCAAAA", &[(10, 0.8)]);
+        let complement = read_with_kmer("a_complement", 0, Strand::minus(), "GCAAAA", &[(9, 0.8)]);
+        let mut combiner = DuplexCombiner::default();
+        let combined = combiner.pair(vec![template, complement], &[Motif::new("GC", 2)]);
+        assert_eq!(combined.len(), 1);
+        assert_eq!(combined[0].strand(), Strand::plus());
+        let scores = combined[0].scores();
+        assert_eq!(scores.iter().map(|s| s.pos).collect::<Vec<_>>(), [10]);
+        assert_eq!(scores[0].n_obs(), 2);
+
+        // Modified base at the start of the motif moves the other way
+        let complement = read_with_kmer("a", 0, Strand::minus(), "CGAAAA", &[(11, 0.8)]);
+        assert_eq!(
+            template_pos(&complement.scores()[0], &[Motif::new("CG", 1)]),
+            Some(10)
+        );
+        let at_start = read_with_kmer("a", 0, Strand::minus(), "CGAAAA", &[(0, 0.8)]);
+        assert_eq!(
+            template_pos(&at_start.scores()[0], &[Motif::new("CG", 1)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_combine_measurements_disagree() {
+        let a = Score::new(1, "A".parse().unwrap(), false, Some(0.9), 0.9);
+        let b = Score::new(1, "A".parse().unwrap(), false, Some(0.1), 0.1);
+        let combined = combine_measurements(&a, &b);
+        assert!((combined.score - 0.5).abs() < 1e-9);
+        assert!((combined.signal_score.unwrap() - 0.5).abs() < 1e-9);
+        assert_eq!(combined.n_obs(), 2);
+        assert!(!combined.skipped);
+
+        // A skipped half with a skip score is still evidence
+        let skip_scored = Score::new(1, "A".parse().unwrap(), true, None, 0.1);
+        let combined = combine_measurements(&a, &skip_scored);
+        assert!((combined.score - 0.5).abs() < 1e-9);
+        assert_eq!(combined.signal_score, Some(0.9));
+        assert_eq!(combined.n_obs(), 2);
+    }
+
+    #[test]
+    fn test_combine_measurements_one_skipped() {
+        let a = Score::new(1, "A".parse().unwrap(), false, Some(0.9), 0.9);
+        let b = Score::new(2, "T".parse().unwrap(), true, None, 0.0);
+        for combined in [combine_measurements(&a, &b), combine_measurements(&b, &a)] {
+            assert_eq!(combined.score, 0.9);
+            assert_eq!(combined.signal_score, Some(0.9));
+            assert!(!combined.skipped);
+            assert_eq!(combined.n_obs(), 1);
+            assert_eq!(combined.sub_scores(), [0.9]);
+        }
+        assert_eq!(combine_measurements(&b, &a).pos, 2);
+        assert_eq!(combine_measurements(&b, &a).kmer, "T");
+
+        let both = combine_measurements(&b, &b);
+        assert!(both.skipped);
+        assert_eq!(both.score, 0.0);
+        assert_eq!(both.n_obs(), 1);
+    }
+}
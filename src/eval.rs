@@ -0,0 +1,448 @@
+//! Evaluate modification calls against a truth set, such as bisulfite data or
+//! known motif positions, with ROC and precision-recall curves.
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use eyre::{Context, Result};
+use fnv::FnvHashMap;
+
+use crate::{
+    arrow::{
+        arrow_utils::is_arrow_file,
+        io::{read_mod_bam_or_arrow, ModFile},
+        metadata::MetadataExt,
+    },
+    utils::open_maybe_gz,
+};
+
+/// Labels for genomic positions, strands are ignored when matching
+#[derive(Debug, Default)]
+pub struct Truth {
+    /// Sorted, merged intervals where every position is modified, positions
+    /// outside of them are unmodified
+    intervals: FnvHashMap<String, Vec<(u64, u64)>>,
+    /// Positions with a known label, unlisted positions are left out
+    sites: FnvHashMap<String, FnvHashMap<u64, bool>>,
+}
+
+impl Truth {
+    /// Read a BED file of modified positions, or a bedMethyl file of modified
+    /// percentages, detected by the number of columns. bedMethyl sites are
+    /// modified at or above min_modified percent, unmodified at or below
+    /// max_unmodified percent, and left out otherwise or if covered by fewer
+    /// reads than min_coverage.
+    pub fn from_path(
+        path: &Path,
+        min_modified: f64,
+        max_unmodified: f64,
+        min_coverage: u64,
+    ) -> Result<Self> {
+        let mut truth = Truth::default();
+        for (idx, line) in open_maybe_gz(path)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") {
+                continue;
+            }
+            let invalid = || format!("Invalid line {} in {}", idx + 1, path.display());
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() < 3 {
+                return Err(eyre::eyre!("Expected at least 3 columns")).wrap_err_with(invalid);
+            }
+            let chrom = fields[0].to_string();
+            let start: u64 = fields[1].parse().wrap_err_with(invalid)?;
+            let end: u64 = fields[2].parse().wrap_err_with(invalid)?;
+            if fields.len() >= 11 {
+                let coverage: u64 = fields[9].parse().wrap_err_with(invalid)?;
+                let percent: f64 = fields[10].parse().wrap_err_with(invalid)?;
+                let label = if percent >= min_modified {
+                    true
+                } else if percent <= max_unmodified {
+                    false
+                } else {
+                    continue;
+                };
+                if coverage < min_coverage {
+                    continue;
+                }
+                let sites = truth.sites.entry(chrom).or_default();
+                for pos in start..end {
+                    sites.insert(pos, label);
+                }
+            } else {
+                truth.intervals.entry(chrom).or_default().push((start, end));
+            }
+        }
+        for intervals in truth.intervals.values_mut() {
+            intervals.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+            for &(start, end) in intervals.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *intervals = merged;
+        }
+        Ok(truth)
+    }
+
+    /// Whether the position is modified, None if it isn't in the truth set
+    pub fn label(&self, chrom: &str, pos: u64) -> Option<bool> {
+        if let Some(label) = self.sites.get(chrom).and_then(|s| s.get(&pos)) {
+            return Some(*label);
+        }
+        if self.intervals.is_empty() {
+            return None;
+        }
+        let intervals = match self.intervals.get(chrom) {
+            Some(intervals) => intervals,
+            None => return Some(false),
+        };
+        let idx = intervals.partition_point(|&(start, _)| start <= pos);
+        Some(idx > 0 && pos < intervals[idx - 1].1)
+    }
+}
+
+/// Counts of predictions at a single threshold, where scores greater than the
+/// threshold are called modified
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Confusion {
+    pub threshold: f64,
+    pub tp: u64,
+    pub fp: u64,
+    pub tn: u64,
+    pub fn_: u64,
+}
+
+impl Confusion {
+    fn ratio(num: u64, denom: u64) -> f64 {
+        if denom == 0 {
+            f64::NAN
+        } else {
+            num as f64 / denom as f64
+        }
+    }
+
+    /// True positive rate, or recall
+    pub fn tpr(&self) -> f64 {
+        Confusion::ratio(self.tp, self.tp + self.fn_)
+    }
+
+    pub fn fpr(&self) -> f64 {
+        Confusion::ratio(self.fp, self.fp + self.tn)
+    }
+
+    pub fn precision(&self) -> f64 {
+        Confusion::ratio(self.tp, self.tp + self.fp)
+    }
+
+    pub fn f1(&self) -> f64 {
+        Confusion::ratio(2 * self.tp, 2 * self.tp + self.fp + self.fn_)
+    }
+}
+
+/// ROC and precision-recall curves, with the area under each
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalReport {
+    pub n_positive: u64,
+    pub n_negative: u64,
+    /// Positions scored but not in the truth set
+    pub n_unlabeled: u64,
+    pub auroc: f64,
+    /// Average precision, the area under the precision-recall curve using
+    /// the step function instead of interpolation
+    pub auprc: f64,
+    /// Ordered from lowest to highest threshold
+    pub curve: Vec<Confusion>,
+}
+
+impl EvalReport {
+    fn from_histograms(positive: &[u64], negative: &[u64], n_unlabeled: u64) -> Self {
+        let n_bins = positive.len();
+        let n_positive = positive.iter().sum::<u64>();
+        let n_negative = negative.iter().sum::<u64>();
+        // Bin i holds scores in (i / n_bins, (i + 1) / n_bins], along with
+        // scores of exactly 0 in the first bin. Walk down from the highest
+        // threshold so counts above it accumulate.
+        let mut curve = Vec::with_capacity(n_bins + 1);
+        let (mut tp, mut fp) = (0, 0);
+        for i in (0..=n_bins).rev() {
+            if i < n_bins {
+                tp += positive[i];
+                fp += negative[i];
+            }
+            curve.push(Confusion {
+                threshold: i as f64 / n_bins as f64,
+                tp,
+                fp,
+                tn: n_negative - fp,
+                fn_: n_positive - tp,
+            });
+        }
+        curve.reverse();
+
+        let (mut auroc, mut auprc) = (0.0, 0.0);
+        for pair in curve.windows(2) {
+            let (lo, hi) = (&pair[0], &pair[1]);
+            if n_positive > 0 && n_negative > 0 {
+                auroc += (lo.fpr() - hi.fpr()) * (lo.tpr() + hi.tpr()) / 2.0;
+            }
+            if lo.tp > hi.tp {
+                auprc += (lo.tpr() - hi.tpr()) * lo.precision();
+            }
+        }
+        EvalReport {
+            n_positive,
+            n_negative,
+            n_unlabeled,
+            auroc: if n_positive > 0 && n_negative > 0 {
+                auroc
+            } else {
+                f64::NAN
+            },
+            auprc: if n_positive > 0 { auprc } else { f64::NAN },
+            curve,
+        }
+    }
+
+    /// Tab-separated confusion matrix and rates at each threshold
+    pub fn write_curve<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "threshold\ttp\tfp\ttn\tfn\ttpr\tfpr\tprecision\tf1")?;
+        for c in self.curve.iter() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                c.threshold,
+                c.tp,
+                c.fp,
+                c.tn,
+                c.fn_,
+                c.tpr(),
+                c.fpr(),
+                c.precision(),
+                c.f1()
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Tab-separated statistic and value pairs
+    pub fn write_summary<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "statistic\tvalue")?;
+        writeln!(writer, "n_positive\t{}", self.n_positive)?;
+        writeln!(writer, "n_negative\t{}", self.n_negative)?;
+        writeln!(writer, "n_unlabeled\t{}", self.n_unlabeled)?;
+        writeln!(writer, "auroc\t{}", self.auroc)?;
+        writeln!(writer, "auprc\t{}", self.auprc)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Compares scores from cawlr score, a modification bam, or cawlr pileup
+/// against a [Truth] set.
+pub struct EvalOptions {
+    n_thresholds: usize,
+    mod_tag: Option<Vec<u8>>,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        EvalOptions {
+            n_thresholds: 100,
+            mod_tag: None,
+        }
+    }
+}
+
+impl EvalOptions {
+    /// Number of evenly spaced thresholds between 0 and 1, defaults to 100
+    pub fn n_thresholds(&mut self, n_thresholds: usize) -> &mut Self {
+        self.n_thresholds = n_thresholds.max(1);
+        self
+    }
+
+    /// Modification tag used if the input is a bam file, ie C+m
+    pub fn mod_tag<B: Into<Vec<u8>>>(&mut self, mod_tag: Option<B>) -> &mut Self {
+        self.mod_tag = mod_tag.map(|t| t.into());
+        self
+    }
+
+    /// Each read's score at a position counts as one prediction for scored
+    /// inputs, while each position counts once for pileup inputs, using the
+    /// fraction of reads modified as the score.
+    pub fn run(&self, input: &Path, truth: &Truth) -> Result<EvalReport> {
+        let mut positive = vec![0u64; self.n_thresholds];
+        let mut negative = vec![0u64; self.n_thresholds];
+        let mut n_unlabeled = 0;
+        let n_bins = self.n_thresholds as f64;
+        let mut add = |chrom: &str, pos: u64, score: f64| {
+            if score.is_nan() {
+                return;
+            }
+            // Scores exactly on a threshold are not greater than it, so they
+            // go in the bin below
+            let bin = ((score.clamp(0.0, 1.0) * n_bins).ceil() as usize).saturating_sub(1);
+            let bin = bin.min(self.n_thresholds - 1);
+            match truth.label(chrom, pos) {
+                Some(true) => positive[bin] += 1,
+                Some(false) => negative[bin] += 1,
+                None => n_unlabeled += 1,
+            }
+        };
+
+        let is_bam = input.extension().map_or(false, |ext| ext == "bam");
+        if is_bam || is_arrow_file(input) {
+            let mod_file = ModFile::open_path(input, self.mod_tag.clone())?;
+            read_mod_bam_or_arrow(mod_file, |read| {
+                if read.is_unaligned() {
+                    return Ok(());
+                }
+                for score in read.scores() {
+                    add(read.chrom(), score.pos, score.score);
+                }
+                Ok(())
+            })?;
+        } else {
+            read_pileup(input, &mut add)?;
+        }
+        if positive.iter().chain(negative.iter()).all(|&n| n == 0) {
+            log::warn!("No scored positions are in the truth set, check chromosome names");
+        }
+        Ok(EvalReport::from_histograms(
+            &positive,
+            &negative,
+            n_unlabeled,
+        ))
+    }
+}
+
+/// Read positions and the fraction modified from cawlr pileup output, either
+/// the TSV or bedMethyl format
+fn read_pileup<F>(input: &Path, mut f: F) -> Result<()>
+where
+    F: FnMut(&str, u64, f64),
+{
+    for (idx, line) in open_maybe_gz(input)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with("chrom\t") || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || format!("Invalid line {} in {}", idx + 1, input.display());
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let pos: u64 = fields
+            .get(1)
+            .ok_or_else(|| eyre::eyre!("Missing start"))
+            .and_then(|s| Ok(s.parse()?))
+            .wrap_err_with(invalid)?;
+        let fraction = match fields.len() {
+            // chrom, start, strand, counts, and fraction_modified
+            7 => match fields[6] {
+                "NA" => continue,
+                fraction => fraction.parse::<f64>().wrap_err_with(invalid)?,
+            },
+            n if n >= 11 => fields[10].parse::<f64>().wrap_err_with(invalid)? / 100.0,
+            _ => {
+                return Err(eyre::eyre!("Not from cawlr pileup")).wrap_err_with(invalid);
+            }
+        };
+        f(fields[0], pos, fraction);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_data::{test_score, write_reads, MiniGenome, TestRead};
+
+    #[test]
+    fn test_truth() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let bed = mini.dir().join("truth.bed");
+        std::fs::write(&bed, "chrI\t10\t12\nchrI\t11\t15\tm\nchrII\t5\t6\n")?;
+        let truth = Truth::from_path(&bed, 90.0, 10.0, 1)?;
+        assert_eq!(truth.label("chrI", 9), Some(false));
+        assert_eq!(truth.label("chrI", 10), Some(true));
+        assert_eq!(truth.label("chrI", 14), Some(true));
+        assert_eq!(truth.label("chrI", 15), Some(false));
+        assert_eq!(truth.label("chrIII", 5), Some(false));
+
+        let bedmethyl = mini.dir().join("truth.bedmethyl");
+        std::fs::write(
+            &bedmethyl,
+            "chrI\t10\t11\tm\t5\t+\t10\t11\t255,0,0\t5\t100.0\n\
+             chrI\t11\t12\tm\t5\t+\t11\t12\t255,0,0\t5\t50.0\n\
+             chrI\t12\t13\tm\t5\t+\t12\t13\t255,0,0\t5\t0.0\n\
+             chrI\t13\t14\tm\t1\t+\t13\t14\t255,0,0\t1\t0.0\n",
+        )?;
+        let truth = Truth::from_path(&bedmethyl, 90.0, 10.0, 2)?;
+        assert_eq!(truth.label("chrI", 10), Some(true));
+        assert_eq!(truth.label("chrI", 11), None);
+        assert_eq!(truth.label("chrI", 12), Some(false));
+        assert_eq!(truth.label("chrI", 13), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let bed = mini.dir().join("truth.bed");
+        std::fs::write(&bed, "chrI\t10\t12\n")?;
+        let truth = Truth::from_path(&bed, 90.0, 10.0, 1)?;
+
+        // Positives at 10 and 11 always score higher than negatives at 12 and
+        // 13, except for one read
+        let input = mini.dir().join("scores.arrow");
+        let reads = [[0.9, 0.8, 0.1, 0.2], [0.7, 0.6, 0.3, 0.65]]
+            .iter()
+            .enumerate()
+            .map(|(i, scores)| {
+                let scores = scores
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &s)| test_score(10 + j as u64, s))
+                    .collect();
+                TestRead::new(format!("read{i}")).scored(scores)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let report = EvalOptions::default().run(&input, &truth)?;
+        assert_eq!((report.n_positive, report.n_negative), (4, 4));
+        // One of 16 positive and negative pairs is ordered wrong
+        assert!(
+            (report.auroc - 15.0 / 16.0).abs() < 1e-10,
+            "{}",
+            report.auroc
+        );
+        assert!(report.auprc > 0.9 && report.auprc < 1.0, "{}", report.auprc);
+        let at_half = report
+            .curve
+            .iter()
+            .find(|c| (c.threshold - 0.5).abs() < 1e-10)
+            .unwrap();
+        assert_eq!(
+            (at_half.tp, at_half.fp, at_half.tn, at_half.fn_),
+            (4, 1, 3, 0)
+        );
+        assert_eq!(report.curve.first().unwrap().tp, 4);
+        assert_eq!(report.curve.last().unwrap().tp, 0);
+
+        let pileup = mini.dir().join("pileup.tsv");
+        std::fs::write(
+            &pileup,
+            "chrom\tstart\tstrand\tn_modified\tn_unmodified\tn_no_call\tfraction_modified\n\
+             chrI\t10\t+\t2\t0\t0\t1\n\
+             chrI\t12\t+\t0\t2\t0\t0\n\
+             chrI\t13\t+\t0\t0\t2\tNA\n",
+        )?;
+        let report = EvalOptions::default().run(&pileup, &truth)?;
+        assert_eq!((report.n_positive, report.n_negative), (1, 1));
+        assert!((report.auroc - 1.0).abs() < 1e-10);
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+//! C interface for scoring single reads as they are sequenced, ie in adaptive
+//! sampling pipelines, without writing them to Arrow files first.
+//!
+//! Models and ranks are loaded once with `cawlr_scorer_new`, then each read is
+//! scored with `cawlr_score_read` from the signal at each position. Functions
+//! returning `int` return 0 on success and -1 on failure, and pointer returning
+//! functions return NULL on failure, with the reason from `cawlr_last_error`.
+//! See `include/cawlr.h` for the declarations.
+//!
+//! Build a shared library with
+//! `cargo rustc --release --lib --crate-type cdylib`.
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    panic::{catch_unwind, UnwindSafe},
+    path::Path,
+    ptr, slice,
+    str::FromStr,
+};
+
+use eyre::{eyre, Result, WrapErr};
+use fnv::FnvHashMap;
+
+use crate::{
+    arrow::{kmer::Kmer, scored_read::Score, signal::Signal},
+    motif::{all_bases, Motif, KMER_SIZE},
+    rank::Ranks,
+    score::{calc_signal_score, check_motifs, ScoreError},
+    train::Model,
+    utils::CawlrIO,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(msg: String) {
+    // Interior nul bytes would truncate the message, replace them instead
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run f, recording the error or panic for cawlr_last_error instead of
+/// unwinding into the caller
+fn ffi_call<T, F>(f: F) -> Option<T>
+where
+    F: FnOnce() -> Result<T> + UnwindSafe,
+{
+    match catch_unwind(f) {
+        Ok(Ok(x)) => Some(x),
+        Ok(Err(e)) => {
+            set_last_error(format!("{e:#}"));
+            None
+        }
+        Err(_) => {
+            set_last_error("cawlr panicked, this is a bug".to_string());
+            None
+        }
+    }
+}
+
+fn status<T>(res: Option<T>) -> c_int {
+    if res.is_some() {
+        0
+    } else {
+        -1
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(eyre!("{name} is NULL"));
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+/// Models and thresholds for scoring single reads, the Rust side of the C
+/// interface
+pub struct CawlrScorer {
+    pos_ctrl: Model,
+    neg_ctrl: Model,
+    ranks: Ranks,
+    motifs: Vec<Motif>,
+    cutoff: f64,
+    p_value_threshold: f64,
+}
+
+impl CawlrScorer {
+    pub fn new(pos_ctrl: Model, neg_ctrl: Model, ranks: Ranks) -> Self {
+        CawlrScorer {
+            pos_ctrl,
+            neg_ctrl,
+            ranks,
+            motifs: all_bases(),
+            cutoff: 10.0,
+            p_value_threshold: 0.05,
+        }
+    }
+
+    pub fn try_new<P: AsRef<Path>>(pos_ctrl: P, neg_ctrl: P, ranks: P) -> Result<Self> {
+        let load_model = |path: &Path| {
+            Model::load(path).wrap_err_with(|| format!("Failed to load model {}", path.display()))
+        };
+        let pos_ctrl = load_model(pos_ctrl.as_ref())?;
+        let neg_ctrl = load_model(neg_ctrl.as_ref())?;
+        let ranks = ranks.as_ref();
+        let ranks = Ranks::load(ranks)
+            .wrap_err_with(|| format!("Failed to load ranks {}", ranks.display()))?;
+        Ok(CawlrScorer::new(pos_ctrl, neg_ctrl, ranks))
+    }
+
+    /// See [ScoreOptions::cutoff](crate::score::ScoreOptions::cutoff)
+    pub fn cutoff(&mut self, cutoff: f64) -> &mut Self {
+        self.cutoff = cutoff;
+        self
+    }
+
+    /// See
+    /// [ScoreOptions::p_value_threshold](crate::score::ScoreOptions::p_value_threshold)
+    pub fn p_value_threshold(&mut self, p_value_threshold: f64) -> &mut Self {
+        self.p_value_threshold = p_value_threshold;
+        self
+    }
+
+    /// Only score kmers containing these motifs, which must fit within a kmer
+    pub fn motifs<V: Into<Vec<Motif>>>(&mut self, motifs: V) -> Result<&mut Self, ScoreError> {
+        let motifs = motifs.into();
+        check_motifs(&motifs, KMER_SIZE)?;
+        self.motifs = motifs;
+        Ok(self)
+    }
+
+    /// Score each position of a read where the kmer starts with one of the
+    /// motifs, in the order of the signals. Unlike cawlr score the genome isn't
+    /// available, so only positions with signal are scored. Fails if one of
+    /// the scored kmers isn't a valid [Kmer].
+    pub fn score_signals(&self, signals: &[Signal]) -> Result<Vec<Score>> {
+        let data_pos: FnvHashMap<u64, &Signal> = signals.iter().map(|s| (s.pos, s)).collect();
+        signals
+            .iter()
+            .filter(|s| self.motifs.iter().any(|m| m.matches_at(&s.kmer, 0)))
+            .map(|s| {
+                let kmer = Kmer::from_str(&s.kmer)
+                    .wrap_err_with(|| format!("Invalid kmer at position {}", s.pos))?;
+                let signal_score = calc_signal_score(
+                    s.pos,
+                    &data_pos,
+                    &self.pos_ctrl,
+                    &self.neg_ctrl,
+                    &self.ranks,
+                    self.cutoff,
+                    self.p_value_threshold,
+                );
+                let score = signal_score.unwrap_or(0.0);
+                Ok(Score::new(
+                    s.pos,
+                    kmer,
+                    signal_score.is_none(),
+                    signal_score,
+                    score,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Current measured at one position of a read
+#[repr(C)]
+pub struct CawlrSignal {
+    /// Zero-based genomic position of the start of the kmer
+    pub pos: u64,
+    /// Reference kmer starting at pos, NUL-terminated
+    pub kmer: *const c_char,
+    /// Current samples aligned to the kmer, averaged to score it
+    pub samples: *const f64,
+    pub n_samples: usize,
+}
+
+/// Score for one position, like the scores in cawlr score outputs
+#[repr(C)]
+pub struct CawlrScore {
+    pub pos: u64,
+    /// True if there was no confident signal to score, score is then 0
+    pub skipped: bool,
+    /// NaN if skipped
+    pub signal_score: f64,
+    pub score: f64,
+}
+
+impl From<&Score> for CawlrScore {
+    fn from(score: &Score) -> Self {
+        CawlrScore {
+            pos: score.pos,
+            skipped: score.skipped,
+            signal_score: score.signal_score.unwrap_or(f64::NAN),
+            score: score.score,
+        }
+    }
+}
+
+unsafe fn signal_from_c(signal: &CawlrSignal) -> Result<Signal> {
+    let kmer = str_arg(signal.kmer, "kmer")?.to_string();
+    if signal.n_samples == 0 {
+        return Err(eyre!("No samples at position {}", signal.pos));
+    }
+    if signal.samples.is_null() {
+        return Err(eyre!("samples at position {} is NULL", signal.pos));
+    }
+    let samples = slice::from_raw_parts(signal.samples, signal.n_samples);
+    let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+    Ok(Signal::new(signal.pos, kmer, mean, 0.0, Vec::new()))
+}
+
+/// Message for the last failed call on this thread, or NULL if none failed.
+/// The message is owned by cawlr and valid until the next failed call on the
+/// same thread.
+#[no_mangle]
+pub extern "C" fn cawlr_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Load the positive and negative control models from cawlr train and the
+/// ranks from cawlr rank. Returns NULL on failure.
+///
+/// # Safety
+///
+/// Each path must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_scorer_new(
+    pos_ctrl: *const c_char,
+    neg_ctrl: *const c_char,
+    ranks: *const c_char,
+) -> *mut CawlrScorer {
+    ffi_call(|| {
+        let pos_ctrl = str_arg(pos_ctrl, "pos_ctrl")?;
+        let neg_ctrl = str_arg(neg_ctrl, "neg_ctrl")?;
+        let ranks = str_arg(ranks, "ranks")?;
+        let scorer = CawlrScorer::try_new(pos_ctrl, neg_ctrl, ranks)?;
+        Ok(Box::into_raw(Box::new(scorer)))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Free a scorer from cawlr_scorer_new, NULL is ignored.
+///
+/// # Safety
+///
+/// scorer must come from cawlr_scorer_new and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_scorer_free(scorer: *mut CawlrScorer) {
+    if !scorer.is_null() {
+        drop(Box::from_raw(scorer));
+    }
+}
+
+/// Positions where either model gives a log probability below -cutoff aren't
+/// scored, defaults to 10
+///
+/// # Safety
+///
+/// scorer must come from cawlr_scorer_new.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_scorer_set_cutoff(scorer: *mut CawlrScorer, cutoff: f64) -> c_int {
+    status(ffi_call(|| {
+        let scorer = scorer.as_mut().ok_or_else(|| eyre!("scorer is NULL"))?;
+        scorer.cutoff(cutoff);
+        Ok(())
+    }))
+}
+
+/// Kmers whose models can't be told apart with a z-test p-value below the
+/// threshold aren't used, defaults to 0.05
+///
+/// # Safety
+///
+/// scorer must come from cawlr_scorer_new.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_scorer_set_p_value_threshold(
+    scorer: *mut CawlrScorer,
+    p_value_threshold: f64,
+) -> c_int {
+    status(ffi_call(|| {
+        let scorer = scorer.as_mut().ok_or_else(|| eyre!("scorer is NULL"))?;
+        scorer.p_value_threshold(p_value_threshold);
+        Ok(())
+    }))
+}
+
+/// Only score kmers starting with these comma separated motifs, ie "2:GC,1:CG",
+/// defaults to every base
+///
+/// # Safety
+///
+/// scorer must come from cawlr_scorer_new and motifs must be a valid
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_scorer_set_motifs(
+    scorer: *mut CawlrScorer,
+    motifs: *const c_char,
+) -> c_int {
+    status(ffi_call(|| {
+        let scorer = scorer.as_mut().ok_or_else(|| eyre!("scorer is NULL"))?;
+        let motifs = str_arg(motifs, "motifs")?
+            .split(',')
+            .map(Motif::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        scorer.motifs(motifs)?;
+        Ok(())
+    }))
+}
+
+/// Score a read from the signal at each of its positions. Scores are written
+/// to scores in the order of the signals, for the positions matching the
+/// motifs, and their number to n_scores. Fails without writing any scores if
+/// a matching kmer is longer than 16 bases or isn't ASCII.
+///
+/// # Safety
+///
+/// scorer must come from cawlr_scorer_new, signals must point to n_signals
+/// valid signals, and scores must have room for n_signals scores.
+#[no_mangle]
+pub unsafe extern "C" fn cawlr_score_read(
+    scorer: *const CawlrScorer,
+    signals: *const CawlrSignal,
+    n_signals: usize,
+    scores: *mut CawlrScore,
+    n_scores: *mut usize,
+) -> c_int {
+    status(ffi_call(|| {
+        let scorer = scorer.as_ref().ok_or_else(|| eyre!("scorer is NULL"))?;
+        if n_scores.is_null() || (n_signals > 0 && (signals.is_null() || scores.is_null())) {
+            return Err(eyre!("signals, scores, and n_scores can't be NULL"));
+        }
+        let signals = if n_signals == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(signals, n_signals)
+                .iter()
+                .map(|s| signal_from_c(s))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let scored = scorer.score_signals(&signals)?;
+        for (i, score) in scored.iter().enumerate() {
+            scores.add(i).write(score.into());
+        }
+        *n_scores = scored.len();
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::train::{ModelDB, ModelParams};
+
+    fn test_scorer() -> CawlrScorer {
+        let kmers = ["GATCGA", "ATCGAT"];
+        let model = |mu_a, mu_b| {
+            kmers
+                .iter()
+                .map(|k| {
+                    let params = ModelParams::new(false, 0.5, mu_a, 1.0, mu_b, 1.0);
+                    (Kmer::from_str(k).unwrap(), params)
+                })
+                .collect::<ModelDB>()
+        };
+        let ranks = kmers
+            .iter()
+            .map(|k| (Kmer::from_str(k).unwrap(), 1.0))
+            .collect::<Ranks>();
+        CawlrScorer::new(
+            Model::new(model(60.0, 90.0)),
+            Model::new(model(60.0, 61.0)),
+            ranks,
+        )
+    }
+
+    #[test]
+    fn test_score_read() {
+        let scorer = test_scorer();
+        let kmers = [
+            CString::new("GATCGA").unwrap(),
+            CString::new("ATCGAT").unwrap(),
+        ];
+        let samples = [89.0, 91.0];
+        let signals = kmers
+            .iter()
+            .enumerate()
+            .map(|(i, kmer)| CawlrSignal {
+                pos: 100 + i as u64,
+                kmer: kmer.as_ptr(),
+                samples: samples.as_ptr(),
+                n_samples: samples.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut scores = (0..signals.len())
+            .map(|_| CawlrScore {
+                pos: 0,
+                skipped: true,
+                signal_score: f64::NAN,
+                score: 0.0,
+            })
+            .collect::<Vec<_>>();
+        let mut n_scores = 0;
+        let res = unsafe {
+            cawlr_score_read(
+                &scorer,
+                signals.as_ptr(),
+                signals.len(),
+                scores.as_mut_ptr(),
+                &mut n_scores,
+            )
+        };
+        assert_eq!(res, 0);
+        assert_eq!(n_scores, 2);
+        assert_eq!(scores[0].pos, 100);
+        assert!(!scores[0].skipped);
+        assert!(scores[0].score > 0.9, "{}", scores[0].score);
+
+        let mut scorer = scorer;
+        let motifs = CString::new("1:A").unwrap();
+        assert_eq!(
+            unsafe { cawlr_scorer_set_motifs(&mut scorer, motifs.as_ptr()) },
+            0
+        );
+        let res = unsafe {
+            cawlr_score_read(
+                &scorer,
+                signals.as_ptr(),
+                signals.len(),
+                scores.as_mut_ptr(),
+                &mut n_scores,
+            )
+        };
+        assert_eq!(res, 0);
+        assert_eq!(n_scores, 1);
+        assert_eq!(scores[0].pos, 101);
+    }
+
+    #[test]
+    fn test_ffi_errors() {
+        let missing = CString::new("missing.pickle").unwrap();
+        let scorer =
+            unsafe { cawlr_scorer_new(missing.as_ptr(), missing.as_ptr(), missing.as_ptr()) };
+        assert!(scorer.is_null());
+        let msg = unsafe { CStr::from_ptr(cawlr_last_error()) };
+        assert!(msg.to_str().unwrap().contains("missing.pickle"));
+
+        let mut scorer = test_scorer();
+        let motifs = CString::new("1:A,1:ATCGATCG").unwrap();
+        assert_eq!(
+            unsafe { cawlr_scorer_set_motifs(&mut scorer, motifs.as_ptr()) },
+            -1
+        );
+        let res =
+            unsafe { cawlr_score_read(&scorer, ptr::null(), 1, ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(res, -1);
+
+        let kmer = CString::new("A".repeat(17)).unwrap();
+        let samples = [90.0];
+        let signal = CawlrSignal {
+            pos: 100,
+            kmer: kmer.as_ptr(),
+            samples: samples.as_ptr(),
+            n_samples: samples.len(),
+        };
+        let mut score = CawlrScore {
+            pos: 0,
+            skipped: true,
+            signal_score: f64::NAN,
+            score: 0.0,
+        };
+        let mut n_scores = 0;
+        let res = unsafe { cawlr_score_read(&scorer, &signal, 1, &mut score, &mut n_scores) };
+        assert_eq!(res, -1);
+        assert_eq!(n_scores, 0);
+        let msg = unsafe { CStr::from_ptr(cawlr_last_error()) };
+        assert!(msg
+            .to_str()
+            .unwrap()
+            .contains("Invalid kmer at position 100"));
+    }
+}
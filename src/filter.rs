@@ -1,4 +1,27 @@
-use crate::{arrow::metadata::MetadataExt, region::Region};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use bam::{BamReader, BamWriter, RecordWriter};
+use eyre::{Result, WrapErr};
+use fnv::FnvHashSet;
+
+use crate::{
+    arrow::{
+        arrow_utils::{
+            dispatch_arrow_kind, load_apply, save, wrap_writer, ArrowKind, ArrowKindFn, SchemaExt,
+        },
+        metadata::{MetadataExt, MetadataMutExt},
+        mod_bam::mod_probs,
+    },
+    hash::Encode,
+    region::{load_regions, FilterError, Region},
+    split::input_schema,
+    utils::{create_output, open_maybe_gz},
+};
 
 pub struct FilterOptions {
     regions: Vec<Region>,
@@ -13,3 +36,392 @@ impl FilterOptions {
         self.regions.iter().any(|r| r.valid(meta))
     }
 }
+
+/// Keep reads by where they align, ie to train without rDNA, mitochondria, or
+/// low-complexity regions that skew kmer distributions
+#[derive(Debug, Clone, Default)]
+pub struct RegionFilter {
+    include: Vec<Region>,
+    exclude: Vec<Region>,
+}
+
+impl RegionFilter {
+    pub fn new(include: Vec<Region>, exclude: Vec<Region>) -> Self {
+        Self { include, exclude }
+    }
+
+    /// Each argument is a BED file or a region string, see [load_regions]
+    pub fn load<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self> {
+        let load = |args: &[S]| -> Result<Vec<Region>> {
+            let mut regions = Vec::new();
+            for arg in args {
+                regions.extend(load_regions(arg.as_ref())?);
+            }
+            Ok(regions)
+        };
+        Ok(Self::new(load(include)?, load(exclude)?))
+    }
+
+    /// Check every region is on a chromosome the genome has, so a region on a
+    /// misspelled chromosome doesn't silently match no reads
+    pub fn check_chroms<F: Fn(&str) -> bool>(&self, has_chrom: F) -> Result<(), FilterError> {
+        match self
+            .include
+            .iter()
+            .chain(self.exclude.iter())
+            .find(|region| !has_chrom(region.chrom()))
+        {
+            Some(region) => Err(FilterError::UnknownChrom(region.chrom().to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// No regions to include or exclude, so every read is kept
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the read overlaps an included region, or there are none, and
+    /// doesn't overlap any excluded region
+    pub fn keep<M: MetadataExt + ?Sized>(&self, meta: &M) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|r| r.valid(meta)))
+            && !self.exclude.iter().any(|r| r.valid(meta))
+    }
+}
+
+/// Keep or remove reads in Arrow files from cawlr collapse, score, or sma by
+/// name, ie chimeric or contaminant reads found by other QC tools, and by
+/// mapping quality. By default every read is kept.
+#[derive(Debug, Clone, Default)]
+pub struct ReadFilter {
+    names: Option<FnvHashSet<String>>,
+    remove_names: bool,
+    min_mapq: Option<u8>,
+}
+
+impl ReadFilter {
+    /// Only keep reads with these names, or remove them instead with
+    /// [ReadFilter::remove_names]
+    pub fn names<I: IntoIterator<Item = String>>(&mut self, names: I) -> &mut Self {
+        self.names = Some(names.into_iter().collect());
+        self
+    }
+
+    /// Load read names from a text file, optionally gzip compressed, with a
+    /// name at the start of each line. Anything after the first whitespace is
+    /// ignored, so ie a TSV with names in the first column works, as do blank
+    /// lines and lines starting with '#'.
+    pub fn load_names<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let path = path.as_ref();
+        let mut names = FnvHashSet::default();
+        for line in open_maybe_gz(path)?.lines() {
+            let line = line.wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.split_whitespace().next() {
+                names.insert(name.to_string());
+            }
+        }
+        log::info!("Loaded {} read names from {}", names.len(), path.display());
+        Ok(self.names(names))
+    }
+
+    /// Remove reads with the names instead of keeping them, ie for a list of
+    /// contaminant reads
+    pub fn remove_names(&mut self, remove_names: bool) -> &mut Self {
+        self.remove_names = remove_names;
+        self
+    }
+
+    /// Remove reads with a lower mapping quality. Reads without one, ie from
+    /// Arrow files written before it was recorded, are removed too.
+    pub fn min_mapq(&mut self, min_mapq: u8) -> &mut Self {
+        self.min_mapq = Some(min_mapq);
+        self
+    }
+
+    pub fn keep<M: MetadataExt + ?Sized>(&self, meta: &M) -> bool {
+        let named = self.names.as_ref().map_or(true, |names| {
+            names.contains(meta.name()) != self.remove_names
+        });
+        let mapped = self.min_mapq.map_or(true, |min_mapq| {
+            meta.mapq().map_or(false, |q| q >= min_mapq)
+        });
+        named && mapped
+    }
+
+    /// Stream the reads of the input to the output, keeping the schema
+    /// metadata of the input. Returns the number of reads kept and removed.
+    pub fn run<P, Q>(&self, input: P, output: Q) -> Result<(usize, usize)>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let input = input.as_ref();
+        let filter = FilterReads {
+            filter: self,
+            input,
+            output: output.as_ref(),
+        };
+        dispatch_arrow_kind(ArrowKind::of(input)?, filter)
+    }
+
+    fn filter<T>(&self, input: &Path, output: &Path) -> Result<(usize, usize)>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let schema = input_schema::<T>(input)?;
+        let mut writer = wrap_writer(create_output(output)?, &schema)?;
+        let (mut kept, mut removed) = (0, 0);
+        let res = load_apply(BufReader::new(File::open(input)?), |reads: Vec<T>| {
+            let n_reads = reads.len();
+            let reads = reads
+                .into_iter()
+                .filter(|read| self.keep(read))
+                .collect::<Vec<_>>();
+            kept += reads.len();
+            removed += n_reads - reads.len();
+            save(&mut writer, &reads)
+        });
+        // A finished output would look complete to later steps, so it is
+        // removed instead
+        if let Err(e) = res {
+            drop(writer);
+            let _ = std::fs::remove_file(output);
+            return Err(e.into());
+        }
+        writer.finish()?;
+        Ok((kept, removed))
+    }
+}
+
+/// [ReadFilter::run] with the type of reads of the input
+struct FilterReads<'a> {
+    filter: &'a ReadFilter,
+    input: &'a Path,
+    output: &'a Path,
+}
+
+impl ArrowKindFn for FilterReads<'_> {
+    type Output = (usize, usize);
+
+    fn call<T>(self, _kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        self.filter.filter::<T>(self.input, self.output)
+    }
+}
+
+/// Remove reads from a modification bam file where the fraction of modified
+/// bases is above a threshold, ie degenerate reads where nearly every base is
+/// called as modified.
+pub struct OvermodOptions {
+    mod_tags: Vec<Vec<u8>>,
+    threshold: f64,
+    min_bases: usize,
+    mod_prob: f64,
+}
+
+impl OvermodOptions {
+    /// Reads are checked against each modification tag, ie "A+a" or "C+m", and
+    /// removed if they are over-modified for any of them.
+    pub fn new<B: Into<Vec<u8>>>(mod_tags: Vec<B>) -> Self {
+        Self {
+            mod_tags: mod_tags.into_iter().map(|t| t.into()).collect(),
+            threshold: 0.9,
+            min_bases: 10,
+            mod_prob: 0.5,
+        }
+    }
+
+    /// Reads with a fraction of modified bases greater than this are removed,
+    /// defaults to 0.9
+    pub fn threshold(&mut self, threshold: f64) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Reads with fewer bases called for a modification are always kept,
+    /// defaults to 10
+    pub fn min_bases(&mut self, min_bases: usize) -> &mut Self {
+        self.min_bases = min_bases;
+        self
+    }
+
+    /// Bases with a modification probability greater than this are counted as
+    /// modified, defaults to 0.5
+    pub fn mod_prob(&mut self, mod_prob: f64) -> &mut Self {
+        self.mod_prob = mod_prob;
+        self
+    }
+
+    fn is_overmodified(&self, probs: &[f64]) -> bool {
+        if probs.is_empty() || probs.len() < self.min_bases {
+            return false;
+        }
+        let n_modified = probs.iter().filter(|&&p| p > self.mod_prob).count();
+        (n_modified as f64 / probs.len() as f64) > self.threshold
+    }
+
+    /// Returns the number of reads kept and removed. Reads without modification
+    /// tags are kept.
+    pub fn run<P, Q>(&self, input: P, output: Q) -> Result<(usize, usize)>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let reader = BamReader::from_path(input, 2u16)?;
+        let mut writer = BamWriter::from_stream(create_output(output)?, reader.header().clone())?;
+        let mut kept = 0;
+        let mut removed = 0;
+        for record in reader {
+            let record = record?;
+            let overmodified = self.mod_tags.iter().any(|tag| {
+                mod_probs(&record, tag)
+                    .map(|(probs, _)| self.is_overmodified(&probs))
+                    .unwrap_or(false)
+            });
+            if overmodified {
+                log::debug!(
+                    "Removing over-modified read {}",
+                    String::from_utf8_lossy(record.name())
+                );
+                removed += 1;
+            } else {
+                writer.write(&record)?;
+                kept += 1;
+            }
+        }
+        writer.finish()?;
+        Ok((kept, removed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::{
+        arrow::scored_read::ScoredRead,
+        test_data::{write_reads, TestRead},
+    };
+
+    #[test]
+    fn test_region_filter() -> Result<()> {
+        let read = |chrom: &str, start| {
+            TestRead::new("read")
+                .chrom(chrom)
+                .span(start, 100)
+                .metadata()
+        };
+        let filter = RegionFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.keep(&read("chrM", 0)));
+
+        let filter = RegionFilter::load(&["chrII"], &["chrII:1000-2000", "chrM"])?;
+        assert!(filter.keep(&read("chrII", 0)));
+        assert!(!filter.keep(&read("chrII", 950)));
+        assert!(!filter.keep(&read("chrM", 0)));
+        assert!(!filter.keep(&read("chrI", 0)));
+
+        let filter = RegionFilter::load(&[], &["chrM"])?;
+        assert!(filter.keep(&read("chrI", 0)));
+        assert!(!filter.keep(&read("chrM", 500)));
+        assert!(filter.check_chroms(|chrom| chrom == "chrM").is_ok());
+        assert!(matches!(
+            filter.check_chroms(|chrom| chrom == "chrI"),
+            Err(FilterError::UnknownChrom(chrom)) if chrom == "chrM"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_filter() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let reads = (0..4)
+            .map(|i| {
+                TestRead::new(format!("read{i}"))
+                    .span(0, 100)
+                    .mapq((i > 0).then_some(i as u8 * 10))
+                    .scored(Vec::new())
+            })
+            .collect::<Vec<_>>();
+        let input = temp_dir.path().join("scores.arrow");
+        write_reads(&input, &reads)?;
+
+        let names_file = temp_dir.path().join("names.txt");
+        std::fs::write(&names_file, "# chimeric\nread1\tchimera\n\nread3\n")?;
+        let mut filter = ReadFilter::default();
+        filter.load_names(&names_file)?;
+        let output = temp_dir.path().join("kept.arrow");
+        assert_eq!(filter.run(&input, &output)?, (2, 2));
+        let read_names = |path: &Path| -> Result<Vec<String>> {
+            let mut names = Vec::new();
+            load_apply(File::open(path)?, |reads: Vec<ScoredRead>| {
+                names.extend(reads.iter().map(|r| r.name().to_string()));
+                Ok(())
+            })?;
+            Ok(names)
+        };
+        assert_eq!(read_names(&output)?, ["read1", "read3"]);
+
+        filter.remove_names(true);
+        let output = temp_dir.path().join("removed.arrow");
+        assert_eq!(filter.run(&input, &output)?, (2, 2));
+        assert_eq!(read_names(&output)?, ["read0", "read2"]);
+
+        let mut filter = ReadFilter::default();
+        filter.min_mapq(20);
+        let output = temp_dir.path().join("mapq.arrow");
+        assert_eq!(filter.run(&input, &output)?, (2, 2));
+        assert_eq!(read_names(&output)?, ["read2", "read3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_overmodified() {
+        let mut opts = OvermodOptions::new(vec!["A+Y"]);
+        opts.min_bases(4).threshold(0.5);
+        assert!(opts.is_overmodified(&[0.9, 0.9, 0.9, 0.1]));
+        assert!(!opts.is_overmodified(&[0.9, 0.9, 0.1, 0.1]));
+        // Too few bases to tell
+        assert!(!opts.is_overmodified(&[0.9, 0.9, 0.9]));
+        assert!(!opts.is_overmodified(&[]));
+    }
+
+    #[test]
+    fn test_overmod_run() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = "extra/modbams/megalodon-modbam.bam";
+        let n_reads = BamReader::from_path(input, 0)?.count();
+
+        let output = temp_dir.path().join("kept.bam");
+        let mut opts = OvermodOptions::new(vec!["A+Y"]);
+        opts.threshold(1.0);
+        assert_eq!(opts.run(input, &output)?, (n_reads, 0));
+        assert_eq!(BamReader::from_path(&output, 0)?.count(), n_reads);
+
+        let output = temp_dir.path().join("removed.bam");
+        opts.threshold(0.0).min_bases(1).mod_prob(0.0);
+        assert_eq!(opts.run(input, &output)?, (0, n_reads));
+        assert_eq!(BamReader::from_path(&output, 0)?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Splitting outputs by the haplotype of each read, recorded from the HP tag of
+//! a phased BAM file given to cawlr collapse, ie from whatshap haplotag.
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use arrow2::io::ipc::write::FileWriter;
+use eyre::Result;
+
+use crate::{
+    arrow::{
+        arrow_utils::{save, wrap_writer_with, ArrowCompression},
+        metadata::MetadataExt,
+        scored_read::ScoredRead,
+    },
+    utils::create_output,
+};
+
+/// Path of the output for a haplotype, keeping the extension of the output,
+/// ie scores.arrow becomes scores.hp1.arrow and sma.bed.gz becomes
+/// sma.hp1.bed.gz
+pub fn haplotype_path(output: &Path, haplotype: i64) -> PathBuf {
+    let name = output
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (name, gz) = match name.strip_suffix(".gz") {
+        Some(name) => (name, ".gz"),
+        None => (name.as_str(), ""),
+    };
+    let (stem, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name, ""),
+    };
+    output.with_file_name(format!("{stem}.hp{haplotype}{ext}{gz}"))
+}
+
+/// Reads grouped by haplotype, leaving out reads without one
+pub(crate) fn group_by_haplotype<T: MetadataExt + Clone>(reads: &[T]) -> BTreeMap<i64, Vec<T>> {
+    let mut by_haplotype: BTreeMap<i64, Vec<T>> = BTreeMap::new();
+    for read in reads {
+        if let Some(hp) = read.haplotype() {
+            by_haplotype.entry(hp).or_default().push(read.clone());
+        }
+    }
+    by_haplotype
+}
+
+/// Output of each haplotype next to the main output, see [haplotype_path].
+/// Outputs are created the first time a read from that haplotype is seen.
+pub(crate) struct HaplotypeOutputs<W> {
+    output: PathBuf,
+    outputs: BTreeMap<i64, W>,
+}
+
+impl<W> HaplotypeOutputs<W> {
+    pub(crate) fn new<P: AsRef<Path>>(output: P) -> Self {
+        HaplotypeOutputs {
+            output: output.as_ref().to_path_buf(),
+            outputs: BTreeMap::new(),
+        }
+    }
+
+    /// Output of a haplotype, created from its path the first time
+    pub(crate) fn get_or_create<F>(&mut self, hp: i64, create: F) -> Result<&mut W>
+    where
+        F: FnOnce(&Path) -> Result<W>,
+    {
+        match self.outputs.entry(hp) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let output = create(&haplotype_path(&self.output, hp))?;
+                Ok(entry.insert(output))
+            }
+        }
+    }
+
+    /// Finish the output of each haplotype, warning if no read had one
+    pub(crate) fn finish<F>(self, mut finish: F) -> Result<()>
+    where
+        F: FnMut(W) -> Result<()>,
+    {
+        if self.outputs.is_empty() {
+            log::warn!("No reads with a haplotype, run cawlr collapse with a phased bam file");
+        }
+        for (hp, output) in self.outputs {
+            finish(output)?;
+            log::info!(
+                "Haplotype {hp} written to {}",
+                haplotype_path(&self.output, hp).display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writes each scored read to an Arrow file for its haplotype
+pub(crate) struct HaplotypeWriters {
+    writers: HaplotypeOutputs<FileWriter<File>>,
+    compression: ArrowCompression,
+}
+
+impl HaplotypeWriters {
+    pub(crate) fn new<P: AsRef<Path>>(output: P, compression: ArrowCompression) -> Self {
+        HaplotypeWriters {
+            writers: HaplotypeOutputs::new(output),
+            compression,
+        }
+    }
+
+    pub(crate) fn save(&mut self, reads: &[ScoredRead]) -> Result<()> {
+        let compression = self.compression;
+        for (hp, reads) in group_by_haplotype(reads) {
+            let writer = self.writers.get_or_create(hp, |path| {
+                wrap_writer_with(create_output(path)?, &ScoredRead::schema(), compression)
+            })?;
+            save(writer, &reads)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        self.writers.finish(|mut writer| Ok(writer.finish()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::{arrow::arrow_utils::load_apply, test_data::TestRead};
+
+    fn read(name: &str, haplotype: Option<i64>) -> ScoredRead {
+        TestRead::new(name).haplotype(haplotype).scored(Vec::new())
+    }
+
+    #[test]
+    fn test_haplotype_path() {
+        assert_eq!(
+            haplotype_path(Path::new("out/scores.arrow"), 2),
+            PathBuf::from("out/scores.hp2.arrow")
+        );
+        assert_eq!(
+            haplotype_path(Path::new("sma.bed.gz"), 1),
+            PathBuf::from("sma.hp1.bed.gz")
+        );
+        assert_eq!(
+            haplotype_path(Path::new("counts"), 1),
+            PathBuf::from("counts.hp1")
+        );
+    }
+
+    #[test]
+    fn test_haplotype_writers() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output = temp_dir.path().join("scores.arrow");
+        let mut writers = HaplotypeWriters::new(&output, ArrowCompression::default());
+        writers.save(&[
+            read("a", Some(1)),
+            read("b", Some(2)),
+            read("untagged", None),
+        ])?;
+        writers.save(&[read("c", Some(1))])?;
+        writers.finish()?;
+        assert!(haplotype_path(&output, 2).exists());
+        assert!(!haplotype_path(&output, 3).exists());
+
+        let mut names = Vec::new();
+        load_apply(
+            File::open(haplotype_path(&output, 1))?,
+            |reads: Vec<ScoredRead>| {
+                names.extend(reads.iter().map(|r| r.name().to_string()));
+                Ok(())
+            },
+        )?;
+        assert_eq!(names, ["a", "c"]);
+        Ok(())
+    }
+}
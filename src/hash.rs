@@ -0,0 +1,350 @@
+//! Content hashes of Arrow files from cawlr, for checking a run reproduces
+//! golden outputs, ie in regression tests or pipelines run with
+//! --deterministic.
+//!
+//! Reads are hashed by value after decoding, so the codec, chunk sizes, and
+//! the schema metadata describing how the file was written, like the command
+//! line, don't change the hash. Other schema metadata, like the samples of a
+//! collapse output, is part of the hash.
+//!
+//! Values are hashed in a fixed encoding, see [Encode], so hashes only change
+//! when the hashed fields do.
+use std::{fmt::Display, fs::File, hash::Hasher, io::BufReader, path::Path, str::FromStr};
+
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::{Result, WrapErr};
+use fnv::FnvHasher;
+
+use crate::arrow::{
+    arrow_utils::{
+        dispatch_arrow_kind, file_metadata, load_apply, ArrowKind, ArrowKindFn, SchemaExt,
+        CAWLR_VERSION_KEY, COMMAND_KEY, COMPRESSION_KEY,
+    },
+    eventalign::Eventalign,
+    metadata::{Metadata, MetadataExt, MetadataMutExt},
+    scored_read::{Score, ScoredRead},
+    signal::Signal,
+    sma_read::{SmaBlock, SmaRead},
+};
+
+/// Schema metadata keys left out of the hash, since they differ between runs
+/// with the same results
+pub const VOLATILE_KEYS: [&str; 3] = [CAWLR_VERSION_KEY, COMMAND_KEY, COMPRESSION_KEY];
+
+/// Hash of the records and schema metadata of an Arrow file, see
+/// [content_hash]. Hashes are equal if their hash is, the number of reads is
+/// only for reporting.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentHash {
+    pub hash: u64,
+    pub n_reads: u64,
+}
+
+impl PartialEq for ContentHash {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Eq for ContentHash {}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.hash)
+    }
+}
+
+/// Parse the hash as printed, the number of reads isn't part of it
+impl FromStr for ContentHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u64::from_str_radix(s, 16) {
+            Ok(hash) if s.len() == 16 => Ok(ContentHash { hash, n_reads: 0 }),
+            _ => Err(format!("Invalid hash \"{s}\", expected 16 hex digits")),
+        }
+    }
+}
+
+/// Hash an Arrow file from cawlr collapse, score, or sma. FNV-1a is used since
+/// its output is the same on every platform and version of Rust, unlike
+/// [std::collections::hash_map::DefaultHasher].
+pub fn content_hash<P: AsRef<Path>>(path: P) -> Result<ContentHash> {
+    let path = path.as_ref();
+    let kind = ArrowKind::of(path)?;
+    let mut file =
+        File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let metadata = file_metadata(&mut file)?;
+
+    let mut hasher = FnvHasher::default();
+    let mut buf = Vec::new();
+    kind.to_string().encode(&mut buf);
+    // Metadata is a BTreeMap, so keys are always hashed in the same order
+    for (key, value) in metadata.iter() {
+        if !VOLATILE_KEYS.contains(&key.as_str()) {
+            key.encode(&mut buf);
+            value.encode(&mut buf);
+        }
+    }
+    hasher.write(&buf);
+    let reads = HashReads {
+        reader: BufReader::new(file),
+        hasher: &mut hasher,
+    };
+    let n_reads = dispatch_arrow_kind(kind, reads)?;
+    Ok(ContentHash {
+        hash: hasher.finish(),
+        n_reads,
+    })
+}
+
+/// Hash each read by the encoding of its fields, returning the number of
+/// reads
+struct HashReads<'a> {
+    reader: BufReader<File>,
+    hasher: &'a mut FnvHasher,
+}
+
+impl ArrowKindFn for HashReads<'_> {
+    type Output = u64;
+
+    fn call<T>(self, _kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let mut n_reads = 0;
+        let mut buf = Vec::new();
+        load_apply(self.reader, |reads: Vec<T>| {
+            for read in reads.iter() {
+                buf.clear();
+                read.encode(&mut buf);
+                self.hasher.write(&buf);
+                n_reads += 1;
+            }
+            Ok(())
+        })?;
+        Ok(n_reads)
+    }
+}
+
+/// Encoding of the values that are hashed. Numbers are little-endian, floats
+/// by their bits, strings and lists are prefixed by their length, and options
+/// by whether they are set, so ie ("ab", "c") and ("a", "bc") hash
+/// differently. Fields added to the Arrow types only change hashes once they
+/// are encoded here.
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+impl Encode for u64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for i64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u16 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(*self));
+    }
+}
+
+impl Encode for f64 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.to_bits().encode(buf);
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_str().encode(buf);
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                true.encode(buf);
+                value.encode(buf);
+            }
+            None => false.encode(buf),
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        for value in self.iter() {
+            value.encode(buf);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf);
+    }
+}
+
+impl Encode for Metadata {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.name.encode(buf);
+        self.chrom.encode(buf);
+        self.start.encode(buf);
+        self.length.encode(buf);
+        self.strand.as_str().encode(buf);
+        self.seq.encode(buf);
+        self.mapq.encode(buf);
+        self.flags.encode(buf);
+        self.identity.encode(buf);
+        self.sample.encode(buf);
+        self.haplotype.encode(buf);
+        self.phase_set.encode(buf);
+    }
+}
+
+impl Encode for Signal {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.pos.encode(buf);
+        self.kmer.encode(buf);
+        self.signal_mean.encode(buf);
+        self.signal_time.encode(buf);
+        self.samples.encode(buf);
+    }
+}
+
+impl Encode for Eventalign {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.metadata.encode(buf);
+        self.signals().encode(buf);
+    }
+}
+
+impl Encode for Score {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.pos.encode(buf);
+        self.kmer.as_str().encode(buf);
+        self.skipped.encode(buf);
+        self.signal_score.encode(buf);
+        self.score.encode(buf);
+        self.n_obs.encode(buf);
+        self.sub_scores.encode(buf);
+    }
+}
+
+impl Encode for ScoredRead {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.metadata.encode(buf);
+        self.scores.encode(buf);
+    }
+}
+
+impl Encode for SmaBlock {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.start.encode(buf);
+        self.length.encode(buf);
+        self.state.is_nucleosome().encode(buf);
+    }
+}
+
+impl Encode for SmaRead {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.metadata.encode(buf);
+        self.blocks.encode(buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::{
+        arrow::arrow_utils::{save, wrap_writer_with, ArrowCompression},
+        test_data::{test_score, TestRead},
+    };
+
+    fn write_scores(path: &Path, score: f64, compression: ArrowCompression) -> Result<()> {
+        let reads = vec![TestRead::new("read").scored(vec![test_score(10, score)]); 3];
+        let mut writer = wrap_writer_with(File::create(path)?, &ScoredRead::schema(), compression)?;
+        // One read per chunk, then the rest in one
+        save(&mut writer, &reads[..1])?;
+        save(&mut writer, &reads[1..])?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_hash() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let lz4 = temp_dir.path().join("lz4.arrow");
+        let zstd = temp_dir.path().join("zstd.arrow");
+        let other = temp_dir.path().join("other.arrow");
+        write_scores(&lz4, 0.5, ArrowCompression::Lz4)?;
+        write_scores(&zstd, 0.5, ArrowCompression::Zstd)?;
+        write_scores(&other, 0.5 + 1e-12, ArrowCompression::Lz4)?;
+
+        let hash = content_hash(&lz4)?;
+        assert_eq!(hash.n_reads, 3);
+        assert_eq!(hash, content_hash(&zstd)?);
+        assert_ne!(hash, content_hash(&other)?);
+        assert_eq!(hash.to_string().parse::<ContentHash>().unwrap(), hash);
+        assert!("abc".parse::<ContentHash>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        "ab".encode(&mut buf);
+        Some(1u16).encode(&mut buf);
+        None::<u16>.encode(&mut buf);
+        assert_eq!(buf, [2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', 1, 1, 0, 0]);
+
+        let mut split = Vec::new();
+        vec!["a".to_string(), "bc".to_string()].encode(&mut split);
+        let mut other = Vec::new();
+        vec!["ab".to_string(), "c".to_string()].encode(&mut other);
+        assert_ne!(split, other);
+    }
+}
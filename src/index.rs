@@ -1,12 +1,27 @@
 use std::{
     fs::File,
-    io::{BufWriter, Write},
-    path::Path,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 use eyre::Result;
+use fnv::FnvHashMap;
+use noodles::{
+    core::Position,
+    csi::binning_index::{index::reference_sequence::bin::Chunk, Indexer},
+    sam::alignment::Record as _,
+};
 
-use crate::arrow::{arrow_utils::load_apply, eventalign::Eventalign, metadata::MetadataExt};
+use crate::{
+    arrow::{
+        arrow_utils::load_apply,
+        eventalign::Eventalign,
+        io::is_bam_file,
+        metadata::{MetadataExt, Strand},
+    },
+    input::open_input,
+    utils::create_output,
+};
 
 fn to_bed_line<M: MetadataExt>(metadata: M, chunk_idx: usize, rec_idx: usize) -> String {
     let chrom = metadata.chrom();
@@ -20,18 +35,20 @@ fn to_bed_line<M: MetadataExt>(metadata: M, chunk_idx: usize, rec_idx: usize) ->
     )
 }
 
+/// Path to the index of an Arrow file, ie sample.arrow.idx.bed
+pub fn index_path<P: AsRef<Path>>(filepath: P) -> PathBuf {
+    let mut idx_filepath = filepath.as_ref().as_os_str().to_owned();
+    idx_filepath.push(".idx.bed");
+    PathBuf::from(idx_filepath)
+}
+
 pub fn index<P>(filepath: P) -> Result<()>
 where
     P: AsRef<Path>,
 {
     let file = File::open(&filepath)?;
-    let output_filepath = filepath
-        .as_ref()
-        .to_str()
-        .ok_or_else(|| eyre::eyre!("Invalid unicode in path"))?;
-    let idx_filepath = format!("{}.idx.bed", output_filepath);
-    let idx_filepath = Path::new(&idx_filepath);
-    let writer = File::create(idx_filepath)?;
+    let idx_filepath = index_path(&filepath);
+    let writer = create_output(idx_filepath)?;
     let mut writer = BufWriter::new(writer);
 
     let mut chunk_idx = 0usize;
@@ -47,9 +64,222 @@ where
     Ok(())
 }
 
+/// Same as [index], or [index_mod_bam] if the input is a BAM file
+pub fn index_input<P>(filepath: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let is_bam = match filepath.as_ref().extension() {
+        Some(ext) => ext == "bam",
+        None => is_bam_file(&filepath),
+    };
+    if is_bam {
+        index_mod_bam(filepath)
+    } else {
+        index(filepath)
+    }
+}
+
+/// Existing BAI index of a BAM file, ie sample.bam.bai or sample.bai
+pub fn bam_index_path<P: AsRef<Path>>(bam_path: P) -> Option<PathBuf> {
+    let bam_path = bam_path.as_ref();
+    [bai_path(bam_path), bam_path.with_extension("bai")]
+        .into_iter()
+        .find(|path| path.exists())
+}
+
+fn bai_path(bam_path: &Path) -> PathBuf {
+    let mut path = bam_path.as_os_str().to_owned();
+    path.push(".bai");
+    PathBuf::from(path)
+}
+
+/// Create a bed file of the aligned reads in a BAM file with modification
+/// calls, ie the first six columns of [index] since a BAM has no Arrow chunks.
+/// The reads of each reference are queried through the BAI index, which is
+/// built next to the BAM if it doesn't already have one.
+pub fn index_mod_bam<P>(filepath: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let filepath = filepath.as_ref();
+    let bai = match bam_index_path(filepath) {
+        Some(bai) => {
+            log::info!("Using existing index {}", bai.display());
+            bai
+        }
+        None => build_bam_index(filepath)?,
+    };
+
+    let mut reader = bam::IndexedReader::build()
+        .bai_path(&bai)
+        .from_path(filepath)?;
+    let header = reader.header().clone();
+    let writer = create_output(index_path(filepath))?;
+    let mut writer = BufWriter::new(writer);
+    for ref_id in 0..header.n_references() as u32 {
+        let (Some(chrom), Some(len)) =
+            (header.reference_name(ref_id), header.reference_len(ref_id))
+        else {
+            continue;
+        };
+        for record in reader.fetch(&bam::Region::new(ref_id, 0, len))? {
+            let record = record?;
+            if !record.flag().is_mapped() {
+                continue;
+            }
+            let read_name = String::from_utf8_lossy(record.name());
+            let start = record.start();
+            let stop = record.calculate_end();
+            let strand = if record.flag().is_reverse_strand() {
+                Strand::minus()
+            } else {
+                Strand::plus()
+            };
+            let strand = strand.as_str();
+            writeln!(writer, "{chrom}\t{start}\t{stop}\t{read_name}\t0\t{strand}")?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Build the BAI index of a position sorted BAM file at sample.bam.bai, same as
+/// samtools index
+fn build_bam_index(bam_path: &Path) -> Result<PathBuf> {
+    log::info!("No index found for {}, building one", bam_path.display());
+    let index = bam_index(bam_path).map_err(|e| {
+        eyre::eyre!(
+            "Failed to index {}, is it sorted by position? {e}",
+            bam_path.display()
+        )
+    })?;
+    let bai = bai_path(bam_path);
+    write_bai(&index, &bai)?;
+    Ok(bai)
+}
+
+fn bam_index(bam_path: &Path) -> io::Result<noodles::bam::bai::Index> {
+    let mut reader = noodles::bam::io::reader::Builder.build_from_path(bam_path)?;
+    let header = reader.read_header()?;
+    let mut record = noodles::bam::Record::default();
+    let mut indexer = Indexer::default();
+    let mut start = reader.get_ref().virtual_position();
+    while reader.read_record(&mut record)? != 0 {
+        let end = reader.get_ref().virtual_position();
+        let context: (Option<usize>, Option<Position>, Option<Position>) = (
+            record.reference_sequence_id().transpose()?,
+            record.alignment_start().transpose()?,
+            record.alignment_end().transpose()?,
+        );
+        let context = match context {
+            (Some(ref_id), Some(start), Some(end)) => {
+                Some((ref_id, start, end, !record.flags().is_unmapped()))
+            }
+            _ => None,
+        };
+        indexer.add_record(context, Chunk::new(start, end))?;
+        start = end;
+    }
+    Ok(indexer.build(header.reference_sequences().len()))
+}
+
+/// Write the BAI through a temporary file so a failed write doesn't leave a
+/// truncated index next to the BAM
+fn write_bai(index: &noodles::bam::bai::Index, bai: &Path) -> io::Result<()> {
+    let dir = match bai.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut buf = BufWriter::new(tmp.as_file_mut());
+        noodles::bam::bai::Writer::new(&mut buf).write_index(index)?;
+        buf.flush()?;
+    }
+    tmp.persist(bai).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Collects the chunks each chromosome has reads in, in order of first
+/// appearance
+#[derive(Default)]
+struct ChromBlocks {
+    order: Vec<(String, Vec<usize>)>,
+    positions: FnvHashMap<String, usize>,
+}
+
+impl ChromBlocks {
+    fn add(&mut self, chrom: &str, block: usize) {
+        let idx = match self.positions.get(chrom) {
+            Some(&idx) => idx,
+            None => {
+                self.positions.insert(chrom.to_string(), self.order.len());
+                self.order.push((chrom.to_string(), Vec::new()));
+                self.order.len() - 1
+            }
+        };
+        let blocks = &mut self.order[idx].1;
+        if blocks.last() != Some(&block) {
+            blocks.push(block);
+        }
+    }
+}
+
+/// Chromosomes of the reads in an Arrow file from cawlr collapse, each with
+/// the indices of the chunks that have reads on it. Uses the index from
+/// [index] if there is one, otherwise reads through the file. Indexes of BAM
+/// files from [index_mod_bam] have no chunks and are an error.
+pub fn chrom_blocks<P: AsRef<Path>>(filepath: P) -> Result<Vec<(String, Vec<usize>)>> {
+    let mut chrom_blocks = ChromBlocks::default();
+    let idx_filepath = index_path(&filepath);
+    if idx_filepath.exists() {
+        let reader = BufReader::new(File::open(&idx_filepath)?);
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() == 6 {
+                return Err(eyre::eyre!(
+                    "{} is the index of a BAM file, which has no Arrow chunks",
+                    idx_filepath.display()
+                ));
+            }
+            let block = fields.get(6).and_then(|b| b.parse::<usize>().ok());
+            match (fields.first(), block) {
+                (Some(chrom), Some(block)) => chrom_blocks.add(chrom, block),
+                _ => {
+                    return Err(eyre::eyre!(
+                        "Invalid line {} in {}: {line}",
+                        line_idx + 1,
+                        idx_filepath.display()
+                    ))
+                }
+            }
+        }
+    } else {
+        log::info!(
+            "No index found for {}, reading it to find chromosomes. Create one with cawlr index to \
+             skip this.",
+            filepath.as_ref().display()
+        );
+        let mut block = 0;
+        load_apply(open_input(&filepath)?, |chunk: Vec<Eventalign>| {
+            for read in chunk.iter() {
+                chrom_blocks.add(read.chrom(), block);
+            }
+            block += 1;
+            Ok(())
+        })?;
+    }
+    Ok(chrom_blocks.order)
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::{fs::File, path::PathBuf};
+
+    use super::*;
+    use crate::{collapse::CollapseOptions, test_data::MiniGenome};
 
     #[test]
     fn test_new_file_extension() {
@@ -58,4 +288,60 @@ mod test {
 
         assert_eq!(x, String::from("test.output.extra.stuff"));
     }
+
+    #[test]
+    fn test_chrom_blocks() -> eyre::Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let scanned = chrom_blocks(&collapsed)?;
+        let chroms = scanned.iter().map(|(c, _)| c.as_str()).collect::<Vec<_>>();
+        assert_eq!(chroms, ["chrI", "chrII"]);
+        assert!(scanned.iter().all(|(_, blocks)| blocks == &[0]));
+
+        index(&collapsed)?;
+        assert!(index_path(&collapsed).exists());
+        assert_eq!(chrom_blocks(&collapsed)?, scanned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_mod_bam() -> eyre::Result<()> {
+        let mini = MiniGenome::new()?;
+        assert_eq!(bam_index_path(mini.bam()), None);
+
+        index_input(mini.bam())?;
+        let bai = mini.dir().join("reads.bam.bai");
+        assert_eq!(bam_index_path(mini.bam()), Some(bai.clone()));
+        let idx = std::fs::read_to_string(index_path(mini.bam()))?;
+        let lines = idx
+            .lines()
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() == 6));
+        let fields = |line: &[&str]| (line[0], line[1], line[3], line[5]);
+        assert_eq!(fields(&lines[0]), ("chrI", "100", "plus-read", "+"));
+        assert_eq!(fields(&lines[1]), ("chrII", "60", "minus-read", "-"));
+
+        // The built index answers region queries
+        let mut reader = bam::IndexedReader::from_path(mini.bam())?;
+        let len = reader.header().reference_len(1).unwrap();
+        let names = reader
+            .fetch(&bam::Region::new(1, 0, len))?
+            .map(|record| record.map(|r| r.name().to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(names, [b"minus-read".to_vec()]);
+
+        // Existing index is used instead of building another
+        let modified = std::fs::metadata(&bai)?.modified()?;
+        index_input(mini.bam())?;
+        assert_eq!(std::fs::metadata(&bai)?.modified()?, modified);
+
+        let err = chrom_blocks(mini.bam()).unwrap_err();
+        assert!(err.to_string().contains("index of a BAM file"), "{err}");
+        Ok(())
+    }
 }
@@ -0,0 +1,205 @@
+//! Summary of an Arrow file written by cawlr, ie what kind of records it has,
+//! how many reads, and how it was created.
+//!
+//! Only the metadata of each read is deserialized, so summarizing large
+//! collapse outputs doesn't load the signal data.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufReader, Write},
+    path::Path,
+};
+
+use arrow2::array::{Array, StructArray};
+use arrow2_convert::deserialize::TryIntoCollection;
+use eyre::{Result, WrapErr};
+
+use crate::arrow::{
+    arrow_utils::{
+        arrow_type, file_metadata, load, migrate, ArrowError, CAWLR_VERSION_KEY, COMMAND_KEY,
+        COMPRESSION_KEY, MODE_KEY, SAMPLES_KEY, SCHEMA_VERSION_KEY,
+    },
+    metadata::{Metadata, MetadataExt},
+};
+
+/// Number of reads on a chromosome and the region they cover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChromSpan {
+    pub n_reads: usize,
+    /// Zero-based start of the leftmost read
+    pub start: u64,
+    /// End of the rightmost read, exclusive
+    pub end: u64,
+}
+
+/// Summary of an Arrow file from cawlr collapse, score, or sma
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrowInfo {
+    /// Type of the records, ie "eventalign" or "scored"
+    pub record_type: String,
+    pub n_blocks: usize,
+    pub n_reads: usize,
+    pub chroms: BTreeMap<String, ChromSpan>,
+    /// Samples of reads tagged by cawlr merge
+    pub samples: BTreeSet<String>,
+    /// Schema metadata recorded when the file was written
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ArrowInfo {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+        );
+        let record_type = arrow_type(&mut file)
+            .wrap_err_with(|| format!("{} is not an Arrow file", path.display()))?;
+        let metadata = file_metadata(&mut file)?;
+
+        let mut info = ArrowInfo {
+            record_type,
+            n_blocks: 0,
+            n_reads: 0,
+            chroms: BTreeMap::new(),
+            samples: BTreeSet::new(),
+            metadata,
+        };
+        for chunk in load(file)? {
+            let chunk = chunk.map_err(ArrowError::UnreadableChunk)?;
+            info.n_blocks += 1;
+            for arr in chunk.into_arrays() {
+                let reads: Vec<Metadata> = metadata_array(arr.as_ref())?.try_into_collection()?;
+                info.add(&reads);
+            }
+        }
+        Ok(info)
+    }
+
+    fn add(&mut self, reads: &[Metadata]) {
+        self.n_reads += reads.len();
+        for read in reads {
+            let span = self
+                .chroms
+                .entry(read.chrom().to_string())
+                .or_insert(ChromSpan {
+                    n_reads: 0,
+                    start: u64::MAX,
+                    end: 0,
+                });
+            span.n_reads += 1;
+            span.start = span.start.min(read.start_0b());
+            span.end = span.end.max(read.seq_stop_1b_excl());
+            if let Some(sample) = read.sample() {
+                if !self.samples.contains(sample) {
+                    self.samples.insert(sample.to_string());
+                }
+            }
+        }
+    }
+
+    /// Version of the cawlr types the file was written with, 0 if it was
+    /// written before versions were recorded
+    pub fn schema_version(&self) -> u32 {
+        self.metadata
+            .get(SCHEMA_VERSION_KEY)
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Codec of the file, if it was recorded
+    pub fn compression(&self) -> Option<&str> {
+        self.metadata.get(COMPRESSION_KEY).map(String::as_str)
+    }
+
+    /// Write a human readable summary, with a tab-separated table of the span
+    /// of each chromosome
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let unknown = "unknown".to_string();
+        let recorded = |key: &str| self.metadata.get(key).unwrap_or(&unknown);
+        writeln!(writer, "type\t{}", self.record_type)?;
+        writeln!(writer, "blocks\t{}", self.n_blocks)?;
+        writeln!(writer, "reads\t{}", self.n_reads)?;
+        writeln!(writer, "schema_version\t{}", self.schema_version())?;
+        writeln!(writer, "compression\t{}", recorded(COMPRESSION_KEY))?;
+        writeln!(writer, "cawlr_version\t{}", recorded(CAWLR_VERSION_KEY))?;
+        writeln!(writer, "command\t{}", recorded(COMMAND_KEY))?;
+        if self.record_type == "eventalign" {
+            let samples = self
+                .metadata
+                .get(SAMPLES_KEY)
+                .map_or("true", String::as_str);
+            writeln!(writer, "raw_samples\t{samples}")?;
+            let mode = self
+                .metadata
+                .get(MODE_KEY)
+                .map_or("dna-genome", String::as_str);
+            writeln!(writer, "mode\t{mode}")?;
+        }
+        if !self.samples.is_empty() {
+            writeln!(writer, "samples\t{}", itertools::join(&self.samples, ","))?;
+        }
+        writeln!(writer)?;
+        writeln!(writer, "chrom\tstart\tend\tn_reads")?;
+        for (chrom, span) in self.chroms.iter() {
+            writeln!(
+                writer,
+                "{chrom}\t{}\t{}\t{}",
+                span.start, span.end, span.n_reads
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata of each record, in the current schema of [Metadata]
+fn metadata_array(arr: &dyn Array) -> Result<Box<dyn Array>> {
+    let missing = || ArrowError::MissingField {
+        field: "metadata".to_string(),
+    };
+    let arr = arr
+        .as_any()
+        .downcast_ref::<StructArray>()
+        .ok_or_else(missing)?;
+    let idx = arr
+        .fields()
+        .iter()
+        .position(|field| field.name == "metadata")
+        .ok_or_else(missing)?;
+    migrate::<Metadata>(arr.values()[idx].clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collapse::CollapseOptions, test_data::MiniGenome};
+
+    #[test]
+    fn test_info() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output = mini.dir().join("collapse.arrow");
+        CollapseOptions::try_new(mini.bam(), &output)?
+            .capacity(1)
+            .run(File::open(mini.eventalign())?)?;
+
+        let info = ArrowInfo::from_path(&output)?;
+        assert_eq!(info.record_type, "eventalign");
+        assert_eq!(info.n_blocks, 2);
+        assert_eq!(info.n_reads, 2);
+        assert_eq!(
+            info.schema_version(),
+            crate::arrow::arrow_utils::SCHEMA_VERSION
+        );
+        assert_eq!(info.compression(), Some("lz4"));
+        assert!(info.chroms.contains_key("chrI"));
+        assert!(info.samples.is_empty());
+
+        let mut summary = Vec::new();
+        info.write(&mut summary)?;
+        let summary = String::from_utf8(summary)?;
+        assert!(summary.starts_with("type\teventalign\nblocks\t2\nreads\t2\n"));
+        assert!(summary.contains("chrom\tstart\tend\tn_reads\n"));
+
+        assert!(ArrowInfo::from_path(mini.bam()).is_err());
+        Ok(())
+    }
+}
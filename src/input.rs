@@ -0,0 +1,425 @@
+//! Opening inputs from local files or remote storage, so large Arrow files and
+//! genomes can be streamed without staging them locally first.
+//!
+//! With the `remote` feature, paths starting with `s3://`, `http://`, or
+//! `https://` are read with range requests through [object_store]. S3
+//! credentials, the region, and the endpoint of S3 compatible stores like MinIO
+//! are read from the same `AWS_` environment variables as the aws CLI, ie
+//! `AWS_ENDPOINT_URL`. Everything else is opened as a local file.
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use bio::io::fasta::IndexedReader;
+use eyre::{Result, WrapErr};
+
+/// Anything an input can be read from, ie what the Arrow loaders and genome
+/// reader need. Inputs are Sync so options holding a genome can be shared
+/// between the threads scoring each chromosome.
+pub trait ReadSeek: Read + Seek + Send + Sync {}
+
+impl<T: Read + Seek + Send + Sync> ReadSeek for T {}
+
+/// Where an input lives and how to open it
+pub trait InputSource: Debug + Send + Sync {
+    /// Open the input for reading from the start
+    fn open(&self) -> Result<Box<dyn ReadSeek>>;
+
+    /// The input at the same location with a suffix added, ie the .fai index
+    /// of a genome
+    fn with_suffix(&self, suffix: &str) -> Box<dyn InputSource>;
+}
+
+/// Input on the local filesystem
+#[derive(Debug, Clone)]
+pub struct LocalFile(pub PathBuf);
+
+impl InputSource for LocalFile {
+    fn open(&self) -> Result<Box<dyn ReadSeek>> {
+        let file =
+            File::open(&self.0).wrap_err_with(|| format!("Failed to open {}", self.0.display()))?;
+        Ok(Box::new(file))
+    }
+
+    fn with_suffix(&self, suffix: &str) -> Box<dyn InputSource> {
+        let mut path = self.0.clone().into_os_string();
+        path.push(suffix);
+        Box::new(LocalFile(path.into()))
+    }
+}
+
+/// Input in an object store or served over HTTP, read with range requests
+#[cfg(feature = "remote")]
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    url: url::Url,
+}
+
+#[cfg(feature = "remote")]
+impl RemoteFile {
+    pub fn parse(url: &str) -> Result<Self> {
+        let url = url::Url::parse(url).wrap_err_with(|| format!("Invalid URL {url}"))?;
+        match url.scheme() {
+            "s3" | "http" | "https" => Ok(RemoteFile { url }),
+            scheme => Err(eyre::eyre!("Unsupported URL scheme {scheme}: {url}")),
+        }
+    }
+
+    /// Options for the object store, the S3 settings from the environment and
+    /// allowing plain http when the URL or S3 endpoint uses it
+    fn store_options(&self) -> Vec<(String, String)> {
+        let mut options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect::<Vec<_>>();
+        let http_endpoint = options
+            .iter()
+            .any(|(key, value)| key == "aws_endpoint_url" && value.starts_with("http://"));
+        if self.url.scheme() == "http" || http_endpoint {
+            options.push(("allow_http".to_string(), "true".to_string()));
+        }
+        options
+    }
+}
+
+#[cfg(feature = "remote")]
+impl InputSource for RemoteFile {
+    fn open(&self) -> Result<Box<dyn ReadSeek>> {
+        let reader =
+            RemoteReader::new(self).wrap_err_with(|| format!("Failed to open {}", self.url))?;
+        Ok(Box::new(reader))
+    }
+
+    fn with_suffix(&self, suffix: &str) -> Box<dyn InputSource> {
+        let mut file = self.clone();
+        let path = format!("{}{suffix}", file.url.path());
+        file.url.set_path(&path);
+        Box::new(file)
+    }
+}
+
+/// Local file or remote location depending on the path, see the module docs
+pub fn input_source<P: AsRef<Path>>(path: P) -> Result<Box<dyn InputSource>> {
+    let path = path.as_ref();
+    match path.to_str() {
+        #[cfg(feature = "remote")]
+        Some(url) if is_remote(url) => Ok(Box::new(RemoteFile::parse(url)?)),
+        #[cfg(not(feature = "remote"))]
+        Some(url) if is_remote(url) => Err(eyre::eyre!(
+            "Reading {url} needs cawlr built with the remote feature, or download it first"
+        )),
+        _ => Ok(Box::new(LocalFile(path.to_path_buf()))),
+    }
+}
+
+/// Whether the path is read from remote storage instead of a local file
+pub fn is_remote<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_str()
+        .map_or(false, |path| path.contains("://"))
+}
+
+/// Open a local or remote input, see [input_source]
+pub fn open_input<P: AsRef<Path>>(path: P) -> Result<Box<dyn ReadSeek>> {
+    input_source(path)?.open()
+}
+
+/// Open a local or remote genome fasta with its .fai index next to it
+pub fn open_genome<P: AsRef<Path>>(path: P) -> Result<IndexedReader<Box<dyn ReadSeek>>> {
+    let source = input_source(&path)?;
+    let fai = source
+        .with_suffix(".fai")
+        .open()
+        .wrap_err("Missing .fai index file, run samtools faidx on the genome")?;
+    IndexedReader::new(source.open()?, BufReader::new(fai))
+        .map_err(|e| eyre::eyre!("Failed to read genome file: {e}"))
+}
+
+/// Open a local genome fasta with its .fai index at fai instead of next to it,
+/// ie from [crate::utils::ensure_faidx]
+pub fn open_genome_with_fai<P, Q>(path: P, fai: Q) -> Result<IndexedReader<Box<dyn ReadSeek>>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let fasta = LocalFile(path.as_ref().to_path_buf()).open()?;
+    let fai = LocalFile(fai.as_ref().to_path_buf()).open()?;
+    IndexedReader::new(fasta, BufReader::new(fai))
+        .map_err(|e| eyre::eyre!("Failed to read genome file: {e}"))
+}
+
+/// Smallest range requested at a time, so small sequential reads don't each
+/// make a request
+#[cfg(feature = "remote")]
+const REMOTE_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Reads a remote file, fetching a block around the current position whenever
+/// a read falls outside the last block fetched.
+#[cfg(feature = "remote")]
+pub struct RemoteReader {
+    store: Box<dyn object_store::ObjectStore>,
+    path: object_store::path::Path,
+    runtime: tokio::runtime::Runtime,
+    len: u64,
+    pos: u64,
+    block_start: u64,
+    block: Vec<u8>,
+}
+
+#[cfg(feature = "remote")]
+impl RemoteReader {
+    pub fn new(file: &RemoteFile) -> Result<Self> {
+        let (store, path) = object_store::parse_url_opts(&file.url, file.store_options())?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let len = runtime.block_on(store.head(&path))?.size;
+        Ok(RemoteReader {
+            store,
+            path,
+            runtime,
+            len,
+            pos: 0,
+            block_start: 0,
+            block: Vec::new(),
+        })
+    }
+
+    /// Total size of the file
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fetch the bytes in the range. Servers that ignore the range and send
+    /// the whole file are rejected by object_store instead of being read.
+    fn fetch(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let bytes = self
+            .runtime
+            .block_on(self.store.get_range(&self.path, start..end))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if bytes.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Expected {} bytes from {}, got {}",
+                    end - start,
+                    self.path,
+                    bytes.len()
+                ),
+            ));
+        }
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(feature = "remote")]
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let block_end = self.block_start + self.block.len() as u64;
+        if self.pos < self.block_start || self.pos >= block_end {
+            let want = buf.len().max(REMOTE_BLOCK_SIZE) as u64;
+            let end = (self.pos + want).min(self.len);
+            self.block = self.fetch(self.pos, end)?;
+            self.block_start = self.pos;
+        }
+        let offset = (self.pos - self.block_start) as usize;
+        let n = buf.len().min(self.block.len() - offset);
+        buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "remote")]
+impl Seek for RemoteReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => offset_pos(self.len, offset),
+            SeekFrom::Current(offset) => offset_pos(self.pos, offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(feature = "remote")]
+fn offset_pos(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(not(feature = "remote"))]
+    #[test]
+    fn test_remote_needs_feature() {
+        assert!(input_source("s3://bucket/genome.fa").is_err());
+        assert!(input_source("genome.fa").is_ok());
+    }
+
+    #[cfg(feature = "remote")]
+    mod remote {
+        use std::{
+            io::{BufRead, BufReader, Write},
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        use super::*;
+        use crate::{
+            arrow::{arrow_utils::load_apply, eventalign::Eventalign},
+            collapse::CollapseOptions,
+            test_data::MiniGenome,
+        };
+
+        /// Serve files from a directory until the test ends, with range
+        /// requests unless ignore_range is set
+        fn serve(dir: PathBuf, ignore_range: bool) -> Result<String> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let url = format!("http://{}", listener.local_addr()?);
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = respond(&dir, stream, ignore_range);
+                }
+            });
+            Ok(url)
+        }
+
+        fn respond(dir: &Path, mut stream: TcpStream, ignore_range: bool) -> Result<()> {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut request = String::new();
+            reader.read_line(&mut request)?;
+            let mut parts = request.split_whitespace();
+            let method = parts.next().unwrap_or("GET");
+            let path = parts.next().unwrap_or("/");
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                if line.trim().is_empty() {
+                    break;
+                }
+                let Some((name, value)) = line.trim().split_once(':') else {
+                    continue;
+                };
+                if name.eq_ignore_ascii_case("range") {
+                    let bytes = value.trim().trim_start_matches("bytes=");
+                    let (start, end) = bytes.split_once('-').unwrap();
+                    range = Some((start.parse::<usize>()?, end.parse::<usize>()?));
+                }
+            }
+            let Ok(data) = std::fs::read(dir.join(path.trim_start_matches('/'))) else {
+                write!(
+                    stream,
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )?;
+                return Ok(());
+            };
+            match range {
+                Some((start, end)) if !ignore_range && method == "GET" => {
+                    let end = end.min(data.len() - 1);
+                    write!(
+                        stream,
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: \
+                         bytes {start}-{end}/{}\r\nConnection: close\r\n\r\n",
+                        end + 1 - start,
+                        data.len()
+                    )?;
+                    stream.write_all(&data[start..=end])?;
+                }
+                _ => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        data.len()
+                    )?;
+                    if method == "GET" {
+                        stream.write_all(&data)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn test_parse_url() -> Result<()> {
+            let file = RemoteFile::parse("s3://bucket/dir/genome.fa")?;
+            let fai = format!("{:?}", file.with_suffix(".fai"));
+            assert!(fai.contains("/dir/genome.fa.fai"));
+            assert!(RemoteFile::parse("ftp://example.org/genome.fa").is_err());
+            assert!(is_remote("https://example.org/genome.fa"));
+            assert!(!is_remote("genome.fa"));
+            Ok(())
+        }
+
+        #[test]
+        fn test_http_input() -> Result<()> {
+            let mini = MiniGenome::new()?;
+            let collapsed = mini.dir().join("collapsed.arrow");
+            let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+            collapse.run(File::open(mini.eventalign())?)?;
+            let url = serve(mini.dir().to_path_buf(), false)?;
+
+            let mut remote = Vec::new();
+            load_apply(
+                open_input(format!("{url}/collapsed.arrow"))?,
+                |reads: Vec<Eventalign>| {
+                    remote.extend(reads);
+                    Ok(())
+                },
+            )?;
+            let mut local = Vec::new();
+            load_apply(open_input(&collapsed)?, |reads: Vec<Eventalign>| {
+                local.extend(reads);
+                Ok(())
+            })?;
+            assert_eq!(remote.len(), 2);
+            assert_eq!(remote, local);
+
+            let mut genome = open_genome(format!("{url}/genome.fa"))?;
+            let mut local_genome = open_genome(mini.genome())?;
+            let (mut seq, mut local_seq) = (Vec::new(), Vec::new());
+            genome.fetch("chrI", 100, 150)?;
+            genome.read(&mut seq)?;
+            local_genome.fetch("chrI", 100, 150)?;
+            local_genome.read(&mut local_seq)?;
+            assert_eq!(seq.len(), 50);
+            assert_eq!(seq, local_seq);
+
+            assert!(open_input(format!("{url}/missing.arrow")).is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn test_http_ignored_range() -> Result<()> {
+            let mini = MiniGenome::new()?;
+            let url = serve(mini.dir().to_path_buf(), true)?;
+            // The whole file sent back for a range request is an error, not
+            // the start of the file
+            let mut reader = open_input(format!("{url}/genome.fa"))?;
+            assert!(reader.read(&mut [0; 16]).is_err());
+            Ok(())
+        }
+    }
+}
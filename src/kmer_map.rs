@@ -0,0 +1,271 @@
+//! Map keyed by kmer, where each of the 4096 6-mers is interned as a [KmerId]
+//! so lookups are array indexing instead of hashing a String.
+//!
+//! Kmers that aren't 6-mers made up of A, C, G, and T, ie containing an N, are
+//! still supported but are stored in a regular hash map. Serializes the same
+//! as a HashMap<String, V>, so pickled models and ranks are unchanged.
+use std::{fmt, marker::PhantomData, ops::Index};
+
+use fnv::FnvHashMap;
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::arrow::kmer::Kmer;
+
+/// Number of distinct 6-mers
+pub const N_KMERS: usize = 4096;
+
+const BASES: [u8; 4] = *b"ACGT";
+
+fn base_code(base: u8) -> Option<u16> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Index of a 6-mer, with two bits per base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KmerId(u16);
+
+impl KmerId {
+    /// Returns None if the kmer isn't a 6-mer of A, C, G, and T
+    pub fn new(kmer: &[u8]) -> Option<Self> {
+        if kmer.len() != 6 {
+            return None;
+        }
+        let mut id = 0;
+        for &base in kmer {
+            id = (id << 2) | base_code(base)?;
+        }
+        Some(KmerId(id))
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn kmer(self) -> Kmer {
+        let mut bases = [0u8; 6];
+        for (idx, base) in bases.iter_mut().enumerate() {
+            let shift = 2 * (5 - idx);
+            *base = BASES[((self.0 >> shift) & 0b11) as usize];
+        }
+        Kmer::from_bytes(&bases).expect("6-mer is always a valid kmer")
+    }
+
+    /// Every 6-mer in order of their id
+    pub fn all() -> impl Iterator<Item = KmerId> {
+        (0..N_KMERS as u16).map(KmerId)
+    }
+}
+
+#[derive(Clone)]
+pub struct KmerMap<V> {
+    /// Allocated on first insert of a 6-mer
+    dense: Vec<Option<V>>,
+    other: FnvHashMap<Kmer, V>,
+    len: usize,
+}
+
+impl<V> Default for KmerMap<V> {
+    fn default() -> Self {
+        KmerMap {
+            dense: Vec::new(),
+            other: FnvHashMap::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<V> KmerMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, kmer: Kmer, value: V) -> Option<V> {
+        let old = match KmerId::new(kmer.as_bytes()) {
+            Some(id) => {
+                if self.dense.is_empty() {
+                    self.dense.resize_with(N_KMERS, || None);
+                }
+                self.dense[id.index()].replace(value)
+            }
+            None => self.other.insert(kmer, value),
+        };
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn get_id(&self, id: KmerId) -> Option<&V> {
+        self.dense.get(id.index()).and_then(Option::as_ref)
+    }
+
+    pub fn get<Q: AsRef<str> + ?Sized>(&self, kmer: &Q) -> Option<&V> {
+        let kmer = kmer.as_ref();
+        match KmerId::new(kmer.as_bytes()) {
+            Some(id) => self.get_id(id),
+            None => self.other.get(&kmer.parse().ok()?),
+        }
+    }
+
+    pub fn contains_key<Q: AsRef<str> + ?Sized>(&self, kmer: &Q) -> bool {
+        self.get(kmer).is_some()
+    }
+
+    /// Iterate over kmers and values, 6-mers first in the order of their
+    /// [KmerId]
+    pub fn iter(&self) -> impl Iterator<Item = (Kmer, &V)> + '_ {
+        self.dense
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, v)| v.as_ref().map(|v| (KmerId(idx as u16).kmer(), v)))
+            .chain(self.other.iter().map(|(&k, v)| (k, v)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Kmer> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<V: 'static> IntoIterator for KmerMap<V> {
+    type Item = (Kmer, V);
+    type IntoIter = Box<dyn Iterator<Item = (Kmer, V)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(
+            self.dense
+                .into_iter()
+                .enumerate()
+                .filter_map(|(idx, v)| v.map(|v| (KmerId(idx as u16).kmer(), v)))
+                .chain(self.other),
+        )
+    }
+}
+
+impl<V> FromIterator<(Kmer, V)> for KmerMap<V> {
+    fn from_iter<T: IntoIterator<Item = (Kmer, V)>>(iter: T) -> Self {
+        let mut map = KmerMap::new();
+        for (kmer, value) in iter {
+            map.insert(kmer, value);
+        }
+        map
+    }
+}
+
+impl<V, Q: AsRef<str> + ?Sized> Index<&Q> for KmerMap<V> {
+    type Output = V;
+
+    fn index(&self, kmer: &Q) -> &V {
+        self.get(kmer)
+            .unwrap_or_else(|| panic!("Kmer {} not found", kmer.as_ref()))
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for KmerMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V: Serialize> Serialize for KmerMap<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len))?;
+        for (kmer, value) in self.iter() {
+            map.serialize_entry(kmer.as_str(), value)?;
+        }
+        map.end()
+    }
+}
+
+struct KmerMapVisitor<V>(PhantomData<V>);
+
+impl<'de, V: Deserialize<'de>> Visitor<'de> for KmerMapVisitor<V> {
+    type Value = KmerMap<V>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of kmers")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut map = KmerMap::new();
+        while let Some((kmer, value)) = access.next_entry::<String, V>()? {
+            let kmer = kmer.parse().map_err(de::Error::custom)?;
+            map.insert(kmer, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for KmerMap<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(KmerMapVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kmer_id() {
+        assert_eq!(KmerId::new(b"AAAAAA"), Some(KmerId(0)));
+        assert_eq!(KmerId::new(b"TTTTTT"), Some(KmerId(4095)));
+        assert_eq!(KmerId::new(b"AAAAAN"), None);
+        assert_eq!(KmerId::new(b"AAAAA"), None);
+        for id in KmerId::all() {
+            assert_eq!(KmerId::new(id.kmer().as_bytes()), Some(id));
+        }
+    }
+
+    #[test]
+    fn test_kmer_map() {
+        let mut map = KmerMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.insert("GATTAC".parse().unwrap(), 1.0), None);
+        assert_eq!(map.insert("GATTAC".parse().unwrap(), 2.0), Some(1.0));
+        map.insert("NATTAC".parse().unwrap(), 3.0);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("GATTAC"), Some(&2.0));
+        assert_eq!(map["NATTAC"], 3.0);
+        assert!(!map.contains_key("AAAAAA"));
+        assert_eq!(map.keys().collect::<Vec<_>>(), ["GATTAC", "NATTAC"]);
+    }
+
+    #[test]
+    fn test_kmer_map_pickle() {
+        let mut map = KmerMap::new();
+        map.insert("GATTAC".parse().unwrap(), 1.0);
+        map.insert("NATTAC".parse().unwrap(), 2.0);
+        let bytes = serde_pickle::to_vec(&map, Default::default()).unwrap();
+
+        // Compatible with ranks saved as a HashMap
+        let hashmap: FnvHashMap<String, f64> =
+            serde_pickle::from_slice(&bytes, Default::default()).unwrap();
+        assert_eq!(hashmap["GATTAC"], 1.0);
+        let bytes = serde_pickle::to_vec(&hashmap, Default::default()).unwrap();
+        let map: KmerMap<f64> = serde_pickle::from_slice(&bytes, Default::default()).unwrap();
+        assert_eq!(map.get("NATTAC"), Some(&2.0));
+        assert_eq!(map.len(), 2);
+    }
+}
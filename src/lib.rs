@@ -1,19 +1,51 @@
 pub mod agg_blocks;
 pub mod arrow;
+pub mod baseline;
+pub mod bigwig;
 pub mod bkde;
+pub mod cancel;
 pub mod collapse;
 pub mod context;
+pub mod diff;
+pub mod discover;
+pub mod doctor;
+pub mod duplex;
+pub mod eval;
+pub mod ffi;
 pub mod filter;
+pub mod haplotype;
+pub mod hash;
 pub mod index;
+pub mod info;
+pub mod input;
+pub mod kmer_map;
+pub mod merge;
 pub mod motif;
+pub mod normalize;
 pub mod npsmlr;
+pub mod pileup;
+pub mod pipeline;
 pub mod plus_strand_map;
+pub mod profile;
+pub mod progress;
+pub mod quantiles;
 pub mod rank;
 pub mod region;
+pub mod repro;
 pub mod score;
 pub mod score_model;
+pub mod score_summary;
 pub mod sma;
+pub mod split;
+pub mod split_clusters;
+pub mod stats;
 mod strand_map;
+pub mod subsample;
+#[cfg(test)]
+mod test_data;
+pub mod track;
 pub mod train;
+pub mod train_test_split;
 pub mod utils;
 pub mod validated;
+pub mod watch;
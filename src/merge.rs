@@ -0,0 +1,242 @@
+//! Concatenate Arrow files from cawlr into one, tagging each read with the
+//! sample it came from, so ie treated and untreated samples can be compared
+//! from a single file.
+use std::{
+    fs::File,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use arrow2::datatypes::{Field, Schema};
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::{Result, WrapErr};
+
+use crate::{
+    arrow::{
+        arrow_utils::{
+            self, dispatch_arrow_kind, eventalign_schema, has_samples, load_apply, read_mode, save,
+            ArrowCompression, ArrowKind, ArrowKindFn, SchemaExt,
+        },
+        metadata::{MetadataExt, MetadataMutExt},
+    },
+    hash::Encode,
+    utils::stdout_or_file,
+};
+
+/// Merges Arrow files of the same type, ie all from cawlr collapse, score, or
+/// sma, setting the sample of each read.
+pub struct MergeOptions {
+    inputs: Vec<(PathBuf, Option<String>)>,
+    compression: ArrowCompression,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            compression: ArrowCompression::default(),
+        }
+    }
+}
+
+impl MergeOptions {
+    /// Add an input whose reads are tagged with the sample. Without one, reads
+    /// keep the sample from an earlier merge, or are tagged with the file name
+    /// without its extension. Inputs can share a sample, ie for flowcells of
+    /// the same library.
+    pub fn input<P: Into<PathBuf>>(&mut self, path: P, sample: Option<String>) -> &mut Self {
+        self.inputs.push((path.into(), sample));
+        self
+    }
+
+    /// Codec for the output, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the merged reads to output, or stdout if there is none
+    pub fn run<P: AsRef<Path>>(&self, output: Option<P>) -> Result<()> {
+        let (first, _) = self
+            .inputs
+            .first()
+            .ok_or_else(|| eyre::eyre!("No files to merge"))?;
+        let kind = ArrowKind::of(first)?;
+        for (path, _) in self.inputs.iter().skip(1) {
+            let other = ArrowKind::of(path)?;
+            if other != kind {
+                eyre::bail!(
+                    "Can't merge {} with {kind} reads and {} with {other} reads",
+                    first.display(),
+                    path.display()
+                );
+            }
+        }
+
+        let merge = MergeReads {
+            merge: self,
+            writer: stdout_or_file(output.as_ref())?,
+            first,
+        };
+        dispatch_arrow_kind(kind, merge)
+    }
+
+    /// Schema of merged eventalign reads, which have to be in the same mode.
+    /// Samples are only recorded as kept if every input kept them.
+    fn eventalign_schema(&self, first: &Path) -> Result<Schema> {
+        let mut samples = true;
+        let mode = read_mode(&mut File::open(first)?)?;
+        for (path, _) in self.inputs.iter() {
+            samples &= has_samples(&mut File::open(path)?)?;
+            let other = read_mode(&mut File::open(path)?)?;
+            if other != mode {
+                eyre::bail!(
+                    "Can't merge {} with {mode} reads and {} with {other} reads",
+                    first.display(),
+                    path.display()
+                );
+            }
+        }
+        Ok(eventalign_schema(samples, mode))
+    }
+
+    fn merge<T, W>(&self, writer: W, schema: &Schema) -> Result<()>
+    where
+        W: Write,
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + MetadataExt
+            + MetadataMutExt
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let mut writer = arrow_utils::wrap_writer_with(writer, schema, self.compression)?;
+        for (path, sample) in self.inputs.iter() {
+            let default_sample = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut n_reads = 0;
+            let reader = BufReader::new(File::open(path)?);
+            load_apply(reader, |mut reads: Vec<T>| {
+                for read in reads.iter_mut() {
+                    match sample {
+                        Some(sample) => read.set_sample(sample.as_str()),
+                        None if read.sample().is_none() => read.set_sample(default_sample.as_str()),
+                        None => (),
+                    }
+                }
+                n_reads += reads.len();
+                save(&mut writer, &reads)
+            })
+            .wrap_err_with(|| format!("Failed to merge {}", path.display()))?;
+            log::info!(
+                "Merged {n_reads} reads from {} as {}",
+                path.display(),
+                sample.as_deref().unwrap_or(&default_sample)
+            );
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+fn schema<T: SchemaExt>() -> Schema {
+    Schema::from(vec![Field::new(T::type_as_str(), T::data_type(), false)])
+}
+
+/// [MergeOptions::run] with the type of reads of the inputs
+struct MergeReads<'a> {
+    merge: &'a MergeOptions,
+    writer: Box<dyn Write + Send>,
+    first: &'a Path,
+}
+
+impl ArrowKindFn for MergeReads<'_> {
+    type Output = ();
+
+    fn call<T>(self, kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let schema = match kind {
+            ArrowKind::Eventalign => self.merge.eventalign_schema(self.first)?,
+            _ => schema::<T>(),
+        };
+        self.merge.merge::<T, _>(self.writer, &schema)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        arrow::{arrow_utils::load_chunks, scored_read::ScoredRead},
+        test_data::{test_score, write_reads, MiniGenome, TestRead},
+    };
+
+    fn write_scored(path: &Path, names: &[&str]) -> Result<()> {
+        let reads = names
+            .iter()
+            .map(|&name| TestRead::new(name).scored(vec![test_score(10, 0.9)]))
+            .collect::<Vec<_>>();
+        write_reads(path, &reads)
+    }
+
+    fn read_scored(path: &Path) -> Result<Vec<ScoredRead>> {
+        let chunks = load_chunks::<_, ScoredRead>(File::open(path)?)?;
+        Ok(chunks.collect::<Result<Vec<_>, _>>()?.concat())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let treated = mini.dir().join("treated.arrow");
+        let untreated = mini.dir().join("untreated.arrow");
+        write_scored(&treated, &["a", "b"])?;
+        write_scored(&untreated, &["c"])?;
+
+        let merged = mini.dir().join("merged.arrow");
+        MergeOptions::default()
+            .input(&treated, None)
+            .input(&untreated, Some("control".to_string()))
+            .run(Some(&merged))?;
+        let reads = read_scored(&merged)?;
+        let samples = reads
+            .iter()
+            .map(|r| (r.name(), r.sample().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            [("a", "treated"), ("b", "treated"), ("c", "control")]
+        );
+
+        // Samples from an earlier merge are kept unless one is given
+        let remerged = mini.dir().join("remerged.arrow");
+        MergeOptions::default()
+            .input(&merged, None)
+            .run(Some(&remerged))?;
+        let reads = read_scored(&remerged)?;
+        assert_eq!(reads[2].sample(), Some("control"));
+
+        // Types can't be mixed
+        let collapsed = mini.dir().join("collapsed.arrow");
+        crate::collapse::CollapseOptions::try_new(mini.bam(), &collapsed)?
+            .run(File::open(mini.eventalign())?)?;
+        let err = MergeOptions::default()
+            .input(&treated, None)
+            .input(&collapsed, None)
+            .run(Some(mini.dir().join("mixed.arrow")));
+        assert!(err.is_err());
+        Ok(())
+    }
+}
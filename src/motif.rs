@@ -1,26 +1,85 @@
-use std::{collections::HashSet, fmt, str::FromStr};
+use std::{fmt, str::FromStr};
 
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+use crate::arrow::kmer::rna_to_dna;
+
+/// Length of the kmers scored by the nanopolish pore models
+pub const KMER_SIZE: usize = 6;
+
+/// Length of the kmers scored by direct RNA pore models
+pub const RNA_KMER_SIZE: usize = 5;
+
+const FORMAT_EXAMPLES: &str = "expected [pos]:[motif] with a one-based position of the modified \
+                               base, ie \"2:GC\", \"1:CG\", or \"3:DRACH\"";
+
+#[derive(Error, Debug, PartialEq, Eq)]
 pub enum MotifError {
-    #[error("Invalid format, should be in the form [pos]:[motif]")]
-    InvalidFormat,
-    #[error("Invalid base, should only be ACGT, uppercase only")]
-    InvalidBase,
-    #[error("Position should be less than the length of the motif given.")]
-    PositionOutsideofMotif,
-    #[error("Position is one-based.")]
-    PositionOneBased,
-    #[error("Position must be positive integer")]
-    PositionParseFailed,
-    #[error("Additional parts not expected. Invalid format")]
-    UnexpectedAdditionalFormat,
+    #[error("Invalid motif \"{input}\": missing ':', {FORMAT_EXAMPLES}")]
+    InvalidFormat { input: String },
+    #[error(
+        "Invalid motif \"{input}\": base '{base}' at position {} of {motif} should be one of \
+         ACGTU or an uppercase IUPAC ambiguity code (RYSWKMBDHVN)",
+        idx + 1
+    )]
+    InvalidBase {
+        input: String,
+        motif: String,
+        base: char,
+        idx: usize,
+    },
+    #[error("Invalid motif \"{input}\": the motif is empty, {FORMAT_EXAMPLES}")]
+    EmptyMotif { input: String },
+    #[error(
+        "Invalid motif \"{input}\": position {position} is past the end of {motif}, which has \
+         {} bases. Did you mean \"{}:{motif}\"?",
+        motif.len(),
+        motif.len()
+    )]
+    PositionOutsideofMotif {
+        input: String,
+        motif: String,
+        position: usize,
+    },
+    #[error("Invalid motif \"{input}\": positions are one-based, did you mean \"1:{motif}\"?")]
+    PositionOneBased { input: String, motif: String },
+    #[error(
+        "Invalid motif \"{input}\": position \"{position}\" is not a positive integer, \
+         {FORMAT_EXAMPLES}"
+    )]
+    PositionParseFailed { input: String, position: String },
+    #[error(
+        "Invalid motif \"{input}\": unexpected \":{extra}\" after the motif, {FORMAT_EXAMPLES}"
+    )]
+    UnexpectedAdditionalFormat { input: String, extra: String },
 }
 
-fn valid_motif_bases(motif: &str) -> bool {
-    let bases = HashSet::from(['A', 'C', 'G', 'T']);
-    !motif.is_empty() && motif.chars().all(|b| bases.contains(&b))
+/// Bases matched by an IUPAC nucleotide code, None if the code is invalid
+fn iupac_bases(code: u8) -> Option<&'static [u8]> {
+    let bases: &[u8] = match code {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGT",
+        _ => return None,
+    };
+    Some(bases)
+}
+
+/// Whether the base in the sequence is one of the bases of the IUPAC code
+fn base_matches(code: u8, base: u8) -> bool {
+    iupac_bases(code).map_or(false, |bases| bases.contains(&base))
 }
 
 #[derive(Debug, Clone)]
@@ -40,26 +99,57 @@ impl Motif {
         }
     }
 
+    /// Parse a motif in the form [pos]:[motif], ie "2:GC". Whether the motif
+    /// fits in the kmers that are scored is checked by
+    /// [ScoreOptions::motifs](crate::score::ScoreOptions::motifs).
     pub fn parse_from_str<T>(string: T) -> Result<Self, MotifError>
     where
         T: AsRef<str>,
     {
-        let string = string.as_ref();
-        let mut iter = string.split(':');
-        let pos = iter
-            .next()
-            .ok_or(MotifError::InvalidFormat)?
-            .parse::<usize>()
-            .map_err(|_| MotifError::PositionParseFailed)?;
-        let motif = iter.next().ok_or(MotifError::InvalidFormat)?;
-        if !valid_motif_bases(motif) {
-            Err(MotifError::InvalidBase)
-        } else if pos == 0 {
-            Err(MotifError::PositionOneBased)
+        let input = string.as_ref().to_string();
+        let (pos, rest) = match input.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(MotifError::InvalidFormat { input }),
+        };
+        let (motif, extra) = match rest.split_once(':') {
+            Some((motif, extra)) => (motif, Some(extra)),
+            None => (rest, None),
+        };
+        let pos = match pos.trim().parse::<usize>() {
+            Ok(pos) => pos,
+            Err(_) => {
+                let position = pos.to_string();
+                return Err(MotifError::PositionParseFailed { input, position });
+            }
+        };
+        if let Some(extra) = extra {
+            let extra = extra.to_string();
+            return Err(MotifError::UnexpectedAdditionalFormat { input, extra });
+        }
+        if motif.is_empty() {
+            return Err(MotifError::EmptyMotif { input });
+        }
+        if let Some((idx, base)) = motif.char_indices().find(|&(_, base)| {
+            !base.is_ascii() || (base != 'U' && iupac_bases(base as u8).is_none())
+        }) {
+            let motif = motif.to_string();
+            return Err(MotifError::InvalidBase {
+                input,
+                motif,
+                base,
+                idx,
+            });
+        }
+        // RNA motifs match the same as DNA, ie "3:DRACU" is "3:DRACT"
+        let motif = rna_to_dna(motif);
+        if pos == 0 {
+            Err(MotifError::PositionOneBased { input, motif })
         } else if pos > motif.len() {
-            Err(MotifError::PositionOutsideofMotif)
-        } else if iter.next().is_some() {
-            Err(MotifError::UnexpectedAdditionalFormat)
+            Err(MotifError::PositionOutsideofMotif {
+                input,
+                motif,
+                position: pos,
+            })
         } else {
             Ok(Motif::new(motif, pos))
         }
@@ -81,9 +171,44 @@ impl Motif {
         self.position - 1
     }
 
+    /// Whether the motif occurs in the sequence starting at offset, with
+    /// ambiguity codes in the motif matching any of their bases.
+    pub fn matches_at<S: AsRef<[u8]> + ?Sized>(&self, seq: &S, offset: usize) -> bool {
+        let seq = seq.as_ref();
+        match seq.get(offset..offset + self.len_motif()) {
+            Some(window) => self
+                .motif
+                .bytes()
+                .zip(window.iter())
+                .all(|(code, &base)| base_matches(code, base)),
+            None => false,
+        }
+    }
+
+    /// Offsets of every occurrence of the motif in the kmer, including
+    /// overlapping occurrences.
+    pub fn offsets_in_kmer<'a, S: AsRef<[u8]> + ?Sized>(
+        &'a self,
+        kmer: &'a S,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let kmer = kmer.as_ref();
+        let n_offsets = (kmer.len() + 1).saturating_sub(self.len_motif());
+        (0..n_offsets).filter(move |&offset| self.matches_at(kmer, offset))
+    }
+
+    /// Whether the kmer of a score matches the motif. Scores from cawlr score
+    /// have kmers starting with the motif, while scores from mod BAM files
+    /// only have the modified base as the kmer.
+    pub fn matches_score_kmer<S: AsRef<[u8]> + ?Sized>(&self, kmer: &S) -> bool {
+        match kmer.as_ref() {
+            [base] => base_matches(self.motif.as_bytes()[self.position_0b()], *base),
+            kmer => self.matches_at(kmer, 0),
+        }
+    }
+
     // TODO impl std::str::pattern::Pattern when it stabilizes
     pub fn within_kmer(&self, kmer: &str) -> bool {
-        kmer.contains(self.motif())
+        self.offsets_in_kmer(kmer).next().is_some()
     }
 
     pub(crate) fn surrounding_idxs(&self, pos: u64) -> impl Iterator<Item = u64> {
@@ -170,6 +295,79 @@ mod test {
         assert!(m.is_err());
     }
 
+    #[test]
+    fn test_motif_errors() {
+        let err = |s: &str| Motif::parse_from_str(s).unwrap_err();
+        assert!(matches!(err("GC"), MotifError::InvalidFormat { .. }));
+        assert!(matches!(
+            err("1:GXC"),
+            MotifError::InvalidBase {
+                base: 'X',
+                idx: 1,
+                ..
+            }
+        ));
+        assert!(matches!(err("1:"), MotifError::EmptyMotif { .. }));
+        assert!(matches!(
+            err("1:TA:"),
+            MotifError::UnexpectedAdditionalFormat { .. }
+        ));
+        assert!(matches!(
+            err("GC:2"),
+            MotifError::PositionParseFailed { .. }
+        ));
+        // Long motifs are fine for sma and mod-BAM input, only scoring limits
+        // them to the kmer size
+        assert!(Motif::parse_from_str("1:GCGCGCG").is_ok());
+
+        let msg = err("3:GC").to_string();
+        assert!(msg.contains("has 2 bases"), "{msg}");
+        assert!(msg.contains("\"2:GC\""), "{msg}");
+        let msg = err("0:CG").to_string();
+        assert!(msg.contains("\"1:CG\""), "{msg}");
+        let msg = err("1:gc").to_string();
+        assert!(msg.contains("base 'g' at position 1"), "{msg}");
+        assert!(err("quack:GC").to_string().contains("ie \"2:GC\""));
+    }
+
+    #[test]
+    fn test_motif_iupac() {
+        let m = Motif::from_str("2:GCH").unwrap();
+        assert!(m.matches_at("GCAAAA", 0));
+        assert!(m.matches_at("AAGCTA", 2));
+        assert!(!m.matches_at("GCGAAA", 0));
+        assert!(!m.matches_at("AAAAGC", 4));
+        assert!(Motif::from_str("3:DRACH").is_ok());
+        assert!(Motif::from_str("1:GCX").is_err());
+        assert!(Motif::from_str("1:gc").is_err());
+
+        let m = Motif::from_str("3:GGACU").unwrap();
+        assert_eq!(m.motif(), "GGACT");
+        assert!(m.matches_at("GGACTA", 0));
+    }
+
+    #[test]
+    fn test_matches_score_kmer() {
+        let m = Motif::from_str("2:GCH").unwrap();
+        assert!(m.matches_score_kmer("GCTAAA"));
+        assert!(!m.matches_score_kmer("GCGAAA"));
+        assert!(m.matches_score_kmer("C"));
+        assert!(!m.matches_score_kmer("A"));
+    }
+
+    #[test]
+    fn test_offsets_in_kmer() {
+        let m = Motif::from_str("1:TA").unwrap();
+        assert_eq!(m.offsets_in_kmer("TATATA").collect::<Vec<_>>(), [0, 2, 4]);
+        assert_eq!(m.offsets_in_kmer("GGGGTA").collect::<Vec<_>>(), [4]);
+        assert!(!m.within_kmer("GGGGGT"));
+
+        let m = Motif::from_str("3:DRACH").unwrap();
+        assert_eq!(m.offsets_in_kmer("CGGACT").collect::<Vec<_>>(), [1]);
+        assert_eq!(m.offsets_in_kmer("AGACA").collect::<Vec<_>>(), [0]);
+        assert_eq!(m.offsets_in_kmer("AC").count(), 0);
+    }
+
     #[test]
     fn test_surrounding_idxs() {
         let m = Motif::from_str("1:CG").unwrap();
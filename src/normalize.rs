@@ -0,0 +1,293 @@
+//! Quantile normalization of scores across samples, so scores from different
+//! flowcells or runs can be compared or aggregated together.
+//!
+//! Each sample's score distribution is mapped onto a reference sample's, ie a
+//! score at the 90th percentile of a sample becomes the score at the 90th
+//! percentile of the reference.
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+
+use crate::{
+    arrow::{
+        arrow_utils::{save, wrap_writer},
+        io::{read_mod_bam_or_arrow, ModFile},
+        scored_read::ScoredRead,
+    },
+    cancel,
+    quantiles::TDigest,
+    utils::create_output,
+};
+
+/// Number of reads written to the output at a time
+const BATCH_SIZE: usize = 1024;
+
+/// Piecewise linear map from the quantiles of one sample to the same
+/// quantiles of the reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantileMap {
+    source: Vec<f64>,
+    reference: Vec<f64>,
+}
+
+impl QuantileMap {
+    /// Both must be sorted and estimated at the same quantiles
+    pub fn new(source: Vec<f64>, reference: Vec<f64>) -> Result<Self> {
+        if source.len() != reference.len() || source.is_empty() {
+            return Err(eyre::eyre!(
+                "Quantiles of the sample and reference must be the same non-zero length"
+            ));
+        }
+        Ok(QuantileMap { source, reference })
+    }
+
+    /// Map a score onto the reference distribution. Scores tied across several
+    /// quantiles, common with the discrete probabilities in modification bam
+    /// files, map to the mean of the reference over those quantiles. NaN
+    /// scores are left as is.
+    pub fn map(&self, x: f64) -> f64 {
+        if x.is_nan() {
+            return x;
+        }
+        let lo = self.source.partition_point(|&q| q < x);
+        let hi = self.source.partition_point(|&q| q <= x);
+        if lo < hi {
+            let tied = &self.reference[lo..hi];
+            return tied.iter().sum::<f64>() / tied.len() as f64;
+        }
+        if lo == 0 {
+            return self.reference[0];
+        }
+        if lo == self.source.len() {
+            return self.reference[lo - 1];
+        }
+        let (x0, x1) = (self.source[lo - 1], self.source[lo]);
+        let (y0, y1) = (self.reference[lo - 1], self.reference[lo]);
+        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+    }
+}
+
+/// Aligns the score distributions of samples to a reference sample, writing
+/// the adjusted scores as new cawlr score outputs.
+pub struct NormalizeOptions {
+    n_quantiles: usize,
+    compression: f64,
+    mod_tag: Option<Vec<u8>>,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions {
+            n_quantiles: 1000,
+            compression: 500.0,
+            mod_tag: None,
+        }
+    }
+}
+
+impl NormalizeOptions {
+    /// Number of evenly spaced quantiles used for the mapping, defaults to
+    /// 1000
+    pub fn n_quantiles(&mut self, n_quantiles: usize) -> &mut Self {
+        self.n_quantiles = n_quantiles;
+        self
+    }
+
+    /// Modification tag used if the inputs are bam files, ie C+m
+    pub fn mod_tag<B: Into<Vec<u8>>>(&mut self, mod_tag: Option<B>) -> &mut Self {
+        self.mod_tag = mod_tag.map(|t| t.into());
+        self
+    }
+
+    /// Estimate the score at each of the evenly spaced quantiles
+    pub fn score_quantiles(&self, input: &Path) -> Result<Vec<f64>> {
+        let mut digest = TDigest::new(self.compression);
+        let mod_file = ModFile::open_path(input, self.mod_tag.clone())?;
+        read_mod_bam_or_arrow(mod_file, |read| {
+            cancel::check()?;
+            read.scores()
+                .iter()
+                .filter(|s| !s.score.is_nan())
+                .for_each(|s| digest.add(s.score));
+            Ok(())
+        })?;
+        let last = self.n_quantiles.max(2) - 1;
+        (0..=last)
+            .map(|i| digest.quantile(i as f64 / last as f64))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| eyre::eyre!("No scores in {}", input.display()))
+    }
+
+    /// Write the input with each score mapped onto the reference quantiles
+    pub fn normalize(&self, input: &Path, reference: &[f64], output: &Path) -> Result<()> {
+        let map = QuantileMap::new(self.score_quantiles(input)?, reference.to_vec())?;
+        let mod_file = ModFile::open_path(input, self.mod_tag.clone())?;
+        let mut writer = wrap_writer(create_output(output)?, &ScoredRead::schema())?;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let res = read_mod_bam_or_arrow(mod_file, |mut read| {
+            cancel::check()?;
+            for score in read.scores.iter_mut() {
+                score.score = map.map(score.score);
+            }
+            batch.push(read);
+            if batch.len() >= BATCH_SIZE {
+                save(&mut writer, &batch)?;
+                batch.clear();
+            }
+            Ok(())
+        })
+        .and_then(|_| save(&mut writer, &batch));
+        writer.finish()?;
+        res
+    }
+
+    /// Normalize each input against the reference, writing
+    /// {output_dir}/{input name}.normalized.arrow for each and returning the
+    /// paths. Inputs with the same name, ie from different directories, are
+    /// written to {input name}.{n}.normalized.arrow instead, where n is the
+    /// input's position starting from 1. Fails before normalizing anything if
+    /// two outputs would still have the same name.
+    pub fn run<P: AsRef<Path>>(
+        &self,
+        reference: &Path,
+        inputs: &[P],
+        output_dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let names = output_names(inputs)?;
+        let ref_quantiles = self.score_quantiles(reference)?;
+        std::fs::create_dir_all(output_dir)?;
+        let mut outputs = Vec::with_capacity(inputs.len());
+        for (input, name) in inputs.iter().zip(names) {
+            let input = input.as_ref();
+            let output = output_dir.join(name);
+            log::info!("Normalizing {} to {}", input.display(), output.display());
+            self.normalize(input, &ref_quantiles, &output)?;
+            outputs.push(output);
+        }
+        Ok(outputs)
+    }
+}
+
+/// File names of the normalized outputs of each input, see
+/// [NormalizeOptions::run]
+fn output_names<P: AsRef<Path>>(inputs: &[P]) -> Result<Vec<String>> {
+    let stems = inputs
+        .iter()
+        .map(|input| {
+            let input = input.as_ref();
+            input
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .ok_or_else(|| eyre::eyre!("Invalid input path: {}", input.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let names = stems
+        .iter()
+        .enumerate()
+        .map(|(idx, stem)| {
+            if stems.iter().filter(|s| *s == stem).count() > 1 {
+                format!("{stem}.{}.normalized.arrow", idx + 1)
+            } else {
+                format!("{stem}.normalized.arrow")
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    for (input, name) in inputs.iter().zip(&names) {
+        if !seen.insert(name) {
+            eyre::bail!(
+                "Output {name} for {} has the same name as the output of another input, \
+                 rename one of them",
+                input.as_ref().display()
+            );
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use super::*;
+    use crate::{
+        arrow::arrow_utils::load_apply,
+        test_data::{test_score, write_reads, MiniGenome, TestRead},
+    };
+
+    #[test]
+    fn test_quantile_map() -> Result<()> {
+        let map = QuantileMap::new(vec![0.0, 0.2, 0.2, 0.6], vec![0.1, 0.3, 0.5, 0.9])?;
+        assert_eq!(map.map(-1.0), 0.1);
+        assert_eq!(map.map(0.1), 0.2);
+        assert_eq!(map.map(0.2), 0.4);
+        assert!((map.map(0.4) - 0.7).abs() < 1e-10);
+        assert_eq!(map.map(1.0), 0.9);
+        assert!(map.map(f64::NAN).is_nan());
+        assert!(QuantileMap::new(vec![0.0], vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let write_sample = |name: &str, shift: f64| -> Result<PathBuf> {
+            let path = mini.dir().join(name);
+            let scores = (0..100)
+                .map(|i| test_score(i, i as f64 / 200.0 + shift))
+                .collect();
+            write_reads(&path, &[TestRead::new("read").span(0, 100).scored(scores)])?;
+            Ok(path)
+        };
+        let reference = write_sample("reference.arrow", 0.0)?;
+        let shifted = write_sample("shifted.arrow", 0.5)?;
+
+        let output_dir = mini.dir().join("normalized");
+        let outputs = NormalizeOptions::default().run(&reference, &[&shifted], &output_dir)?;
+        assert_eq!(outputs, [output_dir.join("shifted.normalized.arrow")]);
+
+        // Inputs with the same name in different directories don't overwrite
+        // each other
+        std::fs::create_dir(mini.dir().join("rep2"))?;
+        let same_name = mini.dir().join("rep2").join("shifted.arrow");
+        std::fs::copy(&shifted, &same_name)?;
+        let same_dir = mini.dir().join("same_name");
+        let same_outputs =
+            NormalizeOptions::default().run(&reference, &[&shifted, &same_name], &same_dir)?;
+        assert_eq!(
+            same_outputs,
+            [
+                same_dir.join("shifted.1.normalized.arrow"),
+                same_dir.join("shifted.2.normalized.arrow")
+            ]
+        );
+        assert!(same_outputs.iter().all(|output| output.exists()));
+
+        // A numbered name can match another input's name, which fails before
+        // overwriting anything
+        let numbered = mini.dir().join("shifted.1.arrow");
+        std::fs::copy(&shifted, &numbered)?;
+        let numbered_dir = mini.dir().join("numbered");
+        let res = NormalizeOptions::default().run(
+            &reference,
+            &[&shifted, &same_name, &numbered],
+            &numbered_dir,
+        );
+        assert!(res.is_err());
+        assert!(!numbered_dir.join("shifted.1.normalized.arrow").exists());
+
+        let mut scores = Vec::new();
+        load_apply(File::open(&outputs[0])?, |reads: Vec<ScoredRead>| {
+            scores.extend(reads.iter().flat_map(|r| r.scores()).map(|s| s.score));
+            Ok(())
+        })?;
+        assert_eq!(scores.len(), 100);
+        for (i, score) in scores.into_iter().enumerate() {
+            assert!((score - i as f64 / 200.0).abs() < 1e-3, "{i}: {score}");
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
 pub mod score;
 pub mod train;
 
-pub use score::ScoreOptions;
+pub use score::{Ensemble, ScoreOptions};
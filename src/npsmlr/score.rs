@@ -1,6 +1,9 @@
 use std::{
+    collections::BTreeMap,
+    fmt,
     io::{Read, Seek, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use eyre::Result;
@@ -8,23 +11,89 @@ use fnv::FnvHashMap;
 
 use crate::{
     arrow::{
-        arrow_utils::load_read_write_arrow,
+        arrow_utils::{
+            has_samples, load_read_write_arrow_with, n_chunks, read_mode, ArrowCompression,
+        },
         eventalign::Eventalign,
         scored_read::{Score, ScoredRead},
         signal::Signal,
     },
+    cancel,
+    haplotype::HaplotypeWriters,
     motif::{all_bases, Motif},
+    progress::{ProgressSink, Reporter, Stage},
+    rank::Ranks,
     train::Model,
     utils::CawlrIO,
 };
 
+/// How scores from multiple positive/negative model pairs are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ensemble {
+    /// Average the log-likelihoods across models before computing the score
+    Mean,
+    /// Score is the fraction of models that call the position modified
+    Vote,
+}
+
+impl Ensemble {
+    /// Combine the log-likelihood sums for the positive and negative models
+    /// from each model pair into a single score. Returns None if no pairs had
+    /// data.
+    fn combine(&self, lnsums: &[(f64, f64)]) -> Option<f64> {
+        if lnsums.is_empty() {
+            return None;
+        }
+        let n = lnsums.len() as f64;
+        let score = match self {
+            Ensemble::Mean => {
+                let pos_sum = lnsums.iter().map(|x| x.0).sum::<f64>() / n;
+                let neg_sum = lnsums.iter().map(|x| x.1).sum::<f64>() / n;
+                lnsum_to_rate(pos_sum, neg_sum)
+            }
+            Ensemble::Vote => {
+                let votes = lnsums
+                    .iter()
+                    .filter(|(pos_sum, neg_sum)| lnsum_to_rate(*pos_sum, *neg_sum) > 0.5)
+                    .count();
+                votes as f64 / n
+            }
+        };
+        Some(score)
+    }
+}
+
+impl fmt::Display for Ensemble {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let res = match self {
+            Ensemble::Mean => "mean",
+            Ensemble::Vote => "vote",
+        };
+        write!(f, "{res}")
+    }
+}
+
+fn lnsum_to_rate(pos_sum: f64, neg_sum: f64) -> f64 {
+    let exp_me = pos_sum.exp();
+    let exp_un = neg_sum.exp();
+
+    log::debug!("exp_me: {exp_me}");
+    log::debug!("exp_un: {exp_un}");
+    exp_me / (exp_me + exp_un)
+}
+
 pub struct ScoreOptions {
-    pos_model: Model,
-    neg_model: Model,
-    ranks: FnvHashMap<String, f64>,
+    models: Vec<(Model, Model)>,
+    ensemble: Ensemble,
+    ranks: Ranks,
     freq_thresh: usize,
     cutoff: f64,
     motifs: Vec<Motif>,
+    skips: bool,
+    haplotype_output: Option<PathBuf>,
+    compression: ArrowCompression,
+    batch_size: Option<usize>,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
 }
 
 impl std::fmt::Debug for ScoreOptions {
@@ -33,28 +102,85 @@ impl std::fmt::Debug for ScoreOptions {
             .field("freq_thresh", &self.freq_thresh)
             .field("cutoff", &self.cutoff)
             .field("motifs", &self.motifs)
+            .field("n_models", &self.models.len())
+            .field("ensemble", &self.ensemble)
+            .field("skips", &self.skips)
             .finish_non_exhaustive()
     }
 }
 
+/// Log-probability of a kmer having signal data under the positive and
+/// negative control models, None if either model is missing it.
+fn presence_lnprobs(pos_ctrl: &Model, neg_ctrl: &Model, kmer: &str) -> Option<(f64, f64)> {
+    let pos_presence = *pos_ctrl.skips().get(kmer)?;
+    let neg_presence = *neg_ctrl.skips().get(kmer)?;
+    if pos_presence > 0.0 && neg_presence > 0.0 {
+        Some((pos_presence.ln(), neg_presence.ln()))
+    } else {
+        None
+    }
+}
+
 fn count_motif_in_kmer(kmer: &str, motif: &Motif) -> usize {
-    kmer.matches(motif.motif()).count()
+    motif.offsets_in_kmer(kmer).count()
+}
+
+/// Genomic position where each motif occurrence starts, found at any offset
+/// within the signal kmers so motifs are scored even if the kmer starting at
+/// the motif has no data. Each occurrence is paired with the signal where it is
+/// closest to the start of the kmer.
+fn motif_starts<'a, 'm, I>(signals: I, motifs: &'m [Motif]) -> Vec<(u64, &'m Motif, &'a Signal)>
+where
+    I: IntoIterator<Item = &'a Signal>,
+{
+    let mut starts: BTreeMap<u64, (usize, &Motif, &Signal)> = BTreeMap::new();
+    for signal in signals {
+        for motif in motifs {
+            for offset in motif.offsets_in_kmer(&signal.kmer) {
+                let start = signal.pos + offset as u64;
+                match starts.get(&start) {
+                    Some(&(closest, _, _)) if closest <= offset => (),
+                    _ => {
+                        starts.insert(start, (offset, motif, signal));
+                    }
+                }
+            }
+        }
+    }
+    starts
+        .into_iter()
+        .map(|(start, (_, motif, signal))| (start, motif, signal))
+        .collect()
+}
+
+/// Kmer starting at a motif found in signal, so the score is written with the
+/// motif at the start of its kmer like the scores from cawlr score. The kmer is
+/// put together from the signals overlapping the rest of it, and is shorter if
+/// the read has no signal for those bases.
+fn kmer_at(signals: &FnvHashMap<u64, &Signal>, signal: &Signal, pos: u64) -> String {
+    let k = signal.kmer.len();
+    let mut kmer = signal.kmer[(pos - signal.pos) as usize..].to_string();
+    let mut next = signal.pos + 1;
+    while kmer.len() < k && next <= pos + kmer.len() as u64 {
+        if let Some(s) = signals.get(&next) {
+            let from = (pos + kmer.len() as u64 - next) as usize;
+            kmer.push_str(s.kmer.get(from..).unwrap_or_default());
+            kmer.truncate(k);
+        }
+        next += 1;
+    }
+    kmer
 }
 
 #[derive(Debug)]
 struct SignalScore<'a> {
     signal: &'a Signal,
-    pos_sum: f64,
-    neg_sum: f64,
+    rate: f64,
 }
 
 impl<'a> SignalScore<'a> {
-    fn new(signal: &'a Signal, pos_sum: f64, neg_sum: f64) -> Self {
-        Self {
-            signal,
-            pos_sum,
-            neg_sum,
-        }
+    fn new(signal: &'a Signal, rate: f64) -> Self {
+        Self { signal, rate }
     }
 }
 
@@ -62,18 +188,23 @@ impl ScoreOptions {
     pub fn new(
         pos_model: Model,
         neg_model: Model,
-        ranks: FnvHashMap<String, f64>,
+        ranks: Ranks,
         freq_thresh: usize,
         cutoff: f64,
         motifs: Vec<Motif>,
     ) -> Self {
         Self {
-            pos_model,
-            neg_model,
+            models: vec![(pos_model, neg_model)],
+            ensemble: Ensemble::Mean,
             ranks,
             freq_thresh,
             cutoff,
             motifs,
+            skips: false,
+            haplotype_output: None,
+            compression: ArrowCompression::default(),
+            batch_size: None,
+            progress_sink: None,
         }
     }
 
@@ -83,12 +214,58 @@ impl ScoreOptions {
     {
         let pos_model = Model::load(pos_model_filepath)?;
         let neg_model = Model::load(neg_model_filepath)?;
-        let ranks = FnvHashMap::load(ranks_filepath)?;
+        let ranks = Ranks::load(ranks_filepath)?;
         let score_options = ScoreOptions::new(pos_model, neg_model, ranks, 10, 10.0, all_bases());
         log::debug!("Score Options: {score_options:?}");
         Ok(score_options)
     }
 
+    /// Load multiple positive and negative control model pairs, ie from
+    /// replicate trainings, and score with an ensemble of them.
+    pub fn load_ensemble<P>(
+        pos_model_filepaths: &[P],
+        neg_model_filepaths: &[P],
+        ranks_filepath: P,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        if pos_model_filepaths.is_empty() || pos_model_filepaths.len() != neg_model_filepaths.len()
+        {
+            return Err(eyre::eyre!(
+                "Need the same number of positive and negative models, got {} and {}",
+                pos_model_filepaths.len(),
+                neg_model_filepaths.len()
+            ));
+        }
+        let mut models = Vec::new();
+        for (pos, neg) in pos_model_filepaths.iter().zip(neg_model_filepaths) {
+            models.push((Model::load(pos)?, Model::load(neg)?));
+        }
+        let ranks = Ranks::load(ranks_filepath)?;
+        let (pos_model, neg_model) = models.remove(0);
+        let mut score_options =
+            ScoreOptions::new(pos_model, neg_model, ranks, 10, 10.0, all_bases());
+        for (pos_model, neg_model) in models {
+            score_options.add_models(pos_model, neg_model);
+        }
+        log::debug!("Score Options: {score_options:?}");
+        Ok(score_options)
+    }
+
+    /// Add another pair of positive and negative models to the ensemble
+    pub fn add_models(&mut self, pos_model: Model, neg_model: Model) -> &mut Self {
+        self.models.push((pos_model, neg_model));
+        self
+    }
+
+    /// How to combine scores when more than one pair of models is used,
+    /// defaults to averaging the log-likelihoods
+    pub fn ensemble(&mut self, ensemble: Ensemble) -> &mut Self {
+        self.ensemble = ensemble;
+        self
+    }
+
     pub fn freq_thresh(&mut self, freq_thresh: usize) -> &mut Self {
         self.freq_thresh = freq_thresh;
         self
@@ -104,12 +281,65 @@ impl ScoreOptions {
         self
     }
 
-    pub fn run<R, W>(&self, reader: R, writer: W) -> Result<()>
+    /// Also use how often the kmer had signal data in each control, from the
+    /// models trained with cawlr train, when computing the rate. Kmers without
+    /// skip frequencies are scored on signal alone.
+    pub fn skips(&mut self, skips: bool) -> &mut Self {
+        self.skips = skips;
+        self
+    }
+
+    /// Also write reads to a separate Arrow file for each haplotype, recorded
+    /// by cawlr collapse from the HP tags of a phased bam file. Files are
+    /// written next to output, ie scores.hp1.arrow and scores.hp2.arrow for
+    /// scores.arrow.
+    pub fn split_haplotypes<P: AsRef<Path>>(&mut self, output: P) -> &mut Self {
+        self.haplotype_output = Some(output.as_ref().to_path_buf());
+        self
+    }
+
+    /// Codec of the output and the outputs of each haplotype, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Load at most this many reads at a time, see
+    /// [load_apply_batched](crate::arrow::arrow_utils::load_apply_batched)
+    pub fn batch_size(&mut self, batch_size: Option<usize>) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is scored
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    pub fn run<R, W>(&self, mut reader: R, writer: W) -> Result<()>
     where
         R: Read + Seek,
         W: Write,
     {
-        load_read_write_arrow(reader, writer, |eventaligns: Vec<Eventalign>| {
+        if !has_samples(&mut reader)? {
+            return Err(eyre::eyre!(
+                "npsmlr scoring needs raw samples, collapse the input again with --samples"
+            ));
+        }
+        let mode = read_mode(&mut reader)?;
+        for (pos_ctrl, neg_ctrl) in self.models.iter() {
+            pos_ctrl.check_mode(mode)?;
+            neg_ctrl.check_mode(mode)?;
+        }
+        let mut reporter = Reporter::new(Stage::Score, self.progress_sink.clone());
+        reporter.total_chunks(n_chunks(&mut reader)?);
+        let mut haplotype_writers = self
+            .haplotype_output
+            .as_ref()
+            .map(|output| HaplotypeWriters::new(output, self.compression));
+        let score_chunk = |eventaligns: Vec<Eventalign>| -> Result<Vec<ScoredRead>> {
+            cancel::check()?;
             let mut scored_reads = Vec::new();
             for eventalign in eventaligns {
                 log::debug!("eventalign: {:?}", eventalign.metadata());
@@ -118,84 +348,229 @@ impl ScoreOptions {
                     .signal_iter()
                     .map(|s| (s.pos, s))
                     .collect::<FnvHashMap<_, _>>();
-                for signal in eventalign.signal_iter() {
-                    log::debug!("signal {signal:?}");
-                    let kmer = &signal.kmer;
-                    if let Some(m) = self.motifs.iter().find(|m| kmer.starts_with(m.motif())) {
-                        log::debug!("Kmer motif matches {m:?}");
-                        let mut kmers = Vec::new();
-                        let surrounding = m.surrounding_idxs(signal.pos);
-                        for surr in surrounding {
-                            log::debug!("Surrounding idx {surr}");
-                            if let Some(&s) = data_map.get(&surr) {
-                                log::debug!("Surrounding signal: {s:?}");
-                                if signal.samples.len() > self.freq_thresh {
-                                    log::debug!(
-                                        "n samples greater than frequency threshold, skipping"
-                                    );
-                                    continue;
-                                }
-
-                                let kmer = &s.kmer;
-                                if count_motif_in_kmer(kmer, m) > 1 {
-                                    log::debug!("Count of motifs in kmer greater than 1, skipping");
-                                    continue;
-                                }
-                                let pm = self.pos_model.gmms().get(kmer);
-                                let nm = self.neg_model.gmms().get(kmer);
-                                if let (Some(pm), Some(nm)) = (pm, nm) {
-                                    let pos_model = pm.mixture();
-                                    let neg_model = nm.single();
-
-                                    if let Some((pos_sum, neg_sum)) =
-                                        s.score_lnsum(&pos_model, &neg_model)
-                                    {
-                                        kmers.push(SignalScore::new(s, pos_sum, neg_sum));
+                for (pos, m, signal) in motif_starts(eventalign.signal_iter(), &self.motifs) {
+                    log::debug!("Motif {m} starts at {pos}, found in signal {signal:?}");
+                    let mut kmers = Vec::new();
+                    let surrounding = m.surrounding_idxs(pos);
+                    for surr in surrounding {
+                        log::debug!("Surrounding idx {surr}");
+                        if let Some(&s) = data_map.get(&surr) {
+                            log::debug!("Surrounding signal: {s:?}");
+                            if signal.samples.len() > self.freq_thresh {
+                                log::debug!("n samples greater than frequency threshold, skipping");
+                                continue;
+                            }
+
+                            let kmer = &s.kmer;
+                            if count_motif_in_kmer(kmer, m) > 1 {
+                                log::debug!("Count of motifs in kmer greater than 1, skipping");
+                                continue;
+                            }
+                            let lnsums = self
+                                .models
+                                .iter()
+                                .filter_map(|(pm, nm)| {
+                                    let pos_model = pm.gmms().get(kmer)?.mixture();
+                                    let neg_model = nm.gmms().get(kmer)?.single();
+                                    let (pos_sum, neg_sum) =
+                                        s.score_lnsum(&pos_model, &neg_model)?;
+                                    match presence_lnprobs(pm, nm, kmer) {
+                                        Some((pos_ln, neg_ln)) if self.skips => {
+                                            Some((pos_sum + pos_ln, neg_sum + neg_ln))
+                                        }
+                                        _ => Some((pos_sum, neg_sum)),
                                     }
-                                }
+                                })
+                                .collect::<Vec<_>>();
+                            if let Some(rate) = self.ensemble.combine(&lnsums) {
+                                kmers.push(SignalScore::new(s, rate));
                             }
                         }
-                        let mut best_signal = None;
-                        let mut diff = f64::NEG_INFINITY;
-                        for ss in kmers.into_iter() {
-                            if let Some(&rank) = self.ranks.get(&ss.signal.kmer) {
-                                log::debug!("signal score: {ss:?}");
-                                if rank > diff {
-                                    diff = rank;
-                                    best_signal = Some(ss);
-                                }
+                    }
+                    let mut best_signal = None;
+                    let mut diff = f64::NEG_INFINITY;
+                    for ss in kmers.into_iter() {
+                        if let Some(&rank) = self.ranks.get(&ss.signal.kmer) {
+                            log::debug!("signal score: {ss:?}");
+                            if rank > diff {
+                                diff = rank;
+                                best_signal = Some(ss);
                             }
                         }
+                    }
 
-                        if let Some(best_signal) = best_signal {
-                            log::debug!("Best signal: {best_signal:?}");
-
-                            let exp_me = best_signal.pos_sum.exp();
-                            let exp_un = best_signal.neg_sum.exp();
-
-                            let rate = exp_me / (exp_me + exp_un);
+                    if let Some(best_signal) = best_signal {
+                        log::debug!("Best signal: {best_signal:?}");
 
-                            log::debug!("exp_me: {exp_me}");
-                            log::debug!("exp_un: {exp_un}");
-                            log::debug!("rate: {rate}");
+                        let rate = best_signal.rate;
+                        log::debug!("rate: {rate}");
 
-                            let score = Score::new(
-                                signal.pos,
-                                signal.kmer.to_string(),
-                                false,
-                                Some(rate),
-                                // 0.0,
-                                rate,
-                            );
-                            scores.push(score);
-                        }
+                        let score = Score::new(
+                            pos,
+                            kmer_at(&data_map, signal, pos).parse()?,
+                            false,
+                            Some(rate),
+                            // 0.0,
+                            rate,
+                        );
+                        scores.push(score);
                     }
                 }
                 let scored = ScoredRead::from_read_with_scores(eventalign, scores);
                 scored_reads.push(scored);
             }
+            reporter.chunk(scored_reads.len());
+            if let Some(haplotype_writers) = haplotype_writers.as_mut() {
+                haplotype_writers.save(&scored_reads)?;
+            }
             Ok(scored_reads)
+        };
+        load_read_write_arrow_with(
+            reader,
+            writer,
+            self.compression,
+            self.batch_size,
+            score_chunk,
+        )?;
+        if let Some(haplotype_writers) = haplotype_writers {
+            haplotype_writers.finish()?;
+        }
+        reporter.finish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use rv::dist::{Gaussian, Mixture};
+
+    use super::*;
+    use crate::{
+        arrow::{
+            arrow_utils::load_apply,
+            kmer::Kmer,
+            metadata::{MetadataExt, Strand},
+        },
+        collapse::CollapseOptions,
+        test_data::{chrom_seq, MiniGenome},
+    };
+
+    #[test]
+    fn test_presence_lnprobs() {
+        let mut pos_ctrl = Model::default();
+        let mut neg_ctrl = Model::default();
+        assert_eq!(presence_lnprobs(&pos_ctrl, &neg_ctrl, "AAAAAA"), None);
+
+        pos_ctrl.insert_skip("AAAAAA".parse().unwrap(), 0.5);
+        neg_ctrl.insert_skip("AAAAAA".parse().unwrap(), 1.0);
+        let (pos_ln, neg_ln) = presence_lnprobs(&pos_ctrl, &neg_ctrl, "AAAAAA").unwrap();
+        assert!((pos_ln - 0.5f64.ln()).abs() < 1e-9);
+        assert_eq!(neg_ln, 0.0);
+        // Presence is less likely in the positive control, so rate goes down
+        assert!(lnsum_to_rate(pos_ln, neg_ln) < 0.5);
+
+        neg_ctrl.insert_skip("AAAAAA".parse().unwrap(), 0.0);
+        assert_eq!(presence_lnprobs(&pos_ctrl, &neg_ctrl, "AAAAAA"), None);
+    }
+
+    #[test]
+    fn test_motif_starts() {
+        let signals = [
+            Signal::new(100, "GGTAGG".to_string(), 90.0, 0.01, vec![90.0]),
+            Signal::new(101, "GTAGGC".to_string(), 90.0, 0.01, vec![90.0]),
+            Signal::new(104, "GGCCTA".to_string(), 90.0, 0.01, vec![90.0]),
+        ];
+        let motifs = vec!["1:TA".parse::<Motif>().unwrap()];
+        let starts = motif_starts(&signals, &motifs)
+            .into_iter()
+            .map(|(pos, _, signal)| (pos, signal.pos))
+            .collect::<Vec<_>>();
+        // TA at 102 is closest to the start of the kmer at 101, and TA at 108 has
+        // no kmer starting at it
+        assert_eq!(starts, [(102, 101), (108, 104)]);
+    }
+
+    #[test]
+    fn test_kmer_at() {
+        let signals = [
+            Signal::new(100, "GGTAGG".to_string(), 90.0, 0.01, vec![90.0]),
+            Signal::new(101, "GTAGGC".to_string(), 90.0, 0.01, vec![90.0]),
+            Signal::new(104, "GGCCTA".to_string(), 90.0, 0.01, vec![90.0]),
+        ];
+        let data_map = signals
+            .iter()
+            .map(|s| (s.pos, s))
+            .collect::<FnvHashMap<_, _>>();
+        assert_eq!(kmer_at(&data_map, &signals[0], 100), "GGTAGG");
+        assert_eq!(kmer_at(&data_map, &signals[1], 102), "TAGGCC");
+        // No signal covers the base after 109
+        assert_eq!(kmer_at(&data_map, &signals[2], 108), "TA");
+    }
+
+    #[test]
+    fn test_score_kmers_match_motif() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        CollapseOptions::try_new(mini.bam(), &collapsed)?.run(File::open(mini.eventalign())?)?;
+
+        let mut pos_model = Model::default();
+        let mut neg_model = Model::default();
+        let mut ranks = Ranks::default();
+        load_apply(File::open(&collapsed)?, |reads: Vec<Eventalign>| {
+            for signal in reads.iter().flat_map(|r| r.signal_iter()) {
+                let kmer: Kmer = signal.kmer.parse()?;
+                let mixture = |mu| {
+                    let components = vec![Gaussian::new(mu, 1.0).unwrap(); 2];
+                    Mixture::new_unchecked(vec![0.5, 0.5], components)
+                };
+                pos_model.insert_gmm(kmer, mixture(signal.signal_mean + 0.5));
+                neg_model.insert_gmm(kmer, mixture(signal.signal_mean));
+                ranks.insert(kmer, 1.0);
+            }
+            Ok(())
+        })?;
+
+        let motifs = vec!["2:GC".parse::<Motif>()?];
+        let scored = mini.dir().join("scored");
+        ScoreOptions::new(pos_model, neg_model, ranks, 10, 10.0, motifs.clone())
+            .run(File::open(&collapsed)?, File::create(&scored)?)?;
+
+        let mut n_scores = 0;
+        load_apply(File::open(&scored)?, |reads: Vec<ScoredRead>| {
+            for read in reads.iter() {
+                for score in read.scores() {
+                    // Same check cawlr sma uses to keep scores
+                    assert!(motifs[0].matches_score_kmer(score.kmer.as_bytes()));
+                    if read.strand() == Strand::plus() {
+                        let seq = chrom_seq(read.chrom());
+                        let start = score.pos as usize;
+                        assert_eq!(*score.kmer, seq[start..start + score.kmer.len()]);
+                    }
+                    n_scores += 1;
+                }
+            }
+            Ok(())
         })?;
+        assert!(n_scores > 0);
         Ok(())
     }
+
+    #[test]
+    fn test_ensemble_combine() {
+        assert_eq!(Ensemble::Mean.combine(&[]), None);
+        assert_eq!(Ensemble::Vote.combine(&[]), None);
+
+        let single = [(-1.0, -2.0)];
+        let rate = lnsum_to_rate(-1.0, -2.0);
+        assert_eq!(Ensemble::Mean.combine(&single), Some(rate));
+        assert_eq!(Ensemble::Vote.combine(&single), Some(1.0));
+
+        let lnsums = [(-1.0, -3.0), (-3.0, -1.0), (-1.0, -2.0)];
+        let mean = Ensemble::Mean.combine(&lnsums).unwrap();
+        assert!((mean - lnsum_to_rate(-5.0 / 3.0, -2.0)).abs() < 1e-12);
+        let vote = Ensemble::Vote.combine(&lnsums).unwrap();
+        assert!((vote - 2.0 / 3.0).abs() < 1e-12);
+    }
 }
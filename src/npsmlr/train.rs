@@ -1,24 +1,36 @@
 use std::{
     collections::HashMap,
+    hash::Hasher,
     io::{Read, Seek, Write},
     path::{Path, PathBuf},
 };
 
 use eyre::Result;
+use fnv::FnvHasher;
 use linfa::{
     traits::{Fit, Transformer},
     DatasetBase, ParamGuard,
 };
 use linfa_clustering::{Dbscan, GaussianMixtureModel};
 use ndarray::Array;
-use rusqlite::{named_params, Connection};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rusqlite::{named_params, Connection, OpenFlags};
 use rv::prelude::{Gaussian, Mixture};
+use tempfile::TempDir;
 
 use crate::{
-    arrow::{arrow_utils::load_read_arrow_measured, eventalign::Eventalign, metadata::MetadataExt},
+    arrow::{
+        arrow_utils::{has_samples, load_read_arrow_measured, read_mode},
+        eventalign::Eventalign,
+        metadata::MetadataExt,
+    },
+    cancel,
+    filter::RegionFilter,
     motif::{all_bases, Motif},
-    train::{mix_to_mix, Model},
-    utils::CawlrIO,
+    repro,
+    train::{mix_to_mix, report_degenerate, KmerThresholds, Model},
+    utils::{self, CawlrIO},
     validated::{self, ValidSampleData},
 };
 
@@ -29,6 +41,11 @@ pub struct TrainOptions {
     dbscan: bool,
     motifs: Vec<Motif>,
     db_path: Option<PathBuf>,
+    thresholds: KmerThresholds,
+    regions: RegionFilter,
+    batch_size: Option<usize>,
+    progress: bool,
+    deterministic: bool,
 }
 
 impl Default for TrainOptions {
@@ -39,6 +56,11 @@ impl Default for TrainOptions {
             dbscan: false,
             motifs: all_bases(),
             db_path: None,
+            thresholds: KmerThresholds::default(),
+            regions: RegionFilter::default(),
+            batch_size: None,
+            progress: false,
+            deterministic: false,
         }
     }
 }
@@ -86,6 +108,39 @@ impl TrainOptions {
         self
     }
 
+    /// Checks kmers need to pass to be in the model, see [KmerThresholds]
+    pub fn thresholds(mut self, thresholds: KmerThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Only train on reads in these regions, see [RegionFilter]
+    pub fn regions(mut self, regions: RegionFilter) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Load at most this many reads at a time, see
+    /// [load_apply_batched](crate::arrow::arrow_utils::load_apply_batched)
+    pub fn batch_size(mut self, batch_size: Option<usize>) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Draw a bar of the chunks of reads loaded so far on stderr
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Sample the training data of each kmer with a fixed seed instead of
+    /// sqlite's RANDOM(), so the same input always gives the same model, see
+    /// [repro::SQLITE_SAMPLE_SEED]
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
     pub fn run<R, W>(self, input: R, mut writer: W) -> Result<()>
     where
         R: Read + Seek,
@@ -96,45 +151,97 @@ impl TrainOptions {
         Ok(())
     }
 
-    pub fn run_model<R>(self, input: R) -> Result<Model>
+    pub fn run_model<R>(self, mut input: R) -> Result<Model>
     where
         R: Read + Seek,
     {
         log::info!("{self:?}");
-        let db_path = {
-            match &self.db_path {
-                Some(db_path) => db_path.clone(),
-                None => std::env::temp_dir().join("npsmlr.db"),
-            }
+        if !has_samples(&mut input)? {
+            return Err(eyre::eyre!(
+                "Training needs raw samples, collapse the input again with --samples"
+            ));
+        }
+        let mode = read_mode(&mut input)?;
+        let mut db = match &self.db_path {
+            Some(db_path) => Db::open(db_path)?,
+            None => Db::open_temp(utils::temp_dir()?)?,
         };
-        let mut db = Db::open(db_path)?;
         log::debug!("Database: {db:?}");
-        load_read_arrow_measured(input, |eventaligns: Vec<Eventalign>| {
+        let mut n_filtered = 0usize;
+        let add_reads = |mut eventaligns: Vec<Eventalign>| -> Result<()> {
+            cancel::check()?;
+            if !self.regions.is_empty() {
+                let n_reads = eventaligns.len();
+                eventaligns.retain(|eventalign| self.regions.keep(eventalign));
+                n_filtered += n_reads - eventaligns.len();
+            }
             db.add_reads(eventaligns, &self.motifs)?;
             Ok(())
-        })?;
+        };
+        load_read_arrow_measured(input, self.batch_size, self.progress, add_reads)?;
+        if !self.regions.is_empty() {
+            log::info!("Skipped {n_filtered} reads outside of training regions");
+        }
 
-        self.train_gmms(db)
+        let mut model = self.train_gmms(db)?;
+        model.set_mode(mode);
+        Ok(model)
     }
 
+    /// Kmers are trained in parallel, each thread reading samples through its
+    /// own connection to the database
     fn train_gmms(&self, db: Db) -> Result<Model> {
-        let mut model = Model::default();
-        for kmer in all_kmers() {
-            log::info!("Training on kmer {kmer}");
-            let samples = db.get_kmer_samples(&kmer, self.n_samples)?;
-            log::info!("n samples: {}", samples.len());
-            if let Some(validated) = validated::ValidSampleData::validated(samples) {
-                match self.train_gmm(validated) {
-                    Ok(gmm) => {
-                        log::info!("Training successful!");
-                        model.insert_gmm(kmer, gmm);
+        let db_path = db.path.clone();
+        let gmms = all_kmers()
+            .into_par_iter()
+            .map_init(
+                || Db::reader(&db_path),
+                |reader, kmer| {
+                    cancel::check()?;
+                    let reader = reader.as_ref().map_err(|e| eyre::eyre!("{e}"))?;
+                    log::info!("Training on kmer {kmer}");
+                    let samples =
+                        get_kmer_samples(reader, &kmer, self.n_samples, self.deterministic)?;
+                    log::info!("n samples: {}", samples.len());
+                    // Kmers without any samples aren't in the reads, ie from
+                    // other motifs, so they aren't reported
+                    if !samples.is_empty() {
+                        if let Err(reason) = self.thresholds.check_samples(samples.len()) {
+                            return Ok(Some(Err((kmer, reason))));
+                        }
                     }
-                    Err(e) => {
-                        log::warn!("kmer {kmer} failed to train with error {e}");
+                    let validated = match validated::ValidSampleData::validated(samples) {
+                        Some(validated) => validated,
+                        None => return Ok(None),
+                    };
+                    match self.train_gmm(validated) {
+                        Ok(gmm) => match self.thresholds.check_fit(&gmm) {
+                            Ok(()) => {
+                                log::info!("Training successful for kmer {kmer}");
+                                Ok(Some(Ok((kmer.parse()?, gmm))))
+                            }
+                            Err(reason) => Ok(Some(Err((kmer, reason)))),
+                        },
+                        Err(e) => {
+                            log::warn!("kmer {kmer} failed to train with error {e}");
+                            Ok(None)
+                        }
                     }
-                }
+                },
+            )
+            .collect::<Result<Vec<_>>>()?;
+        // Keep the database, and its temporary file, until every reader is done
+        drop(db);
+
+        let mut model = Model::default();
+        let mut degenerate = Vec::new();
+        for res in gmms.into_iter().flatten() {
+            match res {
+                Ok((kmer, gmm)) => model.insert_gmm(kmer, gmm),
+                Err(skipped) => degenerate.push(skipped),
             }
         }
+        report_degenerate(&degenerate);
         if model.gmms().is_empty() {
             Err(eyre::eyre!("Not gmms trained due to error. Check logs"))
         } else {
@@ -191,6 +298,12 @@ impl TrainOptions {
         let n_clusters = if self.single { 1 } else { 2 };
         let n_runs = 10;
         let tolerance = 1e-4f64;
+        let params = serde_json::json!({
+            "n_clusters": n_clusters,
+            "n_runs": n_runs,
+            "tolerance": tolerance,
+        });
+        repro::record_seed("npsmlr train gmm init", repro::GMM_SEED, params);
         let gmm = GaussianMixtureModel::params(n_clusters)
             .n_runs(n_runs)
             .tolerance(tolerance)
@@ -204,8 +317,11 @@ impl TrainOptions {
 #[derive(Debug)]
 struct Db {
     limit: usize,
+    path: PathBuf,
     connection: Connection,
     counts: HashMap<String, usize>,
+    // Declared after connection so the file is removed once it is closed
+    temp_dir: Option<TempDir>,
 }
 
 impl Db {
@@ -216,14 +332,25 @@ impl Db {
         }
         let db = Db {
             limit: 50000,
+            path: path.to_path_buf(),
             connection: Connection::open(path)?,
             counts: Default::default(),
+            temp_dir: None,
         };
         db.init()?;
         db.create_idx()?;
         Ok(db)
     }
 
+    /// Open a database in the temporary directory, which is deleted along
+    /// with the database's write-ahead log when training finishes or is
+    /// cancelled
+    fn open_temp(temp_dir: TempDir) -> eyre::Result<Self> {
+        let mut db = Db::open(temp_dir.path().join("npsmlr.db"))?;
+        db.temp_dir = Some(temp_dir);
+        Ok(db)
+    }
+
     fn init(&self) -> eyre::Result<()> {
         self.connection.execute(
             "CREATE TABLE data (
@@ -256,7 +383,7 @@ impl Db {
                 log::debug!("Processing signal kmer: {kmer}");
 
                 // Skip if kmer doesn't match any of the kmers
-                if !motifs.iter().any(|m| kmer.starts_with(m.motif())) {
+                if !motifs.iter().any(|m| m.matches_at(kmer, 0)) {
                     log::debug!("Kmer skipped, doesn't match any motifs");
                     continue;
                 }
@@ -278,19 +405,69 @@ impl Db {
         Ok(())
     }
 
-    fn get_kmer_samples(&self, kmer: &str, n_samples: usize) -> eyre::Result<Vec<f64>> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT sample FROM data where kmer = :kmer ORDER BY RANDOM() LIMIT :n")?;
-        let rows = stmt.query_map(named_params! {":kmer": kmer, ":n": n_samples}, |row| {
-            row.get::<usize, f64>(0)
-        })?;
-        let mut samples = Vec::new();
-        for sample in rows {
-            samples.push(sample?)
+    /// Read-only connection for training on another thread, since connections
+    /// can't be shared between threads
+    fn reader(path: &Path) -> rusqlite::Result<Connection> {
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+}
+
+/// Random subset of the samples for a kmer, see [get_kmer_samples_seeded] for
+/// deterministic mode
+fn get_kmer_samples(
+    connection: &Connection,
+    kmer: &str,
+    n_samples: usize,
+    deterministic: bool,
+) -> eyre::Result<Vec<f64>> {
+    if deterministic {
+        return get_kmer_samples_seeded(connection, kmer, n_samples);
+    }
+    let mut stmt = connection
+        .prepare("SELECT sample FROM data where kmer = :kmer ORDER BY RANDOM() LIMIT :n")?;
+    let rows = stmt.query_map(named_params! {":kmer": kmer, ":n": n_samples}, |row| {
+        row.get::<usize, f64>(0)
+    })?;
+    let mut samples = Vec::new();
+    for sample in rows {
+        samples.push(sample?)
+    }
+    Ok(samples)
+}
+
+/// Random subset of the samples for a kmer with a fixed seed for each kmer, so
+/// the subset doesn't depend on the order kmers are trained in. Samples are
+/// read in the order they were added and kept with a reservoir sample, so at
+/// most n_samples are in memory.
+fn get_kmer_samples_seeded(
+    connection: &Connection,
+    kmer: &str,
+    n_samples: usize,
+) -> eyre::Result<Vec<f64>> {
+    let params = serde_json::json!({ "n_samples": n_samples });
+    repro::record_seed("npsmlr train sampling", repro::SQLITE_SAMPLE_SEED, params);
+    let mut hasher = FnvHasher::default();
+    hasher.write(kmer.as_bytes());
+    let mut rng = SmallRng::seed_from_u64(repro::SQLITE_SAMPLE_SEED ^ hasher.finish());
+
+    let mut stmt =
+        connection.prepare("SELECT sample FROM data where kmer = :kmer ORDER BY rowid")?;
+    let rows = stmt.query_map(named_params! {":kmer": kmer}, |row| {
+        row.get::<usize, f64>(0)
+    })?;
+    let mut samples = Vec::with_capacity(n_samples);
+    for (idx, sample) in rows.enumerate() {
+        let sample = sample?;
+        if idx < n_samples {
+            samples.push(sample);
+        } else {
+            let replace = rng.gen_range(0..=idx);
+            if replace < n_samples {
+                samples[replace] = sample;
+            }
         }
-        Ok(samples)
     }
+    Ok(samples)
 }
 
 #[cfg(test)]
@@ -310,6 +487,22 @@ mod test {
         assert!(opts.train_gmms(db).is_err());
     }
 
+    #[test]
+    fn test_temp_db_removed() {
+        let tmp_dir = TempDir::new().unwrap();
+        let kept_path = tmp_dir.join("kept.db");
+        let db_dir = tempfile::TempDir::new_in(tmp_dir.path()).unwrap();
+        let db_path = db_dir.path().join("npsmlr.db");
+        let db = Db::open_temp(db_dir).expect("Failed to open database file");
+        let kept = Db::open(&kept_path).expect("Failed to open database file");
+        assert!(db_path.exists());
+        drop(db);
+        drop(kept);
+        assert!(!db_path.exists());
+        assert!(!db_path.parent().unwrap().exists());
+        assert!(kept_path.exists());
+    }
+
     #[test]
     fn test_all_kmers() {
         let kmers = all_kmers();
@@ -324,9 +517,8 @@ mod test {
         let eventalign = Eventalign::default();
         db.add_reads(vec![eventalign], &all_bases())
             .expect("Unable to add read");
-        let samples = db
-            .get_kmer_samples("ABCDEF", 5000)
-            .expect("Unable to get samples");
+        let samples =
+            get_kmer_samples(&db.connection, "ABCDEF", 5000, false).expect("Unable to get samples");
         assert!(samples.is_empty());
     }
     #[test]
@@ -350,7 +542,7 @@ mod test {
 
         for (k, xs, unfiltered) in test_cases.into_iter() {
             let err_msg = format!("Unable to retrieve kmer values for {k}");
-            let samples = db.get_kmer_samples(k, 5000).expect(&err_msg);
+            let samples = get_kmer_samples(&db.connection, k, 5000, false).expect(&err_msg);
             if unfiltered {
                 assert_eq!(samples, xs);
             } else {
@@ -359,6 +551,30 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_db_seeded_samples() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut db = Db::open(tmp_dir.join("test.db")).expect("Failed to open database file");
+        let xs = (0..10).map(|x| x as f64).collect::<Vec<_>>();
+        let mut eventalign = Eventalign::default();
+        *eventalign.signal_data_mut() =
+            vec![Signal::new(0, "AAAAAA".to_string(), 1.0, 0.5, xs.clone())];
+        db.add_reads(vec![eventalign], &all_bases())
+            .expect("Unable to add read");
+
+        let samples = get_kmer_samples_seeded(&db.connection, "AAAAAA", 4).unwrap();
+        assert_eq!(samples.len(), 4);
+        assert!(samples.iter().all(|x| xs.contains(x)));
+        let mut distinct = samples.clone();
+        distinct.sort_by(f64::total_cmp);
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+        let again = get_kmer_samples_seeded(&db.connection, "AAAAAA", 4).unwrap();
+        assert_eq!(samples, again);
+        let all = get_kmer_samples_seeded(&db.connection, "AAAAAA", 20).unwrap();
+        assert_eq!(all, xs);
+    }
+
     #[test]
     fn test_db_count() {
         let tmp_dir = TempDir::new().unwrap();
@@ -412,7 +628,7 @@ mod test {
 
         for (k, xs, unfiltered) in test_cases.into_iter() {
             let err_msg = format!("Unable to retrieve kmer values for {k}");
-            let samples = db.get_kmer_samples(k, 5000).expect(&err_msg);
+            let samples = get_kmer_samples(&db.connection, k, 5000, false).expect(&err_msg);
             if unfiltered {
                 assert_eq!(samples, xs);
             } else {
@@ -0,0 +1,636 @@
+//! Per-position counts of modified, unmodified, and no-call reads, the bulk
+//! counterpart to single molecule calls from cawlr sma.
+//!
+//! The inputs counted are listed next to the output in a `.sources` file, ie
+//! pileup.bed.sources for pileup.bed, so a table can be updated with new data
+//! for the same sample without counting a file twice. They are kept out of the
+//! output itself so bedMethyl output can be read by tools that take modkit
+//! output.
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use eyre::{Result, WrapErr};
+
+use crate::{
+    arrow::{
+        io::{read_mod_bam_or_arrow, ModFile},
+        metadata::MetadataExt,
+        scored_read::ScoredRead,
+    },
+    haplotype::HaplotypeOutputs,
+    utils::{create_output, open_maybe_gz, stdout_or_file},
+};
+
+/// File format written by cawlr pileup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PileupFormat {
+    /// Tab-separated counts with a header
+    Tsv,
+    /// bedMethyl with the same columns as modkit pileup, for comparing with
+    /// other modification callers. No-calls are counted as failed calls, and
+    /// the columns cawlr has no counts for are 0.
+    BedMethyl,
+}
+
+impl Default for PileupFormat {
+    fn default() -> Self {
+        PileupFormat::Tsv
+    }
+}
+
+impl FromStr for PileupFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(PileupFormat::Tsv),
+            "bedmethyl" => Ok(PileupFormat::BedMethyl),
+            _ => Err(format!(
+                "Invalid output format \"{s}\", expected either tsv or bedmethyl"
+            )),
+        }
+    }
+}
+
+/// Calls at a single position on one strand
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PileupCounts {
+    pub n_modified: u64,
+    pub n_unmodified: u64,
+    pub n_no_call: u64,
+}
+
+impl PileupCounts {
+    /// Reads with a modified or unmodified call
+    pub fn n_valid(&self) -> u64 {
+        self.n_modified + self.n_unmodified
+    }
+
+    /// Fraction of valid calls that are modified, None if there are none
+    pub fn fraction_modified(&self) -> Option<f64> {
+        let n_valid = self.n_valid();
+        if n_valid == 0 {
+            None
+        } else {
+            Some(self.n_modified as f64 / n_valid as f64)
+        }
+    }
+}
+
+/// Counts keyed by chromosome, then position and strand
+pub type Pileup = BTreeMap<String, BTreeMap<(u64, &'static str), PileupCounts>>;
+
+/// Sample of reads that weren't tagged by cawlr merge, when splitting by sample
+const NO_SAMPLE: &str = ".";
+
+/// What reads are counted under, see [PileupOptions::count_into]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Group {
+    All,
+    Sample,
+}
+
+/// File listing the inputs counted in a pileup, one per line
+fn sources_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".sources");
+    PathBuf::from(path)
+}
+
+fn parse_strand(strand: &str) -> Option<&'static str> {
+    match strand {
+        "+" => Some("+"),
+        "-" => Some("-"),
+        "." => Some("."),
+        _ => None,
+    }
+}
+
+/// Read counts from a previous cawlr pileup output, in either format, and the
+/// inputs counted in it if its sources file is there
+pub fn read_pileup(path: &Path) -> Result<(Pileup, Vec<String>)> {
+    let sources = match std::fs::read_to_string(sources_path(path)) {
+        Ok(sources) => sources.lines().map(String::from).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let reader = open_maybe_gz(path)?;
+    let mut pileup = Pileup::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("chrom\t") {
+            continue;
+        }
+        let invalid = || format!("Invalid pileup at line {}: {line}", line_no + 1);
+        let fields = line.split('\t').collect::<Vec<_>>();
+        // TSV has 7 columns, bedMethyl has the 18 columns of modkit with the
+        // modified and unmodified counts in 12 and 13 and failed calls in 16
+        let (pos, strand, counts) = match fields.len() {
+            7 => (fields[1], fields[2], [fields[3], fields[4], fields[5]]),
+            18 => (fields[1], fields[5], [fields[11], fields[12], fields[15]]),
+            _ => return Err(eyre::eyre!("Not from cawlr pileup")).wrap_err_with(invalid),
+        };
+        let strand = parse_strand(strand)
+            .ok_or_else(|| eyre::eyre!("Invalid strand"))
+            .wrap_err_with(invalid)?;
+        let pos = pos.parse::<u64>().wrap_err_with(invalid)?;
+        let counts = counts
+            .iter()
+            .map(|c| c.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err_with(invalid)?;
+        let entry = pileup
+            .entry(fields[0].to_string())
+            .or_default()
+            .entry((pos, strand))
+            .or_default();
+        entry.n_modified += counts[0];
+        entry.n_unmodified += counts[1];
+        entry.n_no_call += counts[2];
+    }
+    Ok((pileup, sources))
+}
+
+/// Counts calls at each genomic position and strand from cawlr score output or
+/// a modification bam.
+pub struct PileupOptions {
+    threshold: f64,
+    no_call_margin: f64,
+    min_coverage: u64,
+    mod_tag: Option<Vec<u8>>,
+    format: PileupFormat,
+    name: String,
+    update: Option<PathBuf>,
+    split_by_sample: bool,
+    split_haplotypes: bool,
+}
+
+impl Default for PileupOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            no_call_margin: 0.0,
+            min_coverage: 1,
+            mod_tag: None,
+            format: PileupFormat::default(),
+            name: "cawlr".to_string(),
+            update: None,
+            split_by_sample: false,
+            split_haplotypes: false,
+        }
+    }
+}
+
+impl PileupOptions {
+    /// Scores greater than the threshold are counted as modified, defaults to
+    /// 0.5
+    pub fn threshold(&mut self, threshold: f64) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Scores within the margin of the threshold are counted as no-calls
+    /// instead of modified or unmodified, defaults to 0
+    pub fn no_call_margin(&mut self, no_call_margin: f64) -> &mut Self {
+        self.no_call_margin = no_call_margin;
+        self
+    }
+
+    /// Positions with fewer reads, including no-calls, are left out, defaults
+    /// to 1
+    pub fn min_coverage(&mut self, min_coverage: u64) -> &mut Self {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Modification tag used if the input is a bam file, ie C+m
+    pub fn mod_tag<B: Into<Vec<u8>>>(&mut self, mod_tag: Option<B>) -> &mut Self {
+        self.mod_tag = mod_tag.map(|t| t.into());
+        self
+    }
+
+    pub fn format(&mut self, format: PileupFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Written in the name column of bedMethyl output
+    pub fn name<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Add counts to those in a previous cawlr pileup output of the same
+    /// sample, instead of starting from zero. Positions left out of that
+    /// output by --min-coverage can't be recovered, so tables meant to be
+    /// updated should keep every position.
+    pub fn update<P: Into<PathBuf>>(&mut self, existing: Option<P>) -> &mut Self {
+        self.update = existing.map(|p| p.into());
+        self
+    }
+
+    /// Count each sample tagged by cawlr merge separately. TSV output gets a
+    /// sample column after the strand and bedMethyl output uses the sample as
+    /// the name. Reads without a sample are counted under ".".
+    pub fn split_by_sample(&mut self, split_by_sample: bool) -> &mut Self {
+        self.split_by_sample = split_by_sample;
+        self
+    }
+
+    /// Also write the counts of each haplotype to their own output in the same
+    /// format, named after the output, ie pileup.hp1.tsv and pileup.hp2.tsv
+    /// for pileup.tsv. Haplotypes come from the HP tags of a phased bam file,
+    /// and reads without one are only counted in the main output.
+    pub fn split_haplotypes(&mut self, split_haplotypes: bool) -> &mut Self {
+        self.split_haplotypes = split_haplotypes;
+        self
+    }
+
+    fn count(&self, score: f64, counts: &mut PileupCounts) {
+        if score.is_nan() {
+            counts.n_no_call += 1;
+        } else if score > self.threshold + self.no_call_margin {
+            counts.n_modified += 1;
+        } else if score <= self.threshold - self.no_call_margin {
+            counts.n_unmodified += 1;
+        } else {
+            counts.n_no_call += 1;
+        }
+    }
+
+    /// Count calls on each strand at every scored position
+    pub fn pileup(&self, input: &Path) -> Result<Pileup> {
+        let mut pileups = BTreeMap::new();
+        self.count_into(input, Some(Group::All), &mut pileups, None)?;
+        Ok(pileups.remove("").unwrap_or_default())
+    }
+
+    /// Count calls separately for each sample tagged by cawlr merge, see
+    /// [PileupOptions::split_by_sample]
+    pub fn pileup_by_sample(&self, input: &Path) -> Result<BTreeMap<String, Pileup>> {
+        let mut pileups = BTreeMap::new();
+        self.count_into(input, Some(Group::Sample), &mut pileups, None)?;
+        Ok(pileups)
+    }
+
+    /// Count calls separately for each haplotype, leaving out reads without
+    /// one, see [PileupOptions::split_haplotypes]
+    pub fn pileup_by_haplotype(&self, input: &Path) -> Result<BTreeMap<i64, Pileup>> {
+        let mut haplotypes = BTreeMap::new();
+        self.count_into(input, None, &mut BTreeMap::new(), Some(&mut haplotypes))?;
+        Ok(haplotypes)
+    }
+
+    /// Count each read into the pileup for its group, keyed by an empty string
+    /// when counting every read together, and into the pileup of its haplotype
+    /// if there are haplotype pileups, in one pass over the input
+    fn count_into(
+        &self,
+        input: &Path,
+        group: Option<Group>,
+        pileups: &mut BTreeMap<String, Pileup>,
+        mut haplotypes: Option<&mut BTreeMap<i64, Pileup>>,
+    ) -> Result<()> {
+        let mod_file = ModFile::open_path(input, self.mod_tag.clone())?;
+        read_mod_bam_or_arrow(mod_file, |read| {
+            if read.is_unaligned() {
+                return Ok(());
+            }
+            let key = match group {
+                Some(Group::All) => Some(String::new()),
+                Some(Group::Sample) => Some(read.sample().unwrap_or(NO_SAMPLE).to_string()),
+                None => None,
+            };
+            if let Some(key) = key {
+                self.count_read(&read, pileups.entry(key).or_default());
+            }
+            if let (Some(haplotypes), Some(hp)) = (haplotypes.as_mut(), read.haplotype()) {
+                self.count_read(&read, haplotypes.entry(hp).or_default());
+            }
+            Ok(())
+        })
+    }
+
+    fn count_read(&self, read: &ScoredRead, pileup: &mut Pileup) {
+        let strand = read.strand().as_str();
+        let chrom_counts = pileup.entry(read.chrom().to_string()).or_default();
+        for score in read.scores() {
+            let counts = chrom_counts.entry((score.pos, strand)).or_default();
+            self.count(score.score, counts);
+        }
+    }
+
+    /// Write the counts of a sample, which is empty unless splitting by sample
+    fn write_pileup<W: Write>(&self, writer: &mut W, sample: &str, pileup: &Pileup) -> Result<()> {
+        let (sample_col, name) = if self.split_by_sample {
+            (format!("\t{sample}"), sample)
+        } else {
+            (String::new(), self.name.as_str())
+        };
+        for (chrom, chrom_counts) in pileup.iter() {
+            for (&(pos, strand), counts) in chrom_counts.iter() {
+                let coverage = counts.n_valid() + counts.n_no_call;
+                if coverage == 0 || coverage < self.min_coverage {
+                    continue;
+                }
+                match self.format {
+                    PileupFormat::Tsv => {
+                        let fraction = counts
+                            .fraction_modified()
+                            .map_or_else(|| "NA".to_string(), |f| f.to_string());
+                        writeln!(
+                            writer,
+                            "{chrom}\t{pos}\t{strand}{sample_col}\t{}\t{}\t{}\t{fraction}",
+                            counts.n_modified, counts.n_unmodified, counts.n_no_call
+                        )?;
+                    }
+                    PileupFormat::BedMethyl => {
+                        // Same columns as modkit, the valid coverage is also the
+                        // score. Other modifications, deletions, calls that
+                        // differ from the reference, and positions without a
+                        // call aren't counted.
+                        let n_valid = counts.n_valid();
+                        let percent = counts.fraction_modified().unwrap_or(0.0) * 100.0;
+                        writeln!(
+                            writer,
+                            "{chrom}\t{pos}\t{}\t{name}\t{n_valid}\t{strand}\t{pos}\t{}\t255,0,0\t{n_valid}\t{percent:.2}\t{}\t{}\t0\t0\t{}\t0\t0",
+                            pos + 1,
+                            pos + 1,
+                            counts.n_modified,
+                            counts.n_unmodified,
+                            counts.n_no_call,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write counts to output, or stdout if there is none
+    pub fn run<P: AsRef<Path>>(&self, input: &Path, output: Option<P>) -> Result<()> {
+        if self.split_by_sample && self.update.is_some() {
+            return Err(eyre::eyre!(
+                "Pileups split by sample can't be updated, count the merged file instead"
+            ));
+        }
+        if self.split_haplotypes && (self.split_by_sample || self.update.is_some()) {
+            return Err(eyre::eyre!(
+                "Pileups split by haplotype can't also be split by sample or updated"
+            ));
+        }
+        if self.split_haplotypes && output.is_none() {
+            return Err(eyre::eyre!("Splitting by haplotype needs an output file"));
+        }
+        let (pileup, mut sources) = match &self.update {
+            Some(existing) => read_pileup(existing)
+                .wrap_err_with(|| format!("Failed to read {}", existing.display()))?,
+            None => Default::default(),
+        };
+        let source = input
+            .canonicalize()
+            .unwrap_or_else(|_| input.to_path_buf())
+            .display()
+            .to_string();
+        if sources.contains(&source) {
+            return Err(eyre::eyre!(
+                "{source} was already counted in the pileup being updated"
+            ));
+        }
+        let mut pileups = BTreeMap::new();
+        let group = if self.split_by_sample {
+            Group::Sample
+        } else {
+            pileups.insert(String::new(), pileup);
+            Group::All
+        };
+        let mut haplotypes = BTreeMap::new();
+        let haplotype_pileups = self.split_haplotypes.then_some(&mut haplotypes);
+        self.count_into(input, Some(group), &mut pileups, haplotype_pileups)?;
+        sources.push(source);
+        self.write_output(output.as_ref(), &sources, &pileups)?;
+
+        if let Some(output) = output.filter(|_| self.split_haplotypes) {
+            // Each haplotype is counted by now, so its output is written as it's
+            // created
+            let mut outputs = HaplotypeOutputs::new(output);
+            for (hp, pileup) in haplotypes {
+                let pileups = BTreeMap::from([(String::new(), pileup)]);
+                outputs
+                    .get_or_create(hp, |path| self.write_output(Some(path), &sources, &pileups))?;
+            }
+            outputs.finish(|()| Ok(()))?;
+        }
+        Ok(())
+    }
+
+    /// Write the header for TSV output, then the counts of each sample to
+    /// output, or stdout if there is none. Sources are written next to the
+    /// output, see [sources_path].
+    fn write_output<P: AsRef<Path>>(
+        &self,
+        output: Option<P>,
+        sources: &[String],
+        pileups: &BTreeMap<String, Pileup>,
+    ) -> Result<()> {
+        if let Some(output) = output.as_ref() {
+            let mut writer = BufWriter::new(create_output(sources_path(output.as_ref()))?);
+            for source in sources.iter() {
+                writeln!(writer, "{source}")?;
+            }
+            writer.flush()?;
+        }
+        let mut writer = BufWriter::new(stdout_or_file(output.as_ref())?);
+        if self.format == PileupFormat::Tsv {
+            let sample = if self.split_by_sample { "\tsample" } else { "" };
+            writeln!(
+                writer,
+                "chrom\tstart\tstrand{sample}\tn_modified\tn_unmodified\tn_no_call\tfraction_modified"
+            )?;
+        }
+        for (sample, pileup) in pileups.iter() {
+            self.write_pileup(&mut writer, sample, pileup)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        arrow::metadata::Strand,
+        haplotype::haplotype_path,
+        test_data::{test_score, write_reads, MiniGenome, TestRead},
+    };
+
+    #[test]
+    fn test_pileup() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("scores.arrow");
+        let reads = [
+            (Strand::plus(), [0.9, 0.45, f64::NAN]),
+            (Strand::plus(), [0.8, 0.1, 0.2]),
+            (Strand::minus(), [0.1, 0.9, 0.9]),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, (strand, scores))| {
+            let scores = scores
+                .iter()
+                .enumerate()
+                .map(|(j, &s)| test_score(10 + j as u64, s))
+                .collect();
+            TestRead::new(format!("read{i}"))
+                .strand(*strand)
+                .scored(scores)
+        })
+        .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let mut opts = PileupOptions::default();
+        opts.no_call_margin(0.1);
+        let pileup = opts.pileup(&input)?;
+        let counts = &pileup["chrI"];
+        let count = |pos, strand| counts[&(pos, strand)];
+        assert_eq!(
+            count(10, "+"),
+            PileupCounts {
+                n_modified: 2,
+                n_unmodified: 0,
+                n_no_call: 0
+            }
+        );
+        assert_eq!(
+            count(11, "+"),
+            PileupCounts {
+                n_modified: 0,
+                n_unmodified: 1,
+                n_no_call: 1
+            }
+        );
+        assert_eq!(count(12, "+").n_no_call, 1);
+        assert_eq!(count(10, "-").n_unmodified, 1);
+
+        let output = mini.dir().join("pileup.bed");
+        opts.format(PileupFormat::BedMethyl).min_coverage(2);
+        opts.run(&input, Some(&output))?;
+        let lines = std::fs::read_to_string(&output)?;
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "chrI\t10\t11\tcawlr\t2\t+\t10\t11\t255,0,0\t2\t100.00\t2\t0\t0\t0\t0\t0\t0"
+        );
+        assert_eq!(
+            lines[1],
+            "chrI\t11\t12\tcawlr\t1\t+\t11\t12\t255,0,0\t1\t0.00\t0\t1\t0\t0\t1\t0\t0"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sources_path(&output))?
+                .lines()
+                .count(),
+            1
+        );
+
+        // Counting the same file twice is refused, other files are added
+        let mut update = PileupOptions::default();
+        update.update(Some(&output));
+        let updated = mini.dir().join("updated.tsv");
+        assert!(update.run(&input, Some(&updated)).is_err());
+        let copy = mini.dir().join("copy.arrow");
+        std::fs::copy(&input, &copy)?;
+        update.run(&copy, Some(&updated))?;
+        let (pileup, sources) = read_pileup(&updated)?;
+        assert_eq!(sources.len(), 2);
+        assert_eq!(
+            pileup["chrI"][&(10, "+")],
+            PileupCounts {
+                n_modified: 4,
+                n_unmodified: 0,
+                n_no_call: 0
+            }
+        );
+        assert_eq!(pileup["chrI"][&(12, "+")].n_no_call, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pileup_split_by_sample() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("merged.arrow");
+        let reads = [Some("treated"), Some("untreated"), None]
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| {
+                TestRead::new(format!("read{i}"))
+                    .sample(sample)
+                    .scored(vec![test_score(10, 0.9)])
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let mut opts = PileupOptions::default();
+        opts.split_by_sample(true);
+        let pileups = opts.pileup_by_sample(&input)?;
+        assert_eq!(
+            pileups.keys().collect::<Vec<_>>(),
+            [NO_SAMPLE, "treated", "untreated"]
+        );
+        assert_eq!(pileups["treated"]["chrI"][&(10, "+")].n_modified, 1);
+
+        let output = mini.dir().join("pileup.tsv");
+        opts.run(&input, Some(&output))?;
+        let lines = std::fs::read_to_string(&output)?;
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("chrom\tstart\tstrand\tsample\t"));
+        assert_eq!(lines[2], "chrI\t10\t+\ttreated\t1\t0\t0\t1");
+
+        opts.update(Some(&output));
+        assert!(opts
+            .run(&input, Some(mini.dir().join("updated.tsv")))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pileup_split_haplotypes() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("phased.arrow");
+        let reads = [(Some(1), 0.9), (Some(2), 0.1), (Some(1), 0.8), (None, 0.2)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(haplotype, score))| {
+                TestRead::new(format!("read{i}"))
+                    .haplotype(haplotype)
+                    .scored(vec![test_score(10, score)])
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let mut opts = PileupOptions::default();
+        opts.split_haplotypes(true);
+        let output = mini.dir().join("pileup.tsv");
+        opts.run(&input, Some(&output))?;
+        let last_line = |path: &Path| -> Result<String> {
+            let lines = std::fs::read_to_string(path)?;
+            Ok(lines.lines().last().unwrap().to_string())
+        };
+        assert_eq!(last_line(&output)?, "chrI\t10\t+\t2\t2\t0\t0.5");
+        assert_eq!(
+            last_line(&haplotype_path(&output, 1))?,
+            "chrI\t10\t+\t2\t0\t0\t1"
+        );
+        assert_eq!(
+            last_line(&haplotype_path(&output, 2))?,
+            "chrI\t10\t+\t0\t1\t0\t0"
+        );
+        assert!(opts.run::<&Path>(&input, None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,616 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::{Context, Result};
+use itertools::Itertools;
+use log::LevelFilter;
+
+use super::{
+    external::{eventalign_cmd, eventalign_collapse},
+    plan::{cmd_str, Plan},
+    steps::{Step, StepCache},
+    write_manifest, CtrlModels,
+};
+use crate::{
+    agg_blocks,
+    arrow::{
+        arrow_utils::{load_read_write_arrow, SchemaExt},
+        eventalign::Eventalign,
+        metadata::MetadataExt,
+        scored_read::ScoredRead,
+    },
+    filter::FilterOptions,
+    motif::{all_bases, Motif},
+    npsmlr::ScoreOptions,
+    region::Region,
+    sma::SmaOptions,
+    utils::{self, parse_name_from_output_dir, record_cmd, record_output, wrap_cmd},
+};
+
+/// Where [AnalyzeOptions] starts from
+#[derive(Debug, Clone)]
+pub enum AnalyzeInput {
+    /// Align the reads in the locus with nanopolish and collapse them
+    Alignments {
+        bam: PathBuf,
+        reads: PathBuf,
+        genome: PathBuf,
+    },
+    /// Existing output from cawlr collapse, ie for the whole genome, that is
+    /// filtered to the locus instead of rerunning nanopolish
+    Collapse(PathBuf),
+    /// Existing output from cawlr score, filtered to the locus and used as is
+    Score(PathBuf),
+}
+
+/// Analyze a specific locus, producing Genome Browser compatible .bed files
+/// for visualizing nucleosomes on single molecules, and clustering of
+/// nucleosome density
+#[derive(Debug, Clone)]
+pub struct AnalyzeOptions {
+    locus: Region,
+    output_dir: PathBuf,
+    input: AnalyzeInput,
+    ctrls: CtrlModels,
+    motifs: Vec<Motif>,
+    n_clusters: usize,
+    pct: f64,
+    highlights: Vec<String>,
+    nanopolish_path: Option<PathBuf>,
+    samtools_path: Option<PathBuf>,
+    overwrite: bool,
+    force: bool,
+    n_threads: usize,
+    json_log: bool,
+    log_level: LevelFilter,
+}
+
+impl AnalyzeOptions {
+    pub fn new(
+        locus: Region,
+        output_dir: impl Into<PathBuf>,
+        bam: impl Into<PathBuf>,
+        reads: impl Into<PathBuf>,
+        genome: impl Into<PathBuf>,
+        ctrls: CtrlModels,
+        motifs: Vec<Motif>,
+    ) -> Self {
+        let input = AnalyzeInput::Alignments {
+            bam: bam.into(),
+            reads: reads.into(),
+            genome: genome.into(),
+        };
+        AnalyzeOptions::with_input(locus, output_dir, input, ctrls, motifs)
+    }
+
+    /// Start from existing collapse or score output instead of alignments,
+    /// skipping samtools and nanopolish.
+    pub fn with_input(
+        locus: Region,
+        output_dir: impl Into<PathBuf>,
+        input: AnalyzeInput,
+        ctrls: CtrlModels,
+        motifs: Vec<Motif>,
+    ) -> Self {
+        AnalyzeOptions {
+            locus,
+            output_dir: output_dir.into(),
+            input,
+            ctrls,
+            motifs,
+            n_clusters: 3,
+            pct: 0.9,
+            highlights: Vec::new(),
+            nanopolish_path: None,
+            samtools_path: None,
+            overwrite: true,
+            force: false,
+            n_threads: 4,
+            json_log: false,
+            log_level: LevelFilter::Info,
+        }
+    }
+
+    /// Number of clusters to use for clustering script
+    pub fn n_clusters(&mut self, n_clusters: usize) -> &mut Self {
+        self.n_clusters = n_clusters;
+        self
+    }
+
+    /// Percent of read that should overlap region to be clustered
+    pub fn pct(&mut self, pct: f64) -> &mut Self {
+        self.pct = pct;
+        self
+    }
+
+    /// Regions to highlight during clustering
+    pub fn highlights(&mut self, highlights: Vec<String>) -> &mut Self {
+        self.highlights = highlights;
+        self
+    }
+
+    /// If None, will look for nanopolish in $PATH
+    pub fn nanopolish_path(&mut self, nanopolish_path: Option<PathBuf>) -> &mut Self {
+        self.nanopolish_path = nanopolish_path;
+        self
+    }
+
+    /// If None, will look for samtools in $PATH
+    pub fn samtools_path(&mut self, samtools_path: Option<PathBuf>) -> &mut Self {
+        self.samtools_path = samtools_path;
+        self
+    }
+
+    /// Remove the output directory before running if it already exists,
+    /// defaults to true. Otherwise, steps that completed in a previous run
+    /// with the same inputs are skipped.
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    pub fn n_threads(&mut self, n_threads: usize) -> &mut Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Write each step as a JSON line to log.jsonl in the output directory
+    pub fn json_log(&mut self, json_log: bool) -> &mut Self {
+        self.json_log = json_log;
+        self
+    }
+
+    /// Level of logging written to log.txt in the output directory
+    pub fn log_level(&mut self, log_level: LevelFilter) -> &mut Self {
+        self.log_level = log_level;
+        self
+    }
+
+    pub fn run(&self) -> Result<()> {
+        if self.overwrite && self.output_dir.exists() {
+            fs::remove_dir_all(&self.output_dir)?;
+        }
+        fs::create_dir_all(&self.output_dir)?;
+
+        let log_file_path = self.output_dir.join("log.txt");
+        let log_file = File::create(log_file_path)?;
+        simple_logging::log_to(log_file.try_clone()?, self.log_level);
+        let _json_log = self
+            .json_log
+            .then(|| utils::json_log_to(self.output_dir.join("log.jsonl")))
+            .transpose()?;
+        log::info!("{self:?}");
+        let mut steps = StepCache::open(&self.output_dir, self.force)?;
+
+        let name = parse_name_from_output_dir(&self.output_dir)?;
+        let mut outputs = Vec::new();
+        let collapse = self.output_dir.join("collapse.arrow");
+        let scored = self.output_dir.join("score.arrow");
+        match &self.input {
+            AnalyzeInput::Alignments { bam, reads, genome } => {
+                let nanopolish = utils::find_binary("nanopolish", &self.nanopolish_path)?;
+                let filtered_bam = self.output_dir.join("filtered.bam");
+                let step = Step::new("Running samtools")
+                    .input(bam)
+                    .output(&filtered_bam)
+                    .param(&self.locus);
+                steps.run(step, || {
+                    let samtools = utils::find_binary("samtools", &self.samtools_path)?;
+                    let mut cmd = self.samtools_view_cmd(&samtools, bam, &filtered_bam);
+                    record_cmd(&cmd);
+                    log::info!("Output file: {}", filtered_bam.display());
+                    record_output(&filtered_bam);
+                    cmd.output().wrap_err("samtools view failed")?;
+                    Ok(())
+                })?;
+
+                let step = Step::new("nanopolish eventalign sample data | cawlr collapse")
+                    .input(reads)
+                    .input(&filtered_bam)
+                    .input(genome)
+                    .output(&collapse);
+                steps.run(step, || {
+                    eventalign_collapse(
+                        &nanopolish,
+                        reads,
+                        &filtered_bam,
+                        genome,
+                        &collapse,
+                        self.n_threads,
+                        log_file.try_clone()?,
+                    )
+                })?;
+                outputs.push(("filtered_bam", filtered_bam));
+            }
+            AnalyzeInput::Collapse(input) => {
+                let step = Step::new("Filtering collapse to locus")
+                    .input(input)
+                    .output(&collapse)
+                    .param(&self.locus);
+                steps.run(step, || {
+                    record_output(&collapse);
+                    self.filter_locus::<Eventalign>(input, &collapse)
+                })?;
+            }
+            AnalyzeInput::Score(input) => {
+                let step = Step::new("Filtering scores to locus")
+                    .input(input)
+                    .output(&scored)
+                    .param(&self.locus);
+                steps.run(step, || {
+                    record_output(&scored);
+                    self.filter_locus::<ScoredRead>(input, &scored)
+                })?;
+            }
+        }
+
+        if !matches!(self.input, AnalyzeInput::Score(_)) {
+            let step = Step::new("cawlr score")
+                .input(&collapse)
+                .input(&self.ctrls.pos_model)
+                .input(&self.ctrls.neg_model)
+                .input(&self.ctrls.ranks)
+                .output(&scored)
+                .param(self.motifs.iter().join(","));
+            steps.run(step, || {
+                let mut scoring = ScoreOptions::load(
+                    &self.ctrls.pos_model,
+                    &self.ctrls.neg_model,
+                    &self.ctrls.ranks,
+                )?;
+                scoring.motifs(self.motifs.clone());
+                let collapse_file = File::open(&collapse)?;
+                let score_file = File::create(&scored)?;
+                log::info!("{scoring:?}");
+                record_output(&scored);
+                scoring
+                    .run(collapse_file, score_file)
+                    .wrap_err("cawlr npsmlr score failed")
+            })?;
+            outputs.push(("collapse", collapse));
+        }
+        outputs.push(("scored", scored.clone()));
+
+        let track_name = format!("{name}.cawlr.sma");
+        let sma = self.output_dir.join(format!("{track_name}.bed"));
+        let step = Step::new("cawlr sma")
+            .input(&scored)
+            .input(&self.ctrls.pos_scores)
+            .input(&self.ctrls.neg_scores)
+            .output(&sma);
+        steps.run(step, || {
+            let mut sma_opts = SmaOptions::try_new(
+                &self.ctrls.pos_scores,
+                &self.ctrls.neg_scores,
+                all_bases(),
+                &sma,
+            )?;
+            sma_opts.track_name(&track_name);
+            record_output(&sma);
+            sma_opts.run(&scored).wrap_err("cawlr sma failed")
+        })?;
+
+        let agg_output = self.output_dir.join(format!("{track_name}.tsv"));
+        let step = Step::new("Aggregating blocks")
+            .input(&sma)
+            .output(&agg_output);
+        steps.run(step, || {
+            record_output(&agg_output);
+            agg_blocks::run(&sma, Some(&agg_output))
+                .wrap_err("Failed to aggregate single molecule data")
+        })?;
+
+        let (plus_filepath, minus_filepath) = strand_filepaths(&sma);
+        let step = Step::new("Splitting by strand")
+            .input(&sma)
+            .output(&plus_filepath)
+            .output(&minus_filepath);
+        steps.run(step, || {
+            let mut cmd = Command::new("split_by_strand.py");
+            cmd.arg("-i").arg(&sma);
+            record_cmd(&cmd);
+            cmd.output().wrap_err("Failed to split by strand")?;
+            Ok(())
+        })?;
+
+        wrap_cmd("Clustering all reads", || {
+            let mut cmd = self.cluster_region_cmd(&format!("{name} {} all", self.locus), &sma);
+            record_cmd(&cmd);
+            let output = cmd.output().wrap_err("Failed to cluster all reads")?;
+            log::info!("Exit code: {}", output.status);
+            Ok(())
+        })?;
+
+        wrap_cmd("Clustering (+) reads", || {
+            let mut cmd =
+                self.cluster_region_cmd(&format!("{name} {} plus", self.locus), &plus_filepath);
+            record_cmd(&cmd);
+            let output = cmd
+                .output()
+                .wrap_err("Failed to cluster positive strand reads")?;
+            log::info!("Exit code: {}", output.status);
+            Ok(())
+        })?;
+
+        wrap_cmd("Clustering (-) reads", || {
+            let mut cmd =
+                self.cluster_region_cmd(&format!("{name} {} minus", self.locus), &minus_filepath);
+            record_cmd(&cmd);
+            let output = cmd
+                .output()
+                .wrap_err("Failed to cluster negative strand reads")?;
+            log::info!("Exit code: {}", output.status);
+            Ok(())
+        })?;
+
+        outputs.push(("sma", sma.clone()));
+        outputs.push(("sma_plus", plus_filepath));
+        outputs.push(("sma_minus", minus_filepath));
+        outputs.push(("agg_blocks", agg_output));
+        let outputs: Vec<(&str, &Path)> = outputs
+            .iter()
+            .map(|(name, path)| (*name, path.as_path()))
+            .collect();
+        write_manifest(&self.output_dir, "analyze-region", &outputs)?;
+
+        Ok(())
+    }
+
+    /// List the steps [run](Self::run) would perform and check that the
+    /// binaries and input files it needs exist, without running anything.
+    pub fn dry_run(&self) -> Result<Plan> {
+        let mut plan = Plan::default();
+        match &self.input {
+            AnalyzeInput::Alignments { bam, reads, genome } => {
+                plan.input("Alignments", bam);
+                plan.input("Reads", reads);
+                plan.input("Genome", genome);
+            }
+            AnalyzeInput::Collapse(input) => plan.input("Collapse", input),
+            AnalyzeInput::Score(input) => plan.input("Scores", input),
+        }
+        plan.input("(+) ctrl model", &self.ctrls.pos_model);
+        plan.input("(-) ctrl model", &self.ctrls.neg_model);
+        plan.input("Ranks", &self.ctrls.ranks);
+        plan.input("(+) ctrl scores", &self.ctrls.pos_scores);
+        plan.input("(-) ctrl scores", &self.ctrls.neg_scores);
+        plan.binary("split_by_strand.py", &None);
+        plan.binary("cluster_region.py", &None);
+
+        if self.overwrite && self.output_dir.exists() {
+            plan.step(
+                "remove existing output directory",
+                vec![format!("rm -r {}", self.output_dir.display())],
+            );
+        }
+
+        let name = parse_name_from_output_dir(&self.output_dir)?;
+        let collapse = self.output_dir.join("collapse.arrow");
+        let scored = self.output_dir.join("score.arrow");
+        match &self.input {
+            AnalyzeInput::Alignments { bam, reads, genome } => {
+                let nanopolish = plan.binary("nanopolish", &self.nanopolish_path);
+                let samtools = plan.binary("samtools", &self.samtools_path);
+                let filtered_bam = self.output_dir.join("filtered.bam");
+                plan.step(
+                    "Running samtools",
+                    vec![cmd_str(&self.samtools_view_cmd(
+                        &samtools,
+                        bam,
+                        &filtered_bam,
+                    ))],
+                );
+
+                let eventalign =
+                    eventalign_cmd(&nanopolish, reads, &filtered_bam, genome, self.n_threads);
+                plan.step(
+                    "nanopolish eventalign sample data | cawlr collapse",
+                    vec![format!(
+                        "{} | cawlr collapse --bam {} --output {}",
+                        cmd_str(&eventalign),
+                        filtered_bam.display(),
+                        collapse.display()
+                    )],
+                );
+            }
+            AnalyzeInput::Collapse(input) => plan.step(
+                "Filtering collapse to locus",
+                vec![format!(
+                    "cawlr filter eventalign --input {} --output {} --region {}",
+                    input.display(),
+                    collapse.display(),
+                    self.locus
+                )],
+            ),
+            AnalyzeInput::Score(input) => plan.step(
+                "Filtering scores to locus",
+                vec![format!(
+                    "cawlr filter score --input {} --output {} --region {}",
+                    input.display(),
+                    scored.display(),
+                    self.locus
+                )],
+            ),
+        }
+
+        if !matches!(self.input, AnalyzeInput::Score(_)) {
+            plan.step(
+                "cawlr score",
+                vec![format!(
+                    "cawlr npsmlr score --input {} --pos-ctrl {} --neg-ctrl {} --ranks {} \
+                     --output {} --motif {}",
+                    collapse.display(),
+                    self.ctrls.pos_model.display(),
+                    self.ctrls.neg_model.display(),
+                    self.ctrls.ranks.display(),
+                    scored.display(),
+                    self.motifs.iter().join(",")
+                )],
+            );
+        }
+
+        let track_name = format!("{name}.cawlr.sma");
+        let sma = self.output_dir.join(format!("{track_name}.bed"));
+        plan.step(
+            "cawlr sma",
+            vec![format!(
+                "cawlr sma --input {} --pos-ctrl-scores {} --neg-ctrl-scores {} --output {}",
+                scored.display(),
+                self.ctrls.pos_scores.display(),
+                self.ctrls.neg_scores.display(),
+                sma.display()
+            )],
+        );
+
+        let agg_output = self.output_dir.join(format!("{track_name}.tsv"));
+        plan.step(
+            "Aggregating blocks",
+            vec![format!(
+                "aggregate nucleosome blocks from {} into {}",
+                sma.display(),
+                agg_output.display()
+            )],
+        );
+        plan.step(
+            "Splitting by strand",
+            vec![format!("split_by_strand.py -i {}", sma.display())],
+        );
+
+        let (plus_filepath, minus_filepath) = strand_filepaths(&sma);
+        plan.step(
+            "Clustering all reads",
+            vec![cmd_str(&self.cluster_region_cmd(
+                &format!("{name} {} all", self.locus),
+                &sma,
+            ))],
+        );
+        plan.step(
+            "Clustering (+) reads",
+            vec![cmd_str(&self.cluster_region_cmd(
+                &format!("{name} {} plus", self.locus),
+                &plus_filepath,
+            ))],
+        );
+        plan.step(
+            "Clustering (-) reads",
+            vec![cmd_str(&self.cluster_region_cmd(
+                &format!("{name} {} minus", self.locus),
+                &minus_filepath,
+            ))],
+        );
+        Ok(plan)
+    }
+
+    fn samtools_view_cmd(&self, samtools: &Path, bam: &Path, filtered_bam: &Path) -> Command {
+        let mut cmd = Command::new(samtools);
+        cmd.arg("view")
+            .arg("-hb")
+            .arg("--write-index")
+            .arg(bam)
+            .arg(format!("{}", self.locus))
+            .arg("-o")
+            .arg(filtered_bam);
+        cmd
+    }
+
+    /// Keep only the reads of an Arrow file that overlap the locus
+    fn filter_locus<T>(&self, input: &Path, output: &Path) -> Result<()>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let filters = FilterOptions::new(vec![self.locus.clone()]);
+        let reader = File::open(input)?;
+        let writer = File::create(output)?;
+        load_read_write_arrow(reader, writer, |xs: Vec<T>| {
+            Ok(xs.into_iter().filter(|x| filters.any_valid(x)).collect())
+        })
+        .wrap_err_with(|| format!("Failed to filter {} to {}", input.display(), self.locus))
+    }
+
+    fn cluster_region_cmd<S: AsRef<OsStr>>(&self, name: &str, sma_path: S) -> Command {
+        let mut cmd = Command::new("cluster_region.py");
+        cmd.arg("-p")
+            .arg(self.pct.to_string())
+            .arg("-s")
+            .arg(self.locus.start().to_string())
+            .arg("-e")
+            .arg(self.locus.end().to_string())
+            .arg("--suptitle")
+            .arg(name)
+            .arg("-n")
+            .arg(self.n_clusters.to_string())
+            .arg("-i")
+            .arg(&sma_path);
+
+        if !self.highlights.is_empty() {
+            cmd.arg("--highlight");
+            cmd.args(&self.highlights);
+        }
+        cmd
+    }
+}
+
+/// Paths to the (+) and (-) strand bed files written by split_by_strand.py
+fn strand_filepaths(sma: &Path) -> (PathBuf, PathBuf) {
+    let stem: &Path = sma.file_stem().unwrap().as_ref();
+    let parent = sma.parent().unwrap();
+    (
+        parent.join(format!("{}.plus.bed", stem.display())),
+        parent.join(format!("{}.minus.bed", stem.display())),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{arrow::arrow_utils::load_apply, collapse::CollapseOptions, test_data::MiniGenome};
+
+    #[test]
+    fn test_from_collapse() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let ctrls = CtrlModels::new("pos.pickle", "neg.pickle", "ranks", "pos.kde", "neg.kde");
+        let opts = AnalyzeOptions::with_input(
+            "chrI:90-160".parse()?,
+            mini.dir().join("analyze"),
+            AnalyzeInput::Collapse(collapsed.clone()),
+            ctrls,
+            all_bases(),
+        );
+        let plan = opts.dry_run()?;
+        let steps = plan.steps.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(steps[..2], ["Filtering collapse to locus", "cawlr score"]);
+        assert!(!plan.problems.iter().any(|p| p.contains("nanopolish")));
+
+        let filtered = mini.dir().join("filtered.arrow");
+        opts.filter_locus::<Eventalign>(&collapsed, &filtered)?;
+        let mut names = Vec::new();
+        load_apply(File::open(&filtered)?, |reads: Vec<Eventalign>| {
+            names.extend(reads.into_iter().map(|r| r.name().to_string()));
+            Ok(())
+        })?;
+        assert_eq!(names, ["plus-read"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use fnv::FnvHashSet;
+use serde::Deserialize;
+
+use super::{plan::Plan, write_manifest, AnalyzeOptions, CtrlModels, PreprocessOptions};
+use crate::{motif::Motif, region::Region, utils::wrap_cmd};
+
+/// A single sample listed in an experiment manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sample {
+    #[serde(rename = "sample")]
+    pub name: String,
+    pub condition: String,
+    pub reads: PathBuf,
+    pub fast5: PathBuf,
+    #[serde(default)]
+    pub summary: Option<PathBuf>,
+}
+
+/// Samples of an experiment, read from a tab-separated file with a header.
+///
+/// The sample, condition, reads, and fast5 columns are required, and an
+/// optional summary column can give the path to a sequencing_summary.txt.
+/// Relative paths are relative to the directory containing the manifest, and
+/// lines starting with '#' are skipped. For example, with columns separated
+/// by tabs:
+///
+/// ```text
+/// sample  condition  reads              fast5
+/// wt_1    wt         wt_1/reads.fastq   wt_1/fast5
+/// mut_1   mut        mut_1/reads.fastq  mut_1/fast5
+/// ```
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub samples: Vec<Sample>,
+}
+
+impl Manifest {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = fs::File::open(path)
+            .wrap_err_with(|| format!("Failed to open manifest {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Manifest::from_reader(file, base_dir)
+    }
+
+    pub fn from_reader<R: std::io::Read>(reader: R, base_dir: &Path) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .comment(Some(b'#'))
+            .from_reader(reader);
+        let mut samples = Vec::new();
+        let mut names = FnvHashSet::default();
+        for sample in reader.deserialize() {
+            let mut sample: Sample = sample.wrap_err("Invalid line in manifest")?;
+            if sample.name.is_empty() || sample.name.contains('/') {
+                return Err(eyre::eyre!("Invalid sample name: {:?}", sample.name));
+            }
+            if !names.insert(sample.name.clone()) {
+                return Err(eyre::eyre!("Sample {} listed more than once", sample.name));
+            }
+            sample.reads = base_dir.join(&sample.reads);
+            sample.fast5 = base_dir.join(&sample.fast5);
+            sample.summary = sample.summary.map(|s| base_dir.join(s));
+            samples.push(sample);
+        }
+        if samples.is_empty() {
+            return Err(eyre::eyre!("No samples found in manifest"));
+        }
+        Ok(Manifest { samples })
+    }
+
+    /// Conditions in the order they first appear in the manifest
+    pub fn conditions(&self) -> Vec<&str> {
+        let mut conditions: Vec<&str> = Vec::new();
+        for sample in self.samples.iter() {
+            if !conditions.contains(&sample.condition.as_str()) {
+                conditions.push(&sample.condition);
+            }
+        }
+        conditions
+    }
+}
+
+/// Preprocess and analyze every sample of an experiment at a locus, then
+/// compare the fraction of reads with a nucleosome at each position across
+/// samples and conditions.
+#[derive(Debug, Clone)]
+pub struct ExperimentOptions {
+    manifest: Manifest,
+    genome: PathBuf,
+    ctrls: CtrlModels,
+    locus: Region,
+    output_dir: PathBuf,
+    motifs: Vec<Motif>,
+    n_clusters: usize,
+    pct: f64,
+    minimap2_path: Option<PathBuf>,
+    nanopolish_path: Option<PathBuf>,
+    samtools_path: Option<PathBuf>,
+    force: bool,
+    n_threads: usize,
+    json_log: bool,
+}
+
+impl ExperimentOptions {
+    pub fn new(
+        manifest: Manifest,
+        genome: impl Into<PathBuf>,
+        ctrls: CtrlModels,
+        locus: Region,
+        output_dir: impl Into<PathBuf>,
+        motifs: Vec<Motif>,
+    ) -> Self {
+        ExperimentOptions {
+            manifest,
+            genome: genome.into(),
+            ctrls,
+            locus,
+            output_dir: output_dir.into(),
+            motifs,
+            n_clusters: 3,
+            pct: 0.9,
+            minimap2_path: None,
+            nanopolish_path: None,
+            samtools_path: None,
+            force: false,
+            n_threads: 4,
+            json_log: false,
+        }
+    }
+
+    /// Number of clusters to use for clustering script
+    pub fn n_clusters(&mut self, n_clusters: usize) -> &mut Self {
+        self.n_clusters = n_clusters;
+        self
+    }
+
+    /// Percent of read that should overlap region to be clustered
+    pub fn pct(&mut self, pct: f64) -> &mut Self {
+        self.pct = pct;
+        self
+    }
+
+    /// If None, will look for minimap2 in $PATH
+    pub fn minimap2_path(&mut self, minimap2_path: Option<PathBuf>) -> &mut Self {
+        self.minimap2_path = minimap2_path;
+        self
+    }
+
+    /// If None, will look for nanopolish in $PATH
+    pub fn nanopolish_path(&mut self, nanopolish_path: Option<PathBuf>) -> &mut Self {
+        self.nanopolish_path = nanopolish_path;
+        self
+    }
+
+    /// If None, will look for samtools in $PATH
+    pub fn samtools_path(&mut self, samtools_path: Option<PathBuf>) -> &mut Self {
+        self.samtools_path = samtools_path;
+        self
+    }
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    pub fn n_threads(&mut self, n_threads: usize) -> &mut Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Write each step as a JSON line to log.jsonl in the output directory of
+    /// each sample
+    pub fn json_log(&mut self, json_log: bool) -> &mut Self {
+        self.json_log = json_log;
+        self
+    }
+
+    fn preprocess_opts(&self, sample: &Sample) -> PreprocessOptions {
+        let output_dir = self.output_dir.join("preprocess").join(&sample.name);
+        let mut opts =
+            PreprocessOptions::new(&self.genome, &sample.reads, &sample.fast5, output_dir);
+        opts.summary(sample.summary.clone())
+            .minimap2_path(self.minimap2_path.clone())
+            .nanopolish_path(self.nanopolish_path.clone())
+            .samtools_path(self.samtools_path.clone())
+            .force(self.force)
+            .n_threads(self.n_threads)
+            .json_log(self.json_log);
+        opts
+    }
+
+    fn analyze_opts(&self, sample: &Sample) -> AnalyzeOptions {
+        let preprocess_dir = self.output_dir.join("preprocess").join(&sample.name);
+        let mut opts = AnalyzeOptions::new(
+            self.locus.clone(),
+            self.output_dir.join("analyze").join(&sample.name),
+            preprocess_dir.join("aln.bam"),
+            preprocess_dir.join("reads.fastq"),
+            &self.genome,
+            self.ctrls.clone(),
+            self.motifs.clone(),
+        );
+        opts.n_clusters(self.n_clusters)
+            .pct(self.pct)
+            .nanopolish_path(self.nanopolish_path.clone())
+            .samtools_path(self.samtools_path.clone())
+            .overwrite(false)
+            .force(self.force)
+            .n_threads(self.n_threads)
+            .json_log(self.json_log);
+        opts
+    }
+
+    /// Path to the aggregated blocks written by the analyze-region pipeline
+    /// for a sample
+    fn agg_blocks_path(&self, sample: &Sample) -> PathBuf {
+        self.output_dir
+            .join("analyze")
+            .join(&sample.name)
+            .join(format!("{}.cawlr.sma.tsv", sample.name))
+    }
+
+    /// Returns the path to the table comparing samples.
+    pub fn run(&self) -> Result<PathBuf> {
+        fs::create_dir_all(&self.output_dir)?;
+        for sample in self.manifest.samples.iter() {
+            log::info!("Processing sample {}", sample.name);
+            self.preprocess_opts(sample)
+                .run()
+                .wrap_err_with(|| format!("Failed to preprocess sample {}", sample.name))?;
+            self.analyze_opts(sample)
+                .run()
+                .wrap_err_with(|| format!("Failed to analyze sample {}", sample.name))?;
+        }
+
+        let comparison = self.output_dir.join("comparison.tsv");
+        wrap_cmd("Comparing samples", || {
+            let agg_paths = self
+                .manifest
+                .samples
+                .iter()
+                .map(|s| self.agg_blocks_path(s))
+                .collect::<Vec<_>>();
+            let writer = fs::File::create(&comparison)?;
+            compare_samples(&self.manifest, &agg_paths, writer)
+        })?;
+
+        let mut outputs = vec![("comparison".to_string(), comparison.clone())];
+        for sample in self.manifest.samples.iter() {
+            outputs.push((
+                format!("{}_agg_blocks", sample.name),
+                self.agg_blocks_path(sample),
+            ));
+        }
+        let outputs = outputs
+            .iter()
+            .map(|(name, path)| (name.as_str(), path.as_path()))
+            .collect::<Vec<_>>();
+        write_manifest(&self.output_dir, "experiment", &outputs)?;
+        Ok(comparison)
+    }
+
+    /// List the steps [run](Self::run) would perform for every sample and
+    /// check that the binaries and input files it needs exist, without running
+    /// anything.
+    pub fn dry_run(&self) -> Result<Plan> {
+        let mut plan = Plan::default();
+        plan.input("Genome", &self.genome);
+        for sample in self.manifest.samples.iter() {
+            plan.extend(self.preprocess_opts(sample).dry_run()?);
+
+            // Alignments and reads don't exist until the sample is preprocessed
+            let preprocess_dir = self.output_dir.join("preprocess").join(&sample.name);
+            let mut analyze = self.analyze_opts(sample).dry_run()?;
+            analyze
+                .problems
+                .retain(|p| !p.contains(&*preprocess_dir.to_string_lossy()));
+            plan.extend(analyze);
+        }
+        plan.step(
+            "Comparing samples",
+            vec![format!(
+                "compare aggregated blocks into {}",
+                self.output_dir.join("comparison.tsv").display()
+            )],
+        );
+        Ok(plan)
+    }
+}
+
+/// Read the fraction of reads with a nucleosome at each position from the
+/// output of [agg_blocks::run](crate::agg_blocks::run)
+fn read_agg_blocks(path: &Path) -> Result<Vec<((String, u64), f64)>> {
+    let reader = BufReader::new(
+        fs::File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+    );
+    let mut fracs = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match fields.as_slice() {
+            [chrom, pos, _count, _total, frac] => {
+                fracs.push(((chrom.to_string(), pos.parse()?), frac.parse()?));
+            }
+            _ => return Err(eyre::eyre!("Invalid line in {}: {line}", path.display())),
+        }
+    }
+    Ok(fracs)
+}
+
+/// Write a table with the fraction of reads with a nucleosome at each position
+/// for each sample, the mean of each condition, and the difference between
+/// conditions if there are exactly two. Missing values are written as NA.
+fn compare_samples<W: Write>(
+    manifest: &Manifest,
+    agg_paths: &[PathBuf],
+    mut writer: W,
+) -> Result<()> {
+    let n_samples = manifest.samples.len();
+    let mut positions: BTreeMap<(String, u64), Vec<Option<f64>>> = BTreeMap::new();
+    for (idx, path) in agg_paths.iter().enumerate() {
+        for (pos, frac) in read_agg_blocks(path)? {
+            positions
+                .entry(pos)
+                .or_insert_with(|| vec![None; n_samples])[idx] = Some(frac);
+        }
+    }
+
+    let conditions = manifest.conditions();
+    let mut header = vec!["chrom".to_string(), "pos".to_string()];
+    header.extend(manifest.samples.iter().map(|s| s.name.clone()));
+    header.extend(conditions.iter().map(|c| format!("mean_{c}")));
+    if let [a, b] = conditions.as_slice() {
+        header.push(format!("diff_{b}_{a}"));
+    }
+    writeln!(writer, "{}", header.join("\t"))?;
+
+    let fmt_value = |x: Option<f64>| x.map_or("NA".to_string(), |x| x.to_string());
+    for ((chrom, pos), fracs) in positions {
+        let means = conditions
+            .iter()
+            .map(|&c| {
+                let values = manifest
+                    .samples
+                    .iter()
+                    .zip(fracs.iter())
+                    .filter(|(s, _)| s.condition == c)
+                    .filter_map(|(_, &frac)| frac)
+                    .collect::<Vec<_>>();
+                if values.is_empty() {
+                    None
+                } else {
+                    Some(values.iter().sum::<f64>() / values.len() as f64)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut row = vec![chrom, pos.to_string()];
+        row.extend(fracs.iter().map(|&x| fmt_value(x)));
+        row.extend(means.iter().map(|&x| fmt_value(x)));
+        if let [Some(a), Some(b)] = means.as_slice() {
+            row.push((b - a).to_string());
+        } else if means.len() == 2 {
+            row.push(fmt_value(None));
+        }
+        writeln!(writer, "{}", row.join("\t"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_manifest() -> Result<()> {
+        let manifest = "sample\tcondition\treads\tfast5\tsummary\n\
+                        # comment\n\
+                        wt_1\twt\twt_1.fastq\twt_1\t\n\
+                        mut_1\tmut\t/data/mut_1.fastq\tmut_1\tsummary.txt\n";
+        let manifest = Manifest::from_reader(manifest.as_bytes(), Path::new("/experiment"))?;
+        assert_eq!(manifest.samples.len(), 2);
+        assert_eq!(
+            manifest.samples[0].reads,
+            PathBuf::from("/experiment/wt_1.fastq")
+        );
+        assert_eq!(manifest.samples[0].summary, None);
+        assert_eq!(
+            manifest.samples[1].reads,
+            PathBuf::from("/data/mut_1.fastq")
+        );
+        assert_eq!(
+            manifest.samples[1].summary,
+            Some(PathBuf::from("/experiment/summary.txt"))
+        );
+        assert_eq!(manifest.conditions(), ["wt", "mut"]);
+
+        let no_summary = "sample\tcondition\treads\tfast5\nwt_1\twt\twt_1.fastq\twt_1\n";
+        assert!(Manifest::from_reader(no_summary.as_bytes(), Path::new(".")).is_ok());
+
+        let duplicate = "sample\tcondition\treads\tfast5\n\
+                         wt_1\twt\ta.fastq\ta\n\
+                         wt_1\twt\tb.fastq\tb\n";
+        assert!(Manifest::from_reader(duplicate.as_bytes(), Path::new(".")).is_err());
+
+        let missing_column = "sample\treads\tfast5\nwt_1\twt_1.fastq\twt_1\n";
+        assert!(Manifest::from_reader(missing_column.as_bytes(), Path::new(".")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_samples() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let manifest = "sample\tcondition\treads\tfast5\n\
+                        wt_1\twt\ta.fastq\ta\n\
+                        wt_2\twt\tb.fastq\tb\n\
+                        mut_1\tmut\tc.fastq\tc\n";
+        let manifest = Manifest::from_reader(manifest.as_bytes(), temp_dir.path())?;
+        let agg = [
+            "chrI\t10\t1\t2\t0.5\nchrI\t11\t0\t2\t0\n",
+            "chrI\t10\t1\t1\t1\n",
+            "chrI\t10\t1\t4\t0.25\n",
+        ];
+        let agg_paths = agg
+            .iter()
+            .enumerate()
+            .map(|(idx, contents)| {
+                let path = temp_dir.path().join(format!("{idx}.tsv"));
+                fs::write(&path, contents).map(|_| path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut output = Vec::new();
+        compare_samples(&manifest, &agg_paths, &mut output)?;
+        let output = String::from_utf8(output)?;
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "chrom\tpos\twt_1\twt_2\tmut_1\tmean_wt\tmean_mut\tdiff_mut_wt",
+                "chrI\t10\t0.5\t1\t0.25\t0.75\t0.25\t-0.5",
+                "chrI\t11\t0\tNA\tNA\t0\tNA\tNA",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Wrappers around external tools used in the pipelines.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use eyre::{Context, Result};
+
+use crate::{
+    collapse::CollapseOptions,
+    utils::{self, check_if_failed, record_cmd, record_output},
+};
+
+pub(crate) fn np_index_cmd(
+    nanopolish: &Path,
+    fast5s: &Path,
+    reads: &Path,
+    summary: &Option<impl AsRef<Path>>,
+) -> Command {
+    let mut cmd = Command::new(nanopolish);
+    cmd.arg("index").arg("-d").arg(fast5s);
+    if let Some(summary) = summary {
+        cmd.arg("-s").arg(summary.as_ref());
+    }
+    cmd.arg(reads);
+    cmd
+}
+
+pub(crate) fn np_index(
+    nanopolish: &Path,
+    fast5s: &Path,
+    reads: &Path,
+    summary: &Option<impl AsRef<Path>>,
+    log_file: File,
+) -> Result<()> {
+    let mut cmd = np_index_cmd(nanopolish, fast5s, reads, summary);
+    cmd.stderr(log_file);
+    record_cmd(&cmd);
+    let output = cmd.output()?;
+    check_if_failed(output).wrap_err("nanopolish index failed")
+}
+
+pub(crate) fn minimap2_cmd(
+    minimap2: &Path,
+    genome: &Path,
+    reads: &Path,
+    n_threads: usize,
+) -> Command {
+    let mut cmd = Command::new(minimap2);
+    cmd.arg("-ax")
+        .arg("map-ont")
+        .arg("--sam-hit-only")
+        .arg("--secondary=no")
+        .arg("-t")
+        .arg(n_threads.to_string())
+        .arg(genome)
+        .arg(reads);
+    cmd
+}
+
+/// Temporary files are written to tmp_prefix.NNNN.bam
+pub(crate) fn samtools_sort_cmd(samtools: &Path, tmp_prefix: &Path, output: &Path) -> Command {
+    let mut cmd = Command::new(samtools);
+    cmd.arg("sort")
+        .arg("--write-index")
+        .arg("-T")
+        .arg(tmp_prefix)
+        .arg("-o")
+        .arg(output);
+    cmd
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn aln_reads(
+    minimap2: &Path,
+    samtools: &Path,
+    genome: &Path,
+    reads: &Path,
+    output: &Path,
+    n_threads: usize,
+    log_file: File,
+) -> Result<()> {
+    // Removed even if samtools fails
+    let tmp = utils::temp_dir()?;
+    let mut map_cmd = minimap2_cmd(minimap2, genome, reads, n_threads);
+    map_cmd.stdout(Stdio::piped()).stderr(log_file.try_clone()?);
+    record_cmd(&map_cmd);
+    let map_output = map_cmd.spawn()?;
+
+    let mut sam_cmd = samtools_sort_cmd(samtools, &tmp.path().join("samtools"), output);
+    sam_cmd.stderr(log_file).stdin(
+        map_output
+            .stdout
+            .ok_or_else(|| eyre::eyre!("Could not capture minimap2 stdout"))?,
+    );
+    record_cmd(&sam_cmd);
+    record_output(output);
+    let output = sam_cmd.output()?;
+    check_if_failed(output).wrap_err("minimap2 | samtools failed")
+}
+
+pub(crate) fn eventalign_cmd(
+    nanopolish: &Path,
+    reads: &Path,
+    bam: &Path,
+    genome: &Path,
+    n_threads: usize,
+) -> Command {
+    let mut cmd = Command::new(nanopolish);
+    cmd.arg("eventalign")
+        .arg("-r")
+        .arg(reads)
+        .arg("-b")
+        .arg(bam)
+        .arg("-g")
+        .arg(genome)
+        .arg("-t")
+        .arg(n_threads.to_string())
+        .arg("--scale-events")
+        .arg("--print-read-names")
+        .arg("--samples");
+    cmd
+}
+
+/// Runs nanopolish eventalign and pipes the output directly into cawlr
+/// collapse.
+pub(crate) fn eventalign_collapse(
+    nanopolish: &Path,
+    reads: &Path,
+    bam: &Path,
+    genome: &Path,
+    output: &Path,
+    n_threads: usize,
+    log_file: File,
+) -> Result<()> {
+    let mut cmd = eventalign_cmd(nanopolish, reads, bam, genome, n_threads);
+    record_cmd(&cmd);
+    let mut cmd = cmd.stdout(Stdio::piped()).stderr(log_file).spawn()?;
+    let stdout = cmd
+        .stdout
+        .take()
+        .ok_or_else(|| eyre::eyre!("Could not capture stdout"))?;
+    let reader = BufReader::new(stdout);
+    record_output(output);
+    let mut collapse = CollapseOptions::try_new(bam, output)?;
+    collapse.run(reader)?;
+    Ok(())
+}
+
+/// Find all the fastqs within a directory and concatenate them all into a
+/// single file.
+pub(crate) fn concat_fastqs(reads_dir: &Path, output: &Path) -> Result<()> {
+    log::info!("Detected directory, concatenating into a single fastq file.");
+    let mut output_file = BufWriter::new(File::create(output)?);
+    let fastq_matcher = format!(
+        "{}/**/*fastq",
+        reads_dir.as_os_str().to_str().ok_or(eyre::eyre!(
+            "Failed to convert path into str, unicode issue?"
+        ))?
+    );
+    let mut n_fastq_files = 0;
+    for fastq in glob::glob(&fastq_matcher)? {
+        let fastq = fastq?;
+        n_fastq_files += 1;
+        log::info!("Found fastq: {}", fastq.display());
+        let mut fastq_file = BufReader::new(File::open(fastq)?);
+        loop {
+            let buf_len = {
+                let buf = fastq_file.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                output_file.write_all(buf)?;
+                buf.len()
+            };
+            fastq_file.consume(buf_len);
+        }
+    }
+    output_file.flush()?;
+
+    if n_fastq_files == 0 {
+        Err(eyre::eyre!(
+            "No fastq files processed, check if directory contained files ending with .fastq"
+        ))
+    } else {
+        log::info!("Processed {n_fastq_files} fastq files");
+        Ok(())
+    }
+}
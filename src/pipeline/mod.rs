@@ -0,0 +1,110 @@
+//! Pipelines chaining together external tools (minimap2, samtools, nanopolish)
+//! with cawlr commands.
+//!
+//! These are the same pipelines exposed by `cawlr pipeline`, but usable
+//! without going through the command line, for example:
+//!
+//! ```no_run
+//! # use libcawlr::{motif::Motif, pipeline::TrainCtrlsOptions};
+//! # fn main() -> eyre::Result<()> {
+//! let motifs = vec!["2:GC".parse::<Motif>()?];
+//! let ctrls = TrainCtrlsOptions::new(
+//!     "genome.fa",
+//!     "pos-fast5s/",
+//!     "pos.fastq",
+//!     "neg-fast5s/",
+//!     "neg.fastq",
+//!     "training-output",
+//!     motifs,
+//! )
+//! .run()?;
+//! # Ok(())
+//! # }
+//! ```
+mod analyze;
+mod experiment;
+mod external;
+mod plan;
+mod preprocess;
+mod sma_genome;
+mod steps;
+mod train_ctrls;
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+
+pub use self::{
+    analyze::{AnalyzeInput, AnalyzeOptions},
+    experiment::{ExperimentOptions, Manifest, Sample},
+    plan::{Plan, PlannedStep},
+    preprocess::PreprocessOptions,
+    sma_genome::SmaGenomeOptions,
+    train_ctrls::TrainCtrlsOptions,
+};
+
+/// Paths to the models and score distributions of the positive and negative
+/// controls, as produced by [TrainCtrlsOptions::run].
+#[derive(Debug, Clone)]
+pub struct CtrlModels {
+    pub pos_model: PathBuf,
+    pub neg_model: PathBuf,
+    pub ranks: PathBuf,
+    pub pos_scores: PathBuf,
+    pub neg_scores: PathBuf,
+}
+
+impl CtrlModels {
+    pub fn new<P: Into<PathBuf>>(
+        pos_model: P,
+        neg_model: P,
+        ranks: P,
+        pos_scores: P,
+        neg_scores: P,
+    ) -> Self {
+        Self {
+            pos_model: pos_model.into(),
+            neg_model: neg_model.into(),
+            ranks: ranks.into(),
+            pos_scores: pos_scores.into(),
+            neg_scores: neg_scores.into(),
+        }
+    }
+
+    /// Paths to the files within the output directory of a train-ctrls
+    /// pipeline run.
+    pub fn from_train_ctrls_dir<P: AsRef<Path>>(output_dir: P) -> Self {
+        let output_dir = output_dir.as_ref();
+        CtrlModels::new(
+            output_dir.join("pos_train.pickle"),
+            output_dir.join("neg_train.pickle"),
+            output_dir.join("ranks.pickle"),
+            output_dir.join("pos_model_scores.pickle"),
+            output_dir.join("neg_model_scores.pickle"),
+        )
+    }
+}
+
+/// Write manifest.json to the output directory, listing the files produced by
+/// the pipeline so they can be picked up by workflow managers.
+pub(crate) fn write_manifest(
+    output_dir: &Path,
+    pipeline: &str,
+    outputs: &[(&str, &Path)],
+) -> Result<()> {
+    let outputs: serde_json::Map<String, serde_json::Value> = outputs
+        .iter()
+        .map(|(name, path)| (name.to_string(), path.display().to_string().into()))
+        .collect();
+    let manifest = serde_json::json!({
+        "pipeline": pipeline,
+        "output_dir": output_dir,
+        "outputs": outputs,
+    });
+    let writer = File::create(output_dir.join("manifest.json"))?;
+    serde_json::to_writer_pretty(writer, &manifest)?;
+    Ok(())
+}
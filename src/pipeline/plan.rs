@@ -0,0 +1,124 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use which::which;
+
+/// A step of a pipeline, with the commands it would run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedStep {
+    pub name: &'static str,
+    pub commands: Vec<String>,
+}
+
+/// Every step a pipeline would run, produced by the `dry_run` method of each
+/// pipeline without running anything or writing to the output directory.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub steps: Vec<PlannedStep>,
+    /// Missing binaries and input files that would cause the pipeline to fail
+    pub problems: Vec<String>,
+}
+
+impl Plan {
+    /// True if all the required binaries and input files were found
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Append the steps and problems of another plan
+    pub(crate) fn extend(&mut self, other: Plan) {
+        self.steps.extend(other.steps);
+        self.problems.extend(other.problems);
+    }
+
+    pub(crate) fn step(&mut self, name: &'static str, commands: Vec<String>) {
+        self.steps.push(PlannedStep { name, commands });
+    }
+
+    /// Resolve the path to an external tool the same way the pipeline would,
+    /// noting it as a problem if it can't be found.
+    pub(crate) fn binary(
+        &mut self,
+        name: &'static str,
+        binary_filepath: &Option<PathBuf>,
+    ) -> PathBuf {
+        match binary_filepath {
+            Some(p) if p.exists() => p.clone(),
+            Some(p) => {
+                self.problems
+                    .push(format!("{name} not found at {}", p.display()));
+                p.clone()
+            }
+            None => which(name).unwrap_or_else(|_| {
+                self.problems.push(format!("{name} not found in $PATH"));
+                PathBuf::from(name)
+            }),
+        }
+    }
+
+    pub(crate) fn input<P: AsRef<Path>>(&mut self, desc: &str, path: P) {
+        let path = path.as_ref();
+        if !path.exists() {
+            self.problems
+                .push(format!("{desc} does not exist: {}", path.display()));
+        }
+    }
+}
+
+/// Format a command the same way it is logged when the pipeline runs
+pub(crate) fn cmd_str(cmd: &Command) -> String {
+    format!("{cmd:?}")
+}
+
+/// Equivalent shell command for [concat_fastqs](super::external::concat_fastqs)
+pub(crate) fn concat_cmd_str(reads_dir: &Path, output: &Path) -> String {
+    format!(
+        "cat {}/**/*fastq > {}",
+        reads_dir.display(),
+        output.display()
+    )
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{}. {}", idx + 1, step.name)?;
+            for cmd in step.commands.iter() {
+                writeln!(f, "    {cmd}")?;
+            }
+        }
+        if !self.problems.is_empty() {
+            writeln!(f, "Problems:")?;
+            for problem in self.problems.iter() {
+                writeln!(f, "    {problem}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let mut plan = Plan::default();
+        let missing = plan.binary("definitely-not-a-real-binary", &None);
+        assert_eq!(missing, PathBuf::from("definitely-not-a-real-binary"));
+        plan.input("Genome", "/does/not/exist.fa");
+        plan.step(
+            "align reads",
+            vec!["minimap2 genome.fa reads.fastq".to_string()],
+        );
+        assert!(!plan.is_valid());
+        assert_eq!(plan.problems.len(), 2);
+
+        let output = plan.to_string();
+        assert!(output.starts_with("1. align reads\n    minimap2 genome.fa reads.fastq\n"));
+        assert!(output.contains("Genome does not exist: /does/not/exist.fa"));
+    }
+}
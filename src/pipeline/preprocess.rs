@@ -0,0 +1,244 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use log::LevelFilter;
+
+use super::{
+    external::{aln_reads, concat_fastqs, minimap2_cmd, np_index, np_index_cmd, samtools_sort_cmd},
+    plan::{cmd_str, concat_cmd_str, Plan},
+    steps::{Step, StepCache},
+    write_manifest,
+};
+use crate::utils;
+
+/// Preprocess a sample prior to analyzing regions: alignment with minimap2
+/// and indexing with nanopolish.
+#[derive(Debug, Clone)]
+pub struct PreprocessOptions {
+    genome: PathBuf,
+    reads: PathBuf,
+    fast5: PathBuf,
+    summary: Option<PathBuf>,
+    output_dir: PathBuf,
+    minimap2_path: Option<PathBuf>,
+    nanopolish_path: Option<PathBuf>,
+    samtools_path: Option<PathBuf>,
+    overwrite: bool,
+    force: bool,
+    n_threads: usize,
+    json_log: bool,
+}
+
+impl PreprocessOptions {
+    /// If reads is a directory, all .fastq files will be concatenated and
+    /// written to the output directory. If it is a single file, a symlink to
+    /// that file is created in the output directory instead.
+    pub fn new(
+        genome: impl Into<PathBuf>,
+        reads: impl Into<PathBuf>,
+        fast5: impl Into<PathBuf>,
+        output_dir: impl Into<PathBuf>,
+    ) -> Self {
+        PreprocessOptions {
+            genome: genome.into(),
+            reads: reads.into(),
+            fast5: fast5.into(),
+            summary: None,
+            output_dir: output_dir.into(),
+            minimap2_path: None,
+            nanopolish_path: None,
+            samtools_path: None,
+            overwrite: false,
+            force: false,
+            n_threads: 4,
+            json_log: false,
+        }
+    }
+
+    /// Path to a sequencing_summary.txt file, will speed up nanopolish index
+    pub fn summary(&mut self, summary: Option<PathBuf>) -> &mut Self {
+        self.summary = summary;
+        self
+    }
+
+    /// If None, will look for minimap2 in $PATH
+    pub fn minimap2_path(&mut self, minimap2_path: Option<PathBuf>) -> &mut Self {
+        self.minimap2_path = minimap2_path;
+        self
+    }
+
+    /// If None, will look for nanopolish in $PATH
+    pub fn nanopolish_path(&mut self, nanopolish_path: Option<PathBuf>) -> &mut Self {
+        self.nanopolish_path = nanopolish_path;
+        self
+    }
+
+    /// If None, will look for samtools in $PATH
+    pub fn samtools_path(&mut self, samtools_path: Option<PathBuf>) -> &mut Self {
+        self.samtools_path = samtools_path;
+        self
+    }
+
+    /// Remove the output directory before running if it already exists
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    pub fn n_threads(&mut self, n_threads: usize) -> &mut Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Write each step as a JSON line to log.jsonl in the output directory
+    pub fn json_log(&mut self, json_log: bool) -> &mut Self {
+        self.json_log = json_log;
+        self
+    }
+
+    /// Returns the path to the sorted and indexed alignments.
+    pub fn run(&self) -> Result<PathBuf> {
+        if self.overwrite && self.output_dir.exists() {
+            fs::remove_dir_all(&self.output_dir)?;
+        }
+        fs::create_dir_all(&self.output_dir)?;
+
+        let log_file_path = self.output_dir.join("log.txt");
+        let log_file = File::create(log_file_path)?;
+        simple_logging::log_to(log_file.try_clone()?, LevelFilter::Info);
+        let _json_log = self
+            .json_log
+            .then(|| utils::json_log_to(self.output_dir.join("log.jsonl")))
+            .transpose()?;
+
+        log::info!("{self:?}");
+        let mut steps = StepCache::open(&self.output_dir, self.force)?;
+        let reads = self.output_dir.join("reads.fastq");
+        steps.run(
+            Step::new("prepare reads").input(&self.reads).output(&reads),
+            || self.reads_to_single_reads(&reads),
+        )?;
+
+        let minimap2 = utils::find_binary("minimap2", &self.minimap2_path)?;
+        let samtools = utils::find_binary("samtools", &self.samtools_path)?;
+        let aln_bam = self.output_dir.join("aln.bam");
+        let aln_step = Step::new("align reads")
+            .input(&self.genome)
+            .input(&reads)
+            .output(&aln_bam);
+        steps.run(aln_step, || {
+            aln_reads(
+                &minimap2,
+                &samtools,
+                &self.genome,
+                &reads,
+                &aln_bam,
+                self.n_threads,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        let nanopolish = utils::find_binary("nanopolish", &self.nanopolish_path)?;
+        let index_step = Step::new("nanopolish index")
+            .input(&self.fast5)
+            .input(&reads)
+            .output(reads.with_extension("fastq.index.readdb"));
+        steps.run(index_step, || {
+            np_index(
+                &nanopolish,
+                &self.fast5,
+                &reads,
+                &self.summary,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        write_manifest(
+            &self.output_dir,
+            "preprocess-sample",
+            &[("reads", &reads), ("bam", &aln_bam)],
+        )?;
+        Ok(aln_bam)
+    }
+
+    /// List the steps [run](Self::run) would perform and check that the
+    /// binaries and input files it needs exist, without running anything.
+    pub fn dry_run(&self) -> Result<Plan> {
+        let mut plan = Plan::default();
+        plan.input("Genome", &self.genome);
+        plan.input("Reads", &self.reads);
+        plan.input("Fast5 directory", &self.fast5);
+        if let Some(summary) = &self.summary {
+            plan.input("Sequencing summary", summary);
+        }
+        let minimap2 = plan.binary("minimap2", &self.minimap2_path);
+        let samtools = plan.binary("samtools", &self.samtools_path);
+        let nanopolish = plan.binary("nanopolish", &self.nanopolish_path);
+
+        if self.overwrite && self.output_dir.exists() {
+            plan.step(
+                "remove existing output directory",
+                vec![format!("rm -r {}", self.output_dir.display())],
+            );
+        }
+        let reads = self.output_dir.join("reads.fastq");
+        let prepare_reads = if self.reads.is_dir() {
+            concat_cmd_str(&self.reads, &reads)
+        } else {
+            format!("ln -s {} {}", self.reads.display(), reads.display())
+        };
+        plan.step("prepare reads", vec![prepare_reads]);
+
+        let aln_bam = self.output_dir.join("aln.bam");
+        plan.step(
+            "align reads",
+            vec![format!(
+                "{} | {}",
+                cmd_str(&minimap2_cmd(
+                    &minimap2,
+                    &self.genome,
+                    &reads,
+                    self.n_threads
+                )),
+                cmd_str(&samtools_sort_cmd(
+                    &samtools,
+                    &utils::tmp_dir().join("samtools"),
+                    &aln_bam
+                ))
+            )],
+        );
+        plan.step(
+            "nanopolish index",
+            vec![cmd_str(&np_index_cmd(
+                &nanopolish,
+                &self.fast5,
+                &reads,
+                &self.summary,
+            ))],
+        );
+        Ok(plan)
+    }
+
+    fn reads_to_single_reads(&self, output_filepath: &Path) -> Result<()> {
+        if self.reads.is_dir() {
+            concat_fastqs(&self.reads, output_filepath)?;
+        } else {
+            // Left over from a previous run that didn't complete
+            if output_filepath.symlink_metadata().is_ok() {
+                fs::remove_file(output_filepath)?;
+            }
+            std::os::unix::fs::symlink(&self.reads, output_filepath)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use itertools::Itertools;
+use log::LevelFilter;
+
+use super::{
+    plan::Plan,
+    steps::{Step, StepCache},
+    write_manifest, CtrlModels,
+};
+use crate::{
+    agg_blocks::AggOptions,
+    motif::Motif,
+    npsmlr::ScoreOptions,
+    score_model,
+    sma::SmaOptions,
+    track::TrackOptions,
+    utils::{self, parse_name_from_output_dir, record_output, CawlrIO},
+};
+
+/// Infer nucleosomes on single molecules across the whole genome, producing
+/// the single molecule .bed file, aggregated blocks, and occupancy tracks.
+///
+/// Starts from whole-genome collapse output of the sample and a train-ctrls
+/// output directory. Reads are scored and segmented in chunks, with the
+/// chunks segmented in parallel, and the score distributions of the controls
+/// are estimated in parallel.
+#[derive(Debug, Clone)]
+pub struct SmaGenomeOptions {
+    genome: PathBuf,
+    collapse: PathBuf,
+    ctrls_dir: PathBuf,
+    output_dir: PathBuf,
+    motifs: Vec<Motif>,
+    samples: usize,
+    stratify: bool,
+    min_coverage: u64,
+    overwrite: bool,
+    force: bool,
+    json_log: bool,
+    log_level: LevelFilter,
+}
+
+impl SmaGenomeOptions {
+    /// ctrls_dir is the output directory of [TrainCtrlsOptions](super::TrainCtrlsOptions)
+    pub fn new(
+        genome: impl Into<PathBuf>,
+        collapse: impl Into<PathBuf>,
+        ctrls_dir: impl Into<PathBuf>,
+        output_dir: impl Into<PathBuf>,
+        motifs: Vec<Motif>,
+    ) -> Self {
+        SmaGenomeOptions {
+            genome: genome.into(),
+            collapse: collapse.into(),
+            ctrls_dir: ctrls_dir.into(),
+            output_dir: output_dir.into(),
+            motifs,
+            samples: 10_000,
+            stratify: true,
+            min_coverage: 1,
+            overwrite: false,
+            force: false,
+            json_log: false,
+            log_level: LevelFilter::Info,
+        }
+    }
+
+    /// Number of control scores sampled for each kernel density estimate
+    pub fn samples(&mut self, samples: usize) -> &mut Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Sample an equal number of control scores from each chromosome,
+    /// defaults to true so well covered chromosomes don't dominate
+    pub fn stratify(&mut self, stratify: bool) -> &mut Self {
+        self.stratify = stratify;
+        self
+    }
+
+    /// Positions covered by fewer reads are left out of the occupancy track
+    pub fn min_coverage(&mut self, min_coverage: u64) -> &mut Self {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Remove the output directory before running if it already exists
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Write each step as a JSON line to log.jsonl in the output directory
+    pub fn json_log(&mut self, json_log: bool) -> &mut Self {
+        self.json_log = json_log;
+        self
+    }
+
+    /// Level of logging written to log.txt in the output directory
+    pub fn log_level(&mut self, log_level: LevelFilter) -> &mut Self {
+        self.log_level = log_level;
+        self
+    }
+
+    fn ctrls(&self) -> CtrlModels {
+        CtrlModels::from_train_ctrls_dir(&self.ctrls_dir)
+    }
+
+    fn ctrl_scored(&self) -> (PathBuf, PathBuf) {
+        (
+            self.ctrls_dir.join("pos_scored.arrow"),
+            self.ctrls_dir.join("neg_scored.arrow"),
+        )
+    }
+
+    pub fn run(&self) -> Result<()> {
+        if self.overwrite && self.output_dir.exists() {
+            fs::remove_dir_all(&self.output_dir)?;
+        }
+        fs::create_dir_all(&self.output_dir)?;
+
+        let log_file = File::create(self.output_dir.join("log.txt"))?;
+        simple_logging::log_to(log_file, self.log_level);
+        let _json_log = self
+            .json_log
+            .then(|| utils::json_log_to(self.output_dir.join("log.jsonl")))
+            .transpose()?;
+        log::info!("{self:?}");
+        let mut steps = StepCache::open(&self.output_dir, self.force)?;
+        let name = parse_name_from_output_dir(&self.output_dir)?;
+        let ctrls = self.ctrls();
+        let outputs = Outputs::new(&self.output_dir, &name);
+
+        let step = Step::new("cawlr score")
+            .input(&self.collapse)
+            .input(&ctrls.pos_model)
+            .input(&ctrls.neg_model)
+            .input(&ctrls.ranks)
+            .output(&outputs.scored)
+            .param(self.motifs.iter().join(","));
+        steps.run(step, || {
+            let mut scoring = ScoreOptions::load(&ctrls.pos_model, &ctrls.neg_model, &ctrls.ranks)?;
+            scoring.motifs(self.motifs.clone());
+            log::info!("{scoring:?}");
+            record_output(&outputs.scored);
+            scoring
+                .run(File::open(&self.collapse)?, File::create(&outputs.scored)?)
+                .wrap_err("cawlr npsmlr score failed")
+        })?;
+
+        let (pos_scored, neg_scored) = self.ctrl_scored();
+        let step = Step::new("cawlr model-scores (controls)")
+            .input(&pos_scored)
+            .input(&neg_scored)
+            .output(&outputs.pos_scores)
+            .output(&outputs.neg_scores)
+            .param(self.samples)
+            .param(self.stratify);
+        steps.run(step, || {
+            let (pos, neg) = rayon::join(
+                || self.model_scores(&pos_scored, &outputs.pos_scores),
+                || self.model_scores(&neg_scored, &outputs.neg_scores),
+            );
+            pos.wrap_err("Failed to estimate (+) control score distribution")?;
+            neg.wrap_err("Failed to estimate (-) control score distribution")
+        })?;
+
+        let step = Step::new("cawlr sma")
+            .input(&outputs.scored)
+            .input(&outputs.pos_scores)
+            .input(&outputs.neg_scores)
+            .output(&outputs.sma);
+        steps.run(step, || {
+            let mut sma_opts = SmaOptions::try_new(
+                &outputs.pos_scores,
+                &outputs.neg_scores,
+                self.motifs.clone(),
+                &outputs.sma,
+            )?;
+            sma_opts.track_name(format!("{name}.cawlr.sma"));
+            record_output(&outputs.sma);
+            sma_opts.run(&outputs.scored).wrap_err("cawlr sma failed")
+        })?;
+
+        let step = Step::new("Aggregating blocks")
+            .input(&outputs.sma)
+            .output(&outputs.agg_blocks);
+        steps.run(step, || {
+            record_output(&outputs.agg_blocks);
+            AggOptions::default()
+                .run(&outputs.sma, Some(&outputs.agg_blocks))
+                .wrap_err("Failed to aggregate single molecule data")
+        })?;
+
+        let step = Step::new("Occupancy track")
+            .input(&outputs.sma)
+            .input(&self.genome)
+            .output(&outputs.bedgraph)
+            .output(&outputs.bigwig)
+            .param(self.min_coverage);
+        steps.run(step, || {
+            record_output(&outputs.bedgraph);
+            record_output(&outputs.bigwig);
+            let mut track = TrackOptions::default();
+            track
+                .min_coverage(self.min_coverage)
+                .track_name(format!("{name}.occupancy"))
+                .bigwig(&outputs.bigwig, &self.genome);
+            track
+                .run(&outputs.sma, Some(&outputs.bedgraph))
+                .wrap_err("Failed to write occupancy track")
+        })?;
+
+        write_manifest(
+            &self.output_dir,
+            "sma-genome",
+            &[
+                ("scored", &outputs.scored),
+                ("pos_scores", &outputs.pos_scores),
+                ("neg_scores", &outputs.neg_scores),
+                ("sma", &outputs.sma),
+                ("agg_blocks", &outputs.agg_blocks),
+                ("occupancy_bedgraph", &outputs.bedgraph),
+                ("occupancy_bigwig", &outputs.bigwig),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// List the steps [run](Self::run) would perform and check that the input
+    /// files it needs exist, without running anything.
+    pub fn dry_run(&self) -> Result<Plan> {
+        let mut plan = Plan::default();
+        let ctrls = self.ctrls();
+        let (pos_scored, neg_scored) = self.ctrl_scored();
+        plan.input("Genome", &self.genome);
+        let mut fai = self.genome.as_os_str().to_owned();
+        fai.push(".fai");
+        plan.input("Genome index", PathBuf::from(fai));
+        plan.input("Collapse", &self.collapse);
+        plan.input("(+) ctrl model", &ctrls.pos_model);
+        plan.input("(-) ctrl model", &ctrls.neg_model);
+        plan.input("Ranks", &ctrls.ranks);
+        plan.input("(+) ctrl scored", &pos_scored);
+        plan.input("(-) ctrl scored", &neg_scored);
+
+        if self.overwrite && self.output_dir.exists() {
+            plan.step(
+                "remove existing output directory",
+                vec![format!("rm -r {}", self.output_dir.display())],
+            );
+        }
+
+        let name = parse_name_from_output_dir(&self.output_dir)?;
+        let outputs = Outputs::new(&self.output_dir, &name);
+        plan.step(
+            "cawlr score",
+            vec![format!(
+                "cawlr npsmlr score --input {} --pos-ctrl {} --neg-ctrl {} --ranks {} \
+                 --output {} --motif {}",
+                self.collapse.display(),
+                ctrls.pos_model.display(),
+                ctrls.neg_model.display(),
+                ctrls.ranks.display(),
+                outputs.scored.display(),
+                self.motifs.iter().join(",")
+            )],
+        );
+        let stratify = if self.stratify { " --stratify" } else { "" };
+        plan.step(
+            "cawlr model-scores (controls)",
+            [
+                (&pos_scored, &outputs.pos_scores),
+                (&neg_scored, &outputs.neg_scores),
+            ]
+            .iter()
+            .map(|(input, output)| {
+                format!(
+                    "cawlr model-scores --input {} --output {} --samples {}{stratify}",
+                    input.display(),
+                    output.display(),
+                    self.samples
+                )
+            })
+            .collect(),
+        );
+        plan.step(
+            "cawlr sma",
+            vec![format!(
+                "cawlr sma --input {} --pos-ctrl-scores {} --neg-ctrl-scores {} --motif {} \
+                 --output {}",
+                outputs.scored.display(),
+                outputs.pos_scores.display(),
+                outputs.neg_scores.display(),
+                self.motifs.iter().join(","),
+                outputs.sma.display()
+            )],
+        );
+        plan.step(
+            "Aggregating blocks",
+            vec![format!(
+                "aggregate nucleosome blocks from {} into {}",
+                outputs.sma.display(),
+                outputs.agg_blocks.display()
+            )],
+        );
+        plan.step(
+            "Occupancy track",
+            vec![format!(
+                "cawlr track --input {} --output {} --bigwig {} --genome {} --min-coverage {}",
+                outputs.sma.display(),
+                outputs.bedgraph.display(),
+                outputs.bigwig.display(),
+                self.genome.display(),
+                self.min_coverage
+            )],
+        );
+        Ok(plan)
+    }
+
+    fn model_scores(&self, scored: &Path, output: &Path) -> Result<()> {
+        let bkde = score_model::Options::default()
+            .samples(self.samples)
+            .stratify(self.stratify)
+            .run(File::open(scored)?)?;
+        bkde.save_as(output)?;
+        record_output(output);
+        Ok(())
+    }
+}
+
+/// Paths of the files written to the output directory
+struct Outputs {
+    scored: PathBuf,
+    pos_scores: PathBuf,
+    neg_scores: PathBuf,
+    sma: PathBuf,
+    agg_blocks: PathBuf,
+    bedgraph: PathBuf,
+    bigwig: PathBuf,
+}
+
+impl Outputs {
+    fn new(output_dir: &Path, name: &str) -> Self {
+        Outputs {
+            scored: output_dir.join("score.arrow"),
+            pos_scores: output_dir.join("pos_model_scores.pickle"),
+            neg_scores: output_dir.join("neg_model_scores.pickle"),
+            sma: output_dir.join(format!("{name}.cawlr.sma.bed")),
+            agg_blocks: output_dir.join(format!("{name}.cawlr.sma.tsv")),
+            bedgraph: output_dir.join(format!("{name}.occupancy.bedgraph")),
+            bigwig: output_dir.join(format!("{name}.occupancy.bw")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let opts = SmaGenomeOptions::new(
+            "genome.fa",
+            "sample.arrow",
+            "ctrls",
+            temp_dir.path().join("sample"),
+            vec!["2:GC".parse()?],
+        );
+        let plan = opts.dry_run()?;
+        let steps = plan.steps.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            [
+                "cawlr score",
+                "cawlr model-scores (controls)",
+                "cawlr sma",
+                "Aggregating blocks",
+                "Occupancy track"
+            ]
+        );
+        assert_eq!(plan.steps[1].commands.len(), 2);
+        assert!(plan.steps[1].commands[0].ends_with("--stratify"));
+        assert!(plan.steps[2].commands[0].contains("sample.cawlr.sma.bed"));
+        assert!(plan.steps[2].commands[0].contains("--motif 2:GC"));
+        assert!(plan
+            .problems
+            .iter()
+            .any(|p| p.contains("ctrls/pos_scored.arrow")));
+        assert!(!plan.is_valid());
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! Skipping pipeline steps that already completed in a previous run.
+//!
+//! A step is considered complete if all of its outputs exist and its
+//! fingerprint matches the one recorded in steps.json after it last
+//! succeeded. The fingerprint covers the step's parameters along with the
+//! path, size, and modification time of each input, so rerunning a step
+//! invalidates every step downstream of it.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    hash::Hasher,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use eyre::{Context, Result};
+use fnv::FnvHasher;
+
+use crate::utils::{skip_cmd, wrap_cmd};
+
+const STEPS_FILENAME: &str = "steps.json";
+
+/// Inputs, outputs, and parameters of a single pipeline step.
+pub(crate) struct Step {
+    msg: &'static str,
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    params: Vec<String>,
+}
+
+impl Step {
+    pub(crate) fn new(msg: &'static str) -> Self {
+        Step {
+            msg,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    pub(crate) fn input<P: AsRef<Path>>(mut self, input: P) -> Self {
+        self.inputs.push(input.as_ref().to_path_buf());
+        self
+    }
+
+    pub(crate) fn output<P: AsRef<Path>>(mut self, output: P) -> Self {
+        self.outputs.push(output.as_ref().to_path_buf());
+        self
+    }
+
+    /// Any setting that changes the outputs of the step
+    pub(crate) fn param<T: ToString>(mut self, param: T) -> Self {
+        self.params.push(param.to_string());
+        self
+    }
+
+    fn fingerprint(&self) -> Result<String> {
+        let mut hasher = FnvHasher::default();
+        hasher.write(self.msg.as_bytes());
+        for param in self.params.iter() {
+            hasher.write(param.as_bytes());
+        }
+        for output in self.outputs.iter() {
+            hasher.write(output.to_string_lossy().as_bytes());
+        }
+        for input in self.inputs.iter() {
+            let metadata = fs::metadata(input)
+                .wrap_err_with(|| format!("Failed to read input {}", input.display()))?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            hasher.write(input.to_string_lossy().as_bytes());
+            hasher.write_u64(metadata.len());
+            hasher.write_u64(modified.as_secs());
+            hasher.write_u32(modified.subsec_nanos());
+        }
+        Ok(format!("{:016x}", hasher.finish()))
+    }
+}
+
+/// Fingerprints of completed steps, stored as steps.json in the output
+/// directory.
+pub(crate) struct StepCache {
+    path: PathBuf,
+    completed: BTreeMap<String, String>,
+    force: bool,
+}
+
+impl StepCache {
+    /// If force is true, every step will be run regardless of whether it
+    /// completed previously.
+    pub(crate) fn open(output_dir: &Path, force: bool) -> Result<Self> {
+        let path = output_dir.join(STEPS_FILENAME);
+        let completed = if path.exists() && !force {
+            serde_json::from_reader(File::open(&path)?)
+                .wrap_err_with(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(StepCache {
+            path,
+            completed,
+            force,
+        })
+    }
+
+    fn is_complete(&self, step: &Step, fingerprint: &str) -> bool {
+        !self.force
+            && step.outputs.iter().all(|p| p.exists())
+            && self.completed.get(step.msg).map(String::as_str) == Some(fingerprint)
+    }
+
+    /// Run the step with [wrap_cmd], unless it already completed with the
+    /// same inputs.
+    pub(crate) fn run<F>(&mut self, step: Step, f: F) -> Result<()>
+    where
+        F: FnMut() -> Result<()>,
+    {
+        let fingerprint = step.fingerprint()?;
+        if self.is_complete(&step, &fingerprint) {
+            skip_cmd(step.msg, &step.outputs);
+            return Ok(());
+        }
+        self.completed.remove(step.msg);
+        let _span = tracing::info_span!("step", step = step.msg).entered();
+        wrap_cmd(step.msg, f)?;
+
+        self.completed.insert(step.msg.to_string(), fingerprint);
+        let writer = File::create(&self.path)?;
+        serde_json::to_writer_pretty(writer, &self.completed)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_step_cache() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("input.txt");
+        let output = temp_dir.path().join("output.txt");
+        fs::write(&input, "ACGT")?;
+
+        let n_runs = Cell::new(0);
+        let run_step = |force: bool, param: &str| -> Result<()> {
+            let mut cache = StepCache::open(temp_dir.path(), force)?;
+            let step = Step::new("copy").input(&input).output(&output).param(param);
+            cache.run(step, || {
+                n_runs.set(n_runs.get() + 1);
+                fs::copy(&input, &output)?;
+                Ok(())
+            })
+        };
+
+        run_step(false, "a")?;
+        assert_eq!(n_runs.get(), 1);
+
+        // Nothing changed, so the step is skipped
+        run_step(false, "a")?;
+        assert_eq!(n_runs.get(), 1);
+
+        run_step(true, "a")?;
+        assert_eq!(n_runs.get(), 2);
+
+        run_step(false, "b")?;
+        assert_eq!(n_runs.get(), 3);
+
+        fs::remove_file(&output)?;
+        run_step(false, "b")?;
+        assert_eq!(n_runs.get(), 4);
+
+        fs::write(&input, "ACGTACGT")?;
+        run_step(false, "b")?;
+        assert_eq!(n_runs.get(), 5);
+        Ok(())
+    }
+}
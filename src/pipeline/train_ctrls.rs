@@ -0,0 +1,639 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use eyre::{Context, Result};
+use itertools::Itertools;
+use log::LevelFilter;
+
+use super::{
+    external::{
+        aln_reads, concat_fastqs, eventalign_cmd, eventalign_collapse, minimap2_cmd, np_index,
+        np_index_cmd, samtools_sort_cmd,
+    },
+    plan::{cmd_str, concat_cmd_str, Plan},
+    steps::{Step, StepCache},
+    write_manifest, CtrlModels,
+};
+use crate::{
+    motif::Motif,
+    npsmlr::{train::TrainOptions, ScoreOptions},
+    rank::{RankOptions, Ranks},
+    score_model::Options,
+    train::Model,
+    utils::{self, check_if_failed, record_cmd, record_output, wrap_cmd, CawlrIO},
+};
+
+/// Train models for a positive and negative control dataset, starting from
+/// raw reads and fast5s.
+#[derive(Debug, Clone)]
+pub struct TrainCtrlsOptions {
+    genome: PathBuf,
+    pos_fast5: PathBuf,
+    pos_reads: PathBuf,
+    pos_summary: Option<PathBuf>,
+    neg_fast5: PathBuf,
+    neg_reads: PathBuf,
+    neg_summary: Option<PathBuf>,
+    output_dir: PathBuf,
+    nanopolish_path: Option<PathBuf>,
+    minimap2_path: Option<PathBuf>,
+    samtools_path: Option<PathBuf>,
+    n_threads: usize,
+    json_log: bool,
+    force: bool,
+    deterministic: bool,
+    motifs: Vec<Motif>,
+}
+
+impl TrainCtrlsOptions {
+    /// Reads can either be a single fastq or a directory of fastqs, which
+    /// will be concatenated into a single file within the output directory.
+    pub fn new(
+        genome: impl Into<PathBuf>,
+        pos_fast5: impl Into<PathBuf>,
+        pos_reads: impl Into<PathBuf>,
+        neg_fast5: impl Into<PathBuf>,
+        neg_reads: impl Into<PathBuf>,
+        output_dir: impl Into<PathBuf>,
+        motifs: Vec<Motif>,
+    ) -> Self {
+        TrainCtrlsOptions {
+            genome: genome.into(),
+            pos_fast5: pos_fast5.into(),
+            pos_reads: pos_reads.into(),
+            pos_summary: None,
+            neg_fast5: neg_fast5.into(),
+            neg_reads: neg_reads.into(),
+            neg_summary: None,
+            output_dir: output_dir.into(),
+            nanopolish_path: None,
+            minimap2_path: None,
+            samtools_path: None,
+            n_threads: 4,
+            json_log: false,
+            force: false,
+            deterministic: false,
+            motifs,
+        }
+    }
+
+    /// Path to sequencing_summary.txt for the positive control, speeds up
+    /// nanopolish indexing
+    pub fn pos_summary(&mut self, pos_summary: Option<PathBuf>) -> &mut Self {
+        self.pos_summary = pos_summary;
+        self
+    }
+
+    /// Path to sequencing_summary.txt for the negative control, speeds up
+    /// nanopolish indexing
+    pub fn neg_summary(&mut self, neg_summary: Option<PathBuf>) -> &mut Self {
+        self.neg_summary = neg_summary;
+        self
+    }
+
+    /// If None, will look for nanopolish in $PATH
+    pub fn nanopolish_path(&mut self, nanopolish_path: Option<PathBuf>) -> &mut Self {
+        self.nanopolish_path = nanopolish_path;
+        self
+    }
+
+    /// If None, will look for minimap2 in $PATH
+    pub fn minimap2_path(&mut self, minimap2_path: Option<PathBuf>) -> &mut Self {
+        self.minimap2_path = minimap2_path;
+        self
+    }
+
+    /// If None, will look for samtools in $PATH
+    pub fn samtools_path(&mut self, samtools_path: Option<PathBuf>) -> &mut Self {
+        self.samtools_path = samtools_path;
+        self
+    }
+
+    pub fn n_threads(&mut self, n_threads: usize) -> &mut Self {
+        self.n_threads = n_threads;
+        self
+    }
+
+    /// Write each step as a JSON line to log.jsonl in the output directory
+    pub fn json_log(&mut self, json_log: bool) -> &mut Self {
+        self.json_log = json_log;
+        self
+    }
+
+    /// Rerun every step, even those that completed in a previous run with the
+    /// same inputs
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Sample the training data of each kmer with a fixed seed, see
+    /// [TrainOptions::deterministic]
+    pub fn deterministic(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    // Takes a path reads and checks if it is a directory. If its a directory,
+    // all the fastqs will be concatenated into a single file in the output
+    // directory.
+    fn single_reads_path(&self, reads: &Path, name: &str) -> PathBuf {
+        if reads.is_dir() {
+            self.output_dir.join(name)
+        } else {
+            reads.to_path_buf()
+        }
+    }
+
+    /// List the steps [run](Self::run) would perform and check that the
+    /// binaries and input files it needs exist, without running anything.
+    pub fn dry_run(&self) -> Result<Plan> {
+        let mut plan = Plan::default();
+        plan.input("Genome", &self.genome);
+        plan.input("(+) ctrl fast5 directory", &self.pos_fast5);
+        plan.input("(+) ctrl reads", &self.pos_reads);
+        plan.input("(-) ctrl fast5 directory", &self.neg_fast5);
+        plan.input("(-) ctrl reads", &self.neg_reads);
+        if let Some(summary) = &self.pos_summary {
+            plan.input("(+) ctrl sequencing summary", summary);
+        }
+        if let Some(summary) = &self.neg_summary {
+            plan.input("(-) ctrl sequencing summary", summary);
+        }
+        let nanopolish = plan.binary("nanopolish", &self.nanopolish_path);
+        let minimap2 = plan.binary("minimap2", &self.minimap2_path);
+        let samtools = plan.binary("samtools", &self.samtools_path);
+        plan.binary("plot_scoring_dist.py", &None);
+
+        let neg_reads = self.single_reads_path(&self.neg_reads, "neg_reads.fastq");
+        if neg_reads != self.neg_reads {
+            plan.step(
+                "concatenate (-) ctrl fastqs",
+                vec![concat_cmd_str(&self.neg_reads, &neg_reads)],
+            );
+        }
+        let pos_reads = self.single_reads_path(&self.pos_reads, "pos_reads.fastq");
+        if pos_reads != self.pos_reads {
+            plan.step(
+                "concatenate (+) ctrl fastqs",
+                vec![concat_cmd_str(&self.pos_reads, &pos_reads)],
+            );
+        }
+
+        plan.step(
+            "nanopolish index for (+) ctrl",
+            vec![cmd_str(&np_index_cmd(
+                &nanopolish,
+                &self.pos_fast5,
+                &pos_reads,
+                &self.pos_summary,
+            ))],
+        );
+        plan.step(
+            "nanopolish index for (-) ctrl",
+            vec![cmd_str(&np_index_cmd(
+                &nanopolish,
+                &self.neg_fast5,
+                &neg_reads,
+                &self.neg_summary,
+            ))],
+        );
+
+        let pos_aln = self.output_dir.join("pos.bam");
+        let neg_aln = self.output_dir.join("neg.bam");
+        let aln_cmd = |reads: &Path, aln: &Path| {
+            format!(
+                "{} | {}",
+                cmd_str(&minimap2_cmd(
+                    &minimap2,
+                    &self.genome,
+                    reads,
+                    self.n_threads
+                )),
+                cmd_str(&samtools_sort_cmd(
+                    &samtools,
+                    &utils::tmp_dir().join("samtools"),
+                    aln
+                ))
+            )
+        };
+        plan.step("align (+) ctrl reads", vec![aln_cmd(&pos_reads, &pos_aln)]);
+        plan.step("align (-) ctrl reads", vec![aln_cmd(&neg_reads, &neg_aln)]);
+
+        let pos_collapse = self.output_dir.join("pos_collapse.arrow");
+        let neg_collapse = self.output_dir.join("neg_collapse.arrow");
+        let collapse_cmd = |reads: &Path, aln: &Path, collapse: &Path| {
+            let eventalign = eventalign_cmd(&nanopolish, reads, aln, &self.genome, self.n_threads);
+            format!(
+                "{} | cawlr collapse --bam {} --output {}",
+                cmd_str(&eventalign),
+                aln.display(),
+                collapse.display()
+            )
+        };
+        plan.step(
+            "nanopolish eventalign (+) ctrl | cawlr collapse",
+            vec![collapse_cmd(&pos_reads, &pos_aln, &pos_collapse)],
+        );
+        plan.step(
+            "nanopolish eventalign (-) ctrl | cawlr collapse",
+            vec![collapse_cmd(&neg_reads, &neg_aln, &neg_collapse)],
+        );
+
+        let ctrls = CtrlModels::from_train_ctrls_dir(&self.output_dir);
+        let motifs = self.motifs.iter().join(",");
+        let train_cmd = |collapse: &Path, model: &Path, single: &str| {
+            format!(
+                "cawlr npsmlr train --input {} --output {} --dbscan{single} --motif {motifs}",
+                collapse.display(),
+                model.display(),
+            )
+        };
+        plan.step(
+            "Train (+) ctrl",
+            vec![train_cmd(&pos_collapse, &ctrls.pos_model, "")],
+        );
+        plan.step(
+            "Train (-) ctrl",
+            vec![train_cmd(&neg_collapse, &ctrls.neg_model, " --single")],
+        );
+        plan.step(
+            "ranking model kmers",
+            vec![format!(
+                "cawlr rank build --pos-ctrl {} --neg-ctrl {} --output {}",
+                ctrls.pos_model.display(),
+                ctrls.neg_model.display(),
+                ctrls.ranks.display()
+            )],
+        );
+
+        let pos_scores_path = self.output_dir.join("pos_scored.arrow");
+        let neg_scores_path = self.output_dir.join("neg_scored.arrow");
+        let score_cmd = |collapse: &Path, scored: &Path| {
+            format!(
+                "cawlr npsmlr score --input {} --pos-ctrl {} --neg-ctrl {} --ranks {} --output {} \
+                 --motif {motifs}",
+                collapse.display(),
+                ctrls.pos_model.display(),
+                ctrls.neg_model.display(),
+                ctrls.ranks.display(),
+                scored.display()
+            )
+        };
+        plan.step(
+            "Scoring (+) ctrl",
+            vec![score_cmd(&pos_collapse, &pos_scores_path)],
+        );
+        plan.step(
+            "Scoring (-) ctrl",
+            vec![score_cmd(&neg_collapse, &neg_scores_path)],
+        );
+
+        let model_scores_cmd = |scored: &Path, bkde: &Path| {
+            format!(
+                "cawlr model-scores --input {} --output {}",
+                scored.display(),
+                bkde.display()
+            )
+        };
+        plan.step(
+            "(+) model score dist",
+            vec![model_scores_cmd(&pos_scores_path, &ctrls.pos_scores)],
+        );
+        plan.step(
+            "(-) model score dist",
+            vec![model_scores_cmd(&neg_scores_path, &ctrls.neg_scores)],
+        );
+        plan.step(
+            "Score dist",
+            vec![format!(
+                "plot_scoring_dist.py -i {} {} -o {}",
+                ctrls.neg_scores.display(),
+                ctrls.pos_scores.display(),
+                self.output_dir.join("score_dist.png").display()
+            )],
+        );
+        Ok(plan)
+    }
+
+    pub fn run(&self) -> Result<CtrlModels> {
+        log::info!("{self:?}");
+        let nanopolish = utils::find_binary("nanopolish", &self.nanopolish_path)?;
+        let minimap2 = utils::find_binary("minimap2", &self.minimap2_path)?;
+        let samtools = utils::find_binary("samtools", &self.samtools_path)?;
+
+        fs::create_dir_all(&self.output_dir)?;
+
+        let log_file_path = self.output_dir.join("log.txt");
+        let log_file = File::create(log_file_path)?;
+        simple_logging::log_to(log_file.try_clone()?, LevelFilter::Info);
+        let _json_log = self
+            .json_log
+            .then(|| utils::json_log_to(self.output_dir.join("log.jsonl")))
+            .transpose()?;
+        let mut steps = StepCache::open(&self.output_dir, self.force)?;
+
+        let neg_reads = self.single_reads_path(&self.neg_reads, "neg_reads.fastq");
+        if neg_reads != self.neg_reads {
+            let step = Step::new("concatenate (-) ctrl fastqs")
+                .input(&self.neg_reads)
+                .output(&neg_reads);
+            steps.run(step, || concat_fastqs(&self.neg_reads, &neg_reads))?;
+        }
+        let pos_reads = self.single_reads_path(&self.pos_reads, "pos_reads.fastq");
+        if pos_reads != self.pos_reads {
+            let step = Step::new("concatenate (+) ctrl fastqs")
+                .input(&self.pos_reads)
+                .output(&pos_reads);
+            steps.run(step, || concat_fastqs(&self.pos_reads, &pos_reads))?;
+        }
+
+        let step = Step::new("nanopolish index for (+) ctrl")
+            .input(&self.pos_fast5)
+            .input(&pos_reads)
+            .output(readdb_path(&pos_reads));
+        steps.run(step, || {
+            np_index(
+                &nanopolish,
+                &self.pos_fast5,
+                &pos_reads,
+                &self.pos_summary,
+                log_file.try_clone()?,
+            )
+        })?;
+        let step = Step::new("nanopolish index for (-) ctrl")
+            .input(&self.neg_fast5)
+            .input(&neg_reads)
+            .output(readdb_path(&neg_reads));
+        steps.run(step, || {
+            np_index(
+                &nanopolish,
+                &self.neg_fast5,
+                &neg_reads,
+                &self.neg_summary,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        let pos_aln = self.output_dir.join("pos.bam");
+        let step = Step::new("align (+) ctrl reads")
+            .input(&self.genome)
+            .input(&pos_reads)
+            .output(&pos_aln);
+        steps.run(step, || {
+            aln_reads(
+                &minimap2,
+                &samtools,
+                &self.genome,
+                &pos_reads,
+                &pos_aln,
+                self.n_threads,
+                log_file.try_clone()?,
+            )
+        })?;
+        let neg_aln = self.output_dir.join("neg.bam");
+        let step = Step::new("align (-) ctrl reads")
+            .input(&self.genome)
+            .input(&neg_reads)
+            .output(&neg_aln);
+        steps.run(step, || {
+            aln_reads(
+                &minimap2,
+                &samtools,
+                &self.genome,
+                &neg_reads,
+                &neg_aln,
+                self.n_threads,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        let pos_collapse = self.output_dir.join("pos_collapse.arrow");
+        let step = Step::new("nanopolish eventalign (+) ctrl | cawlr collapse")
+            .input(&self.genome)
+            .input(&pos_reads)
+            .input(&pos_aln)
+            .output(&pos_collapse);
+        steps.run(step, || {
+            eventalign_collapse(
+                &nanopolish,
+                &pos_reads,
+                &pos_aln,
+                &self.genome,
+                &pos_collapse,
+                self.n_threads,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        let neg_collapse = self.output_dir.join("neg_collapse.arrow");
+        let step = Step::new("nanopolish eventalign (-) ctrl | cawlr collapse")
+            .input(&self.genome)
+            .input(&neg_reads)
+            .input(&neg_aln)
+            .output(&neg_collapse);
+        steps.run(step, || {
+            eventalign_collapse(
+                &nanopolish,
+                &neg_reads,
+                &neg_aln,
+                &self.genome,
+                &neg_collapse,
+                self.n_threads,
+                log_file.try_clone()?,
+            )
+        })?;
+
+        let ctrls = CtrlModels::from_train_ctrls_dir(&self.output_dir);
+        let motifs = self.motifs.iter().join(",");
+
+        let pos_db_file = self.output_dir.join("pos.db.sqlite3");
+        let neg_db_file = self.output_dir.join("neg.db.sqlite3");
+
+        let step = Step::new("Train (+) ctrl")
+            .input(&pos_collapse)
+            .output(&ctrls.pos_model)
+            .param(&motifs);
+        steps.run(step, || {
+            log::info!("Starting  + training");
+            let pos_model = train_npsmlr(&pos_collapse, &pos_db_file, false, self)?;
+            pos_model.save_as(&ctrls.pos_model)?;
+            record_output(&ctrls.pos_model);
+            Ok(())
+        })?;
+        let step = Step::new("Train (-) ctrl")
+            .input(&neg_collapse)
+            .output(&ctrls.neg_model)
+            .param(&motifs);
+        steps.run(step, || {
+            log::info!("Starting - training");
+            let neg_model = train_npsmlr(&neg_collapse, &neg_db_file, true, self)?;
+            neg_model.save_as(&ctrls.neg_model)?;
+            record_output(&ctrls.neg_model);
+            Ok(())
+        })?;
+        let pos_model = Model::load(&ctrls.pos_model)?;
+        let neg_model = Model::load(&ctrls.neg_model)?;
+
+        let step = Step::new("ranking model kmers")
+            .input(&ctrls.pos_model)
+            .input(&ctrls.neg_model)
+            .output(&ctrls.ranks);
+        steps.run(step, || rank_models(&ctrls.ranks, &pos_model, &neg_model))?;
+        let ranks = Ranks::load(&ctrls.ranks)?;
+
+        let score_opts =
+            ScoreOptions::new(pos_model, neg_model, ranks, 10, 10.0, self.motifs.clone());
+
+        let pos_scores_path = self.output_dir.join("pos_scored.arrow");
+        let step = Step::new("Scoring (+) ctrl")
+            .input(&pos_collapse)
+            .input(&ctrls.pos_model)
+            .input(&ctrls.neg_model)
+            .input(&ctrls.ranks)
+            .output(&pos_scores_path)
+            .param(&motifs);
+        steps.run(step, || {
+            let pos_collapse = File::open(&pos_collapse)?;
+            let pos_scores = File::create(&pos_scores_path)?;
+            score_opts.run(pos_collapse, &pos_scores)?;
+            record_output(&pos_scores_path);
+            log::info!("Finished scoring positive control");
+            Ok(())
+        })?;
+
+        let neg_scores_path = self.output_dir.join("neg_scored.arrow");
+        let step = Step::new("Scoring (-) ctrl")
+            .input(&neg_collapse)
+            .input(&ctrls.pos_model)
+            .input(&ctrls.neg_model)
+            .input(&ctrls.ranks)
+            .output(&neg_scores_path)
+            .param(&motifs);
+        steps.run(step, || {
+            let neg_collapse = File::open(&neg_collapse)?;
+            let neg_scores = File::create(&neg_scores_path)?;
+            score_opts.run(neg_collapse, neg_scores)?;
+            record_output(&neg_scores_path);
+            log::info!("Finished scoring negative control");
+            Ok(())
+        })?;
+
+        let step = Step::new("(+) model score dist")
+            .input(&pos_scores_path)
+            .output(&ctrls.pos_scores);
+        steps.run(step, || {
+            let pos_scores = File::open(&pos_scores_path)?;
+            let pos_bkde = Options::default().run(pos_scores)?;
+            pos_bkde.save_as(&ctrls.pos_scores)?;
+            record_output(&ctrls.pos_scores);
+            log::info!("Completed BKDE for (+) control");
+            Ok(())
+        })?;
+
+        let step = Step::new("(-) model score dist")
+            .input(&neg_scores_path)
+            .output(&ctrls.neg_scores);
+        steps.run(step, || {
+            let neg_scores = File::open(&neg_scores_path)?;
+            let neg_bkde = Options::default().run(neg_scores)?;
+            neg_bkde.save_as(&ctrls.neg_scores)?;
+            record_output(&ctrls.neg_scores);
+            log::info!("Completed BKDE for (-) control");
+            Ok(())
+        })?;
+
+        let score_plot = self.output_dir.join("score_dist.png");
+        let step = Step::new("Score dist")
+            .input(&ctrls.pos_scores)
+            .input(&ctrls.neg_scores)
+            .output(&score_plot);
+        steps.run(step, || {
+            let mut score_dist_cmd = Command::new("plot_scoring_dist.py");
+            score_dist_cmd
+                .arg("-i")
+                .arg(&ctrls.neg_scores)
+                .arg(&ctrls.pos_scores)
+                .arg("-o")
+                .arg(&score_plot)
+                .arg("--title")
+                .arg("Score distribution between (+) and (-) controls");
+            record_cmd(&score_dist_cmd);
+            record_output(&score_plot);
+            score_dist_cmd.stderr(log_file.try_clone()?);
+            let output = score_dist_cmd.output()?;
+            check_if_failed(output).wrap_err("Score distribution command")?;
+
+            Ok(())
+        })?;
+
+        // Training is skipped if it completed in a previous run, so the
+        // databases may not exist
+        wrap_cmd("Cleaning up database files", || {
+            for db_file in [&pos_db_file, &neg_db_file] {
+                if db_file.exists() {
+                    fs::remove_file(db_file)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        write_manifest(
+            &self.output_dir,
+            "train-ctrls",
+            &[
+                ("pos_bam", &pos_aln),
+                ("neg_bam", &neg_aln),
+                ("pos_collapse", &pos_collapse),
+                ("neg_collapse", &neg_collapse),
+                ("pos_model", &ctrls.pos_model),
+                ("neg_model", &ctrls.neg_model),
+                ("ranks", &ctrls.ranks),
+                ("pos_scored", &pos_scores_path),
+                ("neg_scored", &neg_scores_path),
+                ("pos_scores", &ctrls.pos_scores),
+                ("neg_scores", &ctrls.neg_scores),
+                ("score_dist", &score_plot),
+            ],
+        )?;
+
+        Ok(ctrls)
+    }
+}
+
+/// nanopolish index writes several files next to the reads, the readdb is the
+/// last one written.
+fn readdb_path(reads: &Path) -> PathBuf {
+    let mut readdb = reads.as_os_str().to_owned();
+    readdb.push(".index.readdb");
+    PathBuf::from(readdb)
+}
+
+fn train_npsmlr(
+    collapse_file: &Path,
+    db_file: &Path,
+    single: bool,
+    opts: &TrainCtrlsOptions,
+) -> Result<Model> {
+    let train_opts = TrainOptions::default()
+        .dbscan(true)
+        .single(single)
+        .db_path(Some(db_file.to_path_buf()))
+        .motifs(opts.motifs.clone())
+        .deterministic(opts.deterministic);
+    let reader = BufReader::new(File::open(collapse_file)?);
+    let model = train_opts.run_model(reader)?;
+    Ok(model)
+}
+
+fn rank_models(rank_output: &Path, pos_model: &Model, neg_model: &Model) -> Result<()> {
+    let mut rank_opts = RankOptions::default();
+    let ranks = rank_opts.rank(pos_model, neg_model);
+    ranks.save_as(rank_output)?;
+    record_output(rank_output);
+    Ok(())
+}
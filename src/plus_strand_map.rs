@@ -1,55 +1,222 @@
-use std::{collections::hash_map::Entry, path::Path, str::from_utf8};
+use std::{path::Path, str::from_utf8};
 
 use bam::BamReader;
 use eyre::Result;
 use fnv::FnvHashMap;
 
-#[derive(Default)]
-pub struct PlusStrandMap(FnvHashMap<Vec<u8>, bool>);
+use crate::arrow::metadata::MappingInfo;
 
-impl PlusStrandMap {
-    fn new(db: FnvHashMap<Vec<u8>, bool>) -> Self {
-        Self(db)
+/// Eventalign positions can fall slightly outside the alignment they came from,
+/// ie at soft-clipped ends, so alignments are matched within this many bases
+const POSITION_SLACK: u64 = 50;
+
+/// Strand and mapping information of one alignment of a read from the BAM file
+#[derive(Debug, Clone, Copy)]
+struct Alignment {
+    /// Index of the chromosome in the BAM header, start, and end. None for
+    /// unmapped records or reads inserted without a position, which match
+    /// anywhere.
+    region: Option<(u32, u64, u64)>,
+    plus_stranded: bool,
+    /// 0 for primary alignments, 1 for supplementary, and 2 for secondary
+    rank: u8,
+    mapping: Option<MappingInfo>,
+}
+
+impl Alignment {
+    fn from_record(record: &bam::Record) -> Self {
+        let flag = record.flag();
+        let region = if flag.is_mapped() && record.ref_id() >= 0 {
+            let start = record.start().max(0) as u64;
+            let end = record.calculate_end().max(0) as u64;
+            Some((record.ref_id() as u32, start, end))
+        } else {
+            None
+        };
+        let rank = if flag.is_secondary() {
+            2
+        } else if flag.is_supplementary() {
+            1
+        } else {
+            0
+        };
+        Alignment {
+            region,
+            plus_stranded: !flag.is_reverse_strand(),
+            rank,
+            mapping: Some(MappingInfo::from_record(record)),
+        }
     }
 
+    fn is_primary(&self) -> bool {
+        self.rank == 0
+    }
+
+    fn overlaps(&self, ref_id: u32, pos: u64) -> bool {
+        match self.region {
+            Some((id, start, end)) => {
+                id == ref_id
+                    && start.saturating_sub(POSITION_SLACK) <= pos
+                    && pos <= end + POSITION_SLACK
+            }
+            None => true,
+        }
+    }
+}
+
+/// Strand of each read from the BAM file used with nanopolish eventalign.
+///
+/// Reads with supplementary or secondary alignments can be on different
+/// strands in each, so the alignment is found by the chromosome and position
+/// of the eventalign, preferring primary alignments.
+#[derive(Default)]
+pub struct PlusStrandMap {
+    reads: FnvHashMap<Vec<u8>, Vec<Alignment>>,
+    chroms: FnvHashMap<String, u32>,
+    primary_only: bool,
+}
+
+impl PlusStrandMap {
     pub fn from_bam_file<P: AsRef<Path>>(bam_file: P) -> Result<Self> {
-        let mut acc = FnvHashMap::default();
+        let mut reads: FnvHashMap<Vec<u8>, Vec<Alignment>> = FnvHashMap::default();
         let reader = BamReader::from_path(bam_file, 2u16)?;
+        let chroms = reader
+            .header()
+            .reference_names()
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.clone(), idx as u32))
+            .collect();
         for record in reader {
             let record = record?;
             let read_name = record.name();
 
             log::debug!("ReadName from bam: {:?}", from_utf8(read_name));
 
-            let plus_stranded = !record.flag().is_reverse_strand();
-            match acc.entry(read_name.to_owned()) {
-                Entry::Occupied(mut entry) => {
-                    let old_stranded = entry.insert(plus_stranded);
-                    if old_stranded != plus_stranded {
-                        log::debug!("Multimapped read has strand swap");
-                    }
-                }
-                Entry::Vacant(entry) => {
-                    entry.insert(plus_stranded);
-                }
-            }
+            let alignment = Alignment::from_record(&record);
+            reads
+                .entry(read_name.to_owned())
+                .or_default()
+                .push(alignment);
+        }
+        let map = PlusStrandMap {
+            reads,
+            chroms,
+            primary_only: false,
+        };
+        let n_ambiguous = map
+            .reads
+            .keys()
+            .filter(|read_id| map.is_ambiguous(read_id))
+            .count();
+        if n_ambiguous > 0 {
+            log::info!(
+                "{n_ambiguous} reads have alignments on both strands, the strand of each is \
+                 taken from the alignment at its position"
+            );
+        }
+        Ok(map)
+    }
+
+    /// Only use primary alignments, so eventalign from supplementary or
+    /// secondary alignments has no strand and is left out
+    pub fn primary_only(&mut self, primary_only: bool) -> &mut Self {
+        self.primary_only = primary_only;
+        self
+    }
+
+    fn alignments<'a>(&'a self, read_id: &[u8]) -> impl Iterator<Item = &'a Alignment> + 'a {
+        let primary_only = self.primary_only;
+        self.reads
+            .get(read_id)
+            .into_iter()
+            .flatten()
+            .filter(move |alignment| !primary_only || alignment.is_primary())
+    }
+
+    /// Alignment of the read covering pos on chrom, preferring primary, then
+    /// supplementary, then secondary alignments. If the BAM header doesn't
+    /// have chrom, ie it is named differently, any alignment of the read is
+    /// used.
+    fn alignment_at(&self, read_id: &[u8], chrom: &str, pos: u64) -> Option<&Alignment> {
+        match self.chroms.get(chrom) {
+            Some(&ref_id) => self
+                .alignments(read_id)
+                .filter(|alignment| alignment.overlaps(ref_id, pos))
+                .min_by_key(|alignment| alignment.rank),
+            None => self
+                .alignments(read_id)
+                .min_by_key(|alignment| alignment.rank),
         }
-        Ok(PlusStrandMap::new(acc))
     }
 
+    /// Whether the read is on the plus strand, from its primary alignment
     pub fn get<B>(&self, read_id: B) -> Option<bool>
     where
         B: AsRef<[u8]>,
     {
-        let read_id = read_id.as_ref();
-        self.0.get(read_id).cloned()
+        self.alignments(read_id.as_ref())
+            .min_by_key(|alignment| alignment.rank)
+            .map(|alignment| alignment.plus_stranded)
     }
 
+    /// Mapping quality, flags, and identity of the read's primary alignment
+    pub fn mapping<B>(&self, read_id: B) -> Option<MappingInfo>
+    where
+        B: AsRef<[u8]>,
+    {
+        self.alignments(read_id.as_ref())
+            .min_by_key(|alignment| alignment.rank)
+            .and_then(|alignment| alignment.mapping)
+    }
+
+    /// Whether the read is on the plus strand, from the alignment covering pos
+    /// on chrom
+    pub fn strand_at<B>(&self, read_id: B, chrom: &str, pos: u64) -> Option<bool>
+    where
+        B: AsRef<[u8]>,
+    {
+        self.alignment_at(read_id.as_ref(), chrom, pos)
+            .map(|alignment| alignment.plus_stranded)
+    }
+
+    /// Mapping quality, flags, and identity of the alignment covering pos on
+    /// chrom, the flags show if it is supplementary or secondary
+    pub fn mapping_at<B>(&self, read_id: B, chrom: &str, pos: u64) -> Option<MappingInfo>
+    where
+        B: AsRef<[u8]>,
+    {
+        self.alignment_at(read_id.as_ref(), chrom, pos)
+            .and_then(|alignment| alignment.mapping)
+    }
+
+    /// Whether the read has alignments on both strands
+    pub fn is_ambiguous<B>(&self, read_id: B) -> bool
+    where
+        B: AsRef<[u8]>,
+    {
+        let mut strands = self
+            .alignments(read_id.as_ref())
+            .map(|alignment| alignment.plus_stranded);
+        match strands.next() {
+            Some(first) => strands.any(|strand| strand != first),
+            None => false,
+        }
+    }
+
+    /// Add a read on one strand that matches any position, replacing its
+    /// alignments
     pub fn insert<B>(&mut self, read_id: B, plus_stranded: bool)
     where
         B: Into<Vec<u8>>,
     {
-        self.0.insert(read_id.into(), plus_stranded);
+        let alignment = Alignment {
+            region: None,
+            plus_stranded,
+            rank: 0,
+            mapping: None,
+        };
+        self.reads.insert(read_id.into(), vec![alignment]);
     }
 }
 
@@ -62,8 +229,10 @@ mod test {
         let filepath = "extra/single_read.bam";
         let psmap = PlusStrandMap::from_bam_file(filepath).unwrap();
         let read_id: &[u8] = b"20d1aac0-29de-43ae-a0ef-aa8a6766eb70";
-        assert!(psmap.0.contains_key(read_id));
+        assert!(psmap.reads.contains_key(read_id));
         assert_eq!(psmap.get(read_id), Some(true));
+        let mapping = psmap.mapping(read_id).unwrap();
+        assert!(mapping.identity.map_or(true, |i| (0.0..=1.0).contains(&i)));
     }
 
     #[test]
@@ -71,7 +240,40 @@ mod test {
         let filepath = "extra/pos_control.bam";
         let psmap = PlusStrandMap::from_bam_file(filepath).unwrap();
         let read_id: &[u8] = b"ca10c9e3-61d4-439b-abb3-078767d19f8c";
-        assert!(psmap.0.contains_key(read_id));
+        assert!(psmap.reads.contains_key(read_id));
         assert_eq!(psmap.get(read_id), Some(false));
     }
+
+    #[test]
+    fn test_supplementary_alignment() {
+        let alignment = |ref_id, start, plus_stranded, rank| Alignment {
+            region: Some((ref_id, start, start + 1000)),
+            plus_stranded,
+            rank,
+            mapping: None,
+        };
+        let mut psmap = PlusStrandMap::default();
+        psmap.chroms.insert("chrI".to_string(), 0);
+        psmap.chroms.insert("chrII".to_string(), 1);
+        psmap.reads.insert(
+            b"read".to_vec(),
+            vec![
+                alignment(1, 5000, false, 1),
+                alignment(0, 100, true, 0),
+                alignment(0, 5000, true, 2),
+            ],
+        );
+        assert!(psmap.is_ambiguous("read"));
+        assert_eq!(psmap.get("read"), Some(true));
+        assert_eq!(psmap.strand_at("read", "chrI", 120), Some(true));
+        assert_eq!(psmap.strand_at("read", "chrII", 5500), Some(false));
+        assert_eq!(psmap.strand_at("read", "chrII", 100), None);
+        // Differently named chromosomes fall back to the primary alignment
+        assert_eq!(psmap.strand_at("read", "I", 5500), Some(true));
+
+        psmap.primary_only(true);
+        assert_eq!(psmap.strand_at("read", "chrII", 5500), None);
+        assert_eq!(psmap.strand_at("read", "chrI", 5500), None);
+        assert!(!psmap.is_ambiguous("read"));
+    }
 }
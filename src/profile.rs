@@ -0,0 +1,316 @@
+//! Opt-in timing of analysis stages, for `--profile`.
+//!
+//! Stages and chunks of reads are wrapped in [tracing] spans. Without a
+//! profiler these cost next to nothing. [Profiler::start] installs a
+//! subscriber that times every span and writes them out in the Chrome trace
+//! event format, which can be opened in `chrome://tracing`,
+//! [Perfetto](https://ui.perfetto.dev) or converted into a flamegraph.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use eyre::Result;
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static TID: u64 = NEXT_TID.fetch_add(1, Ordering::Relaxed);
+}
+
+fn thread_id() -> u64 {
+    TID.with(|tid| *tid)
+}
+
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Fields,
+    refs: usize,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    spans: HashMap<u64, SpanData>,
+    /// Start times of entered spans per thread, a stack since spans can be
+    /// re-entered
+    entered: HashMap<(u64, u64), Vec<Instant>>,
+    events: Vec<Value>,
+    /// Total time and number of times each span was entered, by name
+    totals: HashMap<&'static str, (f64, u64)>,
+}
+
+struct Trace {
+    start: Instant,
+    state: Mutex<State>,
+}
+
+impl Trace {
+    fn micros(&self, instant: Instant) -> f64 {
+        instant.duration_since(self.start).as_secs_f64() * 1e6
+    }
+}
+
+/// Subscriber recording spans as Chrome trace events
+#[derive(Clone)]
+struct ChromeTrace(Arc<Trace>);
+
+impl Subscriber for ChromeTrace {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let mut state = self.0.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.spans.insert(
+            id,
+            SpanData {
+                metadata: attrs.metadata(),
+                fields,
+                refs: 1,
+            },
+        );
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(data) = state.spans.get_mut(&span.into_u64()) {
+            values.record(&mut data.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let now = Instant::now();
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let name = match fields.0.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => event.metadata().name().to_string(),
+        };
+        let trace_event = json!({
+            "name": name,
+            "cat": event.metadata().target(),
+            "ph": "i",
+            "s": "t",
+            "ts": self.0.micros(now),
+            "pid": process::id(),
+            "tid": thread_id(),
+            "args": fields.0,
+        });
+        self.0.state.lock().unwrap().events.push(trace_event);
+    }
+
+    fn enter(&self, span: &span::Id) {
+        let now = Instant::now();
+        let mut state = self.0.state.lock().unwrap();
+        state
+            .entered
+            .entry((span.into_u64(), thread_id()))
+            .or_default()
+            .push(now);
+    }
+
+    fn exit(&self, span: &span::Id) {
+        let now = Instant::now();
+        let tid = thread_id();
+        let mut state = self.0.state.lock().unwrap();
+        let key = (span.into_u64(), tid);
+        let start = match state.entered.get_mut(&key).and_then(|s| s.pop()) {
+            Some(start) => start,
+            None => return,
+        };
+        if state.entered.get(&key).map_or(false, |s| s.is_empty()) {
+            state.entered.remove(&key);
+        }
+        let (name, target, args) = match state.spans.get(&key.0) {
+            Some(data) => (
+                data.metadata.name(),
+                data.metadata.target(),
+                data.fields.0.clone(),
+            ),
+            None => return,
+        };
+        let dur = now.duration_since(start).as_secs_f64();
+        let total = state.totals.entry(name).or_insert((0.0, 0));
+        total.0 += dur;
+        total.1 += 1;
+        let trace_event = json!({
+            "name": name,
+            "cat": target,
+            "ph": "X",
+            "ts": self.0.micros(start),
+            "dur": dur * 1e6,
+            "pid": process::id(),
+            "tid": tid,
+            "args": args,
+        });
+        state.events.push(trace_event);
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(data) = state.spans.get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        let id = span.into_u64();
+        let closed = match state.spans.get_mut(&id) {
+            Some(data) => {
+                data.refs -= 1;
+                data.refs == 0
+            }
+            None => false,
+        };
+        if closed {
+            state.spans.remove(&id);
+        }
+        closed
+    }
+}
+
+/// Records timing spans while it's alive, and writes them as a Chrome trace
+/// JSON file once finished or dropped.
+pub struct Profiler {
+    trace: Arc<Trace>,
+    path: PathBuf,
+    finished: bool,
+}
+
+impl Profiler {
+    fn new<P: AsRef<Path>>(path: P) -> (Self, ChromeTrace) {
+        let trace = Arc::new(Trace {
+            start: Instant::now(),
+            state: Mutex::new(State::default()),
+        });
+        let profiler = Profiler {
+            trace: trace.clone(),
+            path: path.as_ref().to_path_buf(),
+            finished: false,
+        };
+        (profiler, ChromeTrace(trace))
+    }
+
+    /// Start recording spans from every thread, written to path when the
+    /// Profiler is finished. Can only be started once per process.
+    pub fn start<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let (profiler, subscriber) = Profiler::new(path);
+        tracing::subscriber::set_global_default(subscriber)
+            .map_err(|_| eyre::eyre!("Profiling was already started"))?;
+        Ok(profiler)
+    }
+
+    /// Write the trace and log how long each kind of span took in total.
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.write()
+    }
+
+    fn write(&self) -> Result<()> {
+        let state = self.trace.state.lock().unwrap();
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(&mut writer, &json!({ "traceEvents": state.events }))?;
+        writer.flush()?;
+
+        let mut totals: Vec<_> = state.totals.iter().collect();
+        totals.sort_by(|a, b| b.1 .0.partial_cmp(&a.1 .0).unwrap());
+        for (name, (secs, n)) in totals {
+            log::info!("Profile: {name} took {secs:.3}s over {n} spans");
+        }
+        log::info!("Wrote profile to {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.write() {
+                log::warn!("Failed to write profile: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chrome_trace() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("profile.json");
+        let (profiler, subscriber) = Profiler::new(&path);
+        tracing::subscriber::with_default(subscriber, || {
+            let stage = tracing::info_span!("stage", stage = "score").entered();
+            for n in 0..2u64 {
+                let chunk = tracing::info_span!("chunk", reads = tracing::field::Empty);
+                let _entered = chunk.enter();
+                chunk.record("reads", n);
+            }
+            tracing::info!("done");
+            drop(stage);
+        });
+        profiler.finish()?;
+
+        let trace: Value = serde_json::from_reader(File::open(&path)?)?;
+        let events = trace["traceEvents"].as_array().unwrap();
+        let names: Vec<_> = events.iter().map(|e| e["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["chunk", "chunk", "done", "stage"]);
+        assert_eq!(events[1]["ph"], "X");
+        assert_eq!(events[1]["args"]["reads"], 1);
+        assert_eq!(events[2]["ph"], "i");
+        assert_eq!(events[3]["args"]["stage"], "score");
+        assert!(events[3]["dur"].as_f64().unwrap() >= events[0]["dur"].as_f64().unwrap());
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Progress reporting for embedding cawlr in other applications.
+//!
+//! Implement [ProgressSink] and pass it to the `progress_sink` setter on
+//! [CollapseOptions](crate::collapse::CollapseOptions),
+//! [ScoreOptions](crate::score::ScoreOptions),
+//! [npsmlr::ScoreOptions](crate::npsmlr::ScoreOptions),
+//! [Train](crate::train::Train), [SmaOptions](crate::sma::SmaOptions),
+//! [score_model::Options](crate::score_model::Options), or
+//! [AggOptions](crate::agg_blocks::AggOptions) to receive updates as data is
+//! processed.
+//!
+//! Stages without a sink don't report progress. Pass a [ProgressBarSink] for a
+//! progress bar on stderr, like the cawlr binary does unless --no-progress is
+//! passed.
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use indicatif::{style::TemplateError, ProgressBar, ProgressStyle};
+use tracing::span::EnteredSpan;
+
+/// Step of the analysis reporting progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Collapse,
+    Score,
+    Train,
+    Sma,
+    ModelScores,
+    AggBlocks,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let res = match self {
+            Stage::Collapse => "collapse",
+            Stage::Score => "score",
+            Stage::Train => "train",
+            Stage::Sma => "sma",
+            Stage::ModelScores => "model-scores",
+            Stage::AggBlocks => "agg-blocks",
+        };
+        write!(f, "{res}")
+    }
+}
+
+/// Snapshot of how much data a stage has processed so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub stage: Stage,
+    /// Number of reads processed
+    pub reads: u64,
+    /// Number of chunks of reads processed
+    pub chunks: u64,
+    /// Total number of chunks in the input, None if it isn't known ahead of
+    /// time, ie when streaming nanopolish eventalign output.
+    pub total_chunks: Option<u64>,
+}
+
+impl Progress {
+    fn new(stage: Stage) -> Self {
+        Progress {
+            stage,
+            reads: 0,
+            chunks: 0,
+            total_chunks: None,
+        }
+    }
+
+    /// Fraction of chunks processed, if the total is known
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total_chunks {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.chunks as f64 / total as f64),
+            None => None,
+        }
+    }
+}
+
+/// Receives progress events, must be thread-safe since some stages process
+/// reads in parallel.
+pub trait ProgressSink: Send + Sync {
+    /// Called after each chunk of reads is processed.
+    fn update(&self, progress: &Progress);
+
+    /// Called once the stage has processed all of its input.
+    fn finish(&self, _progress: &Progress) {}
+}
+
+/// Draws a progress bar on stderr for each stage. Shows the fraction of
+/// chunks processed with an estimated time remaining when the total is known,
+/// ie for Arrow input, and a spinner with the number of reads otherwise.
+pub struct ProgressBarSink {
+    bar: Mutex<Option<(Stage, ProgressBar)>>,
+    bar_style: ProgressStyle,
+    spinner_style: ProgressStyle,
+}
+
+impl ProgressBarSink {
+    pub fn new() -> Result<Self, TemplateError> {
+        Ok(ProgressBarSink {
+            bar: Mutex::new(None),
+            bar_style: ProgressStyle::with_template(
+                "{prefix} [{elapsed_precise}] {wide_bar} {pos}/{len} chunks, {msg} reads (ETA \
+                 {eta})",
+            )?,
+            spinner_style: ProgressStyle::with_template(
+                "{prefix} {spinner} [{elapsed_precise}] {msg} reads",
+            )?,
+        })
+    }
+
+    fn new_bar(&self, progress: &Progress) -> ProgressBar {
+        let bar = match progress.total_chunks {
+            Some(total) => ProgressBar::new(total).with_style(self.bar_style.clone()),
+            None => ProgressBar::new_spinner().with_style(self.spinner_style.clone()),
+        };
+        bar.with_prefix(progress.stage.to_string())
+    }
+}
+
+impl ProgressSink for ProgressBarSink {
+    fn update(&self, progress: &Progress) {
+        let mut bar = self.bar.lock().unwrap();
+        let same_bar = matches!(
+            &*bar,
+            Some((stage, bar)) if *stage == progress.stage && bar.length() == progress.total_chunks
+        );
+        if !same_bar {
+            *bar = Some((progress.stage, self.new_bar(progress)));
+        }
+        if let Some((_, bar)) = &*bar {
+            bar.set_position(progress.chunks);
+            bar.set_message(progress.reads.to_string());
+        }
+    }
+
+    fn finish(&self, progress: &Progress) {
+        self.update(progress);
+        if let Some((_, bar)) = self.bar.lock().unwrap().take() {
+            bar.finish();
+        }
+    }
+}
+
+/// Keeps track of counts for a stage and forwards them to the sink, if there
+/// is one. Also times the stage and each chunk with [tracing] spans for
+/// [profile](crate::profile).
+pub(crate) struct Reporter {
+    // Declared before stage_span so it is exited first
+    chunk_span: Option<EnteredSpan>,
+    _stage_span: EnteredSpan,
+    sink: Option<Arc<dyn ProgressSink>>,
+    progress: Progress,
+}
+
+fn chunk_span() -> EnteredSpan {
+    tracing::info_span!("chunk", reads = tracing::field::Empty).entered()
+}
+
+impl Reporter {
+    pub(crate) fn new(stage: Stage, sink: Option<Arc<dyn ProgressSink>>) -> Self {
+        let stage_span = tracing::info_span!("stage", stage = %stage).entered();
+        Reporter {
+            chunk_span: Some(chunk_span()),
+            _stage_span: stage_span,
+            sink,
+            progress: Progress::new(stage),
+        }
+    }
+
+    pub(crate) fn total_chunks(&mut self, total_chunks: usize) {
+        self.progress.total_chunks = Some(total_chunks as u64);
+    }
+
+    pub(crate) fn chunk(&mut self, n_reads: usize) {
+        if let Some(span) = self.chunk_span.take() {
+            span.record("reads", n_reads);
+        }
+        self.chunk_span = Some(chunk_span());
+        self.progress.reads += n_reads as u64;
+        self.progress.chunks += 1;
+        if let Some(sink) = &self.sink {
+            sink.update(&self.progress);
+        }
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.chunk_span = None;
+        if let Some(sink) = &self.sink {
+            sink.finish(&self.progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Progress>>, Mutex<Option<Progress>>);
+
+    impl ProgressSink for Recorder {
+        fn update(&self, progress: &Progress) {
+            self.0.lock().unwrap().push(*progress);
+        }
+
+        fn finish(&self, progress: &Progress) {
+            *self.1.lock().unwrap() = Some(*progress);
+        }
+    }
+
+    #[test]
+    fn test_reporter() {
+        let recorder = Arc::new(Recorder::default());
+        let mut reporter = Reporter::new(Stage::Score, Some(recorder.clone()));
+        reporter.total_chunks(2);
+        reporter.chunk(10);
+        reporter.chunk(5);
+        reporter.finish();
+
+        let updates = recorder.0.lock().unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].reads, 10);
+        assert_eq!(updates[0].fraction(), Some(0.5));
+        let last = recorder.1.lock().unwrap().unwrap();
+        assert_eq!(last.stage, Stage::Score);
+        assert_eq!(last.reads, 15);
+        assert_eq!(last.chunks, 2);
+        assert_eq!(last.fraction(), Some(1.0));
+    }
+
+    #[test]
+    fn test_progress_bar_sink() {
+        let sink = ProgressBarSink::new().unwrap();
+        let mut progress = Progress::new(Stage::Train);
+        progress.total_chunks = Some(4);
+        progress.chunks = 1;
+        progress.reads = 10;
+        sink.update(&progress);
+        {
+            let bar = sink.bar.lock().unwrap();
+            let (stage, bar) = bar.as_ref().unwrap();
+            assert_eq!(*stage, Stage::Train);
+            assert_eq!(bar.length(), Some(4));
+            assert_eq!(bar.position(), 1);
+            assert_eq!(bar.message(), "10");
+        }
+
+        // A new stage gets its own bar
+        let mut progress = Progress::new(Stage::Sma);
+        progress.chunks = 2;
+        sink.update(&progress);
+        assert_eq!(sink.bar.lock().unwrap().as_ref().unwrap().0, Stage::Sma);
+        sink.finish(&progress);
+        assert!(sink.bar.lock().unwrap().is_none());
+    }
+}
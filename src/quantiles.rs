@@ -0,0 +1,322 @@
+//! Quantiles of the score distribution, computed in a single pass over scored
+//! data, to help pick the cutoff for counting a position as modified.
+use std::io::Write;
+
+use eyre::Result;
+
+use crate::arrow::io::{read_mod_bam_or_arrow, ModFile};
+
+/// Number of histogram bins between 0 and 1 used for the Otsu threshold
+const OTSU_BINS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// Merging t-digest, approximates quantiles of a stream of values using
+/// memory bounded by the compression, with the most accuracy near the tails.
+#[derive(Debug, Clone)]
+pub(crate) struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub(crate) fn new(compression: f64) -> Self {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub(crate) fn add(&mut self, x: f64) {
+        self.buffer.push(x);
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        if self.buffer.len() >= (self.compression as usize) * 10 {
+            self.compress();
+        }
+    }
+
+    /// Scale function mapping quantiles to centroid indices, steepest near the
+    /// tails so centroids there stay small
+    fn k_scale(&self, q: f64) -> f64 {
+        self.compression / (2.0 * std::f64::consts::PI) * (2.0 * q.min(1.0) - 1.0).asin()
+    }
+
+    /// Merge buffered values into the centroids, where each centroid can only
+    /// span one unit of the scale function.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points = std::mem::take(&mut self.centroids);
+        points.extend(
+            self.buffer
+                .drain(..)
+                .map(|mean| Centroid { mean, weight: 1.0 }),
+        );
+        points.sort_by(|a, b| a.mean.partial_cmp(&b.mean).unwrap());
+        let total = points.iter().map(|c| c.weight).sum::<f64>();
+
+        let mut merged: Vec<Centroid> = Vec::with_capacity(points.len());
+        let mut cumulative = 0.0;
+        let mut k_left = self.k_scale(0.0);
+        for point in points {
+            if let Some(last) = merged.last_mut() {
+                let weight = last.weight + point.weight;
+                if self.k_scale((cumulative + weight) / total) - k_left <= 1.0 {
+                    last.mean += (point.mean - last.mean) * point.weight / weight;
+                    last.weight = weight;
+                    continue;
+                }
+                cumulative += last.weight;
+                k_left = self.k_scale(cumulative / total);
+            }
+            merged.push(point);
+        }
+        self.centroids = merged;
+        self.count = total;
+    }
+
+    /// Estimate the value at quantile q by interpolating between the centers
+    /// of neighboring centroids, None if no values were added
+    pub(crate) fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.compress();
+        let (first, last) = (self.centroids.first()?, self.centroids.last()?);
+        let target = q.clamp(0.0, 1.0) * self.count;
+        if target <= first.weight / 2.0 {
+            let frac = target / (first.weight / 2.0);
+            return Some(self.min + (first.mean - self.min) * frac);
+        }
+        if target >= self.count - last.weight / 2.0 {
+            let frac = (self.count - target) / (last.weight / 2.0);
+            return Some(self.max - (self.max - last.mean) * frac);
+        }
+        let mut cumulative = 0.0;
+        for pair in self.centroids.windows(2) {
+            let left_center = cumulative + pair[0].weight / 2.0;
+            let right_center = cumulative + pair[0].weight + pair[1].weight / 2.0;
+            if target <= right_center {
+                let frac = (target - left_center) / (right_center - left_center);
+                return Some(pair[0].mean + (pair[1].mean - pair[0].mean) * frac);
+            }
+            cumulative += pair[0].weight;
+        }
+        Some(last.mean)
+    }
+}
+
+/// Linear interpolation between the closest ranks of sorted values
+fn exact_quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64))
+}
+
+/// Threshold that best splits a histogram into two classes, by maximizing the
+/// variance between them. Works well when scores are bimodal, with modified
+/// and unmodified positions each making a peak.
+fn otsu_threshold(histogram: &[u64]) -> Option<f64> {
+    let n_bins = histogram.len() as f64;
+    let total = histogram.iter().sum::<u64>() as f64;
+    let total_sum = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| i as f64 * c as f64)
+        .sum::<f64>();
+    let mut best = None;
+    let mut best_variance = 0.0;
+    let (mut below, mut below_sum) = (0.0, 0.0);
+    for (i, &count) in histogram.iter().enumerate() {
+        below += count as f64;
+        below_sum += i as f64 * count as f64;
+        let above = total - below;
+        if below == 0.0 || above == 0.0 {
+            continue;
+        }
+        let mean_diff = below_sum / below - (total_sum - below_sum) / above;
+        let variance = below * above * mean_diff * mean_diff;
+        if variance > best_variance {
+            best_variance = variance;
+            best = Some((i + 1) as f64 / n_bins);
+        }
+    }
+    best
+}
+
+/// Quantiles of every score in the input, and a suggested modification
+/// threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreQuantiles {
+    pub n_scores: usize,
+    /// Pairs of quantile and the estimated score at that quantile
+    pub quantiles: Vec<(f64, f64)>,
+    /// Threshold splitting scores into unmodified and modified, see
+    /// [Otsu's method](https://en.wikipedia.org/wiki/Otsu%27s_method)
+    pub otsu_threshold: Option<f64>,
+}
+
+impl ScoreQuantiles {
+    /// Tab-separated statistic and value pairs
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "statistic\tvalue")?;
+        writeln!(writer, "n_scores\t{}", self.n_scores)?;
+        for (q, value) in self.quantiles.iter() {
+            writeln!(writer, "q{q}\t{value}")?;
+        }
+        if let Some(threshold) = self.otsu_threshold {
+            writeln!(writer, "otsu_threshold\t{threshold}")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Stream scores from cawlr score or a modification BAM once and estimate
+/// quantiles of the score distribution.
+pub struct QuantileOptions {
+    quantiles: Vec<f64>,
+    exact: bool,
+    compression: f64,
+}
+
+impl Default for QuantileOptions {
+    fn default() -> Self {
+        QuantileOptions {
+            quantiles: vec![0.01, 0.05, 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99],
+            exact: false,
+            compression: 200.0,
+        }
+    }
+}
+
+impl QuantileOptions {
+    /// Quantiles to estimate, between 0 and 1
+    pub fn quantiles<V: Into<Vec<f64>>>(&mut self, quantiles: V) -> &mut Self {
+        self.quantiles = quantiles.into();
+        self
+    }
+
+    /// Keep every score in memory to compute exact quantiles, instead of
+    /// approximating with a t-digest
+    pub fn exact(&mut self, exact: bool) -> &mut Self {
+        self.exact = exact;
+        self
+    }
+
+    /// Higher compression keeps more centroids in the t-digest for more
+    /// accurate quantiles, defaults to 200
+    pub fn compression(&mut self, compression: f64) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn run(&self, mod_file: ModFile) -> Result<ScoreQuantiles> {
+        if let Some(q) = self.quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+            return Err(eyre::eyre!("Quantile {q} is not between 0 and 1"));
+        }
+        let mut digest = TDigest::new(self.compression);
+        let mut all_scores = Vec::new();
+        let mut histogram = vec![0u64; OTSU_BINS];
+        let mut n_scores = 0;
+        read_mod_bam_or_arrow(mod_file, |read| {
+            for score in read.scores().iter().map(|s| s.score) {
+                if score.is_nan() {
+                    continue;
+                }
+                n_scores += 1;
+                let bin = (score.clamp(0.0, 1.0) * OTSU_BINS as f64) as usize;
+                histogram[bin.min(OTSU_BINS - 1)] += 1;
+                if self.exact {
+                    all_scores.push(score);
+                } else {
+                    digest.add(score);
+                }
+            }
+            Ok(())
+        })?;
+
+        all_scores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let quantiles = self
+            .quantiles
+            .iter()
+            .filter_map(|&q| {
+                let value = if self.exact {
+                    exact_quantile(&all_scores, q)
+                } else {
+                    digest.quantile(q)
+                };
+                value.map(|value| (q, value))
+            })
+            .collect();
+        Ok(ScoreQuantiles {
+            n_scores,
+            quantiles,
+            otsu_threshold: otsu_threshold(&histogram),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_tdigest() {
+        let mut rng = SmallRng::seed_from_u64(2456);
+        let mut values = (0..100_000)
+            .map(|_| rng.gen::<f64>().powi(2))
+            .collect::<Vec<_>>();
+        let mut digest = TDigest::new(200.0);
+        values.iter().for_each(|&x| digest.add(x));
+        assert!(digest.centroids.len() <= 200);
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for q in [0.0, 0.001, 0.01, 0.1, 0.5, 0.9, 0.99, 0.999, 1.0] {
+            let expected = exact_quantile(&values, q).unwrap();
+            let estimate = digest.quantile(q).unwrap();
+            assert!(
+                (estimate - expected).abs() < 0.005,
+                "q{q}: {estimate} != {expected}"
+            );
+        }
+        assert_eq!(TDigest::new(200.0).quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_exact_quantile() {
+        let values = [0.0, 0.1, 0.2, 0.3, 0.4];
+        assert_eq!(exact_quantile(&values, 0.5), Some(0.2));
+        assert!((exact_quantile(&values, 0.125).unwrap() - 0.05).abs() < 1e-10);
+        assert_eq!(exact_quantile(&values, 1.0), Some(0.4));
+        assert_eq!(exact_quantile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn test_otsu_threshold() {
+        let mut histogram = vec![0u64; 100];
+        for (i, count) in histogram.iter_mut().enumerate() {
+            if (10..20).contains(&i) || (70..90).contains(&i) {
+                *count = 50;
+            }
+        }
+        let threshold = otsu_threshold(&histogram).unwrap();
+        assert!((0.2..=0.7).contains(&threshold), "{threshold}");
+        assert_eq!(otsu_threshold(&[0, 10, 0]), None);
+    }
+}
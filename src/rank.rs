@@ -1,33 +1,41 @@
-use fnv::{FnvHashMap, FnvHashSet};
+use std::{cmp::Ordering, io::Write};
+
+use eyre::Result;
 use rand::{prelude::SmallRng, SeedableRng};
-use rv::traits::{ContinuousDistr, Rv};
+use rv::{
+    dist::{Gaussian, Mixture},
+    traits::{ContinuousDistr, Rv},
+};
 
 use crate::{
+    kmer_map::KmerMap,
+    repro,
     score::{choose_model, choose_pos_model},
-    train::Model,
+    train::{Model, ModelParams},
 };
 
-pub type Ranks = FnvHashMap<String, f64>;
+pub type Ranks = KmerMap<f64>;
 
 pub struct RankOptions {
     rng: SmallRng,
+    seed: u64,
     n_samples: usize,
 }
 
 impl Default for RankOptions {
     fn default() -> Self {
-        let rng = SmallRng::seed_from_u64(2456);
-        RankOptions {
-            rng,
-            n_samples: 10_000,
-        }
+        RankOptions::new(2456, 10_000)
     }
 }
 
 impl RankOptions {
     pub fn new(seed: u64, n_samples: usize) -> Self {
         let rng = SmallRng::seed_from_u64(seed);
-        RankOptions { rng, n_samples }
+        RankOptions {
+            rng,
+            seed,
+            n_samples,
+        }
     }
 
     // Approximate the Kulback-Leibler Divergence for the two GMMs as mentioned in
@@ -58,35 +66,230 @@ impl RankOptions {
         self.n_samples as f64
     }
 
+    fn record_seed(&self) {
+        let params = serde_json::json!({ "samples": self.n_samples });
+        repro::record_seed("rank", self.seed, params);
+    }
+
     pub fn rank(&mut self, pos_ctrl: &Model, neg_ctrl: &Model) -> Ranks {
-        let mut kmer_ranks = FnvHashMap::default();
-        let pos_ctrl_kmers = pos_ctrl.gmms().keys().collect::<FnvHashSet<&String>>();
-        let neg_ctrl_kmers = neg_ctrl.gmms().keys().collect::<FnvHashSet<&String>>();
-        let kmers = pos_ctrl_kmers.intersection(&neg_ctrl_kmers);
-        for &kmer in kmers {
-            let neg_ctrl_model = &neg_ctrl.gmms()[kmer].mixture();
-            let pos_ctrl_model = &pos_ctrl.gmms()[kmer].mixture();
+        self.record_seed();
+        let mut kmer_ranks = Ranks::default();
+        for (kmer, pos_params) in pos_ctrl.gmms().iter() {
+            let Some(neg_params) = neg_ctrl.gmms().get(&kmer) else {
+                continue;
+            };
+            let neg_ctrl_model = &neg_params.mixture();
+            let pos_ctrl_model = &pos_params.mixture();
 
             let neg_ctrl_model = choose_model(neg_ctrl_model);
             let pos_ctrl_model = choose_pos_model(neg_ctrl_model, pos_ctrl_model);
 
             let kl = self.kl_approx(pos_ctrl_model, neg_ctrl_model);
-            kmer_ranks.insert(kmer.clone(), kl);
+            kmer_ranks.insert(kmer, kl);
         }
         kmer_ranks
     }
 
     pub fn rank_npsmlr(&mut self, pos_ctrl: &Model, neg_ctrl: &Model) -> Ranks {
-        let mut kmer_ranks = FnvHashMap::default();
-        let pos_ctrl_kmers = pos_ctrl.gmms().keys().collect::<FnvHashSet<&String>>();
-        let neg_ctrl_kmers = neg_ctrl.gmms().keys().collect::<FnvHashSet<&String>>();
-        let kmers = pos_ctrl_kmers.intersection(&neg_ctrl_kmers);
-        for &kmer in kmers {
-            let pos_ctrl_model = &pos_ctrl.gmms()[kmer].mixture();
-            let neg_ctrl_model = &neg_ctrl.gmms()[kmer].single();
+        self.record_seed();
+        let mut kmer_ranks = Ranks::default();
+        for (kmer, pos_params) in pos_ctrl.gmms().iter() {
+            let Some(neg_params) = neg_ctrl.gmms().get(&kmer) else {
+                continue;
+            };
+            let pos_ctrl_model = &pos_params.mixture();
+            let neg_ctrl_model = &neg_params.single();
             let kl = self.kl_approx(pos_ctrl_model, neg_ctrl_model);
-            kmer_ranks.insert(kmer.clone(), kl);
+            kmer_ranks.insert(kmer, kl);
         }
         kmer_ranks
     }
 }
+
+/// Positive and negative control distributions of a kmer that are compared by
+/// [RankOptions::rank], or [RankOptions::rank_npsmlr] if npsmlr is set
+fn compared_models(
+    pos_params: &ModelParams,
+    neg_params: &ModelParams,
+    npsmlr: bool,
+) -> (Mixture<Gaussian>, Mixture<Gaussian>) {
+    let single = |g: Gaussian| Mixture::new_unchecked(vec![1.0], vec![g]);
+    if npsmlr {
+        (pos_params.mixture(), single(neg_params.single()))
+    } else {
+        let neg_mix = neg_params.mixture();
+        let pos_mix = pos_params.mixture();
+        let neg_ctrl_model = choose_model(&neg_mix);
+        let pos_ctrl_model = choose_pos_model(neg_ctrl_model, &pos_mix);
+        (
+            single(pos_ctrl_model.clone()),
+            single(neg_ctrl_model.clone()),
+        )
+    }
+}
+
+/// Density of the positive and negative control models of each kmer over a
+/// grid of current levels, along with the rank of the kmer, for plotting how
+/// well the models are separated.
+pub struct RankExportOptions {
+    rank_opts: RankOptions,
+    npsmlr: bool,
+    n_points: usize,
+    range: Option<(f64, f64)>,
+}
+
+impl Default for RankExportOptions {
+    fn default() -> Self {
+        RankExportOptions {
+            rank_opts: RankOptions::default(),
+            npsmlr: false,
+            n_points: 200,
+            range: None,
+        }
+    }
+}
+
+impl RankExportOptions {
+    /// Options for ranking kmers, see [RankOptions::new]
+    pub fn rank_options(&mut self, rank_opts: RankOptions) -> &mut Self {
+        self.rank_opts = rank_opts;
+        self
+    }
+
+    /// Rank using a single gaussian for the negative control, for models from
+    /// cawlr npsmlr train
+    pub fn npsmlr(&mut self, npsmlr: bool) -> &mut Self {
+        self.npsmlr = npsmlr;
+        self
+    }
+
+    /// Number of evenly spaced current levels the densities are evaluated at
+    pub fn n_points(&mut self, n_points: usize) -> &mut Self {
+        self.n_points = n_points;
+        self
+    }
+
+    /// Lowest and highest current level of the grid, by default 4 standard
+    /// deviations past the means of every model
+    pub fn range(&mut self, min: f64, max: f64) -> &mut Self {
+        self.range = Some((min, max));
+        self
+    }
+
+    /// Write a tab-separated table with a header and a line for each kmer and
+    /// current level, with kmers from highest to lowest rank
+    pub fn run<W: Write>(
+        &mut self,
+        pos_ctrl: &Model,
+        neg_ctrl: &Model,
+        mut writer: W,
+    ) -> Result<()> {
+        if self.n_points < 2 {
+            return Err(eyre::eyre!(
+                "Need at least 2 points in the grid, got {}",
+                self.n_points
+            ));
+        }
+        let ranks = if self.npsmlr {
+            self.rank_opts.rank_npsmlr(pos_ctrl, neg_ctrl)
+        } else {
+            self.rank_opts.rank(pos_ctrl, neg_ctrl)
+        };
+        let mut models = ranks
+            .iter()
+            .filter_map(|(kmer, &rank)| {
+                let pos_params = pos_ctrl.gmms().get(&kmer)?;
+                let neg_params = neg_ctrl.gmms().get(&kmer)?;
+                let (pos_model, neg_model) = compared_models(pos_params, neg_params, self.npsmlr);
+                Some((kmer, rank, pos_model, neg_model))
+            })
+            .collect::<Vec<_>>();
+        models.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+
+        writeln!(writer, "kmer\tlevel\tpos_density\tneg_density\trank")?;
+        let components = models
+            .iter()
+            .flat_map(|(_, _, pos, neg)| pos.components().iter().chain(neg.components()));
+        let Some((min, max)) = self.range.or_else(|| grid_range(components)) else {
+            return Ok(());
+        };
+        if min >= max {
+            return Err(eyre::eyre!(
+                "Lowest level {min} must be less than the highest level {max}"
+            ));
+        }
+        let step = (max - min) / (self.n_points - 1) as f64;
+        for (kmer, rank, pos_model, neg_model) in models.iter() {
+            for i in 0..self.n_points {
+                let level = min + step * i as f64;
+                writeln!(
+                    writer,
+                    "{kmer}\t{level:.3}\t{}\t{}\t{rank:.3}",
+                    pos_model.f(&level),
+                    neg_model.f(&level)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Range covering 4 standard deviations past the mean of each Gaussian, None
+/// if there are none
+fn grid_range<'a, I>(components: I) -> Option<(f64, f64)>
+where
+    I: Iterator<Item = &'a Gaussian>,
+{
+    components
+        .map(|g| (g.mu() - 4. * g.sigma(), g.mu() + 4. * g.sigma()))
+        .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::train::ModelDB;
+
+    fn model(mu_a: f64, mu_b: f64) -> Model {
+        let mut gmms = ModelDB::default();
+        for kmer in ["AAAAAA", "CCCCCC"] {
+            let params = ModelParams::new(false, 0.5, mu_a, 1.0, mu_b, 1.0);
+            gmms.insert(kmer.parse().unwrap(), params);
+        }
+        // Third kmer is easier to separate
+        let params = ModelParams::new(false, 0.5, mu_a, 1.0, mu_b + 5.0, 1.0);
+        gmms.insert("GGGGGG".parse().unwrap(), params);
+        Model::new(gmms)
+    }
+
+    #[test]
+    fn test_rank_export() -> Result<()> {
+        let pos_ctrl = model(80.0, 95.0);
+        let neg_ctrl = model(80.0, 82.0);
+        let mut output = Vec::new();
+        RankExportOptions::default()
+            .n_points(5)
+            .range(70.0, 110.0)
+            .run(&pos_ctrl, &neg_ctrl, &mut output)?;
+        let output = String::from_utf8(output)?;
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "kmer\tlevel\tpos_density\tneg_density\trank");
+        assert_eq!(lines.len(), 1 + 3 * 5);
+        assert!(lines[1].starts_with("GGGGGG\t70.000\t"));
+        assert!(lines[5].starts_with("GGGGGG\t110.000\t"));
+
+        let fields = lines[2].split('\t').collect::<Vec<_>>();
+        let pos_density = fields[2].parse::<f64>()?;
+        let neg_density = fields[3].parse::<f64>()?;
+        assert!(pos_density >= 0.0 && neg_density >= 0.0);
+
+        let mut opts = RankExportOptions::default();
+        opts.n_points(1);
+        assert!(opts.run(&pos_ctrl, &neg_ctrl, Vec::new()).is_err());
+        Ok(())
+    }
+}
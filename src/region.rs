@@ -1,9 +1,22 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
 
+use eyre::WrapErr;
+use fnv::FnvHashMap;
 use thiserror::Error;
 
-use crate::arrow::metadata::MetadataExt;
+use crate::{arrow::metadata::MetadataExt, utils::open_maybe_gz};
 
+/// Genomic region, parsed from strings like "chrI:1000-2000". Positions can
+/// have thousands separators, ie "chrI:10,000-20,000", the end can be left
+/// out to go to the end of the chromosome, ie "chrI:10000-", and "chrI" is the
+/// whole chromosome. Regions without an end can be bounded with
+/// [Region::resolve] once chromosome lengths are known.
 #[derive(Clone, Debug)]
 pub struct Region {
     chrom: String,
@@ -24,20 +37,56 @@ impl Region {
         self.start
     }
 
+    /// End of the region, [u64::MAX] if it goes to the end of the chromosome
     pub fn end(&self) -> u64 {
         self.end
     }
 
+    /// Whether the region goes to the end of the chromosome, ie "chrI" or
+    /// "chrI:1000-"
+    pub fn is_open_ended(&self) -> bool {
+        self.end == u64::MAX
+    }
+
+    /// Check the region against chromosome lengths, bounding regions that go
+    /// to the end of the chromosome by its length
+    pub fn resolve(&self, chrom_lens: &FnvHashMap<String, u64>) -> Result<Region, FilterError> {
+        let len = *chrom_lens
+            .get(&self.chrom)
+            .ok_or_else(|| FilterError::UnknownChrom(self.chrom.clone()))?;
+        let end = if self.is_open_ended() { len } else { self.end };
+        if self.start >= len || end > len {
+            return Err(FilterError::OutOfBounds {
+                region: self.to_string(),
+                len,
+            });
+        }
+        Ok(Region::new(self.chrom.clone(), self.start, end))
+    }
+
+    /// Like [Region::resolve], with lengths from the .fai index of the genome.
+    /// Regions are left as is if the genome has no index.
+    pub fn resolve_with_genome<P: AsRef<Path>>(&self, genome: P) -> eyre::Result<Region> {
+        match fai_lengths(genome)? {
+            Some(chrom_lens) => Ok(self.resolve(&chrom_lens)?),
+            None => Ok(self.clone()),
+        }
+    }
+
     pub fn from_bed_line(bed_line: &str) -> Result<Self, FilterError> {
         if bed_line.is_empty() {
             return Err(FilterError::EmptyRegionError);
         }
-        let spliter: Vec<_> = bed_line.split('\t').collect();
-        let chrom = spliter[0].to_string();
-        let start = spliter[1]
-            .parse()
-            .map_err(|_| FilterError::StartParseError)?;
-        let end = spliter[2].parse().map_err(|_| FilterError::EndParseError)?;
+        let mut fields = bed_line.split('\t');
+        let chrom = fields.next().unwrap_or_default().to_string();
+        let start = fields
+            .next()
+            .and_then(|start| start.parse().ok())
+            .ok_or(FilterError::StartParseError)?;
+        let end = fields
+            .next()
+            .and_then(|end| end.trim_end().parse().ok())
+            .ok_or(FilterError::EndParseError)?;
         Ok(Region::new(chrom, start, end))
     }
 
@@ -47,6 +96,53 @@ impl Region {
     }
 }
 
+/// Extensions of BED files, a missing file with one of these is an error
+/// instead of being read as a chromosome name. Other extensions are allowed
+/// in region strings since contigs like NC_001133.9 have dots.
+const BED_EXTENSIONS: [&str; 6] = ["bed", "gz", "bgz", "txt", "tsv", "csv"];
+
+/// Whether the argument is meant as a path, ie it has a directory or ends
+/// with a BED file extension
+fn looks_like_path(region_or_bed: &str) -> bool {
+    let path = Path::new(region_or_bed);
+    region_or_bed.contains(std::path::MAIN_SEPARATOR)
+        || region_or_bed.contains('/')
+        || path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map_or(false, |ext| {
+                BED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+            })
+}
+
+/// Regions from a BED file, which can be gzip compressed, or a single region
+/// string like "chrI:1000-2000" if there is no file at that path. Arguments
+/// that look like a path but don't exist are an error, see [looks_like_path].
+pub fn load_regions(region_or_bed: &str) -> eyre::Result<Vec<Region>> {
+    let path = Path::new(region_or_bed);
+    if !path.is_file() {
+        if looks_like_path(region_or_bed) {
+            eyre::bail!("Region file {region_or_bed} not found");
+        }
+        return Ok(vec![region_or_bed.parse()?]);
+    }
+    let mut regions = Vec::new();
+    for (idx, line) in open_maybe_gz(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let region = Region::from_bed_line(&line)
+            .wrap_err_with(|| format!("Invalid line {} in {region_or_bed}", idx + 1))?;
+        regions.push(region);
+    }
+    Ok(regions)
+}
+
 fn overlaps(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> bool {
     ((b_start <= a_start) && (a_start <= b_end)) || // End overlaps
         ((b_start <= a_end) && (a_end <= b_end)) || // Other end overlaps
@@ -54,12 +150,47 @@ fn overlaps(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> bool {
         ((a_start <= b_start) && (b_end <= a_end))
 }
 
+/// Lengths of each chromosome from the .fai index next to a genome, None if
+/// there is no index
+pub fn fai_lengths<P: AsRef<Path>>(genome: P) -> eyre::Result<Option<FnvHashMap<String, u64>>> {
+    let mut fai = genome.as_ref().as_os_str().to_owned();
+    fai.push(".fai");
+    let fai = Path::new(&fai);
+    if !fai.exists() {
+        return Ok(None);
+    }
+    let mut chrom_lens = FnvHashMap::default();
+    for line in BufReader::new(File::open(fai)?).lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let (Some(chrom), Some(len)) = (fields.next(), fields.next()) else {
+            return Err(eyre::eyre!("Invalid line in {}: {line:?}", fai.display()));
+        };
+        let len = len
+            .parse()
+            .wrap_err_with(|| format!("Invalid length in {}: {line:?}", fai.display()))?;
+        chrom_lens.insert(chrom.to_string(), len);
+    }
+    Ok(Some(chrom_lens))
+}
+
 impl Display for Region {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}-{}", self.chrom, self.start, self.end)
+        match (self.start, self.is_open_ended()) {
+            (0, true) => write!(f, "{}", self.chrom),
+            (start, true) => write!(f, "{}:{start}-", self.chrom),
+            (start, false) => write!(f, "{}:{start}-{}", self.chrom, self.end),
+        }
     }
 }
 
+/// Parse a position, allowing thousands separators
+fn parse_position(s: &str) -> Result<u64, FilterError> {
+    s.replace(',', "")
+        .parse()
+        .map_err(|_| FilterError::ParseError)
+}
+
 impl FromStr for Region {
     type Err = FilterError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -67,15 +198,23 @@ impl FromStr for Region {
             return Err(FilterError::EmptyRegionError);
         }
 
-        let spliterr: Vec<_> = s.split(&[':', '-']).collect();
-        if spliterr.len() != 3 {
-            return Err(FilterError::ParseError);
+        let Some((chrom, range)) = s.rsplit_once(':') else {
+            return Ok(Region::new(s.to_string(), 0, u64::MAX));
+        };
+        if chrom.is_empty() {
+            return Err(FilterError::ChromParseError);
         }
-
-        let chrom = spliterr[0].to_string();
-        let start: u64 = spliterr[1].parse().map_err(|_| FilterError::ParseError)?;
-        let end: u64 = spliterr[2].parse().map_err(|_| FilterError::ParseError)?;
-        Ok(Region::new(chrom, start, end))
+        let (start, end) = range.split_once('-').ok_or(FilterError::ParseError)?;
+        let start = parse_position(start)?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            parse_position(end)?
+        };
+        if start > end {
+            return Err(FilterError::InvalidRange);
+        }
+        Ok(Region::new(chrom.to_string(), start, end))
     }
 }
 
@@ -89,8 +228,14 @@ pub enum FilterError {
     EndParseError,
     #[error("Empty region")]
     EmptyRegionError,
-    #[error("Failed to parse correctly")]
+    #[error("Failed to parse correctly, expected chrom:start-end, chrom:start-, or chrom")]
     ParseError,
+    #[error("Region start is after its end")]
+    InvalidRange,
+    #[error("Chromosome {0} is not in the genome index")]
+    UnknownChrom(String),
+    #[error("Region {region} is past the end of the chromosome, which has length {len}")]
+    OutOfBounds { region: String, len: u64 },
 }
 
 #[cfg(test)]
@@ -112,4 +257,82 @@ mod test {
         let outside_a = (9, 16);
         assert!(overlaps(a.0, a.1, outside_a.0, outside_a.1));
     }
+
+    #[test]
+    fn test_parse_region() {
+        let region: Region = "chrI:10,000-20,000".parse().unwrap();
+        assert_eq!(
+            (region.chrom(), region.start(), region.end()),
+            ("chrI", 10_000, 20_000)
+        );
+        assert_eq!(region.to_string(), "chrI:10000-20000");
+
+        let region: Region = "chrI:10000-".parse().unwrap();
+        assert!(region.is_open_ended());
+        assert_eq!(region.start(), 10_000);
+        assert_eq!(region.to_string(), "chrI:10000-");
+
+        let region: Region = "chrI".parse().unwrap();
+        assert_eq!((region.start(), region.end()), (0, u64::MAX));
+        assert_eq!(region.to_string(), "chrI");
+
+        assert!("chrI:2000-1000".parse::<Region>().is_err());
+        assert!("chrI:1000".parse::<Region>().is_err());
+        assert!(":1000-2000".parse::<Region>().is_err());
+        assert!("chrI:a-2000".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut chrom_lens = FnvHashMap::default();
+        chrom_lens.insert("chrI".to_string(), 5000);
+
+        let region: Region = "chrI:1000-".parse().unwrap();
+        let resolved = region.resolve(&chrom_lens).unwrap();
+        assert_eq!((resolved.start(), resolved.end()), (1000, 5000));
+
+        let region: Region = "chrI:1000-2000".parse().unwrap();
+        assert_eq!(region.resolve(&chrom_lens).unwrap().end(), 2000);
+
+        assert!(matches!(
+            "chrI:1000-6000"
+                .parse::<Region>()
+                .unwrap()
+                .resolve(&chrom_lens),
+            Err(FilterError::OutOfBounds { len: 5000, .. })
+        ));
+        assert!(matches!(
+            "chrII".parse::<Region>().unwrap().resolve(&chrom_lens),
+            Err(FilterError::UnknownChrom(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_regions() -> eyre::Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let bed = temp_dir.path().join("exclude.bed");
+        std::fs::write(
+            &bed,
+            "track name=exclude\nchrXII\t451000\t469000\tRDN1\nchrM\t0\t85779\n",
+        )?;
+        let regions = load_regions(bed.to_str().unwrap())?
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(regions, ["chrXII:451000-469000", "chrM:0-85779"]);
+
+        let regions = load_regions("chrI:10,000-20,000")?;
+        assert_eq!(regions[0].to_string(), "chrI:10000-20000");
+        let regions = load_regions("NC_001133.9:100-200")?;
+        assert_eq!(regions[0].chrom(), "NC_001133.9");
+
+        // Typos in paths aren't read as chromosome names
+        let missing = temp_dir.path().join("exlcude.bed");
+        assert!(load_regions(missing.to_str().unwrap()).is_err());
+        assert!(load_regions("exclude.bed.gz").is_err());
+
+        std::fs::write(&bed, "chrI\t100\n")?;
+        assert!(load_regions(bed.to_str().unwrap()).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,136 @@
+//! Seeds used by the stochastic stages of a run, ie sampling kmers for ranks or
+//! scores for kernel density estimates, saved along with the command line so
+//! the run can be repeated with `cawlr repro`.
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::create_output;
+
+/// Seeds recorded so far by this process, see [record_seed]
+#[allow(clippy::incompatible_msrv)]
+static SEEDS: Mutex<Vec<SeedRecord>> = Mutex::new(Vec::new());
+
+/// Seed for sampling training data from the sqlite database of cawlr npsmlr
+/// train in deterministic mode, see
+/// [TrainOptions::deterministic](crate::npsmlr::train::TrainOptions::deterministic)
+pub const SQLITE_SAMPLE_SEED: u64 = 2456;
+
+/// Seed linfa uses to initialize Gaussian mixture models, which cawlr train
+/// and cawlr npsmlr train don't override
+pub const GMM_SEED: u64 = 42;
+
+/// Effective seed of one stage, along with the parameters that affect what is
+/// sampled with it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeedRecord {
+    pub stage: String,
+    pub seed: u64,
+    pub params: serde_json::Value,
+}
+
+/// Record the seed a stage used, to be saved in the [ReproManifest]. Only the
+/// first record of each stage is kept, so stages that run once per kmer or
+/// per read only log their seed once.
+pub fn record_seed(stage: &str, seed: u64, params: serde_json::Value) {
+    let mut seeds = SEEDS.lock().unwrap_or_else(|e| e.into_inner());
+    if seeds.iter().any(|s| s.stage == stage) {
+        return;
+    }
+    log::info!("{stage} seed: {seed}, parameters: {params}");
+    seeds.push(SeedRecord {
+        stage: stage.to_string(),
+        seed,
+        params,
+    });
+}
+
+/// Seeds recorded since the last call, leaving none recorded
+pub fn take_seeds() -> Vec<SeedRecord> {
+    let mut seeds = SEEDS.lock().unwrap_or_else(|e| e.into_inner());
+    std::mem::take(&mut *seeds)
+}
+
+/// Manifest path for an output file, ie ranks.pickle.repro.json
+pub fn manifest_path<P: AsRef<Path>>(output: P) -> PathBuf {
+    let mut path = output.as_ref().as_os_str().to_owned();
+    path.push(".repro.json");
+    PathBuf::from(path)
+}
+
+/// Everything needed to repeat a run: the cawlr version, the command line and
+/// the directory it was run from, and the seed of each stochastic stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReproManifest {
+    pub cawlr_version: String,
+    pub args: Vec<String>,
+    pub working_dir: PathBuf,
+    pub seeds: Vec<SeedRecord>,
+}
+
+impl ReproManifest {
+    /// Manifest of the current process, with the seeds recorded so far
+    pub fn new(args: Vec<String>, seeds: Vec<SeedRecord>) -> Result<Self> {
+        Ok(ReproManifest {
+            cawlr_version: env!("CARGO_PKG_VERSION").to_string(),
+            args,
+            working_dir: std::env::current_dir()?,
+            seeds,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .wrap_err_with(|| format!("Failed to open manifest {}", path.display()))?;
+        serde_json::from_reader(file)
+            .wrap_err_with(|| format!("Invalid reproducibility manifest {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let writer = create_output(path)?;
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Whether the manifest was written by this version of cawlr, results of
+    /// other versions may differ even with the same seeds
+    pub fn same_version(&self) -> bool {
+        self.cawlr_version == env!("CARGO_PKG_VERSION")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_manifest_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Other tests may record seeds concurrently, only look at this stage
+        let stage = "test manifest roundtrip";
+        record_seed(stage, 2456, serde_json::json!({ "samples": 10 }));
+        record_seed(stage, 1, serde_json::json!({}));
+        let seeds = take_seeds()
+            .into_iter()
+            .filter(|s| s.stage == stage)
+            .collect::<Vec<_>>();
+        assert_eq!(seeds.len(), 1);
+        assert_eq!(seeds[0].seed, 2456);
+        assert!(take_seeds().iter().all(|s| s.stage != stage));
+
+        let args = vec!["cawlr".to_string(), "rank".to_string()];
+        let manifest = ReproManifest::new(args, seeds)?;
+        assert!(manifest.same_version());
+        let path = manifest_path(dir.path().join("ranks.pickle"));
+        assert!(path.ends_with("ranks.pickle.repro.json"));
+        manifest.save(&path)?;
+        assert_eq!(ReproManifest::load(&path)?, manifest);
+        Ok(())
+    }
+}
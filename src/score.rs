@@ -1,41 +1,143 @@
 use std::{
-    collections::HashMap, fmt::Debug, fs::File, hash::BuildHasher, ops::RangeInclusive, path::Path,
+    collections::HashMap,
+    fmt::{Debug, Display},
+    fs::File,
+    hash::BuildHasher,
+    io::{BufWriter, Read, Seek, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use arrow2::io::ipc::write::FileWriter;
 use bio::io::fasta::IndexedReader;
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use fnv::FnvHashMap;
+use rayon::prelude::*;
 use rv::{
     prelude::{Gaussian, Mixture},
     traits::{Cdf, KlDivergence, Rv},
 };
 use statrs::statistics::Statistics;
+use thiserror::Error;
 
 use crate::{
     arrow::{
-        arrow_utils::{load_apply, save, wrap_writer},
+        arrow_utils::{
+            load_apply, load_apply_batched, load_blocks_apply_batched, n_chunks, read_mode, save,
+            wrap_writer_with, ArrowCompression, ArrowError, ReadMode,
+        },
         eventalign::Eventalign,
+        kmer::Kmer,
         metadata::MetadataExt,
         scored_read::{Score, ScoredRead},
         signal::Signal,
     },
-    context,
-    motif::{all_bases, Motif},
+    cancel,
+    context::{self, GenomeCache, SeqCache},
+    duplex::{DuplexCombiner, DuplexSuffixes},
+    haplotype::HaplotypeWriters,
+    index,
+    input::{is_remote, open_genome, open_genome_with_fai, open_input, ReadSeek},
+    motif::{all_bases, Motif, KMER_SIZE, RNA_KMER_SIZE},
+    progress::{ProgressSink, Reporter, Stage},
+    rank::Ranks,
+    region::Region,
+    score_summary::{ScoreSummary, Unscored},
     train::{Model, ModelDB},
-    utils::{chrom_lens, CawlrIO},
+    utils::{self, create_output, take_error, CawlrIO, ChromAlias, FaiIndex},
 };
 
+/// Failures scoring reads with [ScoreOptions]. Invalid options are checked
+/// before any output is created.
+#[derive(Error, Debug)]
+pub enum ScoreError {
+    #[error("Missing .fai index file for {}, run samtools faidx on the genome", genome.display())]
+    MissingFaiIndex { genome: PathBuf },
+    #[error(
+        "Motif {motif} is {} bases long, it can't be longer than the kmer size of {kmer_size}",
+        motif.len_motif()
+    )]
+    MotifTooLong { motif: Motif, kmer_size: usize },
+    #[error("Scoring RNA needs reads collapsed with --mode rna or --mode transcriptome")]
+    NotRna,
+    #[error("Skip weight {weight} must be between 0 and 1")]
+    InvalidSkipWeight { weight: f64 },
+    #[error(transparent)]
+    Arrow(ArrowError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(eyre::Report),
+}
+
+impl From<ArrowError> for ScoreError {
+    fn from(e: ArrowError) -> Self {
+        match e {
+            ArrowError::Io(e) => ScoreError::Io(e),
+            ArrowError::Other(e) => e.into(),
+            e => ScoreError::Arrow(e),
+        }
+    }
+}
+
+impl From<eyre::Report> for ScoreError {
+    fn from(e: eyre::Report) -> Self {
+        take_error::<ScoreError>(e)
+            .or_else(|e| take_error::<ArrowError>(e).map(ScoreError::from))
+            .or_else(|e| take_error::<std::io::Error>(e).map(ScoreError::Io))
+            .unwrap_or_else(ScoreError::Other)
+    }
+}
+
+/// Schema metadata key recording how the skip score was used, ie "weight=0.5"
+/// or "disabled", see [ScoreOptions::skip_score]
+pub const SKIP_SCORE_KEY: &str = "cawlr.skip_score";
+
+/// Motifs must fit within the kmers that are scored
+pub(crate) fn check_motifs(motifs: &[Motif], kmer_size: usize) -> Result<(), ScoreError> {
+    match motifs.iter().find(|m| m.len_motif() > kmer_size) {
+        Some(motif) => Err(ScoreError::MotifTooLong {
+            motif: motif.clone(),
+            kmer_size,
+        }),
+        None => Ok(()),
+    }
+}
+
 pub struct ScoreOptions {
     pos_ctrl: Model,
     neg_ctrl: Model,
-    genome: IndexedReader<File>,
-    chrom_lens: FnvHashMap<String, u64>,
-    rank: FnvHashMap<String, f64>,
-    writer: FileWriter<File>,
+    genome: SeqCache<Box<dyn ReadSeek>>,
+    genome_filepath: PathBuf,
+    /// Index of a local genome, remote genomes use the .fai next to them
+    fai_path: Option<PathBuf>,
+    genome_cache: GenomeCache,
+    chrom_alias: Option<ChromAlias>,
+    rank: Ranks,
+    /// Output file, taken by [ScoreOptions::start_writer] once every option
+    /// recorded in the schema metadata is set
+    output_file: Option<File>,
+    output: PathBuf,
+    split_haplotypes: bool,
+    /// Created along with the output writer, see [ScoreOptions::start_writer]
+    haplotypes: Option<HaplotypeWriters>,
+    compression: ArrowCompression,
+    batch_size: Option<usize>,
     cutoff: f64,
     p_value_threshold: f64,
+    skip_score: bool,
+    skip_weight: f64,
     motifs: Vec<Motif>,
+    rna: bool,
+    kmer_size: usize,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
+    debug: Option<PositionTsv>,
+    details: Option<PositionTsv>,
+    summary: ScoreSummary,
+    summary_output: Option<(PathBuf, File)>,
+    duplex: Option<DuplexCombiner>,
+    by_chrom: bool,
 }
 
 impl ScoreOptions {
@@ -45,29 +147,77 @@ impl ScoreOptions {
         genome_filepath: P,
         rank_filepath: P,
         output: P,
-    ) -> Result<Self>
+    ) -> Result<Self, ScoreError>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        Self::try_new_with_fai(
+            pos_ctrl_filepath,
+            neg_ctrl_filepath,
+            genome_filepath,
+            &FaiIndex::default(),
+            rank_filepath,
+            output,
+        )
+    }
+
+    /// Like [ScoreOptions::try_new], with where the .fai index of a local
+    /// genome is and whether to build it, see [utils::ensure_faidx].
+    pub fn try_new_with_fai<P>(
+        pos_ctrl_filepath: P,
+        neg_ctrl_filepath: P,
+        genome_filepath: P,
+        fai: &FaiIndex,
+        rank_filepath: P,
+        output: P,
+    ) -> Result<Self, ScoreError>
     where
         P: AsRef<Path> + Debug,
     {
-        let schema = ScoredRead::schema();
-        let writer = File::create(output)?;
-        let writer = wrap_writer(writer, &schema)?;
-        let kmer_ranks = FnvHashMap::load(rank_filepath)?;
-        let genome = IndexedReader::from_file(&genome_filepath)
-            .map_err(|_| eyre::eyre!("Failed to read genome file"))?;
-        let chrom_lens = chrom_lens(&genome);
+        let genome_filepath = genome_filepath.as_ref();
+        let fai_path = if is_remote(genome_filepath) {
+            None
+        } else {
+            let fai_path = utils::ensure_faidx(genome_filepath, fai)?;
+            let genome = genome_filepath.to_path_buf();
+            Some(fai_path.ok_or(ScoreError::MissingFaiIndex { genome })?)
+        };
+        let output = output.as_ref().to_path_buf();
+        let output_file = Some(create_output(&output)?);
+        let kmer_ranks = Ranks::load(rank_filepath)?;
+        let genome = open_scored_genome(genome_filepath, fai_path.as_deref())?;
+        let genome = SeqCache::new(genome, GenomeCache::default())?;
         let pos_ctrl_db = Model::load(&pos_ctrl_filepath)?;
         let neg_ctrl_db = Model::load(&neg_ctrl_filepath)?;
         Ok(ScoreOptions {
             pos_ctrl: pos_ctrl_db,
             neg_ctrl: neg_ctrl_db,
             genome,
-            chrom_lens,
+            genome_filepath: genome_filepath.to_path_buf(),
+            fai_path,
+            genome_cache: GenomeCache::default(),
+            chrom_alias: None,
             rank: kmer_ranks,
-            writer,
+            output_file,
+            output,
+            split_haplotypes: false,
+            haplotypes: None,
+            compression: ArrowCompression::default(),
+            batch_size: None,
             cutoff: 10.0,
             p_value_threshold: 0.05,
+            skip_score: false,
+            skip_weight: 0.5,
             motifs: all_bases(),
+            rna: false,
+            kmer_size: KMER_SIZE,
+            progress_sink: None,
+            debug: None,
+            details: None,
+            summary: ScoreSummary::default(),
+            summary_output: None,
+            duplex: None,
+            by_chrom: false,
         })
     }
 
@@ -81,159 +231,790 @@ impl ScoreOptions {
         self
     }
 
-    pub fn motifs<V: Into<Vec<Motif>>>(&mut self, motifs: V) -> &mut Self {
-        self.motifs = motifs.into();
+    /// Also score positions by whether the kmer had signal data, using how
+    /// often it had data in each control from cawlr train. Off by default,
+    /// so positions without signal data score 0, since skip frequencies are
+    /// unreliable with some basecallers.
+    pub fn skip_score(&mut self, skip_score: bool) -> &mut Self {
+        self.skip_score = skip_score;
         self
     }
 
-    fn close(mut self) -> Result<()> {
-        self.writer.finish()?;
+    /// Weight of the skip score when averaged with the signal score, from 0
+    /// for the signal score alone to 1 for the skip score alone, only used
+    /// with [ScoreOptions::skip_score]. Positions without signal data use the
+    /// skip score either way. Defaults to 0.5.
+    pub fn skip_weight(&mut self, weight: f64) -> Result<&mut Self, ScoreError> {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(ScoreError::InvalidSkipWeight { weight });
+        }
+        self.skip_weight = weight;
+        Ok(self)
+    }
+
+    /// Only score kmers containing these motifs, which must fit within a kmer
+    pub fn motifs<V: Into<Vec<Motif>>>(&mut self, motifs: V) -> Result<&mut Self, ScoreError> {
+        let motifs = motifs.into();
+        check_motifs(&motifs, self.kmer_size)?;
+        self.motifs = motifs;
+        Ok(self)
+    }
+
+    /// Score direct RNA reads, which need to be collapsed with the rna or
+    /// transcriptome mode. Kmers are the 5-mers of RNA pore models, and motifs
+    /// match the RNA, ie "3:DRACH" for m6A, so they must fit within a 5-mer.
+    pub fn rna(&mut self, rna: bool) -> Result<&mut Self, ScoreError> {
+        let kmer_size = if rna { RNA_KMER_SIZE } else { KMER_SIZE };
+        check_motifs(&self.motifs, kmer_size)?;
+        self.rna = rna;
+        self.kmer_size = kmer_size;
+        Ok(self)
+    }
+
+    /// How much of the genome to keep in memory, see [GenomeCache]. Preloading
+    /// reads the whole genome before returning.
+    pub fn genome_cache(&mut self, cache: GenomeCache) -> Result<&mut Self, ScoreError> {
+        self.genome.set_cache(cache)?;
+        self.genome_cache = cache;
+        Ok(self)
+    }
+
+    /// Match chromosome names of reads to the genome through an alias file,
+    /// ie when reads are on "1" but the genome uses "chr1"
+    pub fn chrom_alias(&mut self, alias: ChromAlias) -> &mut Self {
+        self.genome.set_chrom_alias(alias.clone());
+        self.chrom_alias = Some(alias);
+        self
+    }
+
+    /// Score each chromosome in a separate thread, each with its own handle to
+    /// the genome, then concatenate the results. Uses the index from
+    /// [crate::index::index] to find the reads on each chromosome if there is
+    /// one. Output reads are grouped by chromosome.
+    pub fn by_chrom(&mut self, by_chrom: bool) -> &mut Self {
+        self.by_chrom = by_chrom;
+        self
+    }
+
+    /// Codec of the output and the outputs of each haplotype, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Load at most this many reads at a time, see [load_apply_batched]
+    pub fn batch_size(&mut self, batch_size: Option<usize>) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is scored
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    /// Write how each position in the region was scored to a TSV, with a row
+    /// for every surrounding kmer with signal data. Rows have the kmer's
+    /// p-value and rank, its log-likelihood under each control model, and
+    /// whether it was chosen for the score. Meant for small regions, since
+    /// every read covering a position adds rows.
+    pub fn debug_tsv<P: AsRef<Path>>(
+        &mut self,
+        region: Region,
+        path: P,
+    ) -> Result<&mut Self, ScoreError> {
+        self.debug = Some(PositionTsv::new(
+            PositionRows::Candidates(region),
+            create_output(path)?,
+            true,
+        )?);
+        Ok(self)
+    }
+
+    /// Write a row for every scored position to a TSV, with the kmer chosen for
+    /// the score, its rank and p-value, its log-likelihood under each control
+    /// model, and the inputs to the skip score. Unlike
+    /// [ScoreOptions::debug_tsv] it covers the whole input with one row per
+    /// position, so it also works with [ScoreOptions::by_chrom].
+    pub fn emit_details<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, ScoreError> {
+        self.details = Some(PositionTsv::new(
+            PositionRows::Chosen,
+            create_output(path)?,
+            true,
+        )?);
+        Ok(self)
+    }
+
+    /// Write counts of scored and unscored positions per chromosome and per
+    /// motif at the end of scoring, as a TSV if the path ends in .tsv and
+    /// otherwise as JSON, see [ScoreSummary]
+    pub fn summary<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, ScoreError> {
+        let path = path.as_ref();
+        self.summary_output = Some((path.to_path_buf(), create_output(path)?));
+        Ok(self)
+    }
+
+    /// Combine the halves of duplex reads, matched by these read name
+    /// suffixes, into one read on the strand of the template. Sites scored in
+    /// both halves get a single score from both measurements, see
+    /// [crate::duplex]. Halves without their other half are written unchanged
+    /// after the combined reads, at the end of each chromosome with
+    /// [ScoreOptions::by_chrom].
+    pub fn duplex(&mut self, suffixes: DuplexSuffixes) -> &mut Self {
+        self.duplex = Some(DuplexCombiner::new(suffixes));
+        self
+    }
+
+    /// Also write reads to a separate output for each haplotype, recorded by
+    /// cawlr collapse from the HP tags of a phased bam file. Outputs are
+    /// written next to the main output, ie scores.hp1.arrow and
+    /// scores.hp2.arrow for scores.arrow.
+    pub fn split_haplotypes(&mut self, split_haplotypes: bool) -> &mut Self {
+        self.split_haplotypes = split_haplotypes;
+        self
+    }
+
+    /// Value of [SKIP_SCORE_KEY] in the output schema metadata
+    fn skip_score_setting(&self) -> String {
+        if self.skip_score {
+            format!("weight={}", self.skip_weight)
+        } else {
+            "disabled".to_string()
+        }
+    }
+
+    /// Arrow writer for the output, with the options in the schema metadata.
+    /// Started when scoring starts, so options set after
+    /// [ScoreOptions::try_new] are recorded.
+    fn start_writer(&mut self) -> Result<FileWriter<File>> {
+        let file = self
+            .output_file
+            .take()
+            .ok_or_else(|| eyre::eyre!("Output {} was already written", self.output.display()))?;
+        let mut schema = ScoredRead::schema();
+        schema
+            .metadata
+            .insert(SKIP_SCORE_KEY.to_string(), self.skip_score_setting());
+        if self.split_haplotypes {
+            self.haplotypes = Some(HaplotypeWriters::new(&self.output, self.compression));
+        }
+        wrap_writer_with(file, &schema, self.compression)
+    }
+
+    fn close(mut self, mut writer: FileWriter<File>) -> Result<()> {
+        if let Some(duplex) = self.duplex.take() {
+            let unpaired = duplex.finish();
+            self.save(&mut writer, unpaired)?;
+        }
+        writer.finish()?;
+        if let Some(debug) = self.debug.as_mut() {
+            debug.writer.flush()?;
+        }
+        if let Some(details) = self.details.as_mut() {
+            details.writer.flush()?;
+        }
+        if let Some(haplotypes) = self.haplotypes {
+            haplotypes.finish()?;
+        }
+        if let Some((path, file)) = self.summary_output {
+            self.summary.log();
+            self.summary
+                .write_for_path(BufWriter::new(file), &path)
+                .wrap_err_with(|| format!("Failed to write summary to {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Reads and both models need to have the same mode, see [ReadMode]
+    fn check_mode(&self, mode: ReadMode) -> Result<()> {
+        if self.rna && mode == ReadMode::DnaGenome {
+            return Err(ScoreError::NotRna.into());
+        }
+        self.pos_ctrl.check_mode(mode)?;
+        self.neg_ctrl.check_mode(mode)?;
         Ok(())
     }
 
     /// For every read in the input file, try to calculate scores for each base
-    /// position and write to file.
-    pub fn run<P>(mut self, input: P) -> Result<()>
+    /// position and write to file. The input can be remote, see
+    /// [crate::input].
+    pub fn run<P>(mut self, input: P) -> Result<(), ScoreError>
     where
         P: AsRef<Path>,
     {
-        let file = File::open(input)?;
-        load_apply(file, |eventaligns| {
-            let scored = eventaligns
-                .into_iter()
-                .flat_map(|e| self.score_eventalign(e))
-                .collect();
-            self.save(scored)
-        })?;
-        self.close()
+        if self.by_chrom {
+            return Ok(self.run_by_chrom(input)?);
+        }
+        let mut file = open_input(input)?;
+        self.check_mode(read_mode(&mut file)?)?;
+        let mut writer = self.start_writer()?;
+        let mut reporter = Reporter::new(Stage::Score, self.progress_sink.clone());
+        reporter.total_chunks(n_chunks(&mut file)?);
+        let mut contigs_checked = false;
+        let res = load_apply_batched(file, self.batch_size, |eventaligns: Vec<Eventalign>| {
+            cancel::check()?;
+            if !contigs_checked {
+                self.genome
+                    .check_contigs(eventaligns.iter().map(|e| e.chrom()))
+                    .wrap_err("Reads are on chromosomes that are not in the genome")?;
+                contigs_checked = true;
+            }
+            let scored = self.score_chunk(eventaligns);
+            reporter.chunk(scored.len());
+            self.save(&mut writer, scored)
+        });
+        reporter.finish();
+        // Finish the output even on error, so reads scored before a
+        // cancellation can still be read
+        self.close(writer)?;
+        Ok(res?)
+    }
+
+    /// Scores each chromosome's reads with a separate worker, see
+    /// [ScoreOptions::by_chrom]
+    fn run_by_chrom<P>(mut self, input: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        if self.debug.is_some() {
+            return Err(eyre::eyre!(
+                "Writing a debug TSV is not supported when scoring by chromosome"
+            ));
+        }
+        let input = input.as_ref();
+        self.check_mode(read_mode(&mut open_input(input)?)?)?;
+        let chrom_blocks = index::chrom_blocks(input)?;
+        self.genome
+            .check_contigs(chrom_blocks.iter().map(|(chrom, _)| chrom.as_str()))
+            .wrap_err("Reads are on chromosomes that are not in the genome")?;
+
+        // Halves of a duplex read are on the same chromosome, so each worker
+        // combines its own
+        let duplex = self.duplex.take();
+        let tmp = utils::temp_dir()?;
+        log::info!("Scoring {} chromosomes", chrom_blocks.len());
+        // Workers are created as each chromosome is scored, so only one genome
+        // handle and output per thread is open at a time
+        let outputs = chrom_blocks
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(idx, (chrom, blocks))| {
+                let output = tmp.path().join(format!("{idx}.arrow"));
+                let mut worker = self.worker(&output)?;
+                if let Some(duplex) = duplex.as_ref() {
+                    worker.duplex(duplex.suffixes().clone());
+                }
+                if self.details.is_some() {
+                    let details = File::create(output.with_extension("details.tsv"))?;
+                    worker.details = Some(PositionTsv::new(PositionRows::Chosen, details, false)?);
+                }
+                let file = open_input(input)?;
+                let mut writer = worker.start_writer()?;
+                let batch_size = worker.batch_size;
+                let res = load_blocks_apply_batched(
+                    file,
+                    &blocks,
+                    batch_size,
+                    |eventaligns: Vec<Eventalign>| {
+                        cancel::check()?;
+                        let eventaligns = eventaligns
+                            .into_iter()
+                            .filter(|e| e.chrom() == chrom)
+                            .collect();
+                        let scored = worker.score_chunk(eventaligns);
+                        worker.save(&mut writer, scored)
+                    },
+                );
+                let summary = std::mem::take(&mut worker.summary);
+                worker.close(writer)?;
+                res.wrap_err_with(|| format!("Failed to score reads on {chrom}"))?;
+                Ok((output, summary))
+            })
+            .collect::<Result<Vec<_>>>();
+
+        let mut writer = self.start_writer()?;
+        let mut reporter = Reporter::new(Stage::Score, self.progress_sink.clone());
+        let res = outputs.and_then(|outputs| {
+            reporter.total_chunks(outputs.len());
+            for (output, summary) in outputs {
+                self.summary.merge(&summary);
+                load_apply(File::open(&output)?, |scored: Vec<ScoredRead>| {
+                    reporter.chunk(scored.len());
+                    self.save(&mut writer, scored)
+                })?;
+                if let Some(details) = self.details.as_mut() {
+                    let mut worker_details = File::open(output.with_extension("details.tsv"))?;
+                    std::io::copy(&mut worker_details, &mut details.writer)?;
+                }
+            }
+            Ok(())
+        });
+        reporter.finish();
+        self.close(writer)?;
+        res
+    }
+
+    /// Copy of the options that scores into its own output, with a separate
+    /// handle to the genome
+    fn worker(&self, output: &Path) -> Result<Self> {
+        let genome = open_scored_genome(&self.genome_filepath, self.fai_path.as_deref())?;
+        let mut genome = SeqCache::new(genome, self.genome_cache)?;
+        if let Some(alias) = self.chrom_alias.clone() {
+            genome.set_chrom_alias(alias);
+        }
+        Ok(ScoreOptions {
+            pos_ctrl: self.pos_ctrl.clone(),
+            neg_ctrl: self.neg_ctrl.clone(),
+            genome,
+            genome_filepath: self.genome_filepath.clone(),
+            fai_path: self.fai_path.clone(),
+            genome_cache: self.genome_cache,
+            chrom_alias: self.chrom_alias.clone(),
+            rank: self.rank.clone(),
+            output_file: Some(File::create(output)?),
+            output: output.to_path_buf(),
+            split_haplotypes: false,
+            haplotypes: None,
+            compression: self.compression,
+            batch_size: self.batch_size,
+            cutoff: self.cutoff,
+            p_value_threshold: self.p_value_threshold,
+            skip_score: self.skip_score,
+            skip_weight: self.skip_weight,
+            motifs: self.motifs.clone(),
+            rna: self.rna,
+            kmer_size: self.kmer_size,
+            progress_sink: None,
+            debug: None,
+            details: None,
+            summary: ScoreSummary::default(),
+            summary_output: None,
+            duplex: None,
+            by_chrom: false,
+        })
+    }
+
+    /// Score a chunk of reads, skipping reads that fail to be scored
+    fn score_chunk(&mut self, eventaligns: Vec<Eventalign>) -> Vec<ScoredRead> {
+        // Reads within a chunk often overlap, the cache is dropped after
+        // each chunk to keep memory usage bounded
+        let mut kmer_cache = KmerCache::default();
+        let mut scored = Vec::with_capacity(eventaligns.len());
+        for eventalign in eventaligns {
+            match self.score_eventalign(eventalign, &mut kmer_cache) {
+                Ok(read) => {
+                    self.summary.reads += 1;
+                    scored.push(read);
+                }
+                Err(e) => {
+                    log::debug!("Failed to score read: {e:?}");
+                    self.summary.failed_reads += 1;
+                }
+            }
+        }
+        match self.duplex.as_mut() {
+            Some(duplex) => duplex.pair(scored, &self.motifs),
+            None => scored,
+        }
     }
 
     /// Write batch of scored reads to the writer.
-    pub(crate) fn save(&mut self, scored: Vec<ScoredRead>) -> Result<()> {
-        save(&mut self.writer, &scored)
+    pub(crate) fn save(
+        &mut self,
+        writer: &mut FileWriter<File>,
+        scored: Vec<ScoredRead>,
+    ) -> Result<()> {
+        if let Some(haplotypes) = self.haplotypes.as_mut() {
+            haplotypes.save(&scored)?;
+        }
+        save(writer, &scored)
     }
 
     /// Scores a single Eventalign read. For each read, loop over each base pair
     /// position, and if the kmer at the position matches the motif attempt to
     /// score it.
-    fn score_eventalign(&mut self, read: Eventalign) -> Result<ScoredRead> {
+    fn score_eventalign(
+        &mut self,
+        read: Eventalign,
+        kmer_cache: &mut KmerCache,
+    ) -> Result<ScoredRead> {
         let mut acc = Vec::new();
-        let context = context::Context::from_read(&mut self.genome, &self.chrom_lens, &read)?;
 
         log::debug!("{:?}", read.metadata());
-        log::debug!("{context:.3?}");
 
         let data_pos = pos_with_data(&read);
-        for pos in read.start_1b()..read.end_1b_excl() {
-            // Get kmer and check if kmer matches the motifs, if there are any supplied
-            let pos_kmer: Option<(&[u8], &Motif)> = context.sixmer_at(pos).and_then(|k| {
-                self.motifs
-                    .iter()
-                    .find(|m| {
-                        let m = m.motif().as_bytes();
-                        k.starts_with(m)
-                    })
-                    .map(|m| (k, m))
-            });
+        let kmers = read_motif_kmers(
+            &mut self.genome,
+            &read,
+            &self.motifs,
+            self.kmer_size,
+            kmer_cache,
+        )?;
+        for (pos, kmer) in kmers {
+            log::debug!("Position {pos} kmer: {kmer}");
 
-            if let Some((kmer, motif)) = pos_kmer {
-                let kmer = std::str::from_utf8(kmer).unwrap().to_string();
-                log::debug!("Position {pos} kmer: {kmer}");
-
-                let signal_score = self.calc_signal_score(pos, &data_pos);
-                // let skipping_score = self.calc_skipping_score(pos, &data_pos, &context, motif)?;
-                // let final_score = signal_score.map_or(skipping_score, |x| x.max(skipping_score));
-                let final_score = signal_score.unwrap_or(0.0);
-                let score = Score::new(
-                    pos,
-                    kmer,
-                    signal_score.is_none(),
-                    signal_score,
-                    // skipping_score,
-                    final_score,
-                );
-                log::debug!("final score: {score:.3?}");
-                acc.push(score)
-            }
+            let signal = self.calc_signal_score(pos, &data_pos);
+            let signal_score = signal.ok();
+            let skip_score = self.calc_skip_score(pos, &kmer, &data_pos);
+            let final_score = combine_scores(signal_score, skip_score, self.skip_weight);
+            self.summary
+                .add(read.chrom(), &kmer, &self.motifs, signal, final_score);
+            let mut tsvs = [self.debug.take(), self.details.take()];
+            let res = tsvs.iter_mut().flatten().try_for_each(|tsv| {
+                tsv.write_position(self, &read, pos, &kmer, &data_pos, signal_score)
+            });
+            [self.debug, self.details] = tsvs;
+            res?;
+            let score = Score::new(
+                pos,
+                kmer,
+                signal_score.is_none(),
+                signal_score,
+                // skipping_score,
+                final_score,
+            );
+            log::debug!("final score: {score:.3?}");
+            acc.push(score)
         }
         let scored_read = ScoredRead::from_read_with_scores(read, acc);
         Ok(scored_read)
     }
 
-    // fn calc_skipping_score(
-    //     &self,
-    //     pos: u64,
-    //     data_pos: &FnvHashMap<u64, &Signal>,
-    //     context: &context::Context,
-    //     motif: &Motif,
-    // ) -> Result<f64> {
-    //     let sur_kmers = context.surrounding(pos, motif);
-    //     let sur_has_data = surround_has_data(pos, data_pos);
-    //     let skipping_scores = sur_kmers
-    //         .into_iter()
-    //         .zip(sur_has_data.into_iter())
-    //         .flat_map(|(kmer, has_data)| {
-    //             let kmer = std::str::from_utf8(kmer).expect("Invalid kmer");
-    //             // let pos_presence = self.pos_ctrl.skips().get(kmer);
-    //             // let neg_presence = self.neg_ctrl.skips().get(kmer);
-    //             match (pos_presence, neg_presence) {
-    //                 (Some(&pos_presence), Some(&neg_presence)) => {
-    //                     if has_data {
-    //                         Some(pos_presence / (pos_presence + neg_presence))
-    //                     } else {
-    //                         let pos_absent = 1. - pos_presence;
-    //                         let neg_absent = 1. - neg_presence;
-    //                         Some(pos_absent / (pos_absent + neg_absent))
-    //                     }
-    //                 }
-    //                 _ => None,
-    //             }
-    //         })
-    //         .collect::<Vec<_>>();
-
-    //     // TODO: Switch to median when it can be correctly handled
-    //     let skip_score = skipping_scores.mean();
-    //     if skip_score.is_nan() {
-    //         Err(eyre::eyre!("No data for calculating median"))
-    //     } else {
-    //         Ok(skip_score)
-    //     }
-    // }
+    /// Probability the position is modified given whether the kmer at the
+    /// position had signal data, from how often the kmer had data in each
+    /// control. None if the skip score is disabled or either control is
+    /// missing the kmer.
+    fn calc_skip_score(
+        &self,
+        pos: u64,
+        kmer: &Kmer,
+        data_pos: &FnvHashMap<u64, &Signal>,
+    ) -> Option<f64> {
+        if !self.skip_score {
+            return None;
+        }
+        let pos_presence = self.pos_ctrl.skip(kmer)?;
+        let neg_presence = self.neg_ctrl.skip(kmer)?;
+        skip_score(pos_presence, neg_presence, data_pos.contains_key(&pos))
+    }
 
     /// For a given position, get the values for the position and surrounding
     /// kmers. Filter for the best kmer model, if there is confidence in the
-    /// model, otherwise return None.
-    fn calc_signal_score(&self, pos: u64, data_pos: &FnvHashMap<u64, &Signal>) -> Option<f64> {
-        log::debug!("Calculating signal score");
-        let sur_signals = surrounding_signal(pos, data_pos);
-        log::debug!("surrounding signals: {sur_signals:.3?}");
-        let best_signal = best_surrounding_signal(
-            sur_signals,
+    /// model, otherwise return why the position wasn't scored.
+    fn calc_signal_score(
+        &self,
+        pos: u64,
+        data_pos: &FnvHashMap<u64, &Signal>,
+    ) -> Result<f64, Unscored> {
+        try_signal_score(
+            pos,
+            data_pos,
+            &self.pos_ctrl,
+            &self.neg_ctrl,
             &self.rank,
-            self.pos_ctrl.gmms(),
-            self.neg_ctrl.gmms(),
+            self.cutoff,
             self.p_value_threshold,
+        )
+    }
+}
+
+/// Score for the best signal at or around a position, see
+/// [ScoreOptions::cutoff] and [ScoreOptions::p_value_threshold]. Returns None if
+/// there is no signal with a confident model.
+pub(crate) fn calc_signal_score(
+    pos: u64,
+    data_pos: &FnvHashMap<u64, &Signal>,
+    pos_ctrl: &Model,
+    neg_ctrl: &Model,
+    ranks: &Ranks,
+    cutoff: f64,
+    p_value_threshold: f64,
+) -> Option<f64> {
+    try_signal_score(
+        pos,
+        data_pos,
+        pos_ctrl,
+        neg_ctrl,
+        ranks,
+        cutoff,
+        p_value_threshold,
+    )
+    .ok()
+}
+
+/// Like [calc_signal_score], with why the position wasn't scored
+fn try_signal_score(
+    pos: u64,
+    data_pos: &FnvHashMap<u64, &Signal>,
+    pos_ctrl: &Model,
+    neg_ctrl: &Model,
+    ranks: &Ranks,
+    cutoff: f64,
+    p_value_threshold: f64,
+) -> Result<f64, Unscored> {
+    log::debug!("Calculating signal score");
+    let sur_signals = surrounding_signal(pos, data_pos).ok_or(Unscored::NoData)?;
+    log::debug!("surrounding signals: {sur_signals:.3?}");
+    let in_models = |s: &&Signal| {
+        pos_ctrl.gmms().contains_key(&s.kmer) && neg_ctrl.gmms().contains_key(&s.kmer)
+    };
+    if !sur_signals.iter().any(in_models) {
+        log::debug!("Missing kmer, unable to score signal.");
+        return Err(Unscored::MissingModel);
+    }
+    let best_signal = best_surrounding_signal(
+        Some(sur_signals),
+        ranks,
+        pos_ctrl.gmms(),
+        neg_ctrl.gmms(),
+        p_value_threshold,
+    );
+
+    log::debug!("Best signal: {best_signal:.3?}");
+
+    let sig = best_signal.ok_or(Unscored::PValue)?;
+    let mean = sig.signal_mean;
+    let kmer = &sig.kmer;
+    let pos_mix = pos_ctrl.gmms().get(kmer);
+    let neg_mix = neg_ctrl.gmms().get(kmer);
+    match (pos_mix, neg_mix) {
+        (Some(pos_gmm), Some(neg_gmm)) => {
+            let neg_mix = neg_gmm.mixture();
+            let pos_mix = pos_gmm.mixture();
+            score_signal(mean, &pos_mix, &neg_mix, cutoff).ok_or(Unscored::Cutoff)
+        }
+        _ => {
+            log::debug!("Missing kmer, unable to score signal.");
+            Err(Unscored::MissingModel)
+        }
+    }
+}
+
+fn or_na<T: Display>(x: Option<T>) -> String {
+    x.map_or_else(|| "NA".to_string(), |x| x.to_string())
+}
+
+/// Which rows a [PositionTsv] writes
+enum PositionRows {
+    /// A row for every candidate kmer around each position in the region, see
+    /// [ScoreOptions::debug_tsv]
+    Candidates(Region),
+    /// A row for every scored position with the chosen kmer, see
+    /// [ScoreOptions::emit_details]
+    Chosen,
+}
+
+impl PositionRows {
+    fn header(&self) -> &'static str {
+        match self {
+            PositionRows::Candidates(_) => {
+                "read_name\tchrom\tpos\tstrand\tkmer\tcandidate_pos\tcandidate_kmer\t\
+                 signal_mean\tp_value\trank\tpasses_p_value\tchosen\tpos_ln_likelihood\t\
+                 neg_ln_likelihood\tscore"
+            }
+            PositionRows::Chosen => {
+                "read_name\tchrom\tpos\tstrand\tkmer\tn_candidates\tn_with_data\t\
+                 pos_skip\tneg_skip\tchosen_pos\tchosen_kmer\trank\tp_value\t\
+                 pos_ln_likelihood\tneg_ln_likelihood\tscore"
+            }
+        }
+    }
+}
+
+/// How a signal compares to the control models of its kmer
+#[derive(Clone, Copy)]
+struct ModelValues {
+    p_value: f64,
+    pos_ln: f64,
+    neg_ln: f64,
+}
+
+impl ModelValues {
+    /// None if either control is missing the kmer
+    fn new(opts: &ScoreOptions, signal: &Signal) -> Option<Self> {
+        let pos_mix = opts.pos_ctrl.gmms().get(&signal.kmer)?.mixture();
+        let neg_mix = opts.neg_ctrl.gmms().get(&signal.kmer)?.mixture();
+        let neg_model = choose_model(&neg_mix);
+        let pos_model = choose_pos_model(neg_model, &pos_mix);
+        Some(ModelValues {
+            p_value: gauss_to_pvalue(pos_model, neg_model),
+            pos_ln: pos_model.ln_f(&signal.signal_mean),
+            neg_ln: neg_model.ln_f(&signal.signal_mean),
+        })
+    }
+}
+
+/// Per-position scoring values written alongside the scores, see
+/// [PositionRows]
+struct PositionTsv {
+    rows: PositionRows,
+    writer: BufWriter<File>,
+}
+
+impl PositionTsv {
+    /// Workers scoring by chromosome leave out the header, since their rows
+    /// are appended to the main output
+    fn new(rows: PositionRows, file: File, header: bool) -> Result<Self> {
+        let mut writer = BufWriter::new(file);
+        if header {
+            writeln!(writer, "{}", rows.header())?;
+        }
+        Ok(PositionTsv { rows, writer })
+    }
+
+    /// Same steps as [ScoreOptions::calc_signal_score], keeping the
+    /// intermediate values. The skip score inputs of [PositionRows::Chosen]
+    /// are how many of the positions around pos had signal data, and how often
+    /// the kmer at pos had signal data in each control.
+    fn write_position(
+        &mut self,
+        opts: &ScoreOptions,
+        read: &Eventalign,
+        pos: u64,
+        kmer: &Kmer,
+        data_pos: &FnvHashMap<u64, &Signal>,
+        score: Option<f64>,
+    ) -> Result<()> {
+        if let PositionRows::Candidates(region) = &self.rows {
+            if read.chrom() != region.chrom() || pos < region.start() || pos >= region.end() {
+                return Ok(());
+            }
+        }
+        let candidates = surrounding_signal(pos, data_pos);
+        let chosen = best_surrounding_signal(
+            candidates.clone(),
+            &opts.rank,
+            opts.pos_ctrl.gmms(),
+            opts.neg_ctrl.gmms(),
+            opts.p_value_threshold,
         );
+        let prefix = format!(
+            "{}\t{}\t{pos}\t{}\t{kmer}",
+            read.name(),
+            read.chrom(),
+            read.strand()
+        );
+        let score = or_na(score);
+        match &self.rows {
+            PositionRows::Candidates(_) => {
+                let Some(candidates) = candidates else {
+                    let na = ["NA"; 9].join("\t");
+                    writeln!(self.writer, "{prefix}\t{na}\t{score}")?;
+                    return Ok(());
+                };
+                for signal in candidates {
+                    let values = ModelValues::new(opts, signal);
+                    let p_value = values.map(|v| v.p_value);
+                    let passes = p_value.map_or(false, |p| p < opts.p_value_threshold);
+                    let is_chosen = chosen.map_or(false, |c| std::ptr::eq(c, signal));
+                    writeln!(
+                        self.writer,
+                        "{prefix}\t{}\t{}\t{}\t{}\t{}\t{passes}\t{is_chosen}\t{}\t{}\t{score}",
+                        signal.pos,
+                        signal.kmer,
+                        signal.signal_mean,
+                        or_na(p_value),
+                        or_na(opts.rank.get(&signal.kmer)),
+                        or_na(values.map(|v| v.pos_ln)),
+                        or_na(values.map(|v| v.neg_ln)),
+                    )?;
+                }
+            }
+            PositionRows::Chosen => {
+                let has_data = surround_has_data(pos, data_pos);
+                let n_with_data = has_data.iter().filter(|&&x| x).count();
+                let values = chosen.and_then(|signal| ModelValues::new(opts, signal));
+                writeln!(
+                    self.writer,
+                    "{prefix}\t{}\t{n_with_data}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{score}",
+                    has_data.len(),
+                    or_na(opts.pos_ctrl.skip(kmer)),
+                    or_na(opts.neg_ctrl.skip(kmer)),
+                    or_na(chosen.map(|signal| signal.pos)),
+                    or_na(chosen.map(|signal| &signal.kmer)),
+                    or_na(chosen.and_then(|signal| opts.rank.get(&signal.kmer))),
+                    or_na(values.map(|v| v.p_value)),
+                    or_na(values.map(|v| v.pos_ln)),
+                    or_na(values.map(|v| v.neg_ln)),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
 
-        log::debug!("Best signal: {best_signal:.3?}");
-
-        best_signal.and_then(|sig| {
-            let mean = sig.signal_mean;
-            let kmer = &sig.kmer;
-            let pos_mix = self.pos_ctrl.gmms().get(kmer);
-            let neg_mix = self.neg_ctrl.gmms().get(kmer);
-            match (pos_mix, neg_mix) {
-                (Some(pos_gmm), Some(neg_gmm)) => {
-                    let neg_mix = neg_gmm.mixture();
-                    let pos_mix = pos_gmm.mixture();
-                    score_signal(mean, &pos_mix, &neg_mix, self.cutoff)
+/// Kmers at each genomic position, shared between reads so overlapping reads
+/// don't need to fetch and convert the same sequence again. Positions where the
+/// kmer doesn't match any motif are stored as None.
+#[derive(Default)]
+pub(crate) struct KmerCache(FnvHashMap<(String, bool), FnvHashMap<u64, Option<Kmer>>>);
+
+impl KmerCache {
+    /// Cached kmers for a chromosome and strand, since the minus strand context
+    /// is complemented
+    fn strand_kmers(
+        &mut self,
+        chrom: &str,
+        minus_strand: bool,
+    ) -> &mut FnvHashMap<u64, Option<Kmer>> {
+        self.0.entry((chrom.to_string(), minus_strand)).or_default()
+    }
+}
+
+/// Positions in the read where the kmer starts with one of the motifs.
+///
+/// Kmers are looked up in the cache first, and the genomic context is only
+/// fetched if the read covers a position that hasn't been seen yet.
+pub(crate) fn read_motif_kmers<R: Read + Seek>(
+    genome: &mut SeqCache<R>,
+    read: &Eventalign,
+    motifs: &[Motif],
+    kmer_size: usize,
+    kmer_cache: &mut KmerCache,
+) -> Result<Vec<(u64, Kmer)>> {
+    let mut acc = Vec::new();
+    let mut context = None;
+    let kmers = kmer_cache.strand_kmers(read.chrom(), read.strand().is_minus_strand());
+    for pos in read.start_1b()..read.end_1b_excl() {
+        let pos_kmer = match kmers.get(&pos) {
+            Some(&kmer) => kmer,
+            None => {
+                if context.is_none() {
+                    let ctxt = context::Context::from_read(genome, read)?;
+                    log::debug!("{ctxt:.3?}");
+                    context = Some(ctxt);
                 }
-                _ => {
-                    log::debug!("Missing kmer, unable to score signal.");
-                    None
+                let kmer = context.as_ref().and_then(|c| c.kmer_at(pos, kmer_size));
+                // Only cache if the kmer is complete, since kmers near the end of the
+                // read are cut off by the end of the context
+                match kmer {
+                    Some(kmer) => {
+                        let kmer = motif_kmer(kmer, motifs)?;
+                        kmers.insert(pos, kmer);
+                        kmer
+                    }
+                    None => None,
                 }
             }
-        })
+        };
+        if let Some(kmer) = pos_kmer {
+            acc.push((pos, kmer));
+        }
+    }
+    Ok(acc)
+}
+
+/// Returns the kmer if any of the motifs start at the first base of the kmer.
+/// Every genomic position is checked, so occurrences at other offsets are
+/// scored at the position the motif starts.
+fn motif_kmer(kmer: &[u8], motifs: &[Motif]) -> Result<Option<Kmer>> {
+    if motifs.iter().any(|m| m.matches_at(kmer, 0)) {
+        Ok(Some(Kmer::from_bytes(kmer)?))
+    } else {
+        Ok(None)
     }
 }
 
@@ -242,6 +1023,42 @@ fn surrounding_pos(pos: u64) -> RangeInclusive<u64> {
     start..=pos
 }
 
+/// Open a genome with its index at fai_path, or the .fai next to it if None
+fn open_scored_genome(
+    genome: &Path,
+    fai_path: Option<&Path>,
+) -> Result<IndexedReader<Box<dyn ReadSeek>>> {
+    match fai_path {
+        Some(fai_path) => open_genome_with_fai(genome, fai_path),
+        None => open_genome(genome),
+    }
+}
+
+/// Probability of modification given whether the kmer had signal data, from
+/// the fraction of times the kmer had data in each control
+fn skip_score(pos_presence: f64, neg_presence: f64, has_data: bool) -> Option<f64> {
+    let (pos, neg) = if has_data {
+        (pos_presence, neg_presence)
+    } else {
+        (1. - pos_presence, 1. - neg_presence)
+    };
+    if pos + neg > 0. {
+        Some(pos / (pos + neg))
+    } else {
+        None
+    }
+}
+
+/// Weighted average of the signal and skip scores, using whichever is
+/// available if only one is, or 0 if neither is
+fn combine_scores(signal_score: Option<f64>, skip_score: Option<f64>, skip_weight: f64) -> f64 {
+    match (signal_score, skip_score) {
+        (Some(signal), Some(skip)) => (1. - skip_weight) * signal + skip_weight * skip,
+        (Some(score), None) | (None, Some(score)) => score,
+        (None, None) => 0.0,
+    }
+}
+
 /// Return list of kmer positions around a given position pos contain signal
 /// current data
 fn surround_has_data<S>(pos: u64, signal_map: &HashMap<u64, &Signal, S>) -> Vec<bool>
@@ -252,9 +1069,10 @@ where
     positions.map(|p| signal_map.get(&p).is_some()).collect()
 }
 
-/// Returns None if none of the positions around a genomic position have signal
-/// measurements.
-fn surrounding_signal<'a, S>(
+/// Returns None if none of the kmers covering a genomic position have signal
+/// measurements. Kmers can be shorter than six bases, ie 5-mers from RNA pore
+/// models, so only signals with a kmer reaching pos are kept.
+pub(crate) fn surrounding_signal<'a, S>(
     pos: u64,
     signal_map: &HashMap<u64, &'a Signal, S>,
 ) -> Option<Vec<&'a Signal>>
@@ -264,6 +1082,7 @@ where
     let positions = surrounding_pos(pos);
     let acc = positions
         .flat_map(|p| signal_map.get(&p))
+        .filter(|signal| signal.pos + signal.kmer.len() as u64 > pos)
         .cloned()
         .collect::<Vec<_>>();
     if acc.is_empty() {
@@ -298,16 +1117,13 @@ fn zscore_to_tt_pvalue(zscore: f64) -> f64 {
 /// Filters out surrounding signal for best signal to use for scoring.
 /// Will return None if one of the signal's kmers have a z-test p-value less
 /// than 0.05.
-fn best_surrounding_signal<'a, S>(
+fn best_surrounding_signal<'a>(
     surrounding: Option<Vec<&'a Signal>>,
-    ranks: &HashMap<String, f64, S>,
+    ranks: &Ranks,
     pos_gmms: &ModelDB,
     neg_gmms: &ModelDB,
     p_value_threshold: f64,
-) -> Option<&'a Signal>
-where
-    S: BuildHasher,
-{
+) -> Option<&'a Signal> {
     log::debug!("Determine best surrounding signal");
     surrounding.and_then(|signals| {
         signals
@@ -350,7 +1166,7 @@ where
 /// Returns HashMap mapping positions as u64 to the respective signal data
 /// Useful for iterating through each base pair position and computing results
 /// based on if there is data or not
-fn pos_with_data(read: &Eventalign) -> FnvHashMap<u64, &Signal> {
+pub(crate) fn pos_with_data(read: &Eventalign) -> FnvHashMap<u64, &Signal> {
     let mut avail_pos = FnvHashMap::default();
     for signal in read.signal_iter() {
         avail_pos.insert(signal.pos, signal);
@@ -429,11 +1245,19 @@ fn score_signal(
 
 #[cfg(test)]
 mod test {
-    use assert_fs::TempDir;
+    use bio::io::fasta::IndexedReader;
     use float_eq::assert_float_eq;
 
     use super::*;
-    use crate::{arrow::arrow_utils::load_iter, collapse::CollapseOptions, motif::Motif};
+    use crate::{
+        arrow::arrow_utils::{file_metadata, load_iter},
+        collapse::CollapseOptions,
+        hash::{content_hash, ContentHash},
+        rank::RankOptions,
+        score_summary::summary_path,
+        test_data::MiniGenome,
+        train::ModelParams,
+    };
 
     #[test]
     fn test_score_signal() {
@@ -478,27 +1302,379 @@ mod test {
         zscore_to_tt_pvalue(f64::INFINITY);
     }
 
+    #[test]
+    fn test_kmer_cache() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+        let read = load_iter(File::open(collapsed)?).next().unwrap()?.remove(0);
+
+        let mut scoring = test_options(&mini, &mini.dir().join("scores"))?;
+
+        let mut kmer_cache = KmerCache::default();
+        let uncached = scoring.score_eventalign(read.clone(), &mut kmer_cache)?;
+        let cached = scoring.score_eventalign(read, &mut kmer_cache)?;
+        assert!(!uncached.scores().is_empty());
+        assert_eq!(uncached.scores().len(), cached.scores().len());
+        for (a, b) in uncached.scores().iter().zip(cached.scores()) {
+            assert_eq!(a.pos, b.pos);
+            assert_eq!(a.kmer, b.kmer);
+            assert!(a.kmer.starts_with("AT") || a.kmer.starts_with("TA"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_score() {
+        // Kmer had data in 80% of positive and 20% of negative control reads
+        assert_float_eq!(skip_score(0.8, 0.2, true).unwrap(), 0.8, abs <= 1e-9);
+        assert_float_eq!(skip_score(0.8, 0.2, false).unwrap(), 0.2, abs <= 1e-9);
+        assert_eq!(skip_score(1.0, 1.0, false), None);
+
+        assert_float_eq!(combine_scores(Some(0.2), Some(0.6), 0.5), 0.4, abs <= 1e-9);
+        assert_eq!(combine_scores(Some(0.2), Some(0.6), 0.0), 0.2);
+        assert_eq!(combine_scores(None, Some(0.6), 0.0), 0.6);
+        assert_eq!(combine_scores(Some(0.2), None, 1.0), 0.2);
+        assert_eq!(combine_scores(None, None, 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_skip_score_metadata() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let run = |name: &str, skip_score: bool| -> Result<Option<String>> {
+            let output = mini.dir().join(name);
+            let mut scoring = test_options(&mini, &output)?;
+            scoring.skip_score(skip_score).skip_weight(0.25)?;
+            let mut writer = scoring.start_writer()?;
+            scoring.save(&mut writer, Vec::new())?;
+            scoring.close(writer)?;
+            let metadata = file_metadata(&mut File::open(output)?)?;
+            Ok(metadata.get(SKIP_SCORE_KEY).cloned())
+        };
+        assert_eq!(run("skips.arrow", true)?.as_deref(), Some("weight=0.25"));
+        assert_eq!(run("no_skips.arrow", false)?.as_deref(), Some("disabled"));
+
+        let mut scoring = test_options(&mini, &mini.dir().join("invalid.arrow"))?;
+        assert!(matches!(
+            scoring.skip_weight(1.5),
+            Err(ScoreError::InvalidSkipWeight { .. })
+        ));
+        Ok(())
+    }
+
+    fn test_options(mini: &MiniGenome, output: &Path) -> Result<ScoreOptions> {
+        let genome = open_genome(mini.genome())?;
+        Ok(ScoreOptions {
+            pos_ctrl: Model::default(),
+            neg_ctrl: Model::default(),
+            genome: SeqCache::new(genome, GenomeCache::default())?,
+            genome_filepath: mini.genome().to_path_buf(),
+            fai_path: None,
+            genome_cache: GenomeCache::default(),
+            chrom_alias: None,
+            rank: Ranks::default(),
+            output_file: Some(File::create(output)?),
+            output: output.to_path_buf(),
+            split_haplotypes: false,
+            haplotypes: None,
+            compression: ArrowCompression::default(),
+            batch_size: None,
+            cutoff: 10.0,
+            p_value_threshold: 0.05,
+            skip_score: true,
+            skip_weight: 0.5,
+            motifs: vec![Motif::new("AT", 2), Motif::new("TA", 1)],
+            rna: false,
+            kmer_size: KMER_SIZE,
+            progress_sink: None,
+            debug: None,
+            details: None,
+            summary: ScoreSummary::default(),
+            summary_output: None,
+            duplex: None,
+            by_chrom: false,
+        })
+    }
+
+    /// Controls for every kmer in the reads, with the negative control at the
+    /// signal of the kmer and the positive control well separated from it, so
+    /// positions with data are scored and close to 0
+    fn test_models(scoring: &mut ScoreOptions, collapsed: &Path) -> Result<()> {
+        let mut pos_gmms = ModelDB::default();
+        let mut neg_gmms = ModelDB::default();
+        load_apply(File::open(collapsed)?, |reads: Vec<Eventalign>| {
+            for signal in reads.iter().flat_map(|read| read.signal_iter()) {
+                let kmer: Kmer = signal.kmer.parse()?;
+                let mean = signal.signal_mean;
+                let neg = ModelParams::new(false, 0.5, mean, 2.0, mean, 2.0);
+                let pos = ModelParams::new(false, 0.5, mean + 20.0, 2.0, mean + 20.0, 2.0);
+                neg_gmms.insert(kmer, neg);
+                pos_gmms.insert(kmer, pos);
+            }
+            Ok(())
+        })?;
+        scoring.pos_ctrl = Model::new(pos_gmms);
+        scoring.neg_ctrl = Model::new(neg_gmms);
+        scoring.rank = RankOptions::new(2456, 100).rank(&scoring.pos_ctrl, &scoring.neg_ctrl);
+        Ok(())
+    }
+
+    #[test]
+    fn test_by_chrom() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let scored = |path: &Path| -> Result<Vec<(String, usize)>> {
+            let mut reads = Vec::new();
+            load_apply(File::open(path)?, |chunk: Vec<ScoredRead>| {
+                reads.extend(
+                    chunk
+                        .iter()
+                        .map(|r| (r.name().to_string(), r.scores().len())),
+                );
+                Ok(())
+            })?;
+            reads.sort();
+            Ok(reads)
+        };
+
+        let sequential = mini.dir().join("sequential.arrow");
+        test_options(&mini, &sequential)?.run(&collapsed)?;
+
+        let by_chrom = mini.dir().join("by_chrom.arrow");
+        let mut scoring = test_options(&mini, &by_chrom)?;
+        scoring.by_chrom(true);
+        scoring.run(&collapsed)?;
+        assert_eq!(scored(&by_chrom)?.len(), 2);
+        assert_eq!(scored(&sequential)?, scored(&by_chrom)?);
+
+        crate::index::index(&collapsed)?;
+        let mut scoring = test_options(&mini, &by_chrom)?;
+        scoring.by_chrom(true);
+        scoring.run(&collapsed)?;
+        assert_eq!(scored(&sequential)?, scored(&by_chrom)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_count_keeps_order() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let score_with = |n_threads: usize, by_chrom: bool| -> Result<ContentHash> {
+            let output = mini.dir().join(format!("{n_threads}.{by_chrom}.arrow"));
+            let mut scoring = test_options(&mini, &output)?;
+            scoring.by_chrom(by_chrom);
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(n_threads)
+                .build()?;
+            pool.install(|| scoring.run(&collapsed))?;
+            content_hash(&output)
+        };
+        for by_chrom in [false, true] {
+            assert_eq!(score_with(1, by_chrom)?, score_with(4, by_chrom)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_options() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let genome = mini.dir().join("no_index.fa");
+        std::fs::copy(mini.genome(), &genome)?;
+        let output = mini.dir().join("scores.arrow");
+        let missing_fai = mini.dir().join("missing.fai");
+        let res = ScoreOptions::try_new_with_fai(
+            &genome,
+            &genome,
+            &genome,
+            FaiIndex::default().path(Some(&missing_fai)),
+            &genome,
+            &output,
+        );
+        assert!(res.is_err());
+        assert!(!output.exists());
+
+        let mut scoring = test_options(&mini, &output)?;
+        let too_long = Motif::parse_from_str("1:GCGCGCG")?;
+        assert!(matches!(
+            scoring.motifs(vec![too_long]),
+            Err(ScoreError::MotifTooLong { kmer_size: 6, .. })
+        ));
+        assert!(scoring.motifs(vec![Motif::new("GC", 2)]).is_ok());
+
+        scoring.motifs(vec![Motif::new("GGACTA", 3)])?;
+        assert!(matches!(
+            scoring.rna(true),
+            Err(ScoreError::MotifTooLong { kmer_size: 5, .. })
+        ));
+        scoring.motifs(vec![Motif::new("DRACH", 3)])?.rna(true)?;
+        assert!(matches!(
+            scoring.motifs(vec![Motif::new("GGACTA", 3)]),
+            Err(ScoreError::MotifTooLong { kmer_size: 5, .. })
+        ));
+
+        // DNA reads can't be scored as RNA
+        let collapsed = mini.dir().join("collapsed.arrow");
+        CollapseOptions::try_new(mini.bam(), &collapsed)?.run(File::open(mini.eventalign())?)?;
+        let err = scoring.run(&collapsed).unwrap_err();
+        assert!(matches!(err, ScoreError::NotRna));
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_tsv() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+        let read = load_iter(File::open(&collapsed)?)
+            .next()
+            .unwrap()?
+            .remove(0);
+
+        let mut scoring = test_options(&mini, &mini.dir().join("scores"))?;
+        test_models(&mut scoring, &collapsed)?;
+        let debug_path = mini.dir().join("debug.tsv");
+        let region: Region = format!("{}:110-130", read.chrom()).parse()?;
+        scoring.debug_tsv(region, &debug_path)?;
+        let writer = scoring.start_writer()?;
+        scoring.score_eventalign(read, &mut KmerCache::default())?;
+        scoring.close(writer)?;
+
+        let debug = std::fs::read_to_string(debug_path)?;
+        let mut lines = debug.lines();
+        assert!(lines.next().unwrap().starts_with("read_name\tchrom\tpos"));
+        let rows = lines.collect::<Vec<_>>();
+        assert!(!rows.is_empty());
+        let mut n_chosen = 0;
+        for row in rows {
+            let fields = row.split('\t').collect::<Vec<_>>();
+            assert_eq!(fields.len(), 15);
+            let pos: u64 = fields[2].parse()?;
+            assert!((110..130).contains(&pos));
+            if fields[11] == "true" {
+                n_chosen += 1;
+                let p_value: f64 = fields[8].parse()?;
+                assert!(p_value < 0.05);
+                assert_eq!(fields[10], "true");
+                let pos_ln: f64 = fields[12].parse()?;
+                let neg_ln: f64 = fields[13].parse()?;
+                assert!(neg_ln > pos_ln);
+                let score: f64 = fields[14].parse()?;
+                assert!(score < 0.5);
+            }
+        }
+        assert!(n_chosen > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_details() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        CollapseOptions::try_new(mini.bam(), &collapsed)?.run(File::open(mini.eventalign())?)?;
+        crate::index::index(&collapsed)?;
+
+        let details = |by_chrom: bool| -> Result<Vec<String>> {
+            let output = mini.dir().join(format!("scores.{by_chrom}.arrow"));
+            let path = mini.dir().join(format!("details.{by_chrom}.tsv"));
+            let mut scoring = test_options(&mini, &output)?;
+            test_models(&mut scoring, &collapsed)?;
+            scoring.by_chrom(by_chrom).emit_details(&path)?;
+            scoring.run(&collapsed)?;
+            let details = std::fs::read_to_string(path)?;
+            Ok(details.lines().map(String::from).collect())
+        };
+        let rows = details(false)?;
+        assert!(rows[0].starts_with("read_name\tchrom\tpos\tstrand\tkmer"));
+        assert!(rows.len() > 1);
+        let mut n_chosen = 0;
+        for row in rows.iter().skip(1) {
+            let fields = row.split('\t').collect::<Vec<_>>();
+            assert_eq!(fields.len(), 16);
+            assert!(fields[4].starts_with("AT") || fields[4].starts_with("TA"));
+            if fields[10] != "NA" {
+                n_chosen += 1;
+                let p_value: f64 = fields[12].parse()?;
+                assert!(p_value < 0.05);
+                let pos_ln: f64 = fields[13].parse()?;
+                let neg_ln: f64 = fields[14].parse()?;
+                assert!(neg_ln > pos_ln);
+                let score: f64 = fields[15].parse()?;
+                assert!(score < 0.5);
+            }
+        }
+        assert!(n_chosen > 0);
+
+        let mut by_chrom = details(true)?;
+        let mut rows = rows;
+        assert_eq!(by_chrom.remove(0), rows.remove(0));
+        by_chrom.sort();
+        rows.sort();
+        assert_eq!(by_chrom, rows);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        CollapseOptions::try_new(mini.bam(), &collapsed)?.run(File::open(mini.eventalign())?)?;
+        crate::index::index(&collapsed)?;
+
+        let summary = |by_chrom: bool| -> Result<serde_json::Value> {
+            let output = mini.dir().join(format!("scores.{by_chrom}.arrow"));
+            let path = summary_path(&output);
+            let mut scoring = test_options(&mini, &output)?;
+            scoring.by_chrom(by_chrom).summary(&path)?;
+            scoring.run(&collapsed)?;
+            Ok(serde_json::from_reader(File::open(path)?)?)
+        };
+        let summary = summary(false)?;
+        let total = &summary["total"];
+        assert!(summary["reads"].as_u64().unwrap() > 0);
+        assert!(total["positions"].as_u64().unwrap() > 0);
+        assert_eq!(
+            total["positions"],
+            total["scored"].as_u64().unwrap() + total["skipped"].as_u64().unwrap()
+        );
+        assert!(summary["chroms"].as_object().unwrap().len() > 0);
+        let by_chrom = summary(true)?;
+        for key in [
+            "positions",
+            "scored",
+            "no_data",
+            "dropped_p_value",
+            "dropped_cutoff",
+        ] {
+            assert_eq!(total[key], by_chrom["total"][key]);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_single_read() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let filepath = "extra/single_read.eventalign.txt";
-        let input = File::open(filepath)?;
-        let bam_file = "extra/single_read.bam";
-        let output = temp_dir.path().join("test");
-        let mut collapse = CollapseOptions::try_new(bam_file, &output)?;
+        let mini = MiniGenome::new()?;
+        let input = File::open(mini.eventalign())?;
+        let output = mini.dir().join("test");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &output)?;
         collapse.run(input)?;
 
         let output = File::open(output)?;
         let reads = load_iter(output).next().unwrap().unwrap();
         let read = &reads[0];
 
-        let genome_file = "extra/sacCer3.fa";
-        let mut genome = IndexedReader::from_file(&genome_file)
+        let genome = IndexedReader::from_file(&mini.genome())
             .map_err(|_| eyre::eyre!("Failed to read genome file."))?;
+        let mut genome = SeqCache::new(genome, GenomeCache::default())?;
 
-        let chrom_lens = chrom_lens(&genome);
-
-        let context = context::Context::from_read(&mut genome, &chrom_lens, read)?;
+        let context = context::Context::from_read(&mut genome, read)?;
         assert_eq!(context.start_slop(), 5);
         // assert_eq!(context.end_slop(), 5);
 
@@ -506,11 +1682,11 @@ mod test {
         assert_eq!(m.position_0b(), 1);
         assert_eq!(
             context
-                .surrounding(182522, &m)
+                .surrounding(119, &m)
                 .into_iter()
                 .flat_map(std::str::from_utf8)
                 .collect::<Vec<_>>(),
-            vec!["ACATAT", "CATATT", "ATATTC", "TATTCA", "ATTCAA", "TTCAAT"]
+            vec!["AGATAT", "GATATG", "ATATGA", "TATGAT", "ATGATT", "TGATTC"]
         );
 
         Ok(())
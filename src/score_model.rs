@@ -1,42 +1,161 @@
-use std::io::{Read, Seek};
+use std::{
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use criterion_stats::univariate::{
     kde::{kernel::Gaussian, Bandwidth, Kde},
     Sample,
 };
-use eyre::Result;
-use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+use eyre::{Result, WrapErr};
+use fnv::FnvHashMap;
+use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
 
 use crate::{
     arrow::{
-        arrow_utils::load_apply,
-        io::{read_mod_bam_or_arrow, ModFile},
+        arrow_utils::{load_apply, n_chunks},
+        io::{read_mod_bam_or_arrow, read_mod_bam_or_arrow_reported, total_chunks, ModFile},
+        metadata::{MetadataExt, Strand},
         scored_read::ScoredRead,
     },
     bkde::BinnedKde,
+    progress::{ProgressSink, Reporter, Stage},
+    repro,
+    utils::CawlrIO,
 };
 
+/// Uniform random sample of a fixed size from a stream of values, see
+/// [reservoir sampling](https://en.wikipedia.org/wiki/Reservoir_sampling).
+#[derive(Debug, Clone, Default)]
+struct Reservoir {
+    seen: u64,
+    values: Vec<f64>,
+}
+
+impl Reservoir {
+    /// With capacity None every value is kept
+    fn add(&mut self, x: f64, capacity: Option<usize>, rng: &mut SmallRng) {
+        self.seen += 1;
+        match capacity {
+            Some(capacity) if self.values.len() >= capacity => {
+                let idx = rng.gen_range(0..self.seen);
+                if let Some(slot) = self.values.get_mut(idx as usize) {
+                    *slot = x;
+                }
+            }
+            _ => self.values.push(x),
+        }
+    }
+}
+
+/// Collects scores for the kernel density estimate, by sampling over the
+/// whole input, optionally split evenly across chromosomes.
+struct Sampler {
+    capacity: Option<usize>,
+    stratify: bool,
+    reservoirs: FnvHashMap<String, Reservoir>,
+}
+
+impl Sampler {
+    fn add<I>(&mut self, chrom: &str, scores: I, rng: &mut SmallRng)
+    where
+        I: IntoIterator<Item = f64>,
+    {
+        let key = if self.stratify { chrom } else { "" };
+        if !self.reservoirs.contains_key(key) {
+            self.reservoirs
+                .insert(key.to_string(), Reservoir::default());
+        }
+        let reservoir = self.reservoirs.get_mut(key).unwrap();
+        for x in scores {
+            reservoir.add(x, self.capacity, rng);
+        }
+    }
+
+    /// Sampled scores, where each chromosome contributes an equal share and
+    /// any share a chromosome can't fill goes to the others
+    fn finish(self, rng: &mut SmallRng) -> Vec<f64> {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => {
+                return self
+                    .reservoirs
+                    .into_values()
+                    .flat_map(|r| r.values)
+                    .collect()
+            }
+        };
+        // Sort for a deterministic order, smallest first so their leftover
+        // share is known before allocating the larger ones
+        let mut reservoirs = self.reservoirs.into_iter().collect::<Vec<_>>();
+        reservoirs.sort_by(|(a, ra), (b, rb)| ra.values.len().cmp(&rb.values.len()).then(a.cmp(b)));
+        let mut remaining = capacity;
+        let mut samples = Vec::with_capacity(capacity);
+        let n_strata = reservoirs.len();
+        for (i, (chrom, reservoir)) in reservoirs.into_iter().enumerate() {
+            let share = remaining / (n_strata - i);
+            let n = share.min(reservoir.values.len());
+            if self.stratify {
+                log::info!("Sampled {n} of {} scores on {chrom}", reservoir.seen);
+            }
+            samples.extend(reservoir.values.choose_multiple(rng, n).cloned());
+            remaining -= n;
+        }
+        samples
+    }
+}
+
+/// Kernel density estimates of the scores of every read, and of the reads on
+/// each strand, from [Options::run_modfile_split_strand]
+pub struct StrandBkdes {
+    pub all: BinnedKde,
+    pub plus: BinnedKde,
+    pub minus: BinnedKde,
+}
+
+impl StrandBkdes {
+    /// Save the estimate of every read to output, and the estimate of each
+    /// strand next to it, see [strand_output_path]
+    pub fn save_as<P: AsRef<Path>>(&self, output: P) -> Result<()> {
+        let output = output.as_ref();
+        self.all.save_as(output)?;
+        self.plus
+            .save_as(strand_output_path(output, Strand::plus()))?;
+        self.minus
+            .save_as(strand_output_path(output, Strand::minus()))?;
+        Ok(())
+    }
+}
+
 pub struct Options {
     samples: usize,
     bins: u32,
     rng: SmallRng,
+    seed: u64,
+    full: bool,
+    stratify: bool,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
 }
 
 impl Default for Options {
     fn default() -> Self {
-        let rng = SmallRng::seed_from_u64(2456);
         let n_samples = 10_000;
         let n_bins = 10_000;
-        Options::new(n_samples, n_bins, rng)
+        Options::new(n_samples, n_bins, 2456)
     }
 }
 
 impl Options {
-    fn new(n_samples: usize, n_bins: u32, rng: SmallRng) -> Self {
+    fn new(n_samples: usize, n_bins: u32, seed: u64) -> Self {
         Self {
             samples: n_samples,
             bins: n_bins,
-            rng,
+            rng: SmallRng::seed_from_u64(seed),
+            seed,
+            full: false,
+            stratify: false,
+            progress_sink: None,
         }
     }
 
@@ -50,54 +169,268 @@ impl Options {
         self
     }
 
-    pub fn run_modfile(&mut self, mod_file: ModFile) -> Result<BinnedKde> {
-        let scores = extract_samples_from_modfile(mod_file)?;
-        let scores: Vec<f64> = scores
-            .choose_multiple(&mut self.rng, self.samples)
-            .cloned()
-            .collect();
-        let kde = sample_kde(&scores)?;
+    /// Seed for sampling scores, so estimates are the same between runs
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.rng = SmallRng::seed_from_u64(seed);
+        self.seed = seed;
+        self
+    }
+
+    /// Use every score instead of sampling, slower but avoids sampling noise
+    pub fn full(&mut self, full: bool) -> &mut Self {
+        self.full = full;
+        self
+    }
+
+    /// Sample an equal number of scores from each chromosome, so that
+    /// chromosomes with more coverage don't dominate the estimate
+    pub fn stratify(&mut self, stratify: bool) -> &mut Self {
+        self.stratify = stratify;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is sampled
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    fn reporter(&self, mod_files: &mut [ModFile]) -> Result<Reporter> {
+        let mut reporter = Reporter::new(Stage::ModelScores, self.progress_sink.clone());
+        if let Some(total) = total_chunks(mod_files)? {
+            reporter.total_chunks(total);
+        }
+        Ok(reporter)
+    }
+
+    fn sampler(&self) -> Sampler {
+        if !self.full {
+            let params = serde_json::json!({
+                "samples": self.samples,
+                "stratify": self.stratify,
+            });
+            repro::record_seed("model-scores", self.seed, params);
+        }
+        Sampler {
+            capacity: if self.full { None } else { Some(self.samples) },
+            stratify: self.stratify,
+            reservoirs: FnvHashMap::default(),
+        }
+    }
+
+    fn bkde(&mut self, sampler: Sampler) -> Result<BinnedKde> {
+        let scores = sampler.finish(&mut self.rng);
+        self.bkde_from_scores(&scores)
+    }
+
+    fn bkde_from_scores(&self, scores: &[f64]) -> Result<BinnedKde> {
+        log::info!("Estimating kernel density from {} scores", scores.len());
+        let kde = sample_kde(scores)?;
         let bkde = BinnedKde::from_kde(self.bins as i32, &kde);
         Ok(bkde)
     }
-    pub fn run_modfile_with<F>(&mut self, mod_file: ModFile, extractor: F) -> Result<BinnedKde>
+
+    pub fn run_modfile(&mut self, mod_file: ModFile) -> Result<BinnedKde> {
+        self.run_modfile_with(mod_file, extract_samples)
+    }
+
+    pub fn run_modfile_with<F>(&mut self, mut mod_file: ModFile, extractor: F) -> Result<BinnedKde>
     where
         F: Fn(&[ScoredRead]) -> Vec<f64>,
     {
-        let scores = extract_samples_from_modfile_with(mod_file, extractor)?;
-        let scores: Vec<f64> = scores
-            .choose_multiple(&mut self.rng, self.samples)
-            .cloned()
-            .collect();
-        let kde = sample_kde(&scores)?;
-        let bkde = BinnedKde::from_kde(self.bins as i32, &kde);
-        Ok(bkde)
+        let mut reporter = self.reporter(std::slice::from_mut(&mut mod_file))?;
+        let mut sampler = self.sampler();
+        let rng = &mut self.rng;
+        read_mod_bam_or_arrow_reported(mod_file, &mut reporter, |read| {
+            let scores = extractor(std::slice::from_ref(&read));
+            sampler.add(read.chrom(), scores, rng);
+            Ok(())
+        })?;
+        reporter.finish();
+        self.bkde(sampler)
     }
 
-    pub fn run_modfile_max(&mut self, mod_file: ModFile) -> Result<BinnedKde> {
-        let scores = extract_max_samples_from_scored_read(mod_file)?;
-        let scores: Vec<f64> = scores
-            .choose_multiple(&mut self.rng, self.samples)
-            .cloned()
-            .collect();
-        let kde = sample_kde(&scores)?;
-        let bkde = BinnedKde::from_kde(self.bins as i32, &kde);
-        Ok(bkde)
+    /// Pool the scores of every file into one kernel density estimate, ie
+    /// the same BAM file read once for each modification tag.
+    pub fn run_modfiles(&mut self, mut mod_files: Vec<ModFile>) -> Result<BinnedKde> {
+        let mut reporter = self.reporter(&mut mod_files)?;
+        let mut sampler = self.sampler();
+        let rng = &mut self.rng;
+        for mod_file in mod_files {
+            read_mod_bam_or_arrow_reported(mod_file, &mut reporter, |read| {
+                let scores = extract_samples(std::slice::from_ref(&read));
+                sampler.add(read.chrom(), scores, rng);
+                Ok(())
+            })?;
+        }
+        reporter.finish();
+        self.bkde(sampler)
+    }
+
+    /// Estimate the score distribution of every read, and separately of the
+    /// reads on each strand, in one pass. Reads with an unknown strand are only
+    /// in the estimate of every read, which is the same as from
+    /// [Options::run_modfile] with the same seed.
+    pub fn run_modfile_split_strand(&mut self, mod_file: ModFile) -> Result<StrandBkdes> {
+        self.run_modfiles_split_strand(vec![mod_file])
+    }
+
+    /// Pool the scores of every file like [Options::run_modfiles], estimating
+    /// each strand separately like [Options::run_modfile_split_strand]
+    pub fn run_modfiles_split_strand(
+        &mut self,
+        mut mod_files: Vec<ModFile>,
+    ) -> Result<StrandBkdes> {
+        let mut reporter = self.reporter(&mut mod_files)?;
+        let mut all = self.sampler();
+        let mut plus = self.sampler();
+        let mut minus = self.sampler();
+        let rng = &mut self.rng;
+        // Strands are sampled with their own generator so the estimate of every
+        // read doesn't change
+        let mut strand_rng = SmallRng::seed_from_u64(self.seed.wrapping_add(1));
+        for mod_file in mod_files {
+            read_mod_bam_or_arrow_reported(mod_file, &mut reporter, |read| {
+                let scores = extract_samples(std::slice::from_ref(&read));
+                let strand = read.strand();
+                if !strand.is_unknown_strand() {
+                    let sampler = if strand.is_minus_strand() {
+                        &mut minus
+                    } else {
+                        &mut plus
+                    };
+                    sampler.add(read.chrom(), scores.iter().copied(), &mut strand_rng);
+                }
+                all.add(read.chrom(), scores, rng);
+                Ok(())
+            })?;
+        }
+        reporter.finish();
+        let all = self.bkde(all)?;
+        let no_scores = |strand: &str| format!("Failed to estimate scores on the {strand} strand");
+        let plus = self.bkde(plus).wrap_err_with(|| no_scores("+"))?;
+        let minus = self.bkde(minus).wrap_err_with(|| no_scores("-"))?;
+        Ok(StrandBkdes { all, plus, minus })
     }
 
-    pub fn run<R>(&mut self, reader: R) -> Result<BinnedKde>
+    /// Pool the scores of several files, ie control flowcells sequenced to
+    /// different depths, where each file contributes a share of the sampled
+    /// scores proportional to its weight. Weights need sampling, so they can't
+    /// be used with [Options::full].
+    pub fn run_weighted(&mut self, mut inputs: Vec<(ModFile, f64)>) -> Result<BinnedKde> {
+        if self.full {
+            eyre::bail!("Weighting inputs needs sampled scores, remove --full");
+        }
+        if let Some((_, weight)) = inputs.iter().find(|(_, w)| !(w.is_finite() && *w > 0.0)) {
+            eyre::bail!("Weights must be positive, found {weight}");
+        }
+        let total: f64 = inputs.iter().map(|(_, w)| w).sum();
+        let mut reporter = Reporter::new(Stage::ModelScores, self.progress_sink.clone());
+        if let Some(total_chunks) = inputs
+            .iter_mut()
+            .map(|(mod_file, _)| mod_file.n_chunks())
+            .sum::<Result<Option<usize>>>()?
+        {
+            reporter.total_chunks(total_chunks);
+        }
+        let n_inputs = inputs.len();
+        let mut remaining = self.samples;
+        let mut scores = Vec::with_capacity(self.samples);
+        for (idx, (mod_file, weight)) in inputs.into_iter().enumerate() {
+            // The last input takes whatever rounding left over
+            let share = if idx + 1 == n_inputs {
+                remaining
+            } else {
+                ((self.samples as f64 * weight / total).round() as usize).min(remaining)
+            };
+            remaining -= share;
+            let mut sampler = self.sampler();
+            sampler.capacity = Some(share);
+            let rng = &mut self.rng;
+            read_mod_bam_or_arrow_reported(mod_file, &mut reporter, |read| {
+                let read_scores = extract_samples(std::slice::from_ref(&read));
+                sampler.add(read.chrom(), read_scores, rng);
+                Ok(())
+            })?;
+            let sampled = sampler.finish(&mut self.rng);
+            if sampled.len() < share {
+                log::warn!(
+                    "Input {} only has {} scores for its share of {share}",
+                    idx + 1,
+                    sampled.len()
+                );
+            }
+            log::info!("Sampled {} scores from input {}", sampled.len(), idx + 1);
+            scores.extend(sampled);
+        }
+        reporter.finish();
+        self.bkde_from_scores(&scores)
+    }
+
+    pub fn run_modfile_max(&mut self, mut mod_file: ModFile) -> Result<BinnedKde> {
+        let mut reporter = self.reporter(std::slice::from_mut(&mut mod_file))?;
+        let mut sampler = self.sampler();
+        let rng = &mut self.rng;
+        let mut n_reads = 0;
+        read_mod_bam_or_arrow_reported(mod_file, &mut reporter, |read| {
+            let max_sample = read.scores().iter().map(|x| x.score).reduce(f64::max);
+            if max_sample.is_some() {
+                n_reads += 1;
+            }
+            sampler.add(read.chrom(), max_sample, rng);
+            Ok(())
+        })?;
+        reporter.finish();
+        if n_reads == 0 {
+            return Err(eyre::eyre!("Error: No max scores found"));
+        }
+        self.bkde(sampler)
+    }
+
+    pub fn run<R>(&mut self, mut reader: R) -> Result<BinnedKde>
     where
         R: Read + Seek,
     {
-        let scores = extract_samples_from_reader(reader)?;
-        let scores: Vec<f64> = scores
-            .choose_multiple(&mut self.rng, self.samples)
-            .cloned()
-            .collect();
-        let kde = sample_kde(&scores)?;
-        let bkde = BinnedKde::from_kde(self.bins as i32, &kde);
-        Ok(bkde)
+        let mut reporter = Reporter::new(Stage::ModelScores, self.progress_sink.clone());
+        reporter.total_chunks(n_chunks(&mut reader)?);
+        let mut sampler = self.sampler();
+        let rng = &mut self.rng;
+        load_apply(reader, |reads: Vec<ScoredRead>| {
+            for read in reads.iter() {
+                let scores = extract_samples(std::slice::from_ref(read));
+                sampler.add(read.chrom(), scores, rng);
+            }
+            reporter.chunk(reads.len());
+            Ok(())
+        })?;
+        reporter.finish();
+        self.bkde(sampler)
+    }
+}
+
+/// Output path for the kernel density estimate of one modification tag, with
+/// the tag added before the extension, ie neg.pickle -> neg.C+m.pickle
+pub fn tag_output_path<P: AsRef<Path>>(output: P, tag: &str) -> PathBuf {
+    let output = output.as_ref();
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(tag);
+    if let Some(ext) = output.extension() {
+        file_name.push(".");
+        file_name.push(ext);
     }
+    output.with_file_name(file_name)
+}
+
+/// Output path for the kernel density estimate of one strand, ie neg.pickle
+/// -> neg.plus.pickle, see [tag_output_path]
+pub fn strand_output_path<P: AsRef<Path>>(output: P, strand: Strand) -> PathBuf {
+    let strand = if strand.is_minus_strand() {
+        "minus"
+    } else {
+        "plus"
+    };
+    tag_output_path(output, strand)
 }
 
 fn sample_kde(samples: &[f64]) -> Result<Kde<f64, Gaussian>> {
@@ -196,6 +529,10 @@ pub fn extract_max_samples(reads: &[ScoredRead]) -> Vec<f64> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        arrow::scored_read::Score,
+        test_data::{write_reads, TestRead},
+    };
 
     #[test]
     fn test_extract_samples() {
@@ -203,4 +540,137 @@ mod test {
         let samples = extract_samples_from_modfile(modfile).unwrap();
         assert_eq!(samples.len(), 15);
     }
+
+    #[test]
+    fn test_run_modfiles() -> Result<()> {
+        let path = "extra/modbams/megalodon-modbam.bam";
+        let open = |tag: &str| ModFile::open_mod_bam(path, tag);
+        let pmf = |bkde: &BinnedKde| {
+            (0..=100)
+                .map(|i| bkde.pmf_from_score(i as f64 / 100.0))
+                .collect::<Vec<_>>()
+        };
+        let mut opts = Options::default();
+        opts.full(true);
+        let scores = extract_samples_from_modfile(open("A+Y")?)?;
+        let single = opts.run_modfile(open("A+Y")?)?;
+        assert_eq!(pmf(&single), pmf(&opts.bkde_from_scores(&scores)?));
+
+        // The file has no C+m calls, so only the A+Y scores are pooled
+        let merged = opts.run_modfiles(vec![open("A+Y")?, open("C+m")?])?;
+        assert_eq!(pmf(&merged), pmf(&single));
+
+        // Scores of every file are pooled
+        let twice = opts.run_modfiles(vec![open("A+Y")?, open("A+Y")?])?;
+        let pooled = [scores.as_slice(), scores.as_slice()].concat();
+        assert_eq!(pmf(&twice), pmf(&opts.bkde_from_scores(&pooled)?));
+        assert_ne!(pmf(&twice), pmf(&single));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_output_path() {
+        assert_eq!(
+            tag_output_path("out/neg.pickle", "C+m"),
+            PathBuf::from("out/neg.C+m.pickle")
+        );
+        assert_eq!(tag_output_path("neg", "C+h"), PathBuf::from("neg.C+h"));
+    }
+
+    #[test]
+    fn test_run_modfile_split_strand() -> Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let path = temp_dir.path().join("scores.arrow");
+        let reads = [(Strand::plus(), 0.2), (Strand::minus(), 0.8)]
+            .iter()
+            .flat_map(|&(strand, x)| std::iter::repeat((strand, x)).take(10))
+            .enumerate()
+            .map(|(i, (strand, x))| {
+                let scores = (0..10)
+                    .map(|j| Score::new(10 + j, "A".parse().unwrap(), false, Some(x), x))
+                    .collect();
+                TestRead::new(format!("read{i}"))
+                    .strand(strand)
+                    .scored(scores)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&path, &reads)?;
+
+        let bkdes = Options::default()
+            .full(true)
+            .run_modfile_split_strand(ModFile::open_arrow(&path)?)?;
+        assert!(bkdes.plus.pmf_from_score(0.2) > bkdes.plus.pmf_from_score(0.8));
+        assert!(bkdes.minus.pmf_from_score(0.8) > bkdes.minus.pmf_from_score(0.2));
+
+        let output = temp_dir.path().join("pos.pickle");
+        bkdes.save_as(&output)?;
+        assert!(output.exists());
+        assert!(strand_output_path(&output, Strand::minus()).exists());
+        assert_eq!(
+            strand_output_path("pos.pickle", Strand::plus()),
+            PathBuf::from("pos.plus.pickle")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_weighted() -> Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let write = |name: &str, x: f64| -> Result<PathBuf> {
+            let path = temp_dir.path().join(name);
+            let scores = (0..100)
+                .map(|j| Score::new(10 + j, "A".parse().unwrap(), false, Some(x), x))
+                .collect();
+            write_reads(&path, &[TestRead::new(name).span(10, 200).scored(scores)])?;
+            Ok(path)
+        };
+        let low = write("low.arrow", 0.2)?;
+        let high = write("high.arrow", 0.8)?;
+
+        let bkde = Options::default().samples(100).run_weighted(vec![
+            (ModFile::open_arrow(&low)?, 3.0),
+            (ModFile::open_arrow(&high)?, 1.0),
+        ])?;
+        assert!(bkde.pmf_from_score(0.2) > 2.0 * bkde.pmf_from_score(0.8));
+
+        let invalid = Options::default().run_weighted(vec![(ModFile::open_arrow(&low)?, 0.0)]);
+        assert!(invalid.is_err());
+        let full = Options::default()
+            .full(true)
+            .run_weighted(vec![(ModFile::open_arrow(&low)?, 1.0)]);
+        assert!(full.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sampler() {
+        let mut rng = SmallRng::seed_from_u64(2456);
+        let mut sampler = Options::default().samples(1000).sampler();
+        sampler.add("chrI", (0..10_000).map(|x| x as f64), &mut rng);
+        let samples = sampler.finish(&mut rng);
+        assert_eq!(samples.len(), 1000);
+        // Sampled across the whole input, not just the start
+        let late = samples.iter().filter(|&&x| x >= 5000.0).count();
+        assert!((400..600).contains(&late), "{late}");
+
+        let mut sampler = Options::default().samples(1000).full(true).sampler();
+        sampler.add("chrI", (0..10_000).map(|x| x as f64), &mut rng);
+        assert_eq!(sampler.finish(&mut rng).len(), 10_000);
+    }
+
+    #[test]
+    fn test_sampler_stratify() {
+        let mut rng = SmallRng::seed_from_u64(2456);
+        let mut sampler = Options::default().samples(1000).stratify(true).sampler();
+        sampler.add("chrI", std::iter::repeat(1.0).take(10_000), &mut rng);
+        sampler.add("chrII", std::iter::repeat(2.0).take(10_000), &mut rng);
+        sampler.add("chrM", std::iter::repeat(3.0).take(100), &mut rng);
+        let samples = sampler.finish(&mut rng);
+        assert_eq!(samples.len(), 1000);
+        let count = |x: f64| samples.iter().filter(|&&s| s == x).count();
+        // chrM only has 100 scores, the rest of its share goes to the others
+        assert_eq!(count(3.0), 100);
+        assert_eq!(count(1.0), 450);
+        assert_eq!(count(2.0), 450);
+    }
 }
@@ -0,0 +1,315 @@
+//! Counts of scored and unscored positions from cawlr score, per chromosome
+//! and per motif. A model trained on a different genome, or with the wrong
+//! motifs, shows up as nearly every position being unscored.
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use serde_json::{json, Value};
+
+use crate::{arrow::kmer::Kmer, motif::Motif};
+
+/// Why a position has no signal score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unscored {
+    /// None of the kmers covering the position have signal data
+    NoData,
+    /// None of the kmers with signal data are in both control models
+    MissingModel,
+    /// None of the kmers pass [crate::score::ScoreOptions::p_value_threshold]
+    PValue,
+    /// The signal is unlikely under both control models, see
+    /// [crate::score::ScoreOptions::cutoff]
+    Cutoff,
+}
+
+/// Number of positions scored, or left unscored for each [Unscored] reason
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PositionCounts {
+    pub scored: u64,
+    pub no_data: u64,
+    pub missing_model: u64,
+    pub dropped_p_value: u64,
+    pub dropped_cutoff: u64,
+    signal_score_sum: f64,
+    score_sum: f64,
+}
+
+impl PositionCounts {
+    fn add(&mut self, signal_score: Result<f64, Unscored>, score: f64) {
+        match signal_score {
+            Ok(signal_score) => {
+                self.scored += 1;
+                self.signal_score_sum += signal_score;
+            }
+            Err(Unscored::NoData) => self.no_data += 1,
+            Err(Unscored::MissingModel) => self.missing_model += 1,
+            Err(Unscored::PValue) => self.dropped_p_value += 1,
+            Err(Unscored::Cutoff) => self.dropped_cutoff += 1,
+        }
+        self.score_sum += score;
+    }
+
+    fn merge(&mut self, other: &PositionCounts) {
+        self.scored += other.scored;
+        self.no_data += other.no_data;
+        self.missing_model += other.missing_model;
+        self.dropped_p_value += other.dropped_p_value;
+        self.dropped_cutoff += other.dropped_cutoff;
+        self.signal_score_sum += other.signal_score_sum;
+        self.score_sum += other.score_sum;
+    }
+
+    /// Positions without a signal score, for any reason
+    pub fn skipped(&self) -> u64 {
+        self.no_data + self.missing_model + self.dropped_p_value + self.dropped_cutoff
+    }
+
+    pub fn positions(&self) -> u64 {
+        self.scored + self.skipped()
+    }
+
+    /// Mean signal score of the scored positions, None if there are none
+    pub fn mean_signal_score(&self) -> Option<f64> {
+        (self.scored > 0).then(|| self.signal_score_sum / self.scored as f64)
+    }
+
+    /// Mean final score of every position, which includes the skip score
+    pub fn mean_score(&self) -> Option<f64> {
+        let positions = self.positions();
+        (positions > 0).then(|| self.score_sum / positions as f64)
+    }
+
+    /// Fraction of positions with a signal score, None if there are none
+    pub fn frac_scored(&self) -> Option<f64> {
+        let positions = self.positions();
+        (positions > 0).then(|| self.scored as f64 / positions as f64)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "positions": self.positions(),
+            "scored": self.scored,
+            "skipped": self.skipped(),
+            "no_data": self.no_data,
+            "missing_model": self.missing_model,
+            "dropped_p_value": self.dropped_p_value,
+            "dropped_cutoff": self.dropped_cutoff,
+            "mean_signal_score": self.mean_signal_score(),
+            "mean_score": self.mean_score(),
+        })
+    }
+
+    fn write_tsv_row<W: Write>(&self, writer: &mut W, group: &str, name: &str) -> Result<()> {
+        writeln!(
+            writer,
+            "{group}\t{name}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.positions(),
+            self.scored,
+            self.skipped(),
+            self.no_data,
+            self.missing_model,
+            self.dropped_p_value,
+            self.dropped_cutoff,
+            fmt_option(self.mean_signal_score()),
+            fmt_option(self.mean_score()),
+        )?;
+        Ok(())
+    }
+}
+
+fn fmt_option(x: Option<f64>) -> String {
+    x.map_or("NA".to_string(), |x| x.to_string())
+}
+
+const SUMMARY_TSV_HEADER: &str = "group\tname\tpositions\tscored\tskipped\tno_data\t\
+                                  missing_model\tdropped_p_value\tdropped_cutoff\t\
+                                  mean_signal_score\tmean_score";
+
+/// Below this fraction of scored positions the summary is logged as a warning
+const LOW_FRAC_SCORED: f64 = 0.1;
+
+/// Position counts over a whole cawlr score run. A position matching more
+/// than one motif is counted under each of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScoreSummary {
+    /// Reads that were scored
+    pub reads: u64,
+    /// Reads that failed to be scored, ie reads past the end of the genome
+    pub failed_reads: u64,
+    total: PositionCounts,
+    chroms: BTreeMap<String, PositionCounts>,
+    motifs: BTreeMap<String, PositionCounts>,
+}
+
+impl ScoreSummary {
+    /// Count a position with the kmer starting at it
+    pub fn add(
+        &mut self,
+        chrom: &str,
+        kmer: &Kmer,
+        motifs: &[Motif],
+        signal_score: Result<f64, Unscored>,
+        score: f64,
+    ) {
+        self.total.add(signal_score, score);
+        self.chroms
+            .entry(chrom.to_string())
+            .or_default()
+            .add(signal_score, score);
+        for motif in motifs.iter().filter(|m| m.matches_at(kmer.as_bytes(), 0)) {
+            self.motifs
+                .entry(motif.to_string())
+                .or_default()
+                .add(signal_score, score);
+        }
+    }
+
+    /// Add the counts of another summary, ie from scoring another chromosome
+    pub fn merge(&mut self, other: &ScoreSummary) {
+        self.reads += other.reads;
+        self.failed_reads += other.failed_reads;
+        self.total.merge(&other.total);
+        for (chrom, counts) in other.chroms.iter() {
+            self.chroms.entry(chrom.clone()).or_default().merge(counts);
+        }
+        for (motif, counts) in other.motifs.iter() {
+            self.motifs.entry(motif.clone()).or_default().merge(counts);
+        }
+    }
+
+    pub fn total(&self) -> &PositionCounts {
+        &self.total
+    }
+
+    pub fn chroms(&self) -> &BTreeMap<String, PositionCounts> {
+        &self.chroms
+    }
+
+    pub fn motifs(&self) -> &BTreeMap<String, PositionCounts> {
+        &self.motifs
+    }
+
+    /// Log the totals, as a warning if few positions were scored
+    pub fn log(&self) {
+        let total = &self.total;
+        let msg = format!(
+            "Scored {} of {} positions in {} reads ({} reads failed), {} without signal \
+             data, {} missing from the models, {} dropped by p-value, {} dropped by cutoff",
+            total.scored,
+            total.positions(),
+            self.reads,
+            self.failed_reads,
+            total.no_data,
+            total.missing_model,
+            total.dropped_p_value,
+            total.dropped_cutoff,
+        );
+        if self.reads > 0 && total.frac_scored().unwrap_or(0.0) < LOW_FRAC_SCORED {
+            log::warn!("{msg}. Check the models were trained on the same genome and motifs");
+        } else {
+            log::info!("{msg}");
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<()> {
+        let groups = |counts: &BTreeMap<String, PositionCounts>| {
+            counts
+                .iter()
+                .map(|(name, counts)| (name.clone(), counts.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let summary = json!({
+            "reads": self.reads,
+            "failed_reads": self.failed_reads,
+            "total": self.total.to_json(),
+            "chroms": groups(&self.chroms),
+            "motifs": groups(&self.motifs),
+        });
+        serde_json::to_writer_pretty(writer, &summary)?;
+        Ok(())
+    }
+
+    /// One row for the totals, then a row for each chromosome and each motif
+    pub fn write_tsv<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "{SUMMARY_TSV_HEADER}")?;
+        self.total.write_tsv_row(&mut writer, "total", "all")?;
+        for (chrom, counts) in self.chroms.iter() {
+            counts.write_tsv_row(&mut writer, "chrom", chrom)?;
+        }
+        for (motif, counts) in self.motifs.iter() {
+            counts.write_tsv_row(&mut writer, "motif", motif)?;
+        }
+        Ok(())
+    }
+
+    /// Write as a TSV if the path ends in .tsv, otherwise as JSON
+    pub fn write_for_path<W: Write, P: AsRef<Path>>(&self, writer: W, path: P) -> Result<()> {
+        if path.as_ref().extension().map_or(false, |ext| ext == "tsv") {
+            self.write_tsv(writer)
+        } else {
+            self.write_json(writer)
+        }
+    }
+}
+
+/// Path of the summary written next to the output of cawlr score, ie
+/// scores.arrow.summary.json for scores.arrow
+pub fn summary_path<P: AsRef<Path>>(output: P) -> PathBuf {
+    let mut path = output.as_ref().as_os_str().to_owned();
+    path.push(".summary.json");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() -> Result<()> {
+        let motifs = vec![
+            Motif::parse_from_str("2:GC")?,
+            Motif::parse_from_str("1:G")?,
+        ];
+        let gc = Kmer::from_bytes(b"GCAAAA")?;
+        let ga = Kmer::from_bytes(b"GAAAAA")?;
+        let mut summary = ScoreSummary::default();
+        summary.add("chrI", &gc, &motifs, Ok(0.8), 0.7);
+        summary.add("chrI", &ga, &motifs, Err(Unscored::Cutoff), 0.1);
+
+        let mut other = ScoreSummary {
+            reads: 1,
+            ..Default::default()
+        };
+        other.add("chrII", &gc, &motifs, Err(Unscored::NoData), 0.0);
+        summary.merge(&other);
+
+        let total = summary.total();
+        assert_eq!(
+            (total.positions(), total.scored, total.skipped()),
+            (3, 1, 2)
+        );
+        assert_eq!(total.mean_signal_score(), Some(0.8));
+        assert_eq!(summary.chroms()["chrI"].dropped_cutoff, 1);
+        assert_eq!(summary.chroms()["chrII"].no_data, 1);
+        assert_eq!(summary.motifs()["2:GC"].positions(), 2);
+        assert_eq!(summary.motifs()["1:G"].positions(), 3);
+
+        let mut tsv = Vec::new();
+        summary.write_for_path(&mut tsv, "summary.tsv")?;
+        let tsv = String::from_utf8(tsv)?;
+        assert_eq!(tsv.lines().count(), 6);
+        assert!(tsv.contains("chrom\tchrII\t1\t0\t1\t1\t0\t0\t0\tNA\t0"));
+
+        let mut json = Vec::new();
+        summary.write_for_path(&mut json, "summary.json")?;
+        let json: Value = serde_json::from_slice(&json)?;
+        assert_eq!(json["reads"], 1);
+        assert_eq!(json["total"]["dropped_cutoff"], 1);
+        assert_eq!(json["motifs"]["2:GC"]["scored"], 1);
+        Ok(())
+    }
+}
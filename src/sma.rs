@@ -1,26 +1,169 @@
 use std::{
+    fmt,
     fs::File,
-    io::{BufWriter, Write},
-    path::Path,
-    sync::{Arc, Mutex},
+    hash::Hasher,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
 };
 
 use eyre::{Context, Result};
+use fnv::FnvHasher;
 use itertools::Itertools;
+use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use crate::{
     arrow::{
-        arrow_utils::load_apply,
+        arrow_utils::{load_apply, n_chunks, save_t, ArrowWriter, SchemaExt},
         io::{read_mod_bam_or_arrow, ModFile},
-        metadata::MetadataExt,
-        scored_read::ScoredRead,
+        metadata::{MetadataExt, Strand},
+        scored_read::{Score, ScoredRead},
+        sma_read::{BlockState, SmaBlock, SmaRead},
     },
     bkde::BinnedKde,
+    cancel,
+    haplotype::{group_by_haplotype, HaplotypeOutputs},
     motif::Motif,
-    utils::CawlrIO,
+    progress::{ProgressSink, Reporter, Stage},
+    repro,
+    score_model::strand_output_path,
+    utils::{stdout_or_file, CawlrIO},
 };
 
+/// Color of a bed entry, written as the itemRgb field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.0, self.1, self.2)
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = String;
+
+    /// Parses colors in the same format as the bed itemRgb field, ie "255,0,0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid color \"{s}\", expected format is R,G,B, ie 255,0,0");
+        let channels = s
+            .split(',')
+            .map(|c| c.trim().parse::<u8>().map_err(|_| err()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match channels[..] {
+            [r, g, b] => Ok(Rgb(r, g, b)),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Colors used for reads on each strand in the bed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrandColors {
+    pub plus: Rgb,
+    pub minus: Rgb,
+    pub unknown: Rgb,
+}
+
+impl StrandColors {
+    /// Orange and blue from the Okabe-Ito palette, which remain
+    /// distinguishable with the common forms of color blindness.
+    pub const fn colorblind() -> Self {
+        StrandColors {
+            plus: Rgb(230, 159, 0),
+            minus: Rgb(0, 114, 178),
+            unknown: Rgb(0, 0, 0),
+        }
+    }
+
+    /// Red and blue, the colors used by earlier versions of cawlr.
+    pub const fn classic() -> Self {
+        StrandColors {
+            plus: Rgb(255, 0, 0),
+            minus: Rgb(0, 0, 255),
+            unknown: Rgb(0, 0, 0),
+        }
+    }
+
+    pub fn color(&self, strand: Strand) -> Rgb {
+        if strand.is_minus_strand() {
+            self.minus
+        } else if strand.is_unknown_strand() {
+            self.unknown
+        } else {
+            self.plus
+        }
+    }
+}
+
+impl Default for StrandColors {
+    fn default() -> Self {
+        StrandColors::colorblind()
+    }
+}
+
+/// File format written by cawlr sma.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmaFormat {
+    /// Bed12 with a block for each nucleosome, for genome browsers
+    Bed,
+    /// Arrow file of [SmaRead], for downstream analysis
+    Arrow,
+}
+
+impl Default for SmaFormat {
+    fn default() -> Self {
+        SmaFormat::Bed
+    }
+}
+
+impl FromStr for SmaFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bed" => Ok(SmaFormat::Bed),
+            "arrow" => Ok(SmaFormat::Arrow),
+            _ => Err(format!(
+                "Invalid output format \"{s}\", expected either bed or arrow"
+            )),
+        }
+    }
+}
+
+/// Order of the blocks of minus strand reads in bed output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmaBlockOrder {
+    /// Blocks in genomic order with starts relative to chromStart, as
+    /// genome browsers expect, for reads on either strand
+    #[default]
+    Genome,
+    /// Blocks of minus strand reads start from the 5' end of the read, so
+    /// they're in reverse genomic order with starts relative to chromEnd. Plus
+    /// strand reads and reads with an unknown strand are in genomic order.
+    Read,
+}
+
+impl FromStr for SmaBlockOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "genome" => Ok(SmaBlockOrder::Genome),
+            "read" => Ok(SmaBlockOrder::Read),
+            _ => Err(format!(
+                "Invalid block order \"{s}\", expected either genome or read"
+            )),
+        }
+    }
+}
+
 /// Converts all the scores in the read into a vector. Each element is either
 /// -1.0 if no value exists, or a score between 0.0 and 1.0.
 /// This vector is usually used in the dynamic alignment step later in single
@@ -39,35 +182,72 @@ pub(crate) fn make_scoring_vec(read: &ScoredRead) -> Vec<f64> {
     calling_vec
 }
 
-struct SmaOutput {
-    n_nucs: usize,
-    starts: Vec<usize>,
-    blks: Vec<usize>,
-}
-
-impl SmaOutput {
-    fn write(&self, writer: &Mutex<Box<dyn Write + Send>>, read: &ScoredRead) -> eyre::Result<()> {
-        let mut w = writer.lock().map_err(|_| eyre::eyre!("Mutex lock error"))?;
-        writeln!(
-            w,
-            "{}\t{}\t{}\t{}\t0\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-            read.chrom(),
-            read.start_0b(),
-            read.end_1b_excl(),
-            read.name(),
-            read.strand(),
-            read.start_0b(),
-            read.end_1b_excl(),
-            read.strand().rgb_str(),
-            self.n_nucs,
-            self.blks.iter().join(","),
-            self.starts.iter().join(","),
-        )?;
-        Ok(())
+/// Bed12 line for the read, with a block for each nucleosome. Bed12 requires
+/// blocks at the start and end of the read, so single base pseudo blocks are
+/// added when the read doesn't start or end with a nucleosome.
+fn bed_line(read: &SmaRead, colors: &StrandColors, order: SmaBlockOrder) -> String {
+    let start = read.start_0b();
+    let end = read.end_1b_excl();
+    let mut nucs = read
+        .nucleosomes()
+        .map(|b| (b.start, b.end()))
+        .collect::<Vec<_>>();
+
+    if nucs.is_empty() || nucs[0].0 != start {
+        nucs.insert(0, (start, start + 1));
     }
+
+    let bend = nucs.last().map(|&(_, b)| b).unwrap();
+    if bend != end {
+        nucs.push((end - 1, end))
+    }
+
+    let block_starts = if order == SmaBlockOrder::Read && read.strand().is_minus_strand() {
+        nucs.reverse();
+        nucs.iter().map(|(_, e)| end - e).join(",")
+    } else {
+        nucs.iter().map(|(s, _)| s - start).join(",")
+    };
+
+    format!(
+        "{}\t{}\t{}\t{}\t0\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        read.chrom(),
+        start,
+        end,
+        read.name(),
+        read.strand(),
+        start,
+        end,
+        colors.color(read.strand()),
+        nucs.len(),
+        nucs.iter().map(|(s, e)| e - s).join(","),
+        block_starts,
+    )
+}
+
+/// Splits the read into linker and nucleosome blocks covering the whole read
+fn to_blocks(read: &ScoredRead, nucs: Vec<(usize, usize)>) -> Vec<SmaBlock> {
+    let mut blocks = Vec::with_capacity(nucs.len() * 2 + 1);
+    let mut cursor = read.start_0b();
+    for (s, e) in nucs {
+        let (s, e) = (s as u64, e as u64);
+        if s > cursor {
+            blocks.push(SmaBlock::new(cursor, s - cursor, BlockState::linker()));
+        }
+        blocks.push(SmaBlock::new(s, e - s, BlockState::nucleosome()));
+        cursor = e;
+    }
+    if cursor < read.end_1b_excl() {
+        blocks.push(SmaBlock::new(
+            cursor,
+            read.end_1b_excl() - cursor,
+            BlockState::linker(),
+        ));
+    }
+    blocks
 }
 
-fn sma2(read: &ScoredRead, pos_scores: &BinnedKde, neg_scores: &BinnedKde) -> SmaOutput {
+fn sma2(read: &ScoredRead, pos_scores: &BinnedKde, neg_scores: &BinnedKde) -> SmaRead {
     let calling_vec = make_scoring_vec(read);
     let base_num = read.end_1b_excl() - read.start_0b() + 1;
 
@@ -182,191 +362,282 @@ fn sma2(read: &ScoredRead, pos_scores: &BinnedKde, neg_scores: &BinnedKde) -> Sm
         nucs.push((ncls_start, read.end_1b_excl() as usize));
     }
 
-    // Add pseudo block at start if read doesn't start with a nucleosome
-    if nucs.is_empty() || nucs[0].0 != read.start_0b() as usize {
-        nucs.insert(0, (read.start_0b() as usize, read.start_0b() as usize + 1));
-    }
-
-    // Add pseduo block at end if read doesn't end with a nucleosome
-    let bend = nucs.last().map(|&(_, b)| b).unwrap();
-    if bend != read.end_1b_excl() as usize {
-        nucs.push((read.end_1b_excl() as usize - 1, read.end_1b_excl() as usize))
-    }
-
-    let n_nucs = nucs.len();
-    let (starts, blks): (Vec<_>, Vec<_>) = nucs
-        .into_iter()
-        .map(|(s, e)| (s - read.start_0b() as usize, (e - s)))
-        .unzip();
-    SmaOutput {
-        n_nucs,
-        starts,
-        blks,
-    }
+    SmaRead::new(read.metadata.clone(), to_blocks(read, nucs))
 }
 
-fn sma(
-    writer: &Mutex<Box<dyn Write + Send>>,
-    pos_scores: &BinnedKde,
-    neg_scores: &BinnedKde,
-    read: &ScoredRead,
-) -> Result<()> {
-    let calling_vec = make_scoring_vec(read);
-    let base_num = read.end_1b_excl() - read.start_0b() + 1;
-
-    // Build matrix
-    let mut prob_mat = Vec::new();
-    (0..base_num + 1).for_each(|_| prob_mat.push([0.0; 148]));
+/// Only keep scores where the kmer matches one of the motifs
+fn filter_motifs(read: &mut ScoredRead, motifs: &[Motif]) {
+    read.scores.retain(|s| matches_motifs(s, motifs));
+}
 
-    let mut ptr_mat = Vec::new();
-    (0..base_num + 1).for_each(|_| ptr_mat.push([-1isize; 148]));
+fn matches_motifs(score: &Score, motifs: &[Motif]) -> bool {
+    motifs
+        .iter()
+        .any(|m| m.matches_score_kmer(score.kmer.as_bytes()))
+}
 
-    // Initialisation
-    let initial_rate: f64 = 1. / 148.;
-    let log_initial_rate = initial_rate.ln();
+/// Number of reads from a modification bam file segmented in parallel at once
+const SMA_CHUNK_SIZE: usize = 1024;
 
-    (0..148).for_each(|j| {
-        prob_mat[1][j] = log_initial_rate;
-        ptr_mat[1][j] = 0;
-    });
+/// Shuffle the scores of a read between its scored positions, keeping which
+/// positions have a score. Segmenting the shuffled read gives a null model of
+/// nucleosome calls with the same amount of missing data. The seed is combined
+/// with the read name so each read is shuffled the same way between runs.
+fn shuffle_scores(read: &ScoredRead, seed: u64) -> ScoredRead {
+    let mut hasher = FnvHasher::with_key(seed);
+    hasher.write(read.name().as_bytes());
+    let mut rng = SmallRng::seed_from_u64(hasher.finish());
+    let mut values = read.scores().iter().map(|s| s.score).collect::<Vec<_>>();
+    values.shuffle(&mut rng);
+    let mut shuffled = read.clone();
+    for (score, value) in shuffled.scores.iter_mut().zip(values) {
+        score.score = value;
+    }
+    shuffled
+}
 
-    // Recursion
-    for i in 2..=base_num {
-        let i = i as usize;
-        let within_linker;
-        let mut back_frm_ncls = 0.0;
+/// Writes segmented reads in either output format
+enum SmaWriter {
+    Bed {
+        writer: Box<dyn Write + Send>,
+        colors: StrandColors,
+        order: SmaBlockOrder,
+    },
+    Arrow(Box<ArrowWriter<Box<dyn Write + Send>, SmaRead>>),
+}
 
-        if calling_vec[i] == -1. {
-            within_linker = prob_mat[i - 1][0];
-            if prob_mat[i - 1][147] != 0.0 {
-                back_frm_ncls = prob_mat[i - 1][147];
+impl SmaWriter {
+    /// Bed output starts with a track line for the genome browser
+    fn new(
+        mut writer: Box<dyn Write + Send>,
+        format: SmaFormat,
+        colors: StrandColors,
+        order: SmaBlockOrder,
+        track_name: &str,
+    ) -> Result<Self> {
+        match format {
+            SmaFormat::Bed => {
+                writeln!(
+                    writer,
+                    "track name=\"{track_name}\" itemRgb=\"on\" visibility=2"
+                )?;
+                Ok(SmaWriter::Bed {
+                    writer,
+                    colors,
+                    order,
+                })
             }
-        } else {
-            // let k = (calling_vec[i] * 1000.) as usize;
-            // within_linker = EMISSION_PGC_ARRAY[k].ln() + prob_mat[i - 1][0];
-            within_linker = pos_scores.pmf_from_score(calling_vec[i]).ln() + prob_mat[i - 1][0];
+            SmaFormat::Arrow => Ok(SmaWriter::Arrow(Box::new(SmaRead::wrap_writer(writer)?))),
+        }
+    }
 
-            if prob_mat[i - 1][147] != 0.0 {
-                // back_frm_ncls = EMISSION_PGC_ARRAY[k].ln() + prob_mat[i - 1][147];
-                back_frm_ncls =
-                    pos_scores.pmf_from_score(calling_vec[i]).ln() + prob_mat[i - 1][147];
+    fn write(&mut self, reads: &[SmaRead]) -> Result<()> {
+        match self {
+            SmaWriter::Bed {
+                writer,
+                colors,
+                order,
+            } => {
+                for read in reads {
+                    writeln!(writer, "{}", bed_line(read, colors, *order))?;
+                }
             }
+            SmaWriter::Arrow(writer) => save_t(writer.as_mut(), reads)?,
         }
+        Ok(())
+    }
 
-        if (back_frm_ncls != 0.0) && (back_frm_ncls > within_linker) {
-            prob_mat[i][0] = back_frm_ncls;
-            ptr_mat[i][0] = 147;
-        } else {
-            prob_mat[i][0] = within_linker;
-            ptr_mat[i][0] = 0;
+    fn finish(self) -> Result<()> {
+        match self {
+            SmaWriter::Bed { mut writer, .. } => writer.flush()?,
+            SmaWriter::Arrow(mut writer) => writer.finish()?,
         }
+        Ok(())
+    }
+}
 
-        if calling_vec[i] == -1. {
-            prob_mat[i][1] = prob_mat[i - 1][0];
-        } else {
-            // let k = (calling_vec[i] * 1000.) as usize;
-            // prob_mat[i][1] = EMISSION_NEG_ARRAY[k].ln() + prob_mat[i - 1][0];
-            prob_mat[i][1] = neg_scores.pmf_from_score(calling_vec[i]).ln() + prob_mat[i - 1][0];
-        }
-        ptr_mat[i][1] = 0;
+/// Extra output segmenting reads with only the scores from some motifs, see
+/// [SmaOptions::motif_track]
+struct MotifTrack {
+    name: String,
+    motifs: Vec<Motif>,
+    writer: Box<dyn Write + Send>,
+}
 
-        for j in 2..=147 {
-            if calling_vec[i] == -1. && prob_mat[i - 1][j - 1] != 0.0 {
-                prob_mat[i][j] = prob_mat[i - 1][j - 1];
-            } else {
-                // let k = (calling_vec[i] * 1000.) as usize;
-                if prob_mat[i - 1][j - 1] != 0. {
-                    // prob_mat[i][j] = EMISSION_NEG_ARRAY[k].ln() + prob_mat[i - 1][j - 1];
-                    prob_mat[i][j] =
-                        neg_scores.pmf_from_score(calling_vec[i]).ln() + prob_mat[i - 1][j - 1];
-                }
-            }
+/// Writes the reads of each haplotype to their own output, created the first
+/// time a read from that haplotype is seen, see [SmaOptions::split_haplotypes]
+struct HaplotypeSmaWriters {
+    format: SmaFormat,
+    colors: StrandColors,
+    order: SmaBlockOrder,
+    track_name: String,
+    writers: HaplotypeOutputs<SmaWriter>,
+}
 
-            if prob_mat[i][j] != 0. {
-                ptr_mat[i][j] = (j - 1) as isize;
-            }
+impl HaplotypeSmaWriters {
+    fn write(&mut self, reads: &[SmaRead]) -> Result<()> {
+        for (hp, reads) in group_by_haplotype(reads) {
+            let writer = self.writers.get_or_create(hp, |path| {
+                let writer = Box::new(BufWriter::new(stdout_or_file(Some(&path))?));
+                let track_name = format!("{}_hp{hp}", self.track_name);
+                SmaWriter::new(writer, self.format, self.colors, self.order, &track_name)
+            })?;
+            writer.write(&reads)?;
         }
+        Ok(())
     }
 
-    let mut max = f64::NEG_INFINITY;
-    let mut max_index = -1;
-    for j in 0..148 {
-        if prob_mat[base_num as usize][j] > max {
-            max = prob_mat[base_num as usize][j];
-            max_index = j as isize;
-        }
+    fn finish(self) -> Result<()> {
+        self.writers.finish(SmaWriter::finish)
     }
+}
 
-    let mut backtrack_vec = Vec::new();
-    for i in (1..=base_num).rev() {
-        backtrack_vec.push(max_index);
-        max_index = ptr_mat[i as usize][max_index as usize];
+/// Reads segmented from a chunk, the same reads with shuffled scores if there
+/// is a null model, and the reads segmented for each motif track
+struct SmaChunk {
+    reads: Vec<SmaRead>,
+    null_reads: Vec<SmaRead>,
+    track_reads: Vec<Vec<SmaRead>>,
+    filtered: FilteredReads,
+}
+
+/// Minimum amount of data a read needs to be segmented, reads with only a few
+/// scores produce unreliable segmentations. By default every read is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ReadFilters {
+    min_scores: usize,
+    min_score_fraction: f64,
+    min_read_length: u64,
+}
+
+/// Number of reads removed by each of the [ReadFilters], a read is only
+/// counted by the first filter it fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct FilteredReads {
+    too_short: usize,
+    too_few_scores: usize,
+    low_score_fraction: usize,
+}
+
+impl FilteredReads {
+    fn total(&self) -> usize {
+        self.too_short + self.too_few_scores + self.low_score_fraction
     }
 
-    backtrack_vec.reverse();
-    let mut ncls_start = 0;
-    let mut ncls_end;
-    let shift = read.start_0b() - 1;
-    let mut in_nucleosome = false;
-    let mut nucs = Vec::new();
-    for (i, bt_idx) in backtrack_vec.into_iter().enumerate() {
-        if bt_idx > 0 {
-            if !in_nucleosome {
-                ncls_start = i + 1 + (shift as usize);
-                in_nucleosome = true;
-            }
-        } else if in_nucleosome {
-            ncls_end = i + 1 + (shift as usize);
-            nucs.push((ncls_start, ncls_end));
-            in_nucleosome = false;
-        }
+    fn add(&mut self, other: &FilteredReads) {
+        self.too_short += other.too_short;
+        self.too_few_scores += other.too_few_scores;
+        self.low_score_fraction += other.low_score_fraction;
     }
-    if in_nucleosome {
-        nucs.push((ncls_start, read.end_1b_excl() as usize));
+}
+
+impl ReadFilters {
+    /// Returns false and counts the read if it fails one of the filters, only
+    /// scores matching the motifs are counted
+    fn keep(&self, read: &ScoredRead, motifs: &[Motif], filtered: &mut FilteredReads) -> bool {
+        let n_scores = read
+            .scores()
+            .iter()
+            .filter(|s| matches_motifs(s, motifs))
+            .count();
+        let length = read.np_length();
+        if length < self.min_read_length {
+            filtered.too_short += 1;
+        } else if n_scores < self.min_scores {
+            filtered.too_few_scores += 1;
+        } else if (n_scores as f64) < self.min_score_fraction * length as f64 {
+            filtered.low_score_fraction += 1;
+        } else {
+            return true;
+        }
+        false
     }
+}
 
-    // Add pseudo block at start if read doesn't start with a nucleosome
-    if nucs.is_empty() || nucs[0].0 != read.start_0b() as usize {
-        nucs.insert(0, (read.start_0b() as usize, read.start_0b() as usize + 1));
+fn count_nucleosomes(reads: &[SmaRead]) -> usize {
+    reads.iter().map(|r| r.nucleosomes().count()).sum()
+}
+
+/// Write each chunk of reads in the order they are received, so segmentation
+/// of the next chunk can continue while the last is written. Returns the
+/// number of nucleosomes called on the reads and on the shuffled reads, and
+/// the number of reads that were filtered out.
+fn write_reads(
+    mut writer: SmaWriter,
+    mut null_writer: Option<SmaWriter>,
+    mut track_writers: Vec<SmaWriter>,
+    mut haplotype_writers: Option<HaplotypeSmaWriters>,
+    rx: Receiver<SmaChunk>,
+) -> Result<(usize, usize, FilteredReads)> {
+    let mut n_nucs = 0;
+    let mut n_null_nucs = 0;
+    let mut filtered = FilteredReads::default();
+    for chunk in rx {
+        filtered.add(&chunk.filtered);
+        n_nucs += count_nucleosomes(&chunk.reads);
+        writer.write(&chunk.reads)?;
+        if let Some(haplotype_writers) = haplotype_writers.as_mut() {
+            haplotype_writers.write(&chunk.reads)?;
+        }
+        if let Some(null_writer) = null_writer.as_mut() {
+            n_null_nucs += count_nucleosomes(&chunk.null_reads);
+            null_writer.write(&chunk.null_reads)?;
+        }
+        for (track_writer, reads) in track_writers.iter_mut().zip(chunk.track_reads) {
+            track_writer.write(&reads)?;
+        }
+    }
+    writer.finish()?;
+    if let Some(null_writer) = null_writer {
+        null_writer.finish()?;
+    }
+    for track_writer in track_writers {
+        track_writer.finish()?;
     }
+    if let Some(haplotype_writers) = haplotype_writers {
+        haplotype_writers.finish()?;
+    }
+    Ok((n_nucs, n_null_nucs, filtered))
+}
 
-    // Add pseduo block at end if read doesn't end with a nucleosome
-    let bend = nucs.last().map(|&(_, b)| b).unwrap();
-    if bend != read.end_1b_excl() as usize {
-        nucs.push((read.end_1b_excl() as usize - 1, read.end_1b_excl() as usize))
-    }
-
-    let n_nucs = nucs.len();
-    let (starts, blks): (Vec<_>, Vec<_>) = nucs
-        .into_iter()
-        .map(|(s, e)| (s - read.start_0b() as usize, (e - s)))
-        .unzip();
-    let mut w = writer.lock().unwrap();
-    writeln!(
-        w,
-        "{}\t{}\t{}\t{}\t0\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-        read.chrom(),
-        read.start_0b(),
-        read.end_1b_excl(),
-        read.name(),
-        read.strand(),
-        read.start_0b(),
-        read.end_1b_excl(),
-        read.strand().rgb_str(),
-        n_nucs,
-        blks.into_iter().join(","),
-        starts.into_iter().join(","),
-    )?;
-    Ok(())
+/// Positive and negative control score distributions, optionally with separate
+/// pairs for reads on each strand
+struct Emissions {
+    pos: BinnedKde,
+    neg: BinnedKde,
+    plus: Option<(BinnedKde, BinnedKde)>,
+    minus: Option<(BinnedKde, BinnedKde)>,
+}
+
+impl Emissions {
+    /// Pair for the strand of a read, falling back to the pair for every
+    /// strand if there is none or the strand is unknown
+    fn for_strand(&self, strand: Strand) -> (&BinnedKde, &BinnedKde) {
+        let pair = if strand.is_unknown_strand() {
+            None
+        } else if strand.is_minus_strand() {
+            self.minus.as_ref()
+        } else {
+            self.plus.as_ref()
+        };
+        pair.map_or((&self.pos, &self.neg), |(pos, neg)| (pos, neg))
+    }
 }
 
 /// Loads and stores data used for single molecule analysis.
 pub struct SmaOptions {
     track_name: Option<String>,
-    pos_bkde: BinnedKde,
-    neg_bkde: BinnedKde,
+    emissions: Emissions,
     motifs: Vec<Motif>,
     writer: Box<dyn Write + Send>,
+    strand_colors: StrandColors,
+    format: SmaFormat,
+    block_order: SmaBlockOrder,
+    null_model: Option<(Box<dyn Write + Send>, u64)>,
+    motif_tracks: Vec<MotifTrack>,
+    haplotype_output: Option<PathBuf>,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
+    read_filters: ReadFilters,
 }
 
 impl SmaOptions {
@@ -378,10 +649,22 @@ impl SmaOptions {
     ) -> Self {
         Self {
             track_name: None,
-            pos_bkde,
-            neg_bkde,
+            emissions: Emissions {
+                pos: pos_bkde,
+                neg: neg_bkde,
+                plus: None,
+                minus: None,
+            },
             motifs,
             writer,
+            strand_colors: StrandColors::default(),
+            format: SmaFormat::default(),
+            block_order: SmaBlockOrder::default(),
+            null_model: None,
+            motif_tracks: Vec::new(),
+            haplotype_output: None,
+            progress_sink: None,
+            read_filters: ReadFilters::default(),
         }
     }
 
@@ -393,9 +676,13 @@ impl SmaOptions {
     ) -> Result<Self> {
         let pos_bkde = BinnedKde::load(pos_scores_path)?;
         let neg_bkde = BinnedKde::load(neg_scores_path)?;
-        let writer = BufWriter::new(File::create(output)?);
-        let writer = Box::new(writer);
-        Ok(SmaOptions::new(pos_bkde, neg_bkde, motifs, writer))
+        let writer = BufWriter::new(stdout_or_file(Some(&output))?);
+        Ok(SmaOptions::new(
+            pos_bkde,
+            neg_bkde,
+            motifs,
+            Box::new(writer),
+        ))
     }
 
     /// Set bed file track name to track during in the genome browser
@@ -404,51 +691,733 @@ impl SmaOptions {
         self
     }
 
-    pub fn run_modfile(mut self, mod_file: ModFile) -> Result<()> {
-    //     todo!()
-    // }
+    /// Score distributions of the positive and negative controls used instead
+    /// for reads on this strand, ie from cawlr model-scores --split-strand.
+    /// Reads on a strand without a pair, or with an unknown strand, use the
+    /// pair given to [SmaOptions::new].
+    pub fn strand_bkdes(
+        &mut self,
+        strand: Strand,
+        pos_bkde: BinnedKde,
+        neg_bkde: BinnedKde,
+    ) -> &mut Self {
+        if strand.is_unknown_strand() {
+            log::warn!("Score distributions for an unknown strand are ignored");
+        } else if strand.is_minus_strand() {
+            self.emissions.minus = Some((pos_bkde, neg_bkde));
+        } else {
+            self.emissions.plus = Some((pos_bkde, neg_bkde));
+        }
+        self
+    }
+
+    /// Load the score distributions of each strand written by cawlr
+    /// model-scores --split-strand next to these paths, see
+    /// [strand_output_path]
+    pub fn load_strand_bkdes<P: AsRef<Path>>(
+        &mut self,
+        pos_scores_path: P,
+        neg_scores_path: P,
+    ) -> Result<&mut Self> {
+        for strand in [Strand::plus(), Strand::minus()] {
+            let load = |path: &P| {
+                let path = strand_output_path(path, strand);
+                BinnedKde::load(&path).wrap_err_with(|| {
+                    format!(
+                        "Failed to load {}, run cawlr model-scores with --split-strand",
+                        path.display()
+                    )
+                })
+            };
+            let (pos_bkde, neg_bkde) = (load(&pos_scores_path)?, load(&neg_scores_path)?);
+            self.strand_bkdes(strand, pos_bkde, neg_bkde);
+        }
+        Ok(self)
+    }
+
+    /// Colors for reads on each strand, defaults to
+    /// [StrandColors::colorblind]
+    pub fn strand_colors(&mut self, strand_colors: StrandColors) -> &mut Self {
+        self.strand_colors = strand_colors;
+        self
+    }
+
+    /// Output file format, defaults to [SmaFormat::Bed]
+    pub fn format(&mut self, format: SmaFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Order of the blocks of minus strand reads in bed output, defaults to
+    /// [SmaBlockOrder::Genome]. Arrow output always has blocks in genomic
+    /// order.
+    pub fn block_order(&mut self, block_order: SmaBlockOrder) -> &mut Self {
+        self.block_order = block_order;
+        self
+    }
+
+    /// Also segment each read with its scores shuffled, see [shuffle_scores],
+    /// and write the results to null_writer in the same format. Comparing the
+    /// number of nucleosomes called on shuffled reads to real reads gives an
+    /// empirical estimate of the false discovery rate.
+    pub fn null_model(&mut self, null_writer: Box<dyn Write + Send>, seed: u64) -> &mut Self {
+        self.null_model = Some((null_writer, seed));
+        self
+    }
+
+    /// Also segment each read with only the scores matching these motifs, and
+    /// write the results to writer in the same format. Each read is loaded
+    /// once for every track, so one run can produce ie GpC accessibility and
+    /// CpG methylation tracks from the same scored file.
+    pub fn motif_track<S: Into<String>>(
+        &mut self,
+        name: S,
+        motifs: Vec<Motif>,
+        writer: Box<dyn Write + Send>,
+    ) -> &mut Self {
+        self.motif_tracks.push(MotifTrack {
+            name: name.into(),
+            motifs,
+            writer,
+        });
+        self
+    }
+
+    /// Also write the reads of each haplotype to their own output in the same
+    /// format, named after output, ie sma.hp1.bed and sma.hp2.bed for sma.bed.
+    /// Haplotypes come from the HP tags of a phased bam file given to cawlr
+    /// collapse, reads without one are only in the main output.
+    pub fn split_haplotypes<P: AsRef<Path>>(&mut self, output: P) -> &mut Self {
+        self.haplotype_output = Some(output.as_ref().to_path_buf());
+        self
+    }
+
+    /// Skip reads with fewer than this many scores, counted after scores that
+    /// don't match the motifs are removed
+    pub fn min_scores(&mut self, min_scores: usize) -> &mut Self {
+        self.read_filters.min_scores = min_scores;
+        self
+    }
+
+    /// Skip reads where fewer than this fraction of positions have a score, ie
+    /// 0.1 for at least one score every 10 bases
+    pub fn min_score_fraction(&mut self, min_score_fraction: f64) -> &mut Self {
+        self.read_filters.min_score_fraction = min_score_fraction;
+        self
+    }
+
+    /// Skip reads aligned to fewer than this many bases
+    pub fn min_read_length(&mut self, min_read_length: u64) -> &mut Self {
+        self.read_filters.min_read_length = min_read_length;
+        self
+    }
+
+    /// Receive progress updates as reads are processed
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    /// Segment reads in parallel on the current rayon thread pool, keeping
+    /// the input order
+    fn segment_chunk(&self, reads: Vec<ScoredRead>) -> SmaChunk {
+        let SmaOptions {
+            emissions,
+            motifs,
+            read_filters,
+            ..
+        } = self;
+        let null_seed = self.null_model.as_ref().map(|(_, seed)| *seed);
+        let track_motifs = self
+            .motif_tracks
+            .iter()
+            .map(|track| track.motifs.as_slice())
+            .collect::<Vec<_>>();
+        let mut filtered = FilteredReads::default();
+        let reads = reads
+            .into_iter()
+            .filter(|read| {
+                let keep = read_filters.keep(read, motifs, &mut filtered);
+                if !keep {
+                    log::debug!("Read {} filtered out, skipping...", read.name());
+                }
+                keep
+            })
+            .collect::<Vec<_>>();
+        let segmented: Vec<(SmaRead, Option<SmaRead>, Vec<SmaRead>)> = reads
+            .into_par_iter()
+            .map(|mut read| {
+                log::info!("{:?}", read.metadata());
+                let (pos_bkde, neg_bkde) = emissions.for_strand(read.strand());
+                let tracks = track_motifs
+                    .iter()
+                    .map(|track_motifs| {
+                        let mut track_read = read.clone();
+                        filter_motifs(&mut track_read, track_motifs);
+                        sma2(&track_read, pos_bkde, neg_bkde)
+                    })
+                    .collect();
+                filter_motifs(&mut read, motifs);
+                let null =
+                    null_seed.map(|seed| sma2(&shuffle_scores(&read, seed), pos_bkde, neg_bkde));
+                (sma2(&read, pos_bkde, neg_bkde), null, tracks)
+            })
+            .collect();
+        let mut chunk = SmaChunk {
+            reads: Vec::with_capacity(segmented.len()),
+            null_reads: Vec::new(),
+            track_reads: vec![Vec::with_capacity(segmented.len()); track_motifs.len()],
+            filtered,
+        };
+        for (read, null, tracks) in segmented {
+            chunk.reads.push(read);
+            chunk.null_reads.extend(null);
+            for (track_reads, track_read) in chunk.track_reads.iter_mut().zip(tracks) {
+                track_reads.push(track_read);
+            }
+        }
+        chunk
+    }
+
+    /// Run f with a sender for chunks of reads that are written on a separate
+    /// thread.
+    fn with_writer<F>(mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&Self, &SyncSender<SmaChunk>) -> Result<()>,
+    {
         let track_name = self
             .track_name
             .clone()
             .unwrap_or_else(|| "cawlr_sma".to_string());
-        writeln!(
-            &mut self.writer,
-            "track name=\"{track_name}\" itemRgb=\"on\" visibility=2"
-        )?;
-
-        let writer = Mutex::new(self.writer);
-        read_mod_bam_or_arrow(mod_file, |read| {
-            if !read.is_unaligned() {
-                log::info!("{:?}", read.metadata());
-                sma(&writer, &self.pos_bkde, &self.neg_bkde, &read)?;
-            } else {
-                log::debug!("Read {} is unaligned, skipping...", read.name())
+        let (format, colors, order) = (self.format, self.strand_colors, self.block_order);
+        if format == SmaFormat::Arrow && order != SmaBlockOrder::Genome {
+            eyre::bail!("Blocks are only written in read order in bed output");
+        }
+        let writer = std::mem::replace(&mut self.writer, Box::new(io::sink()));
+        let writer = SmaWriter::new(writer, format, colors, order, &track_name)?;
+        let null_writer = match self.null_model.as_mut() {
+            Some((null_writer, seed)) => {
+                repro::record_seed("sma null model", *seed, serde_json::json!({}));
+                let null_writer = std::mem::replace(null_writer, Box::new(io::sink()));
+                let null_track_name = format!("{track_name}_null");
+                Some(SmaWriter::new(
+                    null_writer,
+                    format,
+                    colors,
+                    order,
+                    &null_track_name,
+                )?)
+            }
+            None => None,
+        };
+        let track_writers = self
+            .motif_tracks
+            .iter_mut()
+            .map(|track| {
+                let writer = std::mem::replace(&mut track.writer, Box::new(io::sink()));
+                SmaWriter::new(writer, format, colors, order, &track.name)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let haplotype_writers = self
+            .haplotype_output
+            .clone()
+            .map(|output| HaplotypeSmaWriters {
+                format,
+                colors,
+                order,
+                track_name: track_name.clone(),
+                writers: HaplotypeOutputs::new(output),
+            });
+        let (tx, rx) = sync_channel(2);
+        let handle = thread::spawn(move || {
+            write_reads(writer, null_writer, track_writers, haplotype_writers, rx)
+        });
+        let res = f(&self, &tx);
+        drop(tx);
+        let (n_nucs, n_null_nucs, filtered) = handle
+            .join()
+            .map_err(|_| eyre::eyre!("sma writer thread panicked"))??;
+        if self.null_model.is_some() {
+            log::info!(
+                "{n_nucs} nucleosomes called on reads, {n_null_nucs} on shuffled reads, \
+                 estimated FDR {:.4}",
+                n_null_nucs as f64 / n_nucs.max(1) as f64
+            );
+        }
+        if self.read_filters != ReadFilters::default() {
+            log::info!(
+                "Filtered out {} reads, {} too short, {} with too few scores, {} with too \
+                 low a fraction of positions scored",
+                filtered.total(),
+                filtered.too_short,
+                filtered.too_few_scores,
+                filtered.low_score_fraction
+            );
+        }
+        res
+    }
+
+    pub fn run_modfile(self, mod_file: ModFile) -> Result<()> {
+        self.with_writer(|sma, tx| {
+            let mut reporter = Reporter::new(Stage::Sma, sma.progress_sink.clone());
+            let mut chunk = Vec::with_capacity(SMA_CHUNK_SIZE);
+            let mut send_chunk = |chunk: Vec<ScoredRead>| -> Result<()> {
+                cancel::check()?;
+                let n_reads = chunk.len();
+                tx.send(sma.segment_chunk(chunk))
+                    .map_err(|_| eyre::eyre!("sma writer thread stopped"))?;
+                reporter.chunk(n_reads);
+                Ok(())
+            };
+            read_mod_bam_or_arrow(mod_file, |read| {
+                if read.is_unaligned() {
+                    log::debug!("Read {} is unaligned, skipping...", read.name());
+                    return Ok(());
+                }
+                chunk.push(read);
+                if chunk.len() >= SMA_CHUNK_SIZE {
+                    send_chunk(std::mem::take(&mut chunk))?;
+                }
+                Ok(())
+            })?;
+            if !chunk.is_empty() {
+                send_chunk(chunk)?;
             }
+            reporter.finish();
             Ok(())
         })
     }
 
-    pub fn run<P>(mut self, scores_filepath: P) -> Result<()>
+    pub fn run<P>(self, scores_filepath: P) -> Result<()>
     where
         P: AsRef<Path>,
     {
-        let track_name = self
-            .track_name
-            .clone()
-            .unwrap_or_else(|| "cawlr_sma".to_string());
-        writeln!(
-            self.writer,
-            "track name=\"{track_name}\" itemRgb=\"on\" visibility=2"
-        )?;
-
-        let writer = Mutex::new(self.writer);
-        let scores_file = File::open(scores_filepath)?;
-        load_apply(scores_file, |reads: Vec<ScoredRead>| {
-            reads.into_par_iter().try_for_each(|read| {
-                log::info!("{:?}", read.metadata());
-                let output = sma2(&read, &self.pos_bkde, &self.neg_bkde);
-                output.write(&writer, &read)
-            })
+        let mut scores_file = File::open(scores_filepath)?;
+        self.with_writer(|sma, tx| {
+            let mut reporter = Reporter::new(Stage::Sma, sma.progress_sink.clone());
+            reporter.total_chunks(n_chunks(&mut scores_file)?);
+            load_apply(scores_file, |reads: Vec<ScoredRead>| {
+                cancel::check()?;
+                let n_reads = reads.len();
+                tx.send(sma.segment_chunk(reads))
+                    .map_err(|_| eyre::eyre!("sma writer thread stopped"))?;
+                reporter.chunk(n_reads);
+                Ok(())
+            })?;
+            reporter.finish();
+            Ok(())
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+    use criterion_stats::univariate::{
+        kde::{kernel::Gaussian, Bandwidth, Kde},
+        Sample,
+    };
+
+    use super::*;
+    use crate::{
+        arrow::arrow_utils::load_apply,
+        haplotype::haplotype_path,
+        test_data::{test_score, write_reads, TestRead},
+    };
+
+    fn uniform_bkde() -> BinnedKde {
+        let samples = (0..100).map(|x| x as f64 / 100.).collect::<Vec<_>>();
+        let kde = Kde::new(Sample::new(&samples), Gaussian, Bandwidth::Silverman);
+        BinnedKde::from_kde(1000, &kde)
+    }
+
+    fn scored_reads(n_reads: u64) -> Vec<ScoredRead> {
+        (0..n_reads)
+            .map(|i| {
+                let scores = (100 + i..150 + i)
+                    .step_by(5)
+                    .map(|pos| test_score(pos, 0.9))
+                    .collect();
+                TestRead::new(format!("read{i}"))
+                    .span(100 + i, 50)
+                    .scored(scores)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strand_bkdes() {
+        let mut sma = SmaOptions::new(
+            uniform_bkde(),
+            uniform_bkde(),
+            crate::motif::all_bases(),
+            Box::new(io::sink()),
+        );
+        sma.strand_bkdes(Strand::minus(), uniform_bkde(), uniform_bkde());
+        let emissions = &sma.emissions;
+        let (minus_pos, _) = emissions.minus.as_ref().unwrap();
+        assert!(std::ptr::eq(
+            emissions.for_strand(Strand::minus()).0,
+            minus_pos
+        ));
+        // Strands without their own pair use the pair for every strand
+        assert!(std::ptr::eq(
+            emissions.for_strand(Strand::plus()).0,
+            &emissions.pos
+        ));
+        assert!(std::ptr::eq(
+            emissions.for_strand(Strand::unknown()).0,
+            &emissions.pos
+        ));
+    }
+
+    #[test]
+    fn test_sma_output_order() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let reads = scored_reads(SMA_CHUNK_SIZE as u64 + 10);
+        write_reads(&scores_path, &reads)?;
+
+        let output = temp_dir.path().join("sma.bed");
+        let sma = SmaOptions::new(
+            uniform_bkde(),
+            uniform_bkde(),
+            crate::motif::all_bases(),
+            Box::new(File::create(&output)?),
+        );
+        sma.run_modfile(ModFile::open_arrow(&scores_path)?)?;
+
+        let bed = std::fs::read_to_string(output)?;
+        let mut lines = bed.lines();
+        assert!(lines.next().unwrap().starts_with("track"));
+        let names = lines
+            .map(|l| l.split('\t').nth(3).unwrap().to_string())
+            .collect::<Vec<_>>();
+        let expected = reads
+            .iter()
+            .map(|r| r.name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sma_arrow_output() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let reads = scored_reads(5);
+        write_reads(&scores_path, &reads)?;
+
+        let output = temp_dir.path().join("sma.arrow");
+        let mut sma = SmaOptions::new(
+            uniform_bkde(),
+            uniform_bkde(),
+            crate::motif::all_bases(),
+            Box::new(File::create(&output)?),
+        );
+        sma.format(SmaFormat::Arrow);
+        sma.run(&scores_path)?;
+
+        let mut sma_reads = Vec::new();
+        load_apply(File::open(output)?, |mut chunk: Vec<SmaRead>| {
+            sma_reads.append(&mut chunk);
+            Ok(())
+        })?;
+        assert_eq!(sma_reads.len(), reads.len());
+        for (sma_read, read) in sma_reads.iter().zip(reads.iter()) {
+            assert_eq!(sma_read.metadata, read.metadata);
+            let blocks = sma_read.blocks();
+            assert_eq!(blocks[0].start, read.start_0b());
+            assert_eq!(blocks.last().unwrap().end(), read.end_1b_excl());
+            assert!(blocks.windows(2).all(|w| w[0].end() == w[1].start));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_shuffle_scores() {
+        let mut read = scored_reads(1).pop().unwrap();
+        for (i, score) in read.scores.iter_mut().enumerate() {
+            score.score = i as f64 / 10.;
+        }
+        let shuffled = shuffle_scores(&read, 2456);
+        assert_eq!(shuffled.metadata, read.metadata);
+        let positions = |r: &ScoredRead| r.scores().iter().map(|s| s.pos).collect::<Vec<_>>();
+        let values = |r: &ScoredRead| r.scores().iter().map(|s| s.score).collect::<Vec<_>>();
+        assert_eq!(positions(&shuffled), positions(&read));
+        assert_ne!(values(&shuffled), values(&read));
+        let mut sorted = values(&shuffled);
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(sorted, values(&read));
+        assert_eq!(values(&shuffle_scores(&read, 2456)), values(&shuffled));
+    }
+
+    #[test]
+    fn test_sma_null_output() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let reads = scored_reads(5);
+        write_reads(&scores_path, &reads)?;
+
+        let run = |output: &Path, null_output: &Path| -> Result<()> {
+            let mut sma = SmaOptions::new(
+                uniform_bkde(),
+                uniform_bkde(),
+                crate::motif::all_bases(),
+                Box::new(File::create(output)?),
+            );
+            sma.track_name("sma")
+                .null_model(Box::new(File::create(null_output)?), 2456);
+            sma.run(&scores_path)
+        };
+        let output = temp_dir.path().join("sma.bed");
+        let null_output = temp_dir.path().join("null.bed");
+        run(&output, &null_output)?;
+
+        let null_bed = std::fs::read_to_string(&null_output)?;
+        let mut lines = null_bed.lines();
+        assert!(lines.next().unwrap().contains("name=\"sma_null\""));
+        let names = lines
+            .map(|l| l.split('\t').nth(3).unwrap().to_string())
+            .collect::<Vec<_>>();
+        let expected = reads
+            .iter()
+            .map(|r| r.name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+
+        let rerun_null_output = temp_dir.path().join("null2.bed");
+        run(&temp_dir.path().join("sma2.bed"), &rerun_null_output)?;
+        assert_eq!(std::fs::read_to_string(rerun_null_output)?, null_bed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sma_motif_track() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let mut reads = scored_reads(5);
+        for read in reads.iter_mut() {
+            for (i, score) in read.scores.iter_mut().enumerate() {
+                let (kmer, value) = if i % 2 == 0 {
+                    ("GCAAAA", 0.1)
+                } else {
+                    ("CGAAAA", 0.9)
+                };
+                score.kmer = kmer.parse().unwrap();
+                score.score = value;
+            }
+        }
+        write_reads(&scores_path, &reads)?;
+
+        let gc = vec![Motif::new("GC", 2)];
+        let sma_with = |motifs: Vec<Motif>, output: &Path| -> Result<SmaOptions> {
+            let mut sma = SmaOptions::new(
+                uniform_bkde(),
+                uniform_bkde(),
+                motifs,
+                Box::new(File::create(output)?),
+            );
+            sma.track_name("gc");
+            Ok(sma)
+        };
+        let track_output = temp_dir.path().join("gc_track.bed");
+        let mut sma = sma_with(crate::motif::all_bases(), &temp_dir.path().join("all.bed"))?;
+        sma.motif_track("gc", gc.clone(), Box::new(File::create(&track_output)?));
+        sma.run(&scores_path)?;
+
+        let gc_output = temp_dir.path().join("gc.bed");
+        sma_with(gc, &gc_output)?.run(&scores_path)?;
+        let track_bed = std::fs::read_to_string(track_output)?;
+        assert_eq!(track_bed.lines().count(), reads.len() + 1);
+        assert_eq!(track_bed, std::fs::read_to_string(gc_output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sma_split_haplotypes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let mut reads = scored_reads(5);
+        for (i, read) in reads.iter_mut().enumerate() {
+            read.metadata.haplotype = [Some(1), Some(2), None][i % 3];
+        }
+        write_reads(&scores_path, &reads)?;
+
+        let output = temp_dir.path().join("sma.bed");
+        let mut sma = SmaOptions::new(
+            uniform_bkde(),
+            uniform_bkde(),
+            crate::motif::all_bases(),
+            Box::new(File::create(&output)?),
+        );
+        sma.split_haplotypes(&output);
+        sma.run(&scores_path)?;
+
+        let read_names = |hp: i64| -> Result<Vec<String>> {
+            let bed = std::fs::read_to_string(haplotype_path(&output, hp))?;
+            assert!(bed.starts_with(&format!("track name=\"cawlr_sma_hp{hp}\"")));
+            Ok(bed
+                .lines()
+                .skip(1)
+                .map(|line| line.split('\t').nth(3).unwrap().to_string())
+                .collect())
+        };
+        assert_eq!(read_names(1)?, ["read0", "read3"]);
+        assert_eq!(read_names(2)?, ["read1", "read4"]);
+        assert_eq!(std::fs::read_to_string(&output)?.lines().count(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_filters() {
+        // Each read is 50 bases long with 10 scores
+        let read = scored_reads(1).pop().unwrap();
+        let motifs = crate::motif::all_bases();
+        let keep = |filters: ReadFilters| {
+            let mut filtered = FilteredReads::default();
+            let keep = filters.keep(&read, &motifs, &mut filtered);
+            (keep, filtered)
+        };
+        let (kept, filtered) = keep(ReadFilters::default());
+        assert!(kept);
+        assert_eq!(filtered.total(), 0);
+
+        let filters = ReadFilters {
+            min_scores: 10,
+            min_score_fraction: 0.2,
+            min_read_length: 50,
+        };
+        assert!(keep(filters).0);
+
+        let (kept, filtered) = keep(ReadFilters {
+            min_read_length: 51,
+            ..filters
+        });
+        assert!(!kept);
+        assert_eq!(filtered.too_short, 1);
+
+        let (kept, filtered) = keep(ReadFilters {
+            min_scores: 11,
+            ..filters
+        });
+        assert!(!kept);
+        assert_eq!(filtered.too_few_scores, 1);
+
+        let (kept, filtered) = keep(ReadFilters {
+            min_score_fraction: 0.25,
+            ..filters
+        });
+        assert!(!kept);
+        assert_eq!(filtered.low_score_fraction, 1);
+
+        // Only scores matching the motifs are counted
+        let gc = vec![Motif::new("GC", 2)];
+        let mut filtered = FilteredReads::default();
+        assert!(!ReadFilters {
+            min_scores: 1,
+            ..ReadFilters::default()
+        }
+        .keep(&read, &gc, &mut filtered));
+    }
+
+    #[test]
+    fn test_sma_read_filters() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let scores_path = temp_dir.path().join("scores.arrow");
+        let mut reads = scored_reads(4);
+        reads[1].scores.truncate(2);
+        write_reads(&scores_path, &reads)?;
+
+        let output = temp_dir.path().join("sma.bed");
+        let mut sma = SmaOptions::new(
+            uniform_bkde(),
+            uniform_bkde(),
+            crate::motif::all_bases(),
+            Box::new(File::create(&output)?),
+        );
+        sma.min_scores(5);
+        sma.run(&scores_path)?;
+
+        let bed = std::fs::read_to_string(output)?;
+        let names = bed
+            .lines()
+            .skip(1)
+            .map(|l| l.split('\t').nth(3).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["read0", "read2", "read3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bed_line_pseudo_blocks() {
+        let read = TestRead::new("read").span(100, 400).scored(Vec::new());
+        let blocks = to_blocks(&read, vec![(150, 297)]);
+        assert_eq!(
+            blocks,
+            vec![
+                SmaBlock::new(100, 50, BlockState::linker()),
+                SmaBlock::new(150, 147, BlockState::nucleosome()),
+                SmaBlock::new(297, 203, BlockState::linker()),
+            ]
+        );
+        let sma_read = SmaRead::new(read.metadata.clone(), blocks);
+        let line = bed_line(&sma_read, &StrandColors::classic(), SmaBlockOrder::Genome);
+        let fields = line.split('\t').collect::<Vec<_>>();
+        assert_eq!(&fields[9..], ["3", "1,147,1", "0,50,399"]);
+    }
+
+    #[test]
+    fn test_bed_line_block_order() {
+        let bed_fields = |strand: Strand, order: SmaBlockOrder| {
+            let read = TestRead::new("read")
+                .span(100, 400)
+                .strand(strand)
+                .scored(Vec::new());
+            let blocks = to_blocks(&read, vec![(100, 247), (300, 447)]);
+            let sma_read = SmaRead::new(read.metadata.clone(), blocks);
+            let line = bed_line(&sma_read, &StrandColors::classic(), order);
+            line.split('\t')
+                .skip(9)
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let genome = ["3", "147,147,1", "0,200,399"];
+        assert_eq!(bed_fields(Strand::minus(), SmaBlockOrder::Genome), genome);
+        assert_eq!(bed_fields(Strand::plus(), SmaBlockOrder::Read), genome);
+        assert_eq!(
+            bed_fields(Strand::minus(), SmaBlockOrder::Read),
+            ["3", "1,147,147", "0,53,253"]
+        );
+    }
+
+    #[test]
+    fn test_sma_format_from_str() {
+        assert_eq!("bed".parse::<SmaFormat>(), Ok(SmaFormat::Bed));
+        assert_eq!("arrow".parse::<SmaFormat>(), Ok(SmaFormat::Arrow));
+        assert!("bigbed".parse::<SmaFormat>().is_err());
+    }
+
+    #[test]
+    fn test_rgb_from_str() {
+        assert_eq!("230,159,0".parse::<Rgb>(), Ok(Rgb(230, 159, 0)));
+        assert_eq!("0, 114, 178".parse::<Rgb>(), Ok(Rgb(0, 114, 178)));
+        assert!("255,0".parse::<Rgb>().is_err());
+        assert!("256,0,0".parse::<Rgb>().is_err());
+        assert_eq!(Rgb(255, 0, 0).to_string(), "255,0,0");
+    }
+
+    #[test]
+    fn test_strand_colors() {
+        let colors = StrandColors::classic();
+        assert_eq!(
+            colors.color(Strand::plus()).to_string(),
+            Strand::plus().rgb_str()
+        );
+        assert_eq!(
+            colors.color(Strand::minus()).to_string(),
+            Strand::minus().rgb_str()
+        );
+        assert_eq!(colors.color(Strand::unknown()), Rgb(0, 0, 0));
+    }
+}
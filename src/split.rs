@@ -0,0 +1,617 @@
+//! Shard Arrow files from cawlr collapse, score, or sma into smaller files, ie
+//! to score each shard in its own job of an HPC job array, and concatenate the
+//! outputs of each job back into one file with [CatOptions].
+//!
+//! Shards are written to a directory with a manifest listing the reads and
+//! chromosomes in each shard, see [SplitManifest].
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::File,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use arrow2::{
+    datatypes::{Field, Schema},
+    io::ipc::write::FileWriter,
+};
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arrow::{
+        arrow_utils::{
+            self, dispatch_arrow_kind, eventalign_schema, file_metadata, has_samples, load,
+            load_apply, read_mode, save, ArrowCompression, ArrowKind, ArrowKindFn, SchemaExt,
+        },
+        metadata::{MetadataExt, MetadataMutExt},
+    },
+    hash::Encode,
+    utils::{create_output, stdout_or_file},
+};
+
+/// File name of the manifest written next to the shards
+pub const SPLIT_MANIFEST: &str = "manifest.json";
+
+/// How reads are divided between shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitBy {
+    /// Consecutive reads, with about the same number of reads in each shard
+    Reads,
+    /// Whole chromosomes, balancing the number of reads in each shard. Gives
+    /// at most one shard per chromosome.
+    Chrom,
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reads" => Ok(SplitBy::Reads),
+            "chrom" => Ok(SplitBy::Chrom),
+            _ => Err(format!("Invalid split {s}: either 'reads' or 'chrom'")),
+        }
+    }
+}
+
+impl fmt::Display for SplitBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitBy::Reads => write!(f, "reads"),
+            SplitBy::Chrom => write!(f, "chrom"),
+        }
+    }
+}
+
+/// One shard of a split, with its path relative to the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub path: PathBuf,
+    pub reads: usize,
+    pub chroms: BTreeSet<String>,
+}
+
+impl Shard {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            reads: 0,
+            chroms: BTreeSet::new(),
+        }
+    }
+
+    fn add(&mut self, chrom: &str) {
+        self.reads += 1;
+        if !self.chroms.contains(chrom) {
+            self.chroms.insert(chrom.to_string());
+        }
+    }
+}
+
+/// Shards written by [SplitOptions::run], saved as [SPLIT_MANIFEST] in the
+/// output directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitManifest {
+    pub input: PathBuf,
+    pub by: SplitBy,
+    pub shards: Vec<Shard>,
+}
+
+impl SplitManifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .wrap_err_with(|| format!("Invalid split manifest {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let writer = create_output(path)?;
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// Split an Arrow file from cawlr collapse, score, or sma into shards
+pub struct SplitOptions {
+    n_shards: usize,
+    by: SplitBy,
+    compression: ArrowCompression,
+}
+
+impl SplitOptions {
+    pub fn new(n_shards: usize) -> Self {
+        Self {
+            n_shards,
+            by: SplitBy::Reads,
+            compression: ArrowCompression::default(),
+        }
+    }
+
+    /// How reads are divided between shards, by consecutive reads by default
+    pub fn by(&mut self, by: SplitBy) -> &mut Self {
+        self.by = by;
+        self
+    }
+
+    /// Codec for the shards, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write shards to output_dir as shard_000.arrow, shard_001.arrow, ...
+    /// along with the manifest. There are fewer shards than requested if the
+    /// input has fewer reads, or fewer chromosomes when splitting by
+    /// chromosome.
+    pub fn run<P, Q>(&self, input: P, output_dir: Q) -> Result<SplitManifest>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        if self.n_shards == 0 {
+            eyre::bail!("Number of shards must be at least 1");
+        }
+        let input = input.as_ref();
+        let output_dir = output_dir.as_ref();
+        std::fs::create_dir_all(output_dir)
+            .wrap_err_with(|| format!("Failed to create {}", output_dir.display()))?;
+        let split = SplitShards {
+            split: self,
+            input,
+            output_dir,
+        };
+        let shards = dispatch_arrow_kind(ArrowKind::of(input)?, split)?;
+        let manifest = SplitManifest {
+            input: input.to_path_buf(),
+            by: self.by,
+            shards,
+        };
+        manifest.save(output_dir.join(SPLIT_MANIFEST))?;
+        log::info!(
+            "Split {} into {} shards in {}",
+            input.display(),
+            manifest.shards.len(),
+            output_dir.display()
+        );
+        Ok(manifest)
+    }
+
+    fn split<T>(&self, input: &Path, output_dir: &Path) -> Result<Vec<Shard>>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let schema = input_schema::<T>(input)?;
+        let open = |shard: &Shard| -> Result<FileWriter<File>> {
+            let file = create_output(output_dir.join(&shard.path))?;
+            arrow_utils::wrap_writer_with(file, &schema, self.compression)
+        };
+        let reader = || -> Result<BufReader<File>> { Ok(BufReader::new(File::open(input)?)) };
+        match self.by {
+            SplitBy::Reads => {
+                let total = count_reads(reader()?)?;
+                let n_shards = self.n_shards.min(total).max(1);
+                let mut shards = shard_names(n_shards);
+                let mut current = 0;
+                let mut writer = open(&shards[current])?;
+                let mut seen = 0;
+                load_apply(reader()?, |reads: Vec<T>| {
+                    let mut batch = Vec::new();
+                    for read in reads {
+                        let idx = seen * n_shards / total;
+                        seen += 1;
+                        // Shards are written one after another, so only one
+                        // is open at a time
+                        if idx != current {
+                            save(&mut writer, &batch)?;
+                            batch.clear();
+                            writer.finish()?;
+                            current = idx;
+                            writer = open(&shards[current])?;
+                        }
+                        shards[idx].add(read.chrom());
+                        batch.push(read);
+                    }
+                    save(&mut writer, &batch)
+                })?;
+                writer.finish()?;
+                Ok(shards)
+            }
+            SplitBy::Chrom => {
+                let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+                load_apply(reader()?, |reads: Vec<T>| {
+                    for read in reads.iter() {
+                        *counts.entry(read.chrom().to_string()).or_default() += 1;
+                    }
+                    Ok(())
+                })?;
+                let n_shards = self.n_shards.min(counts.len()).max(1);
+                if n_shards < self.n_shards {
+                    log::warn!(
+                        "Only {} chromosomes, writing {n_shards} shards",
+                        counts.len()
+                    );
+                }
+                let assigned = assign_chroms(&counts, n_shards);
+                let mut shards = shard_names(n_shards);
+                let mut writers = shards.iter().map(open).collect::<Result<Vec<_>>>()?;
+                load_apply(reader()?, |reads: Vec<T>| {
+                    let mut batches: Vec<Vec<T>> = (0..n_shards).map(|_| Vec::new()).collect();
+                    for read in reads {
+                        let idx = assigned[read.chrom()];
+                        shards[idx].add(read.chrom());
+                        batches[idx].push(read);
+                    }
+                    for (writer, batch) in writers.iter_mut().zip(batches) {
+                        save(writer, &batch)?;
+                    }
+                    Ok(())
+                })?;
+                for writer in writers.iter_mut() {
+                    writer.finish()?;
+                }
+                Ok(shards)
+            }
+        }
+    }
+}
+
+/// [SplitOptions::run] with the type of reads of the input
+struct SplitShards<'a> {
+    split: &'a SplitOptions,
+    input: &'a Path,
+    output_dir: &'a Path,
+}
+
+impl ArrowKindFn for SplitShards<'_> {
+    type Output = Vec<Shard>;
+
+    fn call<T>(self, _kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        self.split.split::<T>(self.input, self.output_dir)
+    }
+}
+
+/// Zero-padded so shards sort in order, ie for shell globs
+fn shard_names(n_shards: usize) -> Vec<Shard> {
+    let width = (n_shards - 1).to_string().len().max(3);
+    (0..n_shards)
+        .map(|idx| Shard::new(PathBuf::from(format!("shard_{idx:0width$}.arrow"))))
+        .collect()
+}
+
+/// Assign the chromosomes with the most reads first, each to the shard with
+/// the fewest reads so far
+fn assign_chroms(counts: &BTreeMap<String, usize>, n_shards: usize) -> BTreeMap<String, usize> {
+    let mut chroms = counts.iter().collect::<Vec<_>>();
+    chroms.sort_by(|(a_chrom, a), (b_chrom, b)| b.cmp(a).then(a_chrom.cmp(b_chrom)));
+    let mut totals = vec![0; n_shards];
+    let mut assigned = BTreeMap::new();
+    for (chrom, &count) in chroms {
+        let (idx, _) = totals
+            .iter()
+            .enumerate()
+            .min_by_key(|&(idx, &total)| (total, idx))
+            .expect("At least one shard");
+        totals[idx] += count;
+        assigned.insert(chrom.clone(), idx);
+    }
+    assigned
+}
+
+/// Number of reads in an Arrow file, without deserializing them
+fn count_reads(reader: BufReader<File>) -> Result<usize> {
+    let mut total = 0;
+    for chunk in load(reader)? {
+        total += chunk?.len();
+    }
+    Ok(total)
+}
+
+/// Schema to write reads of the input with, keeping the metadata of the
+/// input, ie the mode and skip score settings
+pub(crate) fn input_schema<T: SchemaExt>(input: &Path) -> Result<Schema> {
+    let metadata = file_metadata(&mut File::open(input)?)?;
+    Ok(
+        Schema::from(vec![Field::new(T::type_as_str(), T::data_type(), false)])
+            .with_metadata(metadata),
+    )
+}
+
+/// Concatenate Arrow files of the same type, ie outputs of scoring each shard
+/// from [SplitOptions], keeping reads in the order of the inputs. Unlike
+/// [crate::merge::MergeOptions] the sample of each read is left as is.
+pub struct CatOptions {
+    inputs: Vec<PathBuf>,
+    compression: ArrowCompression,
+}
+
+impl Default for CatOptions {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            compression: ArrowCompression::default(),
+        }
+    }
+}
+
+impl CatOptions {
+    pub fn input<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    /// Codec for the output, LZ4 by default
+    pub fn compression(&mut self, compression: ArrowCompression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write the reads of every input to output, or stdout if there is none.
+    /// The output keeps the schema metadata of the first input. Returns the
+    /// number of reads written.
+    pub fn run<P: AsRef<Path>>(&self, output: Option<P>) -> Result<usize> {
+        let first = self
+            .inputs
+            .first()
+            .ok_or_else(|| eyre::eyre!("No files to concatenate"))?;
+        let kind = ArrowKind::of(first)?;
+        for path in self.inputs.iter().skip(1) {
+            let other = ArrowKind::of(path)?;
+            if other != kind {
+                eyre::bail!(
+                    "Can't concatenate {} with {kind} reads and {} with {other} reads",
+                    first.display(),
+                    path.display()
+                );
+            }
+        }
+
+        let cat = CatReads {
+            cat: self,
+            writer: stdout_or_file(output.as_ref())?,
+            first,
+        };
+        dispatch_arrow_kind(kind, cat)
+    }
+
+    /// Schema of concatenated eventalign reads, which have to be in the same
+    /// mode. Samples are only recorded as kept if every input kept them.
+    fn eventalign_schema(&self, first: &Path) -> Result<Schema> {
+        let mut samples = true;
+        let mode = read_mode(&mut File::open(first)?)?;
+        for path in self.inputs.iter() {
+            samples &= has_samples(&mut File::open(path)?)?;
+            let other = read_mode(&mut File::open(path)?)?;
+            if other != mode {
+                eyre::bail!(
+                    "Can't concatenate {} with {mode} reads and {} with {other} reads",
+                    first.display(),
+                    path.display()
+                );
+            }
+        }
+        Ok(eventalign_schema(samples, mode))
+    }
+
+    fn cat<T, W>(&self, writer: W, schema: &Schema) -> Result<usize>
+    where
+        W: Write,
+        T: ArrowField<Type = T> + ArrowDeserialize + ArrowSerialize + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let mut writer = arrow_utils::wrap_writer_with(writer, schema, self.compression)?;
+        let mut n_reads = 0;
+        for path in self.inputs.iter() {
+            let reader = BufReader::new(File::open(path)?);
+            load_apply(reader, |reads: Vec<T>| {
+                n_reads += reads.len();
+                save(&mut writer, &reads)
+            })
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        }
+        writer.finish()?;
+        log::info!("Wrote {n_reads} reads from {} files", self.inputs.len());
+        Ok(n_reads)
+    }
+}
+
+/// [CatOptions::run] with the type of reads of the inputs
+struct CatReads<'a> {
+    cat: &'a CatOptions,
+    writer: Box<dyn Write + Send>,
+    first: &'a Path,
+}
+
+impl ArrowKindFn for CatReads<'_> {
+    type Output = usize;
+
+    fn call<T>(self, kind: ArrowKind) -> Result<Self::Output>
+    where
+        T: ArrowField<Type = T>
+            + ArrowDeserialize
+            + ArrowSerialize
+            + SchemaExt
+            + MetadataExt
+            + MetadataMutExt
+            + Encode
+            + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let mut schema = input_schema::<T>(self.first)?;
+        if kind == ArrowKind::Eventalign {
+            schema
+                .metadata
+                .extend(self.cat.eventalign_schema(self.first)?.metadata);
+        }
+        self.cat.cat::<T, _>(self.writer, &schema)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        arrow::{
+            arrow_utils::{load_chunks, wrap_writer},
+            eventalign::Eventalign,
+            scored_read::ScoredRead,
+        },
+        score::SKIP_SCORE_KEY,
+        test_data::{test_score, TestRead},
+    };
+
+    fn read_names(path: &Path) -> Result<Vec<String>> {
+        let chunks = load_chunks::<_, ScoredRead>(File::open(path)?)?;
+        let reads = chunks.collect::<Result<Vec<_>, _>>()?.concat();
+        Ok(reads.iter().map(|r| r.name().to_string()).collect())
+    }
+
+    fn write_scores(path: &Path) -> Result<Vec<String>> {
+        let reads = (0..100)
+            .map(|i| {
+                let chrom = match i {
+                    0..=59 => "chrI",
+                    60..=89 => "chrII",
+                    _ => "chrIII",
+                };
+                TestRead::new(format!("read{i}"))
+                    .chrom(chrom)
+                    .span(i * 10, 20)
+                    .scored(vec![test_score(i * 10, 0.9)])
+            })
+            .collect::<Vec<_>>();
+        let mut schema = ScoredRead::schema();
+        schema
+            .metadata
+            .insert(SKIP_SCORE_KEY.to_string(), "disabled".to_string());
+        let mut writer = wrap_writer(File::create(path)?, &schema)?;
+        save(&mut writer, &reads[..45])?;
+        save(&mut writer, &reads[45..])?;
+        writer.finish()?;
+        Ok(reads.iter().map(|r| r.name().to_string()).collect())
+    }
+
+    #[test]
+    fn test_split_reads() -> Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let input = temp_dir.path().join("scores.arrow");
+        let names = write_scores(&input)?;
+
+        let output_dir = temp_dir.path().join("shards");
+        let manifest = SplitOptions::new(3).run(&input, &output_dir)?;
+        assert_eq!(
+            manifest,
+            SplitManifest::load(output_dir.join(SPLIT_MANIFEST))?
+        );
+        let reads = manifest.shards.iter().map(|s| s.reads).collect::<Vec<_>>();
+        assert_eq!(reads, [34, 33, 33]);
+        assert_eq!(manifest.shards[0].path, Path::new("shard_000.arrow"));
+        assert_eq!(
+            manifest.shards[1].chroms.iter().collect::<Vec<_>>(),
+            ["chrI", "chrII"]
+        );
+
+        let mut cat = CatOptions::default();
+        for shard in manifest.shards.iter() {
+            assert_eq!(
+                read_names(&output_dir.join(&shard.path))?.len(),
+                shard.reads
+            );
+            cat.input(output_dir.join(&shard.path));
+        }
+        let merged = temp_dir.path().join("merged.arrow");
+        assert_eq!(cat.run(Some(&merged))?, 100);
+        assert_eq!(read_names(&merged)?, names);
+        let metadata = file_metadata(&mut File::open(&merged)?)?;
+        assert_eq!(metadata[SKIP_SCORE_KEY], "disabled");
+
+        // The shard changes at the first read of the second chunk
+        let shard_dir = temp_dir.path().join("twenty");
+        let manifest = SplitOptions::new(20).run(&input, &shard_dir)?;
+        for shard in manifest.shards.iter() {
+            let chunks = load_chunks::<_, ScoredRead>(File::open(shard_dir.join(&shard.path))?)?
+                .collect::<Result<Vec<_>, _>>()?;
+            assert!(chunks.iter().all(|c| !c.is_empty()));
+        }
+
+        let manifest = SplitOptions::new(500).run(&input, temp_dir.path().join("many"))?;
+        assert_eq!(manifest.shards.len(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_chrom() -> Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let input = temp_dir.path().join("scores.arrow");
+        write_scores(&input)?;
+
+        let output_dir = temp_dir.path().join("shards");
+        let manifest = SplitOptions::new(2)
+            .by(SplitBy::Chrom)
+            .run(&input, &output_dir)?;
+        let chroms = manifest
+            .shards
+            .iter()
+            .map(|s| (s.reads, s.chroms.iter().cloned().collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chroms,
+            [
+                (60, vec!["chrI".to_string()]),
+                (40, vec!["chrII".to_string(), "chrIII".to_string()])
+            ]
+        );
+        for shard in manifest.shards.iter() {
+            let metadata = file_metadata(&mut File::open(output_dir.join(&shard.path))?)?;
+            assert_eq!(metadata[SKIP_SCORE_KEY], "disabled");
+        }
+
+        let manifest = SplitOptions::new(5)
+            .by(SplitBy::Chrom)
+            .run(&input, temp_dir.path().join("per_chrom"))?;
+        assert_eq!(manifest.shards.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cat_mismatched() -> Result<()> {
+        let temp_dir = assert_fs::TempDir::new()?;
+        let scores = temp_dir.path().join("scores.arrow");
+        write_scores(&scores)?;
+        let eventaligns = temp_dir.path().join("collapse.arrow");
+        let mut writer = wrap_writer(
+            File::create(&eventaligns)?,
+            &eventalign_schema(false, Default::default()),
+        )?;
+        save(&mut writer, &[Eventalign::default()])?;
+        writer.finish()?;
+
+        let mut cat = CatOptions::default();
+        cat.input(&scores).input(&eventaligns);
+        assert!(cat.run(Some(temp_dir.path().join("out.arrow"))).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Split single molecule bed files from cawlr sma into one bed file per
+//! cluster, so each cluster can be loaded as a separate genome browser track.
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use fnv::FnvHashMap;
+
+use crate::utils::create_output;
+
+/// Parse cluster assignments, a tab-separated file where the first column is
+/// the read name and the second column is the cluster the read belongs to.
+/// Empty lines, lines starting with '#', and a header starting with
+/// "read_name" are skipped.
+fn parse_assignments<R: BufRead>(reader: R) -> Result<FnvHashMap<String, String>> {
+    let mut assignments = FnvHashMap::default();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || (idx == 0 && line.starts_with("read_name")) {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(read_name), Some(cluster)) if !cluster.is_empty() => {
+                assignments.insert(read_name.to_string(), cluster.to_string());
+            }
+            _ => {
+                return Err(eyre::eyre!(
+                    "Invalid cluster assignment on line {}, expected read name and cluster \
+                     separated by a tab: {line}",
+                    idx + 1
+                ))
+            }
+        }
+    }
+    Ok(assignments)
+}
+
+/// Path of the bed file for a cluster, matching the naming used by
+/// cluster_region.py, ie cluster0.sample.bed
+fn cluster_bed_path(output_dir: &Path, stem: &str, cluster: &str) -> PathBuf {
+    output_dir.join(format!("cluster{cluster}.{stem}.bed"))
+}
+
+/// Write each read in the bed file to the bed file for its cluster. Returns
+/// the path of the bed file for each cluster.
+///
+/// If output_dir is None, files are written to the same directory as the
+/// input bed file. Reads without a cluster assignment are skipped.
+pub fn run(
+    input: &Path,
+    assignments: &Path,
+    output_dir: Option<&Path>,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let assignments = parse_assignments(BufReader::new(File::open(assignments)?))?;
+    let output_dir = output_dir
+        .or_else(|| input.parent())
+        .unwrap_or_else(|| Path::new("."));
+    let stem = input
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| eyre::eyre!("Invalid input filename: {}", input.display()))?;
+
+    let mut outputs = BTreeMap::new();
+    let mut writers = FnvHashMap::default();
+    for cluster in assignments.values() {
+        if outputs.contains_key(cluster) {
+            continue;
+        }
+        let path = cluster_bed_path(output_dir, stem, cluster);
+        let mut writer = BufWriter::new(create_output(&path)?);
+        writeln!(
+            writer,
+            "track name=\"cluster{cluster}.{stem}\" description=\"Cluster {cluster} of {stem}\" \
+             itemRgb=\"on\" visibility=2"
+        )?;
+        writers.insert(cluster.as_str(), writer);
+        outputs.insert(cluster.clone(), path);
+    }
+
+    let mut n_unassigned = 0;
+    let input = BufReader::new(File::open(input)?);
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with("track") || line.starts_with('#') {
+            continue;
+        }
+        let read_name = line
+            .split('\t')
+            .nth(3)
+            .ok_or_else(|| eyre::eyre!("Invalid bed line, missing read name: {line}"))?;
+        match assignments.get(read_name) {
+            Some(cluster) => writeln!(writers.get_mut(cluster.as_str()).unwrap(), "{line}")?,
+            None => n_unassigned += 1,
+        }
+    }
+    for writer in writers.values_mut() {
+        writer.flush()?;
+    }
+    if n_unassigned > 0 {
+        log::warn!("Skipped {n_unassigned} reads without a cluster assignment");
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_split_clusters() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let bed = temp_dir.path().join("sample.bed");
+        fs::write(
+            &bed,
+            "track name=\"sample\" itemRgb=\"on\" visibility=2\n\
+             chrI\t0\t10\tread1\t0\t+\t0\t10\t255,0,0\t1\t1\t0\n\
+             chrI\t0\t10\tread2\t0\t-\t0\t10\t0,0,255\t1\t1\t0\n\
+             chrI\t0\t10\tread3\t0\t+\t0\t10\t255,0,0\t1\t1\t0\n",
+        )?;
+        let clusters = temp_dir.path().join("clusters.tsv");
+        fs::write(&clusters, "read_name\tcluster\nread1\t0\nread2\t1\n")?;
+
+        let outputs = run(&bed, &clusters, None)?;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs["0"], temp_dir.path().join("cluster0.sample.bed"));
+
+        let cluster0 = fs::read_to_string(&outputs["0"])?;
+        let lines = cluster0.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("track name=\"cluster0.sample\""));
+        assert!(lines[1].contains("read1"));
+
+        let cluster1 = fs::read_to_string(&outputs["1"])?;
+        assert!(cluster1.contains("read2"));
+        assert!(!cluster1.contains("read3"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_assignments_invalid() {
+        let res = parse_assignments("read1\t0\nread2\n".as_bytes());
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Per-read summaries of scored data, from either cawlr score or a BAM file
+//! with modification calls.
+use std::io::Write;
+
+use eyre::Result;
+
+use crate::arrow::{
+    io::{read_mod_bam_or_arrow, ModFile},
+    metadata::MetadataExt,
+    scored_read::ScoredRead,
+};
+
+const READ_STATS_HEADER: &str =
+    "read_name\tchrom\tstart\tend\tstrand\tn_scored\tn_modified\tfrac_modified\tmean_score";
+
+/// Summary of the scores of a single read
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadStats {
+    pub n_scored: usize,
+    pub n_modified: usize,
+    pub mean_score: Option<f64>,
+}
+
+impl ReadStats {
+    /// Scores greater than the threshold are counted as modified
+    pub fn from_read(read: &ScoredRead, threshold: f64) -> Self {
+        let scores = read.scores();
+        let n_scored = scores.len();
+        let n_modified = scores.iter().filter(|s| s.score > threshold).count();
+        let mean_score = if n_scored > 0 {
+            Some(scores.iter().map(|s| s.score).sum::<f64>() / n_scored as f64)
+        } else {
+            None
+        };
+        ReadStats {
+            n_scored,
+            n_modified,
+            mean_score,
+        }
+    }
+
+    /// Fraction of scored positions that are modified, None if there are no
+    /// scores
+    pub fn frac_modified(&self) -> Option<f64> {
+        if self.n_scored > 0 {
+            Some(self.n_modified as f64 / self.n_scored as f64)
+        } else {
+            None
+        }
+    }
+}
+
+fn fmt_option(x: Option<f64>) -> String {
+    x.map_or("NA".to_string(), |x| x.to_string())
+}
+
+/// Write a tab-separated line for each read with the number of scored
+/// positions, how many of those are modified, and the mean score.
+pub struct ReadStatsOptions {
+    threshold: f64,
+}
+
+impl Default for ReadStatsOptions {
+    fn default() -> Self {
+        ReadStatsOptions { threshold: 0.5 }
+    }
+}
+
+impl ReadStatsOptions {
+    /// Scores greater than the threshold are counted as modified, defaults to
+    /// 0.5
+    pub fn threshold(&mut self, threshold: f64) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    fn write_read<W: Write>(&self, writer: &mut W, read: &ScoredRead) -> Result<ReadStats> {
+        let stats = ReadStats::from_read(read, self.threshold);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            read.name(),
+            read.chrom(),
+            read.start_0b(),
+            read.end_1b_excl(),
+            read.strand(),
+            stats.n_scored,
+            stats.n_modified,
+            fmt_option(stats.frac_modified()),
+            fmt_option(stats.mean_score),
+        )?;
+        Ok(stats)
+    }
+
+    /// Returns the total number of scored and modified positions across all
+    /// reads.
+    pub fn run<W: Write>(&self, mod_file: ModFile, mut writer: W) -> Result<(usize, usize)> {
+        writeln!(writer, "{READ_STATS_HEADER}")?;
+        let mut n_scored = 0;
+        let mut n_modified = 0;
+        read_mod_bam_or_arrow(mod_file, |read| {
+            let stats = self.write_read(&mut writer, &read)?;
+            n_scored += stats.n_scored;
+            n_modified += stats.n_modified;
+            Ok(())
+        })?;
+        writer.flush()?;
+        Ok((n_scored, n_modified))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{arrow::scored_read::Score, test_data::TestRead};
+
+    fn score(pos: u64, score: f64) -> Score {
+        Score::new(pos, "GCAAAA".parse().unwrap(), false, Some(score), score)
+    }
+
+    #[test]
+    fn test_read_stats() -> Result<()> {
+        let metadata = TestRead::new("read").span(100, 50).metadata();
+        let read = ScoredRead::new(
+            metadata.clone(),
+            vec![score(101, 0.9), score(110, 0.2), score(120, 0.7)],
+        );
+        let stats = ReadStats::from_read(&read, 0.5);
+        assert_eq!(stats.n_scored, 3);
+        assert_eq!(stats.n_modified, 2);
+        assert!((stats.mean_score.unwrap() - 0.6).abs() < 1e-10);
+        assert!((stats.frac_modified().unwrap() - 2. / 3.).abs() < 1e-10);
+
+        let mut output = Vec::new();
+        let empty = ScoredRead::new(metadata, Vec::new());
+        ReadStatsOptions::default().write_read(&mut output, &empty)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!(
+                "read\tchrI\t100\t{}\t+\t0\t0\tNA\tNA\n",
+                empty.end_1b_excl()
+            )
+        );
+        Ok(())
+    }
+}
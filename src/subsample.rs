@@ -0,0 +1,327 @@
+//! Randomly subsample reads from the output of cawlr collapse or cawlr score,
+//! ie for quick parameter sweeps or to balance controls before training.
+//!
+//! Reads are streamed, so memory doesn't grow with the size of the input.
+//! Sampling a number of reads takes two passes, one to count the reads and one
+//! to write the chosen ones, and keeps the reads in the same order as the
+//! input.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Write},
+    path::Path,
+};
+
+use arrow2::datatypes::Schema;
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::{Result, WrapErr};
+use fnv::FnvHashMap;
+use rand::{rngs::SmallRng, seq::index, Rng, SeedableRng};
+
+use crate::{
+    arrow::{
+        arrow_utils::{
+            self, arrow_type, eventalign_schema, has_samples, load_apply, read_mode, save,
+        },
+        eventalign::Eventalign,
+        metadata::MetadataExt,
+        scored_read::ScoredRead,
+    },
+    region::Region,
+    repro,
+    utils::create_output,
+};
+
+/// How many reads to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubsampleTarget {
+    /// Keep each read with this probability
+    Fraction(f64),
+    /// Keep this many reads, or every read if there are fewer
+    Count(usize),
+}
+
+impl fmt::Display for SubsampleTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubsampleTarget::Fraction(fraction) => write!(f, "fraction {fraction}"),
+            SubsampleTarget::Count(count) => write!(f, "{count} reads"),
+        }
+    }
+}
+
+/// Number of reads in the input that passed the region filter, and the number
+/// written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubsampleCounts {
+    pub n_reads: usize,
+    pub n_kept: usize,
+}
+
+/// Write a random subset of the reads of an Arrow file from cawlr collapse or
+/// cawlr score.
+pub struct SubsampleOptions {
+    target: SubsampleTarget,
+    regions: Vec<Region>,
+    per_chrom: bool,
+    seed: u64,
+}
+
+impl SubsampleOptions {
+    pub fn new(target: SubsampleTarget) -> Self {
+        Self {
+            target,
+            regions: Vec::new(),
+            per_chrom: false,
+            seed: 2456,
+        }
+    }
+
+    /// Only sample reads overlapping any of these regions, by default all
+    /// reads are sampled
+    pub fn regions(&mut self, regions: Vec<Region>) -> &mut Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Keep the target number of reads from each chromosome, so chromosomes
+    /// with more reads don't dominate the output. Only used with
+    /// [SubsampleTarget::Count].
+    pub fn per_chrom(&mut self, per_chrom: bool) -> &mut Self {
+        self.per_chrom = per_chrom;
+        self
+    }
+
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Subsample input into output, detecting whether the input is from cawlr
+    /// collapse or cawlr score
+    pub fn run<P, Q>(&self, input: P, output: Q) -> Result<SubsampleCounts>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        match self.target {
+            SubsampleTarget::Fraction(fraction) if !(fraction > 0.0 && fraction <= 1.0) => {
+                eyre::bail!("Fraction of reads to keep must be in (0, 1], found {fraction}")
+            }
+            SubsampleTarget::Fraction(_) if self.per_chrom => {
+                eyre::bail!("Balancing reads per chromosome needs a number of reads to keep")
+            }
+            _ => (),
+        }
+        let input = input.as_ref();
+        let kind = arrow_type(&mut File::open(input)?)
+            .wrap_err_with(|| format!("Failed to read {}", input.display()))?;
+        let writer = create_output(output)?;
+        repro::record_seed(
+            "subsample",
+            self.seed,
+            serde_json::json!({
+                "target": self.target.to_string(),
+                "per_chrom": self.per_chrom,
+            }),
+        );
+        let counts = match kind.as_str() {
+            "eventalign" => {
+                let samples = has_samples(&mut File::open(input)?)?;
+                let mode = read_mode(&mut File::open(input)?)?;
+                let schema = eventalign_schema(samples, mode);
+                self.subsample::<Eventalign, _>(input, writer, &schema)?
+            }
+            "scored" => self.subsample::<ScoredRead, _>(input, writer, &ScoredRead::schema())?,
+            _ => eyre::bail!("Expected output from cawlr collapse or score, found {kind}"),
+        };
+        log::info!(
+            "Kept {} of {} reads by {}",
+            counts.n_kept,
+            counts.n_reads,
+            self.target
+        );
+        Ok(counts)
+    }
+
+    fn in_regions<M: MetadataExt>(&self, read: &M) -> bool {
+        self.regions.is_empty() || self.regions.iter().any(|r| r.valid(read))
+    }
+
+    /// Reads are grouped by chromosome when balancing, otherwise every read is
+    /// in the same group
+    fn group<'a, M: MetadataExt>(&self, read: &'a M) -> &'a str {
+        if self.per_chrom {
+            read.chrom()
+        } else {
+            ""
+        }
+    }
+
+    fn subsample<T, W>(&self, input: &Path, writer: W, schema: &Schema) -> Result<SubsampleCounts>
+    where
+        W: Write,
+        T: ArrowField<Type = T> + ArrowDeserialize + ArrowSerialize + MetadataExt + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        let mut keep: Box<dyn FnMut(&T) -> bool + '_> = match self.target {
+            SubsampleTarget::Fraction(fraction) => Box::new(move |_: &T| rng.gen_bool(fraction)),
+            SubsampleTarget::Count(count) => {
+                let chosen = self.choose::<T>(input, count, &mut rng)?;
+                let mut seen: FnvHashMap<String, usize> = FnvHashMap::default();
+                Box::new(move |read: &T| {
+                    let group = self.group(read);
+                    let idx = seen.entry(group.to_string()).or_default();
+                    let keep = chosen.get(group).map_or(false, |c| c[*idx]);
+                    *idx += 1;
+                    keep
+                })
+            }
+        };
+
+        let mut writer = arrow_utils::wrap_writer(writer, schema)?;
+        let mut counts = SubsampleCounts::default();
+        let res = load_apply(BufReader::new(File::open(input)?), |reads: Vec<T>| {
+            let kept = reads
+                .into_iter()
+                .filter(|read| self.in_regions(read))
+                .inspect(|_| counts.n_reads += 1)
+                .filter(|read| keep(read))
+                .collect::<Vec<_>>();
+            counts.n_kept += kept.len();
+            save(&mut writer, &kept)
+        });
+        // Finish even if sampling failed, so chunks already written stay
+        // readable
+        writer.finish()?;
+        res?;
+        Ok(counts)
+    }
+
+    /// First pass for sampling a number of reads, choosing which reads of each
+    /// group to keep by their index within the group
+    fn choose<T>(
+        &self,
+        input: &Path,
+        count: usize,
+        rng: &mut SmallRng,
+    ) -> Result<FnvHashMap<String, Vec<bool>>>
+    where
+        T: ArrowField<Type = T> + ArrowDeserialize + MetadataExt + 'static,
+        for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+    {
+        // Sorted so groups are sampled in the same order every run
+        let mut totals: BTreeMap<String, usize> = BTreeMap::new();
+        load_apply(BufReader::new(File::open(input)?), |reads: Vec<T>| {
+            for read in reads.iter().filter(|read| self.in_regions(*read)) {
+                *totals.entry(self.group(read).to_string()).or_default() += 1;
+            }
+            Ok(())
+        })?;
+        let mut chosen = FnvHashMap::default();
+        for (group, total) in totals {
+            if total < count {
+                log::warn!(
+                    "Only {total} reads{}, keeping all of them",
+                    if group.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" on {group}")
+                    }
+                );
+            }
+            let mut keep = vec![false; total];
+            for idx in index::sample(rng, total, count.min(total)) {
+                keep[idx] = true;
+            }
+            chosen.insert(group, keep);
+        }
+        Ok(chosen)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{
+        arrow::arrow_utils::{load_chunks, wrap_writer},
+        test_data::{test_score, MiniGenome, TestRead},
+    };
+
+    fn read_names(path: &Path) -> Result<Vec<String>> {
+        let chunks = load_chunks::<_, ScoredRead>(File::open(path)?)?;
+        let reads = chunks.collect::<Result<Vec<_>, _>>()?.concat();
+        Ok(reads.iter().map(|r| r.name().to_string()).collect())
+    }
+
+    #[test]
+    fn test_subsample() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("scores.arrow");
+        let reads = (0..100)
+            .map(|i| {
+                let chrom = if i < 90 { "chrI" } else { "chrII" };
+                TestRead::new(format!("read{i}"))
+                    .chrom(chrom)
+                    .span(i * 10, 20)
+                    .scored(vec![test_score(i * 10, 0.9)])
+            })
+            .collect::<Vec<_>>();
+        let mut writer = wrap_writer(File::create(&input)?, &ScoredRead::schema())?;
+        save(&mut writer, &reads[..50])?;
+        save(&mut writer, &reads[50..])?;
+        writer.finish()?;
+
+        let output = mini.dir().join("subsampled.arrow");
+        let counts = SubsampleOptions::new(SubsampleTarget::Count(20)).run(&input, &output)?;
+        assert_eq!(
+            counts,
+            SubsampleCounts {
+                n_reads: 100,
+                n_kept: 20
+            }
+        );
+        let names = read_names(&output)?;
+        assert_eq!(names.len(), 20);
+        let mut sorted = names.clone();
+        sorted.sort_by_key(|n| n[4..].parse::<usize>().unwrap());
+        assert_eq!(names, sorted, "Reads should stay in input order");
+
+        // Same seed gives the same reads
+        SubsampleOptions::new(SubsampleTarget::Count(20)).run(&input, &output)?;
+        assert_eq!(read_names(&output)?, names);
+
+        let counts = SubsampleOptions::new(SubsampleTarget::Count(5))
+            .per_chrom(true)
+            .run(&input, &output)?;
+        assert_eq!(counts.n_kept, 10);
+        let names = read_names(&output)?;
+        let on_chr_ii = names
+            .iter()
+            .filter(|n| n[4..].parse::<usize>().unwrap() >= 90)
+            .count();
+        assert_eq!(on_chr_ii, 5);
+
+        let counts = SubsampleOptions::new(SubsampleTarget::Fraction(0.5))
+            .regions(vec![Region::from_str("chrII:0-10000")?])
+            .seed(7)
+            .run(&input, &output)?;
+        assert_eq!(counts.n_reads, 10);
+        assert!(read_names(&output)?
+            .iter()
+            .all(|n| n[4..].parse::<usize>().unwrap() >= 90));
+
+        assert!(SubsampleOptions::new(SubsampleTarget::Fraction(1.5))
+            .run(&input, &output)
+            .is_err());
+        assert!(SubsampleOptions::new(SubsampleTarget::Fraction(0.5))
+            .per_chrom(true)
+            .run(&input, &output)
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+//! Tiny synthetic genome with matching nanopolish eventalign and bam files,
+//! written to a temporary directory so unit tests can run without the large
+//! files in extra/, and a builder for reads of tests that only need Arrow
+//! files, see [TestRead].
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use arrow2_convert::{field::ArrowField, serialize::ArrowSerialize};
+use assert_fs::TempDir;
+use bam::{BamWriter, Header, Record, RecordWriter};
+use eyre::Result;
+
+use crate::arrow::{
+    arrow_utils::{save_t, SchemaExt},
+    metadata::{Metadata, Strand},
+    scored_read::{Score, ScoredRead},
+    sma_read::{SmaBlock, SmaRead},
+};
+
+const CHR_I: &str = "TTCGACATAAACAATGAATGACGACTTGAGAGTTTATAATAGAGGTCAGCATGACCCGGGACTCG\
+ACCGGGAGCCCAACGGTGTTTAACAGACAATTCAGCACGAAACGCAAGTTAGATATGATTCATGCTTTACTTAGGCGCTGTCGATA\
+CGGGTCCCTTTCTTGTTCCCGATGTTATCTAAAGCGGGGATGAAAATTTGGTTTCAATGATCACATGAATCTCATAAGCCCGCGAC\
+TAGCACAGCTCGGTCCCTATGTGAGACGCAAATTCCATATGTACACGTTACCCGGTCTACTGG";
+
+const CHR_II: &str = "TCAGAAGCGCTGCGCCTAATCGATCTATTGACGGGAATCACGGAACACTGAGGTATGGTTTCTCCCTT\
+ACGTTGCAGTGGGAGTGTTCACGGAACTTAGCCAATTTGCCCTAGAAACTGGCTTAGCTGGTCAATGGTGTCCGCGAGGCTTACACC\
+CCACGATGAGTACACAGGGGAGCCAATCTCCTTGCAGCCACAGGAAGCGTTATATCCCATAACCCCTACTCCGGCGCCCGCAAGG";
+
+/// Chromosomes of the mini genome and their sequences
+pub(crate) const CHROMS: [(&str, &str); 2] = [("chrI", CHR_I), ("chrII", CHR_II)];
+
+const FASTA_LINE_WIDTH: usize = 60;
+
+const EVENTALIGN_HEADER: &str = "contig\tposition\treference_kmer\tread_name\tstrand\t\
+event_index\tevent_level_mean\tevent_stdv\tevent_length\tmodel_kmer\tmodel_mean\t\
+model_stdv\tstandardized_level\tsamples";
+
+/// A read simulated from the mini genome, with events at every kmer position
+/// from start to stop inclusive except for the skipped positions.
+pub(crate) struct MiniRead {
+    pub(crate) name: &'static str,
+    pub(crate) chrom: &'static str,
+    pub(crate) start: u64,
+    pub(crate) stop: u64,
+    pub(crate) plus_strand: bool,
+    pub(crate) skipped: &'static [u64],
+    pub(crate) haplotype: i64,
+}
+
+pub(crate) const PLUS_READ: MiniRead = MiniRead {
+    name: "plus-read",
+    chrom: "chrI",
+    start: 100,
+    stop: 150,
+    plus_strand: true,
+    skipped: &[120],
+    haplotype: 1,
+};
+
+pub(crate) const MINUS_READ: MiniRead = MiniRead {
+    name: "minus-read",
+    chrom: "chrII",
+    start: 60,
+    stop: 100,
+    plus_strand: false,
+    skipped: &[],
+    haplotype: 2,
+};
+
+pub(crate) const READS: [MiniRead; 2] = [PLUS_READ, MINUS_READ];
+
+/// Sequence of a chromosome in the mini genome
+pub(crate) fn chrom_seq(chrom: &str) -> &'static str {
+    CHROMS
+        .iter()
+        .find(|(name, _)| *name == chrom)
+        .map(|(_, seq)| *seq)
+        .unwrap_or_else(|| panic!("{chrom} is not in the mini genome"))
+}
+
+/// Current level of a kmer, the same kmer always gets the same level
+fn kmer_level(kmer: &str) -> f64 {
+    let sum: u64 = kmer
+        .bytes()
+        .enumerate()
+        .map(|(i, b)| (i as u64 + 1) * b as u64)
+        .sum();
+    70.0 + (sum % 40) as f64
+}
+
+impl MiniRead {
+    /// Length of the aligned sequence, including the last kmer
+    fn seq_len(&self) -> u64 {
+        self.stop + 6 - self.start
+    }
+
+    fn seq(&self) -> &'static str {
+        &chrom_seq(self.chrom)[self.start as usize..(self.start + self.seq_len()) as usize]
+    }
+
+    fn write_eventalign<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let chrom = chrom_seq(self.chrom);
+        let mut event_index = 0;
+        for pos in self.start..=self.stop {
+            if self.skipped.contains(&pos) {
+                continue;
+            }
+            let kmer = &chrom[pos as usize..pos as usize + 6];
+            let level = kmer_level(kmer);
+            // Every third position is split across two events
+            let n_events = if pos % 3 == 0 { 2 } else { 1 };
+            for event in 0..n_events {
+                let samples = (0..4)
+                    .map(|i| format!("{:.3}", level + (event + i) as f64 * 0.25))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                writeln!(
+                    writer,
+                    "{}\t{pos}\t{kmer}\t{}\tt\t{event_index}\t{level:.2}\t0.500\t0.00100\t{kmer}\t\
+                     {level:.2}\t1.50\t0.00\t{samples}",
+                    self.chrom, self.name,
+                )?;
+                event_index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn to_sam(&self) -> String {
+        let flag = if self.plus_strand { 0 } else { 16 };
+        format!(
+            "{}\t{flag}\t{}\t{}\t60\t{}M\t*\t0\t0\t{}\t*\tHP:i:{}",
+            self.name,
+            self.chrom,
+            self.start + 1,
+            self.seq_len(),
+            self.seq(),
+            self.haplotype,
+        )
+    }
+}
+
+/// Temporary directory with the mini genome and reads. Files are removed when
+/// this is dropped.
+pub(crate) struct MiniGenome {
+    dir: TempDir,
+}
+
+impl MiniGenome {
+    pub(crate) fn new() -> Result<Self> {
+        let mini = MiniGenome {
+            dir: TempDir::new()?,
+        };
+        mini.write_genome()?;
+        mini.write_eventalign()?;
+        mini.write_bam()?;
+        Ok(mini)
+    }
+
+    /// Directory containing the files, also useful for test outputs
+    pub(crate) fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Indexed fasta file
+    pub(crate) fn genome(&self) -> PathBuf {
+        self.dir().join("genome.fa")
+    }
+
+    pub(crate) fn eventalign(&self) -> PathBuf {
+        self.dir().join("reads.eventalign.txt")
+    }
+
+    /// Alignments of each read, with their haplotype in the HP tag
+    pub(crate) fn bam(&self) -> PathBuf {
+        self.dir().join("reads.bam")
+    }
+
+    fn write_genome(&self) -> Result<()> {
+        let mut fasta = BufWriter::new(File::create(self.genome())?);
+        let mut fai = BufWriter::new(File::create(self.dir().join("genome.fa.fai"))?);
+        let mut offset = 0;
+        for (name, seq) in CHROMS {
+            let header = format!(">{name}\n");
+            fasta.write_all(header.as_bytes())?;
+            offset += header.len();
+            writeln!(
+                fai,
+                "{name}\t{}\t{offset}\t{FASTA_LINE_WIDTH}\t{}",
+                seq.len(),
+                FASTA_LINE_WIDTH + 1
+            )?;
+            for line in seq.as_bytes().chunks(FASTA_LINE_WIDTH) {
+                fasta.write_all(line)?;
+                fasta.write_all(b"\n")?;
+                offset += line.len() + 1;
+            }
+        }
+        fasta.flush()?;
+        fai.flush()?;
+        Ok(())
+    }
+
+    fn write_eventalign(&self) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(self.eventalign())?);
+        writeln!(writer, "{EVENTALIGN_HEADER}")?;
+        for read in READS.iter() {
+            read.write_eventalign(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_bam(&self) -> Result<()> {
+        let mut header = Header::new();
+        for (name, seq) in CHROMS {
+            header.push_line(&format!("@SQ\tSN:{name}\tLN:{}", seq.len()))?;
+        }
+        let mut writer = BamWriter::from_path(self.bam(), header.clone())?;
+        for read in READS.iter() {
+            let mut record = Record::new();
+            record.fill_from_sam(&read.to_sam(), &header)?;
+            writer.write(&record)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Read for tests, named "read" on the plus strand of chrI from 10 to 30
+/// unless changed
+#[derive(Debug, Clone)]
+pub(crate) struct TestRead(Metadata);
+
+impl TestRead {
+    pub(crate) fn new<S: Into<String>>(name: S) -> Self {
+        TestRead(Metadata::new(
+            name.into(),
+            "chrI".to_string(),
+            10,
+            20,
+            Strand::plus(),
+            String::new(),
+        ))
+    }
+
+    pub(crate) fn chrom(mut self, chrom: &str) -> Self {
+        self.0.chrom = chrom.to_string();
+        self
+    }
+
+    /// Zero-based start and length of the alignment
+    pub(crate) fn span(mut self, start: u64, length: u64) -> Self {
+        self.0.start = start;
+        self.0.length = length;
+        self
+    }
+
+    pub(crate) fn strand(mut self, strand: Strand) -> Self {
+        self.0.strand = strand;
+        self
+    }
+
+    pub(crate) fn mapq(mut self, mapq: Option<u8>) -> Self {
+        self.0.mapq = mapq;
+        self
+    }
+
+    pub(crate) fn sample(mut self, sample: Option<&str>) -> Self {
+        self.0.sample = sample.map(String::from);
+        self
+    }
+
+    pub(crate) fn haplotype(mut self, haplotype: Option<i64>) -> Self {
+        self.0.haplotype = haplotype;
+        self
+    }
+
+    pub(crate) fn metadata(self) -> Metadata {
+        self.0
+    }
+
+    pub(crate) fn scored(self, scores: Vec<Score>) -> ScoredRead {
+        ScoredRead::new(self.0, scores)
+    }
+
+    pub(crate) fn sma(self, blocks: Vec<SmaBlock>) -> SmaRead {
+        SmaRead::new(self.0, blocks)
+    }
+}
+
+/// Score of a position on an "A" kmer, without a signal score
+pub(crate) fn test_score(pos: u64, score: f64) -> Score {
+    Score::new(pos, "A".parse().unwrap(), false, None, score)
+}
+
+/// Write reads to a new Arrow file in one chunk
+pub(crate) fn write_reads<T>(path: &Path, reads: &[T]) -> Result<()>
+where
+    T: ArrowField<Type = T> + ArrowSerialize + SchemaExt + 'static,
+{
+    let mut writer = T::wrap_writer(File::create(path)?)?;
+    save_t(&mut writer, reads)?;
+    writer.finish()?;
+    Ok(())
+}
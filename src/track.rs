@@ -0,0 +1,330 @@
+//! Genome browser tracks of the fraction of reads that are modified, or that
+//! have a nucleosome, at each position. Values are normalized by the number
+//! of reads covering each position.
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use arrow2::io::ipc::read::read_file_metadata;
+use bio::io::fasta::IndexedReader;
+use eyre::Result;
+
+use crate::{
+    agg_blocks::{AggOptions, CountSink},
+    arrow::{
+        arrow_utils::{is_arrow_file, SchemaExt},
+        io::{read_mod_bam_or_arrow, ModFile},
+        metadata::MetadataExt,
+        scored_read::ScoredRead,
+        sma_read::SmaRead,
+    },
+    bigwig::{BedGraphEntry, BigWigWriter},
+    utils::{chrom_lens, create_output, stdout_or_file},
+};
+
+/// Builds bedGraph entries from the counts at each position, merging
+/// adjacent positions with the same value.
+struct BedGraphSink {
+    min_coverage: u64,
+    entries: BTreeMap<String, Vec<BedGraphEntry>>,
+}
+
+impl CountSink for BedGraphSink {
+    fn position(
+        &mut self,
+        chrom: &str,
+        pos: u64,
+        _strand: &str,
+        _sample: &str,
+        count: u64,
+        total: u64,
+    ) -> Result<()> {
+        if total == 0 || total < self.min_coverage {
+            return Ok(());
+        }
+        let value = (count as f64 / total as f64) as f32;
+        let pos = pos as u32;
+        if !self.entries.contains_key(chrom) {
+            self.entries.insert(chrom.to_string(), Vec::new());
+        }
+        let entries = self.entries.get_mut(chrom).unwrap();
+        match entries.last_mut() {
+            Some(last) if last.end == pos && last.value == value => last.end = pos + 1,
+            _ => entries.push(BedGraphEntry::new(pos, pos + 1, value)),
+        }
+        Ok(())
+    }
+}
+
+/// Type of data an input file has, which decides what the track shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrackInput {
+    /// Output of cawlr score or a modification bam, the track is the fraction
+    /// of reads modified
+    Scored,
+    /// Output of cawlr sma, the track is the fraction of reads with a
+    /// nucleosome
+    Sma,
+}
+
+impl TrackInput {
+    fn detect(input: &Path) -> Result<Self> {
+        if input.extension().map_or(false, |ext| ext == "bam") {
+            return Ok(TrackInput::Scored);
+        }
+        if !is_arrow_file(input) {
+            return Ok(TrackInput::Sma);
+        }
+        let metadata = read_file_metadata(&mut File::open(input)?)?;
+        match metadata.schema.fields.first().map(|f| f.name.as_str()) {
+            Some(name) if name == ScoredRead::type_as_str() => Ok(TrackInput::Scored),
+            Some(name) if name == SmaRead::type_as_str() => Ok(TrackInput::Sma),
+            _ => Err(eyre::eyre!(
+                "{} is not from cawlr score or cawlr sma",
+                input.display()
+            )),
+        }
+    }
+}
+
+/// Writes a bedGraph, and optionally a bigWig, of modification fraction or
+/// nucleosome occupancy per genomic position.
+pub struct TrackOptions {
+    threshold: f64,
+    min_coverage: u64,
+    mod_tag: Option<Vec<u8>>,
+    sorted: bool,
+    track_name: Option<String>,
+    bigwig: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for TrackOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            min_coverage: 1,
+            mod_tag: None,
+            sorted: false,
+            track_name: None,
+            bigwig: None,
+        }
+    }
+}
+
+impl TrackOptions {
+    /// Scores greater than the threshold are counted as modified, defaults to
+    /// 0.5
+    pub fn threshold(&mut self, threshold: f64) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Positions covered by fewer reads are left out of the track, defaults
+    /// to 1
+    pub fn min_coverage(&mut self, min_coverage: u64) -> &mut Self {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Modification tag used if the input is a bam file, ie C+m
+    pub fn mod_tag<B: Into<Vec<u8>>>(&mut self, mod_tag: Option<B>) -> &mut Self {
+        self.mod_tag = mod_tag.map(|t| t.into());
+        self
+    }
+
+    /// Output from cawlr sma is sorted by chromosome and start, see
+    /// [AggOptions::sorted]
+    pub fn sorted(&mut self, sorted: bool) -> &mut Self {
+        self.sorted = sorted;
+        self
+    }
+
+    /// Name shown in the genome browser, written in the bedGraph track line
+    pub fn track_name<S: Into<String>>(&mut self, track_name: S) -> &mut Self {
+        self.track_name = Some(track_name.into());
+        self
+    }
+
+    /// Also write a bigWig file, using chromosome sizes from the .fai index of
+    /// the genome
+    pub fn bigwig<P, Q>(&mut self, output: P, genome: Q) -> &mut Self
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.bigwig = Some((output.as_ref().to_owned(), genome.as_ref().to_owned()));
+        self
+    }
+
+    /// Write the bedGraph to output, or stdout if there is none
+    pub fn run<P: AsRef<Path>>(&self, input: &Path, output: Option<P>) -> Result<()> {
+        // Check early so a missing genome doesn't waste a long aggregation
+        let chrom_sizes = match &self.bigwig {
+            Some((_, genome)) => Some(read_chrom_sizes(genome)?),
+            None => None,
+        };
+        let mut sink = BedGraphSink {
+            min_coverage: self.min_coverage,
+            entries: BTreeMap::new(),
+        };
+        let sink = match TrackInput::detect(input)? {
+            TrackInput::Scored => {
+                self.aggregate_scores(input, &mut sink)?;
+                sink
+            }
+            TrackInput::Sma => {
+                let mut agg = AggOptions::default();
+                agg.whole_nucleosomes(true).sorted(self.sorted);
+                agg.aggregate(input, sink)?
+            }
+        };
+
+        let mut writer = BufWriter::new(stdout_or_file(output.as_ref())?);
+        let track_name = self.track_name.as_deref().unwrap_or("cawlr_track");
+        writeln!(writer, "track type=bedGraph name=\"{track_name}\"")?;
+        for (chrom, entries) in sink.entries.iter() {
+            for entry in entries {
+                writeln!(
+                    writer,
+                    "{chrom}\t{}\t{}\t{}",
+                    entry.start, entry.end, entry.value
+                )?;
+            }
+        }
+        writer.flush()?;
+
+        if let (Some((bigwig, _)), Some(chrom_sizes)) = (&self.bigwig, chrom_sizes) {
+            let writer = BufWriter::new(create_output(bigwig)?);
+            BigWigWriter::new(chrom_sizes).write(writer, &sink.entries)?;
+        }
+        Ok(())
+    }
+
+    /// Count the reads with a score and the reads modified at each position
+    fn aggregate_scores(&self, input: &Path, sink: &mut BedGraphSink) -> Result<()> {
+        let mod_file = ModFile::open_path(input, self.mod_tag.clone())?;
+        let mut counts: BTreeMap<String, BTreeMap<u64, (u64, u64)>> = BTreeMap::new();
+        read_mod_bam_or_arrow(mod_file, |read| {
+            if read.is_unaligned() {
+                return Ok(());
+            }
+            let chrom_counts = counts.entry(read.chrom().to_string()).or_default();
+            for score in read.scores() {
+                let (n_modified, n_scored) = chrom_counts.entry(score.pos).or_default();
+                *n_scored += 1;
+                if score.score > self.threshold {
+                    *n_modified += 1;
+                }
+            }
+            Ok(())
+        })?;
+        for (chrom, chrom_counts) in counts {
+            for (pos, (n_modified, n_scored)) in chrom_counts {
+                sink.position(&chrom, pos, "", "", n_modified, n_scored)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_chrom_sizes(genome: &Path) -> Result<Vec<(String, u32)>> {
+    let genome = IndexedReader::from_file(&genome).map_err(|_| {
+        eyre::eyre!(
+            "Failed to read genome index for {}, create it with samtools faidx",
+            genome.display()
+        )
+    })?;
+    chrom_lens(&genome)
+        .into_iter()
+        .map(|(chrom, len)| {
+            let len = u32::try_from(len)
+                .map_err(|_| eyre::eyre!("{chrom} is too long for a bigWig file"))?;
+            Ok((chrom, len))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        arrow::sma_read::{BlockState, SmaBlock},
+        test_data::{test_score, write_reads, MiniGenome, TestRead},
+    };
+
+    fn read_bedgraph(path: &Path) -> Result<Vec<String>> {
+        Ok(std::fs::read_to_string(path)?
+            .lines()
+            .skip(1)
+            .map(String::from)
+            .collect())
+    }
+
+    #[test]
+    fn test_scored_track() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("scores.arrow");
+        let reads = [[0.9, 0.9, 0.1], [0.9, 0.1, 0.1]]
+            .iter()
+            .enumerate()
+            .map(|(i, scores)| {
+                let scores = scores
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &s)| test_score(10 + j as u64, s))
+                    .collect();
+                TestRead::new(format!("read{i}")).scored(scores)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let output = mini.dir().join("track.bedgraph");
+        let bigwig = mini.dir().join("track.bw");
+        let mut opts = TrackOptions::default();
+        opts.bigwig(&bigwig, mini.genome());
+        opts.run(&input, Some(&output))?;
+        assert_eq!(
+            read_bedgraph(&output)?,
+            ["chrI\t10\t11\t1", "chrI\t11\t12\t0.5", "chrI\t12\t13\t0"]
+        );
+        assert!(std::fs::metadata(&bigwig)?.len() > 0);
+
+        let output = mini.dir().join("covered.bedgraph");
+        opts.min_coverage(3);
+        opts.run(&input, Some(&output))?;
+        assert!(read_bedgraph(&output)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sma_track() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input = mini.dir().join("sma.arrow");
+        let reads = [BlockState::nucleosome(), BlockState::linker()]
+            .into_iter()
+            .enumerate()
+            .map(|(i, state)| {
+                let blocks = vec![
+                    SmaBlock::new(20, 2, state),
+                    SmaBlock::new(22, 2, BlockState::nucleosome()),
+                ];
+                TestRead::new(format!("read{i}"))
+                    .chrom("chrII")
+                    .span(20, 4)
+                    .sma(blocks)
+            })
+            .collect::<Vec<_>>();
+        write_reads(&input, &reads)?;
+
+        let output = mini.dir().join("track.bedgraph");
+        TrackOptions::default().run(&input, Some(&output))?;
+        assert_eq!(
+            read_bedgraph(&output)?,
+            ["chrII\t20\t22\t0.5", "chrII\t22\t24\t1"]
+        );
+        Ok(())
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{Debug, Display},
     fs::File,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use bio::io::fasta::IndexedReader;
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use fnv::{FnvHashMap, FnvHashSet};
+use itertools::Itertools;
 use linfa::{
     traits::{Fit, Transformer},
     DatasetBase, ParamGuard,
@@ -18,14 +19,28 @@ use ndarray::Array;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use rv::prelude::{Gaussian, Mixture};
 use serde::{Deserialize, Serialize};
-
-use crate::arrow::{
-    arrow_utils::load_apply,
-    eventalign::Eventalign,
-    metadata::{MetadataExt, Strand},
+use thiserror::Error;
+
+use crate::{
+    arrow::{
+        arrow_utils::{has_samples, load_apply_batched, n_chunks, read_mode, ArrowError, ReadMode},
+        eventalign::Eventalign,
+        kmer::{rna_to_dna, Kmer, KmerError},
+        metadata::{MetadataExt, Strand},
+        signal::Signal,
+    },
+    cancel,
+    context::{GenomeCache, SeqCache},
+    filter::RegionFilter,
+    input::{open_genome_with_fai, ReadSeek},
+    kmer_map::KmerMap,
+    motif::{KMER_SIZE, RNA_KMER_SIZE},
+    progress::{ProgressSink, Reporter, Stage},
+    repro,
+    utils::{self, take_error, ChromAlias, FaiIndex},
 };
 
-pub(crate) type ModelDB = FnvHashMap<String, ModelParams>;
+pub(crate) type ModelDB = KmerMap<ModelParams>;
 type KmerMeans = FnvHashMap<String, Vec<f64>>;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -108,94 +123,346 @@ impl<T: Borrow<Mixture<Gaussian>>> From<T> for ModelParams {
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Model {
     gmms: ModelDB,
-    // skips: FnvHashMap<String, f64>,
+    /// Fraction of positions with each kmer that had signal data, models
+    /// trained before this was added will have none.
+    #[serde(default)]
+    skips: KmerMap<f64>,
+    /// Mode of the reads the model was trained on, models trained before this
+    /// was added are from DNA aligned to a genome.
+    #[serde(default)]
+    mode: ReadMode,
 }
 
 impl Model {
     pub(crate) fn new(gmms: ModelDB) -> Self {
-        Self { gmms, }
+        Self {
+            gmms,
+            skips: KmerMap::default(),
+            mode: ReadMode::default(),
+        }
+    }
+    /// Kmers with a trained model
+    pub fn kmers(&self) -> impl Iterator<Item = Kmer> + '_ {
+        self.gmms.keys()
+    }
+
+    /// Mixture of Gaussians fit to the current levels of a kmer
+    pub fn mixture<Q: AsRef<str> + ?Sized>(&self, kmer: &Q) -> Option<Mixture<Gaussian>> {
+        self.gmms.get(kmer).map(ModelParams::mixture)
     }
+
+    /// Fraction of positions with the kmer that had signal data
+    pub fn skip<Q: AsRef<str> + ?Sized>(&self, kmer: &Q) -> Option<f64> {
+        self.skips.get(kmer).copied()
+    }
+
     /// Get a reference to the model's gmms.
     pub(crate) fn gmms(&self) -> &ModelDB {
         &self.gmms
     }
 
     /// Get a reference to the model's skips.
-    // pub(crate) fn skips(&self) -> &FnvHashMap<String, f64> {
-    //     &self.skips
-    // }
+    pub(crate) fn skips(&self) -> &KmerMap<f64> {
+        &self.skips
+    }
 
-    pub(crate) fn insert_gmm(&mut self, kmer: String, gmm: Mixture<Gaussian>) {
+    pub(crate) fn insert_gmm(&mut self, kmer: Kmer, gmm: Mixture<Gaussian>) {
         let gmm = ModelParams::from(gmm);
         self.gmms.insert(kmer, gmm);
     }
+
+    pub(crate) fn insert_skip(&mut self, kmer: Kmer, presence: f64) {
+        self.skips.insert(kmer, presence);
+    }
+
+    /// Mode of the reads the model was trained on
+    pub fn mode(&self) -> ReadMode {
+        self.mode
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: ReadMode) {
+        self.mode = mode;
+    }
+
+    /// Check reads collapsed with mode can be scored with the model
+    pub fn check_mode(&self, mode: ReadMode) -> Result<(), TrainError> {
+        if self.mode == mode {
+            Ok(())
+        } else {
+            Err(TrainError::ModeMismatch {
+                model: self.mode,
+                reads: mode,
+            })
+        }
+    }
+}
+
+/// Why a kmer was left out of a model, see [KmerThresholds]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DegenerateKmer {
+    /// Number of values the kmer had
+    TooFewSamples(usize),
+    /// Sigma of a component that is zero, not finite, or over the maximum
+    BadSigma(f64),
+    /// Distance between the component means, in units of the larger sigma
+    ComponentsTooClose(f64),
 }
 
-// struct Skips {
-//     count: usize,
-//     total: usize,
-// }
-
-// impl Skips {
-//     fn new(count: usize, total: usize) -> Self {
-//         Self { count, total }
-//     }
-
-//     fn plus_both(&mut self) {
-//         self.total += 1;
-//         self.count += 1;
-//     }
-
-//     fn plus_total(&mut self) {
-//         self.total += 1;
-//     }
-
-//     fn had_score(&mut self, is_score: bool) {
-//         if is_score {
-//             self.plus_both();
-//         } else {
-//             self.plus_total();
-//         }
-//     }
-// }
-
-// impl Default for Skips {
-//     fn default() -> Self {
-//         Skips::new(0, 0)
-//     }
-// }
-
-// struct KmerSkips(FnvHashMap<Vec<u8>, Skips>);
-
-// impl KmerSkips {
-//     fn new() -> Self {
-//         Self(FnvHashMap::default())
-//     }
-// }
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+impl DegenerateKmer {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DegenerateKmer::TooFewSamples(_) => "too_few_samples",
+            DegenerateKmer::BadSigma(_) => "bad_sigma",
+            DegenerateKmer::ComponentsTooClose(_) => "components_too_close",
+        }
+    }
+}
+
+impl Display for DegenerateKmer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DegenerateKmer::TooFewSamples(n) => write!(f, "only {n} samples"),
+            DegenerateKmer::BadSigma(sigma) => write!(f, "component with sigma {sigma:.3}"),
+            DegenerateKmer::ComponentsTooClose(sep) => {
+                write!(f, "components {sep:.3} sigmas apart")
+            }
+        }
+    }
+}
+
+/// Checks on the values and fit of each kmer, so kmers that would give
+/// degenerate Gaussians are left out of the model instead of breaking
+/// scoring. By default only too few values or a component with a sigma of
+/// zero are rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KmerThresholds {
+    min_samples: usize,
+    max_sigma: f64,
+    min_separation: f64,
+}
+
+impl Default for KmerThresholds {
+    fn default() -> Self {
+        KmerThresholds {
+            min_samples: 2,
+            max_sigma: f64::INFINITY,
+            min_separation: 0.0,
+        }
+    }
+}
+
+impl KmerThresholds {
+    /// Thresholds from the command line options shared by cawlr train and
+    /// cawlr npsmlr train, where no max_sigma allows any sigma
+    pub fn new(min_samples: usize, max_sigma: Option<f64>, min_separation: f64) -> Self {
+        KmerThresholds {
+            min_samples,
+            max_sigma: max_sigma.unwrap_or(f64::INFINITY),
+            min_separation,
+        }
+    }
+
+    /// Fewest values a kmer needs to be trained
+    pub fn min_samples(&mut self, min_samples: usize) -> &mut Self {
+        self.min_samples = min_samples;
+        self
+    }
+
+    /// Largest sigma allowed for either component, ie to leave out kmers
+    /// dominated by noise
+    pub fn max_sigma(&mut self, max_sigma: f64) -> &mut Self {
+        self.max_sigma = max_sigma;
+        self
+    }
+
+    /// Smallest distance between the means of the two components, in units
+    /// of the larger sigma. Near-identical components can't tell modified
+    /// from unmodified signal.
+    pub fn min_separation(&mut self, min_separation: f64) -> &mut Self {
+        self.min_separation = min_separation;
+        self
+    }
+
+    pub fn check_samples(&self, n_samples: usize) -> Result<(), DegenerateKmer> {
+        if n_samples < self.min_samples {
+            Err(DegenerateKmer::TooFewSamples(n_samples))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_fit(&self, mix: &Mixture<Gaussian>) -> Result<(), DegenerateKmer> {
+        let components = mix.components();
+        for gauss in components.iter() {
+            let sigma = gauss.sigma();
+            if !sigma.is_finite() || sigma <= 0.0 || sigma > self.max_sigma {
+                return Err(DegenerateKmer::BadSigma(sigma));
+            }
+        }
+        if let [a, b] = components {
+            let separation = (a.mu() - b.mu()).abs() / a.sigma().max(b.sigma());
+            if separation < self.min_separation {
+                return Err(DegenerateKmer::ComponentsTooClose(separation));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Log how many kmers were left out of a model for each reason, and each kmer
+/// at debug level
+pub(crate) fn report_degenerate(degenerate: &[(String, DegenerateKmer)]) {
+    if degenerate.is_empty() {
+        return;
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (kmer, reason) in degenerate.iter() {
+        log::debug!("Skipped kmer {kmer}: {reason}");
+        *counts.entry(reason.reason()).or_default() += 1;
+    }
+    let counts = counts
+        .iter()
+        .map(|(reason, n)| format!("{n} {reason}"))
+        .join(", ");
+    log::warn!("Left {} kmers out of the model: {counts}", degenerate.len());
+}
+
+#[derive(Default)]
+struct Skips {
+    count: usize,
+    total: usize,
+}
+
+impl Skips {
+    fn had_score(&mut self, is_score: bool) {
+        self.total += 1;
+        if is_score {
+            self.count += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct KmerSkips(FnvHashMap<Vec<u8>, Skips>);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum TrainStrategy {
     AvgSample,
     AllSamples,
+    /// Median of the samples in each event, robust to spikes from the open
+    /// pore
+    Median,
+    /// Mean of the samples in each event after removing this fraction of the
+    /// lowest and highest samples
+    TrimmedMean(f32),
 }
 
 impl Display for TrainStrategy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let res = match self {
-            Self::AvgSample => "avg",
-            Self::AllSamples => "all",
-        };
-        write!(f, "{res}")
+        match self {
+            Self::AvgSample => write!(f, "avg"),
+            Self::AllSamples => write!(f, "all"),
+            Self::Median => write!(f, "median"),
+            Self::TrimmedMean(trim) => write!(f, "trimmed:{trim}"),
+        }
+    }
+}
+
+/// Median of values, None if empty
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    } else {
+        Some(values[mid])
+    }
+}
+
+/// Mean after dropping the trim fraction of values from each end, None if
+/// nothing is left
+fn trimmed_mean(values: &mut [f64], trim: f32) -> Option<f64> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n_trim = (values.len() as f64 * trim as f64).floor() as usize;
+    let kept = values.get(n_trim..values.len().saturating_sub(n_trim))?;
+    if kept.is_empty() {
+        None
+    } else {
+        Some(kept.iter().sum::<f64>() / kept.len() as f64)
+    }
+}
+
+/// Failures training a model, returned by [Train::run] and the functions that
+/// set up training
+#[derive(Error, Debug)]
+pub enum TrainError {
+    #[error(
+        "The {strategy} strategy needs raw samples but {} was collapsed with --no-samples, use \
+         the avg strategy or collapse again with --samples",
+        input.display()
+    )]
+    MissingSamples {
+        strategy: TrainStrategy,
+        input: PathBuf,
+    },
+    #[error("No reads in {} to train a model with", input.display())]
+    EmptyModel { input: PathBuf },
+    #[error(
+        "Model was trained on {model} reads but the reads are {reads}, collapse with the same \
+         --mode"
+    )]
+    ModeMismatch { model: ReadMode, reads: ReadMode },
+    #[error(
+        "Training on RNA needs reads collapsed with --mode rna or --mode transcriptome, {} is \
+         DNA",
+        input.display()
+    )]
+    NotRna { input: PathBuf },
+    #[error(transparent)]
+    InvalidKmer(#[from] KmerError),
+    #[error(transparent)]
+    Arrow(ArrowError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(eyre::Report),
+}
+
+impl From<ArrowError> for TrainError {
+    fn from(e: ArrowError) -> Self {
+        match e {
+            ArrowError::Io(e) => TrainError::Io(e),
+            ArrowError::Other(e) => e.into(),
+            e => TrainError::Arrow(e),
+        }
+    }
+}
+
+impl From<eyre::Report> for TrainError {
+    fn from(e: eyre::Report) -> Self {
+        take_error::<TrainError>(e)
+            .or_else(|e| take_error::<ArrowError>(e).map(TrainError::from))
+            .or_else(|e| take_error::<std::io::Error>(e).map(TrainError::Io))
+            .unwrap_or_else(TrainError::Other)
     }
 }
 
 pub struct Train {
     acc: KmerMeans,
-    // skips: KmerSkips,
-    genome: IndexedReader<File>,
+    skips: KmerSkips,
+    genome: SeqCache<Box<dyn ReadSeek>>,
     feather: PathBuf,
     samples: usize,
     strat: TrainStrategy,
+    rna: bool,
+    kmer_size: usize,
+    thresholds: KmerThresholds,
+    regions: RegionFilter,
+    batch_size: Option<usize>,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
 }
 
 impl Train {
@@ -204,24 +471,100 @@ impl Train {
         genome: Q,
         samples: usize,
         strat: TrainStrategy,
-    ) -> Result<Self, eyre::Error>
+    ) -> Result<Self, TrainError>
     where
         P: AsRef<Path>,
         Q: AsRef<Path> + Debug,
     {
-        let genome =
-            IndexedReader::from_file(&genome).map_err(|_| eyre::eyre!("Failed to read genome."))?;
+        Self::try_new_with_fai(filename, genome, &FaiIndex::default(), samples, strat)
+    }
+
+    /// Like [Train::try_new], with where the .fai index of the genome is and
+    /// whether to build it, see [utils::ensure_faidx].
+    pub fn try_new_with_fai<P, Q>(
+        filename: P,
+        genome: Q,
+        fai: &FaiIndex,
+        samples: usize,
+        strat: TrainStrategy,
+    ) -> Result<Self, TrainError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path> + Debug,
+    {
+        let genome = genome.as_ref();
+        let fai_path = utils::ensure_faidx(genome, fai)?.ok_or_else(|| {
+            eyre::eyre!(
+                "Missing .fai index file for {}, run samtools faidx on the genome",
+                genome.display()
+            )
+        })?;
+        let genome = open_genome_with_fai(genome, fai_path)?;
+        let genome = SeqCache::new(genome, GenomeCache::default())?;
         let feather = filename.as_ref().to_owned();
         Ok(Self {
             acc: FnvHashMap::default(),
-            // skips: KmerSkips::new(),
+            skips: KmerSkips::default(),
             genome,
             feather,
             samples,
             strat,
+            rna: false,
+            kmer_size: KMER_SIZE,
+            thresholds: KmerThresholds::default(),
+            regions: RegionFilter::default(),
+            batch_size: None,
+            progress_sink: None,
         })
     }
 
+    /// Checks kmers need to pass to be in the model, see [KmerThresholds]
+    pub fn thresholds(&mut self, thresholds: KmerThresholds) -> &mut Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Only train on reads in these regions, see [RegionFilter]
+    pub fn regions(&mut self, regions: RegionFilter) -> &mut Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Train on direct RNA reads, which need to be collapsed with the rna or
+    /// transcriptome mode. Uracil in kmers is read as thymine, and skips are
+    /// counted over the 5-mers of RNA pore models.
+    pub fn rna(&mut self, rna: bool) -> &mut Self {
+        self.rna = rna;
+        self.kmer_size = if rna { RNA_KMER_SIZE } else { KMER_SIZE };
+        self
+    }
+
+    /// Load at most this many reads at a time, see [load_apply_batched]
+    pub fn batch_size(&mut self, batch_size: Option<usize>) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Receive progress updates after each chunk of reads is processed
+    pub fn progress_sink(&mut self, progress_sink: Arc<dyn ProgressSink>) -> &mut Self {
+        self.progress_sink = Some(progress_sink);
+        self
+    }
+
+    /// How much of the genome to keep in memory, see [GenomeCache]. Preloading
+    /// reads the whole genome before returning.
+    pub fn genome_cache(&mut self, cache: GenomeCache) -> Result<&mut Self, TrainError> {
+        self.genome.set_cache(cache)?;
+        Ok(self)
+    }
+
+    /// Match chromosome names of reads to the genome through an alias file,
+    /// ie when reads are on "1" but the genome uses "chr1"
+    pub fn chrom_alias(&mut self, alias: ChromAlias) -> &mut Self {
+        self.genome.set_chrom_alias(alias);
+        self
+    }
+
     fn kmer_means_insufficient(&self) -> bool {
         self.acc.is_empty() || insufficient(&self.acc, self.samples)
     }
@@ -230,33 +573,93 @@ impl Train {
     //     self.skips.0.is_empty() || self.skips.0.values().any(|x| x.total < self.samples)
     // }
 
-    pub fn run(mut self) -> Result<Model> {
-        let file = File::open(&self.feather)?;
-        load_apply(file, |eventaligns| {
+    pub fn run(mut self) -> Result<Model, TrainError> {
+        let mut file = File::open(&self.feather)?;
+        if self.strat != TrainStrategy::AvgSample && !has_samples(&mut file)? {
+            return Err(TrainError::MissingSamples {
+                strategy: self.strat,
+                input: self.feather.clone(),
+            });
+        }
+        let mode = read_mode(&mut file)?;
+        if self.rna && mode == ReadMode::DnaGenome {
+            return Err(TrainError::NotRna {
+                input: self.feather.clone(),
+            });
+        }
+        self.regions
+            .check_chroms(|chrom| self.genome.has_chrom(chrom))
+            .wrap_err("Region is on a chromosome that is not in the genome")?;
+        let mut reporter = Reporter::new(Stage::Train, self.progress_sink.clone());
+        reporter.total_chunks(n_chunks(&mut file)?);
+        let mut contigs_checked = false;
+        let mut n_filtered = 0usize;
+        load_apply_batched(file, self.batch_size, |eventaligns: Vec<Eventalign>| {
+            cancel::check()?;
+            if !contigs_checked {
+                self.genome
+                    .check_contigs(eventaligns.iter().map(|e| e.chrom()))
+                    .wrap_err("Reads are on chromosomes that are not in the genome")?;
+                contigs_checked = true;
+            }
+            reporter.chunk(eventaligns.len());
             for eventalign in eventaligns.into_iter() {
+                if !self.regions.keep(&eventalign) {
+                    n_filtered += 1;
+                    continue;
+                }
                 if self.kmer_means_insufficient() {
                     match self.strat {
                         TrainStrategy::AvgSample => self.read_to_kmer_means(&eventalign),
                         TrainStrategy::AllSamples => self.read_to_kmer_samples(&eventalign),
+                        TrainStrategy::Median => self.read_to_kmer_reduced(&eventalign, median),
+                        TrainStrategy::TrimmedMean(trim) => {
+                            self.read_to_kmer_reduced(&eventalign, |xs| trimmed_mean(xs, trim))
+                        }
                     }
-                    // self.read_to_skip_counts(&eventalign)?;
+                    self.read_to_skip_counts(&eventalign)?;
                 }
             }
             Ok(())
         })?;
+        if !self.regions.is_empty() {
+            log::info!("Skipped {n_filtered} reads outside of training regions");
+        }
 
+        if self.acc.is_empty() {
+            return Err(TrainError::EmptyModel {
+                input: self.feather.clone(),
+            });
+        }
         // let mut gmms = self.acc;
-        let gmms = self
+        let thresholds = self.thresholds;
+        let trained = self
             .acc
             .into_par_iter()
-            .filter_map(|item| {
-                if let Ok(Some(gmm)) = train_gmm(item.1) {
-                    Some((item.0, ModelParams::from(gmm)))
-                } else {
-                    None
+            .filter_map(|(kmer, values)| {
+                if let Err(reason) = thresholds.check_samples(values.len()) {
+                    return Some(Err((kmer, reason)));
+                }
+                match train_gmm(values) {
+                    Ok(Some(gmm)) => match thresholds.check_fit(&gmm) {
+                        Ok(()) => Some(Ok((kmer, ModelParams::from(gmm)))),
+                        Err(reason) => Some(Err((kmer, reason))),
+                    },
+                    _ => None,
                 }
             })
-            .collect();
+            .collect::<Vec<_>>();
+        let mut gmms = ModelDB::default();
+        let mut degenerate = Vec::new();
+        for res in trained {
+            match res {
+                Ok((kmer, gmm)) => {
+                    gmms.insert(kmer.parse()?, gmm);
+                }
+                Err(skipped) => degenerate.push(skipped),
+            }
+        }
+        report_degenerate(&degenerate);
 
         // for (kmer, kmer_mean) in x {
         //     if kmer_mean.len() > 1 {
@@ -269,21 +672,30 @@ impl Train {
         //     }
         // }
 
-        // let mut ratios = FnvHashMap::default();
-        // for (kmer, skips) in self.skips.0.into_iter() {
-        //     let kmer = String::from_utf8(kmer)?;
-        //     let ratio = (skips.count as f64) / (skips.total as f64);
-        //     ratios.insert(kmer, ratio);
-        // }
-
-        let model = Model::new(gmms);
+        let mut model = Model::new(gmms);
+        model.set_mode(mode);
+        for (kmer, kmer_skips) in self.skips.0.into_iter() {
+            let kmer = Kmer::from_bytes(&kmer)?;
+            let ratio = (kmer_skips.count as f64) / (kmer_skips.total as f64);
+            model.insert_skip(kmer, ratio);
+        }
+        reporter.finish();
 
         Ok(model)
     }
 
+    /// Kmer of the signal, with uracil read as thymine for RNA
+    fn kmer(&self, signal: &Signal) -> String {
+        if self.rna {
+            rna_to_dna(&signal.kmer)
+        } else {
+            signal.kmer.clone()
+        }
+    }
+
     fn read_to_kmer_means(&mut self, read: &Eventalign) {
         for signal in read.signal_iter() {
-            let kmer = signal.kmer.clone();
+            let kmer = self.kmer(signal);
             let entry = self.acc.entry(kmer).or_default();
             if entry.len() > self.samples {
                 continue;
@@ -292,9 +704,28 @@ impl Train {
         }
     }
 
+    /// Reduce the samples of each event to a single value, falling back to the
+    /// event mean if there are no samples
+    fn read_to_kmer_reduced<F>(&mut self, read: &Eventalign, reduce: F)
+    where
+        F: Fn(&mut [f64]) -> Option<f64>,
+    {
+        let mut samples = Vec::new();
+        for signal in read.signal_iter() {
+            let kmer = self.kmer(signal);
+            let entry = self.acc.entry(kmer).or_default();
+            if entry.len() > self.samples {
+                continue;
+            }
+            samples.clear();
+            samples.extend_from_slice(&signal.samples);
+            entry.push(reduce(&mut samples).unwrap_or(signal.signal_mean));
+        }
+    }
+
     fn read_to_kmer_samples(&mut self, read: &Eventalign) {
         for signal in read.signal_iter() {
-            let kmer = signal.kmer.clone();
+            let kmer = self.kmer(signal);
             let entry = self.acc.entry(kmer).or_default();
             if entry.len() > self.samples {
                 continue;
@@ -303,37 +734,36 @@ impl Train {
         }
     }
 
-    // fn read_to_skip_counts(&mut self, read: &Eventalign) -> Result<()> {
-    //     let mut pos_scores = FnvHashSet::default();
-    //     for signal in read.signal_iter() {
-    //         pos_scores.insert(signal.pos);
-    //     }
-    //     let read_seq = self.get_read_seq(read)?;
-    //     for (kmer, pos) in read_seq.windows(6).zip(read.start_0b()..) {
-    //         let has_score = pos_scores.contains(&pos);
-    //         let kskip = self.skips.0.entry(kmer.to_owned()).or_default();
-    //         kskip.had_score(has_score);
-    //     }
-    //     Ok(())
-    // }
-
-    /// Get a mutable reference to the train's genome.
-    pub(crate) fn genome_mut(&mut self) -> &mut IndexedReader<File> {
-        &mut self.genome
+    /// Count how often each kmer in the reference covered by the read had
+    /// signal data.
+    fn read_to_skip_counts(&mut self, read: &Eventalign) -> Result<()> {
+        let mut pos_scores = FnvHashSet::default();
+        for signal in read.signal_iter() {
+            pos_scores.insert(signal.pos);
+        }
+        let read_seq = self.get_read_seq(read)?;
+        let n_kmers = (read_seq.len() + 1).saturating_sub(self.kmer_size);
+        for (idx, kmer) in read_seq.windows(self.kmer_size).enumerate() {
+            // Minus strand sequence is reverse complemented, so the first kmer
+            // is at the end of the read
+            let offset = if read.strand().is_minus_strand() {
+                n_kmers - 1 - idx
+            } else {
+                idx
+            };
+            let has_score = pos_scores.contains(&(read.start_0b() + offset as u64));
+            let kskip = self.skips.0.entry(kmer.to_owned()).or_default();
+            kskip.had_score(has_score);
+        }
+        Ok(())
     }
 
     // TODO: Use Context instead
-    // Genome fasta reader method makes clippy think its wrong but it is actually
-    // correct.
-    #[allow(clippy::read_zero_byte_vec)]
     fn get_read_seq(&mut self, read: &Eventalign) -> Result<Vec<u8>> {
         let strand = read.strand();
         let chrom = read.chrom();
         let start = read.start_0b();
-        self.genome_mut()
-            .fetch(chrom, start, read.seq_stop_1b_excl())?;
-        let mut seq = Vec::new();
-        self.genome_mut().read(&mut seq)?;
+        let seq = self.genome.fetch(chrom, start, read.seq_stop_1b_excl())?;
         let seq = if strand == Strand::plus() {
             seq
         } else {
@@ -393,6 +823,12 @@ fn train_gmm(means: Vec<f64>) -> Result<Option<Mixture<Gaussian>>> {
     let n_clusters = 2;
     let n_runs = 10;
     let tolerance = 1e-4f64;
+    let params = serde_json::json!({
+        "n_clusters": n_clusters,
+        "n_runs": n_runs,
+        "tolerance": tolerance,
+    });
+    repro::record_seed("train gmm init", repro::GMM_SEED, params);
     let gmm = GaussianMixtureModel::params(n_clusters)
         .n_runs(n_runs)
         .tolerance(tolerance)
@@ -436,6 +872,130 @@ mod test {
         assert!(insufficient(&dict, n))
     }
 
+    #[test]
+    fn test_sample_reduction() {
+        let mut samples = [80.0, 81.0, 160.0, 79.0, 82.0];
+        assert_eq!(median(&mut samples), Some(81.0));
+        assert_eq!(median(&mut [1.0, 2.0, 4.0, 3.0]), Some(2.5));
+        assert_eq!(median(&mut []), None);
+
+        let mut samples = [80.0, 81.0, 160.0, 79.0, 82.0, 10.0];
+        assert_eq!(trimmed_mean(&mut samples, 0.2), Some(80.5));
+        assert_eq!(trimmed_mean(&mut samples, 0.0), Some(82.0));
+        assert_eq!(trimmed_mean(&mut [1.0, 2.0], 0.5), None);
+        assert_eq!(trimmed_mean(&mut [], 0.1), None);
+    }
+
+    #[test]
+    fn test_train_skips() -> Result<()> {
+        let mini = crate::test_data::MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        let mut collapse = crate::collapse::CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let train = Train::try_new(&collapsed, mini.genome(), 50, TrainStrategy::AvgSample)?;
+        let model = train.run()?;
+        assert!(!model.skips().is_empty());
+        assert!(model.skips().values().all(|x| (0.0..=1.0).contains(x)));
+        // Most kmers in the read have signal data
+        assert!(model.skips().values().any(|&x| x > 0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_no_samples() -> Result<()> {
+        let mini = crate::test_data::MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        let mut collapse = crate::collapse::CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.samples(false);
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let train = Train::try_new(&collapsed, mini.genome(), 50, TrainStrategy::AllSamples)?;
+        let err = train.run().err().unwrap();
+        assert!(matches!(err, TrainError::MissingSamples { .. }));
+        let train = Train::try_new(&collapsed, mini.genome(), 50, TrainStrategy::AvgSample)?;
+        assert!(!train.run()?.skips().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_rna() -> Result<()> {
+        let mini = crate::test_data::MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed");
+        crate::collapse::CollapseOptions::try_new(mini.bam(), &collapsed)?
+            .run(File::open(mini.eventalign())?)?;
+        let mut train = Train::try_new(&collapsed, mini.genome(), 50, TrainStrategy::AvgSample)?;
+        train.rna(true);
+        let err = train.run().err().unwrap();
+        assert!(matches!(err, TrainError::NotRna { .. }));
+
+        let rna = mini.dir().join("rna");
+        crate::collapse::CollapseOptions::try_new(mini.bam(), &rna)?
+            .mode(ReadMode::Rna)
+            .run(File::open(mini.eventalign())?)?;
+        let mut train = Train::try_new(&rna, mini.genome(), 50, TrainStrategy::AvgSample)?;
+        train.rna(true);
+        let model = train.run()?;
+        assert_eq!(model.mode(), ReadMode::Rna);
+        assert!(model.skips().keys().all(|kmer| kmer.len() == RNA_KMER_SIZE));
+        assert!(model.check_mode(ReadMode::Rna).is_ok());
+        assert!(matches!(
+            model.check_mode(ReadMode::DnaGenome),
+            Err(TrainError::ModeMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_kmer_thresholds() {
+        let mix = |mu_b: f64, sigma_b: f64| {
+            Mixture::new_unchecked(
+                vec![0.5, 0.5],
+                vec![
+                    Gaussian::new_unchecked(80.0, 2.0),
+                    Gaussian::new_unchecked(mu_b, sigma_b),
+                ],
+            )
+        };
+        let mut thresholds = KmerThresholds::default();
+        assert!(thresholds.check_samples(2).is_ok());
+        assert!(thresholds.check_fit(&mix(90.0, 4.0)).is_ok());
+        assert!(thresholds.check_fit(&mix(80.0, 2.0)).is_ok());
+        assert_eq!(
+            thresholds.check_fit(&mix(90.0, 0.0)),
+            Err(DegenerateKmer::BadSigma(0.0))
+        );
+
+        thresholds
+            .min_samples(10)
+            .max_sigma(3.0)
+            .min_separation(1.0);
+        assert_eq!(
+            thresholds.check_samples(5),
+            Err(DegenerateKmer::TooFewSamples(5))
+        );
+        assert_eq!(
+            thresholds.check_fit(&mix(90.0, 4.0)),
+            Err(DegenerateKmer::BadSigma(4.0))
+        );
+        assert_eq!(
+            thresholds.check_fit(&mix(81.0, 2.0)),
+            Err(DegenerateKmer::ComponentsTooClose(0.5))
+        );
+        assert!(thresholds.check_fit(&mix(90.0, 2.0)).is_ok());
+
+        let from_args = KmerThresholds::new(10, None, 1.0);
+        assert_eq!(
+            from_args.check_samples(5),
+            Err(DegenerateKmer::TooFewSamples(5))
+        );
+        assert!(from_args.check_fit(&mix(90.0, 4.0)).is_ok());
+        assert_eq!(
+            from_args.check_fit(&mix(81.0, 2.0)),
+            Err(DegenerateKmer::ComponentsTooClose(0.5))
+        );
+    }
+
     #[test]
     fn test_model_params() {
         let g1 = Gaussian::new_unchecked(1., 2.);
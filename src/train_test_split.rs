@@ -0,0 +1,311 @@
+//! Split reads into training and test sets by genomic partition, so models are
+//! evaluated on reads from loci that weren't used to train them.
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Seek, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use arrow2_convert::{deserialize::ArrowDeserialize, field::ArrowField, serialize::ArrowSerialize};
+use eyre::Result;
+use fnv::FnvHashSet;
+
+use crate::{
+    arrow::{
+        arrow_utils::{load_apply, save_t, SchemaExt},
+        metadata::MetadataExt,
+    },
+    region::Region,
+    utils::create_output,
+};
+
+/// How chromosomes are numbered in the genome, for [Partition::OddEven]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Numbering {
+    /// Decimal numbers, ie chr2, where chrX and chrY are sex chromosomes
+    Decimal,
+    /// Roman numerals, ie chrII in the yeast genome, where chrX is chromosome
+    /// 10
+    Roman,
+}
+
+/// Which reads are held out for testing, the rest are used for training.
+#[derive(Debug, Clone)]
+pub enum Partition {
+    /// Reads on odd numbered chromosomes are used for training and even
+    /// numbered chromosomes for testing. Chromosomes without a number, like
+    /// chrM, are used for training.
+    OddEven(Numbering),
+    /// Reads on these chromosomes are held out
+    Chroms(Vec<String>),
+    /// Reads overlapping any of these regions are held out
+    Regions(Vec<Region>),
+}
+
+impl Partition {
+    pub fn is_test<M: MetadataExt + ?Sized>(&self, meta: &M) -> bool {
+        match self {
+            Partition::OddEven(numbering) => {
+                chrom_number(meta.chrom(), *numbering).map_or(false, |n| n % 2 == 0)
+            }
+            Partition::Chroms(chroms) => chroms.iter().any(|c| c == meta.chrom()),
+            Partition::Regions(regions) => regions.iter().any(|r| r.valid(meta)),
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Partition::OddEven(Numbering::Decimal) => write!(f, "odd-even"),
+            Partition::OddEven(Numbering::Roman) => write!(f, "odd-even:roman"),
+            Partition::Chroms(chroms) => write!(f, "chroms:{}", chroms.join(",")),
+            Partition::Regions(regions) => {
+                let regions = regions.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                write!(f, "regions:{}", regions.join(","))
+            }
+        }
+    }
+}
+
+/// Parses "odd-even", "odd-even:roman", "chroms:chrII,chrIV", or
+/// "regions:chrI:1-1000,..."
+impl FromStr for Partition {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "odd-even" => return Ok(Partition::OddEven(Numbering::Decimal)),
+            "odd-even:roman" => return Ok(Partition::OddEven(Numbering::Roman)),
+            _ => (),
+        }
+        if let Some(chroms) = s.strip_prefix("chroms:") {
+            let chroms = chroms
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            if chroms.is_empty() {
+                eyre::bail!("No chromosomes given to hold out");
+            }
+            return Ok(Partition::Chroms(chroms));
+        }
+        if let Some(regions) = s.strip_prefix("regions:") {
+            let regions = regions
+                .split(',')
+                .filter(|r| !r.is_empty())
+                .map(Region::from_str)
+                .collect::<Result<Vec<_>, _>>()?;
+            if regions.is_empty() {
+                eyre::bail!("No regions given to hold out");
+            }
+            return Ok(Partition::Regions(regions));
+        }
+        Err(eyre::eyre!(
+            "Invalid partition {s}, expected odd-even, odd-even:roman, chroms:<chrom,...>, \
+             or regions:<chrom:start-stop,...>"
+        ))
+    }
+}
+
+/// Number of a chromosome from its name, ie 2 for chr2 or 2, or for chrII
+/// with roman numerals
+fn chrom_number(chrom: &str, numbering: Numbering) -> Option<u64> {
+    let name = chrom_suffix(chrom);
+    match numbering {
+        Numbering::Decimal => name.parse::<u64>().ok(),
+        Numbering::Roman => roman_numeral(&name.to_ascii_lowercase()),
+    }
+}
+
+/// Name of the chromosome without a chr or chromosome prefix
+fn chrom_suffix(chrom: &str) -> &str {
+    let lower = chrom.to_ascii_lowercase();
+    let prefix = ["chromosome", "chr"]
+        .into_iter()
+        .find(|prefix| lower.starts_with(prefix))
+        .map_or(0, str::len);
+    chrom[prefix..].trim_start_matches(|c| c == '_' || c == '-')
+}
+
+/// Value of a roman numeral made of I, V, and X, which covers the chromosomes
+/// of genomes numbered this way
+fn roman_numeral(s: &str) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    let values = s
+        .chars()
+        .map(|c| match c {
+            'i' => Some(1),
+            'v' => Some(5),
+            'x' => Some(10),
+            _ => None,
+        })
+        .collect::<Option<Vec<u64>>>()?;
+    let mut total: i64 = 0;
+    for (idx, &value) in values.iter().enumerate() {
+        match values.get(idx + 1) {
+            Some(&next) if next > value => total -= value as i64,
+            _ => total += value as i64,
+        }
+    }
+    u64::try_from(total).ok().filter(|&n| n > 0)
+}
+
+/// Number of reads written to each set by [split]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SplitCounts {
+    pub train: usize,
+    pub test: usize,
+}
+
+/// Write each read of an Arrow file to either the training or test output,
+/// depending on the partition.
+pub fn split<R, W, T>(reader: R, train: W, test: W, partition: &Partition) -> Result<SplitCounts>
+where
+    R: Read + Seek,
+    W: Write,
+    T: ArrowField<Type = T> + ArrowDeserialize + ArrowSerialize + SchemaExt + MetadataExt + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    let mut train = T::wrap_writer(train)?;
+    let mut test = T::wrap_writer(test)?;
+    let mut counts = SplitCounts::default();
+    let mut unnumbered = FnvHashSet::default();
+    let res = load_apply(reader, |reads: Vec<T>| {
+        let (test_reads, train_reads): (Vec<T>, Vec<T>) =
+            reads.into_iter().partition(|read| partition.is_test(read));
+        if let Partition::OddEven(numbering) = partition {
+            for read in train_reads.iter() {
+                if chrom_number(read.chrom(), *numbering).is_none()
+                    && unnumbered.insert(read.chrom().to_string())
+                {
+                    log::warn!(
+                        "{} has no chromosome number, using it for training",
+                        read.chrom()
+                    );
+                    if *numbering == Numbering::Decimal
+                        && chrom_number(read.chrom(), Numbering::Roman).is_some()
+                    {
+                        log::warn!("Use odd-even:roman if chromosomes are roman numerals");
+                    }
+                }
+            }
+        }
+        counts.train += train_reads.len();
+        counts.test += test_reads.len();
+        save_t(&mut train, &train_reads)?;
+        save_t(&mut test, &test_reads)
+    });
+    // Finish even if splitting failed, so chunks already written stay readable
+    train.finish()?;
+    test.finish()?;
+    res?;
+    log::info!(
+        "Split by {partition}: {} training reads, {} test reads",
+        counts.train,
+        counts.test
+    );
+    Ok(counts)
+}
+
+/// Split an Arrow file into training and test files, see [split]
+pub fn split_files<T, P, Q, S>(
+    input: P,
+    train: Q,
+    test: S,
+    partition: &Partition,
+) -> Result<SplitCounts>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    S: AsRef<Path>,
+    T: ArrowField<Type = T> + ArrowDeserialize + ArrowSerialize + SchemaExt + MetadataExt + 'static,
+    for<'a> &'a <T as ArrowDeserialize>::ArrayType: IntoIterator,
+{
+    let reader = File::open(input)?;
+    let train = create_output(train)?;
+    let test = create_output(test)?;
+    split::<_, _, T>(reader, train, test, partition)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{arrow::eventalign::Eventalign, collapse::CollapseOptions, test_data::MiniGenome};
+
+    #[test]
+    fn test_chrom_number() {
+        use Numbering::*;
+        assert_eq!(chrom_number("chr2", Decimal), Some(2));
+        assert_eq!(chrom_number("17", Decimal), Some(17));
+        assert_eq!(chrom_number("chrII", Decimal), None);
+        assert_eq!(chrom_number("chrX", Decimal), None);
+        assert_eq!(chrom_number("chrY", Decimal), None);
+        assert_eq!(chrom_number("chrM", Decimal), None);
+
+        assert_eq!(chrom_number("chrII", Roman), Some(2));
+        assert_eq!(chrom_number("chrXIV", Roman), Some(14));
+        assert_eq!(chrom_number("Chromosome_IX", Roman), Some(9));
+        assert_eq!(chrom_number("chrX", Roman), Some(10));
+        assert_eq!(chrom_number("chrM", Roman), None);
+        assert_eq!(chrom_number("ChrC", Roman), None);
+        assert_eq!(chrom_number("chr2", Roman), None);
+    }
+
+    #[test]
+    fn test_parse_partition() -> Result<()> {
+        assert!(matches!(
+            "odd-even".parse::<Partition>()?,
+            Partition::OddEven(Numbering::Decimal)
+        ));
+        let roman = "odd-even:roman".parse::<Partition>()?;
+        assert_eq!(roman.to_string(), "odd-even:roman");
+        let chroms = "chroms:chrII,chrIV".parse::<Partition>()?;
+        assert!(matches!(&chroms, Partition::Chroms(c) if c == &["chrII", "chrIV"]));
+        let regions = "regions:chrI:1-100,chrII:5-10".parse::<Partition>()?;
+        assert_eq!(regions.to_string(), "regions:chrI:1-100,chrII:5-10");
+        assert!("chroms:".parse::<Partition>().is_err());
+        assert!("random".parse::<Partition>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let collapsed = mini.dir().join("collapsed.arrow");
+        let mut collapse = CollapseOptions::try_new(mini.bam(), &collapsed)?;
+        collapse.run(File::open(mini.eventalign())?)?;
+
+        let names = |path: &Path| -> Result<Vec<String>> {
+            let mut names = Vec::new();
+            load_apply(File::open(path)?, |reads: Vec<Eventalign>| {
+                names.extend(reads.iter().map(|r| r.name().to_string()));
+                Ok(())
+            })?;
+            Ok(names)
+        };
+
+        let train = mini.dir().join("train.arrow");
+        let test = mini.dir().join("test.arrow");
+        let partition = Partition::OddEven(Numbering::Roman);
+        let counts = split_files::<Eventalign, _, _, _>(&collapsed, &train, &test, &partition)?;
+        assert_eq!(counts, SplitCounts { train: 1, test: 1 });
+        assert_eq!(names(&train)?, ["plus-read"]);
+        assert_eq!(names(&test)?, ["minus-read"]);
+
+        let partition = "regions:chrI:140-200".parse()?;
+        let counts = split::<_, _, Eventalign>(
+            File::open(&collapsed)?,
+            File::create(&train)?,
+            File::create(&test)?,
+            &partition,
+        )?;
+        assert_eq!(counts, SplitCounts { train: 1, test: 1 });
+        assert_eq!(names(&test)?, ["plus-read"]);
+        Ok(())
+    }
+}
@@ -1,24 +1,294 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
     fs::File,
-    hash::{BuildHasher, Hash},
-    io::{stdout, Read, Seek, Write},
+    hash::{BuildHasher, Hash, Hasher},
+    io::{stdout, BufRead, BufReader, BufWriter, Read, Seek, Write},
     path::{Path, PathBuf},
-    process::Output,
-    time::Duration,
+    process::{Command, Output},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bio::io::fasta::IndexedReader;
 use eyre::{Context, Result};
-use fnv::FnvHashMap;
+use flate2::read::MultiGzDecoder;
+use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
 use indicatif::{ProgressBar, ProgressStyle};
+use itertools::Itertools;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_pickle::from_reader;
 use which::which;
 
-use crate::train::Model;
+use crate::{cancel, kmer_map::KmerMap, train::Model};
+
+/// Whether [create_output] may replace existing files, see [allow_overwrite]
+static ALLOW_OVERWRITE: AtomicBool = AtomicBool::new(true);
+
+/// Set whether outputs created with [create_output] may replace existing,
+/// non-empty files. Overwriting is allowed by default, the cawlr binary turns
+/// it off unless --force is passed.
+pub fn allow_overwrite(allow: bool) {
+    ALLOW_OVERWRITE.store(allow, Ordering::Relaxed);
+}
+
+/// Create an output file, failing if it already exists and isn't empty unless
+/// overwriting is allowed with [allow_overwrite]. Every command output should
+/// be created with this instead of [File::create].
+pub fn create_output<P: AsRef<Path>>(path: P) -> Result<File> {
+    create_output_with(path.as_ref(), ALLOW_OVERWRITE.load(Ordering::Relaxed))
+}
+
+fn create_output_with(path: &Path, allow_overwrite: bool) -> Result<File> {
+    let is_nonempty_file = path
+        .metadata()
+        .map(|m| m.is_file() && m.len() > 0)
+        .unwrap_or(false);
+    if is_nonempty_file && !allow_overwrite {
+        eyre::bail!(
+            "Output {} already exists, use --force to overwrite it",
+            path.display()
+        );
+    }
+    File::create(path).wrap_err_with(|| format!("Failed to create {}", path.display()))
+}
+
+/// Environment variable for the directory temporary files are written to,
+/// also set by the --tmp-dir flag of the cawlr binary
+pub const TMP_DIR_ENV: &str = "CAWLR_TMP_DIR";
+
+/// Prefix of the directories created by [temp_dir], followed by the host name
+/// and process id
+const TMP_PREFIX: &str = "cawlr-";
+
+/// Write temporary files to this directory instead of the one from
+/// CAWLR_TMP_DIR or the system temporary directory. Should be called before
+/// any threads are started.
+pub fn set_tmp_dir<P: AsRef<Path>>(dir: P) {
+    std::env::set_var(TMP_DIR_ENV, dir.as_ref());
+}
+
+/// Directory temporary files are written to, see [set_tmp_dir]
+pub fn tmp_dir() -> PathBuf {
+    std::env::var_os(TMP_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Create a directory in [tmp_dir] that is removed, along with everything in
+/// it, when dropped. This includes returning early from errors or Ctrl-C, and
+/// directories left by crashes are removed by [clean_stale_tmp].
+pub fn temp_dir() -> Result<tempfile::TempDir> {
+    temp_dir_in(&tmp_dir())
+}
+
+fn temp_dir_in(dir: &Path) -> Result<tempfile::TempDir> {
+    std::fs::create_dir_all(dir)
+        .wrap_err_with(|| format!("Failed to create temporary directory {}", dir.display()))?;
+    tempfile::Builder::new()
+        .prefix(&format!(
+            "{TMP_PREFIX}{}-{}-",
+            hostname(),
+            std::process::id()
+        ))
+        .tempdir_in(dir)
+        .wrap_err_with(|| format!("Failed to create temporary directory in {}", dir.display()))
+}
+
+/// Remove directories from [temp_dir] whose cawlr process is no longer
+/// running, ie it was killed or crashed. Returns the number removed. Only
+/// directories created on this host are checked, so jobs on other nodes
+/// sharing the directory, ie on NFS or cluster scratch, are left alone.
+pub fn clean_stale_tmp() -> Result<usize> {
+    clean_stale_tmp_in(&tmp_dir(), &hostname())
+}
+
+fn clean_stale_tmp_in(dir: &Path, host: &str) -> Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(0),
+    };
+    let mut n_removed = 0;
+    for entry in entries.flatten() {
+        let pid = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(TMP_PREFIX))
+            .and_then(|rest| tmp_host_pid(rest, host));
+        let path = entry.path();
+        if let Some(pid) = pid {
+            if path.is_dir() && !is_running(pid) {
+                log::info!("Removing stale temporary directory {}", path.display());
+                std::fs::remove_dir_all(&path)?;
+                n_removed += 1;
+            }
+        }
+    }
+    Ok(n_removed)
+}
+
+/// Process id of a [temp_dir] name without the prefix, ie
+/// "node-01-1234-a1b2c3", if it was created on host. Host names can contain
+/// dashes, the process id and random suffix can't.
+fn tmp_host_pid(name: &str, host: &str) -> Option<u32> {
+    let mut parts = name.rsplitn(3, '-');
+    let _suffix = parts.next()?;
+    let pid = parts.next()?.parse::<u32>().ok()?;
+    (parts.next()? == host).then_some(pid)
+}
+
+/// Name of this host, which [temp_dir] directories are named after
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    if res == 0 && len > 0 {
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    } else {
+        "localhost".to_string()
+    }
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks if the process exists, EPERM means it exists but
+    // belongs to another user
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, every process is assumed to still be running
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// Where the .fai index of a local genome is, and whether it's built when
+/// it's missing. By default the index is next to the genome and is built, see
+/// [ensure_faidx].
+#[derive(Debug, Clone)]
+pub struct FaiIndex {
+    path: Option<PathBuf>,
+    build: bool,
+}
+
+impl Default for FaiIndex {
+    fn default() -> Self {
+        FaiIndex {
+            path: None,
+            build: true,
+        }
+    }
+}
+
+impl FaiIndex {
+    /// Index at path instead of next to the genome, it has to exist
+    pub fn path<P: Into<PathBuf>>(&mut self, path: Option<P>) -> &mut Self {
+        self.path = path.map(Into::into);
+        self
+    }
+
+    /// Whether a missing index is built, the cawlr binary turns it off with
+    /// --no-build-fai
+    pub fn build(&mut self, build: bool) -> &mut Self {
+        self.build = build;
+        self
+    }
+}
+
+/// Find the .fai index of a local genome fasta, either the path set on fai or
+/// the .fai next to the genome. A missing index is built if [FaiIndex::build]
+/// is set, next to the genome or in [tmp_dir] if the genome's directory is
+/// read-only. Returns None if there is no index and building is turned off.
+pub fn ensure_faidx<P: AsRef<Path>>(genome: P, fai: &FaiIndex) -> Result<Option<PathBuf>> {
+    let genome = genome.as_ref();
+    if let Some(fai_path) = fai.path.as_ref() {
+        if !fai_path.exists() {
+            eyre::bail!("Genome index {} not found", fai_path.display());
+        }
+        return Ok(Some(fai_path.clone()));
+    }
+    let mut fai_path = genome.as_os_str().to_owned();
+    fai_path.push(".fai");
+    let fai_path = PathBuf::from(fai_path);
+    if fai_path.exists() {
+        return Ok(Some(fai_path));
+    }
+    let tmp_fai = tmp_faidx_path(genome);
+    if is_newer(&tmp_fai, genome) {
+        return Ok(Some(tmp_fai));
+    }
+    if !fai.build {
+        return Ok(None);
+    }
+    log::info!("Building missing genome index {}", fai_path.display());
+    let index = noodles::fasta::index(genome)
+        .wrap_err_with(|| format!("Failed to index genome {}", genome.display()))?;
+    if let Err(e) = write_faidx(&index, &fai_path) {
+        log::warn!(
+            "Can't write {} ({e}), building the genome index at {} instead",
+            fai_path.display(),
+            tmp_fai.display()
+        );
+        std::fs::create_dir_all(tmp_dir())?;
+        write_faidx(&index, &tmp_fai)
+            .wrap_err_with(|| format!("Failed to write {}", tmp_fai.display()))?;
+        return Ok(Some(tmp_fai));
+    }
+    Ok(Some(fai_path))
+}
+
+/// Where the index of a genome in a read-only directory is built, named after
+/// the full path of the genome so genomes with the same file name don't
+/// share an index
+fn tmp_faidx_path(genome: &Path) -> PathBuf {
+    let genome = genome
+        .canonicalize()
+        .unwrap_or_else(|_| genome.to_path_buf());
+    let mut hasher = FnvHasher::default();
+    hasher.write(genome.as_os_str().to_string_lossy().as_bytes());
+    let name = genome
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    tmp_dir().join(format!("{:016x}.{name}.fai", hasher.finish()))
+}
+
+/// Whether path exists and was modified after other
+fn is_newer(path: &Path, other: &Path) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    match (modified(path), modified(other)) {
+        (Some(path), Some(other)) => path >= other,
+        _ => false,
+    }
+}
+
+/// Write the index to a temporary file next to fai and rename it into place,
+/// so other runs never see a partially written index
+fn write_faidx(index: &noodles::fasta::fai::Index, fai: &Path) -> std::io::Result<()> {
+    let dir = match fai.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut writer = noodles::fasta::fai::io::Writer::new(BufWriter::new(tmp.as_file_mut()));
+        writer.write_index(index)?;
+        writer.get_mut().flush()?;
+    }
+    tmp.persist(fai).map_err(|e| e.error)?;
+    Ok(())
+}
 
 /// Allows for writing to File or Stdout depending on if a filename is given.
+/// Files ending in .gz or .bgz are compressed with BGZF like bgzip, so they
+/// can be read with any gzip reader and indexed with tabix.
 ///
 /// TODO: Maybe return with the BufWriter wrapping the trait object, like
 /// BufWriter<Box<dyn Write>> instead of the how we have now.
@@ -27,14 +297,80 @@ where
     P: AsRef<Path>,
 {
     if let Some(fp) = filename {
-        let handle = File::create(fp)?;
-        Ok(Box::new(handle))
+        let handle = create_output(fp)?;
+        if is_gzip_path(fp.as_ref()) {
+            Ok(Box::new(noodles::bgzf::Writer::new(handle)))
+        } else {
+            Ok(Box::new(handle))
+        }
     } else {
         let handle = stdout();
         Ok(Box::new(handle))
     }
 }
 
+/// Open a text file, ie a bed file or a list of read names, decompressing it
+/// if it starts with the gzip magic bytes. bgzip files are multi-member gzip
+/// files so they are handled the same way.
+pub fn open_maybe_gz<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>> {
+    let path = path.as_ref();
+    let mut file =
+        File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut magic = [0u8; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(path)?;
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Take the error out of a report if it is an E, so the typed errors of the
+/// library can be recovered from shared code that returns [eyre::Report].
+/// Errors with context added by [WrapErr](eyre::WrapErr) are left in the
+/// report so the context isn't lost.
+pub(crate) fn take_error<E>(e: eyre::Report) -> Result<E, eyre::Report>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if e.chain().next().map_or(false, |top| top.is::<E>()) {
+        e.downcast()
+    } else {
+        Err(e)
+    }
+}
+
+/// Index a bgzip compressed bed file from [stdout_or_file] with tabix, ie
+/// sample.bed.gz.tbi. The bed file needs to be sorted by chromosome and start.
+pub fn tabix_bed<P: AsRef<Path>>(path: P, tabix_path: &Option<PathBuf>) -> Result<()> {
+    let path = path.as_ref();
+    if !is_gzip_path(path) {
+        return Err(eyre::eyre!(
+            "Indexing with tabix requires an output ending in .gz, found {}",
+            path.display()
+        ));
+    }
+    let tabix = find_binary("tabix", tabix_path)?;
+    let output = Command::new(tabix)
+        .args(["-f", "-p", "bed"])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(eyre::eyre!(
+            "tabix failed to index {}, is it sorted by position? {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext == "gz" || ext == "bgz")
+}
+
 pub trait CawlrIO {
     fn save<W: Write>(&self, writer: &mut W) -> Result<()>;
     fn save_as<P>(&self, filename: P) -> Result<()>
@@ -60,7 +396,7 @@ where
     where
         P: AsRef<Path>,
     {
-        let mut file = File::create(filename)?;
+        let mut file = create_output(filename)?;
         serde_pickle::to_writer(&mut file, &self, Default::default())?;
         Ok(())
     }
@@ -75,6 +411,34 @@ where
     }
 }
 
+impl<V> CawlrIO for KmerMap<V>
+where
+    V: Serialize + DeserializeOwned,
+{
+    fn save<W: Write>(&self, writer: &mut W) -> Result<()> {
+        serde_pickle::to_writer(writer, self, Default::default())?;
+        Ok(())
+    }
+
+    fn save_as<P>(&self, filename: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = create_output(filename)?;
+        serde_pickle::to_writer(&mut file, &self, Default::default())?;
+        Ok(())
+    }
+
+    fn load<P>(filename: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(filename)?;
+        let kmer_map = from_reader(file, Default::default())?;
+        Ok(kmer_map)
+    }
+}
+
 impl CawlrIO for Model {
     fn save<W: Write>(&self, writer: &mut W) -> Result<()> {
         serde_pickle::to_writer(writer, self, Default::default())?;
@@ -85,7 +449,7 @@ impl CawlrIO for Model {
     where
         P: AsRef<Path>,
     {
-        let mut file = File::create(filename)?;
+        let mut file = create_output(filename)?;
         serde_pickle::to_writer(&mut file, &self, Default::default())?;
         Ok(())
     }
@@ -114,6 +478,158 @@ where
     chrom_lens
 }
 
+/// Groups of names for the same chromosome, ie "chr1", "1", and "NC_000001.11",
+/// read from a UCSC style chromAlias file. Every tab-separated field on a line
+/// is a name for the same chromosome, and lines starting with '#' are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ChromAlias {
+    groups: Vec<Vec<String>>,
+    group_idx: FnvHashMap<String, usize>,
+}
+
+impl ChromAlias {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .wrap_err_with(|| format!("Failed to open {}", path.as_ref().display()))?;
+        ChromAlias::from_reader(BufReader::new(file))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut alias = ChromAlias::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let names = line
+                .split('\t')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            for name in names.iter() {
+                alias.group_idx.insert(name.clone(), alias.groups.len());
+            }
+            alias.groups.push(names);
+        }
+        Ok(alias)
+    }
+
+    /// Every name for the chromosome, including the name itself
+    pub(crate) fn aliases<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        let group = self.group_idx.get(name).map(|&idx| &self.groups[idx]);
+        std::iter::once(name).chain(group.into_iter().flatten().map(String::as_str))
+    }
+}
+
+/// Chromosome names from one input matched to the names used by a reference,
+/// ie the genome or the BAM header, see [check_contig_compatibility]
+#[derive(Debug, Clone, Default)]
+pub struct ContigMap {
+    reference: FnvHashSet<String>,
+    alias: Option<ChromAlias>,
+    resolved: FnvHashMap<String, Option<String>>,
+}
+
+impl ContigMap {
+    fn new(reference: FnvHashSet<String>, alias: Option<ChromAlias>) -> Self {
+        ContigMap {
+            reference,
+            alias,
+            resolved: FnvHashMap::default(),
+        }
+    }
+
+    /// Name the reference uses for the contig, None if it isn't in the
+    /// reference under any of its aliases. Contigs not seen when the map was
+    /// created are warned about the first time they are missing.
+    pub fn resolve(&mut self, contig: &str) -> Option<&str> {
+        if !self.resolved.contains_key(contig) {
+            let resolved = self.lookup(contig);
+            if resolved.is_none() {
+                log::warn!("{}", self.missing_message(&[contig]));
+            }
+            self.resolved.insert(contig.to_string(), resolved);
+        }
+        self.resolved[contig].as_deref()
+    }
+
+    fn lookup(&self, contig: &str) -> Option<String> {
+        match &self.alias {
+            Some(alias) => alias
+                .aliases(contig)
+                .find(|name| self.reference.contains(*name))
+                .map(String::from),
+            None => self.reference.contains(contig).then(|| contig.to_string()),
+        }
+    }
+
+    /// Explain which contigs are missing, suggesting an alias file if the
+    /// naming differs by a "chr" prefix
+    fn missing_message(&self, missing: &[&str]) -> String {
+        let mut msg = format!(
+            "Contigs missing from the reference: {}",
+            missing.iter().take(10).join(", ")
+        );
+        if missing.len() > 10 {
+            msg.push_str(&format!(" and {} more", missing.len() - 10));
+        }
+        let prefix_swapped = |contig: &str| match contig.strip_prefix("chr") {
+            Some(stripped) => stripped.to_string(),
+            None => format!("chr{contig}"),
+        };
+        if missing
+            .iter()
+            .any(|contig| self.reference.contains(&prefix_swapped(contig)))
+        {
+            msg.push_str(
+                ". The reference names differ by a \"chr\" prefix, pass a chromosome alias \
+                 file with --chrom-alias to match them",
+            );
+        }
+        msg
+    }
+}
+
+/// Compare the contig names of an input against the names in a reference
+/// before processing, matching names through the alias file if there is one.
+/// Missing contigs are warned about, and if none of the contigs are in the
+/// reference it is an error since every read would be skipped.
+pub fn check_contig_compatibility<'a, 'b, I, J>(
+    reference: I,
+    contigs: J,
+    alias: Option<&ChromAlias>,
+) -> Result<ContigMap>
+where
+    I: IntoIterator<Item = &'a str>,
+    J: IntoIterator<Item = &'b str>,
+{
+    let reference = reference.into_iter().map(String::from).collect();
+    let mut contig_map = ContigMap::new(reference, alias.cloned());
+    let mut n_found = 0;
+    let mut missing = Vec::new();
+    for contig in contigs {
+        if contig_map.resolved.contains_key(contig) {
+            continue;
+        }
+        let resolved = contig_map.lookup(contig);
+        if resolved.is_some() {
+            n_found += 1;
+        } else {
+            missing.push(contig);
+        }
+        contig_map.resolved.insert(contig.to_string(), resolved);
+    }
+    if !missing.is_empty() {
+        let msg = contig_map.missing_message(&missing);
+        if n_found == 0 {
+            return Err(eyre::eyre!(msg));
+        }
+        log::warn!("{msg}");
+    }
+    Ok(contig_map)
+}
+
 pub fn find_binary(name: &'static str, binary_filepath: &Option<PathBuf>) -> eyre::Result<PathBuf> {
     if let Some(p) = binary_filepath {
         Ok(p.to_path_buf())
@@ -122,33 +638,146 @@ pub fn find_binary(name: &'static str, binary_filepath: &Option<PathBuf>) -> eyr
     }
 }
 
-pub fn wrap_cmd<F>(msg: &'static str, mut f: F) -> eyre::Result<()>
+/// A single step of a pipeline, written as one line of JSON to the log set with
+/// [json_log_to].
+#[derive(Debug, Serialize)]
+struct StepEvent {
+    step: &'static str,
+    commands: Vec<String>,
+    /// Seconds since the UNIX epoch
+    start: f64,
+    end: f64,
+    success: bool,
+    /// Step already completed in a previous run
+    skipped: bool,
+    /// Step stopped early by an interrupt, partial outputs are not reused
+    cancelled: bool,
+    exit_codes: Vec<Option<i32>>,
+    outputs: Vec<PathBuf>,
+    error: Option<String>,
+}
+
+struct JsonLog {
+    writer: File,
+    current: Option<StepEvent>,
+}
+
+thread_local! {
+    // Pipelines run each step sequentially from the calling thread
+    static JSON_LOG: RefCell<Option<JsonLog>> = RefCell::new(None);
+}
+
+fn unix_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn with_current_step<F: FnOnce(&mut StepEvent)>(f: F) {
+    JSON_LOG.with(|log| {
+        if let Some(step) = log
+            .borrow_mut()
+            .as_mut()
+            .and_then(|log| log.current.as_mut())
+        {
+            f(step);
+        }
+    })
+}
+
+/// Stops writing the JSON log started by [json_log_to] when dropped, and goes
+/// back to the log that was being written before it, if any.
+#[must_use = "the JSON log stops when the guard is dropped"]
+pub struct JsonLogGuard {
+    previous: Option<JsonLog>,
+}
+
+impl Drop for JsonLogGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        JSON_LOG.with(|log| *log.borrow_mut() = previous);
+    }
+}
+
+/// Write structured events for each step run with [wrap_cmd] or
+/// [wrap_cmd_output] on the current thread as JSON lines to the given file,
+/// until the returned guard is dropped.
+pub fn json_log_to<P: AsRef<Path>>(path: P) -> Result<JsonLogGuard> {
+    let writer = File::create(path)?;
+    let previous = JSON_LOG.with(|log| {
+        log.replace(Some(JsonLog {
+            writer,
+            current: None,
+        }))
+    });
+    Ok(JsonLogGuard { previous })
+}
+
+/// Log the external command that is about to be run, and add it to the
+/// current step if writing a JSON log.
+pub fn record_cmd(cmd: &Command) {
+    log::info!("{cmd:?}");
+    with_current_step(|step| step.commands.push(format!("{cmd:?}")));
+}
+
+/// Add an output file to the current step if writing a JSON log.
+pub fn record_output<P: AsRef<Path>>(path: P) {
+    with_current_step(|step| step.outputs.push(path.as_ref().to_path_buf()));
+}
+
+fn start_step(msg: &'static str) {
+    JSON_LOG.with(|log| {
+        if let Some(log) = log.borrow_mut().as_mut() {
+            log.current = Some(StepEvent {
+                step: msg,
+                commands: Vec::new(),
+                start: unix_secs(),
+                end: 0.0,
+                success: false,
+                skipped: false,
+                cancelled: false,
+                exit_codes: Vec::new(),
+                outputs: Vec::new(),
+                error: None,
+            });
+        }
+    })
+}
+
+fn finish_step<U>(res: &eyre::Result<U>, skipped: bool) {
+    JSON_LOG.with(|log| {
+        if let Some(log) = log.borrow_mut().as_mut() {
+            if let Some(mut step) = log.current.take() {
+                step.end = unix_secs();
+                step.success = res.is_ok();
+                step.skipped = skipped;
+                step.cancelled = res.is_err() && cancel::is_cancelled();
+                step.error = res.as_ref().err().map(|e| format!("{e:#}"));
+                let written = serde_json::to_writer(&mut log.writer, &step)
+                    .map_err(eyre::Error::from)
+                    .and_then(|_| Ok(writeln!(log.writer)?));
+                if let Err(e) = written {
+                    log::warn!("Failed to write JSON log: {e}");
+                }
+            }
+        }
+    })
+}
+
+pub fn wrap_cmd<F>(msg: &'static str, f: F) -> eyre::Result<()>
 where
     F: FnMut() -> eyre::Result<()>,
 {
-    let p = ProgressBar::new_spinner()
-        .with_style(
-            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {msg}").unwrap(),
-        )
-        .with_message(msg);
-    p.enable_steady_tick(Duration::from_millis(100));
-    // f()?;
-    // p.finish_with_message(format!("✅ \"{}\" complete", msg));
-    // Ok(())
-
-    if let Ok(()) = f() {
-        p.finish_with_message(format!("✅ \"{}\" complete", msg));
-        Ok(())
-    } else {
-        p.finish_with_message(format!("❌ \"{}\" failed", msg));
-        Err(eyre::eyre!("Previous command failed, check log.txt"))
-    }
+    wrap_cmd_output(msg, f)
 }
 
 pub fn wrap_cmd_output<F, U>(msg: &'static str, mut f: F) -> eyre::Result<U>
 where
     F: FnMut() -> eyre::Result<U>,
 {
+    // Don't start new steps after an interrupt
+    cancel::check()?;
     let p = ProgressBar::new_spinner()
         .with_style(
             ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {msg}").unwrap(),
@@ -159,17 +788,37 @@ where
     // p.finish_with_message(format!("✅ \"{}\" complete", msg));
     // Ok(())
 
-    if let Ok(u) = f() {
+    start_step(msg);
+    let res = f();
+    finish_step(&res, false);
+    if let Ok(u) = res {
         p.finish_with_message(format!("✅ \"{}\" complete", msg));
         Ok(u)
+    } else if cancel::is_cancelled() {
+        p.finish_with_message(format!("🛑 \"{}\" cancelled", msg));
+        Err(cancel::Cancelled.into())
     } else {
         p.finish_with_message(format!("❌ \"{}\" failed", msg));
         Err(eyre::eyre!("Previous command failed, check log.txt"))
     }
 }
 
+/// Report a step that was skipped because it already completed, along with
+/// the outputs from the previous run.
+pub fn skip_cmd<P: AsRef<Path>>(msg: &'static str, outputs: &[P]) {
+    log::info!("Skipping \"{msg}\", already completed");
+    start_step(msg);
+    outputs.iter().for_each(record_output);
+    finish_step(&Ok(()), true);
+    let p = ProgressBar::new_spinner()
+        .with_style(ProgressStyle::with_template("{msg}").unwrap())
+        .with_message(format!("⏩ \"{}\" already complete, skipping", msg));
+    p.finish();
+}
+
 pub fn check_if_failed(output: Output) -> eyre::Result<()> {
     log::info!("{}", String::from_utf8_lossy(&output.stderr));
+    with_current_step(|step| step.exit_codes.push(output.status.code()));
     if output.status.success() {
         Ok(())
     } else {
@@ -189,3 +838,171 @@ pub fn parse_name_from_output_dir<P: AsRef<Path>>(path: P) -> eyre::Result<Strin
         .ok_or(eyre::eyre!("Invalid path name"))?;
     Ok(name.to_string())
 }
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::test_data::MiniGenome;
+
+    #[test]
+    fn test_create_output() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("output.txt");
+        create_output_with(&path, false)?;
+        // Empty files from a failed run can be replaced
+        create_output_with(&path, false)?.write_all(b"results")?;
+        assert!(create_output_with(&path, false).is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "results");
+        create_output_with(&path, true)?;
+        assert_eq!(std::fs::read_to_string(&path)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_stale_tmp() -> Result<()> {
+        let root = TempDir::new()?;
+        let live = temp_dir_in(root.path())?;
+        assert!(live.path().starts_with(root.path()));
+        // Process ids are never this large on Linux
+        let host = hostname();
+        let stale = root.path().join(format!("{TMP_PREFIX}{host}-4294967-abc"));
+        std::fs::create_dir(&stale)?;
+        std::fs::write(stale.join("npsmlr.db"), "data")?;
+        let other = root.path().join("other");
+        std::fs::create_dir(&other)?;
+        // Can't tell if jobs on other hosts are still running
+        let other_host = root
+            .path()
+            .join(format!("{TMP_PREFIX}{host}-other-4294967-abc"));
+        std::fs::create_dir(&other_host)?;
+
+        assert_eq!(clean_stale_tmp_in(root.path(), &host)?, 1);
+        assert!(!stale.exists());
+        assert!(live.path().exists());
+        assert!(other.exists());
+        assert!(other_host.exists());
+        assert_eq!(tmp_host_pid("node-01-1234-abc", "node-01"), Some(1234));
+        assert_eq!(tmp_host_pid("1234-abc", "node-01"), None);
+
+        let live_path = live.path().to_path_buf();
+        drop(live);
+        assert!(!live_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stdout_or_file_gzip() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("output.bed.gz");
+        let line = "chrI\t100\t200\tread\n";
+        {
+            let mut writer = stdout_or_file(Some(&path))?;
+            for _ in 0..10_000 {
+                writer.write_all(line.as_bytes())?;
+            }
+        }
+        // Ends with the empty block marking the end of a BGZF file
+        let bytes = std::fs::read(&path)?;
+        assert!(bytes.ends_with(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]));
+        let mut decoded = String::new();
+        flate2::read::MultiGzDecoder::new(bytes.as_slice()).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, line.repeat(10_000));
+
+        let path = temp_dir.path().join("output.bed");
+        stdout_or_file(Some(&path))?.write_all(line.as_bytes())?;
+        assert_eq!(std::fs::read_to_string(&path)?, line);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_faidx() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let genome = mini.dir().join("no_index.fa");
+        std::fs::copy(mini.genome(), &genome)?;
+        let mut fai = FaiIndex::default();
+        assert_eq!(ensure_faidx(&genome, fai.build(false))?, None);
+
+        // Same index as samtools faidx
+        let built = ensure_faidx(&genome, fai.build(true))?.unwrap();
+        assert_eq!(built, mini.dir().join("no_index.fa.fai"));
+        assert_eq!(
+            std::fs::read_to_string(built)?,
+            std::fs::read_to_string(mini.dir().join("genome.fa.fai"))?
+        );
+        // Only the index is left, not the temporary file it was written to
+        assert_eq!(std::fs::read_dir(mini.dir())?.count(), 6);
+
+        let elsewhere = mini.dir().join("elsewhere.fai");
+        assert!(ensure_faidx(&genome, fai.path(Some(&elsewhere))).is_err());
+        std::fs::write(&elsewhere, "")?;
+        assert_eq!(ensure_faidx(&genome, &fai)?, Some(elsewhere));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tabix_bed_needs_gzip() {
+        let res = tabix_bed("output.bed", &Some(PathBuf::from("tabix")));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_check_contig_compatibility() -> Result<()> {
+        let reference = ["chr1", "chr2", "chrM"];
+        let err = check_contig_compatibility(reference, ["1", "2"], None).unwrap_err();
+        assert!(err.to_string().contains("--chrom-alias"));
+
+        let mut contigs = check_contig_compatibility(reference, ["chr1", "3"], None)?;
+        assert_eq!(contigs.resolve("chr1"), Some("chr1"));
+        assert_eq!(contigs.resolve("3"), None);
+
+        let alias = ChromAlias::from_reader(
+            "# ucsc\tassembly\tgenbank\nchr1\t1\tCM000663.2\nchr2\t2\tCM000664.2\nchrM\tMT\tJ01415.2\n"
+                .as_bytes(),
+        )?;
+        let mut contigs = check_contig_compatibility(reference, ["1", "2"], Some(&alias))?;
+        assert_eq!(contigs.resolve("1"), Some("chr1"));
+        assert_eq!(contigs.resolve("MT"), Some("chrM"));
+        assert_eq!(contigs.resolve("chr2"), Some("chr2"));
+        assert_eq!(contigs.resolve("chrY"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_log() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let log_path = temp_dir.path().join("log.jsonl");
+        let guard = json_log_to(&log_path)?;
+
+        wrap_cmd("echo", || {
+            let mut cmd = Command::new("echo");
+            cmd.arg("hello");
+            record_cmd(&cmd);
+            record_output("hello.txt");
+            check_if_failed(cmd.output()?)
+        })?;
+        let res = wrap_cmd("fail", || Err(eyre::eyre!("failed on purpose")));
+        assert!(res.is_err());
+        drop(guard);
+        wrap_cmd("after run", || Ok(()))?;
+
+        let log = std::fs::read_to_string(&log_path)?;
+        let events: Vec<serde_json::Value> = log
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["step"], "echo");
+        assert_eq!(events[0]["success"], true);
+        assert_eq!(events[0]["exit_codes"][0], 0);
+        assert_eq!(events[0]["outputs"][0], "hello.txt");
+        assert!(events[0]["commands"][0].as_str().unwrap().contains("hello"));
+        assert_eq!(events[1]["success"], false);
+        assert_eq!(events[1]["error"], "failed on purpose");
+        Ok(())
+    }
+}
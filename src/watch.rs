@@ -0,0 +1,452 @@
+//! Collapse and score eventalign output as it is produced, ie while a flow
+//! cell is still sequencing.
+//!
+//! Input comes either from chunk files appearing in a directory or from an
+//! unbounded eventalign stream. Each chunk is collapsed and scored into its own
+//! Arrow file in the output directory, so results can be read while watching
+//! continues:
+//!
+//! - `collapsed/{chunk}.arrow` and `scored/{chunk}.arrow`, scored files only
+//!   appear once they are complete
+//! - `watch.tsv`, the number of reads, scored positions, and mean score of each
+//!   chunk for quality control
+//! - `watch.done`, the chunks already processed, so watching the same
+//!   directory again resumes where it left off
+//!
+//! A chunk that fails to be processed is logged and skipped, so one malformed
+//! file doesn't stop the watch. It isn't recorded as done, so watching again
+//! retries it.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use eyre::{Result, WrapErr};
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::{
+    arrow::{arrow_utils::load_apply, scored_read::ScoredRead},
+    cancel,
+    collapse::{CollapseError, CollapseOptions},
+    npsmlr::ScoreOptions,
+    utils::create_output,
+};
+
+const DONE_FILE: &str = "watch.done";
+const SUMMARY_FILE: &str = "watch.tsv";
+const SUMMARY_HEADER: &str = "chunk\treads\tscored_positions\tmean_score";
+
+/// Prefix of the chunks split from a stream, followed by their number
+const STREAM_PREFIX: &str = "stream_";
+
+/// Quality control statistics of one scored chunk
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ChunkSummary {
+    reads: usize,
+    scored_positions: usize,
+    mean_score: Option<f64>,
+}
+
+impl ChunkSummary {
+    fn from_scored<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut summary = ChunkSummary::default();
+        let mut total = 0.0;
+        load_apply(File::open(path)?, |reads: Vec<ScoredRead>| {
+            for read in reads {
+                summary.reads += 1;
+                for score in read.scores().iter().filter(|s| !s.skipped) {
+                    summary.scored_positions += 1;
+                    total += score.score;
+                }
+            }
+            Ok(())
+        })?;
+        if summary.scored_positions > 0 {
+            summary.mean_score = Some(total / summary.scored_positions as f64);
+        }
+        Ok(summary)
+    }
+}
+
+/// Size and modification time, a file is considered finished once these
+/// don't change between polls
+type FileState = (u64, Option<SystemTime>);
+
+/// Incrementally collapses and scores chunks of eventalign output.
+pub struct WatchOptions {
+    scoring: ScoreOptions,
+    bam: PathBuf,
+    output_dir: PathBuf,
+    pattern: glob::Pattern,
+    interval: Duration,
+    idle_timeout: Option<Duration>,
+    batch_reads: usize,
+    done: FnvHashSet<String>,
+    /// Chunks that failed this run, not retried until watching again
+    failed: FnvHashSet<String>,
+}
+
+impl WatchOptions {
+    /// Create the output directory, resuming from the chunks already recorded
+    /// in it. The BAM is reread for each chunk, so it can be replaced with one
+    /// including newly aligned reads while watching.
+    pub fn try_new<P, Q>(scoring: ScoreOptions, bam: P, output_dir: Q) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let output_dir = output_dir.as_ref().to_path_buf();
+        fs::create_dir_all(output_dir.join("collapsed"))?;
+        fs::create_dir_all(output_dir.join("scored"))?;
+        let done = match fs::read_to_string(output_dir.join(DONE_FILE)) {
+            Ok(done) => done.lines().map(String::from).collect(),
+            Err(_) => FnvHashSet::default(),
+        };
+        if !done.is_empty() {
+            log::info!("Resuming after {} processed chunks", done.len());
+        }
+        let summary = output_dir.join(SUMMARY_FILE);
+        if !summary.exists() {
+            fs::write(&summary, format!("{SUMMARY_HEADER}\n"))?;
+        }
+        Ok(WatchOptions {
+            scoring,
+            bam: bam.as_ref().to_path_buf(),
+            output_dir,
+            pattern: glob::Pattern::new("*.eventalign.txt")?,
+            interval: Duration::from_secs(30),
+            idle_timeout: None,
+            batch_reads: 1000,
+            done,
+            failed: FnvHashSet::default(),
+        })
+    }
+
+    /// Only process files in the watched directory with names matching the
+    /// glob pattern, defaults to "*.eventalign.txt"
+    pub fn pattern(&mut self, pattern: &str) -> Result<&mut Self> {
+        self.pattern = glob::Pattern::new(pattern)
+            .wrap_err_with(|| format!("Invalid file name pattern {pattern}"))?;
+        Ok(self)
+    }
+
+    /// How often to check the directory for new files, and the longest a
+    /// stream is buffered before its reads are processed. Defaults to 30
+    /// seconds.
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Stop watching a directory once no new files have appeared for this long,
+    /// by default it is watched until interrupted
+    pub fn idle_timeout(&mut self, idle_timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Number of reads from a stream to process at once, defaults to 1000
+    pub fn batch_reads(&mut self, batch_reads: usize) -> &mut Self {
+        self.batch_reads = batch_reads.max(1);
+        self
+    }
+
+    /// Process files in the directory as they finish being written, until
+    /// interrupted or the idle timeout is reached
+    pub fn watch_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let mut last_seen: FnvHashMap<PathBuf, FileState> = FnvHashMap::default();
+        let mut last_activity = Instant::now();
+        loop {
+            cancel::check()?;
+            let mut ready = Vec::new();
+            let mut seen = FnvHashMap::default();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(name) if path.is_file() && self.pattern.matches(name) => name,
+                    _ => continue,
+                };
+                if self.done.contains(name) || self.failed.contains(name) {
+                    continue;
+                }
+                let metadata = path.metadata()?;
+                let state = (metadata.len(), metadata.modified().ok());
+                if last_seen.get(&path) == Some(&state) {
+                    ready.push(path);
+                } else {
+                    seen.insert(path, state);
+                }
+            }
+            last_seen = seen;
+
+            ready.sort();
+            if !ready.is_empty() {
+                last_activity = Instant::now();
+            }
+            for path in ready {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                let res = File::open(&path)
+                    .map_err(eyre::Report::from)
+                    .and_then(|file| self.process(&name, file));
+                self.skip_failed(&name, res)?;
+            }
+
+            if let Some(idle_timeout) = self.idle_timeout {
+                if last_seen.is_empty() && last_activity.elapsed() >= idle_timeout {
+                    log::info!("No new files for {idle_timeout:?}, stopping");
+                    return Ok(());
+                }
+            }
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Split an eventalign stream into chunks of reads and process each,
+    /// until the stream ends. Chunks are cut between reads once there are
+    /// enough reads or the interval has passed since the last chunk.
+    pub fn watch_stream<R: BufRead>(&mut self, mut reader: R) -> Result<()> {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(CollapseError::NoData.into());
+        }
+        let read_col = header
+            .trim_end()
+            .split('\t')
+            .position(|col| col == "read_name" || col == "read_index")
+            .ok_or_else(|| eyre::eyre!("Eventalign header has no read_name or read_index"))?;
+
+        // Chunks that failed aren't done, so continue after the highest number
+        // used rather than the number of chunks done
+        let mut n_chunk = self
+            .done
+            .iter()
+            .chain(&self.failed)
+            .filter_map(|name| name.strip_prefix(STREAM_PREFIX)?.parse::<usize>().ok())
+            .max()
+            .map_or(0, |n| n + 1);
+        let mut chunk = header.clone().into_bytes();
+        let mut n_reads = 0;
+        let mut last_read = String::new();
+        let mut last_flush = Instant::now();
+        for line in reader.lines() {
+            cancel::check()?;
+            let line = line?;
+            let read = line.split('\t').nth(read_col).unwrap_or_default();
+            if read != last_read {
+                if n_reads >= self.batch_reads
+                    || (n_reads > 0 && last_flush.elapsed() >= self.interval)
+                {
+                    let name = format!("{STREAM_PREFIX}{n_chunk:05}");
+                    let res = self.process(&name, Cursor::new(&chunk));
+                    self.skip_failed(&name, res)?;
+                    n_chunk += 1;
+                    chunk.truncate(header.len());
+                    n_reads = 0;
+                    last_flush = Instant::now();
+                }
+                n_reads += 1;
+                last_read = read.to_string();
+            }
+            chunk.extend_from_slice(line.as_bytes());
+            chunk.push(b'\n');
+        }
+        if n_reads > 0 {
+            let name = format!("{STREAM_PREFIX}{n_chunk:05}");
+            let res = self.process(&name, Cursor::new(&chunk));
+            self.skip_failed(&name, res)?;
+        }
+        Ok(())
+    }
+
+    /// Log a chunk that failed instead of stopping the watch, unless it failed
+    /// because of an interrupt
+    fn skip_failed(&mut self, name: &str, res: Result<()>) -> Result<()> {
+        match res {
+            Err(e) if !cancel::is_cancelled() => {
+                log::error!("Skipping {name}: {e:#}");
+                self.failed.insert(name.to_string());
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Collapse and score one chunk, then record it as done
+    fn process<R: Read>(&mut self, name: &str, eventalign: R) -> Result<()> {
+        let stem = name.strip_suffix(".txt").unwrap_or(name);
+        let collapsed = self
+            .output_dir
+            .join("collapsed")
+            .join(format!("{stem}.arrow"));
+        let scored = self.output_dir.join("scored").join(format!("{stem}.arrow"));
+        let partial = scored.with_extension("arrow.partial");
+        // Left over from a chunk that was interrupted
+        for path in [&collapsed, &scored, &partial] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        log::info!("Processing {name}");
+        let mut collapse = CollapseOptions::try_new(&self.bam, &collapsed)?;
+        collapse.progress(false);
+        match collapse.run(eventalign) {
+            Err(CollapseError::NoData) => {
+                log::warn!("{name} has no events, skipping");
+                return self.mark_done(name, &ChunkSummary::default());
+            }
+            res => res.wrap_err_with(|| format!("Failed to collapse {name}"))?,
+        }
+        let writer = BufWriter::new(create_output(&partial)?);
+        self.scoring
+            .run(File::open(&collapsed)?, writer)
+            .wrap_err_with(|| format!("Failed to score {name}"))?;
+        fs::rename(&partial, &scored)?;
+
+        let summary = ChunkSummary::from_scored(&scored)?;
+        self.mark_done(name, &summary)
+    }
+
+    fn mark_done(&mut self, name: &str, summary: &ChunkSummary) -> Result<()> {
+        let append = |file: &str| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.output_dir.join(file))
+        };
+        let mean_score = summary
+            .mean_score
+            .map_or_else(|| "NA".to_string(), |s| format!("{s:.4}"));
+        writeln!(
+            append(SUMMARY_FILE)?,
+            "{name}\t{}\t{}\t{mean_score}",
+            summary.reads,
+            summary.scored_positions
+        )?;
+        writeln!(append(DONE_FILE)?, "{name}")?;
+        log::info!(
+            "{name}: {} reads, {} scored positions, mean score {mean_score}",
+            summary.reads,
+            summary.scored_positions
+        );
+        self.done.insert(name.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::{motif::all_bases, rank::Ranks, test_data::MiniGenome, train::Model};
+
+    fn test_options(mini: &MiniGenome, output_dir: &Path) -> Result<WatchOptions> {
+        let scoring = ScoreOptions::new(
+            Model::default(),
+            Model::default(),
+            Ranks::default(),
+            10,
+            10.0,
+            all_bases(),
+        );
+        let mut watch = WatchOptions::try_new(scoring, mini.bam(), output_dir)?;
+        watch
+            .interval(Duration::from_millis(10))
+            .idle_timeout(Some(Duration::from_millis(50)));
+        Ok(watch)
+    }
+
+    fn done(output_dir: &Path) -> Result<Vec<String>> {
+        let done = fs::read_to_string(output_dir.join(DONE_FILE))?;
+        Ok(done.lines().map(String::from).collect())
+    }
+
+    #[test]
+    fn test_watch_stream() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output_dir = mini.dir().join("watch");
+        let mut watch = test_options(&mini, &output_dir)?;
+        watch.batch_reads(1);
+        watch.watch_stream(BufReader::new(File::open(mini.eventalign())?))?;
+
+        assert_eq!(done(&output_dir)?, vec!["stream_00000", "stream_00001"]);
+        for chunk in ["stream_00000", "stream_00001"] {
+            let scored = output_dir.join("scored").join(format!("{chunk}.arrow"));
+            assert_eq!(ChunkSummary::from_scored(scored)?.reads, 1);
+        }
+        let summary = fs::read_to_string(output_dir.join(SUMMARY_FILE))?;
+        assert_eq!(summary.lines().count(), 3);
+        assert!(summary.starts_with(SUMMARY_HEADER));
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_stream_resume_after_failure() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let output_dir = mini.dir().join("watch");
+        // Every read twice, so there are four chunks of one read
+        let text = fs::read_to_string(mini.eventalign())?;
+        let (header, body) = text.split_once('\n').unwrap();
+        let twice = format!("{header}\n{body}{body}");
+
+        let mut watch = test_options(&mini, &output_dir)?;
+        watch.batch_reads(1);
+        // A directory where the second chunk's output goes makes it fail
+        fs::create_dir(output_dir.join("collapsed").join("stream_00001.arrow"))?;
+        watch.watch_stream(Cursor::new(&twice))?;
+        assert_eq!(
+            done(&output_dir)?,
+            vec!["stream_00000", "stream_00002", "stream_00003"]
+        );
+
+        // Resuming doesn't reuse the names of finished chunks
+        let mut watch = test_options(&mini, &output_dir)?;
+        watch.batch_reads(1);
+        watch.watch_stream(BufReader::new(File::open(mini.eventalign())?))?;
+        assert_eq!(
+            done(&output_dir)?,
+            vec![
+                "stream_00000",
+                "stream_00002",
+                "stream_00003",
+                "stream_00004",
+                "stream_00005"
+            ]
+        );
+        let summary = fs::read_to_string(output_dir.join(SUMMARY_FILE))?;
+        assert_eq!(summary.lines().count(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_dir() -> Result<()> {
+        let mini = MiniGenome::new()?;
+        let input_dir = mini.dir().join("chunks");
+        let output_dir = mini.dir().join("watch");
+        fs::create_dir(&input_dir)?;
+        fs::copy(mini.eventalign(), input_dir.join("a.eventalign.txt"))?;
+        fs::write(input_dir.join("ignored.txt"), "")?;
+        fs::write(input_dir.join("bad.eventalign.txt"), "not eventalign\n1\n")?;
+
+        // The malformed chunk is skipped without stopping the watch
+        test_options(&mini, &output_dir)?.watch_dir(&input_dir)?;
+        assert_eq!(done(&output_dir)?, vec!["a.eventalign.txt"]);
+        let scored = output_dir.join("scored").join("a.eventalign.arrow");
+        assert_eq!(ChunkSummary::from_scored(scored)?.reads, 2);
+
+        // Watching again only processes new chunks
+        fs::remove_file(input_dir.join("bad.eventalign.txt"))?;
+        fs::copy(mini.eventalign(), input_dir.join("b.eventalign.txt"))?;
+        test_options(&mini, &output_dir)?.watch_dir(&input_dir)?;
+        assert_eq!(
+            done(&output_dir)?,
+            vec!["a.eventalign.txt", "b.eventalign.txt"]
+        );
+        Ok(())
+    }
+}
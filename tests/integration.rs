@@ -6,6 +6,10 @@ use escargot::CargoBuild;
 use predicates::prelude::predicate;
 
 #[test]
+#[cfg_attr(
+    not(feature = "large-data-tests"),
+    ignore = "requires large-data-tests feature"
+)]
 fn integration() -> Result<(), Box<dyn Error>> {
     let temp_dir = TempDir::new()?.into_persistent_if(std::env::var("TEST_PERSIST").is_ok());
 
@@ -103,6 +107,7 @@ fn integration() -> Result<(), Box<dyn Error>> {
     let ranks = temp_dir.path().join("ranks");
     Command::new(cawlr)
         .arg("rank")
+        .arg("build")
         .arg("--neg-ctrl")
         .arg(&neg_train)
         .arg("--pos-ctrl")
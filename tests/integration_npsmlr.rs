@@ -6,6 +6,10 @@ use escargot::CargoBuild;
 use log::LevelFilter;
 
 #[test]
+#[cfg_attr(
+    not(feature = "large-data-tests"),
+    ignore = "requires large-data-tests feature"
+)]
 fn integration_npsmlr() -> Result<(), Box<dyn Error>> {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
@@ -103,6 +107,7 @@ fn integration_npsmlr() -> Result<(), Box<dyn Error>> {
     let ranks = temp_dir.path().join("ranks");
     Command::new(cawlr)
         .arg("rank")
+        .arg("build")
         .arg("--neg-ctrl")
         .arg(&neg_train)
         .arg("--pos-ctrl")